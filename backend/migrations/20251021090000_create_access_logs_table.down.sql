@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS idx_access_logs_npub;
+DROP INDEX IF EXISTS idx_access_logs_created_at;
+DROP TABLE IF EXISTS access_logs;
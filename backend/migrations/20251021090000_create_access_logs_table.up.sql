@@ -0,0 +1,13 @@
+-- Create access_logs table recording every handshake/authentication attempt
+CREATE TABLE IF NOT EXISTS access_logs (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    npub TEXT NOT NULL,
+    door_id INTEGER,
+    result TEXT NOT NULL,
+    reason TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Create indexes for the logs page and per-key lookups
+CREATE INDEX IF NOT EXISTS idx_access_logs_created_at ON access_logs(created_at);
+CREATE INDEX IF NOT EXISTS idx_access_logs_npub ON access_logs(npub);
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS idx_key_door_permissions_door_id;
+DROP TABLE IF EXISTS key_door_permissions;
+DROP TABLE IF EXISTS doors;
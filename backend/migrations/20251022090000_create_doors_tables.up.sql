@@ -0,0 +1,19 @@
+-- Create doors table, one row per door on the IntelliM controller
+CREATE TABLE IF NOT EXISTS doors (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name TEXT NOT NULL,
+    controller_door_id INTEGER NOT NULL UNIQUE,
+    enabled BOOLEAN NOT NULL DEFAULT true,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Create join table listing which keys may open which doors
+CREATE TABLE IF NOT EXISTS key_door_permissions (
+    key_id UUID NOT NULL REFERENCES keys(id) ON DELETE CASCADE,
+    door_id UUID NOT NULL REFERENCES doors(id) ON DELETE CASCADE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (key_id, door_id)
+);
+
+-- Create index for door -> keys lookups
+CREATE INDEX IF NOT EXISTS idx_key_door_permissions_door_id ON key_door_permissions(door_id);
@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS idx_key_schedules_key_id;
+DROP TABLE IF EXISTS key_schedules;
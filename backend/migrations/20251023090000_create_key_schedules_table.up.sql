@@ -0,0 +1,14 @@
+-- Create key_schedules table. A key with no schedules may be used at any time;
+-- otherwise it is only allowed inside one of its windows.
+CREATE TABLE IF NOT EXISTS key_schedules (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    key_id UUID NOT NULL REFERENCES keys(id) ON DELETE CASCADE,
+    weekdays SMALLINT[] NOT NULL, -- ISO weekday numbers, 1 = Monday ... 7 = Sunday
+    start_time TIME NOT NULL,
+    end_time TIME NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    CHECK (start_time < end_time)
+);
+
+-- Create index for key -> schedules lookups
+CREATE INDEX IF NOT EXISTS idx_key_schedules_key_id ON key_schedules(key_id);
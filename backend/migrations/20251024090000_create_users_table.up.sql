@@ -0,0 +1,8 @@
+-- Create users table for admin UI accounts
+CREATE TABLE IF NOT EXISTS users (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    username TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL, -- argon2 PHC string
+    role TEXT NOT NULL CHECK (role IN ('admin', 'viewer')),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,3 @@
+DELETE FROM access_logs WHERE npub IS NULL;
+ALTER TABLE access_logs DROP COLUMN IF EXISTS triggered_by;
+ALTER TABLE access_logs ALTER COLUMN npub SET NOT NULL;
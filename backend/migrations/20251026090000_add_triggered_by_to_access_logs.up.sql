@@ -0,0 +1,3 @@
+-- Manual unlocks from the admin UI have no npub; record who triggered them instead
+ALTER TABLE access_logs ALTER COLUMN npub DROP NOT NULL;
+ALTER TABLE access_logs ADD COLUMN IF NOT EXISTS triggered_by TEXT;
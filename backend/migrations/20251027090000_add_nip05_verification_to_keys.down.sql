@@ -0,0 +1,2 @@
+ALTER TABLE keys DROP COLUMN IF EXISTS nip05_checked_at;
+ALTER TABLE keys DROP COLUMN IF EXISTS nip05_verified;
@@ -0,0 +1,3 @@
+-- Track whether a key's NIP-05 address resolves back to its npub
+ALTER TABLE keys ADD COLUMN IF NOT EXISTS nip05_verified BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE keys ADD COLUMN IF NOT EXISTS nip05_checked_at TIMESTAMP WITH TIME ZONE;
@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS idx_guest_passes_npub;
+DROP TABLE IF EXISTS guest_passes;
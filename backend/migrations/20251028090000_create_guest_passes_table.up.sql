@@ -0,0 +1,19 @@
+-- Create guest_passes table. A pass grants access for a limited number of uses, until a
+-- deadline, or both. Passes created without an npub are claimed by the first key that
+-- completes the pass's own handshake URL.
+CREATE TABLE IF NOT EXISTS guest_passes (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    label TEXT NOT NULL,
+    npub TEXT,
+    remaining_uses INTEGER CHECK (remaining_uses >= 0), -- NULL = unlimited until expires_at
+    uses INTEGER NOT NULL DEFAULT 0,
+    expires_at TIMESTAMP WITH TIME ZONE,
+    handshake_url TEXT,
+    created_by TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    last_used_at TIMESTAMP WITH TIME ZONE,
+    CHECK (remaining_uses IS NOT NULL OR expires_at IS NOT NULL)
+);
+
+-- Create index for npub -> guest pass lookups in the handshake loop
+CREATE INDEX IF NOT EXISTS idx_guest_passes_npub ON guest_passes(npub);
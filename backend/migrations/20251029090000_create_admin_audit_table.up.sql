@@ -0,0 +1,12 @@
+-- Create admin_audit table, one row per change made through the admin UI or API
+CREATE TABLE IF NOT EXISTS admin_audit (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    actor TEXT NOT NULL, -- username, or the attempted username for a failed login
+    action TEXT NOT NULL, -- e.g. key.disable, door.unlock, login.failed
+    target TEXT,
+    ip TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Create index for the newest-first audit page
+CREATE INDEX IF NOT EXISTS idx_admin_audit_created_at ON admin_audit(created_at DESC);
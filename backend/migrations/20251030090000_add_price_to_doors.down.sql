@@ -0,0 +1 @@
+ALTER TABLE doors DROP COLUMN IF EXISTS price_sats;
@@ -0,0 +1,2 @@
+-- Price in sats charged through Portal before a door unlocks; NULL keeps the door free
+ALTER TABLE doors ADD COLUMN IF NOT EXISTS price_sats BIGINT CHECK (price_sats > 0);
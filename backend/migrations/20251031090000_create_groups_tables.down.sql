@@ -0,0 +1,5 @@
+DROP INDEX IF EXISTS idx_group_door_permissions_door_id;
+DROP INDEX IF EXISTS idx_key_groups_group_id;
+DROP TABLE IF EXISTS group_door_permissions;
+DROP TABLE IF EXISTS key_groups;
+DROP TABLE IF EXISTS groups;
@@ -0,0 +1,26 @@
+-- Create groups table, named access levels such as "staff" or "cleaning"
+CREATE TABLE IF NOT EXISTS groups (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Create join table listing the members of each group
+CREATE TABLE IF NOT EXISTS key_groups (
+    key_id UUID NOT NULL REFERENCES keys(id) ON DELETE CASCADE,
+    group_id UUID NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (key_id, group_id)
+);
+
+-- Create join table listing which groups may open which doors
+CREATE TABLE IF NOT EXISTS group_door_permissions (
+    group_id UUID NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+    door_id UUID NOT NULL REFERENCES doors(id) ON DELETE CASCADE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (group_id, door_id)
+);
+
+-- Create indexes for group -> keys and door -> groups lookups
+CREATE INDEX IF NOT EXISTS idx_key_groups_group_id ON key_groups(group_id);
+CREATE INDEX IF NOT EXISTS idx_group_door_permissions_door_id ON group_door_permissions(door_id);
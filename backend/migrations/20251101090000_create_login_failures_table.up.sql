@@ -0,0 +1,11 @@
+-- Create login_failures table, failed admin logins counted per client IP
+CREATE TABLE IF NOT EXISTS login_failures (
+    ip TEXT PRIMARY KEY,
+    failures INTEGER NOT NULL DEFAULT 0, -- consecutive failures, reset by a successful login
+    last_username TEXT NOT NULL,
+    last_failed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    locked_until TIMESTAMP WITH TIME ZONE
+);
+
+-- Create index for the most-recent-first admin view
+CREATE INDEX IF NOT EXISTS idx_login_failures_last_failed_at ON login_failures(last_failed_at DESC);
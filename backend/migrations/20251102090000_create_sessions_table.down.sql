@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS idx_sessions_last_used_at;
+DROP TABLE IF EXISTS sessions;
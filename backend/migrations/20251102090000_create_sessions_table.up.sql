@@ -0,0 +1,14 @@
+-- Create sessions table, one row per signed-in browser or API client holding a refresh token
+CREATE TABLE IF NOT EXISTS sessions (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+    refresh_token_hash TEXT NOT NULL UNIQUE, -- SHA-256 hex of the refresh token cookie
+    user_agent TEXT,
+    ip TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    last_used_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL -- pushed forward on every refresh
+);
+
+-- Create index for the most-recently-used-first admin view
+CREATE INDEX IF NOT EXISTS idx_sessions_last_used_at ON sessions(last_used_at DESC);
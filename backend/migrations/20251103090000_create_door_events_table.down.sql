@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS idx_door_events_door_id_created_at;
+DROP TABLE IF EXISTS door_events;
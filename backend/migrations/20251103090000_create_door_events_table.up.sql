@@ -0,0 +1,10 @@
+-- Create door_events table, open/close transitions reported by door sensors
+CREATE TABLE IF NOT EXISTS door_events (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    door_id UUID NOT NULL REFERENCES doors(id) ON DELETE CASCADE,
+    event TEXT NOT NULL CHECK (event IN ('opened', 'closed', 'held_open')),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Create index for a door's most recent events
+CREATE INDEX IF NOT EXISTS idx_door_events_door_id_created_at ON door_events(door_id, created_at DESC);
@@ -0,0 +1,3 @@
+ALTER TABLE keys DROP COLUMN IF EXISTS profile_fetched_at;
+ALTER TABLE keys DROP COLUMN IF EXISTS picture_url;
+ALTER TABLE keys DROP COLUMN IF EXISTS display_name;
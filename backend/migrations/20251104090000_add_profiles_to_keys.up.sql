@@ -0,0 +1,4 @@
+-- Nostr profile (kind 0) display name and picture of each key, refreshed in the background
+ALTER TABLE keys ADD COLUMN IF NOT EXISTS display_name TEXT;
+ALTER TABLE keys ADD COLUMN IF NOT EXISTS picture_url TEXT;
+ALTER TABLE keys ADD COLUMN IF NOT EXISTS profile_fetched_at TIMESTAMP WITH TIME ZONE;
@@ -0,0 +1,4 @@
+DROP INDEX IF EXISTS idx_keys_deleted_at;
+-- Without the column, soft-deleted keys would come back as active ones
+DELETE FROM keys WHERE deleted_at IS NOT NULL;
+ALTER TABLE keys DROP COLUMN IF EXISTS deleted_at;
@@ -0,0 +1,5 @@
+-- Soft delete keys, so access logs and audit entries keep pointing at a key that can be restored
+ALTER TABLE keys ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMP WITH TIME ZONE;
+
+-- Create index for the recently deleted list
+CREATE INDEX IF NOT EXISTS idx_keys_deleted_at ON keys(deleted_at) WHERE deleted_at IS NOT NULL;
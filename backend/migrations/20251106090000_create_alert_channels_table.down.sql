@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS idx_alert_channels_username;
+DROP TABLE IF EXISTS alert_channels;
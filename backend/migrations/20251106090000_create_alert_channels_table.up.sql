@@ -0,0 +1,13 @@
+-- Create alert_channels table, where each admin says how and about what they want to be alerted
+CREATE TABLE IF NOT EXISTS alert_channels (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+    kind TEXT NOT NULL CHECK (kind IN ('email', 'telegram')),
+    target TEXT NOT NULL, -- email address or Telegram chat id
+    triggers TEXT[] NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT true,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Create index for an admin's channels
+CREATE INDEX IF NOT EXISTS idx_alert_channels_username ON alert_channels(username);
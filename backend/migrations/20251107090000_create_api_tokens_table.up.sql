@@ -0,0 +1,10 @@
+-- Create api_tokens table, long-lived bearer tokens for scripts and kiosks
+CREATE TABLE IF NOT EXISTS api_tokens (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE, -- SHA-256 of the token, which is only shown once
+    scopes TEXT[] NOT NULL, -- read-logs, manage-keys, unlock
+    created_by TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    last_used_at TIMESTAMP WITH TIME ZONE
+);
@@ -0,0 +1,2 @@
+ALTER TABLE keys DROP COLUMN IF EXISTS access_count;
+ALTER TABLE keys DROP COLUMN IF EXISTS last_access_at;
@@ -0,0 +1,12 @@
+-- Track how often and when each key last unlocked a door
+ALTER TABLE keys ADD COLUMN IF NOT EXISTS last_access_at TIMESTAMP WITH TIME ZONE;
+ALTER TABLE keys ADD COLUMN IF NOT EXISTS access_count BIGINT NOT NULL DEFAULT 0;
+
+-- Start the counters from the unlocks already in the access log
+UPDATE keys k SET access_count = l.unlocks, last_access_at = l.last_unlock
+FROM (
+    SELECT npub, COUNT(*) AS unlocks, MAX(created_at) AS last_unlock
+    FROM access_logs WHERE result = 'granted' AND npub IS NOT NULL
+    GROUP BY npub
+) l
+WHERE l.npub = k.npub;
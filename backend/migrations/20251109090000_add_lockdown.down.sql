@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS lockdown;
+ALTER TABLE doors DROP COLUMN IF EXISTS locked_down;
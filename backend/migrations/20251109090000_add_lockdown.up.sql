@@ -0,0 +1,13 @@
+-- Doors that decline every unlock until their lockdown is lifted
+ALTER TABLE doors ADD COLUMN IF NOT EXISTS locked_down BOOLEAN NOT NULL DEFAULT false;
+
+-- Site-wide lockdown, a single row
+CREATE TABLE IF NOT EXISTS lockdown (
+    id BOOLEAN PRIMARY KEY DEFAULT true CHECK (id),
+    active BOOLEAN NOT NULL DEFAULT false,
+    reason TEXT,
+    set_by TEXT,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO lockdown (id) VALUES (true) ON CONFLICT DO NOTHING;
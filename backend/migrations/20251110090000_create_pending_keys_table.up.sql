@@ -0,0 +1,10 @@
+-- Keys requested from the public /request-access page, waiting for an admin
+CREATE TABLE IF NOT EXISTS pending_keys (
+    id UUID PRIMARY KEY,
+    npub TEXT NOT NULL UNIQUE,
+    note TEXT,
+    ip TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_pending_keys_ip ON pending_keys (ip);
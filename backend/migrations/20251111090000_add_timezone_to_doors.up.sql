@@ -0,0 +1,2 @@
+-- IANA timezone the door's key schedules are evaluated in; NULL uses the site timezone
+ALTER TABLE doors ADD COLUMN IF NOT EXISTS timezone TEXT;
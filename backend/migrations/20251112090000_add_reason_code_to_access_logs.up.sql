@@ -0,0 +1,2 @@
+-- Machine-readable reason a denied or declined attempt stayed locked, see DenyReason
+ALTER TABLE access_logs ADD COLUMN IF NOT EXISTS reason_code TEXT;
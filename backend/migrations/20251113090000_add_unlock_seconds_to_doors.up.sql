@@ -0,0 +1,2 @@
+-- Seconds the door stays unlocked; NULL leaves it to the door backend's default
+ALTER TABLE doors ADD COLUMN IF NOT EXISTS unlock_seconds INTEGER CHECK (unlock_seconds > 0);
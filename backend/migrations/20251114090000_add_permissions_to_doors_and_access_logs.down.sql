@@ -0,0 +1,2 @@
+ALTER TABLE access_logs DROP COLUMN IF EXISTS granted_permissions;
+ALTER TABLE doors DROP COLUMN IF EXISTS required_permissions;
@@ -0,0 +1,3 @@
+-- NIP permissions a door requires the authenticating wallet to grant, and what it granted
+ALTER TABLE doors ADD COLUMN IF NOT EXISTS required_permissions TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE access_logs ADD COLUMN IF NOT EXISTS granted_permissions TEXT[];
@@ -0,0 +1,2 @@
+-- Keys that enrolled themselves through open enrollment and wait for an admin to enable them
+ALTER TABLE keys ADD COLUMN IF NOT EXISTS pending BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,6 @@
+DROP TRIGGER IF EXISTS group_door_permissions_notify_change ON group_door_permissions;
+DROP TRIGGER IF EXISTS key_schedules_notify_change ON key_schedules;
+DROP TRIGGER IF EXISTS key_groups_notify_change ON key_groups;
+DROP TRIGGER IF EXISTS key_door_permissions_notify_change ON key_door_permissions;
+DROP TRIGGER IF EXISTS keys_notify_change ON keys;
+DROP FUNCTION IF EXISTS notify_key_change();
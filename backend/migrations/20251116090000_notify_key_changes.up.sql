@@ -0,0 +1,56 @@
+-- Announce every change to what a key may open on the portal_access_keys channel, with the
+-- key's npub as payload, or '*' when a group's doors change. The door service keeps the keys
+-- it has checked in memory and drops them when told, whichever service or tool made the change.
+CREATE OR REPLACE FUNCTION notify_key_change() RETURNS trigger AS $$
+DECLARE
+    changed RECORD;
+BEGIN
+    -- Fired once per statement, since any number of keys may be in the group
+    IF TG_TABLE_NAME = 'group_door_permissions' THEN
+        PERFORM pg_notify('portal_access_keys', '*');
+        RETURN NULL;
+    END IF;
+
+    IF TG_OP = 'DELETE' THEN
+        changed := OLD;
+    ELSE
+        changed := NEW;
+    END IF;
+
+    IF TG_TABLE_NAME = 'keys' THEN
+        PERFORM pg_notify('portal_access_keys', changed.npub);
+        IF TG_OP = 'UPDATE' AND OLD.npub <> NEW.npub THEN
+            PERFORM pg_notify('portal_access_keys', OLD.npub);
+        END IF;
+    ELSE
+        -- The key itself is gone when its rows are removed by a cascade; its own
+        -- notification already covers them
+        PERFORM pg_notify('portal_access_keys', npub) FROM keys WHERE id = changed.key_id;
+        IF TG_OP = 'UPDATE' AND OLD.key_id <> NEW.key_id THEN
+            PERFORM pg_notify('portal_access_keys', npub) FROM keys WHERE id = OLD.key_id;
+        END IF;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Counters and profile details change on every unlock and do not matter here
+CREATE TRIGGER keys_notify_change
+    AFTER INSERT OR DELETE OR UPDATE OF npub, status, deleted_at ON keys
+    FOR EACH ROW EXECUTE FUNCTION notify_key_change();
+
+CREATE TRIGGER key_door_permissions_notify_change
+    AFTER INSERT OR UPDATE OR DELETE ON key_door_permissions
+    FOR EACH ROW EXECUTE FUNCTION notify_key_change();
+
+CREATE TRIGGER key_groups_notify_change
+    AFTER INSERT OR UPDATE OR DELETE ON key_groups
+    FOR EACH ROW EXECUTE FUNCTION notify_key_change();
+
+CREATE TRIGGER key_schedules_notify_change
+    AFTER INSERT OR UPDATE OR DELETE ON key_schedules
+    FOR EACH ROW EXECUTE FUNCTION notify_key_change();
+
+CREATE TRIGGER group_door_permissions_notify_change
+    AFTER INSERT OR UPDATE OR DELETE ON group_door_permissions
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_key_change();
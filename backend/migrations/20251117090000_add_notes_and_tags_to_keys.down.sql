@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS idx_keys_tags;
+ALTER TABLE keys DROP COLUMN IF EXISTS tags;
+ALTER TABLE keys DROP COLUMN IF EXISTS notes;
@@ -0,0 +1,6 @@
+-- Free-text notes and tags on keys, e.g. "badge returned 2024-05-02" and "contractor"
+ALTER TABLE keys ADD COLUMN IF NOT EXISTS notes TEXT;
+ALTER TABLE keys ADD COLUMN IF NOT EXISTS tags TEXT[] NOT NULL DEFAULT '{}';
+
+-- Create index for the keys page tag filter
+CREATE INDEX IF NOT EXISTS idx_keys_tags ON keys USING GIN (tags);
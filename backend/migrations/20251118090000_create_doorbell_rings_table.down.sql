@@ -0,0 +1 @@
+DROP TABLE IF EXISTS doorbell_rings;
@@ -0,0 +1,9 @@
+-- Create doorbell_rings table. A visitor whose key may not open a door rings while an admin
+-- may still let them in; the row is removed once answered, or on the next ring after it expires.
+CREATE TABLE IF NOT EXISTS doorbell_rings (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    npub TEXT NOT NULL,
+    door_id INTEGER NOT NULL, -- controller door number, as in access_logs
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
@@ -0,0 +1,10 @@
+DROP INDEX IF EXISTS idx_access_logs_site_id_created_at;
+DROP INDEX IF EXISTS idx_doors_site_id;
+DROP INDEX IF EXISTS idx_keys_site_id;
+ALTER TABLE api_tokens DROP COLUMN IF EXISTS site_id;
+ALTER TABLE sessions DROP COLUMN IF EXISTS site_id;
+ALTER TABLE users DROP COLUMN IF EXISTS site_id;
+ALTER TABLE access_logs DROP COLUMN IF EXISTS site_id;
+ALTER TABLE doors DROP COLUMN IF EXISTS site_id;
+ALTER TABLE keys DROP COLUMN IF EXISTS site_id;
+DROP TABLE IF EXISTS sites;
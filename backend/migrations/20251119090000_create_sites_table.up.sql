@@ -0,0 +1,30 @@
+-- Create sites table: the buildings or locations one deployment serves. Everything that
+-- existed before sites were added belongs to the default site.
+CREATE TABLE IF NOT EXISTS sites (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO sites (id, name) VALUES ('00000000-0000-0000-0000-000000000001', 'Default')
+ON CONFLICT DO NOTHING;
+
+-- Keys and doors belong to one site; a key only opens doors of its own
+ALTER TABLE keys ADD COLUMN IF NOT EXISTS site_id UUID NOT NULL
+    DEFAULT '00000000-0000-0000-0000-000000000001' REFERENCES sites(id);
+ALTER TABLE doors ADD COLUMN IF NOT EXISTS site_id UUID NOT NULL
+    DEFAULT '00000000-0000-0000-0000-000000000001' REFERENCES sites(id);
+
+-- Access log entries take the site of their door
+ALTER TABLE access_logs ADD COLUMN IF NOT EXISTS site_id UUID REFERENCES sites(id);
+UPDATE access_logs l SET site_id = d.site_id FROM doors d WHERE d.controller_door_id = l.door_id;
+
+-- Users, their sessions and API tokens limited to one site; NULL for every site
+ALTER TABLE users ADD COLUMN IF NOT EXISTS site_id UUID REFERENCES sites(id);
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS site_id UUID REFERENCES sites(id) ON DELETE SET NULL;
+ALTER TABLE api_tokens ADD COLUMN IF NOT EXISTS site_id UUID REFERENCES sites(id);
+
+-- Create indexes for the per-site lists
+CREATE INDEX IF NOT EXISTS idx_keys_site_id ON keys(site_id);
+CREATE INDEX IF NOT EXISTS idx_doors_site_id ON doors(site_id);
+CREATE INDEX IF NOT EXISTS idx_access_logs_site_id_created_at ON access_logs(site_id, created_at);
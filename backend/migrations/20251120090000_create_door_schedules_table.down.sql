@@ -0,0 +1,6 @@
+DELETE FROM door_events WHERE event IN ('auto_unlocked', 'auto_locked');
+ALTER TABLE door_events DROP CONSTRAINT IF EXISTS door_events_event_check;
+ALTER TABLE door_events ADD CONSTRAINT door_events_event_check
+    CHECK (event IN ('opened', 'closed', 'held_open'));
+
+DROP TABLE IF EXISTS door_schedules;
@@ -0,0 +1,19 @@
+-- Create door_schedules table, weekly windows a door is held unlocked for (office hours).
+-- The door is unlocked when a window starts and locked again when it ends.
+CREATE TABLE IF NOT EXISTS door_schedules (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    door_id UUID NOT NULL REFERENCES doors(id) ON DELETE CASCADE,
+    weekdays SMALLINT[] NOT NULL, -- ISO weekday numbers, 1 = Monday ... 7 = Sunday
+    unlock_time TIME NOT NULL,
+    lock_time TIME NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    CHECK (unlock_time < lock_time)
+);
+
+-- Create index for door -> schedules lookups
+CREATE INDEX IF NOT EXISTS idx_door_schedules_door_id ON door_schedules(door_id);
+
+-- Scheduled unlocks and locks show in the door's events next to the sensor's
+ALTER TABLE door_events DROP CONSTRAINT IF EXISTS door_events_event_check;
+ALTER TABLE door_events ADD CONSTRAINT door_events_event_check
+    CHECK (event IN ('opened', 'closed', 'held_open', 'auto_unlocked', 'auto_locked'));
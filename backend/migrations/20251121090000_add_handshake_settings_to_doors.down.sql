@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS doors_handshake_token_key;
+ALTER TABLE doors DROP COLUMN IF EXISTS handshake_rotate_secs;
+ALTER TABLE doors DROP COLUMN IF EXISTS handshake_token;
@@ -0,0 +1,6 @@
+-- Static token of the door's handshake URL; NULL keeps the subject from DOOR_SUBJECTS
+ALTER TABLE doors ADD COLUMN IF NOT EXISTS handshake_token TEXT;
+-- Seconds between new handshake URLs with random tokens; NULL keeps one static URL
+ALTER TABLE doors ADD COLUMN IF NOT EXISTS handshake_rotate_secs INTEGER CHECK (handshake_rotate_secs > 0);
+
+CREATE UNIQUE INDEX IF NOT EXISTS doors_handshake_token_key ON doors (handshake_token);
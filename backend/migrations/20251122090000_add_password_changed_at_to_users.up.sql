@@ -0,0 +1,4 @@
+-- When the user last set their password, for forced rotation; existing passwords count from
+-- when their account was created
+ALTER TABLE users ADD COLUMN IF NOT EXISTS password_changed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW();
+UPDATE users SET password_changed_at = created_at;
@@ -0,0 +1,6 @@
+DROP TRIGGER IF EXISTS keys_notify_change ON keys;
+CREATE TRIGGER keys_notify_change
+    AFTER INSERT OR DELETE OR UPDATE OF npub, status, deleted_at ON keys
+    FOR EACH ROW EXECUTE FUNCTION notify_key_change();
+
+ALTER TABLE keys DROP COLUMN IF EXISTS expires_at;
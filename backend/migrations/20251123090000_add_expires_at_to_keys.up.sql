@@ -0,0 +1,8 @@
+-- Optional end date of a key, after which it no longer opens doors
+ALTER TABLE keys ADD COLUMN IF NOT EXISTS expires_at TIMESTAMP WITH TIME ZONE;
+
+-- Announce new end dates too, so the door service drops the key's cached rules
+DROP TRIGGER IF EXISTS keys_notify_change ON keys;
+CREATE TRIGGER keys_notify_change
+    AFTER INSERT OR DELETE OR UPDATE OF npub, status, deleted_at, expires_at ON keys
+    FOR EACH ROW EXECUTE FUNCTION notify_key_change();
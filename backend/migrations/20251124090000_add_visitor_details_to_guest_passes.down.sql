@@ -0,0 +1,3 @@
+ALTER TABLE guest_passes DROP COLUMN IF EXISTS valid_from;
+ALTER TABLE guest_passes DROP COLUMN IF EXISTS email;
+ALTER TABLE guest_passes DROP COLUMN IF EXISTS host;
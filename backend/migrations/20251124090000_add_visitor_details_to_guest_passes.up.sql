@@ -0,0 +1,5 @@
+-- Pre-registered visitors are guest passes with the person they are visiting, the address their
+-- invitation went to, and the start of the window they are expected in
+ALTER TABLE guest_passes ADD COLUMN IF NOT EXISTS host TEXT;
+ALTER TABLE guest_passes ADD COLUMN IF NOT EXISTS email TEXT;
+ALTER TABLE guest_passes ADD COLUMN IF NOT EXISTS valid_from TIMESTAMP WITH TIME ZONE;
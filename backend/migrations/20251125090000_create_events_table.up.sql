@@ -0,0 +1,18 @@
+-- Create events table, the stages one handshake went through (received, key resolved,
+-- decision, authentication, payment, actuation, logged), so a contested decision can be
+-- traced and replayed. Every event of one handshake shares its attempt_id.
+CREATE TABLE IF NOT EXISTS events (
+    id BIGSERIAL PRIMARY KEY,
+    attempt_id UUID NOT NULL,
+    npub TEXT NOT NULL,
+    door_id INTEGER NOT NULL,
+    stage TEXT NOT NULL,
+    data JSONB NOT NULL,
+    access_log_id UUID, -- set on the logged stage, to find the trail of a log entry
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Create indexes for the trail of an attempt and of a log entry, and for pruning
+CREATE INDEX IF NOT EXISTS idx_events_attempt_id ON events(attempt_id);
+CREATE INDEX IF NOT EXISTS idx_events_access_log_id ON events(access_log_id);
+CREATE INDEX IF NOT EXISTS idx_events_created_at ON events(created_at);
@@ -0,0 +1,2 @@
+ALTER TABLE keys DROP COLUMN IF EXISTS photo_type;
+ALTER TABLE keys DROP COLUMN IF EXISTS photo;
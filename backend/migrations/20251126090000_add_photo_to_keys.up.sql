@@ -0,0 +1,3 @@
+-- Name of the key's photo in the photo store, and its image type
+ALTER TABLE keys ADD COLUMN IF NOT EXISTS photo TEXT;
+ALTER TABLE keys ADD COLUMN IF NOT EXISTS photo_type TEXT;
@@ -0,0 +1,16 @@
+-- Create anomalies table: unusual access patterns the anomaly detector flagged, such as a
+-- disabled key that keeps trying or an unlock at an hour the key never used before. Each one
+-- is also raised as an alert; this keeps them for the dashboard.
+CREATE TABLE IF NOT EXISTS anomalies (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    kind TEXT NOT NULL, -- the alert trigger, such as anomaly.unusual_hour
+    npub TEXT NOT NULL,
+    door_id INTEGER,
+    site_id UUID REFERENCES sites(id),
+    detail TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Create indexes for the dashboard and for repeat checks per key
+CREATE INDEX IF NOT EXISTS idx_anomalies_site_id_created_at ON anomalies(site_id, created_at);
+CREATE INDEX IF NOT EXISTS idx_anomalies_npub_kind ON anomalies(npub, kind);
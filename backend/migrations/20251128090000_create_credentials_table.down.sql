@@ -0,0 +1,2 @@
+ALTER TABLE access_logs DROP COLUMN IF EXISTS credential;
+DROP TABLE IF EXISTS credentials;
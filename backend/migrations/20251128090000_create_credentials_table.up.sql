@@ -0,0 +1,15 @@
+-- Create credentials table: badge card numbers read by Wiegand or OSDP readers, each standing
+-- in for the key it belongs to, so a site can run cards next to Nostr keys while it moves over
+CREATE TABLE IF NOT EXISTS credentials (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    key_id UUID NOT NULL REFERENCES keys(id) ON DELETE CASCADE,
+    card_number TEXT NOT NULL UNIQUE, -- as card_readers::normalize_card leaves it
+    label TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Create index for the cards of a key
+CREATE INDEX IF NOT EXISTS idx_credentials_key_id ON credentials(key_id);
+
+-- Access log entries from a badge reader record the card presented
+ALTER TABLE access_logs ADD COLUMN IF NOT EXISTS credential TEXT;
@@ -0,0 +1,4 @@
+DELETE FROM door_events WHERE event = 'relock_failed';
+ALTER TABLE door_events DROP CONSTRAINT IF EXISTS door_events_event_check;
+ALTER TABLE door_events ADD CONSTRAINT door_events_event_check
+    CHECK (event IN ('opened', 'closed', 'held_open', 'auto_unlocked', 'auto_locked'));
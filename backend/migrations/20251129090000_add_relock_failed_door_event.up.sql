@@ -0,0 +1,4 @@
+-- Doors still unlocked after their unlock ended show in the door's events
+ALTER TABLE door_events DROP CONSTRAINT IF EXISTS door_events_event_check;
+ALTER TABLE door_events ADD CONSTRAINT door_events_event_check
+    CHECK (event IN ('opened', 'closed', 'held_open', 'auto_unlocked', 'auto_locked', 'relock_failed'));
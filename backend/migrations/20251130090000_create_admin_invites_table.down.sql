@@ -0,0 +1,4 @@
+ALTER TABLE users DROP COLUMN IF EXISTS totp_secret;
+
+DROP INDEX IF EXISTS idx_admin_invites_created_at;
+DROP TABLE IF EXISTS admin_invites;
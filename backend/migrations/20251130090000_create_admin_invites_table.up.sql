@@ -0,0 +1,18 @@
+-- Create admin_invites table, one-time links a new user sets up their own account with
+CREATE TABLE IF NOT EXISTS admin_invites (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    token_hash TEXT NOT NULL UNIQUE, -- SHA-256 of the link's token, which is only shown once
+    role TEXT NOT NULL CHECK (role IN ('admin', 'viewer')),
+    site_id UUID REFERENCES sites(id) ON DELETE CASCADE, -- None for every site
+    created_by TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    accepted_by TEXT, -- username the invite was used for
+    accepted_at TIMESTAMP WITH TIME ZONE,
+    revoked_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX IF NOT EXISTS idx_admin_invites_created_at ON admin_invites(created_at DESC);
+
+-- Authenticator app secret (hex) of users who turned on two-factor sign in
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_secret TEXT;
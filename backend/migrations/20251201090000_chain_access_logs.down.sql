@@ -0,0 +1,8 @@
+DROP TRIGGER IF EXISTS access_logs_chain ON access_logs;
+DROP FUNCTION IF EXISTS chain_access_log();
+DROP FUNCTION IF EXISTS access_log_hash(TEXT, access_logs);
+DROP FUNCTION IF EXISTS access_log_field(TEXT);
+DROP INDEX IF EXISTS idx_access_logs_seq;
+ALTER TABLE access_logs DROP COLUMN IF EXISTS hash;
+ALTER TABLE access_logs DROP COLUMN IF EXISTS prev_hash;
+ALTER TABLE access_logs DROP COLUMN IF EXISTS seq;
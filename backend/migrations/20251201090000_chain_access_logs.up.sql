@@ -0,0 +1,70 @@
+-- Chain the access log for tamper evidence: every entry stores the hash of the entry before it
+-- and a hash over that and its own content, in the order given by seq. Changing, removing or
+-- reordering an entry breaks the chain from there on, which `verify_access_log_chain` finds.
+ALTER TABLE access_logs ADD COLUMN IF NOT EXISTS seq BIGINT;
+ALTER TABLE access_logs ADD COLUMN IF NOT EXISTS prev_hash TEXT;
+ALTER TABLE access_logs ADD COLUMN IF NOT EXISTS hash TEXT;
+CREATE SEQUENCE IF NOT EXISTS access_logs_seq OWNED BY access_logs.seq;
+
+-- One field of the hashed content: its length in bytes and text, or '-' for NULL, so no two
+-- different entries run together into the same text
+CREATE OR REPLACE FUNCTION access_log_field(value TEXT) RETURNS TEXT AS $$
+    SELECT CASE WHEN value IS NULL THEN '-' ELSE octet_length(value) || ':' || value END;
+$$ LANGUAGE sql IMMUTABLE;
+
+-- Hex SHA-256 of the previous entry's hash and the entry's content; times in UTC to the
+-- microsecond, whatever the session's timezone
+CREATE OR REPLACE FUNCTION access_log_hash(prev_hash TEXT, entry access_logs) RETURNS TEXT AS $$
+    SELECT encode(sha256(convert_to(
+        access_log_field(prev_hash)
+        || access_log_field(entry.seq::text)
+        || access_log_field(entry.id::text)
+        || access_log_field(to_char(entry.created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS.US"Z"'))
+        || access_log_field(entry.npub)
+        || access_log_field(entry.door_id::text)
+        || access_log_field(entry.result)
+        || access_log_field(entry.reason)
+        || access_log_field(entry.reason_code)
+        || access_log_field(entry.triggered_by)
+        || access_log_field(array_to_json(entry.granted_permissions)::text)
+        || access_log_field(entry.credential)
+        || access_log_field(entry.site_id::text),
+        'UTF8')), 'hex');
+$$ LANGUAGE sql STABLE;
+
+-- Existing entries are chained oldest first
+DO $$
+DECLARE
+    entry access_logs;
+    last_hash TEXT;
+BEGIN
+    FOR entry IN SELECT * FROM access_logs ORDER BY created_at, id LOOP
+        entry.seq := nextval('access_logs_seq');
+        entry.prev_hash := last_hash;
+        entry.hash := access_log_hash(last_hash, entry);
+        UPDATE access_logs SET seq = entry.seq, prev_hash = entry.prev_hash, hash = entry.hash
+        WHERE id = entry.id;
+        last_hash := entry.hash;
+    END LOOP;
+END;
+$$;
+
+ALTER TABLE access_logs ALTER COLUMN seq SET NOT NULL;
+ALTER TABLE access_logs ALTER COLUMN hash SET NOT NULL;
+CREATE UNIQUE INDEX IF NOT EXISTS idx_access_logs_seq ON access_logs(seq);
+
+-- New entries are chained one at a time, whichever service or tool writes them, so each links
+-- to the entry committed before it. seq is taken under the lock, so it follows that order too.
+CREATE OR REPLACE FUNCTION chain_access_log() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_advisory_xact_lock(hashtext('access_logs_chain'));
+    NEW.seq := nextval('access_logs_seq');
+    NEW.prev_hash := (SELECT hash FROM access_logs ORDER BY seq DESC LIMIT 1);
+    NEW.hash := access_log_hash(NEW.prev_hash, NEW);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER access_logs_chain
+    BEFORE INSERT ON access_logs
+    FOR EACH ROW EXECUTE FUNCTION chain_access_log();
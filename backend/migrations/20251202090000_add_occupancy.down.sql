@@ -0,0 +1,6 @@
+DROP TRIGGER IF EXISTS access_logs_count_occupancy ON access_logs;
+DROP FUNCTION IF EXISTS count_occupancy();
+ALTER TABLE sites DROP COLUMN IF EXISTS occupancy_updated_at;
+ALTER TABLE sites DROP COLUMN IF EXISTS max_occupancy;
+ALTER TABLE sites DROP COLUMN IF EXISTS occupancy;
+ALTER TABLE doors DROP COLUMN IF EXISTS direction;
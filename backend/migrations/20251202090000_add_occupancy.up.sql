@@ -0,0 +1,28 @@
+-- Occupancy of each site: granted entries through its entry doors count people in, those
+-- through its exit doors count them out. Doors without a direction are not counted.
+ALTER TABLE doors ADD COLUMN IF NOT EXISTS direction TEXT
+    CHECK (direction IN ('entry', 'exit'));
+ALTER TABLE sites ADD COLUMN IF NOT EXISTS occupancy INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE sites ADD COLUMN IF NOT EXISTS max_occupancy INTEGER CHECK (max_occupancy > 0);
+ALTER TABLE sites ADD COLUMN IF NOT EXISTS occupancy_updated_at TIMESTAMP WITH TIME ZONE;
+
+-- Only entries of a key, card or visitor count; a manual unlock lets nobody in in particular.
+-- The count never goes below zero, so people who left without badging out are not owed.
+CREATE OR REPLACE FUNCTION count_occupancy() RETURNS trigger AS $$
+BEGIN
+    IF NEW.result <> 'granted' OR NEW.npub IS NULL THEN
+        RETURN NULL;
+    END IF;
+
+    UPDATE sites s
+    SET occupancy = GREATEST(s.occupancy + CASE d.direction WHEN 'entry' THEN 1 ELSE -1 END, 0),
+        occupancy_updated_at = NEW.created_at
+    FROM doors d
+    WHERE d.controller_door_id = NEW.door_id AND d.direction IS NOT NULL AND s.id = d.site_id;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER access_logs_count_occupancy
+    AFTER INSERT ON access_logs
+    FOR EACH ROW EXECUTE FUNCTION count_occupancy();
@@ -0,0 +1,5 @@
+-- Days the daily activity digest went out for, so web services sharing the database send it once
+CREATE TABLE IF NOT EXISTS digest_runs (
+    day DATE PRIMARY KEY,
+    sent_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
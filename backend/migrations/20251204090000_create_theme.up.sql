@@ -0,0 +1,15 @@
+-- Branding of the admin UI, kiosk and visitor pages, a single row. Empty columns keep the
+-- built-in look.
+CREATE TABLE IF NOT EXISTS theme (
+    id BOOLEAN PRIMARY KEY DEFAULT true CHECK (id),
+    site_name TEXT,
+    primary_color TEXT,
+    background_color TEXT,
+    text_color TEXT,
+    logo TEXT,          -- name in the photo store
+    logo_type TEXT,
+    updated_by TEXT,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO theme (id) VALUES (true) ON CONFLICT DO NOTHING;
@@ -0,0 +1,3 @@
+-- Give the door a new random handshake URL after every handshake, so a photographed QR code
+-- cannot be used again
+ALTER TABLE doors ADD COLUMN IF NOT EXISTS handshake_single_use BOOLEAN NOT NULL DEFAULT FALSE;
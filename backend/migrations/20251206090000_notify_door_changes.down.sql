@@ -0,0 +1,4 @@
+DROP TRIGGER IF EXISTS lockdown_notify_change ON lockdown;
+DROP TRIGGER IF EXISTS sites_notify_change ON sites;
+DROP TRIGGER IF EXISTS doors_notify_change ON doors;
+DROP FUNCTION IF EXISTS notify_door_change();
@@ -0,0 +1,22 @@
+-- Announce every change to the doors, the sites and the lockdown switch on the
+-- portal_access_doors channel, with the table as payload. The door service keeps its door, the
+-- door's site occupancy and the lockdown in memory and reads them again when told.
+CREATE OR REPLACE FUNCTION notify_door_change() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('portal_access_doors', TG_TABLE_NAME);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Fired once per statement: every door is read again whichever rows changed
+CREATE TRIGGER doors_notify_change
+    AFTER INSERT OR UPDATE OR DELETE ON doors
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_door_change();
+
+CREATE TRIGGER sites_notify_change
+    AFTER INSERT OR UPDATE OR DELETE ON sites
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_door_change();
+
+CREATE TRIGGER lockdown_notify_change
+    AFTER INSERT OR UPDATE OR DELETE ON lockdown
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_door_change();
@@ -0,0 +1,3 @@
+-- Time step of the last authentication code each user signed in with; codes of that step or
+-- earlier are refused, so a code cannot be replayed while it is still valid
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_last_step BIGINT;
@@ -0,0 +1,253 @@
+//! The handshake pipeline as a trail of typed events: handshake received → key resolved →
+//! decision → authentication → payment → actuation → logged. Each stage the handshake loop
+//! goes through is stored in `events` under the handshake's attempt id, next to the access log
+//! entry it ended in. A card at a badge reader starts with card presented instead and skips
+//! authentication and payment.
+//!
+//! The key resolved stage keeps everything the decision was made from (the door, the emergency
+//! override, the lockdown, the key's rules and guest pass, the time), and `policy::evaluate`
+//! uses nothing else, so a contested decision can be replayed from its trail: `replay` decides
+//! again from the recorded resolution and tells whether today's rules agree with the recorded
+//! decision.
+
+use crate::database::helpers::{
+    DenyReason, Door, GuestPass, KeyRules, StoredEvent, get_events_of_access_log, insert_event,
+};
+use crate::door_backend::Controller;
+use crate::emergency::OverrideMode;
+use crate::policy;
+
+use chrono::{DateTime, Utc};
+use rocket::tokio::{self, sync::mpsc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use tracing::{error, warn};
+use uuid::Uuid;
+
+// Events waiting to be written before new ones are dropped
+const QUEUE_SIZE: usize = 1024;
+
+/// One handshake, or one card presented at a badge reader, going through the pipeline.
+pub struct Attempt {
+    pub id: Uuid,
+    pub npub: String,
+    /// The card number, for a card.
+    pub credential: Option<String>,
+}
+
+impl Attempt {
+    pub fn new(npub: String) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            npub,
+            credential: None,
+        }
+    }
+
+    /// A card standing in for the key `npub`.
+    pub fn card(npub: String, card_number: String) -> Self {
+        Self {
+            credential: Some(card_number),
+            ..Self::new(npub)
+        }
+    }
+}
+
+/// What the decision stage saw, as it was when the handshake came in, for `policy::evaluate`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Resolution {
+    pub door: Option<Door>,
+    pub override_mode: Option<OverrideMode>,
+    pub site_lockdown: bool,
+    /// Only looked up for entry doors of sites with an occupancy limit.
+    #[serde(default)]
+    pub occupancy: Option<Occupancy>,
+    /// `None` for unknown keys.
+    pub rules: Option<KeyRules>,
+    /// Only looked up for keys the rules do not let in.
+    pub guest_pass: Option<GuestPass>,
+    pub at: DateTime<Utc>,
+    /// The door's timezone, or the site's, for key schedules.
+    pub timezone: String,
+}
+
+/// How many people the door's site holds, and how many it may.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Occupancy {
+    pub count: i32,
+    pub max: i32,
+}
+
+/// Outcome of the decision stage: go on to Portal authentication, on the key's own grant or on
+/// a guest pass that is only used up once authentication succeeds, or stop with a reason.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum Decision {
+    Allow { price_sats: Option<i64> },
+    Guest,
+    Deny { reason: DenyReason },
+}
+
+/// One stage of a handshake, stored as the `data` of its `events` row.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "stage", rename_all = "snake_case")]
+pub enum PipelineEvent {
+    HandshakeReceived,
+    CardPresented {
+        card_number: String,
+        /// Whether the card belongs to a key.
+        known: bool,
+    },
+    KeyResolved {
+        resolution: Box<Resolution>,
+    },
+    Decided {
+        decision: Decision,
+    },
+    Authenticated {
+        approved: bool,
+        granted_permissions: Vec<String>,
+        detail: Option<String>,
+    },
+    Paid {
+        price_sats: i64,
+        paid: bool,
+        detail: Option<String>,
+    },
+    Actuated {
+        unlocked: bool,
+        retries: u32,
+        controller: Controller,
+        error: Option<String>,
+    },
+    Logged {
+        access_log_id: Option<Uuid>,
+        result: String,
+        reason_code: Option<DenyReason>,
+        reason: Option<String>,
+    },
+}
+
+impl PipelineEvent {
+    pub fn stage(&self) -> &'static str {
+        match self {
+            PipelineEvent::HandshakeReceived => "handshake_received",
+            PipelineEvent::CardPresented { .. } => "card_presented",
+            PipelineEvent::KeyResolved { .. } => "key_resolved",
+            PipelineEvent::Decided { .. } => "decided",
+            PipelineEvent::Authenticated { .. } => "authenticated",
+            PipelineEvent::Paid { .. } => "paid",
+            PipelineEvent::Actuated { .. } => "actuated",
+            PipelineEvent::Logged { .. } => "logged",
+        }
+    }
+}
+
+// An event on its way to the writer
+struct QueuedEvent {
+    attempt_id: Uuid,
+    npub: String,
+    door_id: i32,
+    event: PipelineEvent,
+}
+
+/// Writes pipeline events to `events` in the background, in the order they were emitted, so
+/// storing them never holds up a door. Shared between the handshake loops; cloning is cheap.
+#[derive(Clone)]
+pub struct EventLog {
+    queue: mpsc::Sender<QueuedEvent>,
+}
+
+impl EventLog {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        let (queue, mut events) = mpsc::channel::<QueuedEvent>(QUEUE_SIZE);
+        tokio::spawn(async move {
+            while let Some(queued) = events.recv().await {
+                let access_log_id = match &queued.event {
+                    PipelineEvent::Logged { access_log_id, .. } => *access_log_id,
+                    _ => None,
+                };
+                let data = match serde_json::to_value(&queued.event) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        error!(error = %e, "Failed to serialize pipeline event");
+                        continue;
+                    }
+                };
+                if let Err(e) = insert_event(
+                    &pool,
+                    queued.attempt_id,
+                    &queued.npub,
+                    queued.door_id,
+                    queued.event.stage(),
+                    &data,
+                    access_log_id,
+                )
+                .await
+                {
+                    error!(error = ?e, "Failed to record pipeline event");
+                }
+            }
+        });
+
+        Self { queue }
+    }
+
+    pub fn emit(&self, attempt: &Attempt, door_id: i32, event: PipelineEvent) {
+        let queued = QueuedEvent {
+            attempt_id: attempt.id,
+            npub: attempt.npub.clone(),
+            door_id,
+            event,
+        };
+        if let Err(e) = self.queue.try_send(queued) {
+            warn!(
+                stage = e.into_inner().event.stage(),
+                "Event queue full, dropping event"
+            );
+        }
+    }
+}
+
+/// The decision of a recorded handshake made again from its recorded resolution.
+#[derive(Serialize)]
+pub struct Replay {
+    pub recorded: Decision,
+    pub replayed: Decision,
+    pub matches: bool,
+}
+
+/// The trail of the handshake behind an access log entry, with its decision replayed. Empty,
+/// without a replay, for entries that did not come from a handshake.
+#[derive(Serialize)]
+pub struct Trail {
+    pub events: Vec<StoredEvent>,
+    pub replay: Option<Replay>,
+}
+
+pub async fn replay(pool: &Pool<Postgres>, access_log_id: Uuid) -> Result<Trail, sqlx::Error> {
+    let events = get_events_of_access_log(pool, access_log_id).await?;
+
+    let recorded = |stage: &str| {
+        events
+            .iter()
+            .filter(|event| event.stage == stage)
+            .find_map(|event| serde_json::from_value::<PipelineEvent>(event.data.clone()).ok())
+    };
+    let replay = match (recorded("key_resolved"), recorded("decided")) {
+        (
+            Some(PipelineEvent::KeyResolved { resolution }),
+            Some(PipelineEvent::Decided { decision }),
+        ) => {
+            let replayed = policy::evaluate(&resolution);
+            Some(Replay {
+                matches: replayed == decision,
+                recorded: decision,
+                replayed,
+            })
+        }
+        _ => None,
+    };
+
+    Ok(Trail { events, replay })
+}
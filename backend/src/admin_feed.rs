@@ -0,0 +1,124 @@
+use crate::database::helpers::DoorbellRing;
+
+use chrono::{DateTime, Utc};
+use rocket::tokio::sync::broadcast;
+use uuid::Uuid;
+
+// Events a slow subscriber may fall behind by before it skips ahead
+const CHANNEL_CAPACITY: usize = 64;
+
+/// Something the control-room view should show as it happens. Sent on `/ws/admin` as JSON
+/// tagged with `type`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AdminEvent {
+    /// `opened`, `closed` and `held_open` come from the door sensor, `unlocked` and `locked`
+    /// from an admin driving the door, `auto_unlocked` and `auto_locked` from its schedule.
+    Door {
+        door_id: i32,
+        name: String,
+        state: String,
+        at: DateTime<Utc>,
+    },
+    /// A visitor whose key may not open the door is `ringing` until an admin has `approved`
+    /// or `denied` them through `/doorbell/<ring_id>/approve` or `/deny`, or `expires_at`
+    /// passes.
+    Doorbell {
+        ring_id: Uuid,
+        door_id: i32,
+        door_name: Option<String>,
+        npub: String,
+        name: Option<String>,
+        picture_url: Option<String>,
+        state: String,
+        answered_by: Option<String>,
+        expires_at: DateTime<Utc>,
+        at: DateTime<Utc>,
+        site_id: Option<Uuid>,
+    },
+    /// A background task crashed and is being restarted.
+    Task {
+        task: String,
+        status: String,
+        detail: Option<String>,
+        at: DateTime<Utc>,
+    },
+}
+
+impl AdminEvent {
+    pub fn door(door_id: i32, name: &str, state: &str) -> Self {
+        AdminEvent::Door {
+            door_id,
+            name: name.to_string(),
+            state: state.to_string(),
+            at: Utc::now(),
+        }
+    }
+
+    /// `name` and `picture_url` fall back to the ring's key, if the visitor has one.
+    pub fn doorbell(
+        ring: &DoorbellRing,
+        name: Option<String>,
+        picture_url: Option<String>,
+        state: &str,
+        answered_by: Option<&str>,
+    ) -> Self {
+        AdminEvent::Doorbell {
+            ring_id: ring.id,
+            door_id: ring.door_id,
+            door_name: ring.door_name.clone(),
+            npub: ring.npub.clone(),
+            name: ring
+                .profile_name
+                .clone()
+                .or_else(|| ring.display_name.clone())
+                .or(name),
+            picture_url: ring.picture_url.clone().or(picture_url),
+            state: state.to_string(),
+            answered_by: answered_by.map(str::to_string),
+            expires_at: ring.expires_at,
+            at: Utc::now(),
+            site_id: ring.site_id,
+        }
+    }
+
+    pub fn task(task: impl Into<String>, status: &str, detail: Option<String>) -> Self {
+        AdminEvent::Task {
+            task: task.into(),
+            status: status.to_string(),
+            detail,
+            at: Utc::now(),
+        }
+    }
+
+    /// Whether admins working in `site` are shown the event. Only doorbell rings are kept to
+    /// their site; door and task events go to everyone.
+    pub fn in_site(&self, site: Option<Uuid>) -> bool {
+        match self {
+            AdminEvent::Doorbell { site_id, .. } => site.is_none() || *site_id == site,
+            _ => true,
+        }
+    }
+}
+
+/// Fans door, doorbell and background task events out to the `/ws/admin` subscribers. New access log
+/// entries go through `LogFeed`, which the channel subscribes to as well.
+#[derive(Clone)]
+pub struct AdminFeed(broadcast::Sender<AdminEvent>);
+
+impl Default for AdminFeed {
+    fn default() -> Self {
+        Self(broadcast::channel(CHANNEL_CAPACITY).0)
+    }
+}
+
+impl AdminFeed {
+    /// Never blocks; an event published while nobody is watching is simply dropped.
+    pub fn publish(&self, event: AdminEvent) {
+        let _ = self.0.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<AdminEvent> {
+        self.0.subscribe()
+    }
+}
@@ -0,0 +1,401 @@
+use crate::config::AlertsConfig;
+use crate::database::helpers::{AlertChannel, get_alert_channels_for};
+use crate::door_backend::DoorBackend;
+use crate::mailer::Mailer;
+
+use chrono::{DateTime, Utc};
+use rocket::tokio::{self, sync::mpsc};
+use sqlx::{Pool, Postgres};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+// Alerts waiting for delivery before new ones are dropped
+const QUEUE_SIZE: usize = 64;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const TELEGRAM_API: &str = "https://api.telegram.org";
+
+/// What an alert is about. Admins subscribe their channels to any of these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlertKind {
+    RepeatedDeclines,
+    HeldOpen,
+    RelockFailed,
+    KeyDisabled,
+    BackendUnreachable,
+    /// Raised by the anomaly detector, see `anomalies`.
+    DisabledKeyAttempts,
+    UnusualHour,
+    /// Not an alert: the daily activity digest, see `digest`.
+    DailyDigest,
+    /// Sent from the Alerts page to check a channel; nothing subscribes to it.
+    Test,
+}
+
+impl AlertKind {
+    pub const ALL: [AlertKind; 8] = [
+        AlertKind::RepeatedDeclines,
+        AlertKind::HeldOpen,
+        AlertKind::RelockFailed,
+        AlertKind::KeyDisabled,
+        AlertKind::BackendUnreachable,
+        AlertKind::DisabledKeyAttempts,
+        AlertKind::UnusualHour,
+        AlertKind::DailyDigest,
+    ];
+
+    /// Stored in `alert_channels.triggers`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AlertKind::RepeatedDeclines => "auth.repeated_declines",
+            AlertKind::HeldOpen => "door.held_open",
+            AlertKind::RelockFailed => "door.relock_failed",
+            AlertKind::KeyDisabled => "key.disabled",
+            AlertKind::BackendUnreachable => "door_backend.unreachable",
+            AlertKind::DisabledKeyAttempts => "anomaly.disabled_key_attempts",
+            AlertKind::UnusualHour => "anomaly.unusual_hour",
+            AlertKind::DailyDigest => "digest.daily",
+            AlertKind::Test => "test",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            AlertKind::RepeatedDeclines => "Repeated declined authentications",
+            AlertKind::HeldOpen => "Door held open",
+            AlertKind::RelockFailed => "Door did not relock",
+            AlertKind::KeyDisabled => "Key automatically disabled",
+            AlertKind::BackendUnreachable => "Door backend unreachable",
+            AlertKind::DisabledKeyAttempts => "Disabled key keeps trying",
+            AlertKind::UnusualHour => "Unlock at an unusual hour",
+            AlertKind::DailyDigest => "Daily activity digest",
+            AlertKind::Test => "Test alert",
+        }
+    }
+
+    pub fn parse(trigger: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.as_str() == trigger)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub kind: AlertKind,
+    pub door_id: Option<i32>,
+    pub detail: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Alert {
+    pub fn new(kind: AlertKind, door_id: Option<i32>, detail: impl Into<String>) -> Self {
+        Self {
+            kind,
+            door_id,
+            detail: detail.into(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn subject(&self) -> String {
+        match self.door_id {
+            Some(door_id) => format!("[Portal Access] {} (door {})", self.kind.label(), door_id),
+            None => format!("[Portal Access] {}", self.kind.label()),
+        }
+    }
+
+    fn body(&self) -> String {
+        format!(
+            "{}\n\n{}\n\nAt {}",
+            self.kind.label(),
+            self.detail,
+            self.timestamp.to_rfc3339()
+        )
+    }
+}
+
+/// Anything else sent to alert channels, such as the daily digest. Emails carry `html` with
+/// `text` as the plain alternative; Telegram chats get `text`.
+pub struct Message {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+/// One way of reaching an admin. `target` is the channel's `alert_channels.target`.
+#[rocket::async_trait]
+trait Notifier: Send + Sync {
+    async fn send(&self, target: &str, alert: &Alert) -> Result<(), String>;
+    async fn send_message(&self, target: &str, message: &Message) -> Result<(), String>;
+}
+
+struct EmailNotifier {
+    mailer: Mailer,
+}
+
+#[rocket::async_trait]
+impl Notifier for EmailNotifier {
+    async fn send(&self, target: &str, alert: &Alert) -> Result<(), String> {
+        self.mailer
+            .send(target, &alert.subject(), alert.body(), None)
+            .await
+    }
+
+    async fn send_message(&self, target: &str, message: &Message) -> Result<(), String> {
+        self.mailer
+            .send_html(
+                target,
+                &message.subject,
+                message.text.clone(),
+                message.html.clone(),
+            )
+            .await
+    }
+}
+
+struct TelegramNotifier {
+    client: reqwest::Client,
+    bot_token: String,
+}
+
+impl TelegramNotifier {
+    async fn post(&self, chat_id: &str, text: String) -> Result<(), String> {
+        let response = self
+            .client
+            .post(format!(
+                "{}/bot{}/sendMessage",
+                TELEGRAM_API, self.bot_token
+            ))
+            .header("Content-Type", "application/json")
+            .body(
+                serde_json::json!({
+                    "chat_id": chat_id,
+                    "text": text,
+                })
+                .to_string(),
+            )
+            .send()
+            .await
+            // The URL carries the bot token, keep it out of the logs
+            .map_err(|e| e.without_url().to_string())?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("Telegram answered {}", response.status()))
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Notifier for TelegramNotifier {
+    async fn send(&self, target: &str, alert: &Alert) -> Result<(), String> {
+        self.post(target, format!("{}\n\n{}", alert.subject(), alert.detail))
+            .await
+    }
+
+    async fn send_message(&self, target: &str, message: &Message) -> Result<(), String> {
+        self.post(target, format!("{}\n\n{}", message.subject, message.text))
+            .await
+    }
+}
+
+/// Sends security alerts to the email addresses and Telegram chats admins registered on the
+/// Alerts page, each only for the triggers it is subscribed to.
+///
+/// Like webhooks, alerts go through a bounded queue so raising one never blocks the unlock
+/// path. Declined authentications are counted per key, and an alert is raised once a key
+/// collects `alerts.declined_threshold` of them within `alerts.declined_window_secs`.
+#[derive(Clone)]
+pub struct Alerter {
+    queue: mpsc::Sender<Alert>,
+    notifiers: Arc<HashMap<&'static str, Box<dyn Notifier>>>,
+    mailer: Option<Mailer>,
+    declines: Arc<Mutex<HashMap<String, VecDeque<Instant>>>>,
+    declined_threshold: usize,
+    declined_window: Duration,
+}
+
+impl Alerter {
+    pub fn new(pool: Pool<Postgres>, config: &AlertsConfig) -> Result<Self, String> {
+        let mut notifiers: HashMap<&'static str, Box<dyn Notifier>> = HashMap::new();
+
+        let mailer = Mailer::new(config)?;
+        if let Some(mailer) = &mailer {
+            notifiers.insert(
+                "email",
+                Box::new(EmailNotifier {
+                    mailer: mailer.clone(),
+                }),
+            );
+        }
+
+        if let Some(bot_token) = &config.telegram_bot_token {
+            let client = reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .map_err(|e| format!("Failed to build Telegram HTTP client: {}", e))?;
+            notifiers.insert(
+                "telegram",
+                Box::new(TelegramNotifier {
+                    client,
+                    bot_token: bot_token.clone(),
+                }),
+            );
+        }
+
+        let notifiers = Arc::new(notifiers);
+        let (queue, alerts) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(run_worker(pool, notifiers.clone(), alerts));
+
+        Ok(Self {
+            queue,
+            notifiers,
+            mailer,
+            declines: Arc::new(Mutex::new(HashMap::new())),
+            declined_threshold: config.declined_threshold.max(1),
+            declined_window: Duration::from_secs(config.declined_window_secs),
+        })
+    }
+
+    /// Whether alerts can be delivered through `kind` ("email" or "telegram").
+    pub fn supports(&self, kind: &str) -> bool {
+        self.notifiers.contains_key(kind)
+    }
+
+    /// The SMTP server alerts are emailed through, for other mail to admins or visitors.
+    pub fn mailer(&self) -> Option<&Mailer> {
+        self.mailer.as_ref()
+    }
+
+    /// Queue an alert for delivery. Never waits: when the queue is full the alert is dropped.
+    pub fn notify(&self, alert: Alert) {
+        warn!(kind = alert.kind.as_str(), detail = %alert.detail, "Raising alert");
+        if let Err(e) = self.queue.try_send(alert) {
+            warn!(error = %e, "Alert queue full or closed, dropping alert");
+        }
+    }
+
+    /// Counts a declined authentication, raising an alert when the key reaches the threshold.
+    /// The count starts over after each alert.
+    pub fn declined(&self, npub: &str, door_id: Option<i32>) {
+        let now = Instant::now();
+        let reached = {
+            let mut declines = self.declines.lock().expect("Decline tracker lock poisoned");
+            declines.retain(|_, times| {
+                times.retain(|at| now.duration_since(*at) < self.declined_window);
+                !times.is_empty()
+            });
+
+            let times = declines.entry(npub.to_string()).or_default();
+            times.push_back(now);
+            if times.len() >= self.declined_threshold {
+                declines.remove(npub);
+                true
+            } else {
+                false
+            }
+        };
+
+        if reached {
+            self.notify(Alert::new(
+                AlertKind::RepeatedDeclines,
+                door_id,
+                format!(
+                    "{} declined {} authentications within {} seconds",
+                    npub,
+                    self.declined_threshold,
+                    self.declined_window.as_secs()
+                ),
+            ));
+        }
+    }
+
+    /// Sends a sample alert to one channel right away, so an admin can check it works.
+    pub async fn send_test(&self, channel: &AlertChannel) -> Result<(), String> {
+        let notifier = self
+            .notifiers
+            .get(channel.kind.as_str())
+            .ok_or_else(|| format!("{} alerts are not configured", channel.kind))?;
+        let alert = Alert::new(
+            AlertKind::Test,
+            None,
+            "This channel receives alerts from Portal Access Control.",
+        );
+
+        notifier.send(&channel.target, &alert).await
+    }
+
+    /// Sends `message` to one channel, see `digest`.
+    pub async fn send_message(
+        &self,
+        channel: &AlertChannel,
+        message: &Message,
+    ) -> Result<(), String> {
+        let notifier = self
+            .notifiers
+            .get(channel.kind.as_str())
+            .ok_or_else(|| format!("{} alerts are not configured", channel.kind))?;
+
+        notifier.send_message(&channel.target, message).await
+    }
+
+    /// Checks the door backend every `alerts.backend_check_secs` and raises an alert when it
+    /// stops answering. `backend_check_secs = 0` turns the check off.
+    pub fn spawn_backend_monitor(&self, door: Arc<dyn DoorBackend>, config: &AlertsConfig) {
+        if config.backend_check_secs == 0 {
+            return;
+        }
+
+        let alerter = self.clone();
+        let interval = Duration::from_secs(config.backend_check_secs);
+        tokio::spawn(async move {
+            let mut reachable = true;
+            loop {
+                tokio::time::sleep(interval).await;
+                match door.health().await {
+                    Ok(()) if !reachable => {
+                        info!("Door backend reachable again");
+                        reachable = true;
+                    }
+                    Ok(()) => {}
+                    Err(e) if reachable => {
+                        reachable = false;
+                        alerter.notify(Alert::new(
+                            AlertKind::BackendUnreachable,
+                            None,
+                            format!("The door backend cannot be reached: {}", e),
+                        ));
+                    }
+                    Err(_) => {}
+                }
+            }
+        });
+    }
+}
+
+async fn run_worker(
+    pool: Pool<Postgres>,
+    notifiers: Arc<HashMap<&'static str, Box<dyn Notifier>>>,
+    mut alerts: mpsc::Receiver<Alert>,
+) {
+    while let Some(alert) = alerts.recv().await {
+        let channels = match get_alert_channels_for(&pool, alert.kind.as_str()).await {
+            Ok(channels) => channels,
+            Err(e) => {
+                error!(error = ?e, "Failed to load alert channels");
+                continue;
+            }
+        };
+
+        for channel in channels {
+            let Some(notifier) = notifiers.get(channel.kind.as_str()) else {
+                warn!(channel = %channel.id, kind = %channel.kind, "Alert channel kind is not configured, skipping");
+                continue;
+            };
+            if let Err(e) = notifier.send(&channel.target, &alert).await {
+                error!(channel = %channel.id, error = %e, "Failed to send alert");
+            }
+        }
+    }
+}
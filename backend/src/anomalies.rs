@@ -0,0 +1,148 @@
+//! Anomaly detection over the access log. Every `alerts.anomaly_check_secs` the detector looks
+//! for access patterns worth a second look, stores each one in `anomalies` for the dashboard
+//! and raises it as an alert:
+//!
+//! - `anomaly.disabled_key_attempts`: a disabled key was refused
+//!   `alerts.disabled_attempts_threshold` times within `alerts.disabled_attempts_window_secs`
+//! - `anomaly.unusual_hour`: a key unlocked a door at an hour of the day, site time, it did not
+//!   unlock at once in the last `alerts.unusual_hour_history_days`, out of at least
+//!   `alerts.unusual_hour_min_history` unlocks
+
+use crate::alerts::{Alert, AlertKind, Alerter};
+use crate::config::AlertsConfig;
+use crate::database::helpers::{
+    get_disabled_key_attempts, get_unusual_hour_unlocks, insert_anomaly,
+};
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use rocket::tokio;
+use sqlx::{Pool, Postgres};
+use std::time::Duration;
+use tracing::error;
+use uuid::Uuid;
+
+#[derive(Clone, Copy)]
+struct Thresholds {
+    disabled_attempts: i64,
+    disabled_window_secs: i64,
+    history_days: i64,
+    min_history: i64,
+}
+
+/// Runs the anomaly checks in the background. `alerts.anomaly_check_secs = 0` turns them off.
+/// Unlocks are only checked from the moment the detector starts, so a restart never flags old
+/// entries again.
+pub fn spawn_detector(pool: Pool<Postgres>, alerts: Alerter, config: &AlertsConfig, timezone: Tz) {
+    if config.anomaly_check_secs == 0 {
+        return;
+    }
+
+    let interval = Duration::from_secs(config.anomaly_check_secs);
+    let thresholds = Thresholds {
+        disabled_attempts: config.disabled_attempts_threshold.into(),
+        disabled_window_secs: config.disabled_attempts_window_secs.into(),
+        history_days: config.unusual_hour_history_days.into(),
+        min_history: config.unusual_hour_min_history.into(),
+    };
+    tokio::spawn(async move {
+        let mut checked_until = Utc::now();
+        loop {
+            tokio::time::sleep(interval).await;
+            let until = Utc::now();
+            if let Err(e) = check(&pool, &alerts, thresholds, checked_until, until, timezone).await
+            {
+                // The unlocks are checked again on the next run
+                error!(error = ?e, "Anomaly check failed");
+                continue;
+            }
+            checked_until = until;
+        }
+    });
+}
+
+async fn check(
+    pool: &Pool<Postgres>,
+    alerts: &Alerter,
+    thresholds: Thresholds,
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+    timezone: Tz,
+) -> Result<(), sqlx::Error> {
+    let kind = AlertKind::DisabledKeyAttempts;
+    for key in get_disabled_key_attempts(
+        pool,
+        kind.as_str(),
+        thresholds.disabled_attempts,
+        thresholds.disabled_window_secs,
+    )
+    .await?
+    {
+        let detail = format!(
+            "Disabled key {} tried to get in {} times within {} seconds",
+            key.npub, key.attempts, thresholds.disabled_window_secs
+        );
+        flag(
+            pool,
+            alerts,
+            kind,
+            &key.npub,
+            key.door_id,
+            key.site_id,
+            detail,
+        )
+        .await?;
+    }
+
+    let kind = AlertKind::UnusualHour;
+    for unlock in get_unusual_hour_unlocks(
+        pool,
+        since,
+        until,
+        thresholds.history_days,
+        thresholds.min_history,
+        timezone.name(),
+    )
+    .await?
+    {
+        let detail = format!(
+            "{} unlocked at {} between {}:00 and {}:59, an hour it did not use in its last {} unlocks over {} days",
+            unlock.npub,
+            unlock
+                .created_at
+                .with_timezone(&timezone)
+                .format("%Y-%m-%d %H:%M"),
+            unlock.hour,
+            unlock.hour,
+            unlock.history,
+            thresholds.history_days
+        );
+        flag(
+            pool,
+            alerts,
+            kind,
+            &unlock.npub,
+            unlock.door_id,
+            unlock.site_id,
+            detail,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+// Stores the anomaly first, so the next run does not flag it again, then raises the alert
+async fn flag(
+    pool: &Pool<Postgres>,
+    alerts: &Alerter,
+    kind: AlertKind,
+    npub: &str,
+    door_id: Option<i32>,
+    site_id: Option<Uuid>,
+    detail: String,
+) -> Result<(), sqlx::Error> {
+    insert_anomaly(pool, kind.as_str(), npub, door_id, site_id, &detail).await?;
+    alerts.notify(Alert::new(kind, door_id, detail));
+    Ok(())
+}
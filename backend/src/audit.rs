@@ -0,0 +1,43 @@
+use crate::client_ip::ClientIp;
+use crate::database::helpers::insert_audit_entry;
+
+use rocket::{
+    State,
+    http::Status,
+    request::{FromRequest, Outcome, Request},
+};
+use sqlx::{Pool, Postgres};
+use tracing::error;
+
+/// Request guard for handlers that change something: records who did what, and from which
+/// address, in the `admin_audit` table.
+pub struct Audit {
+    pool: Pool<Postgres>,
+    ip: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Audit {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match req.guard::<&State<Pool<Postgres>>>().await {
+            Outcome::Success(pool) => Outcome::Success(Audit {
+                pool: pool.inner().clone(),
+                ip: ClientIp::of(req).map(|ip| ip.to_string()),
+            }),
+            _ => Outcome::Error((Status::InternalServerError, ())),
+        }
+    }
+}
+
+impl Audit {
+    /// Never fails the request it belongs to; an entry that cannot be stored is only logged.
+    pub async fn record(&self, actor: &str, action: &str, target: Option<&str>) {
+        if let Err(e) =
+            insert_audit_entry(&self.pool, actor, action, target, self.ip.as_deref()).await
+        {
+            error!(action, actor, error = ?e, "Failed to record audit entry");
+        }
+    }
+}
@@ -1,58 +1,278 @@
+use crate::client_ip::{AllowedClient, ClientNetworks};
+use crate::config::JwtConfig;
+use crate::controllers::negotiation::API_BASE;
+use crate::controllers::settings::PASSWORD_PATH;
+use crate::database::helpers::{DEFAULT_SITE, touch_api_token};
+use crate::request_log::record_subject;
+use crate::sessions::Sessions;
+
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
 use chrono::{Duration, Utc};
-use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, decode_header, encode,
+};
 use rocket::{
     State,
     http::{Cookie, CookieJar, Status},
     request::{FromRequest, Outcome, Request},
 };
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres};
+use std::marker::PhantomData;
+use std::str::FromStr;
+use std::sync::LazyLock;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+// Marks a bearer token as an API token rather than a session JWT
+const API_TOKEN_PREFIX: &str = "pat_";
 
-#[derive(Debug, Serialize, Deserialize)]
+/// What a user account may do. Viewers can read everything; only admins can change anything.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, rocket::FromFormField,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[field(value = "admin")]
+    Admin,
+    #[default]
+    #[field(value = "viewer")]
+    Viewer,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Viewer => "viewer",
+        }
+    }
+
+    /// What a session of this role may do, in the scopes API tokens are minted with.
+    pub fn scopes(&self) -> Vec<String> {
+        match self {
+            Role::Admin => Scope::granted(&Scope::ALL),
+            Role::Viewer => Scope::granted(&[
+                Scope::ReadLogs,
+                Scope::ReadKeys,
+                Scope::ReadMetrics,
+                Scope::Kiosk,
+            ]),
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role {
+            "admin" => Ok(Role::Admin),
+            "viewer" => Ok(Role::Viewer),
+            other => Err(format!("Unknown role: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
-    pub sub: String, // subject (user identifier)
-    pub exp: usize,  // expiration time
-    pub iat: usize,  // issued at
+    pub sub: String, // subject (username)
+    #[serde(default)]
+    pub role: Role, // tokens issued before roles existed fall back to viewer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<Uuid>, // session the token was issued for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site: Option<Uuid>, // site the holder works in, None for every site
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub password_expired: bool, // only the password may be changed, see `SessionPolicy`
+    #[serde(default)]
+    pub scopes: Vec<String>, // see `Scope`; empty in tokens issued before scopes, which use the role's
+    pub exp: usize, // expiration time
+    pub iat: usize, // issued at
 }
 
 impl Claims {
-    pub fn new(sub: String) -> Self {
+    pub fn new(
+        sub: String,
+        role: Role,
+        sid: Option<Uuid>,
+        site: Option<Uuid>,
+        lifetime: Duration,
+    ) -> Self {
         let now = Utc::now();
         Self {
             sub,
-            exp: (now + Duration::hours(24)).timestamp() as usize, // 24 hours
+            role,
+            sid,
+            site,
+            password_expired: false,
+            scopes: role.scopes(),
+            exp: (now + lifetime).timestamp() as usize,
             iat: now.timestamp() as usize,
         }
     }
-}
 
-pub struct JWTSecret(String);
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        if self.scopes.is_empty() {
+            return self.role.scopes().iter().any(|held| held == scope.as_str());
+        }
+        self.scopes.iter().any(|held| held == scope.as_str())
+    }
 
-impl JWTSecret {
-    pub fn new(secret: String) -> Self {
-        Self(secret)
+    /// The site new keys and doors go to: the holder's, or the default one for every site.
+    pub fn home_site(&self) -> Uuid {
+        self.site.unwrap_or(DEFAULT_SITE)
     }
+}
 
-    pub fn get_secret(&self) -> &str {
-        &self.0
+/// Signing/verification keys for session tokens.
+///
+/// Tokens are always signed with the current key and carry its `kid` in the
+/// header. Previous keys stay valid for verification only, so the secret can be
+/// rotated without logging everyone out at once.
+pub struct JWTKeys {
+    algorithm: Algorithm,
+    current_kid: String,
+    encoding_key: EncodingKey,
+    // Current key first, followed by the keys still accepted during rotation
+    decoding_keys: Vec<(String, DecodingKey)>,
+}
+
+impl JWTKeys {
+    /// Builds the key ring from the `[jwt]` settings:
+    ///
+    /// - `algorithm` (`JWT_ALGORITHM`): defaults to `HS256`
+    /// - `kid` (`JWT_KID`): id of the current key, defaults to `primary`
+    /// - `secret` (`JWT_SECRET`): current secret for HMAC algorithms
+    /// - `private_key_path` / `public_key_path` (`JWT_PRIVATE_KEY_PATH` / `JWT_PUBLIC_KEY_PATH`):
+    ///   PEM files for RSA/EC/EdDSA
+    /// - `previous_keys` (`JWT_PREVIOUS_KEYS`): comma separated `kid:secret` (HMAC) or
+    ///   `kid:/path/to/public.pem` (asymmetric) entries still accepted for verification
+    pub fn from_config(config: &JwtConfig) -> Result<Self, String> {
+        let algorithm = Algorithm::from_str(&config.algorithm)
+            .map_err(|_| format!("Unsupported JWT algorithm: {}", config.algorithm))?;
+        let current_kid = config.kid.clone();
+
+        let (encoding_key, current_decoding_key) = if is_hmac(algorithm) {
+            let secret = config
+                .secret
+                .as_deref()
+                .ok_or("jwt.secret (JWT_SECRET) must be set")?;
+            (
+                EncodingKey::from_secret(secret.as_bytes()),
+                DecodingKey::from_secret(secret.as_bytes()),
+            )
+        } else {
+            let private_pem = read_pem(
+                "jwt.private_key_path (JWT_PRIVATE_KEY_PATH)",
+                config.private_key_path.as_deref(),
+            )?;
+            let public_pem = read_pem(
+                "jwt.public_key_path (JWT_PUBLIC_KEY_PATH)",
+                config.public_key_path.as_deref(),
+            )?;
+            (
+                encoding_key_from_pem(algorithm, &private_pem)?,
+                decoding_key_from_pem(algorithm, &public_pem)?,
+            )
+        };
+
+        let mut decoding_keys = vec![(current_kid.clone(), current_decoding_key)];
+
+        if let Some(previous_keys) = &config.previous_keys {
+            for entry in previous_keys.split(',').filter(|e| !e.trim().is_empty()) {
+                let (kid, material) = entry
+                    .trim()
+                    .split_once(':')
+                    .ok_or_else(|| format!("Invalid jwt.previous_keys entry: {}", entry))?;
+
+                let decoding_key = if is_hmac(algorithm) {
+                    DecodingKey::from_secret(material.as_bytes())
+                } else {
+                    let pem = std::fs::read(material)
+                        .map_err(|e| format!("Failed to read {}: {}", material, e))?;
+                    decoding_key_from_pem(algorithm, &pem)?
+                };
+
+                decoding_keys.push((kid.to_string(), decoding_key));
+            }
+        }
+
+        Ok(Self {
+            algorithm,
+            current_kid,
+            encoding_key,
+            decoding_keys,
+        })
     }
 }
 
-pub fn create_token(claims: &Claims, secret: &str) -> Result<String, jsonwebtoken::errors::Error> {
-    encode(
-        &Header::default(),
-        claims,
-        &EncodingKey::from_secret(secret.as_ref()),
+fn is_hmac(algorithm: Algorithm) -> bool {
+    matches!(
+        algorithm,
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
     )
 }
 
-pub fn validate_token(token: &str, secret: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
-    let validation = Validation::new(Algorithm::HS256);
-    let token_data = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(secret.as_ref()),
-        &validation,
-    )?;
-    Ok(token_data.claims)
+fn read_pem(setting: &str, path: Option<&str>) -> Result<Vec<u8>, String> {
+    let path = path.ok_or_else(|| format!("{} must be set", setting))?;
+    std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))
+}
+
+fn encoding_key_from_pem(algorithm: Algorithm, pem: &[u8]) -> Result<EncodingKey, String> {
+    match algorithm {
+        Algorithm::ES256 | Algorithm::ES384 => EncodingKey::from_ec_pem(pem),
+        Algorithm::EdDSA => EncodingKey::from_ed_pem(pem),
+        _ => EncodingKey::from_rsa_pem(pem),
+    }
+    .map_err(|e| format!("Invalid private key: {}", e))
+}
+
+fn decoding_key_from_pem(algorithm: Algorithm, pem: &[u8]) -> Result<DecodingKey, String> {
+    match algorithm {
+        Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(pem),
+        Algorithm::EdDSA => DecodingKey::from_ed_pem(pem),
+        _ => DecodingKey::from_rsa_pem(pem),
+    }
+    .map_err(|e| format!("Invalid public key: {}", e))
+}
+
+pub fn create_token(
+    claims: &Claims,
+    keys: &JWTKeys,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let mut header = Header::new(keys.algorithm);
+    header.kid = Some(keys.current_kid.clone());
+
+    encode(&header, claims, &keys.encoding_key)
+}
+
+pub fn validate_token(token: &str, keys: &JWTKeys) -> Result<Claims, jsonwebtoken::errors::Error> {
+    let validation = Validation::new(keys.algorithm);
+    let header = decode_header(token)?;
+
+    // Use the key named by the token when we know it, otherwise try the current
+    // key first and fall back to the previous ones (tokens issued before kids).
+    let candidates: Vec<&DecodingKey> = match keys
+        .decoding_keys
+        .iter()
+        .find(|(kid, _)| header.kid.as_deref() == Some(kid.as_str()))
+    {
+        Some((_, key)) => vec![key],
+        None => keys.decoding_keys.iter().map(|(_, key)| key).collect(),
+    };
+
+    let mut last_error = jsonwebtoken::errors::ErrorKind::InvalidSignature.into();
+    for key in candidates {
+        match decode::<Claims>(token, key, &validation) {
+            Ok(token_data) => return Ok(token_data.claims),
+            Err(e) => last_error = e,
+        }
+    }
+
+    Err(last_error)
 }
 
 pub struct AuthenticatedUser(pub Claims);
@@ -62,39 +282,426 @@ impl<'r> FromRequest<'r> for AuthenticatedUser {
     type Error = ();
 
     async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let jwt_secret = req.guard::<&State<JWTSecret>>().await;
-        let jwt_secret = match jwt_secret {
-            Outcome::Success(secret) => secret,
+        if let Outcome::Error(e) = req.guard::<AllowedClient>().await {
+            return Outcome::Error(e);
+        }
+
+        let jwt_keys = req.guard::<&State<JWTKeys>>().await;
+        let jwt_keys = match jwt_keys {
+            Outcome::Success(keys) => keys,
             _ => return Outcome::Error((Status::InternalServerError, ())),
         };
 
-        // Try to get the token from cookies first
+        // API clients send a bearer token, browsers the auth cookie
+        let bearer = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "));
+
         let cookies = req.guard::<&CookieJar<'_>>().await;
         let cookies = match cookies {
             Outcome::Success(cookies) => cookies,
             _ => return Outcome::Error((Status::InternalServerError, ())),
         };
 
-        let token = cookies.get("auth_token").map(|cookie| cookie.value());
+        if let Some(token) = bearer {
+            return match validate_token(token, jwt_keys) {
+                Ok(claims) => signed_in(req, claims),
+                Err(_) => Outcome::Error((Status::Unauthorized, ())),
+            };
+        }
+
+        if let Some(claims) = cookies
+            .get("auth_token")
+            .and_then(|cookie| validate_token(cookie.value(), jwt_keys).ok())
+        {
+            return signed_in(req, claims);
+        }
+
+        // Browsers whose access token ran out are refreshed in place, once per request
+        let refreshed = req
+            .local_cache_async(async {
+                match req.guard::<Sessions<'_>>().await {
+                    Outcome::Success(sessions) => {
+                        sessions.refresh().await.map(|(claims, _)| claims)
+                    }
+                    _ => Err(Status::InternalServerError),
+                }
+            })
+            .await;
+
+        match refreshed {
+            Ok(claims) => signed_in(req, claims.clone()),
+            Err(status) => Outcome::Error((*status, ())),
+        }
+    }
+}
+
+/// Set on requests refused because the user's password has expired, for the 403 catcher.
+pub struct PasswordExpired(pub bool);
+
+// A user whose password has expired may only change it; anything else answers 403
+fn signed_in(req: &Request<'_>, claims: Claims) -> Outcome<AuthenticatedUser, ()> {
+    record_subject(req, &claims.sub);
+    let path = req.uri().path();
+    let changes_password = path
+        .as_str()
+        .strip_prefix(API_BASE)
+        .unwrap_or(path.as_str())
+        == PASSWORD_PATH;
+    if claims.password_expired && !changes_password {
+        req.local_cache(|| PasswordExpired(true));
+        return Outcome::Error((Status::Forbidden, ()));
+    }
+
+    Outcome::Success(AuthenticatedUser(claims))
+}
+
+/// Only lets admins through; anyone else who is logged in gets a 403.
+pub struct AdminUser(pub Claims);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminUser {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match req.guard::<AuthenticatedUser>().await {
+            Outcome::Success(user) if user.0.role == Role::Admin => {
+                Outcome::Success(AdminUser(user.0))
+            }
+            Outcome::Success(_) => Outcome::Error((Status::Forbidden, ())),
+            Outcome::Error(e) => Outcome::Error(e),
+            Outcome::Forward(f) => Outcome::Forward(f),
+        }
+    }
+}
+
+/// Only lets through admins not limited to one site, for what every site shares: groups,
+/// guest passes, webhooks, API tokens and the emergency override.
+pub struct AllSitesAdmin(pub Claims);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AllSitesAdmin {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match req.guard::<AdminUser>().await {
+            Outcome::Success(user) if user.0.site.is_none() => {
+                Outcome::Success(AllSitesAdmin(user.0))
+            }
+            Outcome::Success(_) => Outcome::Error((Status::Forbidden, ())),
+            Outcome::Error(e) => Outcome::Error(e),
+            Outcome::Forward(f) => Outcome::Forward(f),
+        }
+    }
+}
+
+/// What a request may do. Each scope unlocks a fixed set of endpoints, through `RequireScope`.
+/// API tokens hold the scopes they were minted with, signed-in users those of their role.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    ReadLogs,
+    ReadKeys,
+    ManageKeys,
+    Unlock,
+    ReadMetrics,
+    Kiosk,
+    HomeAssistant,
+    ManageUsers,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 8] = [
+        Scope::ReadLogs,
+        Scope::ReadKeys,
+        Scope::ManageKeys,
+        Scope::Unlock,
+        Scope::ReadMetrics,
+        Scope::Kiosk,
+        Scope::HomeAssistant,
+        Scope::ManageUsers,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::ReadLogs => "read-logs",
+            Scope::ReadKeys => "read-keys",
+            Scope::ManageKeys => "manage-keys",
+            Scope::Unlock => "unlock",
+            Scope::ReadMetrics => "read-metrics",
+            Scope::Kiosk => "kiosk",
+            Scope::HomeAssistant => "home-assistant",
+            Scope::ManageUsers => "manage-users",
+        }
+    }
+
+    // Scopes that come with this one: keys cannot be managed without reading them
+    fn implied(&self) -> &'static [Scope] {
+        match self {
+            Scope::ManageKeys => &[Scope::ReadKeys],
+            _ => &[],
+        }
+    }
+
+    /// `scopes` with the ones they imply, as carried in `Claims::scopes`.
+    pub fn granted(scopes: &[Scope]) -> Vec<String> {
+        Scope::ALL
+            .into_iter()
+            .filter(|scope| {
+                scopes
+                    .iter()
+                    .any(|held| held == scope || held.implied().contains(scope))
+            })
+            .map(|scope| scope.as_str().to_string())
+            .collect()
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(scope: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .into_iter()
+            .find(|known| known.as_str() == scope)
+            .ok_or_else(|| format!("Unknown scope: {}", scope))
+    }
+}
+
+/// A long-lived token from the `api_tokens` table, sent as `Authorization: Bearer pat_...` by
+/// scripts and kiosks. It does not stand for a user: endpoints accept it through
+/// `RequireScope`, and only for the scopes it was minted with.
+pub struct ApiToken {
+    pub id: Uuid,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub site: Option<Uuid>,
+}
+
+impl ApiToken {
+    // Claims for handlers shared with users; audit entries name the token
+    fn claims(&self) -> Claims {
+        let mut claims = Claims::new(
+            format!("token:{}", self.name),
+            Role::Viewer,
+            None,
+            self.site,
+            Duration::zero(),
+        );
+        claims.scopes = Scope::granted(&self.scopes);
+        claims
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiToken {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(token) = api_token(req) else {
+            return Outcome::Error((Status::Unauthorized, ()));
+        };
+        if req
+            .rocket()
+            .state::<ClientNetworks>()
+            .is_some_and(ClientNetworks::limits_api_tokens)
+            && let Outcome::Error(e) = req.guard::<AllowedClient>().await
+        {
+            return Outcome::Error(e);
+        }
+        let pool = match req.guard::<&State<Pool<Postgres>>>().await {
+            Outcome::Success(pool) => pool,
+            _ => return Outcome::Error((Status::InternalServerError, ())),
+        };
+
+        match touch_api_token(pool, &hash_api_token(token)).await {
+            Ok(Some(record)) => Outcome::Success(ApiToken {
+                id: record.id,
+                name: record.name,
+                scopes: record
+                    .scopes
+                    .iter()
+                    .filter_map(|scope| scope.parse().ok())
+                    .collect(),
+                site: record.site_id,
+            }),
+            Ok(None) => Outcome::Error((Status::Unauthorized, ())),
+            Err(e) => {
+                error!(error = ?e, "Failed to look up API token");
+                Outcome::Error((Status::InternalServerError, ()))
+            }
+        }
+    }
+}
+
+fn api_token<'r>(req: &'r Request<'_>) -> Option<&'r str> {
+    req.headers()
+        .get_one("Authorization")
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .filter(|token| token.starts_with(API_TOKEN_PREFIX))
+}
+
+/// A new API token to hand out once; only `hash_api_token` of it is stored.
+pub fn generate_api_token() -> String {
+    format!(
+        "{}{}{}",
+        API_TOKEN_PREFIX,
+        Uuid::new_v4().simple(),
+        Uuid::new_v4().simple()
+    )
+}
+
+pub fn hash_api_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Names a `Scope` as a type, for `RequireScope`.
+pub trait ScopeName: Send + Sync + 'static {
+    const SCOPE: Scope;
+}
+
+/// One type per `Scope`, so guards can be written as `RequireScope<scope::ManageKeys>`.
+pub mod scope {
+    use super::{Scope, ScopeName};
 
-        let token = match token {
-            Some(token) => token,
-            None => return Outcome::Error((Status::Unauthorized, ())),
+    macro_rules! scope_names {
+        ($($name:ident),*) => {
+            $(
+                pub struct $name;
+
+                impl ScopeName for $name {
+                    const SCOPE: Scope = Scope::$name;
+                }
+            )*
         };
+    }
+
+    scope_names!(
+        ReadLogs,
+        ReadKeys,
+        ManageKeys,
+        Unlock,
+        ReadMetrics,
+        Kiosk,
+        HomeAssistant,
+        ManageUsers
+    );
+}
+
+/// Request guard for endpoints that need `S::SCOPE`: an API token minted with it, or a
+/// logged-in user whose role grants it. Anyone else who is logged in gets a 403.
+pub struct RequireScope<S: ScopeName>(pub Claims, PhantomData<S>);
+
+#[rocket::async_trait]
+impl<'r, S: ScopeName> FromRequest<'r> for RequireScope<S> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if api_token(req).is_some() {
+            return match req.guard::<ApiToken>().await {
+                Outcome::Success(token) => {
+                    let claims = token.claims();
+                    if !claims.has_scope(S::SCOPE) {
+                        warn!(token = %token.id, scope = S::SCOPE.as_str(), "API token lacks scope");
+                        return Outcome::Error((Status::Forbidden, ()));
+                    }
+                    record_subject(req, &claims.sub);
+                    Outcome::Success(RequireScope(claims, PhantomData))
+                }
+                Outcome::Error(e) => Outcome::Error(e),
+                Outcome::Forward(f) => Outcome::Forward(f),
+            };
+        }
 
-        match validate_token(token, jwt_secret.get_secret()) {
-            Ok(claims) => Outcome::Success(AuthenticatedUser(claims)),
-            Err(_) => Outcome::Error((Status::Unauthorized, ())),
+        match req.guard::<AuthenticatedUser>().await {
+            Outcome::Success(user) if user.0.has_scope(S::SCOPE) => {
+                Outcome::Success(RequireScope(user.0, PhantomData))
+            }
+            Outcome::Success(_) => Outcome::Error((Status::Forbidden, ())),
+            Outcome::Error(e) => Outcome::Error(e),
+            Outcome::Forward(f) => Outcome::Forward(f),
         }
     }
 }
 
-pub fn set_auth_cookie(cookies: &CookieJar<'_>, token: String) {
+/// Any logged-in user, or an API token with `read-logs`.
+pub type CanReadLogs = RequireScope<scope::ReadLogs>;
+
+/// Any logged-in user, or an API token with `read-keys` (or `manage-keys`).
+pub type CanReadKeys = RequireScope<scope::ReadKeys>;
+
+/// An admin, or an API token with `manage-keys`.
+pub type CanManageKeys = RequireScope<scope::ManageKeys>;
+
+/// An admin, or an API token with `unlock`.
+pub type CanUnlock = RequireScope<scope::Unlock>;
+
+/// Any logged-in user, or an API token with `read-metrics`.
+pub type CanReadMetrics = RequireScope<scope::ReadMetrics>;
+
+/// Any logged-in user, or an API token with `kiosk`, for the displays showing a door's
+/// handshake QR code.
+pub type CanShowKiosk = RequireScope<scope::Kiosk>;
+
+/// An admin, or an API token with `home-assistant`, for the lock entities of the Home
+/// Assistant integration.
+pub type CanUseHomeAssistant = RequireScope<scope::HomeAssistant>;
+
+/// An admin, or an API token with `manage-users`, for the user accounts.
+pub type CanManageUsers = RequireScope<scope::ManageUsers>;
+
+/// Any logged-in user, for read-only pages. Admins are viewers too.
+pub struct ViewerUser(pub Claims);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ViewerUser {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        req.guard::<AuthenticatedUser>()
+            .await
+            .map(|user| ViewerUser(user.0))
+    }
+}
+
+pub fn hash_password(password: &str) -> Result<String, String> {
+    // A v4 UUID is 16 bytes from the OS random source, which is all a salt needs
+    let salt = SaltString::encode_b64(uuid::Uuid::new_v4().as_bytes())
+        .map_err(|e| format!("Failed to generate salt: {}", e))?;
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| format!("Failed to hash password: {}", e))
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+// Checked in place of the hash of a user that does not exist
+static UNKNOWN_USER_HASH: LazyLock<String> =
+    LazyLock::new(|| hash_password("unknown user").unwrap_or_default());
+
+/// Like `verify_password` for the user a sign in names, `None` when there is no such user. An
+/// unknown user is refused only after a hash was checked all the same, so a failed sign in
+/// takes as long either way and does not tell which usernames exist.
+pub fn verify_login(password: &str, password_hash: Option<&str>) -> bool {
+    let verified = verify_password(password, password_hash.unwrap_or(&UNKNOWN_USER_HASH));
+    verified && password_hash.is_some()
+}
+
+pub fn set_auth_cookie(cookies: &CookieJar<'_>, token: String, lifetime: Duration) {
     let mut cookie = Cookie::new("auth_token", token);
     cookie.set_http_only(true);
     cookie.set_secure(true);
     cookie.set_same_site(rocket::http::SameSite::Strict);
-    cookie.set_max_age(rocket::time::Duration::hours(24));
+    cookie.set_max_age(rocket::time::Duration::seconds(lifetime.num_seconds()));
 
     cookies.add(cookie);
 }
@@ -102,3 +709,18 @@ pub fn set_auth_cookie(cookies: &CookieJar<'_>, token: String) {
 pub fn remove_auth_cookie(cookies: &CookieJar<'_>) {
     cookies.remove(Cookie::new("auth_token", ""));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_users_never_sign_in() {
+        let hash = hash_password("correct horse").unwrap();
+
+        assert!(verify_login("correct horse", Some(&hash)));
+        assert!(!verify_login("wrong horse", Some(&hash)));
+        assert!(!verify_login("unknown user", None));
+        assert!(!verify_login("", None));
+    }
+}
@@ -0,0 +1,172 @@
+use crate::admin_feed::{AdminEvent, AdminFeed};
+use crate::database::helpers::{
+    Door, DoorSchedule, get_all_door_schedules, get_all_doors, get_lockdown, insert_door_event,
+};
+use crate::door_backend::DoorBackend;
+use crate::emergency::{OverrideMode, OverrideState};
+
+use chrono::{DateTime, Datelike, TimeZone, Utc};
+use chrono_tz::Tz;
+use rocket::tokio;
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+// How often the scheduler checks whether a window started or ended
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Holds doors unlocked during their weekly `door_schedules` windows and locks them again when
+/// a window ends, in the door's timezone or the site's. A door is also locked early when it is
+/// disabled or locked down, the site lockdown is switched on, or a deny-all override is set.
+///
+/// Unlocks are sent for the rest of the window, so a controller that times unlocks itself (such
+/// as IntelliM) still relocks the door if this process is down when the window ends. Failed
+/// commands are sent again on the next check.
+pub struct AutoLockScheduler {
+    pool: Pool<Postgres>,
+    door: Arc<dyn DoorBackend>,
+    overrides: OverrideState,
+    feed: AdminFeed,
+    timezone: Tz,
+    // Doors this scheduler unlocked, and until when
+    held: HashMap<Uuid, DateTime<Utc>>,
+}
+
+impl AutoLockScheduler {
+    pub fn spawn(
+        pool: Pool<Postgres>,
+        door: Arc<dyn DoorBackend>,
+        overrides: OverrideState,
+        feed: AdminFeed,
+        timezone: Tz,
+    ) {
+        let mut scheduler = Self {
+            pool,
+            door,
+            overrides,
+            feed,
+            timezone,
+            held: HashMap::new(),
+        };
+        tokio::spawn(async move {
+            loop {
+                scheduler.check().await;
+                tokio::time::sleep(CHECK_INTERVAL).await;
+            }
+        });
+    }
+
+    async fn check(&mut self) {
+        let (doors, schedules, lockdown) = match (
+            get_all_doors(&self.pool, None).await,
+            get_all_door_schedules(&self.pool).await,
+            get_lockdown(&self.pool).await,
+        ) {
+            (Ok(doors), Ok(schedules), Ok(lockdown)) => (doors, schedules, lockdown),
+            (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+                error!(error = ?e, "Failed to load door schedules");
+                return;
+            }
+        };
+        let deny_all = self
+            .overrides
+            .current()
+            .await
+            .is_some_and(|active| matches!(active.mode, OverrideMode::DenyAll));
+
+        let mut by_door: HashMap<Uuid, Vec<DoorSchedule>> = HashMap::new();
+        for schedule in schedules {
+            by_door.entry(schedule.door_id).or_default().push(schedule);
+        }
+
+        for door in &doors {
+            let until = match by_door.get(&door.id) {
+                Some(schedules)
+                    if door.enabled && !door.locked_down && !lockdown.active && !deny_all =>
+                {
+                    let timezone = door
+                        .timezone
+                        .as_deref()
+                        .and_then(|timezone| timezone.parse().ok())
+                        .unwrap_or(self.timezone);
+                    unlocked_until(schedules, Utc::now().with_timezone(&timezone))
+                }
+                _ => None,
+            };
+
+            match (until, self.held.get(&door.id).copied()) {
+                (Some(until), held) if held != Some(until) => {
+                    self.unlock(door, until, held.is_none()).await
+                }
+                (None, Some(_)) => self.lock(door).await,
+                _ => {}
+            }
+        }
+
+        // Deleted doors have nothing left to lock
+        self.held
+            .retain(|id, _| doors.iter().any(|door| door.id == *id));
+    }
+
+    // A window that follows straight on from the previous one only extends the unlock
+    async fn unlock(&mut self, door: &Door, until: DateTime<Utc>, starts: bool) {
+        let seconds = (until - Utc::now()).num_seconds().max(1) as u32;
+        if let Err(e) = self
+            .door
+            .unlock(door.controller_door_id as u32, Some(seconds))
+            .await
+        {
+            warn!(door = %door.name, error = %e, "Scheduled unlock failed");
+            return;
+        }
+
+        self.held.insert(door.id, until);
+        if starts {
+            info!(door = %door.name, until = %until, "Door unlocked on schedule");
+            self.record(door, "auto_unlocked").await;
+        }
+    }
+
+    async fn lock(&mut self, door: &Door) {
+        if let Err(e) = self.door.lock(door.controller_door_id as u32).await {
+            warn!(door = %door.name, error = %e, "Scheduled lock failed");
+            return;
+        }
+
+        self.held.remove(&door.id);
+        info!(door = %door.name, "Door locked on schedule");
+        self.record(door, "auto_locked").await;
+    }
+
+    async fn record(&self, door: &Door, event: &str) {
+        self.feed
+            .publish(AdminEvent::door(door.controller_door_id, &door.name, event));
+        if let Err(e) = insert_door_event(&self.pool, door.id, event).await {
+            error!(error = ?e, "Failed to record door event");
+        }
+    }
+}
+
+/// When the latest of the windows `now` falls in ends, or `None` outside all of them. Schedule
+/// times are wall-clock times.
+pub fn unlocked_until(schedules: &[DoorSchedule], now: DateTime<Tz>) -> Option<DateTime<Utc>> {
+    let weekday = now.weekday().number_from_monday() as i16;
+    let time = now.time();
+    let lock_time = schedules
+        .iter()
+        .filter(|schedule| {
+            schedule.weekdays.contains(&weekday)
+                && schedule.unlock_time <= time
+                && time < schedule.lock_time
+        })
+        .map(|schedule| schedule.lock_time)
+        .max()?;
+
+    now.timezone()
+        .from_local_datetime(&now.date_naive().and_time(lock_time))
+        .earliest()
+        .map(|until| until.with_timezone(&Utc))
+}
@@ -0,0 +1,360 @@
+//! Administration from the command line, for headless servers where the web UI cannot be
+//! reached. Reads the same configuration as the server and works on the same database; changes
+//! are recorded in the audit log as `cli:<user>`.
+
+use anyhow::{Context, Result, bail};
+use backend::config::Config;
+use backend::database::MIGRATOR;
+use backend::database::helpers::{
+    AccessLog, AccessLogFilter, AccessResult, DEFAULT_SITE, disable_key_by_npub, get_access_logs,
+    get_access_logs_after, get_all_keys, get_door_by_controller_id, grant_key_doors,
+    insert_audit_entry, insert_key, insert_manual_access_log, verify_access_log_chain,
+};
+use backend::door_backend::{self, UnlockRetry};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use rocket::tokio;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{Pool, Postgres};
+use std::time::Duration;
+
+const USAGE: &str = "Usage: portal-access-ctl <command>
+
+Commands:
+  keys add <npub> [--name <name>] [--door <door>]...  Add a key, allowed on the given doors
+  keys list                                          List keys
+  keys disable <npub>                                Disable a key
+  logs tail [-n <count>] [--follow]                  Show the latest access log entries
+  logs verify                                        Check the access log hash chain
+  door unlock <door>                                 Unlock a door
+  migrate                                            Apply pending database migrations
+
+Doors are given by their controller door number.";
+
+// Entries `logs tail` shows without -n
+const DEFAULT_TAIL: i64 = 20;
+
+// How often `logs tail --follow` looks for new entries
+const FOLLOW_INTERVAL: Duration = Duration::from_secs(2);
+
+enum Command {
+    AddKey {
+        npub: String,
+        name: Option<String>,
+        doors: Vec<i32>,
+    },
+    ListKeys,
+    DisableKey {
+        npub: String,
+    },
+    TailLogs {
+        count: i64,
+        follow: bool,
+    },
+    VerifyLogs,
+    UnlockDoor {
+        door: i32,
+    },
+    Migrate,
+}
+
+impl Command {
+    fn parse(args: &[String]) -> Result<Self> {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        match args.as_slice() {
+            ["keys", "add", npub, options @ ..] => {
+                let mut name = None;
+                let mut doors = Vec::new();
+                let mut options = options.iter();
+                while let Some(option) = options.next() {
+                    match (*option, options.next()) {
+                        ("--name", Some(value)) => name = Some(value.to_string()),
+                        ("--door", Some(value)) => doors.push(parse_door(value)?),
+                        _ => bail!("unexpected argument `{}`", option),
+                    }
+                }
+                Ok(Command::AddKey {
+                    npub: npub.to_string(),
+                    name,
+                    doors,
+                })
+            }
+            ["keys", "list"] => Ok(Command::ListKeys),
+            ["keys", "disable", npub] => Ok(Command::DisableKey {
+                npub: npub.to_string(),
+            }),
+            ["logs", "tail", options @ ..] => {
+                let mut count = DEFAULT_TAIL;
+                let mut follow = false;
+                let mut options = options.iter();
+                while let Some(option) = options.next() {
+                    match *option {
+                        "--follow" | "-f" => follow = true,
+                        "-n" => {
+                            let value = options.next().context("-n needs a count")?;
+                            count = value
+                                .parse()
+                                .ok()
+                                .filter(|count| *count > 0)
+                                .with_context(|| format!("invalid count `{}`", value))?;
+                        }
+                        _ => bail!("unexpected argument `{}`", option),
+                    }
+                }
+                Ok(Command::TailLogs { count, follow })
+            }
+            ["logs", "verify"] => Ok(Command::VerifyLogs),
+            ["door", "unlock", door] => Ok(Command::UnlockDoor {
+                door: parse_door(door)?,
+            }),
+            ["migrate"] => Ok(Command::Migrate),
+            _ => bail!("{}", USAGE),
+        }
+    }
+}
+
+fn parse_door(value: &str) -> Result<i32> {
+    value
+        .parse()
+        .ok()
+        .filter(|door| *door > 0)
+        .with_context(|| format!("invalid door number `{}`", value))
+}
+
+#[rocket::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(e) = run(&args).await {
+        eprintln!("{:#}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(args: &[String]) -> Result<()> {
+    let command = Command::parse(args)?;
+    let config = Config::load().await.map_err(|problems| {
+        anyhow::anyhow!(
+            "Invalid configuration:\n  - {}",
+            problems.replace('\n', "\n  - ")
+        )
+    })?;
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&config.database.url)
+        .await
+        .context("Failed to connect to the database")?;
+
+    match command {
+        Command::AddKey { npub, name, doors } => {
+            add_key(&pool, &npub, name.as_deref(), &doors).await
+        }
+        Command::ListKeys => list_keys(&pool).await,
+        Command::DisableKey { npub } => disable_key(&pool, &npub).await,
+        Command::TailLogs { count, follow } => {
+            tail_logs(&pool, config.site.tz(), count, follow).await
+        }
+        Command::VerifyLogs => verify_logs(&pool).await,
+        Command::UnlockDoor { door } => unlock_door(&config, &pool, door).await,
+        Command::Migrate => {
+            MIGRATOR
+                .run(&pool)
+                .await
+                .context("Failed to apply database migrations")?;
+            println!("Database migrations applied");
+            Ok(())
+        }
+    }
+}
+
+// Who the audit log and access log name for a change made here
+fn actor() -> String {
+    let user = std::env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+    format!("cli:{}", user)
+}
+
+async fn audit(pool: &Pool<Postgres>, action: &str, target: &str) -> Result<()> {
+    insert_audit_entry(pool, &actor(), action, Some(target), None)
+        .await
+        .context("Failed to record audit entry")
+}
+
+// The server fetches the key's Nostr profile on its next profile refresh
+async fn add_key(
+    pool: &Pool<Postgres>,
+    npub: &str,
+    name: Option<&str>,
+    doors: &[i32],
+) -> Result<()> {
+    if !npub.starts_with("npub1") || npub.len() != 63 {
+        bail!("Invalid public key format. Must be a valid npub1 key.");
+    }
+
+    // The key joins the site of its doors
+    let mut door_ids = Vec::new();
+    let mut site = None;
+    for door in doors {
+        match get_door_by_controller_id(pool, *door).await? {
+            Some(door) if site.is_some_and(|site| site != door.site_id) => {
+                bail!("Doors of different sites cannot be given to one key")
+            }
+            Some(door) => {
+                door_ids.push(door.id);
+                site = Some(door.site_id);
+            }
+            None => bail!("Door {} not found", door),
+        }
+    }
+
+    let key_id = insert_key(pool, npub, None, name, site.unwrap_or(DEFAULT_SITE))
+        .await
+        .context(
+            "Failed to add key. It may already exist, or be among the recently deleted keys.",
+        )?;
+    grant_key_doors(pool, key_id, &door_ids)
+        .await
+        .context("Key added, but failed to allow it on the doors")?;
+    audit(pool, "key.add", npub).await?;
+
+    println!("Added {}", npub);
+    Ok(())
+}
+
+async fn list_keys(pool: &Pool<Postgres>) -> Result<()> {
+    for key in get_all_keys(pool, None).await? {
+        let name = key
+            .profile_name
+            .or(key.display_name)
+            .unwrap_or_else(|| "-".to_string());
+        let last_access = key
+            .last_access_at
+            .map(|at| at.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_else(|| "never".to_string());
+        println!(
+            "{:<8}  {}  {:<24}  {:>6} unlocks, last {}",
+            if key.status { "enabled" } else { "disabled" },
+            key.npub,
+            name,
+            key.access_count,
+            last_access,
+        );
+    }
+
+    Ok(())
+}
+
+async fn disable_key(pool: &Pool<Postgres>, npub: &str) -> Result<()> {
+    if !disable_key_by_npub(pool, npub).await? {
+        bail!("No enabled key {}", npub);
+    }
+    audit(pool, "key.disable", npub).await?;
+
+    println!("Disabled {}", npub);
+    Ok(())
+}
+
+// Oldest first, like a log file; --follow keeps printing new entries until interrupted
+async fn tail_logs(pool: &Pool<Postgres>, timezone: Tz, count: i64, follow: bool) -> Result<()> {
+    let mut entries = get_access_logs(pool, &AccessLogFilter::default(), count, 0).await?;
+    entries.reverse();
+    if !follow {
+        entries.iter().for_each(|entry| print_log(entry, timezone));
+        return Ok(());
+    }
+
+    let mut last_seen = DateTime::<Utc>::MIN_UTC;
+    loop {
+        for entry in &entries {
+            print_log(entry, timezone);
+            last_seen = entry.created_at;
+        }
+        tokio::time::sleep(FOLLOW_INTERVAL).await;
+        entries = get_access_logs_after(pool, None, last_seen).await?;
+    }
+}
+
+fn print_log(entry: &AccessLog, timezone: Tz) {
+    let who = entry
+        .npub
+        .as_deref()
+        .or(entry.triggered_by.as_deref())
+        .unwrap_or("-");
+    let door = entry
+        .door_id
+        .map(|door| format!("door {}", door))
+        .unwrap_or_else(|| "-".to_string());
+    println!(
+        "{}  {:<12}  {:<7}  {}  {}",
+        entry
+            .created_at
+            .with_timezone(&timezone)
+            .format("%Y-%m-%d %H:%M:%S"),
+        entry.result,
+        door,
+        who,
+        entry.reason.as_deref().unwrap_or(""),
+    );
+}
+
+// Fails when the chain is broken, so a cron job or monitoring check notices
+async fn verify_logs(pool: &Pool<Postgres>) -> Result<()> {
+    let verification = verify_access_log_chain(pool).await?;
+    for chain_break in &verification.breaks {
+        println!(
+            "entry {} ({}): {}",
+            chain_break.seq, chain_break.id, chain_break.problem
+        );
+    }
+    if let Some(anchor) = &verification.anchor {
+        println!("Chain starts after pruned entry {}", anchor);
+    }
+    if let Some(head) = &verification.head {
+        println!("Chain head {}", head);
+    }
+
+    if !verification.is_intact() {
+        bail!(
+            "Access log hash chain is broken at {} of {} entries",
+            verification.breaks.len(),
+            verification.entries
+        );
+    }
+    println!("Checked {} entries, chain intact", verification.entries);
+    Ok(())
+}
+
+// Goes to the access log like a manual unlock from the admin UI. Webhooks and live log viewers
+// are not notified, as they belong to the running server.
+async fn unlock_door(config: &Config, pool: &Pool<Postgres>, door_id: i32) -> Result<()> {
+    let door = get_door_by_controller_id(pool, door_id)
+        .await?
+        .with_context(|| format!("Door {} not found", door_id))?;
+    if !door.enabled {
+        bail!("This door is disabled and will not be unlocked.");
+    }
+
+    let backend = door_backend::from_config(config).map_err(anyhow::Error::msg)?;
+    let outcome = UnlockRetry::new(&config.unlock_retry)
+        .unlock(
+            backend.as_ref(),
+            door.controller_door_id as u32,
+            door.unlock_duration(),
+        )
+        .await;
+    let note = outcome.note();
+    let result = outcome.result;
+
+    let actor = actor();
+    let (access_result, reason) = match (&result, note) {
+        (Ok(()), Some(note)) => (AccessResult::Granted, format!("manual unlock; {}", note)),
+        (Ok(()), None) => (AccessResult::Granted, "manual unlock".to_string()),
+        (Err(reason), _) => (AccessResult::Error, reason.clone()),
+    };
+    insert_manual_access_log(pool, &actor, door_id, access_result, Some(&reason))
+        .await
+        .context("Failed to record access log")?;
+
+    result.map_err(anyhow::Error::msg)?;
+    audit(pool, "door.unlock", &door.name).await?;
+    println!("Unlocked {}", door.name);
+    Ok(())
+}
@@ -0,0 +1,478 @@
+//! Links the admin UI and the door controller when they run as separate services
+//! (`service.role = "web"` and `"door"`), through Postgres NOTIFY on the database they share.
+//!
+//! The web service drives the doors through `RemoteDoors`, whose commands the door service
+//! runs on the real hardware. The door service forwards its access log entries, admin events
+//! and new handshake URLs, and sends a heartbeat with its relay connection and handshake URLs,
+//! so the live views, QR codes and health checks of the web service keep working. The web service sends the emergency
+//! override whenever it changes, and again whenever the door service (re)starts.
+
+use crate::admin_feed::AdminEvent;
+use crate::config::UnlockRetryConfig;
+use crate::database::helpers::AccessLog;
+use crate::door_backend::{Controller, DoorBackend, DoorPosition, DoorState, UnlockRetry};
+use crate::emergency::AccessOverride;
+use crate::portal_loop::AccessController;
+
+use chrono::{DateTime, Utc};
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::tokio::sync::oneshot;
+use rocket::tokio::{self, time::timeout};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+// What each side listens on: commands and overrides for the door service, command results
+// for `RemoteDoors`, and everything else the door service reports for the web service
+const DOOR_CHANNEL: &str = "portal_access_door";
+const REPLY_CHANNEL: &str = "portal_access_reply";
+const WEB_CHANNEL: &str = "portal_access_web";
+
+// How often the door service reports in, and how long the web service waits for it before
+// treating the relay connection and handshake URLs as gone
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
+
+// How long a command may take beyond the unlock retry window
+const COMMAND_MARGIN: Duration = Duration::from_secs(5);
+
+// Pause before trying again after the database could not be reached
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum DoorMessage {
+    Command {
+        id: Uuid,
+        door_id: u32,
+        command: DoorCommand,
+    },
+    Override {
+        access_override: Option<AccessOverride>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum DoorCommand {
+    Unlock { seconds: Option<u32> },
+    Lock,
+    Status,
+    Position,
+    Health,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CommandReply {
+    id: Uuid,
+    result: Result<CommandOutput, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum CommandOutput {
+    Done,
+    Unlocked(Controller),
+    State(DoorState),
+    Position(DoorPosition),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WebMessage {
+    Access {
+        entry: AccessLog,
+    },
+    Event {
+        event: AdminEvent,
+    },
+    Heartbeat {
+        started_at: DateTime<Utc>,
+        relay_connected: bool,
+        handshake_urls: HashMap<u32, Option<String>>,
+    },
+    HandshakeUrl {
+        door_id: u32,
+        url: Option<String>,
+    },
+}
+
+type PendingCommands = Arc<Mutex<HashMap<Uuid, oneshot::Sender<Result<CommandOutput, String>>>>>;
+
+/// The doors of the door service, for the web service. Each command is sent to the door
+/// service and waits for its result; the door service retries failed unlocks itself, so none
+/// of the errors here are transient.
+#[derive(Clone)]
+pub struct RemoteDoors {
+    pool: Pool<Postgres>,
+    timeout: Duration,
+    pending: PendingCommands,
+}
+
+impl RemoteDoors {
+    /// Starts listening for command results right away.
+    pub fn connect(pool: Pool<Postgres>, unlock_retry: &UnlockRetryConfig) -> Self {
+        let doors = Self {
+            pool,
+            timeout: Duration::from_secs(unlock_retry.ttl_secs) + COMMAND_MARGIN,
+            pending: PendingCommands::default(),
+        };
+
+        let pool = doors.pool.clone();
+        let pending = doors.pending.clone();
+        tokio::spawn(async move {
+            let mut listener = Listener::connect(&pool, REPLY_CHANNEL).await;
+            loop {
+                let reply: CommandReply = listener.next().await;
+                let sender = pending
+                    .lock()
+                    .expect("Pending commands lock poisoned")
+                    .remove(&reply.id);
+                // Nobody waits for a reply that came in after its command timed out
+                if let Some(sender) = sender {
+                    let _ = sender.send(reply.result);
+                }
+            }
+        });
+
+        doors
+    }
+
+    async fn command(&self, door_id: u32, command: DoorCommand) -> Result<CommandOutput, String> {
+        let id = Uuid::new_v4();
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .expect("Pending commands lock poisoned")
+            .insert(id, sender);
+
+        let message = DoorMessage::Command {
+            id,
+            door_id,
+            command,
+        };
+        let result = match notify(&self.pool, DOOR_CHANNEL, &message).await {
+            Ok(()) => match timeout(self.timeout, receiver).await {
+                Ok(Ok(result)) => result,
+                _ => Err("the door service did not answer".to_string()),
+            },
+            Err(e) => Err(format!("failed to reach the door service: {}", e)),
+        };
+
+        self.pending
+            .lock()
+            .expect("Pending commands lock poisoned")
+            .remove(&id);
+        result
+    }
+}
+
+fn unexpected_output() -> String {
+    "unexpected answer from the door service".to_string()
+}
+
+#[rocket::async_trait]
+impl DoorBackend for RemoteDoors {
+    async fn unlock(&self, door_id: u32, seconds: Option<u32>) -> Result<(), String> {
+        self.unlock_via(door_id, seconds).await.map(|_| ())
+    }
+
+    async fn unlock_via(&self, door_id: u32, seconds: Option<u32>) -> Result<Controller, String> {
+        match self
+            .command(door_id, DoorCommand::Unlock { seconds })
+            .await?
+        {
+            CommandOutput::Unlocked(controller) => Ok(controller),
+            _ => Err(unexpected_output()),
+        }
+    }
+
+    async fn lock(&self, door_id: u32) -> Result<(), String> {
+        match self.command(door_id, DoorCommand::Lock).await? {
+            CommandOutput::Done => Ok(()),
+            _ => Err(unexpected_output()),
+        }
+    }
+
+    async fn status(&self, door_id: u32) -> Result<DoorState, String> {
+        match self.command(door_id, DoorCommand::Status).await? {
+            CommandOutput::State(state) => Ok(state),
+            _ => Err(unexpected_output()),
+        }
+    }
+
+    async fn position(&self, door_id: u32) -> Result<DoorPosition, String> {
+        match self.command(door_id, DoorCommand::Position).await? {
+            CommandOutput::Position(position) => Ok(position),
+            _ => Err(unexpected_output()),
+        }
+    }
+
+    async fn health(&self) -> Result<(), String> {
+        match self.command(0, DoorCommand::Health).await? {
+            CommandOutput::Done => Ok(()),
+            _ => Err(unexpected_output()),
+        }
+    }
+}
+
+/// Door service side: runs the web service's commands on `controller`'s door backend, takes
+/// over its emergency override, and forwards access log entries and admin events along with a
+/// heartbeat.
+pub fn spawn_door(controller: &AccessController) {
+    let pool = controller.pool.clone();
+    let door = controller.door.clone();
+    let unlock_retry = controller.unlock_retry;
+    let overrides = controller.overrides.clone();
+    tokio::spawn(async move {
+        let mut listener = Listener::connect(&pool, DOOR_CHANNEL).await;
+        loop {
+            match listener.next().await {
+                DoorMessage::Command {
+                    id,
+                    door_id,
+                    command,
+                } => {
+                    // One slow door does not hold up commands for the others
+                    let pool = pool.clone();
+                    let door = door.clone();
+                    tokio::spawn(async move {
+                        let result = run(door.as_ref(), unlock_retry, door_id, command).await;
+                        let reply = CommandReply { id, result };
+                        if let Err(e) = notify(&pool, REPLY_CHANNEL, &reply).await {
+                            error!(door_id, error = %e, "Failed to answer door command");
+                        }
+                    });
+                }
+                DoorMessage::Override { access_override } => overrides.replace(access_override),
+            }
+        }
+    });
+
+    let pool = controller.pool.clone();
+    let mut entries = controller.log_feed.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let entry = match entries.recv().await {
+                Ok(entry) => entry,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+            forward(&pool, &WebMessage::Access { entry }).await;
+        }
+    });
+
+    let pool = controller.pool.clone();
+    let mut events = controller.admin_feed.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+            forward(&pool, &WebMessage::Event { event }).await;
+        }
+    });
+
+    // Single-use URLs are replaced after every handshake, sooner than the next heartbeat
+    let pool = controller.pool.clone();
+    let mut changes = controller.handshake_urls.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let (door_id, url) = match changes.recv().await {
+                Ok(change) => change,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+            forward(&pool, &WebMessage::HandshakeUrl { door_id, url }).await;
+        }
+    });
+
+    let pool = controller.pool.clone();
+    let relay_health = controller.relay_health.clone();
+    let handshake_urls = controller.handshake_urls.clone();
+    let started_at = Utc::now();
+    tokio::spawn(async move {
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            heartbeat.tick().await;
+            let message = WebMessage::Heartbeat {
+                started_at,
+                relay_connected: relay_health.is_connected(),
+                handshake_urls: handshake_urls.all(),
+            };
+            forward(&pool, &message).await;
+        }
+    });
+}
+
+async fn run(
+    door: &dyn DoorBackend,
+    unlock_retry: UnlockRetry,
+    door_id: u32,
+    command: DoorCommand,
+) -> Result<CommandOutput, String> {
+    match command {
+        DoorCommand::Unlock { seconds } => {
+            let outcome = unlock_retry.unlock(door, door_id, seconds).await;
+            outcome
+                .result
+                .map(|()| CommandOutput::Unlocked(outcome.controller))
+        }
+        DoorCommand::Lock => door.lock(door_id).await.map(|()| CommandOutput::Done),
+        DoorCommand::Status => door.status(door_id).await.map(CommandOutput::State),
+        DoorCommand::Position => door.position(door_id).await.map(CommandOutput::Position),
+        DoorCommand::Health => door.health().await.map(|()| CommandOutput::Done),
+    }
+}
+
+async fn forward(pool: &Pool<Postgres>, message: &WebMessage) {
+    if let Err(e) = notify(pool, WEB_CHANNEL, message).await {
+        error!(error = %e, "Failed to notify the web service");
+    }
+}
+
+/// Web service side: follows the door service, keeping `controller`'s relay connection,
+/// handshake URLs, live access log and admin events up to date, and sends it the emergency
+/// override.
+pub fn spawn_web(controller: &AccessController) {
+    // Counted from startup, so a door service starting alongside has time to report in
+    let last_heartbeat = Arc::new(Mutex::new(Instant::now()));
+
+    let pool = controller.pool.clone();
+    let view = controller.clone();
+    let heartbeats = last_heartbeat.clone();
+    tokio::spawn(async move {
+        let mut listener = Listener::connect(&pool, WEB_CHANNEL).await;
+        let mut door_service_started = None;
+        loop {
+            match listener.next().await {
+                WebMessage::Access { entry } => view.log_feed.publish(entry),
+                WebMessage::Event { event } => view.admin_feed.publish(event),
+                WebMessage::HandshakeUrl { door_id, url } => {
+                    if view.handshake_urls.get(door_id).is_some() {
+                        view.handshake_urls.set(door_id, url);
+                    }
+                }
+                WebMessage::Heartbeat {
+                    started_at,
+                    relay_connected,
+                    handshake_urls,
+                } => {
+                    *heartbeats.lock().expect("Heartbeat lock poisoned") = Instant::now();
+                    view.relay_health.set_connected(relay_connected);
+                    for (door_id, url) in handshake_urls {
+                        if view.handshake_urls.get(door_id).is_some() {
+                            view.handshake_urls.set(door_id, url);
+                        }
+                    }
+                    // A door service that just started knows no override yet
+                    if door_service_started != Some(started_at) {
+                        info!(%started_at, "Door service connected");
+                        door_service_started = Some(started_at);
+                        send_override(&pool, view.overrides.current().await).await;
+                    }
+                }
+            }
+        }
+    });
+
+    let pool = controller.pool.clone();
+    let mut overrides = controller.overrides.subscribe();
+    tokio::spawn(async move {
+        while overrides.changed().await.is_ok() {
+            let access_override = overrides.borrow_and_update().clone();
+            send_override(&pool, access_override).await;
+        }
+    });
+
+    let relay_health = controller.relay_health.clone();
+    let handshake_urls = controller.handshake_urls.clone();
+    tokio::spawn(async move {
+        let mut check = tokio::time::interval(HEARTBEAT_INTERVAL);
+        let mut silent = false;
+        loop {
+            check.tick().await;
+            let last = *last_heartbeat.lock().expect("Heartbeat lock poisoned");
+            let heard_from = last.elapsed() < HEARTBEAT_TIMEOUT;
+            if heard_from || silent {
+                silent = !heard_from;
+                continue;
+            }
+
+            warn!("Door service not heard from");
+            silent = true;
+            relay_health.set_connected(false);
+            for door_id in handshake_urls.all().into_keys() {
+                handshake_urls.set(door_id, None);
+            }
+        }
+    });
+}
+
+async fn send_override(pool: &Pool<Postgres>, access_override: Option<AccessOverride>) {
+    let message = DoorMessage::Override { access_override };
+    if let Err(e) = notify(pool, DOOR_CHANNEL, &message).await {
+        error!(error = %e, "Failed to send the emergency override to the door service");
+    }
+}
+
+// Payloads are capped by Postgres at just under 8000 bytes
+async fn notify(
+    pool: &Pool<Postgres>,
+    channel: &str,
+    message: &impl Serialize,
+) -> Result<(), String> {
+    let payload = serde_json::to_string(message).map_err(|e| e.to_string())?;
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(channel)
+        .bind(payload)
+        .execute(pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+// One LISTEN connection. Once connected, a dropped connection is re-established on the next
+// message; whatever was sent in the meantime is lost.
+struct Listener(PgListener);
+
+impl Listener {
+    async fn connect(pool: &Pool<Postgres>, channel: &str) -> Self {
+        loop {
+            let listener = match PgListener::connect_with(pool).await {
+                Ok(mut listener) => listener.listen(channel).await.map(|()| listener),
+                Err(e) => Err(e),
+            };
+            match listener {
+                Ok(listener) => return Self(listener),
+                Err(e) => {
+                    warn!(channel, error = ?e, "Failed to listen for service messages, retrying");
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    }
+
+    async fn next<T: DeserializeOwned>(&mut self) -> T {
+        loop {
+            match self.0.recv().await {
+                Ok(notification) => match serde_json::from_str(notification.payload()) {
+                    Ok(message) => return message,
+                    Err(e) => warn!(error = %e, "Ignoring malformed service message"),
+                },
+                Err(e) => {
+                    warn!(error = ?e, "Lost the connection for service messages");
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    }
+}
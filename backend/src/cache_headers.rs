@@ -0,0 +1,46 @@
+use crate::config::HttpConfig;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::{Request, Response};
+
+/// Sets `Cache-Control` on every response that does not set its own. `/static` files may be
+/// reused for `http.static_max_age_secs`; everything else is per user and has to be revalidated,
+/// so neither a shared cache nor the service worker ever serves a page or an API answer that
+/// the server would not give now.
+pub struct CacheHeaders {
+    static_files: String,
+}
+
+impl CacheHeaders {
+    pub fn new(config: &HttpConfig) -> Self {
+        Self {
+            static_files: match config.static_max_age_secs {
+                0 => "no-cache".to_string(),
+                max_age => format!("public, max-age={}", max_age),
+            },
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for CacheHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Cache headers",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if res.headers().get_one("Cache-Control").is_some() {
+            return;
+        }
+        let cache_control = if req.uri().path().starts_with("/static/") {
+            self.static_files.clone()
+        } else {
+            "private, no-cache".to_string()
+        };
+        res.set_header(Header::new("Cache-Control", cache_control));
+    }
+}
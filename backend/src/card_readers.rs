@@ -0,0 +1,78 @@
+//! Badge readers as a second credential source, so a site can keep its Wiegand or OSDP cards
+//! working while it moves to Nostr keys. Each reader sits behind a device that writes one card
+//! number per line: a serial converter (`/dev/ttyUSB0`, its speed set beforehand with `stty`)
+//! or a FIFO fed by a GPIO Wiegand decoder. `card_readers.inputs` names each door's device.
+//! Cards are mapped to keys in `credentials` and go through the same decision as a handshake,
+//! see `AccessController::handle_card`.
+
+use crate::config::CardReadersConfig;
+use crate::portal_loop::AccessController;
+
+use rocket::tokio::fs::File;
+use rocket::tokio::io::{AsyncBufReadExt, BufReader};
+use rocket::tokio::{self, time};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+// Pause before a reader's device is opened again, after it failed or was closed
+const REOPEN_DELAY: Duration = Duration::from_secs(1);
+
+/// Reads the badge reader of every door that has one, for as long as the service runs.
+pub fn spawn(controllers: &[AccessController], config: &CardReadersConfig) {
+    let inputs = config
+        .inputs()
+        .expect("Badge readers were validated with the configuration");
+    for (door_id, path) in inputs {
+        let Some(controller) = controllers.iter().find(|c| c.door_id == door_id) else {
+            continue;
+        };
+        info!(door_id, %path, "Reading badge reader");
+        tokio::spawn(read_cards(controller.clone(), path));
+    }
+}
+
+// Cards are handled one at a time, in the order they were presented
+async fn read_cards(controller: AccessController, path: String) {
+    let door_id = controller.door_id;
+    loop {
+        match File::open(&path).await {
+            Ok(file) => {
+                let mut lines = BufReader::new(file).lines();
+                loop {
+                    match lines.next_line().await {
+                        Ok(Some(line)) => match normalize_card(&line) {
+                            Some(card_number) => controller.handle_card(card_number).await,
+                            None if line.trim().is_empty() => {}
+                            None => warn!(door_id, line = line.trim(), "Unreadable card number"),
+                        },
+                        Ok(None) => {
+                            warn!(door_id, %path, "Badge reader closed, reopening");
+                            break;
+                        }
+                        Err(e) => {
+                            error!(door_id, %path, error = %e, "Failed to read badge reader");
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(e) => error!(door_id, %path, error = %e, "Failed to open badge reader"),
+        }
+        time::sleep(REOPEN_DELAY).await;
+    }
+}
+
+/// A card number as `credentials` stores it: letters and digits only, upper case and without
+/// leading zeros, as readers and converters pad numbers differently. `None` for anything else.
+pub fn normalize_card(raw: &str) -> Option<String> {
+    let card = raw.trim();
+    if card.is_empty() || !card.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    let card = card.trim_start_matches('0').to_ascii_uppercase();
+    Some(if card.is_empty() {
+        "0".to_string()
+    } else {
+        card
+    })
+}
@@ -0,0 +1,132 @@
+use crate::config::{HttpConfig, IpRange};
+
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome, Request},
+};
+use std::net::IpAddr;
+use tracing::warn;
+
+/// Which clients may use the admin UI and the API, and which reverse proxies are believed about
+/// who the client is, managed by Rocket. See `http.admin_allowlist`,
+/// `http.allowlist_api_tokens` and `http.trusted_proxies`.
+pub struct ClientNetworks {
+    allowlist: Vec<IpRange>,
+    api_tokens: bool,
+    trusted_proxies: Vec<IpRange>,
+}
+
+impl ClientNetworks {
+    /// Both lists are checked by `Config::load`, so entries that do not parse are skipped.
+    pub fn new(config: &HttpConfig) -> Self {
+        Self {
+            allowlist: config.admin_allowlist().unwrap_or_default(),
+            api_tokens: config.allowlist_api_tokens,
+            trusted_proxies: config.trusted_proxies().unwrap_or_default(),
+        }
+    }
+
+    /// Whether requests made with an API token have to pass `AllowedClient` too.
+    pub fn limits_api_tokens(&self) -> bool {
+        self.api_tokens
+    }
+
+    fn trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|range| range.contains(ip))
+    }
+
+    fn allowed(&self, ip: Option<IpAddr>) -> bool {
+        self.allowlist.is_empty()
+            || ip.is_some_and(|ip| self.allowlist.iter().any(|range| range.contains(ip)))
+    }
+
+    // The peer is the client unless it is a trusted proxy. Then `X-Forwarded-For` is read from
+    // the right, since every proxy appends the address it got the request from, and the first
+    // address that is not a trusted proxy is the client. Anything left of it could be forged.
+    fn client_ip(&self, req: &Request<'_>) -> Option<IpAddr> {
+        let mut client = req.remote()?.ip();
+        if !self.trusted(client) {
+            return Some(client);
+        }
+
+        let forwarded: Vec<IpAddr> = req
+            .headers()
+            .get("X-Forwarded-For")
+            .flat_map(|header| header.split(','))
+            .filter_map(|hop| hop.trim().parse().ok())
+            .collect();
+        if forwarded.is_empty() {
+            return req
+                .headers()
+                .get_one("X-Real-IP")
+                .and_then(|ip| ip.trim().parse().ok())
+                .or(Some(client));
+        }
+        for hop in forwarded.into_iter().rev() {
+            client = hop;
+            if !self.trusted(hop) {
+                break;
+            }
+        }
+        Some(client)
+    }
+}
+
+/// The address a request came from, looking through trusted reverse proxies. Use it instead of
+/// `Request::client_ip`, which believes `X-Real-IP` from anyone.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl ClientIp {
+    pub fn of(req: &Request<'_>) -> Option<IpAddr> {
+        req.local_cache(|| {
+            ClientIp(match req.rocket().state::<ClientNetworks>() {
+                Some(networks) => networks.client_ip(req),
+                None => req.remote().map(|remote| remote.ip()),
+            })
+        })
+        .0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ClientIp(ClientIp::of(req)))
+    }
+}
+
+/// Set on requests refused by `AllowedClient`, for the 403 catcher.
+pub struct OutsideAllowlist(pub bool);
+
+/// Request guard for the login, every signed-in page and, unless `http.allowlist_api_tokens` is
+/// off, every API token: answers 403 to clients outside `http.admin_allowlist`. Lets everyone
+/// through when the list is empty.
+pub struct AllowedClient;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AllowedClient {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(networks) = req.rocket().state::<ClientNetworks>() else {
+            return Outcome::Success(AllowedClient);
+        };
+        // Guards run more than once per request, but the refusal is logged once
+        let refused = req.local_cache(|| {
+            let ip = ClientIp::of(req);
+            let refused = !networks.allowed(ip);
+            if refused {
+                warn!(?ip, path = %req.uri().path(), "Admin request from outside the allowlist");
+            }
+            OutsideAllowlist(refused)
+        });
+        if refused.0 {
+            Outcome::Error((Status::Forbidden, ()))
+        } else {
+            Outcome::Success(AllowedClient)
+        }
+    }
+}
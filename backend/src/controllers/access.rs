@@ -1,19 +1,60 @@
+use crate::access_events::replay;
+use crate::audit::Audit;
 use crate::auth::{
-    AuthenticatedUser, Claims, JWTSecret, create_token, remove_auth_cookie, set_auth_cookie,
+    AuthenticatedUser, CanManageKeys, CanReadKeys, CanReadLogs, PasswordExpired, Role, Scope,
+    verify_login,
 };
-use crate::database::helpers::{get_all_keys, insert_key, toggle_key_status, delete_key_by_id};
-use rocket::{catch, Request};
-use rocket::{
-    State, form::Form, get, http::CookieJar, http::Status, post, response::Redirect,
-    serde::json::Json,
+use crate::client_ip::{AllowedClient, OutsideAllowlist};
+use crate::controllers::doors::render_qr;
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::controllers::schedules::DAY_NAMES;
+use crate::controllers::settings::PASSWORD_PATH;
+use crate::database::helpers::{
+    AccessLogExportRow, AccessLogFilter, AccessResult, KeyBatchAction, KeyFilter, KeyOrder,
+    KeyStatusFilter, PublicKey, approve_pending_key, batch_update_keys, count_access_logs,
+    count_keys, delete_key_by_id, delete_pending_key, get_access_log_by_id, get_access_logs,
+    get_all_doors, get_all_groups, get_all_keys, get_deleted_keys, get_group_by_id,
+    get_key_access_logs, get_key_by_id, get_key_doors, get_key_schedules, get_key_tags,
+    get_pending_keys, get_user_by_username, grant_key_doors, insert_key, restore_key_by_id,
+    search_keys, set_key_photo, stream_access_log_export, toggle_key_status, update_key_details,
+    use_totp_step, verify_access_log_chain,
 };
-use rocket_dyn_templates::{Template, context};
+use crate::door_backend::CircuitState;
+use crate::live_logs::LogFeed;
+use crate::login_throttle::LoginAttempt;
+use crate::nip05::spawn_verification;
+use crate::photos::{Photos, image_type, photo_name};
+use crate::portal_loop::AccessController;
+use crate::profiles;
+use crate::relays::{PortalConnection, PortalIdentities};
+use crate::sessions::Sessions;
+use crate::templating::Page;
+use crate::totp;
+use chrono::{DateTime, NaiveDate, Utc};
+use rocket::fs::TempFile;
+use rocket::futures::StreamExt;
+use rocket::http::{ContentType, Header};
+use rocket::response::stream::{Event, EventStream, TextStream};
+use rocket::tokio::io::AsyncReadExt;
+use rocket::tokio::{join, select, sync::broadcast::error::RecvError, time::timeout};
+use rocket::{Request, Shutdown, catch};
+use rocket::{State, form::Form, get, http::Status, post, response::Redirect, serde::json::Json};
+use rocket_dyn_templates::context;
 use sqlx::{Pool, Postgres};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, warn};
 use uuid::Uuid;
 
+// How long the readiness check waits on the database and the door backend
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(rocket::form::FromForm)]
 pub struct AuthRequest {
+    username: String,
     password: String,
+    totp: Option<String>, // authenticator app code, for users with two-factor sign in
 }
 
 #[derive(rocket::form::FromForm)]
@@ -21,72 +62,738 @@ pub struct KeyRequest {
     npub: String,
     nip05: Option<String>,
     profile_name: Option<String>,
+    door_ids: Vec<String>,
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct KeyDetailsRequest {
+    notes: String,
+    tags: String, // comma separated
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct KeyBatchRequest {
+    key_ids: Vec<String>,
+    action: String, // enable, disable, delete, group or expiry
+    group_id: Option<String>,
+    expires_at: Option<String>, // date or RFC 3339 timestamp, empty to clear
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct KeyApproval {
+    door_ids: Vec<String>,
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct KeyPhotoRequest<'r> {
+    photo: TempFile<'r>,
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct KeyImportRequest<'r> {
+    file: TempFile<'r>,
+    door_ids: Vec<String>,
 }
 
+/// Readiness check: probes Postgres, the Portal relay connection and the door backend, and
+/// answers 503 when any of them is down.
 #[get("/health_check")]
-pub fn health_check(_pool_state: &State<Pool<Postgres>>) -> Result<Json<String>, Status> {
-    Ok(Json("Ok".to_string()))
+pub async fn health_check(
+    pool: &State<Pool<Postgres>>,
+    controller: &State<AccessController>,
+    identities: &State<PortalIdentities>,
+) -> (Status, Json<serde_json::Value>) {
+    let (database, door_backend) = join!(
+        probe(sqlx::query("SELECT 1").execute(pool.inner())),
+        probe(controller.door.health()),
+    );
+    let relay = if identities.all_connected() {
+        Ok(())
+    } else {
+        Err("no relay connection".to_string())
+    };
+
+    let circuit = controller.door.circuit();
+    let ready = database.is_ok() && relay.is_ok() && door_backend.is_ok();
+    let status = if ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+
+    (
+        status,
+        Json(serde_json::json!({
+            "status": if ready { "ok" } else { "unavailable" },
+            "components": {
+                "database": component_status(database),
+                "relay": component_status(relay),
+                "door_backend": with_circuit(component_status(door_backend), circuit),
+            }
+        })),
+    )
+}
+
+// Helper function to run one health probe, giving up after HEALTH_CHECK_TIMEOUT
+async fn probe<T, E: std::fmt::Display>(
+    check: impl Future<Output = Result<T, E>>,
+) -> Result<(), String> {
+    match timeout(HEALTH_CHECK_TIMEOUT, check).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timed out".to_string()),
+    }
+}
+
+// The door backend's status also says whether commands are going through to the controller
+fn with_circuit(mut status: serde_json::Value, circuit: Option<CircuitState>) -> serde_json::Value {
+    if let Some(circuit) = circuit {
+        status["circuit"] = serde_json::json!(circuit);
+    }
+    status
+}
+
+fn component_status(result: Result<(), String>) -> serde_json::Value {
+    match result {
+        Ok(()) => serde_json::json!({ "status": "ok" }),
+        Err(error) => serde_json::json!({ "status": "down", "error": error }),
+    }
 }
 
 #[get("/login")]
-pub fn login_page(user: AuthenticatedUser) -> Template {
-    Template::render("login", context! {})
+pub fn login_page(_client: AllowedClient) -> Page {
+    Page::render("login", context! {})
 }
 
-#[get("/logs")]
-pub fn logs_page(user: AuthenticatedUser) -> Template {
-    Template::render(
-        "logs",
-        context! {
-            user: user.0.sub
-        },
+// Number of access log entries shown per page
+const LOGS_PAGE_SIZE: i64 = 50;
+
+#[derive(rocket::form::FromForm)]
+pub struct LogListQuery {
+    key: Option<String>, // npub prefix or name
+    door: Option<i32>,   // controller door number
+    result: Option<AccessResult>,
+    from: Option<String>,
+    to: Option<String>,
+    page: Option<i64>,
+}
+
+/// One page of the access log, newest first, narrowed down by `key`, `door`, `result` and the
+/// `from`/`to` dates or timestamps.
+#[get("/logs?<query..>")]
+pub async fn logs_page(
+    pool: &State<Pool<Postgres>>,
+    user: CanReadLogs,
+    format: Format,
+    query: LogListQuery,
+) -> Negotiated {
+    let page = query.page.unwrap_or(1).max(1);
+
+    let site = user.0.site;
+    let (Some(from), Some(to)) = (
+        parse_bound(query.from.as_deref(), false),
+        parse_bound(query.to.as_deref(), true),
+    ) else {
+        return logs_error(
+            &user,
+            format,
+            Status::BadRequest,
+            "from and to must be YYYY-MM-DD dates or RFC 3339 timestamps",
+        );
+    };
+    let filter = AccessLogFilter {
+        key: query
+            .key
+            .as_deref()
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(str::to_string),
+        door_id: query.door,
+        result: query.result,
+        from,
+        to,
+        site,
+    };
+
+    let logs = get_access_logs(
+        pool,
+        &filter,
+        LOGS_PAGE_SIZE,
+        page.saturating_sub(1).saturating_mul(LOGS_PAGE_SIZE),
     )
+    .await;
+    let total = count_access_logs(pool, &filter).await;
+
+    match (logs, total) {
+        (Ok(logs), Ok(total)) => {
+            let total_pages = ((total + LOGS_PAGE_SIZE - 1) / LOGS_PAGE_SIZE).max(1);
+            Negotiated::page(
+                format,
+                "logs",
+                context! {
+                    user: &user.0.sub,
+                    logs: logs,
+                    doors: get_all_doors(pool, site).await.unwrap_or_default(),
+                    can_unlock: user.0.has_scope(Scope::Unlock),
+                    key: &filter.key,
+                    door: filter.door_id,
+                    result: filter.result.map(|result| result.as_str()),
+                    from: query.from.as_deref().filter(|from| !from.is_empty()),
+                    to: query.to.as_deref().filter(|to| !to.is_empty()),
+                    filtered: filter.is_filtered(),
+                    total: total,
+                    page: page,
+                    total_pages: total_pages,
+                    prev_page: (page > 1).then(|| page - 1),
+                    next_page: (page < total_pages).then(|| page + 1)
+                },
+            )
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            error!(error = ?e, "Failed to load access logs");
+            logs_error(
+                &user,
+                format,
+                Status::InternalServerError,
+                "Failed to load access logs",
+            )
+        }
+    }
 }
 
-#[post("/login", data = "<auth_request>")]
-pub fn login(
-    _pool_state: &State<Pool<Postgres>>,
-    jwt_secret: &State<JWTSecret>,
-    cookies: &CookieJar<'_>,
-    auth_request: Form<AuthRequest>,
-) -> Result<Redirect, Template> {
-    dotenvy::dotenv().ok();
+// Helper function to report an error on the logs page, or as JSON
+fn logs_error(
+    user: &CanReadLogs,
+    format: Format,
+    status: Status,
+    error_message: &str,
+) -> Negotiated {
+    match format {
+        Format::Html => Negotiated::page(
+            format,
+            "logs",
+            context! {
+                user: &user.0.sub,
+                error_message: error_message
+            },
+        ),
+        Format::Json => Negotiated::error(status, error_message),
+    }
+}
 
-    let expected_pass = match std::env::var("AUTH_PASS") {
-        Ok(pass) => pass,
-        Err(_) => {
-            return Err(Template::render(
-                "login",
+// Number of entries in a page of /logs/summary and /keys/summary
+const SUMMARY_PAGE_SIZE: i64 = 20;
+
+/// A page of the access log cut down to what a phone shows in a list, always JSON, with the
+/// same filters as `/logs`. Polling with `from` set to the newest `created_at` seen fetches
+/// only what is new.
+#[get("/logs/summary?<query..>")]
+pub async fn logs_summary(
+    pool: &State<Pool<Postgres>>,
+    user: CanReadLogs,
+    query: LogListQuery,
+) -> Negotiated {
+    let page = query.page.unwrap_or(1).max(1);
+    let (Some(from), Some(to)) = (
+        parse_bound(query.from.as_deref(), false),
+        parse_bound(query.to.as_deref(), true),
+    ) else {
+        return Negotiated::error(
+            Status::BadRequest,
+            "from and to must be YYYY-MM-DD dates or RFC 3339 timestamps",
+        );
+    };
+    let filter = AccessLogFilter {
+        key: query
+            .key
+            .as_deref()
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(str::to_string),
+        door_id: query.door,
+        result: query.result,
+        from,
+        to,
+        site: user.0.site,
+    };
+
+    let logs = get_access_logs(
+        pool,
+        &filter,
+        SUMMARY_PAGE_SIZE,
+        page.saturating_sub(1).saturating_mul(SUMMARY_PAGE_SIZE),
+    )
+    .await;
+    let total = count_access_logs(pool, &filter).await;
+
+    match (logs, total) {
+        (Ok(logs), Ok(total)) => {
+            let entries: Vec<_> = logs
+                .iter()
+                .map(|log| {
+                    context! {
+                        id: log.id,
+                        created_at: log.created_at,
+                        npub: &log.npub,
+                        name: log.profile_name.as_ref().or(log.display_name.as_ref()),
+                        triggered_by: &log.triggered_by,
+                        door_id: log.door_id,
+                        result: &log.result,
+                        reason: &log.reason,
+                    }
+                })
+                .collect();
+            Negotiated::json(
+                Status::Ok,
                 context! {
-                    error: "Server configuration error"
+                    entries: entries,
+                    page: page,
+                    total_pages: ((total + SUMMARY_PAGE_SIZE - 1) / SUMMARY_PAGE_SIZE).max(1),
                 },
+            )
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            error!(error = ?e, "Failed to load access logs");
+            Negotiated::error(Status::InternalServerError, "Failed to load access logs")
+        }
+    }
+}
+
+/// Export body with a download filename.
+#[derive(rocket::Responder)]
+pub struct Download<T> {
+    body: T,
+    content_type: ContentType,
+    disposition: Header<'static>,
+}
+
+#[get("/logs/export?<format>&<from>&<to>")]
+pub async fn export_logs(
+    pool: &State<Pool<Postgres>>,
+    user: CanReadLogs,
+    format: Option<&str>,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<Download<TextStream![String]>, Negotiated> {
+    let csv = match format.unwrap_or("csv") {
+        "csv" => true,
+        "json" => false,
+        _ => {
+            return Err(Negotiated::error(
+                Status::BadRequest,
+                "format must be csv or json",
             ));
         }
     };
+    let (Some(from), Some(to)) = (parse_bound(from, false), parse_bound(to, true)) else {
+        return Err(Negotiated::error(
+            Status::BadRequest,
+            "from and to must be YYYY-MM-DD dates or RFC 3339 timestamps",
+        ));
+    };
+
+    let pool = pool.inner().clone();
+    let site = user.0.site;
+    let body = TextStream! {
+        let mut rows = stream_access_log_export(&pool, site, from, to);
+        let mut first = true;
+        yield if csv { CSV_HEADER.to_string() } else { "[".to_string() };
+
+        while let Some(row) = rows.next().await {
+            match row {
+                Ok(row) if csv => yield csv_line(&row),
+                Ok(row) => {
+                    let separator = if first { "\n" } else { ",\n" };
+                    yield format!("{}{}", separator, serde_json::to_string(&row).unwrap_or_default());
+                }
+                Err(e) => {
+                    // Headers are already sent, so all we can do is cut the export short
+                    error!(error = ?e, "Access log export failed");
+                    break;
+                }
+            }
+            first = false;
+        }
+
+        if !csv {
+            yield "\n]\n".to_string();
+        }
+    };
+
+    let (content_type, extension) = if csv {
+        (ContentType::CSV, "csv")
+    } else {
+        (ContentType::JSON, "json")
+    };
+    Ok(Download {
+        body,
+        content_type,
+        disposition: Header::new(
+            "Content-Disposition",
+            format!("attachment; filename=\"access-logs.{}\"", extension),
+        ),
+    })
+}
+
+/// The pipeline events behind an access log entry, from the handshake to the entry, with the
+/// decision replayed from the recorded resolution. Entries that did not come from a handshake,
+/// or whose events are past retention, have no events.
+#[get("/logs/<log_id>/events")]
+pub async fn log_events_page(
+    pool: &State<Pool<Postgres>>,
+    user: CanReadLogs,
+    format: Format,
+    log_id: &str,
+) -> Negotiated {
+    let Ok(uuid) = Uuid::parse_str(log_id) else {
+        return logs_error(&user, format, Status::BadRequest, "Invalid log entry ID");
+    };
+    let log = match get_access_log_by_id(pool, uuid).await {
+        Ok(Some(log)) if log.in_site(user.0.site) => log,
+        Ok(_) => return logs_error(&user, format, Status::NotFound, "Log entry not found"),
+        Err(e) => {
+            error!(error = ?e, "Failed to load access log entry");
+            return logs_error(
+                &user,
+                format,
+                Status::InternalServerError,
+                "Failed to load log entry",
+            );
+        }
+    };
 
-    if auth_request.password == expected_pass {
-        let claims = Claims::new("authenticated_user".to_string());
-        let token = match create_token(&claims, jwt_secret.get_secret()) {
-            Ok(token) => token,
-            Err(_) => {
-                return Err(Template::render(
-                    "login",
+    match replay(pool, uuid).await {
+        Ok(trail) => match format {
+            Format::Html => {
+                let events: Vec<_> = trail
+                    .events
+                    .iter()
+                    .map(|event| {
+                        context! {
+                            stage: &event.stage,
+                            created_at: event.created_at,
+                            data: serde_json::to_string_pretty(&event.data).unwrap_or_default(),
+                        }
+                    })
+                    .collect();
+                Negotiated::page(
+                    format,
+                    "log_events",
                     context! {
-                        error: "Failed to create authentication token"
+                        user: &user.0.sub,
+                        log: log,
+                        events: events,
+                        replay: trail.replay,
                     },
-                ));
+                )
             }
-        };
+            Format::Json => Negotiated::json(
+                Status::Ok,
+                context! {
+                    log: log,
+                    events: trail.events,
+                    replay: trail.replay,
+                },
+            ),
+        },
+        Err(e) => {
+            error!(error = ?e, "Failed to load pipeline events");
+            logs_error(
+                &user,
+                format,
+                Status::InternalServerError,
+                "Failed to load pipeline events",
+            )
+        }
+    }
+}
+
+/// Checks the access log's hash chain, always JSON; answers `409` with the entries that break
+/// it. The chain runs through the entries of every site, so users limited to one cannot check it.
+#[get("/logs/verify")]
+pub async fn verify_logs(pool: &State<Pool<Postgres>>, user: CanReadLogs) -> Negotiated {
+    if user.0.site.is_some() {
+        return Negotiated::error(
+            Status::Forbidden,
+            "The access log chain covers every site; ask an admin of every site to verify it",
+        );
+    }
+
+    match verify_access_log_chain(pool).await {
+        Ok(verification) => {
+            if !verification.is_intact() {
+                warn!(breaks = ?verification.breaks, "Access log hash chain is broken");
+            }
+            let status = if verification.is_intact() {
+                Status::Ok
+            } else {
+                Status::Conflict
+            };
+            Negotiated::json(
+                status,
+                context! {
+                    intact: verification.is_intact(),
+                    entries: verification.entries,
+                    breaks: &verification.breaks,
+                    anchor: &verification.anchor,
+                    head: &verification.head,
+                },
+            )
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to verify access log chain");
+            Negotiated::error(
+                Status::InternalServerError,
+                "Failed to verify access log chain",
+            )
+        }
+    }
+}
+
+/// Server-Sent Events feed of access log entries of the user's site as they are recorded, one
+/// `access` event per entry, so the logs page can update without a refresh.
+#[get("/logs/stream")]
+pub fn stream_logs(
+    feed: &State<LogFeed>,
+    user: CanReadLogs,
+    mut shutdown: Shutdown,
+) -> EventStream![] {
+    let site = user.0.site;
+    let mut entries = feed.subscribe();
+    EventStream! {
+        loop {
+            let entry = select! {
+                entry = entries.recv() => match entry {
+                    Ok(entry) if entry.in_site(site) => entry,
+                    Ok(_) => continue,
+                    Err(RecvError::Closed) => break,
+                    // A viewer that fell behind skips ahead; a refresh shows what it missed
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+                _ = &mut shutdown => break,
+            };
+            yield Event::json(&entry).event("access");
+        }
+    }
+}
+
+const CSV_HEADER: &str =
+    "timestamp,npub,profile_name,door_id,door_name,result,reason_code,reason,triggered_by\n";
+
+// Helper function to parse an export bound. Missing bounds are open; a bare date covers the
+// whole day, so `to=2025-10-31` includes the 31st.
+fn parse_bound(value: Option<&str>, end_of_range: bool) -> Option<Option<DateTime<Utc>>> {
+    let Some(value) = value.filter(|value| !value.is_empty()) else {
+        return Some(None);
+    };
+
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Some(Some(timestamp.with_timezone(&Utc)));
+    }
 
-        set_auth_cookie(cookies, token);
-        Ok(Redirect::to("/logs"))
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    let date = if end_of_range { date.succ_opt()? } else { date };
+    Some(Some(date.and_hms_opt(0, 0, 0)?.and_utc()))
+}
+
+// Helper function to render one export row as a CSV line
+fn csv_line(row: &AccessLogExportRow) -> String {
+    let fields = [
+        row.created_at.to_rfc3339(),
+        row.npub.clone().unwrap_or_default(),
+        row.profile_name.clone().unwrap_or_default(),
+        row.door_id.map(|id| id.to_string()).unwrap_or_default(),
+        row.door_name.clone().unwrap_or_default(),
+        row.result.clone(),
+        row.reason_code.clone().unwrap_or_default(),
+        row.reason.clone().unwrap_or_default(),
+        row.triggered_by.clone().unwrap_or_default(),
+    ];
+
+    let mut line = fields
+        .iter()
+        .map(|field| csv_field(field))
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push('\n');
+    line
+}
+
+// Quote fields that need it, and defuse values a spreadsheet would run as a formula
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
     } else {
-        Err(Template::render(
+        value
+    }
+}
+
+#[post("/login", data = "<auth_request>")]
+pub async fn login(
+    _client: AllowedClient,
+    pool: &State<Pool<Postgres>>,
+    sessions: Sessions<'_>,
+    audit: Audit,
+    attempt: LoginAttempt,
+    format: Format,
+    auth_request: Form<AuthRequest>,
+) -> Negotiated {
+    let username = auth_request.username.trim();
+    if let Some(seconds) = attempt.locked_for().await {
+        return login_error(
+            format,
+            Status::TooManyRequests,
+            &format!(
+                "Too many failed login attempts. Try again in {} seconds.",
+                seconds
+            ),
+        );
+    }
+
+    let user = match get_user_by_username(pool, username).await {
+        Ok(user) => user,
+        Err(_) => {
+            return login_error(
+                format,
+                Status::InternalServerError,
+                "Failed to check credentials",
+            );
+        }
+    };
+
+    let verified = verify_login(
+        &auth_request.password,
+        user.as_ref().map(|user| user.password_hash.as_str()),
+    );
+    let user = match user {
+        Some(user) if verified => user,
+        _ => {
+            audit.record(username, "login.failed", None).await;
+            attempt.failed(username).await;
+            return login_error(format, Status::Unauthorized, "Invalid username or password");
+        }
+    };
+    if let Some(secret) = &user.totp_secret {
+        let code = auth_request.totp.as_deref().unwrap_or_default();
+        // A code already signed in with is refused even while it is still valid
+        let accepted = match totp::verify(secret, code) {
+            Some(step) => match use_totp_step(pool, user.id, step).await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!(error = ?e, "Failed to record authentication code");
+                    return login_error(
+                        format,
+                        Status::InternalServerError,
+                        "Failed to check credentials",
+                    );
+                }
+            },
+            None => false,
+        };
+        if !accepted {
+            audit.record(username, "login.failed", None).await;
+            attempt.failed(username).await;
+            return login_error(
+                format,
+                Status::Unauthorized,
+                "Invalid or missing authentication code",
+            );
+        }
+    }
+
+    let role = match user.role.parse::<Role>() {
+        Ok(role) => role,
+        Err(_) => {
+            return login_error(
+                format,
+                Status::InternalServerError,
+                "Server configuration error",
+            );
+        }
+    };
+
+    let token = match sessions
+        .start(
+            user.username.clone(),
+            role,
+            user.site_id,
+            user.password_changed_at,
+        )
+        .await
+    {
+        Ok(token) => token,
+        Err(e) => {
+            error!(error = %e, "Failed to sign in");
+            return login_error(
+                format,
+                Status::InternalServerError,
+                "Failed to create authentication token",
+            );
+        }
+    };
+
+    attempt.succeeded().await;
+    audit.record(&user.username, "login", None).await;
+    // An expired password has to be changed before anything else
+    let password_expired = sessions.password_expired(user.password_changed_at);
+    Negotiated::done(
+        format,
+        if password_expired {
+            PASSWORD_PATH
+        } else {
+            "/logs"
+        },
+        Status::Ok,
+        serde_json::json!({
+            "message": "Authentication successful",
+            "token": token,
+            "expires_in": sessions.access_ttl().num_seconds(),
+            "role": role,
+            "scopes": role.scopes(),
+            "password_expired": password_expired
+        }),
+    )
+}
+
+#[post("/refresh")]
+pub async fn refresh(_client: AllowedClient, sessions: Sessions<'_>, format: Format) -> Negotiated {
+    match sessions.refresh().await {
+        Ok((claims, token)) => Negotiated::done(
+            format,
+            "/logs",
+            Status::Ok,
+            serde_json::json!({
+                "token": token,
+                "expires_in": sessions.access_ttl().num_seconds(),
+                "role": claims.role,
+                "scopes": claims.scopes
+            }),
+        ),
+        Err(status) if status == Status::Unauthorized => match format {
+            Format::Html => Negotiated::redirect("/login"),
+            Format::Json => Negotiated::error(Status::Unauthorized, "Session expired or revoked"),
+        },
+        Err(status) => login_error(format, status, "Failed to refresh session"),
+    }
+}
+
+// Helper function to report a login error in the requested format
+fn login_error(format: Format, status: Status, error: &str) -> Negotiated {
+    match format {
+        Format::Html => Negotiated::page(
+            format,
             "login",
             context! {
-                error: "Invalid password"
+                error: error
             },
-        ))
+        ),
+        Format::Json => Negotiated::error(status, error),
     }
 }
 
@@ -105,35 +812,178 @@ pub fn protected_endpoint(
 }
 
 #[post("/logout")]
-pub fn logout(cookies: &CookieJar<'_>) -> Redirect {
-    // Remove the authentication cookie
-    remove_auth_cookie(cookies);
+pub async fn logout(
+    sessions: Sessions<'_>,
+    user: Option<AuthenticatedUser>,
+    audit: Audit,
+) -> Redirect {
+    if let Some(user) = user {
+        audit.record(&user.0.sub, "logout", None).await;
+    }
+
+    // Revoke the session and remove both cookies
+    sessions.end().await;
 
     Redirect::to("/login")
 }
 
 // Key Management Endpoints
 
-#[get("/keys")]
+// How far back the keys page lists deleted keys for a restore
+const RECENTLY_DELETED_DAYS: i64 = 30;
+
+// Number of keys shown per page
+const KEYS_PAGE_SIZE: i64 = 50;
+
+#[derive(rocket::form::FromForm)]
+pub struct KeyListQuery {
+    sort: Option<KeyOrder>,
+    unused_days: Option<i64>,
+    q: Option<String>, // npub prefix, name or NIP-05
+    status: Option<KeyStatusFilter>,
+    tag: Option<String>,
+    page: Option<i64>,
+}
+
+/// One page of keys in `sort` order, narrowed down by `q`, `status` and `tag`. With `unused_days`
+/// only the keys that have not unlocked a door in that many days, longest unused first.
+#[get("/keys?<query..>")]
 pub async fn keys_page(
     pool: &State<Pool<Postgres>>,
-    _user: AuthenticatedUser,
-) -> Result<Template, Template> {
-    match get_all_keys(pool).await {
-        Ok(keys) => Ok(Template::render(
-            "keys",
-            context! {
-                keys: keys
-            },
-        )),
-        Err(e) => {
-            dbg!(e);
-            Err(Template::render(
+    user: CanReadKeys,
+    format: Format,
+    query: KeyListQuery,
+) -> Negotiated {
+    let sort = query.sort.unwrap_or_default();
+    let page = query.page.unwrap_or(1).max(1);
+    let unused_days = query.unused_days;
+    let filter = KeyFilter {
+        search: query
+            .q
+            .as_deref()
+            .map(str::trim)
+            .filter(|q| !q.is_empty())
+            .map(str::to_string),
+        status: query.status.unwrap_or_default(),
+        unused_days,
+        tag: query.tag.as_deref().and_then(|tag| parse_tags(tag).pop()),
+        site: user.0.site,
+    };
+
+    let keys = search_keys(
+        pool,
+        &filter,
+        sort,
+        KEYS_PAGE_SIZE,
+        page.saturating_sub(1).saturating_mul(KEYS_PAGE_SIZE),
+    )
+    .await;
+    let total = count_keys(pool, &filter).await;
+
+    match (keys, total) {
+        (Ok(keys), Ok(total)) => {
+            let total_pages = ((total + KEYS_PAGE_SIZE - 1) / KEYS_PAGE_SIZE).max(1);
+            Negotiated::page(
+                format,
                 "keys",
                 context! {
-                    error_message: "Failed to load keys"
+                    keys: keys,
+                    sort: sort.as_str(),
+                    unused_days: unused_days,
+                    q: &filter.search,
+                    status: filter.status.as_str(),
+                    tag: &filter.tag,
+                    tags: get_key_tags(pool, user.0.site).await.unwrap_or_default(),
+                    filtered: filter.search.is_some()
+                        || filter.status != KeyStatusFilter::All
+                        || filter.tag.is_some(),
+                    total: total,
+                    page: page,
+                    total_pages: total_pages,
+                    prev_page: (page > 1).then(|| page - 1),
+                    next_page: (page < total_pages).then(|| page + 1),
+                    pending_keys: get_pending_keys(pool, user.0.site).await.unwrap_or_default(),
+                    deleted_keys: recently_deleted_keys(pool, user.0.site).await,
+                    doors: get_all_doors(pool, user.0.site).await.unwrap_or_default(),
+                    groups: get_all_groups(pool).await.unwrap_or_default(),
+                    can_unlock: user.0.has_scope(Scope::Unlock)
                 },
-            ))
+            )
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            error!(error = ?e, "Failed to load keys");
+            keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to load keys",
+            )
+            .await
+        }
+    }
+}
+
+/// A page of keys cut down to what a phone shows in a list, always JSON, with the same sorting
+/// and filters as `/keys`.
+#[get("/keys/summary?<query..>")]
+pub async fn keys_summary(
+    pool: &State<Pool<Postgres>>,
+    user: CanReadKeys,
+    query: KeyListQuery,
+) -> Negotiated {
+    let page = query.page.unwrap_or(1).max(1);
+    let filter = KeyFilter {
+        search: query
+            .q
+            .as_deref()
+            .map(str::trim)
+            .filter(|q| !q.is_empty())
+            .map(str::to_string),
+        status: query.status.unwrap_or_default(),
+        unused_days: query.unused_days,
+        tag: query.tag.as_deref().and_then(|tag| parse_tags(tag).pop()),
+        site: user.0.site,
+    };
+
+    let keys = search_keys(
+        pool,
+        &filter,
+        query.sort.unwrap_or_default(),
+        SUMMARY_PAGE_SIZE,
+        page.saturating_sub(1).saturating_mul(SUMMARY_PAGE_SIZE),
+    )
+    .await;
+    let total = count_keys(pool, &filter).await;
+
+    match (keys, total) {
+        (Ok(keys), Ok(total)) => {
+            let keys: Vec<_> = keys
+                .iter()
+                .map(|key| {
+                    context! {
+                        id: key.id,
+                        npub: &key.npub,
+                        name: key.profile_name.as_ref().or(key.display_name.as_ref()),
+                        status: key.status,
+                        pending: key.pending,
+                        last_access_at: key.last_access_at,
+                        expires_at: key.expires_at,
+                    }
+                })
+                .collect();
+            Negotiated::json(
+                Status::Ok,
+                context! {
+                    keys: keys,
+                    page: page,
+                    total_pages: ((total + SUMMARY_PAGE_SIZE - 1) / SUMMARY_PAGE_SIZE).max(1),
+                },
+            )
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            error!(error = ?e, "Failed to load keys");
+            Negotiated::error(Status::InternalServerError, "Failed to load keys")
         }
     }
 }
@@ -141,93 +991,1434 @@ pub async fn keys_page(
 #[post("/keys", data = "<key_request>")]
 pub async fn add_key(
     pool: &State<Pool<Postgres>>,
-    _user: AuthenticatedUser,
+    portal: &State<PortalConnection>,
+    user: CanManageKeys,
+    audit: Audit,
+    format: Format,
     key_request: Form<KeyRequest>,
-) -> Result<Redirect, Template> {
-    // Validate npub format
-    if !key_request.npub.starts_with("npub1") || key_request.npub.len() != 63 {
-        return Err(render_keys_with_error(pool, "Invalid public key format. Must be a valid npub1 key.").await);
+) -> Negotiated {
+    if !is_valid_npub(&key_request.npub) {
+        return keys_error(
+            pool,
+            user.0.site,
+            format,
+            Status::UnprocessableEntity,
+            "Invalid public key format. Must be a valid npub1 key.",
+        )
+        .await;
     }
 
-    match insert_key(
+    let door_ids: Result<Vec<Uuid>, _> = key_request
+        .door_ids
+        .iter()
+        .map(|id| Uuid::parse_str(id))
+        .collect();
+    let door_ids = match door_ids {
+        Ok(door_ids) => door_ids,
+        Err(_) => {
+            return keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::BadRequest,
+                "Invalid door ID",
+            )
+            .await;
+        }
+    };
+
+    let key_id = match insert_key(
         pool,
         &key_request.npub,
         key_request.nip05.as_deref(),
         key_request.profile_name.as_deref(),
+        user.0.home_site(),
     )
     .await
     {
-        Ok(_) => Ok(Redirect::to("/keys")),
-        Err(_) => Err(render_keys_with_error(pool, "Failed to add key. It may already exist.").await),
+        Ok(key_id) => key_id,
+        Err(_) => {
+            return keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::Conflict,
+                "Failed to add key. It may already exist, or be among the recently deleted keys.",
+            )
+            .await;
+        }
+    };
+
+    audit
+        .record(&user.0.sub, "key.add", Some(&key_request.npub))
+        .await;
+
+    profiles::spawn_fetch(
+        pool.inner().clone(),
+        Arc::new(portal.inner().clone()),
+        vec![(key_id, key_request.npub.clone())],
+    );
+
+    if let Some(nip05) = key_request.nip05.as_deref().map(str::trim)
+        && !nip05.is_empty()
+    {
+        spawn_verification(
+            pool.inner().clone(),
+            key_id,
+            key_request.npub.clone(),
+            nip05.to_string(),
+        );
+    }
+
+    match grant_key_doors(pool, key_id, &door_ids).await {
+        Ok(_) => Negotiated::done(
+            format,
+            "/keys",
+            Status::Created,
+            serde_json::json!({ "id": key_id, "npub": key_request.npub, "door_ids": door_ids }),
+        ),
+        Err(_) => {
+            keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Key added, but assigning its doors failed. Assign them from the Doors page.",
+            )
+            .await
+        }
     }
 }
 
 #[post("/keys/<key_id>/toggle")]
 pub async fn toggle_key(
     pool: &State<Pool<Postgres>>,
-    _user: AuthenticatedUser,
+    user: CanManageKeys,
+    audit: Audit,
+    format: Format,
     key_id: String,
-) -> Result<Redirect, Template> {
+) -> Negotiated {
     let uuid = match Uuid::parse_str(&key_id) {
         Ok(uuid) => uuid,
         Err(_) => {
-            return Err(render_keys_with_error(pool, "Invalid key ID").await);
+            return keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::BadRequest,
+                "Invalid key ID",
+            )
+            .await;
         }
     };
 
-    match toggle_key_status(pool, uuid).await {
-        Ok(_) => Ok(Redirect::to("/keys")),
-        Err(_) => Err(render_keys_with_error(pool, "Failed to toggle key status").await),
+    // Looked up first so the audit trail names the key and which way it was switched
+    let key = get_key_by_id(pool, uuid, user.0.site).await.ok().flatten();
+
+    match toggle_key_status(pool, uuid, user.0.site).await {
+        Ok(true) => {
+            let action = match &key {
+                Some(key) if key.status => "key.disable",
+                Some(_) => "key.enable",
+                None => "key.toggle",
+            };
+            audit
+                .record(
+                    &user.0.sub,
+                    action,
+                    Some(&audit_key_target(key.as_ref(), uuid)),
+                )
+                .await;
+            Negotiated::done(
+                format,
+                "/keys",
+                Status::Ok,
+                serde_json::json!({ "id": uuid }),
+            )
+        }
+        Ok(false) => keys_error(pool, user.0.site, format, Status::NotFound, "Key not found").await,
+        Err(_) => {
+            keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to toggle key status",
+            )
+            .await
+        }
     }
 }
 
 #[post("/keys/<key_id>/delete")]
 pub async fn delete_key(
     pool: &State<Pool<Postgres>>,
-    _user: AuthenticatedUser,
+    user: CanManageKeys,
+    audit: Audit,
+    format: Format,
     key_id: String,
-) -> Result<Redirect, Template> {
+) -> Negotiated {
     let uuid = match Uuid::parse_str(&key_id) {
         Ok(uuid) => uuid,
         Err(_) => {
-            return Err(render_keys_with_error(pool, "Invalid key ID").await);
+            return keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::BadRequest,
+                "Invalid key ID",
+            )
+            .await;
         }
     };
 
-    match delete_key_by_id(pool, uuid).await {
-        Ok(_) => Ok(Redirect::to("/keys")),
-        Err(_) => Err(render_keys_with_error(pool, "Failed to delete key").await),
-    }
-}
+    let key = get_key_by_id(pool, uuid, user.0.site).await.ok().flatten();
 
-// Helper function to render keys template with error message
-async fn render_keys_with_error(
-    pool: &Pool<Postgres>,
-    error_message: &str,
-) -> Template {
-    match get_all_keys(pool).await {
-        Ok(keys) => Template::render(
-            "keys",
-            context! {
-                keys: keys,
-                error_message: error_message
-            },
-        ),
-        Err(_) => Template::render(
-            "keys",
-            context! {
-                error_message: error_message
-            },
-        ),
+    match delete_key_by_id(pool, uuid, user.0.site).await {
+        Ok(true) => {
+            audit
+                .record(
+                    &user.0.sub,
+                    "key.delete",
+                    Some(&audit_key_target(key.as_ref(), uuid)),
+                )
+                .await;
+            Negotiated::done(
+                format,
+                "/keys",
+                Status::Ok,
+                serde_json::json!({ "id": uuid }),
+            )
+        }
+        Ok(false) => keys_error(pool, user.0.site, format, Status::NotFound, "Key not found").await,
+        Err(_) => {
+            keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to delete key",
+            )
+            .await
+        }
     }
 }
 
-#[catch(401)]
-pub fn unauthorized_handler(_req: &Request) -> Redirect {
-    Redirect::to("/login")
-}
+/// Enables, disables, deletes, adds to a group or sets the end date of several keys at once.
+/// Either every selected key changes or, when one of them is gone, none does.
+#[post("/keys/batch", data = "<batch>")]
+pub async fn batch_keys(
+    pool: &State<Pool<Postgres>>,
+    user: CanManageKeys,
+    audit: Audit,
+    format: Format,
+    batch: Form<KeyBatchRequest>,
+) -> Negotiated {
+    let key_ids: Result<Vec<Uuid>, _> =
+        batch.key_ids.iter().map(|id| Uuid::parse_str(id)).collect();
+    let key_ids = match key_ids {
+        Ok(key_ids) if !key_ids.is_empty() => key_ids,
+        Ok(_) => {
+            return keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::UnprocessableEntity,
+                "Select at least one key.",
+            )
+            .await;
+        }
+        Err(_) => {
+            return keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::BadRequest,
+                "Invalid key ID",
+            )
+            .await;
+        }
+    };
 
-#[catch(404)]
-pub fn not_found_handler(_req: &Request) -> Redirect {
-    Redirect::to("/logs")
-}
\ No newline at end of file
+    let action = match batch.action.as_str() {
+        "enable" => KeyBatchAction::Enable,
+        "disable" => KeyBatchAction::Disable,
+        "delete" => KeyBatchAction::Delete,
+        "group" => {
+            let group = match batch.group_id.as_deref().map(Uuid::parse_str) {
+                Some(Ok(group_id)) => get_group_by_id(pool, group_id).await,
+                _ => Ok(None),
+            };
+            match group {
+                Ok(Some(group)) => KeyBatchAction::AddToGroup(group.id),
+                Ok(None) => {
+                    return keys_error(
+                        pool,
+                        user.0.site,
+                        format,
+                        Status::NotFound,
+                        "Group not found",
+                    )
+                    .await;
+                }
+                Err(e) => {
+                    error!(error = ?e, "Failed to load group");
+                    return keys_error(
+                        pool,
+                        user.0.site,
+                        format,
+                        Status::InternalServerError,
+                        "Failed to update keys",
+                    )
+                    .await;
+                }
+            }
+        }
+        // A bare date is the last day the keys work
+        "expiry" => match parse_bound(batch.expires_at.as_deref(), true) {
+            Some(expires_at) => KeyBatchAction::SetExpiry(expires_at),
+            None => {
+                return keys_error(
+                    pool,
+                    user.0.site,
+                    format,
+                    Status::BadRequest,
+                    "Invalid expiry date",
+                )
+                .await;
+            }
+        },
+        _ => {
+            return keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::BadRequest,
+                "Unknown batch action",
+            )
+            .await;
+        }
+    };
+
+    match batch_update_keys(pool, &key_ids, &action, user.0.site).await {
+        Ok(true) => {
+            audit
+                .record(
+                    &user.0.sub,
+                    &format!("key.batch.{}", batch.action),
+                    Some(&format!("{} keys", key_ids.len())),
+                )
+                .await;
+            Negotiated::done(
+                format,
+                "/keys",
+                Status::Ok,
+                serde_json::json!({ "action": batch.action, "key_ids": key_ids }),
+            )
+        }
+        Ok(false) => {
+            keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::NotFound,
+                "Some of the selected keys no longer exist. Nothing was changed.",
+            )
+            .await
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to update keys");
+            keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to update keys",
+            )
+            .await
+        }
+    }
+}
+
+const KEYS_CSV_HEADER: &str = "npub,profile_name,nip05,enabled,created_at\n";
+
+// Most rows one import may carry
+const MAX_IMPORT_ROWS: usize = 5000;
+
+// Number of a key's latest access log entries shown on its page
+const KEY_HISTORY_SIZE: i64 = 20;
+
+/// A key with its notes and tags, schedule, doors and latest access log entries. The entries
+/// are left out for API tokens without `read-logs`.
+#[get("/keys/<key_id>")]
+pub async fn key_page(
+    pool: &State<Pool<Postgres>>,
+    user: CanReadKeys,
+    format: Format,
+    key_id: String,
+) -> Negotiated {
+    let uuid = match Uuid::parse_str(&key_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::BadRequest,
+                "Invalid key ID",
+            )
+            .await;
+        }
+    };
+
+    let key = match get_key_by_id(pool, uuid, user.0.site).await {
+        Ok(Some(key)) => key,
+        Ok(None) => {
+            return keys_error(pool, user.0.site, format, Status::NotFound, "Key not found").await;
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to load key");
+            return keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to load key",
+            )
+            .await;
+        }
+    };
+
+    let schedules = get_key_schedules(pool, uuid).await;
+    let doors = get_key_doors(pool, uuid).await;
+    let history = if user.0.has_scope(Scope::ReadLogs) {
+        get_key_access_logs(pool, &key.npub, KEY_HISTORY_SIZE)
+            .await
+            .map(Some)
+    } else {
+        Ok(None)
+    };
+
+    match (schedules, doors, history) {
+        (Ok(schedules), Ok(doors), Ok(history)) => Negotiated::page(
+            format,
+            "key",
+            context! {
+                name: key.profile_name.as_ref().or(key.display_name.as_ref()),
+                tags: key.tags.join(", "),
+                key: key,
+                schedules: schedules,
+                day_names: DAY_NAMES,
+                doors: doors,
+                can_read_logs: history.is_some(),
+                history: history,
+            },
+        ),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+            error!(error = ?e, "Failed to load key details");
+            keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to load key",
+            )
+            .await
+        }
+    }
+}
+
+/// Replaces a key's notes and tags.
+#[post("/keys/<key_id>", data = "<details>")]
+pub async fn update_key(
+    pool: &State<Pool<Postgres>>,
+    user: CanManageKeys,
+    audit: Audit,
+    format: Format,
+    key_id: String,
+    details: Form<KeyDetailsRequest>,
+) -> Negotiated {
+    let uuid = match Uuid::parse_str(&key_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::BadRequest,
+                "Invalid key ID",
+            )
+            .await;
+        }
+    };
+
+    let notes = Some(details.notes.trim()).filter(|notes| !notes.is_empty());
+    let tags = parse_tags(&details.tags);
+    match update_key_details(pool, uuid, user.0.site, notes, &tags).await {
+        Ok(true) => {
+            audit
+                .record(&user.0.sub, "key.update", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                format!("/keys/{}", uuid),
+                Status::Ok,
+                serde_json::json!({ "id": uuid, "notes": notes, "tags": tags }),
+            )
+        }
+        Ok(false) => keys_error(pool, user.0.site, format, Status::NotFound, "Key not found").await,
+        Err(e) => {
+            error!(error = ?e, "Failed to update key");
+            keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to update key",
+            )
+            .await
+        }
+    }
+}
+
+/// The key's photo, for the keys page and the access log.
+#[get("/keys/<key_id>/photo")]
+pub async fn key_photo(
+    pool: &State<Pool<Postgres>>,
+    photos: &State<Photos>,
+    user: CanReadKeys,
+    key_id: &str,
+) -> Result<(ContentType, Vec<u8>), Negotiated> {
+    let Ok(uuid) = Uuid::parse_str(key_id) else {
+        return Err(Negotiated::error(Status::BadRequest, "Invalid key ID"));
+    };
+    let (photo, photo_type) = match get_key_by_id(pool, uuid, user.0.site).await {
+        Ok(Some(PublicKey {
+            photo: Some(photo),
+            photo_type,
+            ..
+        })) => (photo, photo_type),
+        Ok(_) => return Err(Negotiated::error(Status::NotFound, "Photo not found")),
+        Err(e) => {
+            error!(error = ?e, "Failed to load key");
+            return Err(Negotiated::error(
+                Status::InternalServerError,
+                "Failed to load key",
+            ));
+        }
+    };
+
+    match photos.store.get(&photo).await {
+        Ok(Some(body)) => {
+            let content_type = photo_type
+                .as_deref()
+                .and_then(ContentType::parse_flexible)
+                .unwrap_or(ContentType::Binary);
+            Ok((content_type, body))
+        }
+        Ok(None) => Err(Negotiated::error(Status::NotFound, "Photo not found")),
+        Err(e) => {
+            error!(error = %e, "Failed to load key photo");
+            Err(Negotiated::error(
+                Status::InternalServerError,
+                "Failed to load photo",
+            ))
+        }
+    }
+}
+
+/// Replaces the key's photo with an uploaded JPEG, PNG or WebP image of at most
+/// `photos.max_bytes`.
+#[post("/keys/<key_id>/photo", data = "<photo_request>")]
+pub async fn upload_key_photo(
+    pool: &State<Pool<Postgres>>,
+    photos: &State<Photos>,
+    user: CanManageKeys,
+    audit: Audit,
+    format: Format,
+    key_id: String,
+    photo_request: Form<KeyPhotoRequest<'_>>,
+) -> Negotiated {
+    let site = user.0.site;
+    let Ok(uuid) = Uuid::parse_str(&key_id) else {
+        return keys_error(pool, site, format, Status::BadRequest, "Invalid key ID").await;
+    };
+
+    if photo_request.photo.len() > photos.max_bytes {
+        return keys_error(
+            pool,
+            site,
+            format,
+            Status::PayloadTooLarge,
+            &format!("The photo may be at most {} KB.", photos.max_bytes / 1024),
+        )
+        .await;
+    }
+    let mut body = Vec::new();
+    let read = match photo_request.photo.open().await {
+        Ok(mut file) => file.read_to_end(&mut body).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    let image_type = read.ok().and_then(|()| image_type(&body));
+    let Some((content_type, extension)) = image_type else {
+        return keys_error(
+            pool,
+            site,
+            format,
+            Status::UnsupportedMediaType,
+            "The photo must be a JPEG, PNG or WebP image.",
+        )
+        .await;
+    };
+
+    let name = photo_name(uuid, extension);
+    if let Err(e) = photos.store.put(&name, body, content_type).await {
+        error!(error = %e, "Failed to store key photo");
+        return keys_error(
+            pool,
+            site,
+            format,
+            Status::InternalServerError,
+            "Failed to store photo",
+        )
+        .await;
+    }
+
+    let previous = match set_key_photo(pool, uuid, site, Some((&name, content_type))).await {
+        Ok(Some(previous)) => previous,
+        Ok(None) => {
+            discard_photo(photos, &name).await;
+            return keys_error(pool, site, format, Status::NotFound, "Key not found").await;
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to set key photo");
+            discard_photo(photos, &name).await;
+            return keys_error(
+                pool,
+                site,
+                format,
+                Status::InternalServerError,
+                "Failed to store photo",
+            )
+            .await;
+        }
+    };
+    if let Some(previous) = previous {
+        discard_photo(photos, &previous).await;
+    }
+
+    audit
+        .record(&user.0.sub, "key.photo", Some(&uuid.to_string()))
+        .await;
+    Negotiated::done(
+        format,
+        format!("/keys/{}", uuid),
+        Status::Ok,
+        serde_json::json!({ "id": uuid, "photo_url": format!("/keys/{}/photo", uuid) }),
+    )
+}
+
+/// Removes the key's photo.
+#[post("/keys/<key_id>/photo/delete")]
+pub async fn delete_key_photo(
+    pool: &State<Pool<Postgres>>,
+    photos: &State<Photos>,
+    user: CanManageKeys,
+    audit: Audit,
+    format: Format,
+    key_id: String,
+) -> Negotiated {
+    let site = user.0.site;
+    let Ok(uuid) = Uuid::parse_str(&key_id) else {
+        return keys_error(pool, site, format, Status::BadRequest, "Invalid key ID").await;
+    };
+
+    match set_key_photo(pool, uuid, site, None).await {
+        Ok(Some(previous)) => {
+            if let Some(previous) = previous {
+                discard_photo(photos, &previous).await;
+            }
+            audit
+                .record(&user.0.sub, "key.photo_delete", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                format!("/keys/{}", uuid),
+                Status::Ok,
+                serde_json::json!({ "id": uuid, "photo_url": null }),
+            )
+        }
+        Ok(None) => keys_error(pool, site, format, Status::NotFound, "Key not found").await,
+        Err(e) => {
+            error!(error = ?e, "Failed to remove key photo");
+            keys_error(
+                pool,
+                site,
+                format,
+                Status::InternalServerError,
+                "Failed to remove photo",
+            )
+            .await
+        }
+    }
+}
+
+// Helper function to remove a photo no key refers to anymore. A failure only leaves a stray
+// file behind, so it is logged and otherwise ignored.
+async fn discard_photo(photos: &Photos, name: &str) {
+    if let Err(e) = photos.store.delete(name).await {
+        warn!(error = %e, photo = name, "Failed to remove unused key photo");
+    }
+}
+
+// Helper function to turn comma separated tags into distinct, lowercase ones
+fn parse_tags(tags: &str) -> Vec<String> {
+    let mut parsed: Vec<String> = tags
+        .split(',')
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    parsed.sort();
+    parsed.dedup();
+    parsed
+}
+
+/// Printable badge for a key, with its name, Nostr picture and a QR code of its npub, for
+/// operators issuing physical reference cards.
+#[get("/keys/<key_id>/credential")]
+pub async fn key_credential(
+    pool: &State<Pool<Postgres>>,
+    user: CanReadKeys,
+    format: Format,
+    key_id: String,
+) -> Result<Page, Negotiated> {
+    let uuid = match Uuid::parse_str(&key_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return Err(keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::BadRequest,
+                "Invalid key ID",
+            )
+            .await);
+        }
+    };
+
+    let key = match get_key_by_id(pool, uuid, user.0.site).await {
+        Ok(Some(key)) => key,
+        Ok(None) => {
+            return Err(
+                keys_error(pool, user.0.site, format, Status::NotFound, "Key not found").await,
+            );
+        }
+        Err(_) => {
+            return Err(keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to load key",
+            )
+            .await);
+        }
+    };
+
+    let qr = render_qr(&key.npub, false)
+        .map(|svg| String::from_utf8_lossy(&svg).into_owned())
+        .map_err(|e| {
+            error!(error = %e, "Failed to render key QR code");
+            Negotiated::error(Status::InternalServerError, "Failed to render QR code")
+        })?;
+    // Inlined into the page, which has no use for the XML declaration
+    let qr = match qr.find("<svg") {
+        Some(start) => qr[start..].to_string(),
+        None => qr,
+    };
+
+    Ok(Page::render(
+        "credential",
+        context! {
+            name: key.profile_name.as_ref().or(key.display_name.as_ref()),
+            key: key,
+            qr: qr,
+        },
+    ))
+}
+
+/// All keys as a CSV or JSON download. The CSV can be fed back into `/keys/import`.
+#[get("/keys/export?<format>")]
+pub async fn export_keys(
+    pool: &State<Pool<Postgres>>,
+    user: CanReadKeys,
+    format: Option<&str>,
+) -> Result<Download<String>, Negotiated> {
+    let csv = match format.unwrap_or("csv") {
+        "csv" => true,
+        "json" => false,
+        _ => {
+            return Err(Negotiated::error(
+                Status::BadRequest,
+                "format must be csv or json",
+            ));
+        }
+    };
+
+    let keys = get_all_keys(pool, user.0.site).await.map_err(|e| {
+        error!(error = ?e, "Failed to load keys for export");
+        Negotiated::error(Status::InternalServerError, "Failed to load keys")
+    })?;
+
+    let (body, content_type, extension) = if csv {
+        let mut body = KEYS_CSV_HEADER.to_string();
+        for key in &keys {
+            let fields = [
+                key.npub.clone(),
+                key.profile_name.clone().unwrap_or_default(),
+                key.nip05.clone().unwrap_or_default(),
+                key.status.to_string(),
+                key.created_at.to_rfc3339(),
+            ];
+            let line = fields
+                .iter()
+                .map(|field| csv_field(field))
+                .collect::<Vec<_>>()
+                .join(",");
+            body.push_str(&line);
+            body.push('\n');
+        }
+        (body, ContentType::CSV, "csv")
+    } else {
+        let body = serde_json::to_string_pretty(&keys).unwrap_or_default();
+        (body, ContentType::JSON, "json")
+    };
+
+    Ok(Download {
+        body,
+        content_type,
+        disposition: Header::new(
+            "Content-Disposition",
+            format!("attachment; filename=\"keys.{}\"", extension),
+        ),
+    })
+}
+
+/// One key read from an import file.
+#[derive(serde::Deserialize)]
+struct ImportRow {
+    npub: String,
+    profile_name: Option<String>,
+    nip05: Option<String>,
+    // The JSON export calls this `status`
+    #[serde(alias = "status")]
+    enabled: Option<bool>,
+}
+
+#[derive(serde::Serialize)]
+struct ImportError {
+    row: usize,
+    npub: String,
+    error: String,
+}
+
+/// Adds keys in bulk from an uploaded CSV or JSON file (the format `/keys/export` writes).
+/// Every row is checked on its own: good rows are added and granted `door_ids`, bad ones
+/// are reported back by row number without stopping the rest.
+#[post("/keys/import", data = "<import_request>")]
+pub async fn import_keys(
+    pool: &State<Pool<Postgres>>,
+    portal: &State<PortalConnection>,
+    user: CanManageKeys,
+    audit: Audit,
+    format: Format,
+    import_request: Form<KeyImportRequest<'_>>,
+) -> Negotiated {
+    let door_ids: Result<Vec<Uuid>, _> = import_request
+        .door_ids
+        .iter()
+        .map(|id| Uuid::parse_str(id))
+        .collect();
+    let door_ids = match door_ids {
+        Ok(door_ids) => door_ids,
+        Err(_) => {
+            return keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::BadRequest,
+                "Invalid door ID",
+            )
+            .await;
+        }
+    };
+
+    let mut contents = String::new();
+    let read = match import_request.file.open().await {
+        Ok(mut file) => file.read_to_string(&mut contents).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if read.is_err() {
+        return keys_error(
+            pool,
+            user.0.site,
+            format,
+            Status::BadRequest,
+            "The import file must be UTF-8 text.",
+        )
+        .await;
+    }
+
+    let rows = match parse_import(&contents) {
+        Ok(rows) if rows.len() > MAX_IMPORT_ROWS => {
+            return keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::PayloadTooLarge,
+                &format!("An import may hold at most {} keys.", MAX_IMPORT_ROWS),
+            )
+            .await;
+        }
+        Ok(rows) => rows,
+        Err(e) => {
+            return keys_error(pool, user.0.site, format, Status::UnprocessableEntity, &e).await;
+        }
+    };
+
+    // Npubs are unique across sites, so every site's keys count
+    let mut known: HashSet<String> = match get_all_keys(pool, None).await {
+        Ok(keys) => keys.into_iter().map(|key| key.npub).collect(),
+        Err(e) => {
+            error!(error = ?e, "Failed to load keys for import");
+            return keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to load keys",
+            )
+            .await;
+        }
+    };
+
+    let deleted: HashSet<String> = get_deleted_keys(pool, DateTime::<Utc>::MIN_UTC, None)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .map(|key| key.npub)
+        .collect();
+
+    let mut imported = Vec::new();
+    let mut errors = Vec::new();
+    for (index, row) in rows.into_iter().enumerate() {
+        let npub = row.npub.trim().to_string();
+        let reject = |error: &str| ImportError {
+            row: index + 1,
+            npub: npub.clone(),
+            error: error.to_string(),
+        };
+
+        if !is_valid_npub(&npub) {
+            errors.push(reject(
+                "Invalid public key format. Must be a valid npub1 key.",
+            ));
+            continue;
+        }
+        if deleted.contains(&npub) {
+            errors.push(reject(
+                "Key was deleted. Restore it from the keys page instead.",
+            ));
+            continue;
+        }
+        if !known.insert(npub.clone()) {
+            errors.push(reject("Key already exists."));
+            continue;
+        }
+
+        let nip05 = non_empty(row.nip05);
+        let profile_name = non_empty(row.profile_name);
+        let key_id = match insert_key(
+            pool,
+            &npub,
+            nip05.as_deref(),
+            profile_name.as_deref(),
+            user.0.home_site(),
+        )
+        .await
+        {
+            Ok(key_id) => key_id,
+            Err(e) => {
+                error!(error = ?e, %npub, "Failed to import key");
+                errors.push(reject("Failed to add key."));
+                continue;
+            }
+        };
+
+        if let Err(e) = grant_key_doors(pool, key_id, &door_ids).await {
+            error!(error = ?e, %npub, "Failed to grant doors to imported key");
+            errors.push(reject("Key added, but assigning its doors failed."));
+        }
+        if row.enabled == Some(false)
+            && let Err(e) = toggle_key_status(pool, key_id, user.0.site).await
+        {
+            error!(error = ?e, %npub, "Failed to disable imported key");
+            errors.push(reject("Key added, but disabling it failed."));
+        }
+        if let Some(nip05) = nip05 {
+            spawn_verification(pool.inner().clone(), key_id, npub.clone(), nip05);
+        }
+        imported.push((key_id, npub));
+    }
+
+    if !imported.is_empty() {
+        audit
+            .record(
+                &user.0.sub,
+                "key.import",
+                Some(&format!("{} keys", imported.len())),
+            )
+            .await;
+    }
+
+    let count = imported.len();
+    let ids: Vec<Uuid> = imported.iter().map(|(key_id, _)| *key_id).collect();
+    profiles::spawn_fetch(
+        pool.inner().clone(),
+        Arc::new(portal.inner().clone()),
+        imported,
+    );
+
+    let status = if ids.is_empty() && !errors.is_empty() {
+        Status::UnprocessableEntity
+    } else {
+        Status::Ok
+    };
+    match format {
+        Format::Html => Negotiated::page(
+            format,
+            "keys",
+            context! {
+                keys: get_all_keys(pool, user.0.site).await.unwrap_or_default(),
+                pending_keys: get_pending_keys(pool, user.0.site).await.unwrap_or_default(),
+                deleted_keys: recently_deleted_keys(pool, user.0.site).await,
+                doors: get_all_doors(pool, user.0.site).await.unwrap_or_default(),
+                can_unlock: user.0.has_scope(Scope::Unlock),
+                success_message: (count > 0).then(|| format!("Imported {} keys.", count)),
+                import_errors: errors
+            },
+        ),
+        Format::Json => Negotiated::json(
+            status,
+            serde_json::json!({ "imported": count, "ids": ids, "errors": errors }),
+        ),
+    }
+}
+
+// Helper function to read an import file: a JSON array of keys, or CSV with a header row
+// naming the columns (`npub` is required, `profile_name`, `nip05` and `enabled` optional)
+fn parse_import(contents: &str) -> Result<Vec<ImportRow>, String> {
+    let contents = contents.trim_start_matches('\u{feff}');
+    if contents.trim_start().starts_with('[') {
+        return serde_json::from_str(contents)
+            .map_err(|e| format!("The import file is not a valid JSON key list: {}", e));
+    }
+
+    let mut records = parse_csv(contents)?.into_iter();
+    let header: Vec<String> = records
+        .next()
+        .ok_or("The import file is empty.")?
+        .iter()
+        .map(|column| column.trim().to_lowercase())
+        .collect();
+    let column = |name: &str| header.iter().position(|column| column == name);
+    let npub = column("npub").ok_or("The CSV header needs an npub column.")?;
+    let (profile_name, nip05, enabled) =
+        (column("profile_name"), column("nip05"), column("enabled"));
+
+    // Values the export defused with a leading quote are restored
+    let field = |record: &[String], index: Option<usize>| {
+        index
+            .and_then(|index| record.get(index))
+            .map(|value| match value.strip_prefix('\'') {
+                Some(rest) if rest.starts_with(['=', '+', '-', '@']) => rest.to_string(),
+                _ => value.clone(),
+            })
+    };
+
+    Ok(records
+        .filter(|record| record.iter().any(|value| !value.trim().is_empty()))
+        .map(|record| ImportRow {
+            npub: field(&record, Some(npub)).unwrap_or_default(),
+            profile_name: field(&record, profile_name),
+            nip05: field(&record, nip05),
+            enabled: field(&record, enabled).map(|value| value.trim() != "false"),
+        })
+        .collect())
+}
+
+// Helper function to split CSV text into records, honouring quoted fields
+fn parse_csv(contents: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = contents.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (true, c) => field.push(c),
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => record.push(std::mem::take(&mut field)),
+            (false, '\r') => {}
+            (false, '\n') => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            (false, c) => field.push(c),
+        }
+    }
+
+    if quoted {
+        return Err("The CSV file has an unterminated quoted field.".to_string());
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn non_empty(value: Option<String>) -> Option<String> {
+    value
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn is_valid_npub(npub: &str) -> bool {
+    npub.starts_with("npub1") && npub.len() == 63
+}
+
+/// Brings back a deleted key together with its doors, groups and schedules.
+#[post("/keys/<key_id>/restore")]
+pub async fn restore_key(
+    pool: &State<Pool<Postgres>>,
+    user: CanManageKeys,
+    audit: Audit,
+    format: Format,
+    key_id: String,
+) -> Negotiated {
+    let uuid = match Uuid::parse_str(&key_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::BadRequest,
+                "Invalid key ID",
+            )
+            .await;
+        }
+    };
+
+    match restore_key_by_id(pool, uuid, user.0.site).await {
+        Ok(true) => {
+            let key = get_key_by_id(pool, uuid, user.0.site).await.ok().flatten();
+            audit
+                .record(
+                    &user.0.sub,
+                    "key.restore",
+                    Some(&audit_key_target(key.as_ref(), uuid)),
+                )
+                .await;
+            Negotiated::done(
+                format,
+                "/keys",
+                Status::Ok,
+                serde_json::json!({ "id": uuid }),
+            )
+        }
+        Ok(false) => {
+            keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::NotFound,
+                "Deleted key not found",
+            )
+            .await
+        }
+        Err(_) => {
+            keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to restore key",
+            )
+            .await
+        }
+    }
+}
+
+/// Grants a request from the public access request page: the npub becomes an enabled key,
+/// with the doors picked on the keys page.
+#[post("/keys/pending/<pending_id>/approve", data = "<approval>")]
+pub async fn approve_key_request(
+    pool: &State<Pool<Postgres>>,
+    portal: &State<PortalConnection>,
+    user: CanManageKeys,
+    audit: Audit,
+    format: Format,
+    pending_id: String,
+    approval: Form<KeyApproval>,
+) -> Negotiated {
+    let uuid = match Uuid::parse_str(&pending_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::BadRequest,
+                "Invalid request ID",
+            )
+            .await;
+        }
+    };
+    let door_ids: Result<Vec<Uuid>, _> = approval
+        .door_ids
+        .iter()
+        .map(|id| Uuid::parse_str(id))
+        .collect();
+    let door_ids = match door_ids {
+        Ok(door_ids) => door_ids,
+        Err(_) => {
+            return keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::BadRequest,
+                "Invalid door ID",
+            )
+            .await;
+        }
+    };
+
+    let (key_id, npub) = match approve_pending_key(pool, uuid, user.0.site).await {
+        Ok(Some(approved)) => approved,
+        Ok(None) => {
+            return keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::NotFound,
+                "Access request not found",
+            )
+            .await;
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to approve access request");
+            return keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::Conflict,
+                "Failed to approve the request. The key may already exist, or be among the recently deleted keys.",
+            )
+            .await;
+        }
+    };
+
+    audit.record(&user.0.sub, "key.approve", Some(&npub)).await;
+    profiles::spawn_fetch(
+        pool.inner().clone(),
+        Arc::new(portal.inner().clone()),
+        vec![(key_id, npub.clone())],
+    );
+
+    match grant_key_doors(pool, key_id, &door_ids).await {
+        Ok(_) => Negotiated::done(
+            format,
+            "/keys",
+            Status::Created,
+            serde_json::json!({ "id": key_id, "npub": npub, "door_ids": door_ids }),
+        ),
+        Err(_) => {
+            keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Key added, but assigning its doors failed. Assign them from the Doors page.",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/keys/pending/<pending_id>/deny")]
+pub async fn deny_key_request(
+    pool: &State<Pool<Postgres>>,
+    user: CanManageKeys,
+    audit: Audit,
+    format: Format,
+    pending_id: String,
+) -> Negotiated {
+    let uuid = match Uuid::parse_str(&pending_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::BadRequest,
+                "Invalid request ID",
+            )
+            .await;
+        }
+    };
+
+    match delete_pending_key(pool, uuid, user.0.site).await {
+        Ok(Some(npub)) => {
+            audit.record(&user.0.sub, "key.deny", Some(&npub)).await;
+            Negotiated::done(
+                format,
+                "/keys",
+                Status::Ok,
+                serde_json::json!({ "id": uuid }),
+            )
+        }
+        Ok(None) => {
+            keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::NotFound,
+                "Access request not found",
+            )
+            .await
+        }
+        Err(_) => {
+            keys_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to deny the request",
+            )
+            .await
+        }
+    }
+}
+
+// Helper function to list the keys of `site` deleted within RECENTLY_DELETED_DAYS
+async fn recently_deleted_keys(pool: &Pool<Postgres>, site: Option<Uuid>) -> Vec<PublicKey> {
+    let since = Utc::now() - chrono::Duration::days(RECENTLY_DELETED_DAYS);
+    get_deleted_keys(pool, since, site)
+        .await
+        .unwrap_or_default()
+}
+
+// Helper function to name a key in the audit trail by its npub, falling back to its id
+fn audit_key_target(key: Option<&PublicKey>, key_id: Uuid) -> String {
+    key.map(|key| key.npub.clone())
+        .unwrap_or_else(|| key_id.to_string())
+}
+
+// Helper function to report a key management error in the requested format
+async fn keys_error(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+    format: Format,
+    status: Status,
+    error_message: &str,
+) -> Negotiated {
+    match format {
+        Format::Html => Negotiated::Page(render_keys_with_error(pool, site, error_message).await),
+        Format::Json => Negotiated::error(status, error_message),
+    }
+}
+
+// Helper function to render keys template with error message
+async fn render_keys_with_error(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+    error_message: &str,
+) -> Page {
+    let filter = KeyFilter {
+        site,
+        ..KeyFilter::default()
+    };
+    match search_keys(pool, &filter, KeyOrder::default(), KEYS_PAGE_SIZE, 0).await {
+        Ok(keys) => Page::render(
+            "keys",
+            context! {
+                keys: keys,
+                sort: KeyOrder::default().as_str(),
+                status: filter.status.as_str(),
+                pending_keys: get_pending_keys(pool, site).await.unwrap_or_default(),
+                doors: get_all_doors(pool, site).await.unwrap_or_default(),
+                groups: get_all_groups(pool).await.unwrap_or_default(),
+                error_message: error_message
+            },
+        ),
+        Err(_) => Page::render(
+            "keys",
+            context! {
+                error_message: error_message
+            },
+        ),
+    }
+}
+
+#[catch(401)]
+pub fn unauthorized_handler(req: &Request) -> Negotiated {
+    match Format::of(req) {
+        Format::Html => Negotiated::redirect("/login"),
+        Format::Json => Negotiated::error(Status::Unauthorized, "Authentication required"),
+    }
+}
+
+#[catch(403)]
+pub fn forbidden_handler(req: &Request) -> Negotiated {
+    if req.local_cache(|| PasswordExpired(false)).0 {
+        return match Format::of(req) {
+            Format::Html => Negotiated::redirect(PASSWORD_PATH),
+            Format::Json => Negotiated::error(
+                Status::Forbidden,
+                "Your password has expired. Change it at /settings/password.",
+            ),
+        };
+    }
+    if req.local_cache(|| OutsideAllowlist(false)).0 {
+        return match Format::of(req) {
+            Format::Html => Negotiated::page(Format::Html, "forbidden", context! { network: true }),
+            Format::Json => Negotiated::error(
+                Status::Forbidden,
+                "The admin panel cannot be used from this network",
+            ),
+        };
+    }
+
+    match Format::of(req) {
+        Format::Html => Negotiated::page(Format::Html, "forbidden", context! {}),
+        Format::Json => {
+            Negotiated::error(Status::Forbidden, "Your role does not allow this action")
+        }
+    }
+}
+
+#[catch(404)]
+pub fn not_found_handler(req: &Request) -> Negotiated {
+    match Format::of(req) {
+        Format::Html => Negotiated::redirect("/logs"),
+        Format::Json => Negotiated::error(Status::NotFound, "Not found"),
+    }
+}
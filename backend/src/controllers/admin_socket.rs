@@ -0,0 +1,78 @@
+use crate::auth::AdminUser;
+use crate::database::health::DbHealth;
+use crate::portal_loop::AccessController;
+use crate::relays::{PortalConnection, PortalIdentities};
+use rocket::futures::{SinkExt, StreamExt};
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::tokio::{select, time::interval};
+use rocket::{Shutdown, State, get};
+use rocket_ws::{Channel, Message, WebSocket};
+use std::time::Duration;
+
+// How often connected browsers get a health snapshot
+const HEALTH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// WebSocket for a live control-room view. Pushes door state changes, doorbell rings, every
+/// new access log entry and a health snapshot of the background tasks, one JSON message each,
+/// tagged with `type` (`door`, `doorbell`, `task`, `access` and `health`). Access entries and
+/// doorbell rings are limited to the admin's site. The channel only pushes; anything the
+/// browser sends is ignored.
+#[get("/ws/admin")]
+pub fn admin_socket(
+    ws: WebSocket,
+    user: AdminUser,
+    controller: &State<AccessController>,
+    portal: &State<PortalConnection>,
+    identities: &State<PortalIdentities>,
+    db_health: &State<DbHealth>,
+    shutdown: Shutdown,
+) -> Channel<'static> {
+    let site = user.0.site;
+    let mut entries = controller.log_feed.subscribe();
+    let mut events = controller.admin_feed.subscribe();
+    let identities = identities.inner().clone();
+    let portal = portal.inner().clone();
+    let db_health = db_health.inner().clone();
+
+    ws.channel(move |mut stream| {
+        Box::pin(async move {
+            let mut shutdown = shutdown;
+            // The first tick fires right away, so a new viewer starts with a snapshot
+            let mut health = interval(HEALTH_INTERVAL);
+            loop {
+                let message = select! {
+                    entry = entries.recv() => match entry {
+                        Ok(entry) if entry.in_site(site) => {
+                            serde_json::json!({ "type": "access", "entry": entry })
+                        }
+                        Ok(_) => continue,
+                        Err(RecvError::Closed) => break,
+                        // A viewer that fell behind skips ahead; a reload shows what it missed
+                        Err(RecvError::Lagged(_)) => continue,
+                    },
+                    event = events.recv() => match event {
+                        Ok(event) if event.in_site(site) => serde_json::json!(event),
+                        Ok(_) => continue,
+                        Err(RecvError::Closed) => break,
+                        Err(RecvError::Lagged(_)) => continue,
+                    },
+                    _ = health.tick() => serde_json::json!({
+                        "type": "health",
+                        "database": db_health.is_healthy(),
+                        "portal_connected": identities.all_connected(),
+                        "relays": portal.statuses(),
+                    }),
+                    incoming = stream.next() => match incoming {
+                        Some(Ok(Message::Close(_))) | None => break,
+                        Some(Ok(_)) => continue,
+                        Some(Err(e)) => return Err(e),
+                    },
+                    _ = &mut shutdown => break,
+                };
+                stream.send(Message::Text(message.to_string())).await?;
+            }
+
+            Ok(())
+        })
+    })
+}
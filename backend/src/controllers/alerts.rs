@@ -0,0 +1,298 @@
+use crate::alerts::{AlertKind, Alerter};
+use crate::audit::Audit;
+use crate::auth::AdminUser;
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::database::helpers::{
+    delete_alert_channel as delete_alert_channel_by_id, get_alert_channel, get_user_alert_channels,
+    insert_alert_channel,
+};
+use rocket::{State, form::Form, get, http::Status, post};
+use rocket_dyn_templates::context;
+use sqlx::{Pool, Postgres};
+use tracing::error;
+use uuid::Uuid;
+
+#[derive(rocket::form::FromForm)]
+pub struct AlertChannelRequest {
+    kind: String,
+    target: String,
+    triggers: Vec<String>,
+}
+
+#[get("/alerts")]
+pub async fn alerts_page(
+    pool: &State<Pool<Postgres>>,
+    alerter: &State<Alerter>,
+    user: AdminUser,
+    format: Format,
+) -> Negotiated {
+    match get_user_alert_channels(pool, &user.0.sub).await {
+        Ok(channels) => Negotiated::page(
+            format,
+            "alerts",
+            context! {
+                channels: channels,
+                triggers: trigger_options(),
+                email_enabled: alerter.supports("email"),
+                telegram_enabled: alerter.supports("telegram")
+            },
+        ),
+        Err(e) => {
+            error!(error = ?e, "Failed to load alert channels");
+            alerts_error(
+                pool,
+                alerter,
+                &user,
+                format,
+                Status::InternalServerError,
+                "Failed to load alert channels",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/alerts", data = "<channel_request>")]
+pub async fn add_alert_channel(
+    pool: &State<Pool<Postgres>>,
+    alerter: &State<Alerter>,
+    user: AdminUser,
+    audit: Audit,
+    format: Format,
+    channel_request: Form<AlertChannelRequest>,
+) -> Negotiated {
+    let kind = channel_request.kind.as_str();
+    let target = channel_request.target.trim();
+
+    let problem = match kind {
+        "email" | "telegram" if !alerter.supports(kind) => {
+            Some("This kind of alert is not configured on the server.")
+        }
+        "email" if target.parse::<lettre::message::Mailbox>().is_err() => {
+            Some("Enter a valid email address.")
+        }
+        "telegram" if target.is_empty() || target.contains(char::is_whitespace) => {
+            Some("Enter a Telegram chat ID, such as 123456789 or @channel.")
+        }
+        "email" | "telegram" => None,
+        _ => Some("Alerts go by email or Telegram."),
+    };
+    let problem = problem.or_else(|| {
+        if channel_request.triggers.is_empty() {
+            Some("Pick at least one trigger.")
+        } else if channel_request
+            .triggers
+            .iter()
+            .any(|trigger| AlertKind::parse(trigger).is_none())
+        {
+            Some("Unknown alert trigger.")
+        } else {
+            None
+        }
+    });
+    if let Some(problem) = problem {
+        return alerts_error(
+            pool,
+            alerter,
+            &user,
+            format,
+            Status::UnprocessableEntity,
+            problem,
+        )
+        .await;
+    }
+
+    match insert_alert_channel(pool, &user.0.sub, kind, target, &channel_request.triggers).await {
+        Ok(id) => {
+            audit
+                .record(
+                    &user.0.sub,
+                    "alert.add",
+                    Some(&format!("{}:{}", kind, target)),
+                )
+                .await;
+            Negotiated::done(
+                format,
+                "/alerts",
+                Status::Created,
+                serde_json::json!({ "id": id }),
+            )
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to add alert channel");
+            alerts_error(
+                pool,
+                alerter,
+                &user,
+                format,
+                Status::InternalServerError,
+                "Failed to add alert channel",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/alerts/<channel_id>/test")]
+pub async fn test_alert_channel(
+    pool: &State<Pool<Postgres>>,
+    alerter: &State<Alerter>,
+    user: AdminUser,
+    format: Format,
+    channel_id: String,
+) -> Negotiated {
+    let uuid = match Uuid::parse_str(&channel_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return alerts_error(
+                pool,
+                alerter,
+                &user,
+                format,
+                Status::BadRequest,
+                "Invalid alert channel ID",
+            )
+            .await;
+        }
+    };
+
+    let channel = match get_alert_channel(pool, uuid, &user.0.sub).await {
+        Ok(Some(channel)) => channel,
+        Ok(None) => {
+            return alerts_error(
+                pool,
+                alerter,
+                &user,
+                format,
+                Status::NotFound,
+                "Alert channel not found",
+            )
+            .await;
+        }
+        Err(_) => {
+            return alerts_error(
+                pool,
+                alerter,
+                &user,
+                format,
+                Status::InternalServerError,
+                "Failed to load alert channel",
+            )
+            .await;
+        }
+    };
+
+    match alerter.send_test(&channel).await {
+        Ok(()) => Negotiated::done(
+            format,
+            "/alerts",
+            Status::Ok,
+            serde_json::json!({ "id": uuid, "sent": true }),
+        ),
+        Err(e) => {
+            alerts_error(
+                pool,
+                alerter,
+                &user,
+                format,
+                Status::BadGateway,
+                &format!("Failed to send test alert: {}", e),
+            )
+            .await
+        }
+    }
+}
+
+#[post("/alerts/<channel_id>/delete")]
+pub async fn delete_alert_channel(
+    pool: &State<Pool<Postgres>>,
+    alerter: &State<Alerter>,
+    user: AdminUser,
+    audit: Audit,
+    format: Format,
+    channel_id: String,
+) -> Negotiated {
+    let uuid = match Uuid::parse_str(&channel_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return alerts_error(
+                pool,
+                alerter,
+                &user,
+                format,
+                Status::BadRequest,
+                "Invalid alert channel ID",
+            )
+            .await;
+        }
+    };
+
+    match delete_alert_channel_by_id(pool, uuid, &user.0.sub).await {
+        Ok(true) => {
+            audit
+                .record(&user.0.sub, "alert.delete", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                "/alerts",
+                Status::Ok,
+                serde_json::json!({ "id": uuid }),
+            )
+        }
+        Ok(false) => {
+            alerts_error(
+                pool,
+                alerter,
+                &user,
+                format,
+                Status::NotFound,
+                "Alert channel not found",
+            )
+            .await
+        }
+        Err(_) => {
+            alerts_error(
+                pool,
+                alerter,
+                &user,
+                format,
+                Status::InternalServerError,
+                "Failed to delete alert channel",
+            )
+            .await
+        }
+    }
+}
+
+// Trigger checkboxes for the add form
+fn trigger_options() -> Vec<serde_json::Value> {
+    AlertKind::ALL
+        .into_iter()
+        .map(|kind| serde_json::json!({ "value": kind.as_str(), "label": kind.label() }))
+        .collect()
+}
+
+// Helper function to report an alert channel error in the requested format
+async fn alerts_error(
+    pool: &Pool<Postgres>,
+    alerter: &Alerter,
+    user: &AdminUser,
+    format: Format,
+    status: Status,
+    error_message: &str,
+) -> Negotiated {
+    match format {
+        Format::Html => Negotiated::page(
+            format,
+            "alerts",
+            context! {
+                channels: get_user_alert_channels(pool, &user.0.sub).await.unwrap_or_default(),
+                triggers: trigger_options(),
+                email_enabled: alerter.supports("email"),
+                telegram_enabled: alerter.supports("telegram"),
+                error_message: error_message
+            },
+        ),
+        Format::Json => Negotiated::error(status, error_message),
+    }
+}
@@ -0,0 +1,57 @@
+use crate::openapi::ApiDocs;
+use rocket::http::Header;
+use rocket::response::content::RawHtml;
+use rocket::serde::json::Json;
+use rocket::{State, get};
+
+/// Swagger UI page, with a Content-Security-Policy that lets it load its assets.
+#[derive(rocket::Responder)]
+pub struct SwaggerUi {
+    body: RawHtml<String>,
+    content_security_policy: Header<'static>,
+}
+
+/// The OpenAPI 3 document of the JSON API, for generating clients. Also served as
+/// `/api/openapi.json`, which `JsonExtension` rewrites to this route.
+#[get("/api/openapi")]
+pub fn openapi_document(docs: &State<ApiDocs>) -> Json<serde_json::Value> {
+    Json(docs.document.clone())
+}
+
+/// Swagger UI for the OpenAPI document. Requests made from it carry the session cookie, so
+/// they run as the signed-in user.
+#[get("/api/docs")]
+pub fn swagger_ui(docs: &State<ApiDocs>) -> Option<SwaggerUi> {
+    let url = docs.swagger_ui_url.as_deref()?;
+    let origin = docs.swagger_ui_origin()?;
+
+    let body = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <title>Portal Access Control API</title>
+    <link rel="stylesheet" href="{url}/swagger-ui.css">
+</head>
+<body>
+    <div id="swagger-ui"></div>
+    <script src="{url}/swagger-ui-bundle.js"></script>
+    <script>
+        SwaggerUIBundle({{ url: '/api/openapi.json', dom_id: '#swagger-ui' }});
+    </script>
+</body>
+</html>
+"#
+    );
+    Some(SwaggerUi {
+        body: RawHtml(body),
+        content_security_policy: Header::new(
+            "Content-Security-Policy",
+            format!(
+                "default-src 'self'; script-src 'self' 'unsafe-inline' {origin}; \
+                 style-src 'self' 'unsafe-inline' {origin}; img-src 'self' data: {origin}; \
+                 frame-ancestors 'none'; base-uri 'self'"
+            ),
+        ),
+    })
+}
@@ -0,0 +1,203 @@
+use crate::audit::Audit;
+use crate::auth::{AllSitesAdmin, Scope, generate_api_token, hash_api_token};
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::database::helpers::{
+    delete_api_token_by_id, get_all_api_tokens, get_all_sites, insert_api_token,
+};
+use rocket::{State, form::Form, get, http::Status, post};
+use rocket_dyn_templates::context;
+use sqlx::{Pool, Postgres};
+use tracing::error;
+use uuid::Uuid;
+
+#[derive(rocket::form::FromForm)]
+pub struct ApiTokenRequest {
+    name: String,
+    scopes: Vec<String>,
+    site_id: Option<String>, // empty for every site
+}
+
+#[get("/api-tokens")]
+pub async fn api_tokens_page(
+    pool: &State<Pool<Postgres>>,
+    _user: AllSitesAdmin,
+    format: Format,
+) -> Negotiated {
+    match get_all_api_tokens(pool).await {
+        Ok(tokens) => Negotiated::page(
+            format,
+            "api_tokens",
+            context! {
+                tokens: tokens,
+                scopes: scope_names(),
+                sites: get_all_sites(pool).await.unwrap_or_default()
+            },
+        ),
+        Err(e) => {
+            error!(error = ?e, "Failed to load API tokens");
+            api_tokens_error(
+                pool,
+                format,
+                Status::InternalServerError,
+                "Failed to load API tokens",
+            )
+            .await
+        }
+    }
+}
+
+/// Mints a token. It is only ever shown in this response, so browsers get the page back with
+/// the token on it instead of a redirect.
+#[post("/api-tokens", data = "<token_request>")]
+pub async fn add_api_token(
+    pool: &State<Pool<Postgres>>,
+    user: AllSitesAdmin,
+    audit: Audit,
+    format: Format,
+    token_request: Form<ApiTokenRequest>,
+) -> Negotiated {
+    let name = token_request.name.trim();
+    if name.is_empty() {
+        return api_tokens_error(
+            pool,
+            format,
+            Status::UnprocessableEntity,
+            "A token needs a name.",
+        )
+        .await;
+    }
+    if token_request.scopes.is_empty() {
+        return api_tokens_error(
+            pool,
+            format,
+            Status::UnprocessableEntity,
+            "Pick at least one scope.",
+        )
+        .await;
+    }
+    if let Some(e) = token_request
+        .scopes
+        .iter()
+        .find_map(|scope| scope.parse::<Scope>().err())
+    {
+        return api_tokens_error(pool, format, Status::UnprocessableEntity, &e).await;
+    }
+    let site = match token_request.site_id.as_deref().filter(|id| !id.is_empty()) {
+        Some(id) => match Uuid::parse_str(id) {
+            Ok(site) => Some(site),
+            Err(_) => {
+                return api_tokens_error(pool, format, Status::BadRequest, "Invalid site ID").await;
+            }
+        },
+        None => None,
+    };
+
+    let token = generate_api_token();
+    match insert_api_token(
+        pool,
+        name,
+        &hash_api_token(&token),
+        &token_request.scopes,
+        &user.0.sub,
+        site,
+    )
+    .await
+    {
+        Ok(id) => {
+            audit.record(&user.0.sub, "api_token.add", Some(name)).await;
+            match format {
+                Format::Html => Negotiated::page(
+                    format,
+                    "api_tokens",
+                    context! {
+                        tokens: get_all_api_tokens(pool).await.unwrap_or_default(),
+                        scopes: scope_names(),
+                        sites: get_all_sites(pool).await.unwrap_or_default(),
+                        new_token: token
+                    },
+                ),
+                Format::Json => Negotiated::json(
+                    Status::Created,
+                    serde_json::json!({ "id": id, "token": token }),
+                ),
+            }
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to add API token");
+            api_tokens_error(
+                pool,
+                format,
+                Status::InternalServerError,
+                "Failed to add API token",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/api-tokens/<token_id>/delete")]
+pub async fn delete_api_token(
+    pool: &State<Pool<Postgres>>,
+    user: AllSitesAdmin,
+    audit: Audit,
+    format: Format,
+    token_id: String,
+) -> Negotiated {
+    let uuid = match Uuid::parse_str(&token_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return api_tokens_error(pool, format, Status::BadRequest, "Invalid token ID").await;
+        }
+    };
+
+    match delete_api_token_by_id(pool, uuid).await {
+        Ok(true) => {
+            audit
+                .record(&user.0.sub, "api_token.delete", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                "/api-tokens",
+                Status::Ok,
+                serde_json::json!({ "id": uuid }),
+            )
+        }
+        Ok(false) => api_tokens_error(pool, format, Status::NotFound, "Token not found").await,
+        Err(_) => {
+            api_tokens_error(
+                pool,
+                format,
+                Status::InternalServerError,
+                "Failed to revoke API token",
+            )
+            .await
+        }
+    }
+}
+
+// Scope checkboxes for the add form
+fn scope_names() -> Vec<&'static str> {
+    Scope::ALL.iter().map(Scope::as_str).collect()
+}
+
+// Helper function to report an API token management error in the requested format
+async fn api_tokens_error(
+    pool: &Pool<Postgres>,
+    format: Format,
+    status: Status,
+    error_message: &str,
+) -> Negotiated {
+    match format {
+        Format::Html => Negotiated::page(
+            format,
+            "api_tokens",
+            context! {
+                tokens: get_all_api_tokens(pool).await.unwrap_or_default(),
+                scopes: scope_names(),
+                sites: get_all_sites(pool).await.unwrap_or_default(),
+                error_message: error_message
+            },
+        ),
+        Format::Json => Negotiated::error(status, error_message),
+    }
+}
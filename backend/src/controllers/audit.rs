@@ -0,0 +1,139 @@
+use crate::audit::Audit;
+use crate::auth::AllSitesAdmin;
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::database::helpers::{
+    clear_login_failures, count_audit_entries, get_audit_entries, get_recent_login_failures,
+};
+use rocket::{State, get, http::Status, post};
+use rocket_dyn_templates::context;
+use sqlx::{Pool, Postgres};
+use tracing::{error, info};
+
+// Number of audit entries shown per page
+const AUDIT_PAGE_SIZE: i64 = 50;
+
+// Number of client IPs shown on the failed login page
+const RECENT_LOGIN_FAILURES: i64 = 100;
+
+#[get("/audit?<page>")]
+pub async fn audit_page(
+    pool: &State<Pool<Postgres>>,
+    _user: AllSitesAdmin,
+    format: Format,
+    page: Option<i64>,
+) -> Negotiated {
+    let page = page.unwrap_or(1).max(1);
+
+    let entries = get_audit_entries(
+        pool,
+        AUDIT_PAGE_SIZE,
+        page.saturating_sub(1).saturating_mul(AUDIT_PAGE_SIZE),
+    )
+    .await;
+    let total = count_audit_entries(pool).await;
+
+    match (entries, total) {
+        (Ok(entries), Ok(total)) => {
+            let total_pages = ((total + AUDIT_PAGE_SIZE - 1) / AUDIT_PAGE_SIZE).max(1);
+            Negotiated::page(
+                format,
+                "audit",
+                context! {
+                    entries: entries,
+                    page: page,
+                    total_pages: total_pages,
+                    prev_page: (page > 1).then(|| page - 1),
+                    next_page: (page < total_pages).then(|| page + 1)
+                },
+            )
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            error!(error = ?e, "Failed to load the audit trail");
+            match format {
+                Format::Html => Negotiated::page(
+                    format,
+                    "audit",
+                    context! {
+                        error_message: "Failed to load the audit trail"
+                    },
+                ),
+                Format::Json => Negotiated::error(
+                    Status::InternalServerError,
+                    "Failed to load the audit trail",
+                ),
+            }
+        }
+    }
+}
+
+#[get("/audit/logins")]
+pub async fn login_failures_page(
+    pool: &State<Pool<Postgres>>,
+    _user: AllSitesAdmin,
+    format: Format,
+) -> Negotiated {
+    match get_recent_login_failures(pool, RECENT_LOGIN_FAILURES).await {
+        Ok(failures) => Negotiated::page(format, "login_failures", context! { failures: failures }),
+        Err(e) => {
+            error!(error = ?e, "Failed to load failed logins");
+            login_failures_error(
+                pool,
+                format,
+                Status::InternalServerError,
+                "Failed to load failed logins",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/audit/logins/<ip>/clear")]
+pub async fn clear_login_lockout(
+    pool: &State<Pool<Postgres>>,
+    user: AllSitesAdmin,
+    audit: Audit,
+    format: Format,
+    ip: String,
+) -> Negotiated {
+    match clear_login_failures(pool, &ip).await {
+        Ok(_) => {
+            info!(%ip, by = %user.0.sub, "Login lockout cleared");
+            audit.record(&user.0.sub, "login.unlock", Some(&ip)).await;
+            Negotiated::done(
+                format,
+                "/audit/logins",
+                Status::Ok,
+                serde_json::json!({ "ip": ip }),
+            )
+        }
+        Err(_) => {
+            login_failures_error(
+                pool,
+                format,
+                Status::InternalServerError,
+                "Failed to clear the lockout",
+            )
+            .await
+        }
+    }
+}
+
+// Helper function to report a failed login page error in the requested format
+async fn login_failures_error(
+    pool: &Pool<Postgres>,
+    format: Format,
+    status: Status,
+    error_message: &str,
+) -> Negotiated {
+    match format {
+        Format::Html => Negotiated::page(
+            format,
+            "login_failures",
+            context! {
+                failures: get_recent_login_failures(pool, RECENT_LOGIN_FAILURES).await.unwrap_or_default(),
+                error_message: error_message
+            },
+        ),
+        Format::Json => Negotiated::error(status, error_message),
+    }
+}
@@ -0,0 +1,167 @@
+use crate::audit::Audit;
+use crate::auth::{CanManageKeys, CanReadKeys};
+use crate::card_readers::normalize_card;
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::controllers::site_key;
+use crate::database::helpers::{
+    delete_credential_by_id, get_key_by_id, get_key_credentials, insert_credential,
+};
+use rocket::{State, form::Form, get, http::Status, post};
+use rocket_dyn_templates::context;
+use sqlx::{Pool, Postgres};
+use tracing::error;
+use uuid::Uuid;
+
+#[derive(rocket::form::FromForm)]
+pub struct CardRequest {
+    card_number: String,
+    label: Option<String>,
+}
+
+/// The badge cards that stand in for a key at the doors' badge readers.
+#[get("/keys/<key_id>/cards")]
+pub async fn cards_page(
+    pool: &State<Pool<Postgres>>,
+    user: CanReadKeys,
+    format: Format,
+    key_id: String,
+) -> Negotiated {
+    match site_key(pool, &key_id, user.0.site).await {
+        Ok(uuid) => render_cards(pool, format, uuid, Status::Ok, None).await,
+        Err(error) => error,
+    }
+}
+
+#[post("/keys/<key_id>/cards", data = "<card_request>")]
+pub async fn add_card(
+    pool: &State<Pool<Postgres>>,
+    user: CanManageKeys,
+    audit: Audit,
+    format: Format,
+    key_id: String,
+    card_request: Form<CardRequest>,
+) -> Negotiated {
+    let uuid = match site_key(pool, &key_id, user.0.site).await {
+        Ok(uuid) => uuid,
+        Err(error) => return error,
+    };
+
+    let Some(card_number) = normalize_card(&card_request.card_number) else {
+        return render_cards(
+            pool,
+            format,
+            uuid,
+            Status::UnprocessableEntity,
+            Some("Card numbers may only hold letters and digits."),
+        )
+        .await;
+    };
+    let label = card_request
+        .label
+        .as_deref()
+        .map(str::trim)
+        .filter(|label| !label.is_empty());
+
+    match insert_credential(pool, uuid, &card_number, label).await {
+        Ok(Some(id)) => {
+            audit
+                .record(&user.0.sub, "card.add", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                format!("/keys/{}/cards", uuid),
+                Status::Created,
+                serde_json::json!({ "id": id, "card_number": card_number }),
+            )
+        }
+        Ok(None) => {
+            render_cards(
+                pool,
+                format,
+                uuid,
+                Status::Conflict,
+                Some("This card already belongs to a key."),
+            )
+            .await
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to add card");
+            render_cards(
+                pool,
+                format,
+                uuid,
+                Status::InternalServerError,
+                Some("Failed to add card. The key may no longer exist."),
+            )
+            .await
+        }
+    }
+}
+
+#[post("/keys/<key_id>/cards/<card_id>/delete")]
+pub async fn delete_card(
+    pool: &State<Pool<Postgres>>,
+    user: CanManageKeys,
+    audit: Audit,
+    format: Format,
+    key_id: String,
+    card_id: String,
+) -> Negotiated {
+    let Ok(card_uuid) = Uuid::parse_str(&card_id) else {
+        return Negotiated::error(Status::BadRequest, "Invalid card ID");
+    };
+    let uuid = match site_key(pool, &key_id, user.0.site).await {
+        Ok(uuid) => uuid,
+        Err(error) => return error,
+    };
+
+    match delete_credential_by_id(pool, uuid, card_uuid).await {
+        Ok(true) => {
+            audit
+                .record(&user.0.sub, "card.delete", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                format!("/keys/{}/cards", uuid),
+                Status::Ok,
+                serde_json::json!({ "id": card_uuid }),
+            )
+        }
+        Ok(false) => {
+            render_cards(pool, format, uuid, Status::NotFound, Some("Card not found")).await
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to delete card");
+            render_cards(
+                pool,
+                format,
+                uuid,
+                Status::InternalServerError,
+                Some("Failed to delete card"),
+            )
+            .await
+        }
+    }
+}
+
+// Helper function to render a key's cards, or report an error, in the requested format
+async fn render_cards(
+    pool: &Pool<Postgres>,
+    format: Format,
+    key_id: Uuid,
+    status: Status,
+    error_message: Option<&str>,
+) -> Negotiated {
+    match (format, error_message) {
+        (Format::Json, Some(error_message)) => Negotiated::error(status, error_message),
+        _ => Negotiated::page(
+            format,
+            "cards",
+            context! {
+                key: get_key_by_id(pool, key_id, None).await.ok().flatten(),
+                cards: get_key_credentials(pool, key_id).await.unwrap_or_default(),
+                error_message: error_message
+            },
+        ),
+    }
+}
@@ -0,0 +1,148 @@
+use crate::alerts::AlertKind;
+use crate::auth::ViewerUser;
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::database::helpers::{
+    Anomaly, Site, get_daily_unlocks, get_hourly_unlocks, get_recent_anomalies, get_result_counts,
+    get_site_occupancy, get_weekly_unique_keys,
+};
+use rocket::{State, get, http::Status};
+use rocket_dyn_templates::context;
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+use tracing::error;
+
+// Periods the dashboard covers
+const DASHBOARD_DAYS: i32 = 30;
+const DASHBOARD_WEEKS: i32 = 12;
+const ANOMALY_DAYS: i32 = 7;
+const ANOMALY_LIMIT: i64 = 20;
+
+/// Usage statistics over the access log. Every series comes with a `percent` of its largest
+/// value so the page can draw bars without a charting library; API clients can ignore it.
+/// Anomalies flagged over the last week are listed on top, see `anomalies`.
+#[get("/dashboard")]
+pub async fn dashboard_page(
+    pool: &State<Pool<Postgres>>,
+    user: ViewerUser,
+    format: Format,
+) -> Negotiated {
+    let stats = rocket::tokio::try_join!(
+        get_daily_unlocks(pool, user.0.site, DASHBOARD_DAYS),
+        get_weekly_unique_keys(pool, user.0.site, DASHBOARD_WEEKS),
+        get_hourly_unlocks(pool, user.0.site, DASHBOARD_DAYS),
+        get_result_counts(pool, user.0.site, DASHBOARD_DAYS),
+        get_recent_anomalies(pool, user.0.site, ANOMALY_DAYS, ANOMALY_LIMIT),
+        get_site_occupancy(pool, user.0.site),
+    );
+
+    match stats {
+        Ok((daily, weekly, hourly, results, anomalies, occupancy)) => {
+            let attempts: i64 = results.iter().map(|r| r.count).sum();
+            let refused: i64 = results
+                .iter()
+                .filter(|r| r.result == "declined" || r.result == "denied")
+                .map(|r| r.count)
+                .sum();
+            let decline_rate = if attempts > 0 {
+                (refused as f64 / attempts as f64 * 1000.0).round() / 10.0
+            } else {
+                0.0
+            };
+
+            Negotiated::page(
+                format,
+                "dashboard",
+                context! {
+                    days: DASHBOARD_DAYS,
+                    weeks: DASHBOARD_WEEKS,
+                    total_unlocks: daily.iter().map(|d| d.unlocks).sum::<i64>(),
+                    attempts: attempts,
+                    decline_rate: decline_rate,
+                    daily_unlocks: with_percent(&daily, |d| d.unlocks),
+                    weekly_unique_keys: with_percent(&weekly, |w| w.unique_keys),
+                    hourly_unlocks: with_percent(&hourly, |h| h.unlocks),
+                    results: results,
+                    anomaly_days: ANOMALY_DAYS,
+                    anomalies: with_label(&anomalies),
+                    occupancy: occupancy_of(&occupancy)
+                },
+            )
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to load dashboard statistics");
+            match format {
+                Format::Html => Negotiated::page(
+                    format,
+                    "dashboard",
+                    context! {
+                        error_message: "Failed to load dashboard statistics"
+                    },
+                ),
+                Format::Json => Negotiated::error(
+                    Status::InternalServerError,
+                    "Failed to load dashboard statistics",
+                ),
+            }
+        }
+    }
+}
+
+/// How many people each site holds right now, for sites with entry or exit doors. `full` sites
+/// decline entries until someone badges out or an admin resets the count.
+#[get("/occupancy")]
+pub async fn occupancy(pool: &State<Pool<Postgres>>, user: ViewerUser) -> Negotiated {
+    match get_site_occupancy(pool, user.0.site).await {
+        Ok(sites) => Negotiated::json(Status::Ok, occupancy_of(&sites)),
+        Err(e) => {
+            error!(error = ?e, "Failed to load site occupancy");
+            Negotiated::error(Status::InternalServerError, "Failed to load site occupancy")
+        }
+    }
+}
+
+// Helper function to shape sites into occupancy rows, flagging the full ones
+fn occupancy_of(sites: &[Site]) -> Vec<serde_json::Value> {
+    sites
+        .iter()
+        .map(|site| {
+            serde_json::json!({
+                "site_id": site.id,
+                "name": site.name,
+                "occupancy": site.occupancy,
+                "max_occupancy": site.max_occupancy,
+                "updated_at": site.occupancy_updated_at,
+                "full": site.max_occupancy.is_some_and(|max| site.occupancy >= max),
+            })
+        })
+        .collect()
+}
+
+// Helper function to add each row's share of the series maximum, for bar widths
+fn with_percent<T: Serialize>(rows: &[T], value: impl Fn(&T) -> i64) -> Vec<serde_json::Value> {
+    let max = rows.iter().map(&value).max().unwrap_or(0).max(1);
+    rows.iter()
+        .map(|row| {
+            let mut json = serde_json::to_value(row).unwrap_or_default();
+            if let Some(object) = json.as_object_mut() {
+                object.insert("percent".to_string(), (value(row) * 100 / max).into());
+            }
+            json
+        })
+        .collect()
+}
+
+// Helper function to add the alert label of each anomaly's kind
+fn with_label(anomalies: &[Anomaly]) -> Vec<serde_json::Value> {
+    anomalies
+        .iter()
+        .map(|anomaly| {
+            let mut json = serde_json::to_value(anomaly).unwrap_or_default();
+            if let (Some(object), Some(kind)) =
+                (json.as_object_mut(), AlertKind::parse(&anomaly.kind))
+            {
+                object.insert("label".to_string(), kind.label().into());
+            }
+            json
+        })
+        .collect()
+}
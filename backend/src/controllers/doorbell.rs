@@ -0,0 +1,212 @@
+use crate::admin_feed::AdminEvent;
+use crate::audit::Audit;
+use crate::auth::CanUnlock;
+use crate::controllers::doors::doors_error;
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::database::helpers::{
+    AccessResult, DoorbellRing, get_door_by_controller_id, insert_doorbell_access_log,
+    take_doorbell_ring,
+};
+use crate::portal_loop::AccessController;
+use crate::request_id::RequestId;
+use crate::webhooks::AccessEvent;
+use rocket::{State, http::Status, post};
+use tracing::{Instrument, error, info, info_span};
+use uuid::Uuid;
+
+// Where the admin UI goes after answering, the page with the doorbell on it
+const DOORBELL_PAGE: &str = "/logs";
+
+/// Lets a visitor in once: unlocks the door they rang at, as a manual unlock would, and logs
+/// it under both their npub and the admin answering.
+#[post("/doorbell/<ring_id>/approve")]
+pub async fn approve_visitor(
+    controller: &State<AccessController>,
+    request_id: RequestId,
+    user: CanUnlock,
+    audit: Audit,
+    format: Format,
+    ring_id: String,
+) -> Negotiated {
+    let ring = match take_ring(controller, user.0.site, format, &ring_id).await {
+        Ok(ring) => ring,
+        Err(response) => return response,
+    };
+    let user = user.0;
+
+    async {
+        let pool = &controller.pool;
+        let door = match get_door_by_controller_id(pool, ring.door_id).await {
+            Ok(Some(door)) if door.enabled => door,
+            Ok(_) => {
+                return doors_error(
+                    pool,
+                    user.site,
+                    format,
+                    Status::Conflict,
+                    "This door is disabled or no longer exists and will not be unlocked.",
+                )
+                .await;
+            }
+            Err(e) => {
+                error!(error = ?e, "Failed to load door");
+                return doors_error(
+                    pool,
+                    user.site,
+                    format,
+                    Status::InternalServerError,
+                    "Failed to load door",
+                )
+                .await;
+            }
+        };
+
+        let outcome = controller
+            .unlock_retry
+            .unlock(
+                controller.door.as_ref(),
+                ring.door_id as u32,
+                door.unlock_duration(),
+            )
+            .await;
+        let note = outcome.note();
+        let result = outcome.result;
+
+        let (access_result, reason) = match (&result, note) {
+            (Ok(()), Some(note)) => (
+                AccessResult::Granted,
+                format!("let in at the doorbell; {}", note),
+            ),
+            (Ok(()), None) => (AccessResult::Granted, "let in at the doorbell".to_string()),
+            (Err(reason), _) => (AccessResult::Error, reason.clone()),
+        };
+        match insert_doorbell_access_log(
+            pool,
+            &ring.npub,
+            &user.sub,
+            ring.door_id,
+            access_result,
+            Some(&reason),
+        )
+        .await
+        {
+            Ok(log) => controller.log_feed.publish(log),
+            Err(e) => error!(error = ?e, "Failed to record access log"),
+        }
+        audit
+            .record(&user.sub, "doorbell.approve", Some(&ring.npub))
+            .await;
+        controller.admin_feed.publish(AdminEvent::doorbell(
+            &ring,
+            None,
+            None,
+            "approved",
+            Some(&user.sub),
+        ));
+
+        match result {
+            Ok(()) => {
+                info!(door = %door.name, by = %user.sub, "Visitor let in at the doorbell");
+                controller.webhooks.notify(AccessEvent::doorbell_unlock(
+                    &ring.npub,
+                    &user.sub,
+                    ring.door_id,
+                ));
+                controller.admin_feed.publish(AdminEvent::door(
+                    ring.door_id,
+                    &door.name,
+                    "unlocked",
+                ));
+                Negotiated::done(
+                    format,
+                    DOORBELL_PAGE,
+                    Status::Ok,
+                    serde_json::json!({ "id": ring.id }),
+                )
+            }
+            Err(reason) => doors_error(pool, user.site, format, Status::BadGateway, &reason).await,
+        }
+    }
+    .instrument(info_span!("doorbell", request_id = %request_id, npub = %ring.npub))
+    .await
+}
+
+/// Turns a visitor away; the door stays locked and their attempt stays logged as denied.
+#[post("/doorbell/<ring_id>/deny")]
+pub async fn deny_visitor(
+    controller: &State<AccessController>,
+    user: CanUnlock,
+    audit: Audit,
+    format: Format,
+    ring_id: String,
+) -> Negotiated {
+    let ring = match take_ring(controller, user.0.site, format, &ring_id).await {
+        Ok(ring) => ring,
+        Err(response) => return response,
+    };
+
+    info!(npub = %ring.npub, by = %user.0.sub, "Visitor turned away at the doorbell");
+    audit
+        .record(&user.0.sub, "doorbell.deny", Some(&ring.npub))
+        .await;
+    controller.admin_feed.publish(AdminEvent::doorbell(
+        &ring,
+        None,
+        None,
+        "denied",
+        Some(&user.0.sub),
+    ));
+    Negotiated::done(
+        format,
+        DOORBELL_PAGE,
+        Status::Ok,
+        serde_json::json!({ "id": ring.id }),
+    )
+}
+
+// Helper function to claim a ring at a door of `site` for an answer, or report why it cannot
+// be answered
+async fn take_ring(
+    controller: &AccessController,
+    site: Option<Uuid>,
+    format: Format,
+    ring_id: &str,
+) -> Result<DoorbellRing, Negotiated> {
+    let pool = &controller.pool;
+    let uuid = match Uuid::parse_str(ring_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return Err(doors_error(
+                pool,
+                site,
+                format,
+                Status::BadRequest,
+                "Invalid doorbell ID",
+            )
+            .await);
+        }
+    };
+
+    match take_doorbell_ring(pool, uuid, site).await {
+        Ok(Some(ring)) => Ok(ring),
+        Ok(None) => Err(doors_error(
+            pool,
+            site,
+            format,
+            Status::Gone,
+            "The visitor is no longer waiting: someone else answered, or it has been too long.",
+        )
+        .await),
+        Err(e) => {
+            error!(error = ?e, "Failed to load doorbell ring");
+            Err(doors_error(
+                pool,
+                site,
+                format,
+                Status::InternalServerError,
+                "Failed to answer the doorbell",
+            )
+            .await)
+        }
+    }
+}
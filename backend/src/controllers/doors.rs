@@ -0,0 +1,1574 @@
+use crate::admin_feed::AdminEvent;
+use crate::audit::Audit;
+use crate::auth::{AdminUser, CanShowKiosk, CanUnlock, Claims, ViewerUser};
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::controllers::schedules::{DAY_NAMES, parse_time};
+use crate::database::helpers::{
+    AccessResult, Door, delete_door_by_id, delete_door_schedule_by_id, get_all_doors,
+    get_all_groups, get_all_keys, get_door_by_id, get_door_group_ids, get_door_key_ids,
+    get_door_schedules, get_recent_door_events, insert_door, insert_door_schedule,
+    insert_manual_access_log, set_door_direction, set_door_groups, set_door_handshake,
+    set_door_keys, set_door_lockdown, set_door_price, set_door_required_permissions,
+    set_door_timezone, set_door_unlock_seconds, toggle_door_status,
+};
+use crate::door_backend::{DoorBackend, DoorPosition, DoorState};
+use crate::portal_loop::AccessController;
+use crate::request_id::RequestId;
+use crate::templating::Page;
+use crate::webhooks::AccessEvent;
+use chrono_tz::Tz;
+use image::{DynamicImage, ImageFormat, Luma};
+use qrcode::{QrCode, render::svg};
+use rocket::http::{ContentType, Header};
+use rocket::response::stream::{Event, EventStream};
+use rocket::tokio::{select, sync::broadcast::error::RecvError};
+use rocket::{Shutdown, State, form::Form, get, http::Status, post};
+use rocket_dyn_templates::context;
+use sqlx::{Pool, Postgres};
+use std::io::Cursor;
+use std::sync::Arc;
+use tracing::{Instrument, error, info, info_span, warn};
+use uuid::Uuid;
+
+// Pages with a quick unlock button that a manual unlock may return to
+const UNLOCK_RETURN_PAGES: [&str; 3] = ["/doors", "/keys", "/logs"];
+
+// Sensor and schedule events listed on a door's page
+const RECENT_DOOR_EVENTS: i64 = 20;
+
+// Smallest width and height of a handshake QR code, in pixels
+const QR_SIZE: u32 = 320;
+
+// Longest unlock a door may be set to; anything longer is better served by disabling the lock
+const MAX_UNLOCK_SECONDS: i32 = 600;
+
+// Bounds on how often a rotating handshake URL is replaced; wallets need time to open a code
+// before it goes stale
+const MIN_HANDSHAKE_ROTATE_SECS: i32 = 30;
+const MAX_HANDSHAKE_ROTATE_SECS: i32 = 86_400;
+
+// Longest static handshake token
+const MAX_HANDSHAKE_TOKEN_LEN: usize = 64;
+
+// Bounds on the permissions a door can require from the authenticating wallet
+const MAX_REQUIRED_PERMISSIONS: usize = 20;
+const MAX_PERMISSION_LEN: usize = 64;
+
+#[derive(rocket::form::FromForm)]
+pub struct DoorRequest {
+    name: String,
+    controller_door_id: i32,
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct DoorKeysRequest {
+    key_ids: Vec<String>,
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct DoorGroupsRequest {
+    group_ids: Vec<String>,
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct DoorLockdownRequest {
+    locked_down: bool,
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct DoorPriceRequest {
+    price_sats: Option<i64>, // empty for a free door
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct DoorDirectionRequest {
+    direction: Option<String>, // entry, exit, or empty for a door not counted
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct DoorTimezoneRequest {
+    timezone: Option<String>, // empty for the site timezone
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct DoorHandshakeRequest {
+    rotating: bool,
+    token: Option<String>,    // empty for the DOOR_SUBJECTS subject
+    rotate_secs: Option<i32>, // only for rotating URLs
+    single_use: bool,
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct DoorRequiredPermissionsRequest {
+    required_permissions: Option<String>, // separated by commas or spaces, empty for none
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct DoorScheduleRequest {
+    weekdays: Vec<i16>,
+    unlock_time: String,
+    lock_time: String,
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct DoorUnlockDurationRequest {
+    unlock_seconds: Option<i32>, // empty for the door backend's default
+}
+
+#[get("/doors")]
+pub async fn doors_page(
+    pool: &State<Pool<Postgres>>,
+    user: ViewerUser,
+    format: Format,
+) -> Negotiated {
+    match get_all_doors(pool, user.0.site).await {
+        Ok(doors) => Negotiated::page(
+            format,
+            "doors",
+            context! {
+                doors: doors
+            },
+        ),
+        Err(e) => {
+            error!(error = ?e, "Failed to load doors");
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to load doors",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/doors", data = "<door_request>")]
+pub async fn add_door(
+    pool: &State<Pool<Postgres>>,
+    user: AdminUser,
+    audit: Audit,
+    format: Format,
+    door_request: Form<DoorRequest>,
+) -> Negotiated {
+    let name = door_request.name.trim();
+    if name.is_empty() || door_request.controller_door_id < 0 {
+        return doors_error(
+            pool,
+            user.0.site,
+            format,
+            Status::UnprocessableEntity,
+            "A door needs a name and a valid controller door number.",
+        )
+        .await;
+    }
+
+    match insert_door(
+        pool,
+        name,
+        door_request.controller_door_id,
+        user.0.home_site(),
+    )
+    .await
+    {
+        Ok(id) => {
+            audit.record(&user.0.sub, "door.add", Some(name)).await;
+            Negotiated::done(
+                format,
+                "/doors",
+                Status::Created,
+                serde_json::json!({ "id": id }),
+            )
+        }
+        Err(_) => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::Conflict,
+                "Failed to add door. The controller door number may already be in use.",
+            )
+            .await
+        }
+    }
+}
+
+#[get("/doors/<door_id>")]
+pub async fn door_page(
+    pool: &State<Pool<Postgres>>,
+    controller: &State<AccessController>,
+    door_backend: &State<Arc<dyn DoorBackend>>,
+    user: ViewerUser,
+    format: Format,
+    door_id: String,
+) -> Negotiated {
+    let uuid = match Uuid::parse_str(&door_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::BadRequest,
+                "Invalid door ID",
+            )
+            .await;
+        }
+    };
+
+    let door = match get_door_by_id(pool, uuid, user.0.site).await {
+        Ok(Some(door)) => door,
+        Ok(None) => {
+            return doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::NotFound,
+                "Door not found",
+            )
+            .await;
+        }
+        Err(_) => {
+            return doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to load door",
+            )
+            .await;
+        }
+    };
+
+    // The door page still renders when the hardware can't be reached
+    let lock_state = door_backend
+        .status(door.controller_door_id as u32)
+        .await
+        .unwrap_or(DoorState::Unknown);
+    let position = door_backend
+        .position(door.controller_door_id as u32)
+        .await
+        .unwrap_or(DoorPosition::Unknown);
+    let events = get_recent_door_events(pool, uuid, RECENT_DOOR_EVENTS)
+        .await
+        .unwrap_or_default();
+    let schedules = get_door_schedules(pool, uuid).await.unwrap_or_default();
+    let handshake_url = controller
+        .handshake_urls
+        .get(door.controller_door_id as u32)
+        .flatten();
+
+    match (
+        get_all_keys(pool, Some(door.site_id)).await,
+        get_door_key_ids(pool, uuid).await,
+        get_all_groups(pool).await,
+        get_door_group_ids(pool, uuid).await,
+    ) {
+        (Ok(keys), Ok(allowed), Ok(groups), Ok(allowed_groups)) => {
+            let keys: Vec<serde_json::Value> = keys
+                .into_iter()
+                .map(|key| {
+                    serde_json::json!({
+                        "allowed": allowed.contains(&key.id),
+                        "key": key,
+                    })
+                })
+                .collect();
+            let groups: Vec<serde_json::Value> = groups
+                .into_iter()
+                .map(|group| {
+                    serde_json::json!({
+                        "allowed": allowed_groups.contains(&group.id),
+                        "group": group,
+                    })
+                })
+                .collect();
+
+            Negotiated::page(
+                format,
+                "door",
+                context! {
+                    door: door,
+                    lock_state: lock_state,
+                    position: position,
+                    handshake_url: handshake_url,
+                    events: events,
+                    schedules: schedules,
+                    day_names: DAY_NAMES,
+                    keys: keys,
+                    groups: groups
+                },
+            )
+        }
+        _ => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to load door permissions",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/doors/<door_id>/keys", data = "<keys_request>")]
+pub async fn set_door_permissions(
+    pool: &State<Pool<Postgres>>,
+    user: AdminUser,
+    audit: Audit,
+    format: Format,
+    door_id: String,
+    keys_request: Form<DoorKeysRequest>,
+) -> Negotiated {
+    let uuid = match site_door_id(pool, user.0.site, format, &door_id).await {
+        Ok(uuid) => uuid,
+        Err(error) => return error,
+    };
+
+    let key_ids: Result<Vec<Uuid>, _> = keys_request
+        .key_ids
+        .iter()
+        .map(|id| Uuid::parse_str(id))
+        .collect();
+    let key_ids = match key_ids {
+        Ok(key_ids) => key_ids,
+        Err(_) => {
+            return doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::BadRequest,
+                "Invalid key ID",
+            )
+            .await;
+        }
+    };
+
+    match set_door_keys(pool, uuid, &key_ids).await {
+        Ok(_) => {
+            audit
+                .record(&user.0.sub, "door.permissions", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                "/doors",
+                Status::Ok,
+                serde_json::json!({ "id": uuid, "key_ids": key_ids }),
+            )
+        }
+        Err(_) => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to update door permissions",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/doors/<door_id>/groups", data = "<groups_request>")]
+pub async fn set_door_group_permissions(
+    pool: &State<Pool<Postgres>>,
+    user: AdminUser,
+    audit: Audit,
+    format: Format,
+    door_id: String,
+    groups_request: Form<DoorGroupsRequest>,
+) -> Negotiated {
+    let uuid = match site_door_id(pool, user.0.site, format, &door_id).await {
+        Ok(uuid) => uuid,
+        Err(error) => return error,
+    };
+
+    let group_ids: Result<Vec<Uuid>, _> = groups_request
+        .group_ids
+        .iter()
+        .map(|id| Uuid::parse_str(id))
+        .collect();
+    let group_ids = match group_ids {
+        Ok(group_ids) => group_ids,
+        Err(_) => {
+            return doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::BadRequest,
+                "Invalid group ID",
+            )
+            .await;
+        }
+    };
+
+    match set_door_groups(pool, uuid, &group_ids).await {
+        Ok(_) => {
+            audit
+                .record(&user.0.sub, "door.groups", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                format!("/doors/{}", uuid),
+                Status::Ok,
+                serde_json::json!({ "id": uuid, "group_ids": group_ids }),
+            )
+        }
+        Err(_) => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to update door groups",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/doors/<door_id>/price", data = "<price_request>")]
+pub async fn set_price(
+    pool: &State<Pool<Postgres>>,
+    user: AdminUser,
+    audit: Audit,
+    format: Format,
+    door_id: String,
+    price_request: Form<DoorPriceRequest>,
+) -> Negotiated {
+    let uuid = match site_door_id(pool, user.0.site, format, &door_id).await {
+        Ok(uuid) => uuid,
+        Err(error) => return error,
+    };
+
+    let price_sats = price_request.price_sats;
+    if price_sats.is_some_and(|price| price < 1) {
+        return doors_error(
+            pool,
+            user.0.site,
+            format,
+            Status::UnprocessableEntity,
+            "The price must be at least 1 sat, or empty for a free door.",
+        )
+        .await;
+    }
+
+    match set_door_price(pool, uuid, price_sats).await {
+        Ok(_) => {
+            let target = match price_sats {
+                Some(price) => format!("{}: {} sats", uuid, price),
+                None => format!("{}: free", uuid),
+            };
+            audit.record(&user.0.sub, "door.price", Some(&target)).await;
+            Negotiated::done(
+                format,
+                format!("/doors/{}", uuid),
+                Status::Ok,
+                serde_json::json!({ "id": uuid, "price_sats": price_sats }),
+            )
+        }
+        Err(_) => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to update door price",
+            )
+            .await
+        }
+    }
+}
+
+/// Permissions the authenticating wallet has to grant before the door opens.
+#[post(
+    "/doors/<door_id>/required-permissions",
+    data = "<permissions_request>"
+)]
+pub async fn set_required_permissions(
+    pool: &State<Pool<Postgres>>,
+    user: AdminUser,
+    audit: Audit,
+    format: Format,
+    door_id: String,
+    permissions_request: Form<DoorRequiredPermissionsRequest>,
+) -> Negotiated {
+    let uuid = match site_door_id(pool, user.0.site, format, &door_id).await {
+        Ok(uuid) => uuid,
+        Err(error) => return error,
+    };
+
+    let mut permissions: Vec<String> = permissions_request
+        .required_permissions
+        .as_deref()
+        .unwrap_or_default()
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|permission| !permission.is_empty())
+        .map(str::to_string)
+        .collect();
+    permissions.dedup();
+    if permissions.len() > MAX_REQUIRED_PERMISSIONS
+        || permissions
+            .iter()
+            .any(|permission| permission.len() > MAX_PERMISSION_LEN)
+    {
+        return doors_error(
+            pool,
+            user.0.site,
+            format,
+            Status::UnprocessableEntity,
+            &format!(
+                "A door may require at most {} permissions of up to {} characters each.",
+                MAX_REQUIRED_PERMISSIONS, MAX_PERMISSION_LEN
+            ),
+        )
+        .await;
+    }
+
+    match set_door_required_permissions(pool, uuid, &permissions).await {
+        Ok(true) => {
+            let target = format!("{}: {}", uuid, permissions.join(", "));
+            audit
+                .record(&user.0.sub, "door.required_permissions", Some(&target))
+                .await;
+            Negotiated::done(
+                format,
+                format!("/doors/{}", uuid),
+                Status::Ok,
+                serde_json::json!({ "id": uuid, "required_permissions": permissions }),
+            )
+        }
+        Ok(false) => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::NotFound,
+                "Door not found",
+            )
+            .await
+        }
+        Err(_) => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to update door permissions",
+            )
+            .await
+        }
+    }
+}
+
+/// How long the door stays unlocked after a handshake or a manual unlock.
+#[post("/doors/<door_id>/unlock-duration", data = "<duration_request>")]
+pub async fn set_unlock_duration(
+    pool: &State<Pool<Postgres>>,
+    user: AdminUser,
+    audit: Audit,
+    format: Format,
+    door_id: String,
+    duration_request: Form<DoorUnlockDurationRequest>,
+) -> Negotiated {
+    let uuid = match site_door_id(pool, user.0.site, format, &door_id).await {
+        Ok(uuid) => uuid,
+        Err(error) => return error,
+    };
+
+    let unlock_seconds = duration_request.unlock_seconds;
+    if unlock_seconds.is_some_and(|seconds| !(1..=MAX_UNLOCK_SECONDS).contains(&seconds)) {
+        return doors_error(
+            pool,
+            user.0.site,
+            format,
+            Status::UnprocessableEntity,
+            &format!(
+                "The unlock duration must be between 1 and {} seconds, or empty for the default.",
+                MAX_UNLOCK_SECONDS
+            ),
+        )
+        .await;
+    }
+
+    match set_door_unlock_seconds(pool, uuid, unlock_seconds).await {
+        Ok(true) => {
+            let target = match unlock_seconds {
+                Some(seconds) => format!("{}: {} seconds", uuid, seconds),
+                None => format!("{}: default", uuid),
+            };
+            audit
+                .record(&user.0.sub, "door.unlock_duration", Some(&target))
+                .await;
+            Negotiated::done(
+                format,
+                format!("/doors/{}", uuid),
+                Status::Ok,
+                serde_json::json!({ "id": uuid, "unlock_seconds": unlock_seconds }),
+            )
+        }
+        Ok(false) => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::NotFound,
+                "Door not found",
+            )
+            .await
+        }
+        Err(_) => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to update door unlock duration",
+            )
+            .await
+        }
+    }
+}
+
+/// Counts the people let in through the door into the site's occupancy (`entry`), or out of it
+/// (`exit`). An entry door stays locked while the site is at its maximum occupancy.
+#[post("/doors/<door_id>/direction", data = "<direction_request>")]
+pub async fn set_direction(
+    pool: &State<Pool<Postgres>>,
+    user: AdminUser,
+    audit: Audit,
+    format: Format,
+    door_id: String,
+    direction_request: Form<DoorDirectionRequest>,
+) -> Negotiated {
+    let uuid = match site_door_id(pool, user.0.site, format, &door_id).await {
+        Ok(uuid) => uuid,
+        Err(error) => return error,
+    };
+
+    let direction = direction_request
+        .direction
+        .as_deref()
+        .map(str::trim)
+        .filter(|direction| !direction.is_empty());
+    if direction.is_some_and(|direction| direction != "entry" && direction != "exit") {
+        return doors_error(
+            pool,
+            user.0.site,
+            format,
+            Status::UnprocessableEntity,
+            "The direction must be entry or exit, or empty for a door not counted.",
+        )
+        .await;
+    }
+
+    match set_door_direction(pool, uuid, direction).await {
+        Ok(true) => {
+            let target = format!("{}: {}", uuid, direction.unwrap_or("not counted"));
+            audit
+                .record(&user.0.sub, "door.direction", Some(&target))
+                .await;
+            Negotiated::done(
+                format,
+                format!("/doors/{}", uuid),
+                Status::Ok,
+                serde_json::json!({ "id": uuid, "direction": direction }),
+            )
+        }
+        Ok(false) => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::NotFound,
+                "Door not found",
+            )
+            .await
+        }
+        Err(_) => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to update door direction",
+            )
+            .await
+        }
+    }
+}
+
+/// Evaluates the door's key schedules in its own timezone instead of the site's.
+#[post("/doors/<door_id>/timezone", data = "<timezone_request>")]
+pub async fn set_timezone(
+    pool: &State<Pool<Postgres>>,
+    user: AdminUser,
+    audit: Audit,
+    format: Format,
+    door_id: String,
+    timezone_request: Form<DoorTimezoneRequest>,
+) -> Negotiated {
+    let uuid = match site_door_id(pool, user.0.site, format, &door_id).await {
+        Ok(uuid) => uuid,
+        Err(error) => return error,
+    };
+
+    let timezone = timezone_request
+        .timezone
+        .as_deref()
+        .map(str::trim)
+        .filter(|timezone| !timezone.is_empty());
+    if timezone.is_some_and(|timezone| timezone.parse::<Tz>().is_err()) {
+        return doors_error(
+            pool,
+            user.0.site,
+            format,
+            Status::UnprocessableEntity,
+            "Unknown timezone. Use an IANA name such as Europe/Rome, or leave it empty for the site timezone.",
+        )
+        .await;
+    }
+
+    match set_door_timezone(pool, uuid, timezone).await {
+        Ok(true) => {
+            let target = format!("{}: {}", uuid, timezone.unwrap_or("site timezone"));
+            audit
+                .record(&user.0.sub, "door.timezone", Some(&target))
+                .await;
+            Negotiated::done(
+                format,
+                format!("/doors/{}", uuid),
+                Status::Ok,
+                serde_json::json!({ "id": uuid, "timezone": timezone }),
+            )
+        }
+        Ok(false) => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::NotFound,
+                "Door not found",
+            )
+            .await
+        }
+        Err(_) => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to update door timezone",
+            )
+            .await
+        }
+    }
+}
+
+/// Serves the door's handshake URL under a static token, so printed QR codes keep working, or
+/// under a new random token every `rotate_secs`, so a photographed code soon stops working.
+/// A `single_use` URL is replaced with a new random one after every handshake, so a code works
+/// once. The handshake loop picks the change up within a minute.
+#[post("/doors/<door_id>/handshake", data = "<handshake_request>")]
+pub async fn set_handshake(
+    pool: &State<Pool<Postgres>>,
+    user: AdminUser,
+    audit: Audit,
+    format: Format,
+    door_id: String,
+    handshake_request: Form<DoorHandshakeRequest>,
+) -> Negotiated {
+    let uuid = match site_door_id(pool, user.0.site, format, &door_id).await {
+        Ok(uuid) => uuid,
+        Err(error) => return error,
+    };
+
+    let token = handshake_request
+        .token
+        .as_deref()
+        .map(str::trim)
+        .filter(|token| !token.is_empty());
+    if token.is_some_and(|token| {
+        token.len() > MAX_HANDSHAKE_TOKEN_LEN
+            || !token
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }) {
+        return doors_error(
+            pool,
+            user.0.site,
+            format,
+            Status::UnprocessableEntity,
+            "A handshake token may only use letters, digits, '-' and '_', up to 64 characters.",
+        )
+        .await;
+    }
+
+    let rotate_secs = match (handshake_request.rotating, handshake_request.rotate_secs) {
+        (false, _) => None,
+        (true, Some(secs))
+            if (MIN_HANDSHAKE_ROTATE_SECS..=MAX_HANDSHAKE_ROTATE_SECS).contains(&secs) =>
+        {
+            Some(secs)
+        }
+        (true, _) => {
+            return doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::UnprocessableEntity,
+                "A rotating handshake URL needs an interval between 30 seconds and 24 hours.",
+            )
+            .await;
+        }
+    };
+
+    let single_use = handshake_request.single_use;
+    match set_door_handshake(pool, uuid, token, rotate_secs, single_use).await {
+        Ok(true) => {
+            let target = match (rotate_secs, single_use) {
+                (Some(secs), true) => format!("{}: single use, rotating every {}s", uuid, secs),
+                (Some(secs), false) => format!("{}: rotating every {}s", uuid, secs),
+                (None, true) => format!("{}: single use", uuid),
+                (None, false) => format!("{}: static {}", uuid, token.unwrap_or("subject")),
+            };
+            audit
+                .record(&user.0.sub, "door.handshake", Some(&target))
+                .await;
+            Negotiated::done(
+                format,
+                format!("/doors/{}", uuid),
+                Status::Ok,
+                serde_json::json!({
+                    "id": uuid,
+                    "handshake_token": token,
+                    "handshake_rotate_secs": rotate_secs,
+                    "handshake_single_use": single_use
+                }),
+            )
+        }
+        Ok(false) => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::NotFound,
+                "Door not found",
+            )
+            .await
+        }
+        Err(_) => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::Conflict,
+                "Failed to update the handshake. Another door may already use this token.",
+            )
+            .await
+        }
+    }
+}
+
+/// Holds the door unlocked during a weekly window, such as office hours, and locks it when the
+/// window ends. See `AutoLockScheduler`.
+#[post("/doors/<door_id>/schedules", data = "<schedule_request>")]
+pub async fn add_door_schedule(
+    pool: &State<Pool<Postgres>>,
+    user: AdminUser,
+    audit: Audit,
+    format: Format,
+    door_id: String,
+    schedule_request: Form<DoorScheduleRequest>,
+) -> Negotiated {
+    let uuid = match site_door_id(pool, user.0.site, format, &door_id).await {
+        Ok(uuid) => uuid,
+        Err(error) => return error,
+    };
+
+    let weekdays = &schedule_request.weekdays;
+    if weekdays.is_empty() || weekdays.iter().any(|day| !(1..=7).contains(day)) {
+        return doors_error(
+            pool,
+            user.0.site,
+            format,
+            Status::UnprocessableEntity,
+            "Pick at least one day of the week.",
+        )
+        .await;
+    }
+
+    let (unlock_time, lock_time) = match (
+        parse_time(&schedule_request.unlock_time),
+        parse_time(&schedule_request.lock_time),
+    ) {
+        (Some(unlock_time), Some(lock_time)) if unlock_time < lock_time => (unlock_time, lock_time),
+        _ => {
+            return doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::UnprocessableEntity,
+                "Times must be HH:MM and the door must lock after it unlocks.",
+            )
+            .await;
+        }
+    };
+
+    match insert_door_schedule(pool, uuid, weekdays, unlock_time, lock_time).await {
+        Ok(id) => {
+            audit
+                .record(&user.0.sub, "door.schedule.add", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                format!("/doors/{}", uuid),
+                Status::Created,
+                serde_json::json!({ "id": id }),
+            )
+        }
+        Err(_) => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to add schedule",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/doors/<door_id>/schedules/<schedule_id>/delete")]
+pub async fn delete_door_schedule(
+    pool: &State<Pool<Postgres>>,
+    user: AdminUser,
+    audit: Audit,
+    format: Format,
+    door_id: String,
+    schedule_id: String,
+) -> Negotiated {
+    let uuid = match site_door_id(pool, user.0.site, format, &door_id).await {
+        Ok(uuid) => uuid,
+        Err(error) => return error,
+    };
+    let Ok(schedule_uuid) = Uuid::parse_str(&schedule_id) else {
+        return doors_error(
+            pool,
+            user.0.site,
+            format,
+            Status::BadRequest,
+            "Invalid schedule ID",
+        )
+        .await;
+    };
+
+    match delete_door_schedule_by_id(pool, uuid, schedule_uuid).await {
+        Ok(_) => {
+            audit
+                .record(&user.0.sub, "door.schedule.delete", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                format!("/doors/{}", uuid),
+                Status::Ok,
+                serde_json::json!({ "id": schedule_uuid }),
+            )
+        }
+        Err(_) => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to delete schedule",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/doors/<door_id>/toggle")]
+pub async fn toggle_door(
+    pool: &State<Pool<Postgres>>,
+    user: AdminUser,
+    audit: Audit,
+    format: Format,
+    door_id: String,
+) -> Negotiated {
+    let uuid = match site_door_id(pool, user.0.site, format, &door_id).await {
+        Ok(uuid) => uuid,
+        Err(error) => return error,
+    };
+
+    match toggle_door_status(pool, uuid).await {
+        Ok(_) => {
+            info!(door = %uuid, by = %user.0.sub, "Door toggled");
+            audit
+                .record(&user.0.sub, "door.toggle", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                "/doors",
+                Status::Ok,
+                serde_json::json!({ "id": uuid }),
+            )
+        }
+        Err(_) => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to toggle door status",
+            )
+            .await
+        }
+    }
+}
+
+/// Locks one door down or lifts its lockdown. A locked down door declines every handshake
+/// until it is lifted; admins can still unlock it from here.
+#[post("/doors/<door_id>/lockdown", data = "<lockdown_request>")]
+pub async fn set_lockdown_door(
+    pool: &State<Pool<Postgres>>,
+    user: AdminUser,
+    audit: Audit,
+    format: Format,
+    door_id: String,
+    lockdown_request: Form<DoorLockdownRequest>,
+) -> Negotiated {
+    let uuid = match site_door_id(pool, user.0.site, format, &door_id).await {
+        Ok(uuid) => uuid,
+        Err(error) => return error,
+    };
+
+    let locked_down = lockdown_request.locked_down;
+    match set_door_lockdown(pool, uuid, locked_down).await {
+        Ok(true) => {
+            warn!(door = %uuid, locked_down, by = %user.0.sub, "Door lockdown changed");
+            let action = if locked_down {
+                "door.lockdown"
+            } else {
+                "door.lockdown_clear"
+            };
+            audit
+                .record(&user.0.sub, action, Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                format!("/doors/{}", uuid),
+                Status::Ok,
+                serde_json::json!({ "id": uuid, "locked_down": locked_down }),
+            )
+        }
+        Ok(false) => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::NotFound,
+                "Door not found",
+            )
+            .await
+        }
+        Err(_) => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to change door lockdown",
+            )
+            .await
+        }
+    }
+}
+
+/// Handshake QR code image, not cached so a display always shows the current URL.
+#[derive(rocket::Responder)]
+pub struct QrImage {
+    body: Vec<u8>,
+    content_type: ContentType,
+    cache_control: Header<'static>,
+}
+
+/// The door's current key handshake URL, for kiosk displays drawing their own QR code. A
+/// rotating URL is replaced every `handshake_rotate_secs`, so displays should poll at least
+/// that often.
+#[get("/doors/<door_id>/handshake-url")]
+pub async fn door_handshake_url(
+    pool: &State<Pool<Postgres>>,
+    controller: &State<AccessController>,
+    user: CanShowKiosk,
+    door_id: String,
+) -> Negotiated {
+    let door = match kiosk_door(pool, user.0.site, &door_id).await {
+        Ok(door) => door,
+        Err(error) => return error,
+    };
+
+    match current_handshake_url(controller, &door) {
+        Ok(url) => Negotiated::json(
+            Status::Ok,
+            serde_json::json!({
+                "id": door.id,
+                "handshake_url": url,
+                "rotating": door.handshake_rotate_secs.is_some() || door.handshake_single_use,
+                "handshake_rotate_secs": door.handshake_rotate_secs,
+                "handshake_single_use": door.handshake_single_use
+            }),
+        ),
+        Err(error) => error,
+    }
+}
+
+/// Server-Sent Events feed of the door's key handshake URL, for kiosk displays: one `handshake`
+/// event with the current URL on connecting and another whenever the loop replaces it, such as
+/// after every handshake on a single-use URL. The URL is `null` while it is unavailable.
+#[get("/doors/<door_id>/handshake-url/stream")]
+pub async fn stream_door_handshake_url(
+    pool: &State<Pool<Postgres>>,
+    controller: &State<AccessController>,
+    user: CanShowKiosk,
+    door_id: String,
+    mut shutdown: Shutdown,
+) -> Result<EventStream![], Negotiated> {
+    let door = kiosk_door(pool, user.0.site, &door_id).await?;
+    let served = door.controller_door_id as u32;
+    let urls = controller.handshake_urls.clone();
+    // Subscribed before the current URL is read, so no change falls in between
+    let mut changes = urls.subscribe();
+    let Some(current) = urls.get(served) else {
+        return Err(Negotiated::error(
+            Status::NotFound,
+            "No handshake loop serves this door; add it to DOOR_SUBJECTS",
+        ));
+    };
+
+    Ok(EventStream! {
+        yield Event::json(&serde_json::json!({ "handshake_url": current })).event("handshake");
+        loop {
+            let url = select! {
+                change = changes.recv() => match change {
+                    Ok((door_id, url)) if door_id == served => url,
+                    Ok(_) => continue,
+                    Err(RecvError::Closed) => break,
+                    // A display that fell behind skips ahead to the URL as it is now
+                    Err(RecvError::Lagged(_)) => urls.get(served).flatten(),
+                },
+                _ = &mut shutdown => break,
+            };
+            yield Event::json(&serde_json::json!({ "handshake_url": url })).event("handshake");
+        }
+    })
+}
+
+/// The door's current key handshake URL as a QR code, SVG by default or `format=png`.
+#[get("/doors/<door_id>/qr?<format>")]
+pub async fn door_qr(
+    pool: &State<Pool<Postgres>>,
+    controller: &State<AccessController>,
+    user: CanShowKiosk,
+    door_id: String,
+    format: Option<&str>,
+) -> Result<QrImage, Negotiated> {
+    let png = match format.unwrap_or("svg") {
+        "svg" => false,
+        "png" => true,
+        _ => {
+            return Err(Negotiated::error(
+                Status::BadRequest,
+                "format must be svg or png",
+            ));
+        }
+    };
+
+    let door = kiosk_door(pool, user.0.site, &door_id).await?;
+    let url = current_handshake_url(controller, &door)?;
+
+    let body = render_qr(&url, png).map_err(|e| {
+        error!(error = %e, "Failed to render handshake QR code");
+        Negotiated::error(Status::InternalServerError, "Failed to render QR code")
+    })?;
+    Ok(QrImage {
+        body,
+        content_type: if png {
+            ContentType::PNG
+        } else {
+            ContentType::SVG
+        },
+        cache_control: Header::new("Cache-Control", "no-store"),
+    })
+}
+
+/// Full-screen page for a display at the door, showing its handshake QR code and picking up
+/// a new code whenever the handshake URL changes.
+#[get("/doors/<door_id>/kiosk")]
+pub async fn door_kiosk(
+    pool: &State<Pool<Postgres>>,
+    user: ViewerUser,
+    format: Format,
+    door_id: String,
+) -> Result<Page, Negotiated> {
+    let uuid = match Uuid::parse_str(&door_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return Err(doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::BadRequest,
+                "Invalid door ID",
+            )
+            .await);
+        }
+    };
+
+    match get_door_by_id(pool, uuid, user.0.site).await {
+        Ok(Some(door)) => Ok(Page::render("kiosk", context! { door: door })),
+        Ok(None) => Err(doors_error(
+            pool,
+            user.0.site,
+            format,
+            Status::NotFound,
+            "Door not found",
+        )
+        .await),
+        Err(_) => Err(doors_error(
+            pool,
+            user.0.site,
+            format,
+            Status::InternalServerError,
+            "Failed to load door",
+        )
+        .await),
+    }
+}
+
+/// Renders text, such as a handshake URL, as an SVG or PNG QR code.
+pub fn render_qr(text: &str, png: bool) -> Result<Vec<u8>, String> {
+    let code = QrCode::new(text.as_bytes()).map_err(|e| e.to_string())?;
+    if !png {
+        let svg = code
+            .render::<svg::Color>()
+            .min_dimensions(QR_SIZE, QR_SIZE)
+            .build();
+        return Ok(svg.into_bytes());
+    }
+
+    let image = code
+        .render::<Luma<u8>>()
+        .min_dimensions(QR_SIZE, QR_SIZE)
+        .build();
+    let mut bytes = Vec::new();
+    DynamicImage::ImageLuma8(image)
+        .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+        .map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+#[post("/doors/<door_id>/delete")]
+pub async fn delete_door(
+    pool: &State<Pool<Postgres>>,
+    user: AdminUser,
+    audit: Audit,
+    format: Format,
+    door_id: String,
+) -> Negotiated {
+    let uuid = match site_door_id(pool, user.0.site, format, &door_id).await {
+        Ok(uuid) => uuid,
+        Err(error) => return error,
+    };
+
+    match delete_door_by_id(pool, uuid).await {
+        Ok(_) => {
+            audit
+                .record(&user.0.sub, "door.delete", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                "/doors",
+                Status::Ok,
+                serde_json::json!({ "id": uuid }),
+            )
+        }
+        Err(_) => {
+            doors_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to delete door",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/doors/<door_id>/unlock?<return_to>")]
+pub async fn unlock_door(
+    controller: &State<AccessController>,
+    request_id: RequestId,
+    user: CanUnlock,
+    audit: Audit,
+    format: Format,
+    door_id: String,
+    return_to: Option<String>,
+) -> Negotiated {
+    let actuation = DoorActuation {
+        controller: controller.inner(),
+        unlock: true,
+        return_to: return_to.as_deref(),
+    };
+    actuate_door(actuation, user.0, audit, format, &door_id)
+        .instrument(info_span!("manual_door", request_id = %request_id))
+        .await
+}
+
+#[post("/doors/<door_id>/lock")]
+pub async fn lock_door(
+    controller: &State<AccessController>,
+    request_id: RequestId,
+    user: AdminUser,
+    audit: Audit,
+    format: Format,
+    door_id: String,
+) -> Negotiated {
+    let actuation = DoorActuation {
+        controller: controller.inner(),
+        unlock: false,
+        return_to: None,
+    };
+    actuate_door(actuation, user.0, audit, format, &door_id)
+        .instrument(info_span!("manual_door", request_id = %request_id))
+        .await
+}
+
+/// What `actuate_door` should do, and where the admin UI goes afterwards. The controller
+/// provides the door backend and where manual unlocks are announced.
+pub struct DoorActuation<'a> {
+    pub controller: &'a AccessController,
+    pub unlock: bool,
+    pub return_to: Option<&'a str>,
+}
+
+/// Unlocks or locks a door from the admin UI or an API token. Manual unlocks go to the access
+/// log, live log viewers and webhooks like handshake unlocks, attributed to the admin user or
+/// `token:<name>`.
+pub async fn actuate_door(
+    actuation: DoorActuation<'_>,
+    user: Claims,
+    audit: Audit,
+    format: Format,
+    door_id: &str,
+) -> Negotiated {
+    let pool = &actuation.controller.pool;
+    let uuid = match Uuid::parse_str(door_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return doors_error(
+                pool,
+                user.site,
+                format,
+                Status::BadRequest,
+                "Invalid door ID",
+            )
+            .await;
+        }
+    };
+
+    let door = match get_door_by_id(pool, uuid, user.site).await {
+        Ok(Some(door)) => door,
+        Ok(None) => {
+            return doors_error(pool, user.site, format, Status::NotFound, "Door not found").await;
+        }
+        Err(_) => {
+            return doors_error(
+                pool,
+                user.site,
+                format,
+                Status::InternalServerError,
+                "Failed to load door",
+            )
+            .await;
+        }
+    };
+
+    let unlock = actuation.unlock;
+    if unlock && !door.enabled {
+        return doors_error(
+            pool,
+            user.site,
+            format,
+            Status::Conflict,
+            "This door is disabled and will not be unlocked.",
+        )
+        .await;
+    }
+
+    let controller_door_id = door.controller_door_id as u32;
+    let (result, note) = if unlock {
+        let outcome = actuation
+            .controller
+            .unlock_retry
+            .unlock(
+                actuation.controller.door.as_ref(),
+                controller_door_id,
+                door.unlock_duration(),
+            )
+            .await;
+        let note = outcome.note();
+        (outcome.result, note)
+    } else {
+        (
+            actuation.controller.door.lock(controller_door_id).await,
+            None,
+        )
+    };
+
+    if unlock {
+        let (access_result, reason) = match (&result, note) {
+            (Ok(()), Some(note)) => (AccessResult::Granted, format!("manual unlock; {}", note)),
+            (Ok(()), None) => (AccessResult::Granted, "manual unlock".to_string()),
+            (Err(reason), _) => (AccessResult::Error, reason.clone()),
+        };
+        match insert_manual_access_log(
+            pool,
+            &user.sub,
+            door.controller_door_id,
+            access_result,
+            Some(&reason),
+        )
+        .await
+        {
+            Ok(log) => actuation.controller.log_feed.publish(log),
+            Err(e) => error!(error = ?e, "Failed to record access log"),
+        }
+        if result.is_ok() {
+            actuation.controller.relock.watch(&door);
+            actuation
+                .controller
+                .webhooks
+                .notify(AccessEvent::manual_unlock(
+                    &user.sub,
+                    door.controller_door_id,
+                ));
+        }
+    }
+
+    match result {
+        Ok(()) => {
+            info!(
+                door = %door.name,
+                by = %user.sub,
+                "Door {}",
+                if unlock { "unlocked" } else { "locked" }
+            );
+            let action = if unlock { "door.unlock" } else { "door.lock" };
+            audit.record(&user.sub, action, Some(&door.name)).await;
+            actuation.controller.admin_feed.publish(AdminEvent::door(
+                door.controller_door_id,
+                &door.name,
+                if unlock { "unlocked" } else { "locked" },
+            ));
+            let redirect_to = match actuation.return_to {
+                Some(page) if UNLOCK_RETURN_PAGES.contains(&page) => page.to_string(),
+                _ => format!("/doors/{}", uuid),
+            };
+            Negotiated::done(
+                format,
+                redirect_to,
+                Status::Ok,
+                serde_json::json!({ "id": uuid }),
+            )
+        }
+        Err(reason) => doors_error(pool, user.site, format, Status::BadGateway, &reason).await,
+    }
+}
+
+// Helper function to parse a door ID from the path, answering with an error unless the door
+// exists in `site`
+// Helper function to load a door for the kiosk endpoints, which answer in JSON
+async fn kiosk_door(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+    door_id: &str,
+) -> Result<Door, Negotiated> {
+    let uuid = Uuid::parse_str(door_id)
+        .map_err(|_| Negotiated::error(Status::BadRequest, "Invalid door ID"))?;
+    match get_door_by_id(pool, uuid, site).await {
+        Ok(Some(door)) => Ok(door),
+        Ok(None) => Err(Negotiated::error(Status::NotFound, "Door not found")),
+        Err(_) => Err(Negotiated::error(
+            Status::InternalServerError,
+            "Failed to load door",
+        )),
+    }
+}
+
+// The URL the door's handshake loop currently listens on
+fn current_handshake_url(controller: &AccessController, door: &Door) -> Result<String, Negotiated> {
+    match controller
+        .handshake_urls
+        .get(door.controller_door_id as u32)
+    {
+        Some(Some(url)) => Ok(url),
+        Some(None) => Err(Negotiated::error(
+            Status::ServiceUnavailable,
+            "The door's handshake URL is not available yet",
+        )),
+        None => Err(Negotiated::error(
+            Status::NotFound,
+            "No handshake loop serves this door; add it to DOOR_SUBJECTS",
+        )),
+    }
+}
+
+async fn site_door_id(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+    format: Format,
+    door_id: &str,
+) -> Result<Uuid, Negotiated> {
+    let Ok(uuid) = Uuid::parse_str(door_id) else {
+        return Err(doors_error(pool, site, format, Status::BadRequest, "Invalid door ID").await);
+    };
+
+    match get_door_by_id(pool, uuid, site).await {
+        Ok(Some(_)) => Ok(uuid),
+        Ok(None) => Err(doors_error(pool, site, format, Status::NotFound, "Door not found").await),
+        Err(_) => Err(doors_error(
+            pool,
+            site,
+            format,
+            Status::InternalServerError,
+            "Failed to load door",
+        )
+        .await),
+    }
+}
+
+// Helper function to report a door management error in the requested format
+pub async fn doors_error(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+    format: Format,
+    status: Status,
+    error_message: &str,
+) -> Negotiated {
+    match format {
+        Format::Html => Negotiated::page(
+            format,
+            "doors",
+            context! {
+                doors: get_all_doors(pool, site).await.unwrap_or_default(),
+                error_message: error_message
+            },
+        ),
+        Format::Json => Negotiated::error(status, error_message),
+    }
+}
@@ -0,0 +1,195 @@
+use crate::audit::Audit;
+use crate::auth::{AllSitesAdmin, ViewerUser};
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::database::helpers::{get_all_doors, get_lockdown, set_lockdown};
+use crate::emergency::{OverrideMode, OverrideState};
+use rocket::{State, form::Form, get, http::Status, post};
+use rocket_dyn_templates::context;
+use sqlx::{Pool, Postgres};
+use tracing::{error, warn};
+use uuid::Uuid;
+
+// Longest an emergency override may stay active before it has to be re-issued
+const MAX_OVERRIDE_MINUTES: i64 = 24 * 60;
+
+#[derive(rocket::form::FromForm)]
+pub struct OverrideRequest {
+    mode: OverrideMode,
+    minutes: i64,
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct LockdownRequest {
+    active: bool,
+    reason: Option<String>,
+}
+
+#[get("/emergency")]
+pub async fn emergency_page(
+    pool: &State<Pool<Postgres>>,
+    overrides: &State<OverrideState>,
+    user: ViewerUser,
+    format: Format,
+) -> Negotiated {
+    let (lockdown, doors) = match (
+        get_lockdown(pool).await,
+        get_all_doors(pool, user.0.site).await,
+    ) {
+        (Ok(lockdown), Ok(doors)) => (lockdown, doors),
+        _ => {
+            return emergency_error(
+                pool,
+                overrides,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to load the lockdown state",
+            )
+            .await;
+        }
+    };
+
+    Negotiated::page(
+        format,
+        "emergency",
+        context! {
+            active_override: overrides.current().await,
+            max_minutes: MAX_OVERRIDE_MINUTES,
+            lockdown: lockdown,
+            locked_down_doors: doors.into_iter().filter(|door| door.locked_down).collect::<Vec<_>>()
+        },
+    )
+}
+
+#[post("/emergency", data = "<override_request>")]
+pub async fn set_override(
+    pool: &State<Pool<Postgres>>,
+    overrides: &State<OverrideState>,
+    user: AllSitesAdmin,
+    audit: Audit,
+    format: Format,
+    override_request: Form<OverrideRequest>,
+) -> Negotiated {
+    if override_request.minutes < 1 || override_request.minutes > MAX_OVERRIDE_MINUTES {
+        return emergency_error(
+            pool,
+            overrides,
+            user.0.site,
+            format,
+            Status::UnprocessableEntity,
+            "Duration must be between 1 minute and 24 hours.",
+        )
+        .await;
+    }
+
+    let active_override = overrides
+        .set(
+            override_request.mode,
+            chrono::Duration::minutes(override_request.minutes),
+            user.0.sub,
+        )
+        .await;
+    audit
+        .record(
+            &active_override.triggered_by,
+            "emergency.set",
+            Some(&format!(
+                "{:?} until {}",
+                active_override.mode, active_override.expires_at
+            )),
+        )
+        .await;
+
+    Negotiated::done(format, "/emergency", Status::Ok, active_override)
+}
+
+#[post("/emergency/clear")]
+pub async fn clear_override(
+    overrides: &State<OverrideState>,
+    user: AllSitesAdmin,
+    audit: Audit,
+    format: Format,
+) -> Negotiated {
+    overrides.clear(&user.0.sub).await;
+    audit.record(&user.0.sub, "emergency.clear", None).await;
+
+    Negotiated::done(
+        format,
+        "/emergency",
+        Status::Ok,
+        serde_json::json!({ "active_override": null }),
+    )
+}
+
+/// Switches the site-wide lockdown on or off. While it is on, every handshake on every door
+/// is declined and logged, until an admin lifts it.
+#[post("/emergency/lockdown", data = "<lockdown_request>")]
+pub async fn set_site_lockdown(
+    pool: &State<Pool<Postgres>>,
+    overrides: &State<OverrideState>,
+    user: AllSitesAdmin,
+    audit: Audit,
+    format: Format,
+    lockdown_request: Form<LockdownRequest>,
+) -> Negotiated {
+    let reason = lockdown_request
+        .reason
+        .as_deref()
+        .map(str::trim)
+        .filter(|reason| !reason.is_empty());
+
+    match set_lockdown(pool, lockdown_request.active, reason, &user.0.sub).await {
+        Ok(lockdown) => {
+            warn!(active = lockdown.active, by = %user.0.sub, reason, "Site lockdown changed");
+            let action = if lockdown.active {
+                "lockdown.set"
+            } else {
+                "lockdown.clear"
+            };
+            audit.record(&user.0.sub, action, reason).await;
+            Negotiated::done(format, "/emergency", Status::Ok, lockdown)
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to change the site lockdown");
+            emergency_error(
+                pool,
+                overrides,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to change the site lockdown",
+            )
+            .await
+        }
+    }
+}
+
+// Helper function to report an emergency override error in the requested format
+async fn emergency_error(
+    pool: &Pool<Postgres>,
+    overrides: &OverrideState,
+    site: Option<Uuid>,
+    format: Format,
+    status: Status,
+    error_message: &str,
+) -> Negotiated {
+    match format {
+        Format::Html => Negotiated::page(
+            format,
+            "emergency",
+            context! {
+                active_override: overrides.current().await,
+                max_minutes: MAX_OVERRIDE_MINUTES,
+                lockdown: get_lockdown(pool).await.ok(),
+                locked_down_doors: get_all_doors(pool, site)
+                    .await
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|door| door.locked_down)
+                    .collect::<Vec<_>>(),
+                error_message: error_message
+            },
+        ),
+        Format::Json => Negotiated::error(status, error_message),
+    }
+}
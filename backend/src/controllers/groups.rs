@@ -0,0 +1,251 @@
+use crate::audit::Audit;
+use crate::auth::{AllSitesAdmin, ViewerUser};
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::database::helpers::{
+    delete_group_by_id, get_all_groups, get_all_keys, get_group_by_id, get_group_key_ids,
+    insert_group, set_group_keys,
+};
+use rocket::{State, form::Form, get, http::Status, post};
+use rocket_dyn_templates::context;
+use sqlx::{Pool, Postgres};
+use tracing::error;
+use uuid::Uuid;
+
+#[derive(rocket::form::FromForm)]
+pub struct GroupRequest {
+    name: String,
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct GroupKeysRequest {
+    key_ids: Vec<String>,
+}
+
+#[get("/groups")]
+pub async fn groups_page(
+    pool: &State<Pool<Postgres>>,
+    _user: ViewerUser,
+    format: Format,
+) -> Negotiated {
+    match get_all_groups(pool).await {
+        Ok(groups) => Negotiated::page(format, "groups", context! { groups: groups }),
+        Err(e) => {
+            error!(error = ?e, "Failed to load groups");
+            groups_error(
+                pool,
+                format,
+                Status::InternalServerError,
+                "Failed to load groups",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/groups", data = "<group_request>")]
+pub async fn add_group(
+    pool: &State<Pool<Postgres>>,
+    user: AllSitesAdmin,
+    audit: Audit,
+    format: Format,
+    group_request: Form<GroupRequest>,
+) -> Negotiated {
+    let name = group_request.name.trim();
+    if name.is_empty() {
+        return groups_error(
+            pool,
+            format,
+            Status::UnprocessableEntity,
+            "A group needs a name.",
+        )
+        .await;
+    }
+
+    match insert_group(pool, name).await {
+        Ok(id) => {
+            audit.record(&user.0.sub, "group.add", Some(name)).await;
+            Negotiated::done(
+                format,
+                format!("/groups/{}", id),
+                Status::Created,
+                serde_json::json!({ "id": id }),
+            )
+        }
+        Err(_) => {
+            groups_error(
+                pool,
+                format,
+                Status::Conflict,
+                "Failed to add group. The name may already be in use.",
+            )
+            .await
+        }
+    }
+}
+
+#[get("/groups/<group_id>")]
+pub async fn group_page(
+    pool: &State<Pool<Postgres>>,
+    user: ViewerUser,
+    format: Format,
+    group_id: String,
+) -> Negotiated {
+    let uuid = match Uuid::parse_str(&group_id) {
+        Ok(uuid) => uuid,
+        Err(_) => return groups_error(pool, format, Status::BadRequest, "Invalid group ID").await,
+    };
+
+    let group = match get_group_by_id(pool, uuid).await {
+        Ok(Some(group)) => group,
+        Ok(None) => return groups_error(pool, format, Status::NotFound, "Group not found").await,
+        Err(_) => {
+            return groups_error(
+                pool,
+                format,
+                Status::InternalServerError,
+                "Failed to load group",
+            )
+            .await;
+        }
+    };
+
+    match (
+        get_all_keys(pool, user.0.site).await,
+        get_group_key_ids(pool, uuid).await,
+    ) {
+        (Ok(keys), Ok(members)) => {
+            let keys: Vec<serde_json::Value> = keys
+                .into_iter()
+                .map(|key| {
+                    serde_json::json!({
+                        "member": members.contains(&key.id),
+                        "key": key,
+                    })
+                })
+                .collect();
+
+            Negotiated::page(
+                format,
+                "group",
+                context! {
+                    group: group,
+                    keys: keys
+                },
+            )
+        }
+        _ => {
+            groups_error(
+                pool,
+                format,
+                Status::InternalServerError,
+                "Failed to load group members",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/groups/<group_id>/keys", data = "<keys_request>")]
+pub async fn set_group_members(
+    pool: &State<Pool<Postgres>>,
+    user: AllSitesAdmin,
+    audit: Audit,
+    format: Format,
+    group_id: String,
+    keys_request: Form<GroupKeysRequest>,
+) -> Negotiated {
+    let uuid = match Uuid::parse_str(&group_id) {
+        Ok(uuid) => uuid,
+        Err(_) => return groups_error(pool, format, Status::BadRequest, "Invalid group ID").await,
+    };
+
+    let key_ids: Result<Vec<Uuid>, _> = keys_request
+        .key_ids
+        .iter()
+        .map(|id| Uuid::parse_str(id))
+        .collect();
+    let key_ids = match key_ids {
+        Ok(key_ids) => key_ids,
+        Err(_) => return groups_error(pool, format, Status::BadRequest, "Invalid key ID").await,
+    };
+
+    match set_group_keys(pool, uuid, &key_ids).await {
+        Ok(_) => {
+            audit
+                .record(&user.0.sub, "group.members", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                "/groups",
+                Status::Ok,
+                serde_json::json!({ "id": uuid, "key_ids": key_ids }),
+            )
+        }
+        Err(_) => {
+            groups_error(
+                pool,
+                format,
+                Status::InternalServerError,
+                "Failed to update group members",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/groups/<group_id>/delete")]
+pub async fn delete_group(
+    pool: &State<Pool<Postgres>>,
+    user: AllSitesAdmin,
+    audit: Audit,
+    format: Format,
+    group_id: String,
+) -> Negotiated {
+    let uuid = match Uuid::parse_str(&group_id) {
+        Ok(uuid) => uuid,
+        Err(_) => return groups_error(pool, format, Status::BadRequest, "Invalid group ID").await,
+    };
+
+    match delete_group_by_id(pool, uuid).await {
+        Ok(_) => {
+            audit
+                .record(&user.0.sub, "group.delete", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                "/groups",
+                Status::Ok,
+                serde_json::json!({ "id": uuid }),
+            )
+        }
+        Err(_) => {
+            groups_error(
+                pool,
+                format,
+                Status::InternalServerError,
+                "Failed to delete group",
+            )
+            .await
+        }
+    }
+}
+
+// Helper function to report a group management error in the requested format
+async fn groups_error(
+    pool: &Pool<Postgres>,
+    format: Format,
+    status: Status,
+    error_message: &str,
+) -> Negotiated {
+    match format {
+        Format::Html => Negotiated::page(
+            format,
+            "groups",
+            context! {
+                groups: get_all_groups(pool).await.unwrap_or_default(),
+                error_message: error_message
+            },
+        ),
+        Format::Json => Negotiated::error(status, error_message),
+    }
+}
@@ -0,0 +1,386 @@
+use crate::alerts::Alerter;
+use crate::audit::Audit;
+use crate::auth::AllSitesAdmin;
+use crate::controllers::doors::render_qr;
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::database::helpers::{
+    GuestPass, NewGuestPass, delete_guest_pass_by_id, get_all_guest_passes, insert_guest_pass,
+};
+use crate::mailer::{MailAttachment, Mailer};
+use crate::portal_loop::AccessController;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use lettre::message::header::ContentType;
+use rocket::{State, form::Form, get, http::Status, post};
+use rocket_dyn_templates::context;
+use sqlx::{Pool, Postgres};
+use tracing::{error, info};
+use uuid::Uuid;
+
+#[derive(rocket::form::FromForm)]
+pub struct GuestPassRequest {
+    label: String,
+    npub: Option<String>,
+    max_uses: Option<i32>,
+    valid_from: Option<String>,
+    valid_until: Option<String>,
+    host: Option<String>,  // whom a pre-registered visitor is visiting
+    email: Option<String>, // where to send the pass's handshake URL
+}
+
+#[get("/guest-passes")]
+pub async fn guest_passes_page(
+    pool: &State<Pool<Postgres>>,
+    _user: AllSitesAdmin,
+    format: Format,
+) -> Negotiated {
+    match get_all_guest_passes(pool).await {
+        Ok(passes) => Negotiated::page(format, "guest_passes", context! { passes: passes }),
+        Err(_) => {
+            guest_passes_error(
+                pool,
+                format,
+                Status::InternalServerError,
+                "Failed to load guest passes",
+            )
+            .await
+        }
+    }
+}
+
+/// Creates a pass. A pass without an npub gets its own handshake URL, which is emailed to the
+/// visitor with a QR code when an `email` is given.
+#[post("/guest-passes", data = "<pass_request>")]
+pub async fn add_guest_pass(
+    pool: &State<Pool<Postgres>>,
+    controller: &State<AccessController>,
+    alerts: &State<Alerter>,
+    user: AllSitesAdmin,
+    audit: Audit,
+    format: Format,
+    pass_request: Form<GuestPassRequest>,
+) -> Negotiated {
+    let label = pass_request.label.trim();
+    let npub = pass_request
+        .npub
+        .as_deref()
+        .map(str::trim)
+        .filter(|npub| !npub.is_empty());
+    let valid_until = pass_request
+        .valid_until
+        .as_deref()
+        .filter(|value| !value.is_empty());
+    let valid_from = pass_request
+        .valid_from
+        .as_deref()
+        .filter(|value| !value.is_empty());
+    let host = pass_request
+        .host
+        .as_deref()
+        .map(str::trim)
+        .filter(|host| !host.is_empty());
+    let email = pass_request
+        .email
+        .as_deref()
+        .map(str::trim)
+        .filter(|email| !email.is_empty());
+
+    if label.is_empty() {
+        return guest_passes_error(
+            pool,
+            format,
+            Status::UnprocessableEntity,
+            "A guest pass needs a label.",
+        )
+        .await;
+    }
+
+    if let Some(npub) = npub
+        && (!npub.starts_with("npub1") || npub.len() != 63)
+    {
+        return guest_passes_error(
+            pool,
+            format,
+            Status::UnprocessableEntity,
+            "Invalid public key format. Must be a valid npub1 key.",
+        )
+        .await;
+    }
+
+    if pass_request.max_uses.is_some_and(|uses| uses < 1) {
+        return guest_passes_error(
+            pool,
+            format,
+            Status::UnprocessableEntity,
+            "The number of uses must be at least 1.",
+        )
+        .await;
+    }
+
+    let expires_at = match valid_until.map(|value| parse_deadline(value, controller.timezone)) {
+        None => None,
+        Some(Some(expires_at)) if expires_at > Utc::now() => Some(expires_at),
+        Some(_) => {
+            return guest_passes_error(
+                pool,
+                format,
+                Status::UnprocessableEntity,
+                "The deadline must be a date and time in the future.",
+            )
+            .await;
+        }
+    };
+
+    let valid_from = match valid_from.map(|value| parse_deadline(value, controller.timezone)) {
+        None => None,
+        Some(Some(valid_from)) if expires_at.is_none_or(|expires_at| valid_from < expires_at) => {
+            Some(valid_from)
+        }
+        Some(_) => {
+            return guest_passes_error(
+                pool,
+                format,
+                Status::UnprocessableEntity,
+                "The start must be a date and time before the deadline.",
+            )
+            .await;
+        }
+    };
+
+    let mailer = match email {
+        None => None,
+        Some(_) if npub.is_some() => {
+            return guest_passes_error(
+                pool,
+                format,
+                Status::UnprocessableEntity,
+                "Only passes without a public key have a handshake URL to email.",
+            )
+            .await;
+        }
+        Some(email) if email.parse::<lettre::message::Mailbox>().is_err() => {
+            return guest_passes_error(
+                pool,
+                format,
+                Status::UnprocessableEntity,
+                "Invalid email address",
+            )
+            .await;
+        }
+        Some(_) => match alerts.mailer() {
+            Some(mailer) => Some(mailer),
+            None => {
+                return guest_passes_error(
+                    pool,
+                    format,
+                    Status::UnprocessableEntity,
+                    "Email is not configured. Set alerts.smtp_url and alerts.smtp_from.",
+                )
+                .await;
+            }
+        },
+    };
+
+    if pass_request.max_uses.is_none() && expires_at.is_none() {
+        return guest_passes_error(
+            pool,
+            format,
+            Status::UnprocessableEntity,
+            "A guest pass needs a number of uses, a deadline, or both.",
+        )
+        .await;
+    }
+
+    let new_pass = NewGuestPass {
+        label,
+        npub,
+        max_uses: pass_request.max_uses,
+        valid_from,
+        expires_at,
+        host,
+        email,
+    };
+    let pass = match insert_guest_pass(pool, &new_pass, &user.0.sub).await {
+        Ok(pass) => pass,
+        Err(_) => {
+            return guest_passes_error(
+                pool,
+                format,
+                Status::InternalServerError,
+                "Failed to create guest pass",
+            )
+            .await;
+        }
+    };
+    info!(guest_pass = %pass.label, by = %user.0.sub, "Guest pass created");
+
+    // Passes not tied to an npub are handed out as their own handshake URL
+    let handshake_url = if npub.is_none() {
+        match controller.open_guest_handshake(&pass).await {
+            Ok(url) => Some(url),
+            Err(e) => {
+                error!(guest_pass = %pass.label, error = %e, "Failed to open guest pass handshake");
+                let _ = delete_guest_pass_by_id(pool, pass.id).await;
+                return guest_passes_error(
+                    pool,
+                    format,
+                    Status::BadGateway,
+                    "Failed to create a handshake URL for the guest pass",
+                )
+                .await;
+            }
+        }
+    } else {
+        None
+    };
+    audit
+        .record(&user.0.sub, "guest_pass.add", Some(&pass.label))
+        .await;
+
+    if let (Some(mailer), Some(email), Some(url)) = (mailer, email, &handshake_url)
+        && let Err(e) = send_invitation(mailer, email, &pass, url, controller.timezone).await
+    {
+        error!(guest_pass = %pass.label, error = %e, "Failed to email visitor invitation");
+        return match format {
+            Format::Html => {
+                guest_passes_error(
+                    pool,
+                    format,
+                    Status::BadGateway,
+                    "Guest pass created, but emailing it failed. Share its handshake URL yourself.",
+                )
+                .await
+            }
+            Format::Json => Negotiated::json(
+                Status::Created,
+                serde_json::json!({ "id": pass.id, "handshake_url": handshake_url, "emailed": false }),
+            ),
+        };
+    }
+
+    Negotiated::done(
+        format,
+        "/guest-passes",
+        Status::Created,
+        serde_json::json!({
+            "id": pass.id,
+            "handshake_url": handshake_url,
+            "emailed": email.is_some()
+        }),
+    )
+}
+
+// Helper function to email a visitor their pass's handshake URL, with a QR code of it to scan
+async fn send_invitation(
+    mailer: &Mailer,
+    email: &str,
+    pass: &GuestPass,
+    handshake_url: &str,
+    timezone: Tz,
+) -> Result<(), String> {
+    let local = |at: DateTime<Utc>| at.with_timezone(&timezone).format("%Y-%m-%d %H:%M %Z");
+    let mut body = match &pass.host {
+        Some(host) => format!(
+            "Hello {},\n\n{} has registered your visit.",
+            pass.label, host
+        ),
+        None => format!("Hello {},\n\nYour visit has been registered.", pass.label),
+    };
+    body.push_str(&format!(
+        " Open this link in your Nostr wallet, or scan the attached QR code, to open the door:\n\n{}\n",
+        handshake_url
+    ));
+    match (pass.valid_from, pass.expires_at) {
+        (Some(from), Some(until)) => body.push_str(&format!(
+            "\nIt works from {} until {}.\n",
+            local(from),
+            local(until)
+        )),
+        (Some(from), None) => body.push_str(&format!("\nIt works from {}.\n", local(from))),
+        (None, Some(until)) => body.push_str(&format!("\nIt works until {}.\n", local(until))),
+        (None, None) => {}
+    }
+
+    let qr_code = MailAttachment {
+        filename: "door.png".to_string(),
+        content_type: ContentType::parse("image/png").expect("Valid content type"),
+        body: render_qr(handshake_url, true)?,
+    };
+    mailer
+        .send(email, "Your visit: door access", body, Some(qr_code))
+        .await
+}
+
+#[post("/guest-passes/<pass_id>/delete")]
+pub async fn delete_guest_pass(
+    pool: &State<Pool<Postgres>>,
+    user: AllSitesAdmin,
+    audit: Audit,
+    format: Format,
+    pass_id: String,
+) -> Negotiated {
+    let uuid = match Uuid::parse_str(&pass_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return guest_passes_error(pool, format, Status::BadRequest, "Invalid guest pass ID")
+                .await;
+        }
+    };
+
+    match delete_guest_pass_by_id(pool, uuid).await {
+        Ok(_) => {
+            info!(guest_pass = %uuid, by = %user.0.sub, "Guest pass revoked");
+            audit
+                .record(&user.0.sub, "guest_pass.delete", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                "/guest-passes",
+                Status::Ok,
+                serde_json::json!({ "id": uuid }),
+            )
+        }
+        Err(_) => {
+            guest_passes_error(
+                pool,
+                format,
+                Status::InternalServerError,
+                "Failed to delete guest pass",
+            )
+            .await
+        }
+    }
+}
+
+// Helper function to parse a deadline from a datetime-local input (site time) or RFC 3339
+fn parse_deadline(value: &str, timezone: Tz) -> Option<DateTime<Utc>> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Some(timestamp.with_timezone(&Utc));
+    }
+
+    let local = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M").ok()?;
+    timezone
+        .from_local_datetime(&local)
+        .earliest()
+        .map(|deadline| deadline.with_timezone(&Utc))
+}
+
+// Helper function to report a guest pass error in the requested format
+async fn guest_passes_error(
+    pool: &Pool<Postgres>,
+    format: Format,
+    status: Status,
+    error_message: &str,
+) -> Negotiated {
+    match format {
+        Format::Html => Negotiated::page(
+            format,
+            "guest_passes",
+            context! {
+                passes: get_all_guest_passes(pool).await.unwrap_or_default(),
+                error_message: error_message
+            },
+        ),
+        Format::Json => Negotiated::error(status, error_message),
+    }
+}
@@ -0,0 +1,88 @@
+use crate::audit::Audit;
+use crate::auth::{CanUnlock, CanUseHomeAssistant};
+use crate::controllers::doors::{DoorActuation, actuate_door};
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::database::helpers::{get_all_doors, get_door_by_id};
+use crate::door_backend::DoorBackend;
+use crate::home_assistant::{self, LockStates};
+use crate::portal_loop::AccessController;
+use crate::request_id::RequestId;
+use rocket::futures::future::join_all;
+use rocket::{State, get, http::Status, post};
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+use tracing::{Instrument, error, info_span};
+use uuid::Uuid;
+
+/// Every door of the token's site as a Home Assistant lock entity: its lock `state`,
+/// `position`, whether it is `available` and when it was `last_changed`.
+#[get("/home-assistant/locks")]
+pub async fn home_assistant_locks(
+    pool: &State<Pool<Postgres>>,
+    door_backend: &State<Arc<dyn DoorBackend>>,
+    states: &State<LockStates>,
+    user: CanUseHomeAssistant,
+) -> Negotiated {
+    let doors = match get_all_doors(pool, user.0.site).await {
+        Ok(doors) => doors,
+        Err(e) => {
+            error!(error = ?e, "Failed to load doors");
+            return Negotiated::error(Status::InternalServerError, "Failed to load doors");
+        }
+    };
+
+    let locks = join_all(
+        doors
+            .iter()
+            .map(|door| home_assistant::lock(door_backend.as_ref(), states, door)),
+    )
+    .await;
+    Negotiated::json(Status::Ok, serde_json::json!({ "locks": locks }))
+}
+
+/// One door as a Home Assistant lock entity.
+#[get("/home-assistant/locks/<door_id>")]
+pub async fn home_assistant_lock(
+    pool: &State<Pool<Postgres>>,
+    door_backend: &State<Arc<dyn DoorBackend>>,
+    states: &State<LockStates>,
+    user: CanUseHomeAssistant,
+    door_id: String,
+) -> Negotiated {
+    let Ok(uuid) = Uuid::parse_str(&door_id) else {
+        return Negotiated::error(Status::BadRequest, "Invalid door ID");
+    };
+    match get_door_by_id(pool, uuid, user.0.site).await {
+        Ok(Some(door)) => Negotiated::json(
+            Status::Ok,
+            home_assistant::lock(door_backend.as_ref(), states, &door).await,
+        ),
+        Ok(None) => Negotiated::error(Status::NotFound, "Door not found"),
+        Err(e) => {
+            error!(error = ?e, "Failed to load door");
+            Negotiated::error(Status::InternalServerError, "Failed to load door")
+        }
+    }
+}
+
+/// The lock's `unlock` service: unlocks the door like `POST /doors/<door_id>/unlock`, recorded
+/// as `token:<name>`. Needs the `unlock` scope as well, so a token that only shows the locks
+/// cannot open the doors.
+#[post("/home-assistant/locks/<door_id>/unlock")]
+pub async fn home_assistant_unlock(
+    controller: &State<AccessController>,
+    request_id: RequestId,
+    user: CanUseHomeAssistant,
+    _unlock: CanUnlock,
+    audit: Audit,
+    door_id: String,
+) -> Negotiated {
+    let actuation = DoorActuation {
+        controller: controller.inner(),
+        unlock: true,
+        return_to: None,
+    };
+    actuate_door(actuation, user.0, audit, Format::Json, &door_id)
+        .instrument(info_span!("manual_door", request_id = %request_id))
+        .await
+}
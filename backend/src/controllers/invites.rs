@@ -0,0 +1,448 @@
+use crate::audit::Audit;
+use crate::auth::{AdminUser, Role, hash_password};
+use crate::client_ip::AllowedClient;
+use crate::config::InvitesConfig;
+use crate::controllers::doors::render_qr;
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::controllers::users::MIN_PASSWORD_LENGTH;
+use crate::database::helpers::{
+    AdminInvite, Site, accept_admin_invite, get_admin_invite_by_token_hash, get_admin_invites,
+    get_all_sites, insert_admin_invite, revoke_admin_invite,
+};
+use crate::totp;
+use chrono::{Duration, Utc};
+use rocket::{State, form::Form, get, http::Status, post};
+use rocket_dyn_templates::context;
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres};
+use tracing::{error, info};
+use uuid::Uuid;
+
+/// How long invite links work and where they point, from `[invites]`.
+pub struct InvitePolicy {
+    ttl: Duration,
+    base_url: String,
+}
+
+impl InvitePolicy {
+    pub fn new(config: &InvitesConfig) -> Self {
+        Self {
+            ttl: Duration::hours(config.ttl_hours.into()),
+            base_url: config.base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn link(&self, token: &str) -> String {
+        format!("{}/invite/{}", self.base_url, token)
+    }
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct InviteRequest {
+    role: Role,
+    site_id: Option<String>, // empty for every site; ignored for admins of one site
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct InviteAcceptance {
+    username: String,
+    password: String,
+    confirm_password: String,
+    // Both empty to sign in without a code
+    totp_secret: Option<String>,
+    totp_code: Option<String>,
+}
+
+// A new link token; only its hash is stored
+fn generate_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[get("/admin/invites")]
+pub async fn invites_page(
+    pool: &State<Pool<Postgres>>,
+    user: AdminUser,
+    format: Format,
+) -> Negotiated {
+    match get_admin_invites(pool, user.0.site).await {
+        Ok(invites) => Negotiated::page(
+            format,
+            "invites",
+            context! {
+                invites: invites,
+                sites: site_choices(pool, user.0.site).await
+            },
+        ),
+        Err(e) => {
+            error!(error = ?e, "Failed to load invites");
+            invites_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to load invites",
+            )
+            .await
+        }
+    }
+}
+
+/// Makes a one-time link for a new account with the given role. The link is only ever shown in
+/// this response, so browsers get the page back with it instead of a redirect.
+#[post("/admin/invite", data = "<invite_request>")]
+pub async fn add_invite(
+    pool: &State<Pool<Postgres>>,
+    policy: &State<InvitePolicy>,
+    user: AdminUser,
+    audit: Audit,
+    format: Format,
+    invite_request: Form<InviteRequest>,
+) -> Negotiated {
+    // Admins of one site can only invite to it
+    let site = match (user.0.site, invite_request.site_id.as_deref()) {
+        (Some(site), _) => Some(site),
+        (None, None | Some("")) => None,
+        (None, Some(id)) => match Uuid::parse_str(id) {
+            Ok(site) => Some(site),
+            Err(_) => {
+                return invites_error(
+                    pool,
+                    user.0.site,
+                    format,
+                    Status::BadRequest,
+                    "Invalid site ID",
+                )
+                .await;
+            }
+        },
+    };
+
+    let token = generate_token();
+    let expires_at = Utc::now() + policy.ttl;
+    match insert_admin_invite(
+        pool,
+        &hash_token(&token),
+        invite_request.role.as_str(),
+        site,
+        &user.0.sub,
+        expires_at,
+    )
+    .await
+    {
+        Ok(id) => {
+            info!(invite = %id, role = invite_request.role.as_str(), by = %user.0.sub, "Invite created");
+            audit
+                .record(&user.0.sub, "invite.add", Some(&id.to_string()))
+                .await;
+            let link = policy.link(&token);
+            match format {
+                Format::Html => Negotiated::page(
+                    format,
+                    "invites",
+                    context! {
+                        invites: get_admin_invites(pool, user.0.site).await.unwrap_or_default(),
+                        sites: site_choices(pool, user.0.site).await,
+                        new_link: link,
+                        new_link_expires_at: expires_at
+                    },
+                ),
+                Format::Json => Negotiated::json(
+                    Status::Created,
+                    serde_json::json!({ "id": id, "link": link, "expires_at": expires_at }),
+                ),
+            }
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to add invite");
+            invites_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to create invite",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/admin/invites/<invite_id>/revoke")]
+pub async fn revoke_invite(
+    pool: &State<Pool<Postgres>>,
+    user: AdminUser,
+    audit: Audit,
+    format: Format,
+    invite_id: String,
+) -> Negotiated {
+    let uuid = match Uuid::parse_str(&invite_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return invites_error(
+                pool,
+                user.0.site,
+                format,
+                Status::BadRequest,
+                "Invalid invite ID",
+            )
+            .await;
+        }
+    };
+
+    match revoke_admin_invite(pool, uuid, user.0.site).await {
+        Ok(true) => {
+            info!(invite = %uuid, by = %user.0.sub, "Invite revoked");
+            audit
+                .record(&user.0.sub, "invite.revoke", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                "/admin/invites",
+                Status::Ok,
+                serde_json::json!({ "id": uuid }),
+            )
+        }
+        Ok(false) => {
+            invites_error(
+                pool,
+                user.0.site,
+                format,
+                Status::NotFound,
+                "Invite not found, or it was already used or revoked.",
+            )
+            .await
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to revoke invite");
+            invites_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to revoke invite",
+            )
+            .await
+        }
+    }
+}
+
+/// Public page an invite link opens, where the new user picks a username and password and can
+/// turn on two-factor sign in with a fresh authenticator app secret.
+#[get("/invite/<token>")]
+pub async fn invite_page(
+    _client: AllowedClient,
+    pool: &State<Pool<Postgres>>,
+    format: Format,
+    token: &str,
+) -> Negotiated {
+    match open_invite(pool, token).await {
+        Ok(invite) => accept_page(format, &invite, token, None, None),
+        Err((status, message)) => invite_error(format, status, message),
+    }
+}
+
+/// Creates the account an invite link is for and uses the link up. A `totp_code` from the
+/// authenticator app proves `totp_secret` was set up before it is turned on.
+#[post("/invite/<token>", data = "<acceptance>")]
+pub async fn accept_invite(
+    _client: AllowedClient,
+    pool: &State<Pool<Postgres>>,
+    audit: Audit,
+    format: Format,
+    token: &str,
+    acceptance: Form<InviteAcceptance>,
+) -> Negotiated {
+    let invite = match open_invite(pool, token).await {
+        Ok(invite) => invite,
+        Err((status, message)) => return invite_error(format, status, message),
+    };
+    let secret = acceptance
+        .totp_secret
+        .as_deref()
+        .map(str::trim)
+        .filter(|secret| !secret.is_empty());
+    let retry = |status, message| {
+        let page = accept_page(format, &invite, token, secret, Some(message));
+        match format {
+            Format::Html => page,
+            Format::Json => Negotiated::error(status, message),
+        }
+    };
+
+    let username = acceptance.username.trim();
+    if username.is_empty() || acceptance.password.len() < MIN_PASSWORD_LENGTH {
+        return retry(
+            Status::UnprocessableEntity,
+            "Pick a username and a password of at least 8 characters.",
+        );
+    }
+    if acceptance.password != acceptance.confirm_password {
+        return retry(Status::UnprocessableEntity, "The passwords do not match.");
+    }
+    let code = acceptance
+        .totp_code
+        .as_deref()
+        .map(str::trim)
+        .filter(|code| !code.is_empty());
+    // The step of the code is kept, so it cannot sign in again right after
+    let step = secret
+        .zip(code)
+        .and_then(|(secret, code)| totp::verify(secret, code));
+    let two_factor = match (secret, code, step) {
+        (Some(secret), Some(_), Some(step)) => Some((secret, step)),
+        (_, Some(_), _) => {
+            return retry(
+                Status::UnprocessableEntity,
+                "The authentication code is wrong. Check the time on your phone and try again.",
+            );
+        }
+        (_, None, _) => None,
+    };
+
+    let password_hash = match hash_password(&acceptance.password) {
+        Ok(password_hash) => password_hash,
+        Err(e) => {
+            error!(error = %e, "Failed to hash password");
+            return retry(Status::InternalServerError, "Failed to create account");
+        }
+    };
+
+    match accept_admin_invite(
+        pool,
+        &hash_token(token),
+        username,
+        &password_hash,
+        two_factor.map(|(secret, _)| secret),
+        two_factor.map(|(_, step)| step),
+    )
+    .await
+    {
+        Ok(Some(id)) => {
+            info!(username, invite = %invite.id, two_factor = two_factor.is_some(), "Invite accepted");
+            audit
+                .record(username, "invite.accept", Some(&invite.id.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                "/login",
+                Status::Created,
+                serde_json::json!({ "id": id, "username": username, "role": invite.role }),
+            )
+        }
+        Ok(None) => invite_error(
+            format,
+            Status::Gone,
+            "This invite link has expired, was revoked or was already used.",
+        ),
+        Err(_) => retry(
+            Status::Conflict,
+            "Failed to create account. The username may already be taken.",
+        ),
+    }
+}
+
+// The invite behind a link, if it can still be used
+async fn open_invite(
+    pool: &Pool<Postgres>,
+    token: &str,
+) -> Result<AdminInvite, (Status, &'static str)> {
+    match get_admin_invite_by_token_hash(pool, &hash_token(token)).await {
+        Ok(Some(invite)) if invite.status == "open" => Ok(invite),
+        Ok(Some(_)) => Err((
+            Status::Gone,
+            "This invite link has expired, was revoked or was already used.",
+        )),
+        Ok(None) => Err((Status::NotFound, "This invite link is not valid.")),
+        Err(e) => {
+            error!(error = ?e, "Failed to load invite");
+            Err((Status::InternalServerError, "Failed to load invite"))
+        }
+    }
+}
+
+// Helper function to render the page a new user sets up their account on, with an
+// authenticator app secret: the one they already scanned when the form comes back, or a new one
+fn accept_page(
+    format: Format,
+    invite: &AdminInvite,
+    token: &str,
+    secret: Option<&str>,
+    error_message: Option<&str>,
+) -> Negotiated {
+    let secret = secret
+        .filter(|secret| totp::setup_key(secret).is_some())
+        .map(str::to_string)
+        .unwrap_or_else(totp::generate_secret);
+    // The username is not picked yet, so the app lists the account by site or role
+    let account = invite.site_name.as_deref().unwrap_or(&invite.role);
+    let uri = totp::otpauth_uri(account, &secret);
+    let qr = uri
+        .as_deref()
+        .and_then(|uri| render_qr(uri, false).ok())
+        .and_then(|svg| String::from_utf8(svg).ok());
+    Negotiated::page(
+        format,
+        "invite",
+        context! {
+            token: token,
+            role: &invite.role,
+            site_name: &invite.site_name,
+            expires_at: invite.expires_at,
+            totp_secret: &secret,
+            totp_key: totp::setup_key(&secret),
+            otpauth_uri: uri,
+            totp_qr: qr,
+            min_length: MIN_PASSWORD_LENGTH,
+            error_message: error_message
+        },
+    )
+}
+
+// Helper function to report a link that cannot be used in the requested format
+fn invite_error(format: Format, status: Status, error_message: &str) -> Negotiated {
+    match format {
+        Format::Html => Negotiated::page(
+            format,
+            "invite",
+            context! {
+                invalid: true,
+                error_message: error_message
+            },
+        ),
+        Format::Json => Negotiated::error(status, error_message),
+    }
+}
+
+// Helper function to list the sites an invite can be limited to, only offered to admins of
+// every site
+async fn site_choices(pool: &Pool<Postgres>, site: Option<Uuid>) -> Vec<Site> {
+    match site {
+        Some(_) => Vec::new(),
+        None => get_all_sites(pool).await.unwrap_or_default(),
+    }
+}
+
+// Helper function to report an invite management error in the requested format
+async fn invites_error(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+    format: Format,
+    status: Status,
+    error_message: &str,
+) -> Negotiated {
+    match format {
+        Format::Html => Negotiated::page(
+            format,
+            "invites",
+            context! {
+                invites: get_admin_invites(pool, site).await.unwrap_or_default(),
+                sites: site_choices(pool, site).await,
+                error_message: error_message
+            },
+        ),
+        Format::Json => Negotiated::error(status, error_message),
+    }
+}
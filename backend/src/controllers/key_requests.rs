@@ -0,0 +1,120 @@
+use crate::client_ip::ClientIp;
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::database::helpers::{count_pending_keys_from_ip, insert_pending_key};
+use crate::templating::Page;
+use rocket::{State, form::Form, get, http::Status, post};
+use rocket_dyn_templates::context;
+use sqlx::{Pool, Postgres};
+use tracing::{error, info};
+
+// Longest note kept with a request
+const MAX_NOTE_LEN: usize = 500;
+
+// Requests one address may have waiting at once, so the page cannot be used to flood the list
+const MAX_PENDING_PER_IP: i64 = 3;
+
+#[derive(rocket::form::FromForm)]
+pub struct AccessRequest {
+    npub: String,
+    note: Option<String>,
+}
+
+/// Public page where a visitor asks for a key. Requests wait on the keys page for an admin.
+#[get("/request-access")]
+pub fn request_access_page() -> Page {
+    Page::render("request_access", context! {})
+}
+
+#[post("/request-access", data = "<access_request>")]
+pub async fn request_access(
+    pool: &State<Pool<Postgres>>,
+    format: Format,
+    ip: ClientIp,
+    access_request: Form<AccessRequest>,
+) -> Negotiated {
+    let npub = access_request.npub.trim();
+    if !npub.starts_with("npub1") || npub.len() != 63 {
+        return request_access_error(
+            format,
+            Status::UnprocessableEntity,
+            "Invalid public key format. Must be a valid npub1 key.",
+        );
+    }
+
+    let note = access_request
+        .note
+        .as_deref()
+        .map(str::trim)
+        .filter(|note| !note.is_empty());
+    if note.is_some_and(|note| note.chars().count() > MAX_NOTE_LEN) {
+        return request_access_error(
+            format,
+            Status::UnprocessableEntity,
+            &format!("The note may be at most {} characters.", MAX_NOTE_LEN),
+        );
+    }
+
+    let ip = ip.0.map(|ip| ip.to_string());
+    if let Some(ip) = &ip {
+        match count_pending_keys_from_ip(pool, ip).await {
+            Ok(count) if count >= MAX_PENDING_PER_IP => {
+                return request_access_error(
+                    format,
+                    Status::TooManyRequests,
+                    "Too many requests are already waiting for approval. Please try again later.",
+                );
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!(error = ?e, "Failed to count pending access requests");
+                return request_access_error(
+                    format,
+                    Status::InternalServerError,
+                    "Failed to submit the request",
+                );
+            }
+        }
+    }
+
+    match insert_pending_key(pool, npub, note, ip.as_deref()).await {
+        Ok(true) => {
+            info!(%npub, "Access requested");
+            match format {
+                Format::Html => Negotiated::page(
+                    format,
+                    "request_access",
+                    context! {
+                        success_message: "Your request was sent. An administrator will review it."
+                    },
+                ),
+                Format::Json => {
+                    Negotiated::json(Status::Created, serde_json::json!({ "npub": npub }))
+                }
+            }
+        }
+        Ok(false) => request_access_error(
+            format,
+            Status::Conflict,
+            "This key already has access or is waiting for approval.",
+        ),
+        Err(e) => {
+            error!(error = ?e, "Failed to store access request");
+            request_access_error(
+                format,
+                Status::InternalServerError,
+                "Failed to submit the request",
+            )
+        }
+    }
+}
+
+// Helper function to report an access request error in the requested format
+fn request_access_error(format: Format, status: Status, error_message: &str) -> Negotiated {
+    Negotiated::error_page(
+        format,
+        status,
+        "request_access",
+        context! { error_message: error_message },
+        error_message,
+    )
+}
@@ -0,0 +1,55 @@
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::i18n::{Catalogs, set_language_cookie};
+use rocket::http::uri::Absolute;
+use rocket::http::{CookieJar, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::{State, form::Form, post};
+use std::convert::Infallible;
+
+#[derive(rocket::form::FromForm)]
+pub struct LanguageRequest {
+    lang: String,
+}
+
+// Path of the page a form was sent from, taken from the Referer header. Only the path is kept,
+// so it cannot send the browser to another site.
+pub struct ReturnTo(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ReturnTo {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let path = req
+            .headers()
+            .get_one("Referer")
+            .and_then(|referer| Absolute::parse(referer).ok())
+            .map(|referer| referer.path().to_string())
+            .filter(|path| path.starts_with('/') && !path.starts_with("//"));
+        Outcome::Success(ReturnTo(path))
+    }
+}
+
+/// Remembers the language the admin UI is shown in, in a cookie, and goes back to the page it
+/// was picked on.
+#[post("/language", data = "<language_request>")]
+pub fn set_language(
+    catalogs: &State<Catalogs>,
+    cookies: &CookieJar<'_>,
+    return_to: ReturnTo,
+    format: Format,
+    language_request: Form<LanguageRequest>,
+) -> Negotiated {
+    let language = language_request.lang.trim().to_lowercase();
+    if !catalogs.supports(&language) {
+        return Negotiated::error(Status::UnprocessableEntity, "Unknown language");
+    }
+
+    set_language_cookie(cookies, &language);
+    Negotiated::done(
+        format,
+        return_to.0.unwrap_or_else(|| "/dashboard".to_string()),
+        Status::Ok,
+        serde_json::json!({ "lang": language }),
+    )
+}
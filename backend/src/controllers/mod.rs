@@ -1 +1,53 @@
-pub mod access;
\ No newline at end of file
+pub mod access;
+pub mod admin_socket;
+pub mod alerts;
+pub mod api_docs;
+pub mod api_tokens;
+pub mod audit;
+pub mod cards;
+pub mod dashboard;
+pub mod doorbell;
+pub mod doors;
+pub mod emergency;
+pub mod groups;
+pub mod guest_passes;
+pub mod home_assistant;
+pub mod invites;
+pub mod key_requests;
+pub mod language;
+pub mod negotiation;
+pub mod opening_hours;
+pub mod pwa;
+pub mod schedules;
+pub mod sessions;
+pub mod settings;
+pub mod sites;
+pub mod status;
+pub mod users;
+pub mod webhooks;
+
+use crate::controllers::negotiation::Negotiated;
+use crate::database::helpers::get_key_by_id;
+use rocket::http::Status;
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+
+/// Parses the key ID of a `/keys/<key_id>/...` route, answering with an error unless the key
+/// exists in `site`.
+pub async fn site_key(
+    pool: &Pool<Postgres>,
+    key_id: &str,
+    site: Option<Uuid>,
+) -> Result<Uuid, Negotiated> {
+    let Ok(key_id) = Uuid::parse_str(key_id) else {
+        return Err(Negotiated::error(Status::BadRequest, "Invalid key ID"));
+    };
+    match get_key_by_id(pool, key_id, site).await {
+        Ok(Some(_)) => Ok(key_id),
+        Ok(None) => Err(Negotiated::error(Status::NotFound, "Key not found")),
+        Err(_) => Err(Negotiated::error(
+            Status::InternalServerError,
+            "Failed to load key",
+        )),
+    }
+}
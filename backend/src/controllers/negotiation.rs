@@ -0,0 +1,137 @@
+use crate::templating::Page;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Accept, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::Redirect;
+use rocket::serde::json::Json;
+use rocket::{Data, Responder};
+use serde::Serialize;
+use std::convert::Infallible;
+
+/// Mount point of the JSON REST API.
+pub const API_BASE: &str = "/api/v1";
+
+/// Response format picked from the `Accept` header (or a `.json` path suffix).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Html,
+    Json,
+}
+
+impl Format {
+    pub fn of(req: &Request<'_>) -> Self {
+        // Everything under the versioned API speaks JSON regardless of headers
+        let wants_json = req.uri().path().starts_with(API_BASE)
+            || req
+                .accept()
+                .map(|accept| accept.preferred().media_type().is_json())
+                .unwrap_or(false);
+
+        if wants_json {
+            Format::Json
+        } else {
+            Format::Html
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Format {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Format::of(req))
+    }
+}
+
+/// One responder for both browsers and API clients, so handlers share a single code path.
+#[derive(Responder)]
+pub enum Negotiated {
+    Page(Page),
+    Redirect(Box<Redirect>),
+    Json((Status, Json<serde_json::Value>)),
+}
+
+impl Negotiated {
+    /// Renders `template` for browsers, or returns the same context as JSON.
+    pub fn page<C: Serialize>(format: Format, template: &'static str, context: C) -> Self {
+        match format {
+            Format::Html => Negotiated::Page(Page::render(template, context)),
+            Format::Json => Negotiated::json(Status::Ok, context),
+        }
+    }
+
+    /// Outcome of a successful mutation: browsers follow `redirect_to`, clients get `body`.
+    pub fn done<B: Serialize>(
+        format: Format,
+        redirect_to: impl Into<String>,
+        status: Status,
+        body: B,
+    ) -> Self {
+        match format {
+            Format::Html => Negotiated::redirect(redirect_to),
+            Format::Json => Negotiated::json(status, body),
+        }
+    }
+
+    pub fn redirect(uri: impl Into<String>) -> Self {
+        Negotiated::Redirect(Box::new(Redirect::to(uri.into())))
+    }
+
+    /// A failed request: browsers get `template` rendered with `context` under `status`,
+    /// clients the usual `{"error": message}`.
+    pub fn error_page<C: Serialize>(
+        format: Format,
+        status: Status,
+        template: &'static str,
+        context: C,
+        message: &str,
+    ) -> Self {
+        match format {
+            Format::Html => Negotiated::Page(Page::render(template, context).with_status(status)),
+            Format::Json => Negotiated::error(status, message),
+        }
+    }
+
+    pub fn error(status: Status, message: &str) -> Self {
+        Negotiated::json(status, serde_json::json!({ "error": message }))
+    }
+
+    pub fn json<B: Serialize>(status: Status, body: B) -> Self {
+        match serde_json::to_value(body) {
+            Ok(value) => Negotiated::Json((status, Json(value))),
+            Err(_) => {
+                Negotiated::error(Status::InternalServerError, "Failed to serialize response")
+            }
+        }
+    }
+}
+
+/// Rewrites `/path.json` to `/path` with `Accept: application/json`.
+pub struct JsonExtension;
+
+#[rocket::async_trait]
+impl Fairing for JsonExtension {
+    fn info(&self) -> Info {
+        Info {
+            name: "JSON path extension",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let rewritten = match req.uri().path().as_str().strip_suffix(".json") {
+            Some(path) => match req.uri().query() {
+                Some(query) => format!("{}?{}", path, query),
+                None => path.to_string(),
+            },
+            None => return,
+        };
+
+        if let Ok(uri) = Origin::parse_owned(rewritten) {
+            req.set_uri(uri);
+            req.replace_header(Accept::JSON);
+        }
+    }
+}
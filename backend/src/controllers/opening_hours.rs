@@ -0,0 +1,88 @@
+use crate::config::OpeningHoursConfig;
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::emergency::OverrideState;
+use crate::opening_hours;
+use chrono_tz::Tz;
+use rocket::{State, get, http::Status};
+use rocket_dyn_templates::context;
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres};
+use tracing::error;
+
+/// Whether the opening hours page is served and to whom, from `[opening_hours]`.
+pub struct OpeningHoursPolicy {
+    enabled: bool,
+    // Hash of the member token, compared instead of the token so the time a comparison takes
+    // says nothing about it
+    token_hash: Option<String>,
+    timezone: Tz,
+}
+
+impl OpeningHoursPolicy {
+    pub fn new(config: &OpeningHoursConfig, timezone: Tz) -> Self {
+        let token = config.token.trim();
+        Self {
+            enabled: config.enabled,
+            token_hash: (!token.is_empty()).then(|| hash_token(token)),
+            timezone,
+        }
+    }
+
+    fn admits(&self, token: Option<&str>) -> bool {
+        match &self.token_hash {
+            Some(expected) => token.is_some_and(|token| hash_token(token.trim()) == *expected),
+            None => true,
+        }
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Public page telling members which doors are open right now and when they are held open
+/// during the week. Needs `?token=` when a member token is configured.
+#[get("/opening-hours?<token>")]
+pub async fn opening_hours_page(
+    policy: &State<OpeningHoursPolicy>,
+    pool: &State<Pool<Postgres>>,
+    overrides: &State<OverrideState>,
+    format: Format,
+    token: Option<&str>,
+) -> Negotiated {
+    if !policy.enabled {
+        return opening_hours_error(format, Status::NotFound, "This page is not available.");
+    }
+    if !policy.admits(token) {
+        return opening_hours_error(
+            format,
+            Status::Forbidden,
+            "Open this page from the link you were given as a member.",
+        );
+    }
+
+    match opening_hours::current(pool, overrides, policy.timezone).await {
+        Ok(sites) => Negotiated::page(format, "opening_hours", context! { sites: sites }),
+        Err(e) => {
+            error!(error = ?e, "Failed to load opening hours");
+            opening_hours_error(
+                format,
+                Status::InternalServerError,
+                "Failed to load the opening hours",
+            )
+        }
+    }
+}
+
+fn opening_hours_error(format: Format, status: Status, error_message: &str) -> Negotiated {
+    match format {
+        Format::Html => Negotiated::page(
+            format,
+            "opening_hours",
+            context! {
+                error_message: error_message
+            },
+        ),
+        Format::Json => Negotiated::error(status, error_message),
+    }
+}
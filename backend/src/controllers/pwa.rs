@@ -0,0 +1,60 @@
+use crate::theme::Theme;
+use rocket::State;
+use rocket::fs::{NamedFile, relative};
+use rocket::get;
+use rocket::http::{ContentType, Header};
+use rocket::serde::json::Json;
+use serde_json::{Value, json};
+
+/// Web app manifest body with its own content type.
+#[derive(rocket::Responder)]
+pub struct Manifest {
+    body: Json<Value>,
+    content_type: ContentType,
+}
+
+/// Service worker script, revalidated on every load so an update is picked up at once.
+#[derive(rocket::Responder)]
+pub struct ServiceWorker {
+    body: NamedFile,
+    cache_control: Header<'static>,
+}
+
+/// Web app manifest, so phones can install the admin panel to the home screen. Browsers fetch
+/// it without cookies, so it is public. Named and colored after the theme.
+#[get("/manifest.webmanifest")]
+pub fn manifest(theme: &State<Theme>) -> Manifest {
+    let theme = theme.current();
+    let background = theme.background_color.as_deref().unwrap_or("#1a1a2e");
+    Manifest {
+        body: Json(json!({
+            "name": theme.site_name,
+            "short_name": "Portal",
+            "description": "Keys, doors and access logs of the building",
+            "start_url": "/dashboard",
+            "scope": "/",
+            "display": "standalone",
+            "background_color": background,
+            "theme_color": background,
+            "icons": [
+                {
+                    "src": "/static/icons/icon.svg",
+                    "sizes": "any",
+                    "type": "image/svg+xml",
+                    "purpose": "any maskable"
+                }
+            ]
+        })),
+        content_type: ContentType::new("application", "manifest+json"),
+    }
+}
+
+/// The service worker, served from the root so it covers every page. See `static/js/sw.js`.
+#[get("/sw.js")]
+pub async fn service_worker() -> Option<ServiceWorker> {
+    let body = NamedFile::open(relative!("static/js/sw.js")).await.ok()?;
+    Some(ServiceWorker {
+        body,
+        cache_control: Header::new("Cache-Control", "no-cache"),
+    })
+}
@@ -0,0 +1,175 @@
+use crate::audit::Audit;
+use crate::auth::{CanManageKeys, CanReadKeys};
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::controllers::site_key;
+use crate::database::helpers::{
+    delete_schedule_by_id, get_key_by_id, get_key_schedules, insert_schedule,
+};
+use chrono::NaiveTime;
+use rocket::{State, form::Form, get, http::Status, post};
+use rocket_dyn_templates::context;
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+
+// Indexed by ISO weekday number, so the template can look names up directly
+pub const DAY_NAMES: [&str; 8] = ["", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+#[derive(rocket::form::FromForm)]
+pub struct ScheduleRequest {
+    weekdays: Vec<i16>,
+    start_time: String,
+    end_time: String,
+}
+
+#[get("/keys/<key_id>/schedules")]
+pub async fn schedules_page(
+    pool: &State<Pool<Postgres>>,
+    user: CanReadKeys,
+    format: Format,
+    key_id: String,
+) -> Negotiated {
+    match site_key(pool, &key_id, user.0.site).await {
+        Ok(uuid) => render_schedules(pool, format, uuid, Status::Ok, None).await,
+        Err(error) => error,
+    }
+}
+
+#[post("/keys/<key_id>/schedules", data = "<schedule_request>")]
+pub async fn add_schedule(
+    pool: &State<Pool<Postgres>>,
+    user: CanManageKeys,
+    audit: Audit,
+    format: Format,
+    key_id: String,
+    schedule_request: Form<ScheduleRequest>,
+) -> Negotiated {
+    let uuid = match site_key(pool, &key_id, user.0.site).await {
+        Ok(uuid) => uuid,
+        Err(error) => return error,
+    };
+
+    let weekdays = &schedule_request.weekdays;
+    if weekdays.is_empty() || weekdays.iter().any(|day| !(1..=7).contains(day)) {
+        return render_schedules(
+            pool,
+            format,
+            uuid,
+            Status::UnprocessableEntity,
+            Some("Pick at least one day of the week."),
+        )
+        .await;
+    }
+
+    let (start_time, end_time) = match (
+        parse_time(&schedule_request.start_time),
+        parse_time(&schedule_request.end_time),
+    ) {
+        (Some(start_time), Some(end_time)) if start_time < end_time => (start_time, end_time),
+        _ => {
+            return render_schedules(
+                pool,
+                format,
+                uuid,
+                Status::UnprocessableEntity,
+                Some("Times must be HH:MM and the window must end after it starts."),
+            )
+            .await;
+        }
+    };
+
+    match insert_schedule(pool, uuid, weekdays, start_time, end_time).await {
+        Ok(id) => {
+            audit
+                .record(&user.0.sub, "schedule.add", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                format!("/keys/{}/schedules", uuid),
+                Status::Created,
+                serde_json::json!({ "id": id }),
+            )
+        }
+        Err(_) => {
+            render_schedules(
+                pool,
+                format,
+                uuid,
+                Status::InternalServerError,
+                Some("Failed to add schedule. The key may no longer exist."),
+            )
+            .await
+        }
+    }
+}
+
+#[post("/keys/<key_id>/schedules/<schedule_id>/delete")]
+pub async fn delete_schedule(
+    pool: &State<Pool<Postgres>>,
+    user: CanManageKeys,
+    audit: Audit,
+    format: Format,
+    key_id: String,
+    schedule_id: String,
+) -> Negotiated {
+    let Ok(schedule_uuid) = Uuid::parse_str(&schedule_id) else {
+        return Negotiated::error(Status::BadRequest, "Invalid schedule ID");
+    };
+    let uuid = match site_key(pool, &key_id, user.0.site).await {
+        Ok(uuid) => uuid,
+        Err(error) => return error,
+    };
+
+    match delete_schedule_by_id(pool, uuid, schedule_uuid).await {
+        Ok(_) => {
+            audit
+                .record(&user.0.sub, "schedule.delete", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                format!("/keys/{}/schedules", uuid),
+                Status::Ok,
+                serde_json::json!({ "id": schedule_uuid }),
+            )
+        }
+        Err(_) => {
+            render_schedules(
+                pool,
+                format,
+                uuid,
+                Status::InternalServerError,
+                Some("Failed to delete schedule"),
+            )
+            .await
+        }
+    }
+}
+
+// Helper function to parse an HTML time input ("08:00", or "08:00:00" from some browsers)
+pub fn parse_time(value: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(value, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(value, "%H:%M:%S"))
+        .ok()
+}
+
+// Helper function to render a key's schedules, or report an error, in the requested format
+async fn render_schedules(
+    pool: &Pool<Postgres>,
+    format: Format,
+    key_id: Uuid,
+    status: Status,
+    error_message: Option<&str>,
+) -> Negotiated {
+    match (format, error_message) {
+        (Format::Json, Some(error_message)) => Negotiated::error(status, error_message),
+        _ => Negotiated::page(
+            format,
+            "schedules",
+            context! {
+                key: get_key_by_id(pool, key_id, None).await.ok().flatten(),
+                schedules: get_key_schedules(pool, key_id).await.unwrap_or_default(),
+                day_names: DAY_NAMES,
+                error_message: error_message
+            },
+        ),
+    }
+}
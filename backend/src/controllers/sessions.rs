@@ -0,0 +1,100 @@
+use crate::audit::Audit;
+use crate::auth::AllSitesAdmin;
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::database::helpers::{delete_session_by_id, get_active_sessions};
+use rocket::{State, get, http::Status, post};
+use rocket_dyn_templates::context;
+use sqlx::{Pool, Postgres};
+use tracing::{error, info};
+use uuid::Uuid;
+
+#[get("/sessions")]
+pub async fn sessions_page(
+    pool: &State<Pool<Postgres>>,
+    user: AllSitesAdmin,
+    format: Format,
+) -> Negotiated {
+    match get_active_sessions(pool).await {
+        Ok(sessions) => Negotiated::page(
+            format,
+            "sessions",
+            context! {
+                sessions: sessions,
+                current_session: user.0.sid
+            },
+        ),
+        Err(e) => {
+            error!(error = ?e, "Failed to load sessions");
+            sessions_error(
+                pool,
+                format,
+                Status::InternalServerError,
+                "Failed to load sessions",
+            )
+            .await
+        }
+    }
+}
+
+/// Revoked sessions can no longer be refreshed; their current access token still works until
+/// it expires.
+#[post("/sessions/<session_id>/revoke")]
+pub async fn revoke_session(
+    pool: &State<Pool<Postgres>>,
+    user: AllSitesAdmin,
+    audit: Audit,
+    format: Format,
+    session_id: String,
+) -> Negotiated {
+    let uuid = match Uuid::parse_str(&session_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return sessions_error(pool, format, Status::BadRequest, "Invalid session ID").await;
+        }
+    };
+
+    match delete_session_by_id(pool, uuid).await {
+        Ok(true) => {
+            info!(session = %uuid, by = %user.0.sub, "Session revoked");
+            audit
+                .record(&user.0.sub, "session.revoke", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                "/sessions",
+                Status::Ok,
+                serde_json::json!({ "id": uuid }),
+            )
+        }
+        Ok(false) => sessions_error(pool, format, Status::NotFound, "Session not found").await,
+        Err(_) => {
+            sessions_error(
+                pool,
+                format,
+                Status::InternalServerError,
+                "Failed to revoke session",
+            )
+            .await
+        }
+    }
+}
+
+// Helper function to report a session management error in the requested format
+async fn sessions_error(
+    pool: &Pool<Postgres>,
+    format: Format,
+    status: Status,
+    error_message: &str,
+) -> Negotiated {
+    match format {
+        Format::Html => Negotiated::page(
+            format,
+            "sessions",
+            context! {
+                sessions: get_active_sessions(pool).await.unwrap_or_default(),
+                error_message: error_message
+            },
+        ),
+        Format::Json => Negotiated::error(status, error_message),
+    }
+}
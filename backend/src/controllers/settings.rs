@@ -0,0 +1,468 @@
+use crate::audit::Audit;
+use crate::auth::{AllSitesAdmin, ViewerUser, hash_password, verify_password};
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::controllers::users::MIN_PASSWORD_LENGTH;
+use crate::database::helpers::{
+    delete_other_sessions, get_theme, get_user_by_username, set_theme, set_theme_logo,
+    set_user_password,
+};
+use crate::photos::{Photos, image_type, logo_name};
+use crate::sessions::{SessionPolicy, Sessions};
+use crate::theme::{DEFAULT_SITE_NAME, Theme, is_color};
+use rocket::fs::TempFile;
+use rocket::http::ContentType;
+use rocket::tokio::io::AsyncReadExt;
+use rocket::{State, form::Form, get, http::Status, post};
+use rocket_dyn_templates::context;
+use sqlx::{Pool, Postgres};
+use tracing::{error, info, warn};
+
+// Longest site name the theme takes, so it fits the navigation bar
+const MAX_SITE_NAME_LENGTH: usize = 60;
+
+/// The one page a user whose password has expired can still reach.
+pub const PASSWORD_PATH: &str = "/settings/password";
+
+#[derive(rocket::form::FromForm)]
+pub struct PasswordChange {
+    current_password: String,
+    new_password: String,
+    confirm_password: String,
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct ThemeRequest {
+    site_name: String,        // empty for the default name
+    primary_color: String,    // #rrggbb, empty for the built-in color
+    background_color: String, // likewise
+    text_color: String,       // likewise
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct LogoRequest<'r> {
+    logo: TempFile<'r>,
+}
+
+#[get("/settings/password?<changed>")]
+pub async fn password_page(
+    pool: &State<Pool<Postgres>>,
+    user: ViewerUser,
+    policy: &State<SessionPolicy>,
+    format: Format,
+    changed: Option<bool>,
+) -> Negotiated {
+    match get_user_by_username(pool, &user.0.sub).await {
+        Ok(Some(account)) => {
+            let expires_at = policy.password_expires_at(account.password_changed_at);
+            let expired = policy.password_expired(account.password_changed_at);
+            match format {
+                Format::Html => Negotiated::page(
+                    format,
+                    "password",
+                    context! {
+                        changed_at: account.password_changed_at,
+                        expires_at: expires_at,
+                        expired: expired,
+                        min_length: MIN_PASSWORD_LENGTH,
+                        success_message: changed.is_some().then_some("Password changed")
+                    },
+                ),
+                Format::Json => Negotiated::json(
+                    Status::Ok,
+                    serde_json::json!({
+                        "changed_at": account.password_changed_at,
+                        "expires_at": expires_at,
+                        "expired": expired
+                    }),
+                ),
+            }
+        }
+        Ok(None) => Negotiated::error(Status::NotFound, "User not found"),
+        Err(e) => {
+            error!(error = ?e, "Failed to load user");
+            Negotiated::error(Status::InternalServerError, "Failed to load user")
+        }
+    }
+}
+
+/// Changes the signed-in user's password after checking the current one, and signs out every
+/// other session of theirs. API tokens are not tied to a password and keep working.
+#[post("/settings/password", data = "<change>")]
+pub async fn change_password(
+    pool: &State<Pool<Postgres>>,
+    user: ViewerUser,
+    sessions: Sessions<'_>,
+    audit: Audit,
+    format: Format,
+    change: Form<PasswordChange>,
+) -> Negotiated {
+    let account = match get_user_by_username(pool, &user.0.sub).await {
+        Ok(Some(account)) => account,
+        Ok(None) => {
+            return password_error(format, Status::NotFound, "User not found");
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to load user");
+            return password_error(
+                format,
+                Status::InternalServerError,
+                "Failed to check credentials",
+            );
+        }
+    };
+
+    if !verify_password(&change.current_password, &account.password_hash) {
+        audit
+            .record(&user.0.sub, "user.password.failed", None)
+            .await;
+        return password_error(
+            format,
+            Status::Forbidden,
+            "The current password is incorrect.",
+        );
+    }
+    if change.new_password.len() < MIN_PASSWORD_LENGTH {
+        return password_error(
+            format,
+            Status::UnprocessableEntity,
+            "The new password must be at least 8 characters.",
+        );
+    }
+    if change.new_password != change.confirm_password {
+        return password_error(
+            format,
+            Status::UnprocessableEntity,
+            "The new passwords do not match.",
+        );
+    }
+    if change.new_password == change.current_password {
+        return password_error(
+            format,
+            Status::UnprocessableEntity,
+            "The new password must differ from the current one.",
+        );
+    }
+
+    let password_hash = match hash_password(&change.new_password) {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!(error = %e, "Failed to hash password");
+            return password_error(
+                format,
+                Status::InternalServerError,
+                "Failed to change password",
+            );
+        }
+    };
+
+    match set_user_password(pool, &user.0.sub, &password_hash).await {
+        Ok(true) => {}
+        Ok(false) => return password_error(format, Status::NotFound, "User not found"),
+        Err(e) => {
+            error!(error = ?e, "Failed to change password");
+            return password_error(
+                format,
+                Status::InternalServerError,
+                "Failed to change password",
+            );
+        }
+    }
+
+    let signed_out = match delete_other_sessions(pool, &user.0.sub, user.0.sid).await {
+        Ok(count) => count,
+        Err(e) => {
+            error!(error = ?e, user = %user.0.sub, "Failed to revoke other sessions");
+            0
+        }
+    };
+    // Re-issue this session's access token, which may still be marked as having to change the
+    // password; clients without the refresh cookie call /refresh themselves
+    let token = sessions.refresh().await.ok().map(|(_, token)| token);
+
+    info!(user = %user.0.sub, signed_out, "Password changed");
+    audit.record(&user.0.sub, "user.password", None).await;
+    Negotiated::done(
+        format,
+        "/settings/password?changed=true",
+        Status::Ok,
+        serde_json::json!({
+            "message": "Password changed",
+            "sessions_revoked": signed_out,
+            "token": token
+        }),
+    )
+}
+
+// Helper function to report a password change error in the requested format
+fn password_error(format: Format, status: Status, error_message: &str) -> Negotiated {
+    match format {
+        Format::Html => Negotiated::page(
+            format,
+            "password",
+            context! {
+                min_length: MIN_PASSWORD_LENGTH,
+                error_message: error_message
+            },
+        ),
+        Format::Json => Negotiated::error(status, error_message),
+    }
+}
+
+/// The site name, colors and logo every page carries, see `theme`. Shared by all sites.
+#[get("/settings/theme")]
+pub async fn theme_page(
+    pool: &State<Pool<Postgres>>,
+    _user: AllSitesAdmin,
+    format: Format,
+) -> Negotiated {
+    render_theme(pool, format, Status::Ok, None).await
+}
+
+#[post("/settings/theme", data = "<theme_request>")]
+pub async fn set_theme_settings(
+    pool: &State<Pool<Postgres>>,
+    theme: &State<Theme>,
+    user: AllSitesAdmin,
+    audit: Audit,
+    format: Format,
+    theme_request: Form<ThemeRequest>,
+) -> Negotiated {
+    let site_name = optional(&theme_request.site_name);
+    if site_name.is_some_and(|name| name.chars().count() > MAX_SITE_NAME_LENGTH) {
+        return render_theme(
+            pool,
+            format,
+            Status::UnprocessableEntity,
+            Some("The site name may be at most 60 characters."),
+        )
+        .await;
+    }
+    let colors = [
+        optional(&theme_request.primary_color),
+        optional(&theme_request.background_color),
+        optional(&theme_request.text_color),
+    ];
+    if colors.iter().flatten().any(|color| !is_color(color)) {
+        return render_theme(
+            pool,
+            format,
+            Status::UnprocessableEntity,
+            Some("Colors are written as # and six hex digits, such as #4fc3f7."),
+        )
+        .await;
+    }
+
+    match set_theme(pool, site_name, colors, &user.0.sub).await {
+        Ok(settings) => {
+            theme.set(&settings);
+            audit.record(&user.0.sub, "theme.update", None).await;
+            Negotiated::done(format, "/settings/theme", Status::Ok, settings)
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to update theme");
+            render_theme(
+                pool,
+                format,
+                Status::InternalServerError,
+                Some("Failed to update theme"),
+            )
+            .await
+        }
+    }
+}
+
+/// Replaces the logo with an uploaded JPEG, PNG or WebP image of at most `photos.max_bytes`,
+/// kept in the photo store.
+#[post("/settings/theme/logo", data = "<logo_request>")]
+pub async fn upload_logo(
+    pool: &State<Pool<Postgres>>,
+    theme: &State<Theme>,
+    photos: &State<Photos>,
+    user: AllSitesAdmin,
+    audit: Audit,
+    format: Format,
+    logo_request: Form<LogoRequest<'_>>,
+) -> Negotiated {
+    if logo_request.logo.len() > photos.max_bytes {
+        return render_theme(
+            pool,
+            format,
+            Status::PayloadTooLarge,
+            Some(&format!(
+                "The logo may be at most {} KB.",
+                photos.max_bytes / 1024
+            )),
+        )
+        .await;
+    }
+    let mut body = Vec::new();
+    let read = match logo_request.logo.open().await {
+        Ok(mut file) => file.read_to_end(&mut body).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    let Some((content_type, extension)) = read.ok().and_then(|()| image_type(&body)) else {
+        return render_theme(
+            pool,
+            format,
+            Status::UnsupportedMediaType,
+            Some("The logo must be a JPEG, PNG or WebP image."),
+        )
+        .await;
+    };
+
+    let name = logo_name(extension);
+    if let Err(e) = photos.store.put(&name, body, content_type).await {
+        error!(error = %e, "Failed to store logo");
+        return render_theme(
+            pool,
+            format,
+            Status::InternalServerError,
+            Some("Failed to store logo"),
+        )
+        .await;
+    }
+
+    match set_theme_logo(pool, Some((&name, content_type)), &user.0.sub).await {
+        Ok((settings, previous)) => {
+            theme.set(&settings);
+            if let Some(previous) = previous {
+                discard_logo(photos, &previous).await;
+            }
+            audit.record(&user.0.sub, "theme.logo", None).await;
+            Negotiated::done(format, "/settings/theme", Status::Ok, settings)
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to set logo");
+            discard_logo(photos, &name).await;
+            render_theme(
+                pool,
+                format,
+                Status::InternalServerError,
+                Some("Failed to store logo"),
+            )
+            .await
+        }
+    }
+}
+
+#[post("/settings/theme/logo/delete")]
+pub async fn delete_logo(
+    pool: &State<Pool<Postgres>>,
+    theme: &State<Theme>,
+    photos: &State<Photos>,
+    user: AllSitesAdmin,
+    audit: Audit,
+    format: Format,
+) -> Negotiated {
+    match set_theme_logo(pool, None, &user.0.sub).await {
+        Ok((settings, previous)) => {
+            theme.set(&settings);
+            if let Some(previous) = previous {
+                discard_logo(photos, &previous).await;
+            }
+            audit.record(&user.0.sub, "theme.logo_delete", None).await;
+            Negotiated::done(format, "/settings/theme", Status::Ok, settings)
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to remove logo");
+            render_theme(
+                pool,
+                format,
+                Status::InternalServerError,
+                Some("Failed to remove logo"),
+            )
+            .await
+        }
+    }
+}
+
+/// The logo, for every page including the sign-in page and kiosks, so no sign-in is needed.
+#[get("/theme/logo")]
+pub async fn theme_logo(
+    pool: &State<Pool<Postgres>>,
+    photos: &State<Photos>,
+) -> Result<(ContentType, Vec<u8>), Negotiated> {
+    let settings = match get_theme(pool).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!(error = ?e, "Failed to load theme");
+            return Err(Negotiated::error(
+                Status::InternalServerError,
+                "Failed to load logo",
+            ));
+        }
+    };
+    let Some(logo) = settings.logo else {
+        return Err(Negotiated::error(Status::NotFound, "No logo set"));
+    };
+
+    match photos.store.get(&logo).await {
+        Ok(Some(body)) => {
+            let content_type = settings
+                .logo_type
+                .as_deref()
+                .and_then(ContentType::parse_flexible)
+                .unwrap_or(ContentType::Binary);
+            Ok((content_type, body))
+        }
+        Ok(None) => Err(Negotiated::error(Status::NotFound, "No logo set")),
+        Err(e) => {
+            error!(error = %e, "Failed to load logo");
+            Err(Negotiated::error(
+                Status::InternalServerError,
+                "Failed to load logo",
+            ))
+        }
+    }
+}
+
+// Helper function to show the theme settings, with an error when there is one
+async fn render_theme(
+    pool: &Pool<Postgres>,
+    format: Format,
+    status: Status,
+    error_message: Option<&str>,
+) -> Negotiated {
+    if let (Format::Json, Some(message)) = (format, error_message) {
+        return Negotiated::error(status, message);
+    }
+
+    match get_theme(pool).await {
+        Ok(settings) => Negotiated::page(
+            format,
+            "theme",
+            context! {
+                settings: settings,
+                default_site_name: DEFAULT_SITE_NAME,
+                error_message: error_message
+            },
+        ),
+        Err(e) => {
+            error!(error = ?e, "Failed to load theme");
+            match format {
+                Format::Html => Negotiated::page(
+                    format,
+                    "theme",
+                    context! {
+                        default_site_name: DEFAULT_SITE_NAME,
+                        error_message: "Failed to load theme"
+                    },
+                ),
+                Format::Json => {
+                    Negotiated::error(Status::InternalServerError, "Failed to load theme")
+                }
+            }
+        }
+    }
+}
+
+// Helper function to treat an empty field as unset
+fn optional(value: &str) -> Option<&str> {
+    Some(value.trim()).filter(|value| !value.is_empty())
+}
+
+async fn discard_logo(photos: &Photos, name: &str) {
+    if let Err(e) = photos.store.delete(name).await {
+        warn!(error = %e, logo = name, "Failed to remove unused logo");
+    }
+}
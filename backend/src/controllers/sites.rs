@@ -0,0 +1,316 @@
+use crate::audit::Audit;
+use crate::auth::{AdminUser, AllSitesAdmin, AuthenticatedUser, Role};
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::database::helpers::{
+    Site, get_all_sites, get_user_by_username, insert_site, set_site_occupancy,
+};
+use crate::sessions::Sessions;
+use rocket::{State, form::Form, get, http::Status, post};
+use rocket_dyn_templates::context;
+use sqlx::{Pool, Postgres};
+use tracing::{error, info};
+use uuid::Uuid;
+
+#[derive(rocket::form::FromForm)]
+pub struct SiteRequest {
+    name: String,
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct OccupancyRequest {
+    max_occupancy: Option<i32>, // empty for no limit
+    occupancy: Option<i32>,     // empty to keep counting from the current count
+}
+
+#[derive(rocket::form::FromForm)]
+pub struct SiteSelection {
+    site_id: Option<String>, // empty for every site
+}
+
+/// The sites of the deployment and the one the user is working in. Users limited to one site
+/// only see theirs.
+#[get("/sites")]
+pub async fn sites_page(
+    pool: &State<Pool<Postgres>>,
+    user: AuthenticatedUser,
+    format: Format,
+) -> Negotiated {
+    render_sites(pool, &user, format, Status::Ok, None).await
+}
+
+#[post("/sites", data = "<site_request>")]
+pub async fn add_site(
+    pool: &State<Pool<Postgres>>,
+    user: AllSitesAdmin,
+    audit: Audit,
+    format: Format,
+    site_request: Form<SiteRequest>,
+) -> Negotiated {
+    let user = AuthenticatedUser(user.0);
+    let name = site_request.name.trim();
+    if name.is_empty() {
+        return render_sites(
+            pool,
+            &user,
+            format,
+            Status::UnprocessableEntity,
+            Some("A site needs a name."),
+        )
+        .await;
+    }
+
+    match insert_site(pool, name).await {
+        Ok(id) => {
+            audit.record(&user.0.sub, "site.add", Some(name)).await;
+            Negotiated::done(
+                format,
+                "/sites",
+                Status::Created,
+                serde_json::json!({ "id": id }),
+            )
+        }
+        Err(_) => {
+            render_sites(
+                pool,
+                &user,
+                format,
+                Status::Conflict,
+                Some("Failed to add site. The name may already be in use."),
+            )
+            .await
+        }
+    }
+}
+
+/// Sets how many people the site may hold, and resets its count when `occupancy` is given. Only
+/// granted entries through doors with a direction are counted, see `set_direction`.
+#[post("/sites/<site_id>/occupancy", data = "<occupancy_request>")]
+pub async fn set_occupancy(
+    pool: &State<Pool<Postgres>>,
+    user: AdminUser,
+    audit: Audit,
+    format: Format,
+    site_id: String,
+    occupancy_request: Form<OccupancyRequest>,
+) -> Negotiated {
+    let user = AuthenticatedUser(user.0);
+    let site = match Uuid::parse_str(&site_id) {
+        Ok(site) if user.0.site.is_none_or(|own| own == site) => site,
+        Ok(_) => {
+            return render_sites(
+                pool,
+                &user,
+                format,
+                Status::NotFound,
+                Some("Site not found"),
+            )
+            .await;
+        }
+        Err(_) => {
+            return render_sites(
+                pool,
+                &user,
+                format,
+                Status::BadRequest,
+                Some("Invalid site ID"),
+            )
+            .await;
+        }
+    };
+
+    let OccupancyRequest {
+        max_occupancy,
+        occupancy,
+    } = *occupancy_request;
+    if max_occupancy.is_some_and(|max| max < 1) || occupancy.is_some_and(|count| count < 0) {
+        return render_sites(
+            pool,
+            &user,
+            format,
+            Status::UnprocessableEntity,
+            Some("The maximum occupancy must be at least 1, or empty for no limit, and the count at least 0."),
+        )
+        .await;
+    }
+
+    match set_site_occupancy(pool, site, max_occupancy, occupancy).await {
+        Ok(true) => {
+            let target = match (max_occupancy, occupancy) {
+                (Some(max), Some(count)) => format!("{}: max {}, count {}", site, max, count),
+                (Some(max), None) => format!("{}: max {}", site, max),
+                (None, Some(count)) => format!("{}: no limit, count {}", site, count),
+                (None, None) => format!("{}: no limit", site),
+            };
+            audit
+                .record(&user.0.sub, "site.occupancy", Some(&target))
+                .await;
+            Negotiated::done(
+                format,
+                "/sites",
+                Status::Ok,
+                serde_json::json!({ "id": site, "max_occupancy": max_occupancy, "occupancy": occupancy }),
+            )
+        }
+        Ok(false) => {
+            render_sites(
+                pool,
+                &user,
+                format,
+                Status::NotFound,
+                Some("Site not found"),
+            )
+            .await
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to update site occupancy");
+            render_sites(
+                pool,
+                &user,
+                format,
+                Status::InternalServerError,
+                Some("Failed to update site occupancy"),
+            )
+            .await
+        }
+    }
+}
+
+/// Switches the session to one site, or back to every site with an empty `site_id`. Only
+/// users not limited to a site can switch.
+#[post("/site", data = "<selection>")]
+pub async fn switch_site(
+    pool: &State<Pool<Postgres>>,
+    sessions: Sessions<'_>,
+    user: AuthenticatedUser,
+    format: Format,
+    selection: Form<SiteSelection>,
+) -> Negotiated {
+    let site = match selection.site_id.as_deref().filter(|id| !id.is_empty()) {
+        Some(id) => match Uuid::parse_str(id) {
+            Ok(site) => Some(site),
+            Err(_) => {
+                return render_sites(
+                    pool,
+                    &user,
+                    format,
+                    Status::BadRequest,
+                    Some("Invalid site ID"),
+                )
+                .await;
+            }
+        },
+        None => None,
+    };
+
+    match get_user_by_username(pool, &user.0.sub).await {
+        Ok(Some(account)) if account.site_id.is_none() => {}
+        Ok(_) => {
+            return render_sites(
+                pool,
+                &user,
+                format,
+                Status::Forbidden,
+                Some("Your account is limited to one site."),
+            )
+            .await;
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to load user");
+            return render_sites(
+                pool,
+                &user,
+                format,
+                Status::InternalServerError,
+                Some("Failed to switch site"),
+            )
+            .await;
+        }
+    }
+    let sites = get_all_sites(pool).await.unwrap_or_default();
+    if site.is_some_and(|site| !sites.iter().any(|known| known.id == site)) {
+        return render_sites(
+            pool,
+            &user,
+            format,
+            Status::NotFound,
+            Some("Site not found"),
+        )
+        .await;
+    }
+
+    if let Err(e) = sessions.switch_site(&user.0, site).await {
+        error!(error = %e, "Failed to switch site");
+        return render_sites(
+            pool,
+            &user,
+            format,
+            Status::InternalServerError,
+            Some("Failed to switch site"),
+        )
+        .await;
+    }
+
+    info!(user = %user.0.sub, site = ?site, "Switched site");
+    Negotiated::done(
+        format,
+        "/sites",
+        Status::Ok,
+        serde_json::json!({ "site_id": site }),
+    )
+}
+
+// Helper function to render the sites, or report an error, in the requested format
+async fn render_sites(
+    pool: &Pool<Postgres>,
+    user: &AuthenticatedUser,
+    format: Format,
+    status: Status,
+    error_message: Option<&str>,
+) -> Negotiated {
+    let sites = match get_all_sites(pool).await {
+        Ok(sites) => sites,
+        Err(e) => {
+            error!(error = ?e, "Failed to load sites");
+            return match format {
+                Format::Html => Negotiated::page(
+                    format,
+                    "sites",
+                    context! { error_message: "Failed to load sites" },
+                ),
+                Format::Json => {
+                    Negotiated::error(Status::InternalServerError, "Failed to load sites")
+                }
+            };
+        }
+    };
+    if let (Format::Json, Some(error_message)) = (format, error_message) {
+        return Negotiated::error(status, error_message);
+    }
+
+    // Whether the account may switch, rather than just the session's current site
+    let limited = match get_user_by_username(pool, &user.0.sub).await {
+        Ok(Some(account)) => account.site_id.is_some(),
+        _ => true,
+    };
+    let sites: Vec<Site> = if limited {
+        sites
+            .into_iter()
+            .filter(|site| Some(site.id) == user.0.site)
+            .collect()
+    } else {
+        sites
+    };
+
+    Negotiated::page(
+        format,
+        "sites",
+        context! {
+            sites: sites,
+            current_site: user.0.site,
+            can_switch: !limited,
+            can_add: !limited && user.0.site.is_none() && user.0.role == Role::Admin,
+            can_manage: user.0.role == Role::Admin,
+            error_message: error_message
+        },
+    )
+}
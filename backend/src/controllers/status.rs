@@ -0,0 +1,52 @@
+use crate::auth::{CanReadMetrics, ViewerUser};
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::relays::{PortalConnection, PortalIdentities};
+use crate::request_log::RequestMetrics;
+use rocket::http::ContentType;
+use rocket::{State, get};
+use rocket_dyn_templates::context;
+
+/// Portal relay connection as seen by the handshake loops and the relay monitor, with the
+/// service identities the doors are served under.
+#[get("/status")]
+pub fn status_page(
+    identities: &State<PortalIdentities>,
+    portal: &State<PortalConnection>,
+    _user: ViewerUser,
+    format: Format,
+) -> Negotiated {
+    let identities: Vec<_> = identities
+        .0
+        .iter()
+        .map(|identity| {
+            serde_json::json!({
+                "npub": identity.connection.npub(),
+                "doors": identity.doors,
+                "connected": identity.health.is_connected(),
+            })
+        })
+        .collect();
+    Negotiated::page(
+        format,
+        "status",
+        context! {
+            connected: identities.iter().all(|identity| identity["connected"] == true),
+            several_identities: identities.len() > 1,
+            identities,
+            relays: portal.statuses()
+        },
+    )
+}
+
+/// Request latency histograms and relay deliveries, in the Prometheus text format.
+#[get("/metrics")]
+pub fn metrics(
+    metrics: &State<RequestMetrics>,
+    identities: &State<PortalIdentities>,
+    _user: CanReadMetrics,
+) -> (ContentType, String) {
+    (
+        ContentType::Plain,
+        format!("{}{}", metrics.render(), identities.render_metrics()),
+    )
+}
@@ -0,0 +1,231 @@
+use crate::audit::Audit;
+use crate::auth::{CanManageUsers, Role, hash_password};
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::database::helpers::{
+    Site, delete_user_by_id, get_all_sites, get_all_users, insert_user,
+};
+use rocket::{State, form::Form, get, http::Status, post};
+use rocket_dyn_templates::context;
+use sqlx::{Pool, Postgres};
+use tracing::{error, info};
+use uuid::Uuid;
+
+// Shortest password accepted for a new account
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+#[derive(rocket::form::FromForm)]
+pub struct UserRequest {
+    username: String,
+    password: String,
+    role: Role,
+    site_id: Option<String>, // empty for every site; ignored for admins of one site
+}
+
+#[get("/users")]
+pub async fn users_page(
+    pool: &State<Pool<Postgres>>,
+    user: CanManageUsers,
+    format: Format,
+) -> Negotiated {
+    match get_all_users(pool, user.0.site).await {
+        Ok(users) => Negotiated::page(
+            format,
+            "users",
+            context! {
+                users: users,
+                sites: site_choices(pool, user.0.site).await,
+                current_user: user.0.sub
+            },
+        ),
+        Err(_) => {
+            users_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to load users",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/users", data = "<user_request>")]
+pub async fn add_user(
+    pool: &State<Pool<Postgres>>,
+    user: CanManageUsers,
+    audit: Audit,
+    format: Format,
+    user_request: Form<UserRequest>,
+) -> Negotiated {
+    let username = user_request.username.trim();
+    if username.is_empty() || user_request.password.len() < MIN_PASSWORD_LENGTH {
+        return users_error(
+            pool,
+            user.0.site,
+            format,
+            Status::UnprocessableEntity,
+            "A user needs a username and a password of at least 8 characters.",
+        )
+        .await;
+    }
+
+    let password_hash = match hash_password(&user_request.password) {
+        Ok(password_hash) => password_hash,
+        Err(e) => {
+            error!(error = %e, "Failed to hash password");
+            return users_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to add user",
+            )
+            .await;
+        }
+    };
+
+    // Admins of one site can only add users to it
+    let site = match (user.0.site, user_request.site_id.as_deref()) {
+        (Some(site), _) => Some(site),
+        (None, None | Some("")) => None,
+        (None, Some(id)) => match Uuid::parse_str(id) {
+            Ok(site) => Some(site),
+            Err(_) => {
+                return users_error(
+                    pool,
+                    user.0.site,
+                    format,
+                    Status::BadRequest,
+                    "Invalid site ID",
+                )
+                .await;
+            }
+        },
+    };
+
+    match insert_user(
+        pool,
+        username,
+        &password_hash,
+        user_request.role.as_str(),
+        site,
+    )
+    .await
+    {
+        Ok(id) => {
+            info!(
+                username,
+                role = user_request.role.as_str(),
+                by = %user.0.sub,
+                "User added"
+            );
+            audit.record(&user.0.sub, "user.add", Some(username)).await;
+            Negotiated::done(
+                format,
+                "/users",
+                Status::Created,
+                serde_json::json!({ "id": id }),
+            )
+        }
+        Err(_) => {
+            users_error(
+                pool,
+                user.0.site,
+                format,
+                Status::Conflict,
+                "Failed to add user. The username may already be taken.",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/users/<user_id>/delete")]
+pub async fn delete_user(
+    pool: &State<Pool<Postgres>>,
+    user: CanManageUsers,
+    audit: Audit,
+    format: Format,
+    user_id: String,
+) -> Negotiated {
+    let uuid = match Uuid::parse_str(&user_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return users_error(
+                pool,
+                user.0.site,
+                format,
+                Status::BadRequest,
+                "Invalid user ID",
+            )
+            .await;
+        }
+    };
+
+    match delete_user_by_id(pool, uuid, user.0.site).await {
+        Ok(true) => {
+            info!(user = %uuid, by = %user.0.sub, "User deleted");
+            audit
+                .record(&user.0.sub, "user.delete", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                "/users",
+                Status::Ok,
+                serde_json::json!({ "id": uuid }),
+            )
+        }
+        Ok(false) => {
+            users_error(
+                pool,
+                user.0.site,
+                format,
+                Status::Conflict,
+                "User not found, or it is the last admin account for every site.",
+            )
+            .await
+        }
+        Err(_) => {
+            users_error(
+                pool,
+                user.0.site,
+                format,
+                Status::InternalServerError,
+                "Failed to delete user",
+            )
+            .await
+        }
+    }
+}
+
+// Helper function to list the sites a new user can be limited to, only offered to admins of
+// every site
+async fn site_choices(pool: &Pool<Postgres>, site: Option<Uuid>) -> Vec<Site> {
+    match site {
+        Some(_) => Vec::new(),
+        None => get_all_sites(pool).await.unwrap_or_default(),
+    }
+}
+
+// Helper function to report a user management error in the requested format
+async fn users_error(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+    format: Format,
+    status: Status,
+    error_message: &str,
+) -> Negotiated {
+    match format {
+        Format::Html => Negotiated::page(
+            format,
+            "users",
+            context! {
+                users: get_all_users(pool, site).await.unwrap_or_default(),
+                sites: site_choices(pool, site).await,
+                error_message: error_message
+            },
+        ),
+        Format::Json => Negotiated::error(status, error_message),
+    }
+}
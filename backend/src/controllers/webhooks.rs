@@ -0,0 +1,196 @@
+use crate::audit::Audit;
+use crate::auth::AllSitesAdmin;
+use crate::controllers::negotiation::{Format, Negotiated};
+use crate::database::helpers::{
+    delete_webhook_by_id, get_all_webhooks, get_recent_webhook_deliveries, insert_webhook,
+    toggle_webhook_status,
+};
+use crate::webhooks::generate_secret;
+use rocket::{State, form::Form, get, http::Status, post};
+use rocket_dyn_templates::context;
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+
+// Number of recent deliveries shown under the webhook list
+const RECENT_DELIVERIES: i64 = 50;
+
+#[derive(rocket::form::FromForm)]
+pub struct WebhookRequest {
+    url: String,
+}
+
+#[get("/webhooks")]
+pub async fn webhooks_page(
+    pool: &State<Pool<Postgres>>,
+    _user: AllSitesAdmin,
+    format: Format,
+) -> Negotiated {
+    match (
+        get_all_webhooks(pool).await,
+        get_recent_webhook_deliveries(pool, RECENT_DELIVERIES).await,
+    ) {
+        (Ok(webhooks), Ok(deliveries)) => Negotiated::page(
+            format,
+            "webhooks",
+            context! {
+                webhooks: webhooks,
+                deliveries: deliveries
+            },
+        ),
+        _ => {
+            webhooks_error(
+                pool,
+                format,
+                Status::InternalServerError,
+                "Failed to load webhooks",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/webhooks", data = "<webhook_request>")]
+pub async fn add_webhook(
+    pool: &State<Pool<Postgres>>,
+    user: AllSitesAdmin,
+    audit: Audit,
+    format: Format,
+    webhook_request: Form<WebhookRequest>,
+) -> Negotiated {
+    let url = webhook_request.url.trim();
+    let valid = reqwest::Url::parse(url)
+        .map(|parsed| matches!(parsed.scheme(), "http" | "https"))
+        .unwrap_or(false);
+    if !valid {
+        return webhooks_error(
+            pool,
+            format,
+            Status::UnprocessableEntity,
+            "The webhook URL must be a valid http(s) URL.",
+        )
+        .await;
+    }
+
+    let secret = generate_secret();
+    match insert_webhook(pool, url, &secret).await {
+        Ok(id) => {
+            audit.record(&user.0.sub, "webhook.add", Some(url)).await;
+            Negotiated::done(
+                format,
+                "/webhooks",
+                Status::Created,
+                serde_json::json!({ "id": id, "secret": secret }),
+            )
+        }
+        Err(_) => {
+            webhooks_error(
+                pool,
+                format,
+                Status::InternalServerError,
+                "Failed to add webhook",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/webhooks/<webhook_id>/toggle")]
+pub async fn toggle_webhook(
+    pool: &State<Pool<Postgres>>,
+    user: AllSitesAdmin,
+    audit: Audit,
+    format: Format,
+    webhook_id: String,
+) -> Negotiated {
+    let uuid = match Uuid::parse_str(&webhook_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return webhooks_error(pool, format, Status::BadRequest, "Invalid webhook ID").await;
+        }
+    };
+
+    match toggle_webhook_status(pool, uuid).await {
+        Ok(_) => {
+            audit
+                .record(&user.0.sub, "webhook.toggle", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                "/webhooks",
+                Status::Ok,
+                serde_json::json!({ "id": uuid }),
+            )
+        }
+        Err(_) => {
+            webhooks_error(
+                pool,
+                format,
+                Status::InternalServerError,
+                "Failed to toggle webhook",
+            )
+            .await
+        }
+    }
+}
+
+#[post("/webhooks/<webhook_id>/delete")]
+pub async fn delete_webhook(
+    pool: &State<Pool<Postgres>>,
+    user: AllSitesAdmin,
+    audit: Audit,
+    format: Format,
+    webhook_id: String,
+) -> Negotiated {
+    let uuid = match Uuid::parse_str(&webhook_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return webhooks_error(pool, format, Status::BadRequest, "Invalid webhook ID").await;
+        }
+    };
+
+    match delete_webhook_by_id(pool, uuid).await {
+        Ok(_) => {
+            audit
+                .record(&user.0.sub, "webhook.delete", Some(&uuid.to_string()))
+                .await;
+            Negotiated::done(
+                format,
+                "/webhooks",
+                Status::Ok,
+                serde_json::json!({ "id": uuid }),
+            )
+        }
+        Err(_) => {
+            webhooks_error(
+                pool,
+                format,
+                Status::InternalServerError,
+                "Failed to delete webhook",
+            )
+            .await
+        }
+    }
+}
+
+// Helper function to report a webhook management error in the requested format
+async fn webhooks_error(
+    pool: &Pool<Postgres>,
+    format: Format,
+    status: Status,
+    error_message: &str,
+) -> Negotiated {
+    match format {
+        Format::Html => Negotiated::page(
+            format,
+            "webhooks",
+            context! {
+                webhooks: get_all_webhooks(pool).await.unwrap_or_default(),
+                deliveries: get_recent_webhook_deliveries(pool, RECENT_DELIVERIES)
+                    .await
+                    .unwrap_or_default(),
+                error_message: error_message
+            },
+        ),
+        Format::Json => Negotiated::error(status, error_message),
+    }
+}
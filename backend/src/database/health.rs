@@ -0,0 +1,45 @@
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::{error, info};
+
+// How often the background probe pings the database
+const PROBE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Shared flag reflecting whether the last database probe succeeded.
+#[derive(Clone)]
+pub struct DbHealth(Arc<AtomicBool>);
+
+impl DbHealth {
+    pub fn new(healthy: bool) -> Self {
+        Self(Arc::new(AtomicBool::new(healthy)))
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set_healthy(&self, healthy: bool) {
+        let was_healthy = self.0.swap(healthy, Ordering::Relaxed);
+        if was_healthy != healthy {
+            if healthy {
+                info!("Database connection restored");
+            } else {
+                error!("Database degraded, probe failed");
+            }
+        }
+    }
+
+    /// Spawns a lightweight `SELECT 1` probe that keeps the flag up to date.
+    pub fn spawn_probe(&self, pool: Pool<Postgres>) {
+        let health = self.clone();
+        rocket::tokio::spawn(async move {
+            loop {
+                let healthy = sqlx::query("SELECT 1").execute(&pool).await.is_ok();
+                health.set_healthy(healthy);
+                rocket::tokio::time::sleep(PROBE_INTERVAL).await;
+            }
+        });
+    }
+}
@@ -1,7 +1,39 @@
-use chrono::{DateTime, Utc};
-use sqlx::{Pool, Postgres};
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use rocket::futures::StreamExt;
+use rocket::futures::stream::BoxStream;
+use sha2::{Digest, Sha256};
+use sqlx::postgres::PgConnection;
+use sqlx::{Executor, Pool, Postgres, Type};
+use std::collections::HashSet;
 use uuid::Uuid;
 
+// Queries loading a key's rules, prepared up front on each new connection by
+// `prepare_unlock_queries`
+const KEY_STATUS_SQL: &str =
+    "SELECT id, status, expires_at FROM keys WHERE npub = $1 AND deleted_at IS NULL";
+// Doors of other sites are left out, even when a group grants them
+const KEY_DOORS_SQL: &str = "SELECT id FROM doors
+             WHERE site_id = (SELECT site_id FROM keys WHERE id = $1)
+               AND id IN (SELECT door_id FROM key_door_permissions WHERE key_id = $1
+                          UNION SELECT p.door_id FROM key_groups g
+                                JOIN group_door_permissions p ON p.group_id = g.group_id WHERE g.key_id = $1)";
+const KEY_WINDOWS_SQL: &str =
+    "SELECT weekdays, start_time, end_time FROM key_schedules WHERE key_id = $1";
+
+/// Prepares the queries behind `get_key_rules` on a fresh connection, with the same parameter
+/// types the helper binds. sqlx keeps them in the connection's statement cache, so the first
+/// key loaded through the connection skips parsing and planning.
+pub async fn prepare_unlock_queries(conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+    let uuid = <Uuid as Type<Postgres>>::type_info();
+    conn.prepare_with(KEY_STATUS_SQL, &[<&str as Type<Postgres>>::type_info()])
+        .await?;
+    conn.prepare_with(KEY_DOORS_SQL, std::slice::from_ref(&uuid))
+        .await?;
+    conn.prepare_with(KEY_WINDOWS_SQL, &[uuid]).await?;
+
+    Ok(())
+}
+
 #[derive(sqlx::FromRow, serde::Serialize)]
 pub struct PublicKey {
     pub id: Uuid,
@@ -10,27 +42,280 @@ pub struct PublicKey {
     pub profile_name: Option<String>,
     pub status: bool,
     pub created_at: DateTime<Utc>,
+    pub nip05_verified: bool,
+    pub nip05_checked_at: Option<DateTime<Utc>>,
+    pub display_name: Option<String>, // from the key's Nostr profile
+    pub picture_url: Option<String>,
+    pub profile_fetched_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>, // set for soft-deleted keys
+    pub last_access_at: Option<DateTime<Utc>>, // last time the key unlocked a door
+    pub access_count: i64,
+    pub pending: bool, // self-enrolled, not enabled by an admin yet
+    pub notes: Option<String>,
+    pub tags: Vec<String>,
+    pub site_id: Uuid,
+    pub expires_at: Option<DateTime<Utc>>, // the key stops opening doors then
+    pub photo: Option<String>,             // name in the photo store, see `photos`
+    pub photo_type: Option<String>,
 }
 
 // Database helper functions
 
-pub async fn get_all_keys(pool: &Pool<Postgres>) -> Result<Vec<PublicKey>, sqlx::Error> {
-    sqlx::query_as::<_, PublicKey>("SELECT * FROM keys ORDER BY created_at DESC")
+// Helpers taking a `site` only see and change the rows of that site, or of every site when it
+// is `None`
+
+/// The site everything created before sites existed belongs to, and where new keys and doors
+/// go when they are added while viewing every site.
+pub const DEFAULT_SITE: Uuid = Uuid::from_u128(1);
+
+/// A building or location served by the deployment, owning its own keys, doors and logs.
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct Site {
+    pub id: Uuid,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub occupancy: i32, // people let in through entry doors and not out through exit doors
+    pub max_occupancy: Option<i32>, // entry doors stay locked once reached, None for no limit
+    pub occupancy_updated_at: Option<DateTime<Utc>>,
+}
+
+pub async fn get_all_sites(pool: &Pool<Postgres>) -> Result<Vec<Site>, sqlx::Error> {
+    sqlx::query_as::<_, Site>("SELECT * FROM sites ORDER BY name")
         .fetch_all(pool)
         .await
 }
 
+pub async fn insert_site(pool: &Pool<Postgres>, name: &str) -> Result<Uuid, sqlx::Error> {
+    sqlx::query_scalar::<_, Uuid>("INSERT INTO sites (name) VALUES ($1) RETURNING id")
+        .bind(name)
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn get_site_by_id(pool: &Pool<Postgres>, id: Uuid) -> Result<Option<Site>, sqlx::Error> {
+    sqlx::query_as::<_, Site>("SELECT * FROM sites WHERE id = $1")
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+}
+
+/// The sites, or the one site, with doors that count occupancy.
+pub async fn get_site_occupancy(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+) -> Result<Vec<Site>, sqlx::Error> {
+    sqlx::query_as::<_, Site>(
+        "SELECT * FROM sites s
+         WHERE ($1::uuid IS NULL OR s.id = $1)
+           AND EXISTS (SELECT 1 FROM doors d WHERE d.site_id = s.id AND d.direction IS NOT NULL)
+         ORDER BY s.name",
+    )
+    .bind(site)
+    .fetch_all(pool)
+    .await
+}
+
+/// Sets the site's occupancy limit, and its count when `occupancy` is given, e.g. to start
+/// again from zero after hours.
+pub async fn set_site_occupancy(
+    pool: &Pool<Postgres>,
+    site_id: Uuid,
+    max_occupancy: Option<i32>,
+    occupancy: Option<i32>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE sites SET max_occupancy = $2,
+             occupancy = COALESCE($3, occupancy),
+             occupancy_updated_at = CASE WHEN $3 IS NULL THEN occupancy_updated_at ELSE NOW() END
+         WHERE id = $1",
+    )
+    .bind(site_id)
+    .bind(max_occupancy)
+    .bind(occupancy)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+// Soft-deleted keys are left out everywhere except the recently deleted list and the joins
+// that name keys in the access log
+
+pub async fn get_all_keys(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+) -> Result<Vec<PublicKey>, sqlx::Error> {
+    sqlx::query_as::<_, PublicKey>(
+        "SELECT * FROM keys WHERE deleted_at IS NULL AND ($1::uuid IS NULL OR site_id = $1) ORDER BY created_at DESC",
+    )
+    .bind(site)
+    .fetch_all(pool)
+    .await
+}
+
+/// Sort order of the keys page.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, rocket::form::FromFormField)]
+pub enum KeyOrder {
+    #[default]
+    #[field(value = "added")]
+    Added,
+    #[field(value = "name")]
+    Name,
+    #[field(value = "last_access")]
+    LastAccess,
+    #[field(value = "access_count")]
+    AccessCount,
+}
+
+impl KeyOrder {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyOrder::Added => "added",
+            KeyOrder::Name => "name",
+            KeyOrder::LastAccess => "last_access",
+            KeyOrder::AccessCount => "access_count",
+        }
+    }
+
+    fn order_by(&self) -> &'static str {
+        match self {
+            KeyOrder::Added => "created_at DESC",
+            KeyOrder::Name => "COALESCE(profile_name, display_name, npub)",
+            KeyOrder::LastAccess => "last_access_at DESC NULLS LAST",
+            KeyOrder::AccessCount => "access_count DESC, last_access_at DESC NULLS LAST",
+        }
+    }
+}
+
+/// Status filter of the keys page.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, rocket::form::FromFormField)]
+pub enum KeyStatusFilter {
+    #[default]
+    #[field(value = "all")]
+    All,
+    #[field(value = "enabled")]
+    Enabled,
+    #[field(value = "disabled")]
+    Disabled,
+}
+
+impl KeyStatusFilter {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyStatusFilter::All => "all",
+            KeyStatusFilter::Enabled => "enabled",
+            KeyStatusFilter::Disabled => "disabled",
+        }
+    }
+
+    fn status(&self) -> Option<bool> {
+        match self {
+            KeyStatusFilter::All => None,
+            KeyStatusFilter::Enabled => Some(true),
+            KeyStatusFilter::Disabled => Some(false),
+        }
+    }
+}
+
+/// Which keys the keys page lists. `search` matches the start of the npub or anywhere in the
+/// profile name, Nostr display name or NIP-05 identifier, ignoring case. With `unused_days`
+/// only keys that have not unlocked a door in that many days are listed, counting never used
+/// keys from when they were added. `tag` only lists keys carrying that tag, and `site` the
+/// keys of that site.
+#[derive(Debug, Default)]
+pub struct KeyFilter {
+    pub search: Option<String>,
+    pub status: KeyStatusFilter,
+    pub unused_days: Option<i64>,
+    pub tag: Option<String>,
+    pub site: Option<Uuid>,
+}
+
+const KEY_FILTER_SQL: &str = "deleted_at IS NULL
+    AND ($1::text IS NULL OR npub LIKE $1 || '%' OR profile_name ILIKE '%' || $1 || '%'
+         OR display_name ILIKE '%' || $1 || '%' OR nip05 ILIKE '%' || $1 || '%')
+    AND ($2::boolean IS NULL OR status = $2)
+    AND ($3::integer IS NULL OR COALESCE(last_access_at, created_at) < NOW() - make_interval(days => $3))
+    AND ($4::text IS NULL OR tags @> ARRAY[$4])
+    AND ($5::uuid IS NULL OR site_id = $5)";
+
+// Helper function to make user input match literally inside a LIKE pattern
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// One page of the keys matching `filter`. Unused keys come longest unused first, anything
+/// else in `order`.
+pub async fn search_keys(
+    pool: &Pool<Postgres>,
+    filter: &KeyFilter,
+    order: KeyOrder,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<PublicKey>, sqlx::Error> {
+    let order_by = match filter.unused_days {
+        Some(_) => "COALESCE(last_access_at, created_at)",
+        None => order.order_by(),
+    };
+    sqlx::query_as::<_, PublicKey>(&format!(
+        "SELECT * FROM keys WHERE {} ORDER BY {}, id LIMIT $6 OFFSET $7",
+        KEY_FILTER_SQL, order_by
+    ))
+    .bind(filter.search.as_deref().map(escape_like))
+    .bind(filter.status.status())
+    .bind(filter.unused_days.map(|days| days.max(0) as i32))
+    .bind(&filter.tag)
+    .bind(filter.site)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn count_keys(pool: &Pool<Postgres>, filter: &KeyFilter) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar::<_, i64>(&format!(
+        "SELECT COUNT(*) FROM keys WHERE {}",
+        KEY_FILTER_SQL
+    ))
+    .bind(filter.search.as_deref().map(escape_like))
+    .bind(filter.status.status())
+    .bind(filter.unused_days.map(|days| days.max(0) as i32))
+    .bind(&filter.tag)
+    .bind(filter.site)
+    .fetch_one(pool)
+    .await
+}
+
+/// Keys deleted since `deleted_since`, most recently deleted first.
+pub async fn get_deleted_keys(
+    pool: &Pool<Postgres>,
+    deleted_since: DateTime<Utc>,
+    site: Option<Uuid>,
+) -> Result<Vec<PublicKey>, sqlx::Error> {
+    sqlx::query_as::<_, PublicKey>(
+        "SELECT * FROM keys WHERE deleted_at >= $1 AND ($2::uuid IS NULL OR site_id = $2) ORDER BY deleted_at DESC",
+    )
+    .bind(deleted_since)
+    .bind(site)
+    .fetch_all(pool)
+    .await
+}
+
 pub async fn insert_key(
     pool: &Pool<Postgres>,
     npub: &str,
     nip05: Option<&str>,
     profile_name: Option<&str>,
-) -> Result<(), sqlx::Error> {
+    site_id: Uuid,
+) -> Result<Uuid, sqlx::Error> {
     let id = Uuid::new_v4();
     let now = Utc::now();
 
     sqlx::query(
-        "INSERT INTO keys (id, npub, nip05, profile_name, status, created_at) VALUES ($1, $2, $3, $4, $5, $6)"
+        "INSERT INTO keys (id, npub, nip05, profile_name, status, created_at, site_id) VALUES ($1, $2, $3, $4, $5, $6, $7)"
     )
     .bind(id)
     .bind(npub)
@@ -38,35 +323,3198 @@ pub async fn insert_key(
     .bind(profile_name)
     .bind(true) // Default to enabled
     .bind(now)
+    .bind(site_id)
     .execute(pool)
     .await?;
 
-    Ok(())
+    Ok(id)
+}
+
+/// Enables a disabled key or disables an enabled one. Either way a self-enrolled key is no
+/// longer pending once an admin has looked at it.
+/// Adds an unknown npub as a disabled, pending key allowed on `door_id`, for open enrollment.
+/// Returns None, adding nothing, when the npub already has a key (deleted ones included) or an
+/// access request, or when `max_pending` keys are already waiting.
+pub async fn enroll_key(
+    pool: &Pool<Postgres>,
+    npub: &str,
+    door_id: Uuid,
+    max_pending: i64,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let key_id = sqlx::query_scalar::<_, Uuid>(
+        "INSERT INTO keys (id, npub, status, pending, created_at, site_id)
+         SELECT $1, $2, false, true, NOW(), (SELECT site_id FROM doors WHERE id = $4)
+         WHERE (SELECT COUNT(*) FROM keys WHERE pending AND deleted_at IS NULL) < $3
+           AND NOT EXISTS (SELECT 1 FROM pending_keys WHERE npub = $2)
+         ON CONFLICT (npub) DO NOTHING
+         RETURNING id",
+    )
+    .bind(Uuid::new_v4())
+    .bind(npub)
+    .bind(max_pending)
+    .bind(door_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if let Some(key_id) = key_id {
+        sqlx::query("INSERT INTO key_door_permissions (key_id, door_id) VALUES ($1, $2)")
+            .bind(key_id)
+            .bind(door_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    Ok(key_id)
+}
+
+/// Enables a disabled key or disables an enabled one; returns false when the key is unknown or
+/// deleted.
+pub async fn toggle_key_status(
+    pool: &Pool<Postgres>,
+    key_id: Uuid,
+    site: Option<Uuid>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE keys SET status = NOT status, pending = false WHERE id = $1 AND deleted_at IS NULL AND ($2::uuid IS NULL OR site_id = $2)",
+    )
+    .bind(key_id)
+    .bind(site)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Disables an enabled key; returns false when the key is unknown or already disabled.
+pub async fn disable_key_by_npub(pool: &Pool<Postgres>, npub: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE keys SET status = false WHERE npub = $1 AND status AND deleted_at IS NULL",
+    )
+    .bind(npub)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
 }
 
-pub async fn toggle_key_status(pool: &Pool<Postgres>, key_id: Uuid) -> Result<(), sqlx::Error> {
-    sqlx::query("UPDATE keys SET status = NOT status WHERE id = $1")
+/// Soft-deletes a key. Its door, group and schedule assignments are kept for a restore.
+/// Returns false when the key is unknown or already deleted.
+pub async fn delete_key_by_id(
+    pool: &Pool<Postgres>,
+    key_id: Uuid,
+    site: Option<Uuid>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE keys SET deleted_at = NOW() WHERE id = $1 AND deleted_at IS NULL AND ($2::uuid IS NULL OR site_id = $2)")
         .bind(key_id)
+        .bind(site)
         .execute(pool)
         .await?;
 
+    Ok(result.rows_affected() > 0)
+}
+
+/// Brings back a soft-deleted key; returns false when the key is unknown or not deleted.
+pub async fn restore_key_by_id(
+    pool: &Pool<Postgres>,
+    key_id: Uuid,
+    site: Option<Uuid>,
+) -> Result<bool, sqlx::Error> {
+    let result =
+        sqlx::query("UPDATE keys SET deleted_at = NULL WHERE id = $1 AND deleted_at IS NOT NULL AND ($2::uuid IS NULL OR site_id = $2)")
+            .bind(key_id)
+            .bind(site)
+            .execute(pool)
+            .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// What `batch_update_keys` does to every selected key.
+pub enum KeyBatchAction {
+    Enable,
+    Disable,
+    Delete,
+    AddToGroup(Uuid),
+    SetExpiry(Option<DateTime<Utc>>), // `None` clears the end date
+}
+
+/// Applies `action` to all of `key_ids` in one transaction. Nothing changes, and false is
+/// returned, when any of the keys is unknown, deleted or outside `site`.
+pub async fn batch_update_keys(
+    pool: &Pool<Postgres>,
+    key_ids: &[Uuid],
+    action: &KeyBatchAction,
+    site: Option<Uuid>,
+) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    // Locked so none of them is deleted or moved while the batch runs
+    let found = sqlx::query_scalar::<_, Uuid>(
+        "SELECT id FROM keys WHERE id = ANY($1) AND deleted_at IS NULL AND ($2::uuid IS NULL OR site_id = $2) FOR UPDATE",
+    )
+    .bind(key_ids)
+    .bind(site)
+    .fetch_all(&mut *tx)
+    .await?;
+    if found.len() != key_ids.iter().collect::<HashSet<_>>().len() {
+        return Ok(false);
+    }
+
+    let query = match action {
+        KeyBatchAction::Enable => {
+            sqlx::query("UPDATE keys SET status = true, pending = false WHERE id = ANY($1)")
+                .bind(key_ids)
+        }
+        KeyBatchAction::Disable => {
+            sqlx::query("UPDATE keys SET status = false WHERE id = ANY($1)").bind(key_ids)
+        }
+        KeyBatchAction::Delete => {
+            sqlx::query("UPDATE keys SET deleted_at = NOW() WHERE id = ANY($1)").bind(key_ids)
+        }
+        KeyBatchAction::AddToGroup(group_id) => sqlx::query(
+            "INSERT INTO key_groups (key_id, group_id) SELECT UNNEST($1::uuid[]), $2 ON CONFLICT DO NOTHING",
+        )
+        .bind(key_ids)
+        .bind(group_id),
+        KeyBatchAction::SetExpiry(expires_at) => {
+            sqlx::query("UPDATE keys SET expires_at = $2 WHERE id = ANY($1)")
+                .bind(key_ids)
+                .bind(expires_at)
+        }
+    };
+    query.execute(&mut *tx).await?;
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct PendingKey {
+    pub id: Uuid,
+    pub npub: String,
+    pub note: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+// Access requests come from the public page and belong to no site, so only users working in
+// every site (`site` is `None`) see them, and approve or deny them
+
+pub async fn get_pending_keys(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+) -> Result<Vec<PendingKey>, sqlx::Error> {
+    sqlx::query_as::<_, PendingKey>(
+        "SELECT * FROM pending_keys WHERE $1::uuid IS NULL ORDER BY created_at",
+    )
+    .bind(site)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn count_pending_keys_from_ip(
+    pool: &Pool<Postgres>,
+    ip: &str,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar("SELECT COUNT(*) FROM pending_keys WHERE ip = $1")
+        .bind(ip)
+        .fetch_one(pool)
+        .await
+}
+
+/// Files an access request. Returns `false` when the npub already has a key, deleted ones
+/// included, or is already waiting for approval.
+pub async fn insert_pending_key(
+    pool: &Pool<Postgres>,
+    npub: &str,
+    note: Option<&str>,
+    ip: Option<&str>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "INSERT INTO pending_keys (id, npub, note, ip, created_at)
+         SELECT $1, $2, $3, $4, NOW() WHERE NOT EXISTS (SELECT 1 FROM keys WHERE npub = $2)
+         ON CONFLICT (npub) DO NOTHING",
+    )
+    .bind(Uuid::new_v4())
+    .bind(npub)
+    .bind(note)
+    .bind(ip)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Turns an access request into an enabled key of the default site without doors. Returns the
+/// new key's id and npub, or `None` when the request no longer exists or `site` cannot see it.
+pub async fn approve_pending_key(
+    pool: &Pool<Postgres>,
+    pending_id: Uuid,
+    site: Option<Uuid>,
+) -> Result<Option<(Uuid, String)>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let npub = sqlx::query_scalar::<_, String>(
+        "DELETE FROM pending_keys WHERE id = $1 AND $2::uuid IS NULL RETURNING npub",
+    )
+    .bind(pending_id)
+    .bind(site)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(npub) = npub else {
+        return Ok(None);
+    };
+
+    let key_id = Uuid::new_v4();
+    sqlx::query(
+        "INSERT INTO keys (id, npub, status, created_at, site_id) VALUES ($1, $2, true, NOW(), $3)",
+    )
+    .bind(key_id)
+    .bind(&npub)
+    .bind(DEFAULT_SITE)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(Some((key_id, npub)))
+}
+
+/// Drops an access request. Returns the npub it was for, if it existed and `site` can see it.
+pub async fn delete_pending_key(
+    pool: &Pool<Postgres>,
+    pending_id: Uuid,
+    site: Option<Uuid>,
+) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar("DELETE FROM pending_keys WHERE id = $1 AND $2::uuid IS NULL RETURNING npub")
+        .bind(pending_id)
+        .bind(site)
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn set_key_profile(
+    pool: &Pool<Postgres>,
+    key_id: Uuid,
+    display_name: Option<&str>,
+    picture_url: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE keys SET display_name = $2, picture_url = $3, profile_fetched_at = NOW() WHERE id = $1",
+    )
+    .bind(key_id)
+    .bind(display_name)
+    .bind(picture_url)
+    .execute(pool)
+    .await?;
+
     Ok(())
 }
 
-pub async fn delete_key_by_id(pool: &Pool<Postgres>, key_id: Uuid) -> Result<(), sqlx::Error> {
-    sqlx::query("DELETE FROM keys WHERE id = $1")
+/// Keys whose profile was never fetched or was last fetched before `fetched_before`.
+pub async fn get_keys_due_profile_refresh(
+    pool: &Pool<Postgres>,
+    fetched_before: DateTime<Utc>,
+) -> Result<Vec<(Uuid, String)>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT id, npub FROM keys WHERE deleted_at IS NULL AND (profile_fetched_at IS NULL OR profile_fetched_at < $1) ORDER BY profile_fetched_at NULLS FIRST",
+    )
+    .bind(fetched_before)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn set_key_nip05_verification(
+    pool: &Pool<Postgres>,
+    key_id: Uuid,
+    verified: bool,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE keys SET nip05_verified = $2, nip05_checked_at = NOW() WHERE id = $1")
         .bind(key_id)
+        .bind(verified)
         .execute(pool)
         .await?;
 
     Ok(())
 }
 
-pub async fn is_key_enabled(pool: &Pool<Postgres>, npub: &str) -> Result<bool, sqlx::Error> {
-    let result = sqlx::query_scalar::<_, bool>("SELECT status FROM keys WHERE npub = $1")
-        .bind(npub)
+/// Outcome of a handshake/authentication attempt, stored as text in `access_logs.result`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, rocket::form::FromFormField)]
+pub enum AccessResult {
+    #[field(value = "granted")]
+    Granted,
+    #[field(value = "denied")]
+    Denied,
+    #[field(value = "declined")]
+    Declined,
+    #[field(value = "error")]
+    Error,
+    #[field(value = "rate_limited")]
+    RateLimited,
+    #[field(value = "unpaid")]
+    Unpaid,
+    #[field(value = "debounced")]
+    Debounced,
+}
+
+impl AccessResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccessResult::Granted => "granted",
+            AccessResult::Denied => "denied",
+            AccessResult::Declined => "declined",
+            AccessResult::Error => "error",
+            AccessResult::RateLimited => "rate_limited",
+            AccessResult::Unpaid => "unpaid",
+            AccessResult::Debounced => "debounced",
+        }
+    }
+}
+
+/// Why an attempt was denied or declined, stored as text in `access_logs.reason_code`.
+/// `access_logs.reason` keeps the human-readable detail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DenyReason {
+    DoorNotRegistered,
+    DoorDisabled,
+    EmergencyOverride,
+    SiteLockdown,
+    DoorLockdown,
+    KeyUnknown,
+    KeyDisabled,
+    KeyExpired,
+    OutsideSchedule,
+    DoorNotAllowed,
+    GuestPassExpired,
+    AuthenticationDeclined,
+    MissingPermissions,
+    OccupancyFull,
+}
+
+impl DenyReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DenyReason::DoorNotRegistered => "door_not_registered",
+            DenyReason::DoorDisabled => "door_disabled",
+            DenyReason::EmergencyOverride => "emergency_override",
+            DenyReason::SiteLockdown => "site_lockdown",
+            DenyReason::DoorLockdown => "door_lockdown",
+            DenyReason::KeyUnknown => "key_unknown",
+            DenyReason::KeyDisabled => "key_disabled",
+            DenyReason::KeyExpired => "key_expired",
+            DenyReason::OutsideSchedule => "outside_schedule",
+            DenyReason::DoorNotAllowed => "door_not_allowed",
+            DenyReason::GuestPassExpired => "guest_pass_expired",
+            DenyReason::AuthenticationDeclined => "authentication_declined",
+            DenyReason::MissingPermissions => "missing_permissions",
+            DenyReason::OccupancyFull => "occupancy_full",
+        }
+    }
+
+    /// Default text for `access_logs.reason`.
+    pub fn describe(&self) -> &'static str {
+        match self {
+            DenyReason::DoorNotRegistered => "door not registered",
+            DenyReason::DoorDisabled => "door disabled",
+            DenyReason::EmergencyOverride => "emergency override: deny all",
+            DenyReason::SiteLockdown => "site lockdown",
+            DenyReason::DoorLockdown => "door lockdown",
+            DenyReason::KeyUnknown => "key unknown",
+            DenyReason::KeyDisabled => "key disabled",
+            DenyReason::KeyExpired => "key expired",
+            DenyReason::OutsideSchedule => "key outside its schedule",
+            DenyReason::DoorNotAllowed => "key not allowed on this door",
+            DenyReason::GuestPassExpired => "guest pass expired or used up",
+            DenyReason::AuthenticationDeclined => "authentication declined",
+            DenyReason::MissingPermissions => "required permissions not granted",
+            DenyReason::OccupancyFull => "site at its maximum occupancy",
+        }
+    }
+}
+
+#[derive(Clone, sqlx::FromRow, serde::Serialize, serde::Deserialize)]
+pub struct AccessLog {
+    pub id: Uuid,
+    pub npub: Option<String>,
+    pub door_id: Option<i32>,
+    pub result: String,
+    pub reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub triggered_by: Option<String>, // admin user behind a manual unlock
+    pub reason_code: Option<String>,
+    pub granted_permissions: Option<Vec<String>>, // what the wallet granted on authentication
+    pub site_id: Option<Uuid>, // the door's site, None for doors unknown when it was logged
+    #[sqlx(default)]
+    pub credential: Option<String>, // the card presented at a badge reader
+    // Joined in from the key for the logs page; absent on freshly inserted rows
+    #[sqlx(default)]
+    pub profile_name: Option<String>,
+    #[sqlx(default)]
+    pub display_name: Option<String>,
+    #[sqlx(default)]
+    pub picture_url: Option<String>,
+    // Where the key's photo is served, also on freshly inserted rows
+    #[sqlx(default)]
+    pub photo_url: Option<String>,
+    // Joined in from the door for a key's history
+    #[sqlx(default)]
+    pub door_name: Option<String>,
+}
+
+impl AccessLog {
+    /// Whether someone working in `site` may see the entry; every entry shows for `None`.
+    pub fn in_site(&self, site: Option<Uuid>) -> bool {
+        site.is_none() || self.site_id == site
+    }
+}
+
+/// What one attempt at a door ended in, as `insert_access_log` records it.
+pub struct NewAccessLog<'a> {
+    pub npub: &'a str,
+    pub door_id: Option<i32>,
+    pub result: AccessResult,
+    pub reason_code: Option<DenyReason>,
+    pub reason: Option<&'a str>,
+    pub granted_permissions: Option<&'a [String]>,
+    pub credential: Option<&'a str>,
+}
+
+pub async fn insert_access_log(
+    pool: &Pool<Postgres>,
+    entry: &NewAccessLog<'_>,
+) -> Result<AccessLog, sqlx::Error> {
+    // A granted attempt also bumps the key's counters, in the same statement
+    sqlx::query_as::<_, AccessLog>(&format!(
+        "WITH counted AS (
+             UPDATE keys SET access_count = access_count + 1, last_access_at = $6
+             WHERE npub = $2 AND $4 = 'granted'
+         )
+         INSERT INTO access_logs (id, npub, door_id, result, reason, created_at, reason_code, granted_permissions, credential, site_id)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, (SELECT site_id FROM doors WHERE controller_door_id = $3))
+         RETURNING *, (SELECT {} FROM keys k WHERE k.npub = $2 AND k.deleted_at IS NULL)",
+        PHOTO_URL_SQL
+    ))
+    .bind(Uuid::new_v4())
+    .bind(entry.npub)
+    .bind(entry.door_id)
+    .bind(entry.result.as_str())
+    .bind(entry.reason)
+    .bind(Utc::now())
+    .bind(entry.reason_code.map(|code| code.as_str()))
+    .bind(entry.granted_permissions)
+    .bind(entry.credential)
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn get_access_log_by_id(
+    pool: &Pool<Postgres>,
+    id: Uuid,
+) -> Result<Option<AccessLog>, sqlx::Error> {
+    sqlx::query_as::<_, AccessLog>("SELECT * FROM access_logs WHERE id = $1")
+        .bind(id)
         .fetch_optional(pool)
+        .await
+}
+
+/// Records an unlock triggered from the admin UI rather than a Portal handshake.
+pub async fn insert_manual_access_log(
+    pool: &Pool<Postgres>,
+    triggered_by: &str,
+    door_id: i32,
+    result: AccessResult,
+    reason: Option<&str>,
+) -> Result<AccessLog, sqlx::Error> {
+    sqlx::query_as::<_, AccessLog>(
+        "INSERT INTO access_logs (door_id, result, reason, triggered_by, site_id)
+         VALUES ($1, $2, $3, $4, (SELECT site_id FROM doors WHERE controller_door_id = $1)) RETURNING *",
+    )
+    .bind(door_id)
+    .bind(result.as_str())
+    .bind(reason)
+    .bind(triggered_by)
+    .fetch_one(pool)
+    .await
+}
+
+/// Records an unlock an admin approved for a visitor at the doorbell.
+pub async fn insert_doorbell_access_log(
+    pool: &Pool<Postgres>,
+    npub: &str,
+    triggered_by: &str,
+    door_id: i32,
+    result: AccessResult,
+    reason: Option<&str>,
+) -> Result<AccessLog, sqlx::Error> {
+    sqlx::query_as::<_, AccessLog>(
+        "INSERT INTO access_logs (npub, door_id, result, reason, triggered_by, site_id)
+         VALUES ($1, $2, $3, $4, $5, (SELECT site_id FROM doors WHERE controller_door_id = $2)) RETURNING *",
+    )
+    .bind(npub)
+    .bind(door_id)
+    .bind(result.as_str())
+    .bind(reason)
+    .bind(triggered_by)
+    .fetch_one(pool)
+    .await
+}
+
+// An access log entry as its hash covers it, see the `chain_access_logs` migration
+#[derive(sqlx::FromRow)]
+struct ChainedEntry {
+    seq: i64,
+    id: Uuid,
+    created_at: DateTime<Utc>,
+    npub: Option<String>,
+    door_id: Option<i32>,
+    result: String,
+    reason: Option<String>,
+    reason_code: Option<String>,
+    triggered_by: Option<String>,
+    granted_permissions: Option<Vec<String>>,
+    credential: Option<String>,
+    site_id: Option<Uuid>,
+    prev_hash: Option<String>,
+    hash: String,
+}
+
+impl ChainedEntry {
+    // The hash the database computes in `access_log_hash`, worked out again here so a changed
+    // database function cannot vouch for changed entries
+    fn expected_hash(&self) -> String {
+        let fields = [
+            self.prev_hash.clone(),
+            Some(self.seq.to_string()),
+            Some(self.id.to_string()),
+            Some(self.created_at.format("%Y-%m-%dT%H:%M:%S%.6fZ").to_string()),
+            self.npub.clone(),
+            self.door_id.map(|door_id| door_id.to_string()),
+            Some(self.result.clone()),
+            self.reason.clone(),
+            self.reason_code.clone(),
+            self.triggered_by.clone(),
+            self.granted_permissions
+                .as_ref()
+                .map(|granted| serde_json::to_string(granted).unwrap_or_default()),
+            self.credential.clone(),
+            self.site_id.map(|site_id| site_id.to_string()),
+        ];
+
+        let mut content = String::new();
+        for field in fields {
+            match field {
+                Some(value) => content.push_str(&format!("{}:{}", value.len(), value)),
+                None => content.push('-'),
+            }
+        }
+        hex::encode(Sha256::digest(content.as_bytes()))
+    }
+}
+
+/// An access log entry that breaks the hash chain.
+#[derive(Debug, serde::Serialize)]
+pub struct ChainBreak {
+    pub seq: i64,
+    pub id: Uuid,
+    pub problem: &'static str,
+}
+
+/// What `verify_access_log_chain` found.
+#[derive(Debug, serde::Serialize)]
+pub struct ChainVerification {
+    pub entries: u64,
+    pub breaks: Vec<ChainBreak>,
+    /// The `prev_hash` of the oldest entry kept, the hash of the last entry the retention job
+    /// pruned; `None` while nothing has been pruned.
+    pub anchor: Option<String>,
+    /// The hash of the newest entry. Entries removed from the end of the log leave no break,
+    /// so auditors note this down and check it is still in the chain next time.
+    pub head: Option<String>,
+}
+
+impl ChainVerification {
+    pub fn is_intact(&self) -> bool {
+        self.breaks.is_empty()
+    }
+}
+
+/// Walks the whole access log in chain order and checks every entry's hash against its
+/// content and the entry before it.
+pub async fn verify_access_log_chain(
+    pool: &Pool<Postgres>,
+) -> Result<ChainVerification, sqlx::Error> {
+    let mut entries = sqlx::query_as::<_, ChainedEntry>(
+        "SELECT seq, id, created_at, npub, door_id, result, reason, reason_code, triggered_by,
+                granted_permissions, credential, site_id, prev_hash, hash
+         FROM access_logs ORDER BY seq",
+    )
+    .fetch(pool);
+
+    let mut verification = ChainVerification {
+        entries: 0,
+        breaks: Vec::new(),
+        anchor: None,
+        head: None,
+    };
+    while let Some(entry) = entries.next().await {
+        let entry = entry?;
+        match &verification.head {
+            None => verification.anchor = entry.prev_hash.clone(),
+            Some(previous) if entry.prev_hash.as_ref() != Some(previous) => {
+                verification.breaks.push(ChainBreak {
+                    seq: entry.seq,
+                    id: entry.id,
+                    problem: "does not follow the entry before it",
+                });
+            }
+            Some(_) => {}
+        }
+        if entry.expected_hash() != entry.hash {
+            verification.breaks.push(ChainBreak {
+                seq: entry.seq,
+                id: entry.id,
+                problem: "content does not match its hash",
+            });
+        }
+        verification.entries += 1;
+        verification.head = Some(entry.hash);
+    }
+
+    Ok(verification)
+}
+
+/// Which entries the logs page lists. `key` matches the start of the npub or anywhere in the
+/// key's profile or Nostr display name, ignoring case; `door_id` is the controller door number.
+/// Entries are kept from `from` up to, but not including, `to`.
+#[derive(Debug, Default)]
+pub struct AccessLogFilter {
+    pub key: Option<String>,
+    pub door_id: Option<i32>,
+    pub result: Option<AccessResult>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub site: Option<Uuid>,
+}
+
+impl AccessLogFilter {
+    /// Whether anything narrows the log down beyond the site.
+    pub fn is_filtered(&self) -> bool {
+        self.key.is_some()
+            || self.door_id.is_some()
+            || self.result.is_some()
+            || self.from.is_some()
+            || self.to.is_some()
+    }
+}
+
+const ACCESS_LOG_FILTER_SQL: &str = "($1::text IS NULL OR l.npub LIKE $1 || '%'
+         OR k.profile_name ILIKE '%' || $1 || '%' OR k.display_name ILIKE '%' || $1 || '%')
+    AND ($2::integer IS NULL OR l.door_id = $2)
+    AND ($3::text IS NULL OR l.result = $3)
+    AND ($4::timestamptz IS NULL OR l.created_at >= $4)
+    AND ($5::timestamptz IS NULL OR l.created_at < $5)
+    AND ($6::uuid IS NULL OR l.site_id = $6)";
+
+/// One page of the entries matching `filter`, newest first.
+// The `photo_url` of an access log entry, from its key joined in as `k`
+const PHOTO_URL_SQL: &str =
+    "CASE WHEN k.photo IS NOT NULL THEN '/keys/' || k.id || '/photo' END AS photo_url";
+
+pub async fn get_access_logs(
+    pool: &Pool<Postgres>,
+    filter: &AccessLogFilter,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<AccessLog>, sqlx::Error> {
+    sqlx::query_as::<_, AccessLog>(&format!(
+        "SELECT l.*, k.profile_name, k.display_name, k.picture_url, {} FROM access_logs l LEFT JOIN keys k ON k.npub = l.npub WHERE {} ORDER BY l.created_at DESC LIMIT $7 OFFSET $8",
+        PHOTO_URL_SQL, ACCESS_LOG_FILTER_SQL
+    ))
+    .bind(filter.key.as_deref().map(escape_like))
+    .bind(filter.door_id)
+    .bind(filter.result.map(|result| result.as_str()))
+    .bind(filter.from)
+    .bind(filter.to)
+    .bind(filter.site)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await
+}
+
+/// Entries newer than `after`, oldest first, for following the log as it grows.
+pub async fn get_access_logs_after(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+    after: DateTime<Utc>,
+) -> Result<Vec<AccessLog>, sqlx::Error> {
+    sqlx::query_as::<_, AccessLog>(&format!(
+        "SELECT l.*, k.profile_name, k.display_name, k.picture_url, {} FROM access_logs l LEFT JOIN keys k ON k.npub = l.npub WHERE l.created_at > $1 AND ($2::uuid IS NULL OR l.site_id = $2) ORDER BY l.created_at",
+        PHOTO_URL_SQL
+    ))
+    .bind(after)
+    .bind(site)
+    .fetch_all(pool)
+    .await
+}
+
+/// The latest `limit` entries for one npub, newest first, with the name of the door joined in.
+pub async fn get_key_access_logs(
+    pool: &Pool<Postgres>,
+    npub: &str,
+    limit: i64,
+) -> Result<Vec<AccessLog>, sqlx::Error> {
+    sqlx::query_as::<_, AccessLog>(
+        "SELECT l.*, d.name AS door_name FROM access_logs l LEFT JOIN doors d ON d.controller_door_id = l.door_id WHERE l.npub = $1 ORDER BY l.created_at DESC LIMIT $2",
+    )
+    .bind(npub)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+/// An access log row with the key's profile name and the door's name joined in, for exports.
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct AccessLogExportRow {
+    pub created_at: DateTime<Utc>,
+    pub npub: Option<String>,
+    pub profile_name: Option<String>,
+    pub door_id: Option<i32>,
+    pub door_name: Option<String>,
+    pub result: String,
+    pub reason_code: Option<String>,
+    pub reason: Option<String>,
+    pub triggered_by: Option<String>,
+}
+
+/// Streams access log rows in `[from, to)`, oldest first, without loading them all at once.
+pub fn stream_access_log_export(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> BoxStream<'_, Result<AccessLogExportRow, sqlx::Error>> {
+    sqlx::query_as::<_, AccessLogExportRow>(
+        "SELECT l.created_at, l.npub, k.profile_name, l.door_id, d.name AS door_name, l.result, l.reason_code, l.reason, l.triggered_by
+         FROM access_logs l
+         LEFT JOIN keys k ON k.npub = l.npub
+         LEFT JOIN doors d ON d.controller_door_id = l.door_id
+         WHERE ($1::timestamptz IS NULL OR l.created_at >= $1)
+           AND ($2::timestamptz IS NULL OR l.created_at < $2)
+           AND ($3::uuid IS NULL OR l.site_id = $3)
+         ORDER BY l.created_at",
+    )
+    .bind(from)
+    .bind(to)
+    .bind(site)
+    .fetch(pool)
+}
+
+/// Logs the retention job archives and prunes.
+#[derive(Debug, Clone, Copy)]
+pub enum LogTable {
+    AccessLogs,
+    AdminAudit,
+    Events,
+}
+
+impl LogTable {
+    pub fn name(self) -> &'static str {
+        match self {
+            LogTable::AccessLogs => "access_logs",
+            LogTable::AdminAudit => "admin_audit",
+            LogTable::Events => "events",
+        }
+    }
+}
+
+/// Streams the rows of `table` created before `cutoff`, oldest first, each as the JSON object
+/// of its columns.
+pub fn stream_log_rows_before(
+    pool: &Pool<Postgres>,
+    table: LogTable,
+    cutoff: DateTime<Utc>,
+) -> BoxStream<'_, Result<serde_json::Value, sqlx::Error>> {
+    let query = match table {
+        LogTable::AccessLogs => {
+            "SELECT row_to_json(t) FROM access_logs t WHERE t.created_at < $1 ORDER BY t.created_at"
+        }
+        LogTable::AdminAudit => {
+            "SELECT row_to_json(t) FROM admin_audit t WHERE t.created_at < $1 ORDER BY t.created_at"
+        }
+        LogTable::Events => {
+            "SELECT row_to_json(t) FROM events t WHERE t.created_at < $1 ORDER BY t.id"
+        }
+    };
+
+    sqlx::query_scalar::<_, serde_json::Value>(query)
+        .bind(cutoff)
+        .fetch(pool)
+}
+
+pub async fn delete_log_rows_before(
+    pool: &Pool<Postgres>,
+    table: LogTable,
+    cutoff: DateTime<Utc>,
+) -> Result<u64, sqlx::Error> {
+    let query = match table {
+        LogTable::AccessLogs => "DELETE FROM access_logs WHERE created_at < $1",
+        LogTable::AdminAudit => "DELETE FROM admin_audit WHERE created_at < $1",
+        LogTable::Events => "DELETE FROM events WHERE created_at < $1",
+    };
+
+    let result = sqlx::query(query).bind(cutoff).execute(pool).await?;
+    Ok(result.rows_affected())
+}
+
+/// One stage of a handshake as stored in `events`, see `access_events::PipelineEvent`.
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct StoredEvent {
+    pub id: i64,
+    pub attempt_id: Uuid,
+    pub npub: String,
+    pub door_id: i32,
+    pub stage: String,
+    pub data: serde_json::Value,
+    pub access_log_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+pub async fn insert_event(
+    pool: &Pool<Postgres>,
+    attempt_id: Uuid,
+    npub: &str,
+    door_id: i32,
+    stage: &str,
+    data: &serde_json::Value,
+    access_log_id: Option<Uuid>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO events (attempt_id, npub, door_id, stage, data, access_log_id)
+         VALUES ($1, $2, $3, $4, $5, $6)",
+    )
+    .bind(attempt_id)
+    .bind(npub)
+    .bind(door_id)
+    .bind(stage)
+    .bind(data)
+    .bind(access_log_id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Every stage of the handshake that ended in the access log entry `access_log_id`, in order.
+/// Empty for entries that did not come from a handshake, such as manual unlocks.
+pub async fn get_events_of_access_log(
+    pool: &Pool<Postgres>,
+    access_log_id: Uuid,
+) -> Result<Vec<StoredEvent>, sqlx::Error> {
+    sqlx::query_as::<_, StoredEvent>(
+        "SELECT * FROM events WHERE attempt_id IN (
+             SELECT attempt_id FROM events WHERE access_log_id = $1
+         ) ORDER BY id",
+    )
+    .bind(access_log_id)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn count_access_logs(
+    pool: &Pool<Postgres>,
+    filter: &AccessLogFilter,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar::<_, i64>(&format!(
+        "SELECT COUNT(*) FROM access_logs l LEFT JOIN keys k ON k.npub = l.npub WHERE {}",
+        ACCESS_LOG_FILTER_SQL
+    ))
+    .bind(filter.key.as_deref().map(escape_like))
+    .bind(filter.door_id)
+    .bind(filter.result.map(|result| result.as_str()))
+    .bind(filter.from)
+    .bind(filter.to)
+    .bind(filter.site)
+    .fetch_one(pool)
+    .await
+}
+
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct DailyUnlocks {
+    pub day: NaiveDate,
+    pub unlocks: i64,
+}
+
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct WeeklyKeys {
+    pub week: NaiveDate, // Monday the week starts on
+    pub unique_keys: i64,
+}
+
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct HourlyUnlocks {
+    pub hour: i32,
+    pub unlocks: i64,
+}
+
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct ResultCount {
+    pub result: String,
+    pub count: i64,
+}
+
+/// Granted unlocks per day for the last `days` days, today included; days without any are 0.
+pub async fn get_daily_unlocks(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+    days: i32,
+) -> Result<Vec<DailyUnlocks>, sqlx::Error> {
+    sqlx::query_as::<_, DailyUnlocks>(
+        "SELECT d::date AS day, COUNT(l.id) AS unlocks
+         FROM generate_series(CURRENT_DATE - ($1 - 1), CURRENT_DATE, INTERVAL '1 day') d
+         LEFT JOIN access_logs l
+           ON l.result = 'granted' AND l.created_at >= d AND l.created_at < d + INTERVAL '1 day'
+          AND ($2::uuid IS NULL OR l.site_id = $2)
+         GROUP BY d ORDER BY d",
+    )
+    .bind(days)
+    .bind(site)
+    .fetch_all(pool)
+    .await
+}
+
+/// Distinct npubs granted access per week for the last `weeks` weeks, this week included.
+pub async fn get_weekly_unique_keys(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+    weeks: i32,
+) -> Result<Vec<WeeklyKeys>, sqlx::Error> {
+    sqlx::query_as::<_, WeeklyKeys>(
+        "SELECT w::date AS week, COUNT(DISTINCT l.npub) AS unique_keys
+         FROM generate_series(date_trunc('week', NOW()) - ($1 - 1) * INTERVAL '1 week', date_trunc('week', NOW()), INTERVAL '1 week') w
+         LEFT JOIN access_logs l
+           ON l.result = 'granted' AND l.npub IS NOT NULL AND l.created_at >= w AND l.created_at < w + INTERVAL '1 week'
+          AND ($2::uuid IS NULL OR l.site_id = $2)
+         GROUP BY w ORDER BY w",
+    )
+    .bind(weeks)
+    .bind(site)
+    .fetch_all(pool)
+    .await
+}
+
+/// Granted unlocks by hour of day over the last `days` days, all 24 hours included.
+pub async fn get_hourly_unlocks(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+    days: i32,
+) -> Result<Vec<HourlyUnlocks>, sqlx::Error> {
+    sqlx::query_as::<_, HourlyUnlocks>(
+        "SELECT h AS hour, COUNT(l.id) AS unlocks
+         FROM generate_series(0, 23) h
+         LEFT JOIN access_logs l
+           ON l.result = 'granted' AND EXTRACT(HOUR FROM l.created_at)::INT = h
+          AND l.created_at >= NOW() - $1 * INTERVAL '1 day'
+          AND ($2::uuid IS NULL OR l.site_id = $2)
+         GROUP BY h ORDER BY h",
+    )
+    .bind(days)
+    .bind(site)
+    .fetch_all(pool)
+    .await
+}
+
+/// Handshake outcomes over the last `days` days. Manual unlocks from the admin UI are left out.
+pub async fn get_result_counts(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+    days: i32,
+) -> Result<Vec<ResultCount>, sqlx::Error> {
+    sqlx::query_as::<_, ResultCount>(
+        "SELECT result, COUNT(*) AS count FROM access_logs
+         WHERE npub IS NOT NULL AND created_at >= NOW() - $1 * INTERVAL '1 day'
+           AND ($2::uuid IS NULL OR site_id = $2)
+         GROUP BY result ORDER BY count DESC",
+    )
+    .bind(days)
+    .bind(site)
+    .fetch_all(pool)
+    .await
+}
+
+/// What happened over one day, for the daily digest.
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct DigestCounts {
+    pub unlocks: i64,
+    pub declined: i64, // declined or denied handshakes and cards
+    pub new_keys: i64,
+}
+
+pub async fn get_digest_counts(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+    from: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> Result<DigestCounts, sqlx::Error> {
+    sqlx::query_as::<_, DigestCounts>(
+        "SELECT
+             COUNT(*) FILTER (WHERE l.result = 'granted') AS unlocks,
+             COUNT(*) FILTER (WHERE l.result IN ('declined', 'denied')) AS declined,
+             (SELECT COUNT(*) FROM keys k
+              WHERE k.created_at >= $2 AND k.created_at < $3 AND NOT k.pending
+                AND ($1::uuid IS NULL OR k.site_id = $1)) AS new_keys
+         FROM access_logs l
+         WHERE l.created_at >= $2 AND l.created_at < $3
+           AND ($1::uuid IS NULL OR l.site_id = $1)",
+    )
+    .bind(site)
+    .bind(from)
+    .bind(until)
+    .fetch_one(pool)
+    .await
+}
+
+/// A door that failed to unlock or did not relock, for the daily digest.
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct DoorErrors {
+    pub door_id: i32,
+    pub name: String,
+    pub unlock_errors: i64,
+    pub relock_failures: i64,
+}
+
+/// Doors with failed unlocks or relocks between `from` and `until`, the most troubled first.
+pub async fn get_door_errors(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+    from: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> Result<Vec<DoorErrors>, sqlx::Error> {
+    sqlx::query_as::<_, DoorErrors>(
+        "SELECT * FROM (
+             SELECT d.controller_door_id AS door_id, d.name,
+                 (SELECT COUNT(*) FROM access_logs l
+                  WHERE l.door_id = d.controller_door_id AND l.result = 'error'
+                    AND l.created_at >= $2 AND l.created_at < $3) AS unlock_errors,
+                 (SELECT COUNT(*) FROM door_events e
+                  WHERE e.door_id = d.id AND e.event = 'relock_failed'
+                    AND e.created_at >= $2 AND e.created_at < $3) AS relock_failures
+             FROM doors d
+             WHERE ($1::uuid IS NULL OR d.site_id = $1)
+         ) errors
+         WHERE unlock_errors > 0 OR relock_failures > 0
+         ORDER BY unlock_errors + relock_failures DESC, door_id",
+    )
+    .bind(site)
+    .bind(from)
+    .bind(until)
+    .fetch_all(pool)
+    .await
+}
+
+/// Claims sending the digest of `day`; `false` when another web service already did.
+pub async fn claim_digest_day(pool: &Pool<Postgres>, day: NaiveDate) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("INSERT INTO digest_runs (day) VALUES ($1) ON CONFLICT DO NOTHING")
+        .bind(day)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+#[derive(Clone, sqlx::FromRow, serde::Serialize, serde::Deserialize)]
+pub struct Door {
+    pub id: Uuid,
+    pub name: String,
+    pub controller_door_id: i32,
+    pub enabled: bool,
+    pub created_at: DateTime<Utc>,
+    pub price_sats: Option<i64>, // charged through Portal before unlocking, None for free doors
+    pub locked_down: bool,
+    pub timezone: Option<String>, // overrides the site timezone for key schedules
+    pub unlock_seconds: Option<i32>, // None for the door backend's default
+    pub required_permissions: Vec<String>, // the wallet must grant all of them
+    pub site_id: Uuid,
+    pub handshake_token: Option<String>, // overrides the DOOR_SUBJECTS subject
+    pub handshake_rotate_secs: Option<i32>, // None keeps one static handshake URL
+    #[serde(default)] // missing from resolutions recorded before it was added
+    pub handshake_single_use: bool, // a new random handshake URL after every handshake
+    pub direction: Option<String>, // entry or exit for the site's occupancy, None for not counted
+}
+
+impl Door {
+    /// How long an unlock holds the door open, for `DoorBackend::unlock`.
+    pub fn unlock_duration(&self) -> Option<u32> {
+        self.unlock_seconds.map(|seconds| seconds as u32)
+    }
+}
+
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct DoorEvent {
+    pub id: Uuid,
+    pub door_id: Uuid,
+    pub event: String, // opened, closed, held_open, auto_unlocked, auto_locked or relock_failed
+    pub created_at: DateTime<Utc>,
+}
+
+pub async fn insert_door_event(
+    pool: &Pool<Postgres>,
+    door_id: Uuid,
+    event: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO door_events (door_id, event) VALUES ($1, $2)")
+        .bind(door_id)
+        .bind(event)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn get_recent_door_events(
+    pool: &Pool<Postgres>,
+    door_id: Uuid,
+    limit: i64,
+) -> Result<Vec<DoorEvent>, sqlx::Error> {
+    sqlx::query_as::<_, DoorEvent>(
+        "SELECT * FROM door_events WHERE door_id = $1 ORDER BY created_at DESC LIMIT $2",
+    )
+    .bind(door_id)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+/// Whether the door's latest scheduled event unlocked it, so it is meant to stay unlocked until
+/// its window ends.
+pub async fn is_held_on_schedule(
+    pool: &Pool<Postgres>,
+    door_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let latest = sqlx::query_scalar::<_, String>(
+        "SELECT event FROM door_events
+         WHERE door_id = $1 AND event IN ('auto_unlocked', 'auto_locked')
+         ORDER BY created_at DESC LIMIT 1",
+    )
+    .bind(door_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(latest.as_deref() == Some("auto_unlocked"))
+}
+
+/// A weekly window a door is held unlocked for, see `auto_lock`.
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct DoorSchedule {
+    pub id: Uuid,
+    pub door_id: Uuid,
+    pub weekdays: Vec<i16>,
+    pub unlock_time: NaiveTime,
+    pub lock_time: NaiveTime,
+    pub created_at: DateTime<Utc>,
+}
+
+pub async fn get_door_schedules(
+    pool: &Pool<Postgres>,
+    door_id: Uuid,
+) -> Result<Vec<DoorSchedule>, sqlx::Error> {
+    sqlx::query_as::<_, DoorSchedule>(
+        "SELECT * FROM door_schedules WHERE door_id = $1 ORDER BY unlock_time, created_at",
+    )
+    .bind(door_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// The schedules of every enabled door, for the auto-lock scheduler.
+pub async fn get_all_door_schedules(
+    pool: &Pool<Postgres>,
+) -> Result<Vec<DoorSchedule>, sqlx::Error> {
+    sqlx::query_as::<_, DoorSchedule>(
+        "SELECT s.* FROM door_schedules s JOIN doors d ON d.id = s.door_id WHERE d.enabled",
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn insert_door_schedule(
+    pool: &Pool<Postgres>,
+    door_id: Uuid,
+    weekdays: &[i16],
+    unlock_time: NaiveTime,
+    lock_time: NaiveTime,
+) -> Result<Uuid, sqlx::Error> {
+    sqlx::query_scalar::<_, Uuid>(
+        "INSERT INTO door_schedules (door_id, weekdays, unlock_time, lock_time) VALUES ($1, $2, $3, $4) RETURNING id",
+    )
+    .bind(door_id)
+    .bind(weekdays)
+    .bind(unlock_time)
+    .bind(lock_time)
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn delete_door_schedule_by_id(
+    pool: &Pool<Postgres>,
+    door_id: Uuid,
+    schedule_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM door_schedules WHERE id = $1 AND door_id = $2")
+        .bind(schedule_id)
+        .bind(door_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn get_all_doors(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+) -> Result<Vec<Door>, sqlx::Error> {
+    sqlx::query_as::<_, Door>(
+        "SELECT * FROM doors WHERE ($1::uuid IS NULL OR site_id = $1) ORDER BY name",
+    )
+    .bind(site)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_door_by_id(
+    pool: &Pool<Postgres>,
+    door_id: Uuid,
+    site: Option<Uuid>,
+) -> Result<Option<Door>, sqlx::Error> {
+    sqlx::query_as::<_, Door>(
+        "SELECT * FROM doors WHERE id = $1 AND ($2::uuid IS NULL OR site_id = $2)",
+    )
+    .bind(door_id)
+    .bind(site)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn get_door_by_controller_id(
+    pool: &Pool<Postgres>,
+    controller_door_id: i32,
+) -> Result<Option<Door>, sqlx::Error> {
+    sqlx::query_as::<_, Door>("SELECT * FROM doors WHERE controller_door_id = $1")
+        .bind(controller_door_id)
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn insert_door(
+    pool: &Pool<Postgres>,
+    name: &str,
+    controller_door_id: i32,
+    site_id: Uuid,
+) -> Result<Uuid, sqlx::Error> {
+    let id = Uuid::new_v4();
+
+    sqlx::query(
+        "INSERT INTO doors (id, name, controller_door_id, enabled, created_at, site_id) VALUES ($1, $2, $3, $4, $5, $6)",
+    )
+    .bind(id)
+    .bind(name)
+    .bind(controller_door_id)
+    .bind(true) // Default to enabled
+    .bind(Utc::now())
+    .bind(site_id)
+    .execute(pool)
+    .await?;
+
+    Ok(id)
+}
+
+/// Registers the door served by the handshake loop if it is missing. A freshly
+/// registered door is granted to every existing key so upgrading from the single
+/// `DOOR_ID` setup keeps the same keys working.
+pub async fn ensure_door(
+    pool: &Pool<Postgres>,
+    controller_door_id: i32,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let inserted = sqlx::query_scalar::<_, Uuid>(
+        "INSERT INTO doors (id, name, controller_door_id, enabled, created_at) VALUES ($1, $2, $3, true, $4) ON CONFLICT (controller_door_id) DO NOTHING RETURNING id",
+    )
+    .bind(Uuid::new_v4())
+    .bind(format!("Door {}", controller_door_id))
+    .bind(controller_door_id)
+    .bind(Utc::now())
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if let Some(door_id) = inserted {
+        sqlx::query("INSERT INTO key_door_permissions (key_id, door_id) SELECT id, $1 FROM keys WHERE deleted_at IS NULL")
+            .bind(door_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await
+}
+
+pub async fn toggle_door_status(pool: &Pool<Postgres>, door_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE doors SET enabled = NOT enabled WHERE id = $1")
+        .bind(door_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Locks a door down or lifts its lockdown. Returns whether the door exists.
+pub async fn set_door_lockdown(
+    pool: &Pool<Postgres>,
+    door_id: Uuid,
+    locked_down: bool,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE doors SET locked_down = $1 WHERE id = $2")
+        .bind(locked_down)
+        .bind(door_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Counts granted entries through the door in the site's occupancy, as `entry` or `exit`, or
+/// stops counting them with `None`.
+pub async fn set_door_direction(
+    pool: &Pool<Postgres>,
+    door_id: Uuid,
+    direction: Option<&str>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE doors SET direction = $1 WHERE id = $2")
+        .bind(direction)
+        .bind(door_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Sets the timezone the door's key schedules are evaluated in; `None` goes back to the site
+/// timezone.
+pub async fn set_door_timezone(
+    pool: &Pool<Postgres>,
+    door_id: Uuid,
+    timezone: Option<&str>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE doors SET timezone = $1 WHERE id = $2")
+        .bind(timezone)
+        .bind(door_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Serves the door's handshake URL under `token` (None for its `DOOR_SUBJECTS` subject), or
+/// under a new random token every `rotate_secs` seconds, or after every handshake when
+/// `single_use`.
+pub async fn set_door_handshake(
+    pool: &Pool<Postgres>,
+    door_id: Uuid,
+    token: Option<&str>,
+    rotate_secs: Option<i32>,
+    single_use: bool,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE doors SET handshake_token = $1, handshake_rotate_secs = $2, handshake_single_use = $3 WHERE id = $4",
+    )
+    .bind(token)
+    .bind(rotate_secs)
+    .bind(single_use)
+    .bind(door_id)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn set_door_required_permissions(
+    pool: &Pool<Postgres>,
+    door_id: Uuid,
+    permissions: &[String],
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE doors SET required_permissions = $1 WHERE id = $2")
+        .bind(permissions)
+        .bind(door_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn set_door_unlock_seconds(
+    pool: &Pool<Postgres>,
+    door_id: Uuid,
+    unlock_seconds: Option<i32>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE doors SET unlock_seconds = $1 WHERE id = $2")
+        .bind(unlock_seconds)
+        .bind(door_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// The site-wide lockdown switch, a single row.
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct Lockdown {
+    pub active: bool,
+    pub reason: Option<String>,
+    pub set_by: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+pub async fn get_lockdown(pool: &Pool<Postgres>) -> Result<Lockdown, sqlx::Error> {
+    sqlx::query_as::<_, Lockdown>("SELECT active, reason, set_by, updated_at FROM lockdown")
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn set_lockdown(
+    pool: &Pool<Postgres>,
+    active: bool,
+    reason: Option<&str>,
+    set_by: &str,
+) -> Result<Lockdown, sqlx::Error> {
+    sqlx::query_as::<_, Lockdown>(
+        "UPDATE lockdown SET active = $1, reason = $2, set_by = $3, updated_at = NOW() RETURNING active, reason, set_by, updated_at",
+    )
+    .bind(active)
+    .bind(reason)
+    .bind(set_by)
+    .fetch_one(pool)
+    .await
+}
+
+/// The branding of the pages, a single row; `None` keeps the built-in look.
+#[derive(Clone, sqlx::FromRow, serde::Serialize)]
+pub struct ThemeSettings {
+    pub site_name: Option<String>,
+    pub primary_color: Option<String>, // #rrggbb, like the colors below
+    pub background_color: Option<String>,
+    pub text_color: Option<String>,
+    pub logo: Option<String>, // name in the photo store
+    pub logo_type: Option<String>,
+    pub updated_by: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+const THEME_COLUMNS: &str = "site_name, primary_color, background_color, text_color, logo, logo_type, updated_by, updated_at";
+
+pub async fn get_theme(pool: &Pool<Postgres>) -> Result<ThemeSettings, sqlx::Error> {
+    sqlx::query_as::<_, ThemeSettings>(&format!("SELECT {} FROM theme", THEME_COLUMNS))
+        .fetch_one(pool)
+        .await
+}
+
+/// Sets the site name and colors, leaving the logo as it is.
+pub async fn set_theme(
+    pool: &Pool<Postgres>,
+    site_name: Option<&str>,
+    colors: [Option<&str>; 3], // primary, background and text
+    updated_by: &str,
+) -> Result<ThemeSettings, sqlx::Error> {
+    let [primary_color, background_color, text_color] = colors;
+    sqlx::query_as::<_, ThemeSettings>(&format!(
+        "UPDATE theme SET site_name = $1, primary_color = $2, background_color = $3, text_color = $4,
+             updated_by = $5, updated_at = NOW()
+         RETURNING {}",
+        THEME_COLUMNS
+    ))
+    .bind(site_name)
+    .bind(primary_color)
+    .bind(background_color)
+    .bind(text_color)
+    .bind(updated_by)
+    .fetch_one(pool)
+    .await
+}
+
+/// Replaces the logo, or removes it with `None`. Returns the theme and the name of the logo it
+/// replaced, for removing it from the store.
+pub async fn set_theme_logo(
+    pool: &Pool<Postgres>,
+    logo: Option<(&str, &str)>,
+    updated_by: &str,
+) -> Result<(ThemeSettings, Option<String>), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let previous = sqlx::query_scalar::<_, Option<String>>("SELECT logo FROM theme FOR UPDATE")
+        .fetch_one(&mut *tx)
+        .await?;
+    let theme = sqlx::query_as::<_, ThemeSettings>(&format!(
+        "UPDATE theme SET logo = $1, logo_type = $2, updated_by = $3, updated_at = NOW()
+         RETURNING {}",
+        THEME_COLUMNS
+    ))
+    .bind(logo.map(|(name, _)| name))
+    .bind(logo.map(|(_, image_type)| image_type))
+    .bind(updated_by)
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok((theme, previous))
+}
+
+pub async fn set_door_price(
+    pool: &Pool<Postgres>,
+    door_id: Uuid,
+    price_sats: Option<i64>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE doors SET price_sats = $1 WHERE id = $2")
+        .bind(price_sats)
+        .bind(door_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn delete_door_by_id(pool: &Pool<Postgres>, door_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM doors WHERE id = $1")
+        .bind(door_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn get_door_key_ids(
+    pool: &Pool<Postgres>,
+    door_id: Uuid,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    sqlx::query_scalar::<_, Uuid>("SELECT key_id FROM key_door_permissions WHERE door_id = $1")
+        .bind(door_id)
+        .fetch_all(pool)
+        .await
+}
+
+/// Replaces the set of keys allowed to open a door.
+pub async fn set_door_keys(
+    pool: &Pool<Postgres>,
+    door_id: Uuid,
+    key_ids: &[Uuid],
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    // Deleted keys are not on the form, so their permissions are kept for a restore
+    sqlx::query(
+        "DELETE FROM key_door_permissions WHERE door_id = $1 AND key_id IN (SELECT id FROM keys WHERE deleted_at IS NULL)",
+    )
+        .bind(door_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        "INSERT INTO key_door_permissions (key_id, door_id)
+         SELECT id, $2 FROM keys WHERE id = ANY($1) AND site_id = (SELECT site_id FROM doors WHERE id = $2)",
+    )
+        .bind(key_ids)
+        .bind(door_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await
+}
+
+pub async fn grant_key_doors(
+    pool: &Pool<Postgres>,
+    key_id: Uuid,
+    door_ids: &[Uuid],
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO key_door_permissions (key_id, door_id)
+         SELECT $1, id FROM doors WHERE id = ANY($2) AND site_id = (SELECT site_id FROM keys WHERE id = $1)
+         ON CONFLICT DO NOTHING",
+    )
+    .bind(key_id)
+    .bind(door_ids)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// A door a key may open, and whether it was granted directly, through groups, or both.
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct KeyDoor {
+    pub id: Uuid,
+    pub name: String,
+    pub controller_door_id: i32,
+    pub enabled: bool,
+    pub direct: bool,
+    pub groups: Vec<String>, // the key's groups granted the door
+}
+
+pub async fn get_key_doors(
+    pool: &Pool<Postgres>,
+    key_id: Uuid,
+) -> Result<Vec<KeyDoor>, sqlx::Error> {
+    sqlx::query_as::<_, KeyDoor>(
+        "SELECT d.id, d.name, d.controller_door_id, d.enabled,
+                EXISTS (SELECT 1 FROM key_door_permissions p WHERE p.key_id = $1 AND p.door_id = d.id) AS direct,
+                ARRAY (SELECT g.name FROM key_groups kg
+                       JOIN group_door_permissions p ON p.group_id = kg.group_id
+                       JOIN groups g ON g.id = kg.group_id
+                       WHERE kg.key_id = $1 AND p.door_id = d.id ORDER BY g.name) AS groups
+         FROM doors d
+         WHERE d.site_id = (SELECT site_id FROM keys WHERE id = $1)
+           AND d.id IN (SELECT door_id FROM key_door_permissions WHERE key_id = $1
+                        UNION SELECT p.door_id FROM key_groups g
+                              JOIN group_door_permissions p ON p.group_id = g.group_id WHERE g.key_id = $1)
+         ORDER BY d.name",
+    )
+    .bind(key_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// A named set of keys, granted doors as a whole.
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct Group {
+    pub id: Uuid,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    #[sqlx(default)]
+    pub member_count: i64,
+}
+
+pub async fn get_all_groups(pool: &Pool<Postgres>) -> Result<Vec<Group>, sqlx::Error> {
+    sqlx::query_as::<_, Group>(
+        "SELECT g.*, (SELECT COUNT(*) FROM key_groups kg JOIN keys k ON k.id = kg.key_id WHERE kg.group_id = g.id AND k.deleted_at IS NULL) AS member_count FROM groups g ORDER BY g.name",
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_group_by_id(
+    pool: &Pool<Postgres>,
+    group_id: Uuid,
+) -> Result<Option<Group>, sqlx::Error> {
+    sqlx::query_as::<_, Group>("SELECT * FROM groups WHERE id = $1")
+        .bind(group_id)
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn insert_group(pool: &Pool<Postgres>, name: &str) -> Result<Uuid, sqlx::Error> {
+    let id = Uuid::new_v4();
+
+    sqlx::query("INSERT INTO groups (id, name, created_at) VALUES ($1, $2, $3)")
+        .bind(id)
+        .bind(name)
+        .bind(Utc::now())
+        .execute(pool)
+        .await?;
+
+    Ok(id)
+}
+
+pub async fn delete_group_by_id(pool: &Pool<Postgres>, group_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM groups WHERE id = $1")
+        .bind(group_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn get_group_key_ids(
+    pool: &Pool<Postgres>,
+    group_id: Uuid,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    sqlx::query_scalar::<_, Uuid>("SELECT key_id FROM key_groups WHERE group_id = $1")
+        .bind(group_id)
+        .fetch_all(pool)
+        .await
+}
+
+/// Replaces the members of a group.
+pub async fn set_group_keys(
+    pool: &Pool<Postgres>,
+    group_id: Uuid,
+    key_ids: &[Uuid],
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    // Deleted keys are not on the form, so their memberships are kept for a restore
+    sqlx::query(
+        "DELETE FROM key_groups WHERE group_id = $1 AND key_id IN (SELECT id FROM keys WHERE deleted_at IS NULL)",
+    )
+        .bind(group_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("INSERT INTO key_groups (key_id, group_id) SELECT UNNEST($1::uuid[]), $2")
+        .bind(key_ids)
+        .bind(group_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await
+}
+
+pub async fn get_door_group_ids(
+    pool: &Pool<Postgres>,
+    door_id: Uuid,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    sqlx::query_scalar::<_, Uuid>("SELECT group_id FROM group_door_permissions WHERE door_id = $1")
+        .bind(door_id)
+        .fetch_all(pool)
+        .await
+}
+
+/// Replaces the set of groups allowed to open a door.
+pub async fn set_door_groups(
+    pool: &Pool<Postgres>,
+    door_id: Uuid,
+    group_ids: &[Uuid],
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("DELETE FROM group_door_permissions WHERE door_id = $1")
+        .bind(door_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        "INSERT INTO group_door_permissions (group_id, door_id) SELECT UNNEST($1::uuid[]), $2",
+    )
+    .bind(group_ids)
+    .bind(door_id)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await
+}
+
+/// A weekly time window in which a key may be used, in the server's local time.
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct Schedule {
+    pub id: Uuid,
+    pub key_id: Uuid,
+    pub weekdays: Vec<i16>,
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+    pub created_at: DateTime<Utc>,
+}
+
+pub async fn get_key_by_id(
+    pool: &Pool<Postgres>,
+    key_id: Uuid,
+    site: Option<Uuid>,
+) -> Result<Option<PublicKey>, sqlx::Error> {
+    sqlx::query_as::<_, PublicKey>(
+        "SELECT * FROM keys WHERE id = $1 AND deleted_at IS NULL AND ($2::uuid IS NULL OR site_id = $2)",
+    )
+    .bind(key_id)
+    .bind(site)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Replaces a key's notes and tags. Returns false when there is no such key.
+pub async fn update_key_details(
+    pool: &Pool<Postgres>,
+    key_id: Uuid,
+    site: Option<Uuid>,
+    notes: Option<&str>,
+    tags: &[String],
+) -> Result<bool, sqlx::Error> {
+    let result =
+        sqlx::query("UPDATE keys SET notes = $2, tags = $3 WHERE id = $1 AND deleted_at IS NULL AND ($4::uuid IS NULL OR site_id = $4)")
+            .bind(key_id)
+            .bind(notes)
+            .bind(tags)
+            .bind(site)
+            .execute(pool)
+            .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Sets a key's photo, as its name in the photo store and image type, or removes it with
+/// `None`. Returns the name of the photo it replaces, if any, or `None` when there is no such
+/// key.
+pub async fn set_key_photo(
+    pool: &Pool<Postgres>,
+    key_id: Uuid,
+    site: Option<Uuid>,
+    photo: Option<(&str, &str)>,
+) -> Result<Option<Option<String>>, sqlx::Error> {
+    sqlx::query_scalar::<_, Option<String>>(
+        "UPDATE keys k SET photo = $2, photo_type = $3
+         FROM (SELECT id, photo FROM keys WHERE id = $1 FOR UPDATE) previous
+         WHERE k.id = previous.id AND k.deleted_at IS NULL AND ($4::uuid IS NULL OR k.site_id = $4)
+         RETURNING previous.photo",
+    )
+    .bind(key_id)
+    .bind(photo.map(|(name, _)| name))
+    .bind(photo.map(|(_, image_type)| image_type))
+    .bind(site)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Every tag in use on a key, in alphabetical order.
+pub async fn get_key_tags(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar::<_, String>(
+        "SELECT DISTINCT unnest(tags) AS tag FROM keys WHERE deleted_at IS NULL AND ($1::uuid IS NULL OR site_id = $1) ORDER BY tag",
+    )
+    .bind(site)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_key_schedules(
+    pool: &Pool<Postgres>,
+    key_id: Uuid,
+) -> Result<Vec<Schedule>, sqlx::Error> {
+    sqlx::query_as::<_, Schedule>(
+        "SELECT * FROM key_schedules WHERE key_id = $1 ORDER BY start_time, created_at",
+    )
+    .bind(key_id)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn insert_schedule(
+    pool: &Pool<Postgres>,
+    key_id: Uuid,
+    weekdays: &[i16],
+    start_time: NaiveTime,
+    end_time: NaiveTime,
+) -> Result<Uuid, sqlx::Error> {
+    sqlx::query_scalar::<_, Uuid>(
+        "INSERT INTO key_schedules (key_id, weekdays, start_time, end_time) VALUES ($1, $2, $3, $4) RETURNING id",
+    )
+    .bind(key_id)
+    .bind(weekdays)
+    .bind(start_time)
+    .bind(end_time)
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn delete_schedule_by_id(
+    pool: &Pool<Postgres>,
+    key_id: Uuid,
+    schedule_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM key_schedules WHERE id = $1 AND key_id = $2")
+        .bind(schedule_id)
+        .bind(key_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// A badge card that stands in for its key at the badge readers, see `card_readers`.
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct Credential {
+    pub id: Uuid,
+    pub key_id: Uuid,
+    pub card_number: String,
+    pub label: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+pub async fn get_key_credentials(
+    pool: &Pool<Postgres>,
+    key_id: Uuid,
+) -> Result<Vec<Credential>, sqlx::Error> {
+    sqlx::query_as::<_, Credential>(
+        "SELECT * FROM credentials WHERE key_id = $1 ORDER BY created_at",
+    )
+    .bind(key_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Adds a card to a key. `None` when the card already belongs to a key.
+pub async fn insert_credential(
+    pool: &Pool<Postgres>,
+    key_id: Uuid,
+    card_number: &str,
+    label: Option<&str>,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    sqlx::query_scalar::<_, Uuid>(
+        "INSERT INTO credentials (key_id, card_number, label) VALUES ($1, $2, $3)
+         ON CONFLICT (card_number) DO NOTHING RETURNING id",
+    )
+    .bind(key_id)
+    .bind(card_number)
+    .bind(label)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn delete_credential_by_id(
+    pool: &Pool<Postgres>,
+    key_id: Uuid,
+    credential_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM credentials WHERE id = $1 AND key_id = $2")
+        .bind(credential_id)
+        .bind(key_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// The npub of the key a card belongs to. Cards of deleted keys open nothing.
+pub async fn get_credential_npub(
+    pool: &Pool<Postgres>,
+    card_number: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar::<_, String>(
+        "SELECT k.npub FROM credentials c JOIN keys k ON k.id = c.key_id
+         WHERE c.card_number = $1 AND k.deleted_at IS NULL",
+    )
+    .bind(card_number)
+    .fetch_optional(pool)
+    .await
+}
+
+/// What the unlock path checks about a key: whether it is enabled and until when, the doors of
+/// its site it may open directly or through its groups, and the weekly windows it is limited to.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct KeyRules {
+    pub enabled: bool,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub door_ids: HashSet<Uuid>,
+    pub windows: Vec<ScheduleWindow>,
+}
+
+#[derive(Clone, sqlx::FromRow, serde::Serialize, serde::Deserialize)]
+pub struct ScheduleWindow {
+    pub weekdays: Vec<i16>,
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+}
+
+/// Loads the rules of a key, or `None` when there is no such key.
+pub async fn get_key_rules(
+    pool: &Pool<Postgres>,
+    npub: &str,
+) -> Result<Option<KeyRules>, sqlx::Error> {
+    let Some((key_id, enabled, expires_at)) =
+        sqlx::query_as::<_, (Uuid, bool, Option<DateTime<Utc>>)>(KEY_STATUS_SQL)
+            .bind(npub)
+            .fetch_optional(pool)
+            .await?
+    else {
+        return Ok(None);
+    };
+    if !enabled {
+        return Ok(Some(KeyRules {
+            enabled,
+            expires_at,
+            door_ids: HashSet::new(),
+            windows: Vec::new(),
+        }));
+    }
+
+    let door_ids = sqlx::query_scalar::<_, Uuid>(KEY_DOORS_SQL)
+        .bind(key_id)
+        .fetch_all(pool)
+        .await?;
+    let windows = sqlx::query_as::<_, ScheduleWindow>(KEY_WINDOWS_SQL)
+        .bind(key_id)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(Some(KeyRules {
+        enabled,
+        expires_at,
+        door_ids: door_ids.into_iter().collect(),
+        windows,
+    }))
+}
+
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct User {
+    pub id: Uuid,
+    pub username: String,
+    #[serde(skip_serializing)]
+    pub password_hash: String,
+    pub role: String,
+    pub created_at: DateTime<Utc>,
+    pub site_id: Option<Uuid>, // None for users of every site
+    pub password_changed_at: DateTime<Utc>,
+    #[serde(skip_serializing)]
+    pub totp_secret: Option<String>, // hex, None without two-factor sign in
+    // Joined in for the user list
+    #[sqlx(default)]
+    pub site_name: Option<String>,
+}
+
+pub async fn get_all_users(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+) -> Result<Vec<User>, sqlx::Error> {
+    sqlx::query_as::<_, User>(
+        "SELECT u.*, s.name AS site_name FROM users u LEFT JOIN sites s ON s.id = u.site_id
+         WHERE ($1::uuid IS NULL OR u.site_id = $1) ORDER BY u.username",
+    )
+    .bind(site)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_user_by_username(
+    pool: &Pool<Postgres>,
+    username: &str,
+) -> Result<Option<User>, sqlx::Error> {
+    sqlx::query_as::<_, User>("SELECT * FROM users WHERE username = $1")
+        .bind(username)
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn insert_user(
+    pool: &Pool<Postgres>,
+    username: &str,
+    password_hash: &str,
+    role: &str,
+    site_id: Option<Uuid>,
+) -> Result<Uuid, sqlx::Error> {
+    sqlx::query_scalar::<_, Uuid>(
+        "INSERT INTO users (username, password_hash, role, site_id) VALUES ($1, $2, $3, $4) RETURNING id",
+    )
+    .bind(username)
+    .bind(password_hash)
+    .bind(role)
+    .bind(site_id)
+    .fetch_one(pool)
+    .await
+}
+
+/// Creates the first admin account; does nothing once any user exists.
+pub async fn ensure_admin_user(
+    pool: &Pool<Postgres>,
+    username: &str,
+    password_hash: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "INSERT INTO users (username, password_hash, role) SELECT $1, $2, 'admin' WHERE NOT EXISTS (SELECT 1 FROM users)",
+    )
+    .bind(username)
+    .bind(password_hash)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Replaces a user's password hash and restarts its rotation clock. Returns whether the user
+/// exists.
+pub async fn set_user_password(
+    pool: &Pool<Postgres>,
+    username: &str,
+    password_hash: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE users SET password_hash = $2, password_changed_at = NOW() WHERE username = $1",
+    )
+    .bind(username)
+    .bind(password_hash)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Deletes a user unless that would leave no admin of every site. Returns whether a row was
+/// deleted.
+pub async fn delete_user_by_id(
+    pool: &Pool<Postgres>,
+    user_id: Uuid,
+    site: Option<Uuid>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "DELETE FROM users WHERE id = $1 AND ($2::uuid IS NULL OR site_id = $2)
+           AND (role <> 'admin' OR site_id IS NOT NULL
+                OR (SELECT COUNT(*) FROM users WHERE role = 'admin' AND site_id IS NULL) > 1)",
+    )
+    .bind(user_id)
+    .bind(site)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// A one-time link a new user sets up their own account with. Only the link token's hash is
+/// stored, and it is never read back.
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct AdminInvite {
+    pub id: Uuid,
+    pub role: String,
+    pub site_id: Option<Uuid>, // None for an account of every site
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub accepted_by: Option<String>,
+    pub accepted_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub status: String, // open, accepted, revoked or expired
+    // Joined in for the invite list
+    #[sqlx(default)]
+    pub site_name: Option<String>,
+}
+
+const ADMIN_INVITE_COLUMNS: &str =
+    "i.id, i.role, i.site_id, i.created_by, i.created_at, i.expires_at,
+    i.accepted_by, i.accepted_at, i.revoked_at, s.name AS site_name,
+    CASE WHEN i.accepted_at IS NOT NULL THEN 'accepted'
+         WHEN i.revoked_at IS NOT NULL THEN 'revoked'
+         WHEN i.expires_at <= NOW() THEN 'expired'
+         ELSE 'open' END AS status";
+
+pub async fn get_admin_invites(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+) -> Result<Vec<AdminInvite>, sqlx::Error> {
+    sqlx::query_as::<_, AdminInvite>(&format!(
+        "SELECT {} FROM admin_invites i LEFT JOIN sites s ON s.id = i.site_id
+         WHERE ($1::uuid IS NULL OR i.site_id = $1) ORDER BY i.created_at DESC",
+        ADMIN_INVITE_COLUMNS
+    ))
+    .bind(site)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_admin_invite_by_token_hash(
+    pool: &Pool<Postgres>,
+    token_hash: &str,
+) -> Result<Option<AdminInvite>, sqlx::Error> {
+    sqlx::query_as::<_, AdminInvite>(&format!(
+        "SELECT {} FROM admin_invites i LEFT JOIN sites s ON s.id = i.site_id
+         WHERE i.token_hash = $1",
+        ADMIN_INVITE_COLUMNS
+    ))
+    .bind(token_hash)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn insert_admin_invite(
+    pool: &Pool<Postgres>,
+    token_hash: &str,
+    role: &str,
+    site_id: Option<Uuid>,
+    created_by: &str,
+    expires_at: DateTime<Utc>,
+) -> Result<Uuid, sqlx::Error> {
+    sqlx::query_scalar::<_, Uuid>(
+        "INSERT INTO admin_invites (token_hash, role, site_id, created_by, expires_at)
+         VALUES ($1, $2, $3, $4, $5) RETURNING id",
+    )
+    .bind(token_hash)
+    .bind(role)
+    .bind(site_id)
+    .bind(created_by)
+    .bind(expires_at)
+    .fetch_one(pool)
+    .await
+}
+
+/// Revokes an invite that has not been used yet. Returns whether there was one to revoke.
+pub async fn revoke_admin_invite(
+    pool: &Pool<Postgres>,
+    invite_id: Uuid,
+    site: Option<Uuid>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE admin_invites SET revoked_at = NOW()
+         WHERE id = $1 AND ($2::uuid IS NULL OR site_id = $2)
+           AND accepted_at IS NULL AND revoked_at IS NULL",
+    )
+    .bind(invite_id)
+    .bind(site)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Uses up an open invite and creates the account it is for, in one transaction. `None` when
+/// the invite was used, revoked or ran out in the meantime; a taken username is an error and
+/// leaves the invite open.
+pub async fn accept_admin_invite(
+    pool: &Pool<Postgres>,
+    token_hash: &str,
+    username: &str,
+    password_hash: &str,
+    totp_secret: Option<&str>,
+    totp_step: Option<i64>,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let invite = sqlx::query_as::<_, (String, Option<Uuid>)>(
+        "UPDATE admin_invites SET accepted_at = NOW(), accepted_by = $2
+         WHERE token_hash = $1 AND accepted_at IS NULL AND revoked_at IS NULL
+           AND expires_at > NOW()
+         RETURNING role, site_id",
+    )
+    .bind(token_hash)
+    .bind(username)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some((role, site_id)) = invite else {
+        return Ok(None);
+    };
+
+    let user_id = sqlx::query_scalar::<_, Uuid>(
+        "INSERT INTO users (username, password_hash, role, site_id, totp_secret, totp_last_step)
+         VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+    )
+    .bind(username)
+    .bind(password_hash)
+    .bind(role)
+    .bind(site_id)
+    .bind(totp_secret)
+    .bind(totp_step)
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(Some(user_id))
+}
+
+/// Records `step` as the time step of the user's last authentication code. Returns false when
+/// a code of that step or a later one was already used, so every code signs in only once.
+pub async fn use_totp_step(
+    pool: &Pool<Postgres>,
+    user_id: Uuid,
+    step: i64,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE users SET totp_last_step = $2
+         WHERE id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)",
+    )
+    .bind(user_id)
+    .bind(step)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// A refresh session, without its token hash; revoking one deletes its row.
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct Session {
+    pub id: Uuid,
+    pub username: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub site_id: Option<Uuid>, // the site picked, for users not tied to one
+}
+
+pub async fn get_active_sessions(pool: &Pool<Postgres>) -> Result<Vec<Session>, sqlx::Error> {
+    sqlx::query_as::<_, Session>(
+        "SELECT id, username, user_agent, ip, created_at, last_used_at, expires_at, site_id FROM sessions WHERE expires_at > NOW() ORDER BY last_used_at DESC",
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn insert_session(
+    pool: &Pool<Postgres>,
+    username: &str,
+    refresh_token_hash: &str,
+    user_agent: Option<&str>,
+    ip: Option<&str>,
+    expires_at: DateTime<Utc>,
+    site_id: Option<Uuid>,
+) -> Result<Uuid, sqlx::Error> {
+    sqlx::query_scalar::<_, Uuid>(
+        "INSERT INTO sessions (username, refresh_token_hash, user_agent, ip, expires_at, site_id) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+    )
+    .bind(username)
+    .bind(refresh_token_hash)
+    .bind(user_agent)
+    .bind(ip)
+    .bind(expires_at)
+    .bind(site_id)
+    .fetch_one(pool)
+    .await
+}
+
+/// Switches a session to another site, or to every site with `None`.
+pub async fn set_session_site(
+    pool: &Pool<Postgres>,
+    session_id: Uuid,
+    site_id: Option<Uuid>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE sessions SET site_id = $2 WHERE id = $1")
+        .bind(session_id)
+        .bind(site_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Slides an unexpired session's expiry forward. Returns `None` for unknown, revoked or
+/// expired refresh tokens.
+pub async fn touch_session(
+    pool: &Pool<Postgres>,
+    refresh_token_hash: &str,
+    expires_at: DateTime<Utc>,
+) -> Result<Option<Session>, sqlx::Error> {
+    sqlx::query_as::<_, Session>(
+        "UPDATE sessions SET last_used_at = NOW(), expires_at = $2 WHERE refresh_token_hash = $1 AND expires_at > NOW() RETURNING id, username, user_agent, ip, created_at, last_used_at, expires_at, site_id",
+    )
+    .bind(refresh_token_hash)
+    .bind(expires_at)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn delete_session_by_id(
+    pool: &Pool<Postgres>,
+    session_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM sessions WHERE id = $1")
+        .bind(session_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Revokes every session of `username` but `keep`. Returns how many were revoked.
+pub async fn delete_other_sessions(
+    pool: &Pool<Postgres>,
+    username: &str,
+    keep: Option<Uuid>,
+) -> Result<u64, sqlx::Error> {
+    let result =
+        sqlx::query("DELETE FROM sessions WHERE username = $1 AND ($2::uuid IS NULL OR id <> $2)")
+            .bind(username)
+            .bind(keep)
+            .execute(pool)
+            .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn delete_session_by_token(
+    pool: &Pool<Postgres>,
+    refresh_token_hash: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM sessions WHERE refresh_token_hash = $1")
+        .bind(refresh_token_hash)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn delete_expired_sessions(pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM sessions WHERE expires_at <= NOW()")
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct Webhook {
+    pub id: Uuid,
+    pub url: String,
+    pub secret: String,
+    pub enabled: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A delivery attempt joined with the URL it went to, for the admin UI.
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct WebhookDelivery {
+    pub id: Uuid,
+    pub url: String,
+    pub event: String,
+    pub status: String,
+    pub attempts: i32,
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+pub async fn get_all_webhooks(pool: &Pool<Postgres>) -> Result<Vec<Webhook>, sqlx::Error> {
+    sqlx::query_as::<_, Webhook>("SELECT * FROM webhooks ORDER BY created_at")
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn get_enabled_webhooks(pool: &Pool<Postgres>) -> Result<Vec<Webhook>, sqlx::Error> {
+    sqlx::query_as::<_, Webhook>("SELECT * FROM webhooks WHERE enabled = true")
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn insert_webhook(
+    pool: &Pool<Postgres>,
+    url: &str,
+    secret: &str,
+) -> Result<Uuid, sqlx::Error> {
+    sqlx::query_scalar::<_, Uuid>("INSERT INTO webhooks (url, secret) VALUES ($1, $2) RETURNING id")
+        .bind(url)
+        .bind(secret)
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn toggle_webhook_status(
+    pool: &Pool<Postgres>,
+    webhook_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE webhooks SET enabled = NOT enabled WHERE id = $1")
+        .bind(webhook_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn delete_webhook_by_id(
+    pool: &Pool<Postgres>,
+    webhook_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM webhooks WHERE id = $1")
+        .bind(webhook_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn insert_webhook_delivery(
+    pool: &Pool<Postgres>,
+    webhook_id: Uuid,
+    event: &str,
+    payload: &str,
+) -> Result<Uuid, sqlx::Error> {
+    sqlx::query_scalar::<_, Uuid>(
+        "INSERT INTO webhook_deliveries (webhook_id, event, payload) VALUES ($1, $2, $3) RETURNING id",
+    )
+    .bind(webhook_id)
+    .bind(event)
+    .bind(payload)
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn update_webhook_delivery(
+    pool: &Pool<Postgres>,
+    delivery_id: Uuid,
+    status: &str,
+    attempts: i32,
+    response_status: Option<i32>,
+    last_error: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE webhook_deliveries SET status = $2, attempts = $3, response_status = $4, last_error = $5, updated_at = NOW() WHERE id = $1",
+    )
+    .bind(delivery_id)
+    .bind(status)
+    .bind(attempts)
+    .bind(response_status)
+    .bind(last_error)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_recent_webhook_deliveries(
+    pool: &Pool<Postgres>,
+    limit: i64,
+) -> Result<Vec<WebhookDelivery>, sqlx::Error> {
+    sqlx::query_as::<_, WebhookDelivery>(
+        "SELECT d.id, w.url, d.event, d.status, d.attempts, d.response_status, d.last_error, d.created_at, d.updated_at
+         FROM webhook_deliveries d JOIN webhooks w ON w.id = d.webhook_id
+         ORDER BY d.created_at DESC LIMIT $1",
+    )
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+// A pass stays open while it has uses left and has not expired
+const GUEST_PASS_OPEN: &str =
+    "(expires_at IS NULL OR expires_at > NOW()) AND (remaining_uses IS NULL OR remaining_uses > 0)";
+// and may be used once its window has started
+const GUEST_PASS_STARTED: &str = "(valid_from IS NULL OR valid_from <= NOW())";
+
+#[derive(Clone, sqlx::FromRow, serde::Serialize, serde::Deserialize)]
+pub struct GuestPass {
+    pub id: Uuid,
+    pub label: String,
+    pub npub: Option<String>,
+    pub remaining_uses: Option<i32>,
+    pub uses: i32,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub handshake_url: Option<String>,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub host: Option<String>,  // who a pre-registered visitor is visiting
+    pub email: Option<String>, // where the visitor's invitation was sent
+    pub valid_from: Option<DateTime<Utc>>,
+    #[sqlx(default)]
+    pub usable: bool,
+    #[sqlx(default)]
+    pub upcoming: bool, // open, but its window has not started yet
+}
+
+/// What a new guest pass allows, and for pre-registered visitors who they visit.
+pub struct NewGuestPass<'a> {
+    pub label: &'a str,
+    pub npub: Option<&'a str>,
+    pub max_uses: Option<i32>,
+    pub valid_from: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub host: Option<&'a str>,
+    pub email: Option<&'a str>,
+}
+
+pub async fn get_all_guest_passes(pool: &Pool<Postgres>) -> Result<Vec<GuestPass>, sqlx::Error> {
+    sqlx::query_as::<_, GuestPass>(&format!(
+        "SELECT *, ({0}) AND {1} AS usable, ({0}) AND NOT {1} AS upcoming FROM guest_passes ORDER BY created_at DESC",
+        GUEST_PASS_OPEN, GUEST_PASS_STARTED
+    ))
+    .fetch_all(pool)
+    .await
+}
+
+/// Passes with their own handshake URL that can still be used, now or once their window
+/// starts, so their listeners can be reopened after a restart.
+pub async fn get_open_guest_passes(pool: &Pool<Postgres>) -> Result<Vec<GuestPass>, sqlx::Error> {
+    sqlx::query_as::<_, GuestPass>(&format!(
+        "SELECT * FROM guest_passes WHERE handshake_url IS NOT NULL AND {}",
+        GUEST_PASS_OPEN
+    ))
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn insert_guest_pass(
+    pool: &Pool<Postgres>,
+    pass: &NewGuestPass<'_>,
+    created_by: &str,
+) -> Result<GuestPass, sqlx::Error> {
+    sqlx::query_as::<_, GuestPass>(
+        "INSERT INTO guest_passes (label, npub, remaining_uses, valid_from, expires_at, host, email, created_by)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING *",
+    )
+    .bind(pass.label)
+    .bind(pass.npub)
+    .bind(pass.max_uses)
+    .bind(pass.valid_from)
+    .bind(pass.expires_at)
+    .bind(pass.host)
+    .bind(pass.email)
+    .bind(created_by)
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn set_guest_pass_handshake_url(
+    pool: &Pool<Postgres>,
+    pass_id: Uuid,
+    handshake_url: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE guest_passes SET handshake_url = $2 WHERE id = $1")
+        .bind(pass_id)
+        .bind(handshake_url)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn delete_guest_pass_by_id(
+    pool: &Pool<Postgres>,
+    pass_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM guest_passes WHERE id = $1")
+        .bind(pass_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Binds an unclaimed pass to the first npub that completes its handshake; returns false once
+/// the pass already belongs to someone.
+pub async fn claim_guest_pass(
+    pool: &Pool<Postgres>,
+    pass_id: Uuid,
+    npub: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE guest_passes SET npub = $2 WHERE id = $1 AND npub IS NULL")
+        .bind(pass_id)
+        .bind(npub)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn get_usable_guest_pass(
+    pool: &Pool<Postgres>,
+    npub: &str,
+) -> Result<Option<GuestPass>, sqlx::Error> {
+    sqlx::query_as::<_, GuestPass>(&format!(
+        "SELECT * FROM guest_passes WHERE npub = $1 AND {} AND {} ORDER BY expires_at NULLS LAST LIMIT 1",
+        GUEST_PASS_OPEN, GUEST_PASS_STARTED
+    ))
+    .bind(npub)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Uses up one entry on a pass. The check and the decrement happen in a single UPDATE, so two
+/// concurrent handshakes cannot both take the last use; returns false when the pass was no
+/// longer usable.
+pub async fn consume_guest_pass(pool: &Pool<Postgres>, pass_id: Uuid) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(&format!(
+        "UPDATE guest_passes SET remaining_uses = remaining_uses - 1, uses = uses + 1, last_used_at = NOW() WHERE id = $1 AND {} AND {}",
+        GUEST_PASS_OPEN, GUEST_PASS_STARTED
+    ))
+    .bind(pass_id)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+// Joins what the admins are shown about a ring onto the `ring` CTE: the visitor's key, when it
+// has one, and the door
+const DOORBELL_RING_DETAILS: &str =
+    "SELECT ring.*, k.profile_name, k.display_name, k.picture_url, d.name AS door_name, d.site_id
+     FROM ring
+     LEFT JOIN keys k ON k.npub = ring.npub AND k.deleted_at IS NULL
+     LEFT JOIN doors d ON d.controller_door_id = ring.door_id";
+
+/// A visitor waiting at a door for an admin to let them in.
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct DoorbellRing {
+    pub id: Uuid,
+    pub npub: String,
+    pub door_id: i32,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub profile_name: Option<String>,
+    pub display_name: Option<String>,
+    pub picture_url: Option<String>,
+    pub door_name: Option<String>,
+    pub site_id: Option<Uuid>, // the door's site
+}
+
+/// Adds a ring that can be answered for `answer_secs`, clearing out the ones nobody answered.
+pub async fn insert_doorbell_ring(
+    pool: &Pool<Postgres>,
+    npub: &str,
+    door_id: i32,
+    answer_secs: u64,
+) -> Result<DoorbellRing, sqlx::Error> {
+    sqlx::query("DELETE FROM doorbell_rings WHERE expires_at <= NOW()")
+        .execute(pool)
+        .await?;
+
+    sqlx::query_as::<_, DoorbellRing>(&format!(
+        "WITH ring AS (INSERT INTO doorbell_rings (npub, door_id, expires_at) VALUES ($1, $2, NOW() + make_interval(secs => $3)) RETURNING *) {}",
+        DOORBELL_RING_DETAILS
+    ))
+    .bind(npub)
+    .bind(door_id)
+    .bind(answer_secs as f64)
+    .fetch_one(pool)
+    .await
+}
+
+/// Removes a ring to answer it. Taking it in a single DELETE means only one admin's answer
+/// counts; `None` once it was answered, has expired or is at a door outside `site`.
+pub async fn take_doorbell_ring(
+    pool: &Pool<Postgres>,
+    ring_id: Uuid,
+    site: Option<Uuid>,
+) -> Result<Option<DoorbellRing>, sqlx::Error> {
+    sqlx::query_as::<_, DoorbellRing>(&format!(
+        "WITH ring AS (
+             DELETE FROM doorbell_rings WHERE id = $1 AND expires_at > NOW()
+               AND ($2::uuid IS NULL OR door_id IN (SELECT controller_door_id FROM doors WHERE site_id = $2))
+             RETURNING *
+         ) {}",
+        DOORBELL_RING_DETAILS
+    ))
+    .bind(ring_id)
+    .bind(site)
+    .fetch_optional(pool)
+    .await
+}
+
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct AuditEntry {
+    pub id: Uuid,
+    pub actor: String,
+    pub action: String,
+    pub target: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+pub async fn insert_audit_entry(
+    pool: &Pool<Postgres>,
+    actor: &str,
+    action: &str,
+    target: Option<&str>,
+    ip: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO admin_audit (actor, action, target, ip) VALUES ($1, $2, $3, $4)")
+        .bind(actor)
+        .bind(action)
+        .bind(target)
+        .bind(ip)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn get_audit_entries(
+    pool: &Pool<Postgres>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<AuditEntry>, sqlx::Error> {
+    sqlx::query_as::<_, AuditEntry>(
+        "SELECT * FROM admin_audit ORDER BY created_at DESC LIMIT $1 OFFSET $2",
+    )
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn count_audit_entries(pool: &Pool<Postgres>) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM admin_audit")
+        .fetch_one(pool)
+        .await
+}
+
+/// Failed admin logins from one client IP.
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct LoginFailure {
+    pub ip: String,
+    pub failures: i32,
+    pub last_username: String,
+    pub last_failed_at: DateTime<Utc>,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+pub async fn get_recent_login_failures(
+    pool: &Pool<Postgres>,
+    limit: i64,
+) -> Result<Vec<LoginFailure>, sqlx::Error> {
+    sqlx::query_as::<_, LoginFailure>(
+        "SELECT * FROM login_failures ORDER BY last_failed_at DESC LIMIT $1",
+    )
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+/// Returns when the lockout on `ip` ends, if it is locked out right now.
+pub async fn get_login_lockout(
+    pool: &Pool<Postgres>,
+    ip: &str,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    sqlx::query_scalar::<_, DateTime<Utc>>(
+        "SELECT locked_until FROM login_failures WHERE ip = $1 AND locked_until > NOW()",
+    )
+    .bind(ip)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Counts a failed login from `ip` and returns its consecutive failures so far.
+pub async fn record_login_failure(
+    pool: &Pool<Postgres>,
+    ip: &str,
+    username: &str,
+) -> Result<i32, sqlx::Error> {
+    sqlx::query_scalar::<_, i32>(
+        "INSERT INTO login_failures (ip, failures, last_username, last_failed_at) VALUES ($1, 1, $2, NOW())
+         ON CONFLICT (ip) DO UPDATE SET failures = login_failures.failures + 1, last_username = $2, last_failed_at = NOW()
+         RETURNING failures",
+    )
+    .bind(ip)
+    .bind(username)
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn lock_out_login(
+    pool: &Pool<Postgres>,
+    ip: &str,
+    locked_until: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE login_failures SET locked_until = $1 WHERE ip = $2")
+        .bind(locked_until)
+        .bind(ip)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Resets the failure count and any lockout on `ip`, after a successful login or by an admin.
+pub async fn clear_login_failures(pool: &Pool<Postgres>, ip: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE login_failures SET failures = 0, locked_until = NULL WHERE ip = $1")
+        .bind(ip)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Where one admin is alerted (an email address or a Telegram chat) and about which triggers.
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct AlertChannel {
+    pub id: Uuid,
+    pub username: String,
+    pub kind: String,
+    pub target: String,
+    pub triggers: Vec<String>,
+    pub enabled: bool,
+    pub created_at: DateTime<Utc>,
+    // Joined in for the daily digest: the owner's site, None for admins of every site
+    #[sqlx(default)]
+    #[serde(skip_serializing)]
+    pub site_id: Option<Uuid>,
+}
+
+pub async fn get_user_alert_channels(
+    pool: &Pool<Postgres>,
+    username: &str,
+) -> Result<Vec<AlertChannel>, sqlx::Error> {
+    sqlx::query_as::<_, AlertChannel>(
+        "SELECT * FROM alert_channels WHERE username = $1 ORDER BY created_at",
+    )
+    .bind(username)
+    .fetch_all(pool)
+    .await
+}
+
+/// Enabled channels subscribed to `trigger`.
+pub async fn get_alert_channels_for(
+    pool: &Pool<Postgres>,
+    trigger: &str,
+) -> Result<Vec<AlertChannel>, sqlx::Error> {
+    sqlx::query_as::<_, AlertChannel>(
+        "SELECT * FROM alert_channels WHERE enabled AND $1 = ANY(triggers)",
+    )
+    .bind(trigger)
+    .fetch_all(pool)
+    .await
+}
+
+/// Enabled channels subscribed to `trigger`, with the site their owner is limited to.
+pub async fn get_alert_channels_with_site(
+    pool: &Pool<Postgres>,
+    trigger: &str,
+) -> Result<Vec<AlertChannel>, sqlx::Error> {
+    sqlx::query_as::<_, AlertChannel>(
+        "SELECT c.*, u.site_id FROM alert_channels c
+         LEFT JOIN users u ON u.username = c.username
+         WHERE c.enabled AND $1 = ANY(c.triggers)",
+    )
+    .bind(trigger)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_alert_channel(
+    pool: &Pool<Postgres>,
+    channel_id: Uuid,
+    username: &str,
+) -> Result<Option<AlertChannel>, sqlx::Error> {
+    sqlx::query_as::<_, AlertChannel>(
+        "SELECT * FROM alert_channels WHERE id = $1 AND username = $2",
+    )
+    .bind(channel_id)
+    .bind(username)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn insert_alert_channel(
+    pool: &Pool<Postgres>,
+    username: &str,
+    kind: &str,
+    target: &str,
+    triggers: &[String],
+) -> Result<Uuid, sqlx::Error> {
+    sqlx::query_scalar::<_, Uuid>(
+        "INSERT INTO alert_channels (username, kind, target, triggers) VALUES ($1, $2, $3, $4) RETURNING id",
+    )
+    .bind(username)
+    .bind(kind)
+    .bind(target)
+    .bind(triggers)
+    .fetch_one(pool)
+    .await
+}
+
+/// Deletes one of `username`'s channels. Returns whether a row was deleted.
+pub async fn delete_alert_channel(
+    pool: &Pool<Postgres>,
+    channel_id: Uuid,
+    username: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM alert_channels WHERE id = $1 AND username = $2")
+        .bind(channel_id)
+        .bind(username)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// An unusual access pattern flagged by the anomaly detector, see `anomalies`. `kind` is the
+/// alert trigger it was raised as.
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct Anomaly {
+    pub id: Uuid,
+    pub kind: String,
+    pub npub: String,
+    pub door_id: Option<i32>,
+    pub site_id: Option<Uuid>,
+    pub detail: String,
+    pub created_at: DateTime<Utc>,
+}
+
+pub async fn insert_anomaly(
+    pool: &Pool<Postgres>,
+    kind: &str,
+    npub: &str,
+    door_id: Option<i32>,
+    site_id: Option<Uuid>,
+    detail: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO anomalies (kind, npub, door_id, site_id, detail) VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(kind)
+    .bind(npub)
+    .bind(door_id)
+    .bind(site_id)
+    .bind(detail)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Anomalies flagged over the last `days` days, newest first.
+pub async fn get_recent_anomalies(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+    days: i32,
+    limit: i64,
+) -> Result<Vec<Anomaly>, sqlx::Error> {
+    sqlx::query_as::<_, Anomaly>(
+        "SELECT * FROM anomalies
+         WHERE created_at >= NOW() - $1 * INTERVAL '1 day' AND ($2::uuid IS NULL OR site_id = $2)
+         ORDER BY created_at DESC LIMIT $3",
+    )
+    .bind(days)
+    .bind(site)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+/// A disabled key that kept trying, with the door and site of its latest attempt.
+#[derive(sqlx::FromRow)]
+pub struct DisabledKeyAttempts {
+    pub npub: String,
+    pub door_id: Option<i32>,
+    pub site_id: Option<Uuid>,
+    pub attempts: i64,
+}
+
+/// Disabled keys refused at least `threshold` times within the last `window_secs` seconds.
+/// Keys already flagged as `kind` within the window are left out, so each burst is flagged once.
+pub async fn get_disabled_key_attempts(
+    pool: &Pool<Postgres>,
+    kind: &str,
+    threshold: i64,
+    window_secs: i64,
+) -> Result<Vec<DisabledKeyAttempts>, sqlx::Error> {
+    sqlx::query_as::<_, DisabledKeyAttempts>(
+        "SELECT l.npub,
+                (array_agg(l.door_id ORDER BY l.created_at DESC))[1] AS door_id,
+                (array_agg(l.site_id ORDER BY l.created_at DESC))[1] AS site_id,
+                COUNT(*) AS attempts
+         FROM access_logs l
+         WHERE l.reason_code = 'key_disabled'
+           AND l.created_at >= NOW() - $3 * INTERVAL '1 second'
+           AND NOT EXISTS (
+               SELECT 1 FROM anomalies a
+               WHERE a.npub = l.npub AND a.kind = $1
+                 AND a.created_at >= NOW() - $3 * INTERVAL '1 second'
+           )
+         GROUP BY l.npub
+         HAVING COUNT(*) >= $2",
+    )
+    .bind(kind)
+    .bind(threshold)
+    .bind(window_secs)
+    .fetch_all(pool)
+    .await
+}
+
+/// An unlock at an hour of the day its key had not used before.
+#[derive(sqlx::FromRow)]
+pub struct UnusualHourUnlock {
+    pub npub: String,
+    pub door_id: Option<i32>,
+    pub site_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub hour: i32,
+    pub history: i64,
+}
+
+/// Granted handshakes logged after `since` and up to `until` whose hour of the day, in
+/// `timezone`, none of the key's unlocks in the `history_days` days before fell in. Keys with
+/// fewer than `min_history` unlocks in that time have no habits to go by and are left out.
+pub async fn get_unusual_hour_unlocks(
+    pool: &Pool<Postgres>,
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+    history_days: i64,
+    min_history: i64,
+    timezone: &str,
+) -> Result<Vec<UnusualHourUnlock>, sqlx::Error> {
+    sqlx::query_as::<_, UnusualHourUnlock>(
+        "SELECT l.npub, l.door_id, l.site_id, l.created_at,
+                EXTRACT(HOUR FROM l.created_at AT TIME ZONE $5)::INT AS hour,
+                history.unlocks AS history
+         FROM access_logs l
+         CROSS JOIN LATERAL (
+             SELECT COUNT(*) AS unlocks,
+                    COUNT(*) FILTER (
+                        WHERE EXTRACT(HOUR FROM h.created_at AT TIME ZONE $5)
+                            = EXTRACT(HOUR FROM l.created_at AT TIME ZONE $5)
+                    ) AS same_hour
+             FROM access_logs h
+             WHERE h.npub = l.npub AND h.result = 'granted'
+               AND h.created_at < l.created_at
+               AND h.created_at >= l.created_at - $3 * INTERVAL '1 day'
+         ) history
+         WHERE l.result = 'granted' AND l.npub IS NOT NULL
+           AND l.created_at > $1 AND l.created_at <= $2
+           AND history.unlocks >= $4 AND history.same_hour = 0
+         ORDER BY l.created_at",
+    )
+    .bind(since)
+    .bind(until)
+    .bind(history_days)
+    .bind(min_history)
+    .bind(timezone)
+    .fetch_all(pool)
+    .await
+}
+
+/// A long-lived API token. Only the token's hash is stored, and it is never read back.
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct ApiTokenRecord {
+    pub id: Uuid,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub site_id: Option<Uuid>, // None for tokens acting on every site
+    // Joined in for the token list
+    #[sqlx(default)]
+    pub site_name: Option<String>,
+}
+
+pub async fn get_all_api_tokens(pool: &Pool<Postgres>) -> Result<Vec<ApiTokenRecord>, sqlx::Error> {
+    sqlx::query_as::<_, ApiTokenRecord>(
+        "SELECT t.id, t.name, t.scopes, t.created_by, t.created_at, t.last_used_at, t.site_id, s.name AS site_name
+         FROM api_tokens t LEFT JOIN sites s ON s.id = t.site_id ORDER BY t.created_at DESC",
+    )
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn insert_api_token(
+    pool: &Pool<Postgres>,
+    name: &str,
+    token_hash: &str,
+    scopes: &[String],
+    created_by: &str,
+    site_id: Option<Uuid>,
+) -> Result<Uuid, sqlx::Error> {
+    sqlx::query_scalar::<_, Uuid>(
+        "INSERT INTO api_tokens (name, token_hash, scopes, created_by, site_id) VALUES ($1, $2, $3, $4, $5) RETURNING id",
+    )
+    .bind(name)
+    .bind(token_hash)
+    .bind(scopes)
+    .bind(created_by)
+    .bind(site_id)
+    .fetch_one(pool)
+    .await
+}
+
+/// Looks a token up by hash and marks it used. `None` for an unknown or revoked token.
+pub async fn touch_api_token(
+    pool: &Pool<Postgres>,
+    token_hash: &str,
+) -> Result<Option<ApiTokenRecord>, sqlx::Error> {
+    sqlx::query_as::<_, ApiTokenRecord>(
+        "UPDATE api_tokens SET last_used_at = NOW() WHERE token_hash = $1 RETURNING id, name, scopes, created_by, created_at, last_used_at, site_id",
+    )
+    .bind(token_hash)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Revokes a token. Returns whether a row was deleted.
+pub async fn delete_api_token_by_id(
+    pool: &Pool<Postgres>,
+    token_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM api_tokens WHERE id = $1")
+        .bind(token_id)
+        .execute(pool)
         .await?;
 
-    Ok(result.unwrap_or(false))
+    Ok(result.rows_affected() > 0)
 }
@@ -1 +1,7 @@
+pub mod health;
 pub mod helpers;
+
+use sqlx::migrate::Migrator;
+
+/// Schema migrations from ./migrations, embedded at compile time.
+pub static MIGRATOR: Migrator = sqlx::migrate!();
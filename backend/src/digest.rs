@@ -0,0 +1,190 @@
+//! The daily activity digest. Once a day, at `alerts.digest_hour` site time, every alert channel
+//! subscribed to `digest.daily` gets the previous day's unlocks, declined attempts, new keys and
+//! the doors that failed to unlock or did not relock. Channels of admins limited to one site only
+//! get that site's figures. Emails are rendered from `templates/email/daily_digest.html.hbs`,
+//! which is built into the binary.
+
+use crate::alerts::{AlertKind, Alerter, Message};
+use crate::config::AlertsConfig;
+use crate::database::helpers::{
+    DigestCounts, DoorErrors, claim_digest_day, get_alert_channels_with_site, get_digest_counts,
+    get_door_errors, get_site_by_id,
+};
+
+use chrono::{DateTime, Days, NaiveDate, NaiveTime, Timelike, Utc};
+use chrono_tz::Tz;
+use rocket::tokio;
+use rocket_dyn_templates::handlebars::Handlebars;
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::{error, info};
+use uuid::Uuid;
+
+// How often the job checks whether the digest is due
+const CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+const TEMPLATE_NAME: &str = "daily_digest";
+const TEMPLATE: &str = include_str!("../templates/email/daily_digest.html.hbs");
+
+/// One day of activity, for every site or one.
+#[derive(Serialize)]
+pub struct Digest {
+    pub day: NaiveDate,
+    /// The site's name, `None` for every site.
+    pub site: Option<String>,
+    #[serde(flatten)]
+    pub counts: DigestCounts,
+    pub doors: Vec<DoorErrors>,
+}
+
+impl Digest {
+    fn subject(&self) -> String {
+        match &self.site {
+            Some(site) => format!("[Portal Access] Daily digest for {} ({})", self.day, site),
+            None => format!("[Portal Access] Daily digest for {}", self.day),
+        }
+    }
+
+    fn text(&self) -> String {
+        let mut text = format!(
+            "Unlocks: {}\nDeclined or denied attempts: {}\nNew keys: {}\n",
+            self.counts.unlocks, self.counts.declined, self.counts.new_keys
+        );
+        if self.doors.is_empty() {
+            text.push_str("\nNo door errors.\n");
+        } else {
+            text.push_str("\nDoors with errors:\n");
+            for door in &self.doors {
+                text.push_str(&format!(
+                    "- {} (door {}): {} failed unlocks, {} relock failures\n",
+                    door.name, door.door_id, door.unlock_errors, door.relock_failures
+                ));
+            }
+        }
+        text
+    }
+}
+
+/// The digest template, ready to render.
+pub fn template() -> Result<Handlebars<'static>, String> {
+    let mut handlebars = Handlebars::new();
+    handlebars
+        .register_template_string(TEMPLATE_NAME, TEMPLATE)
+        .map_err(|e| format!("Invalid digest template: {}", e))?;
+    Ok(handlebars)
+}
+
+/// Sends the digest in the background, see the module docs. Nothing is sent while no channel
+/// is subscribed to it.
+pub fn spawn_digest(pool: Pool<Postgres>, alerts: Alerter, config: &AlertsConfig, timezone: Tz) {
+    let handlebars = match template() {
+        Ok(handlebars) => handlebars,
+        Err(e) => {
+            error!(error = %e, "Daily digest disabled");
+            return;
+        }
+    };
+
+    let digest_hour = config.digest_hour;
+    tokio::spawn(async move {
+        // The hour is checked several times, but the digest is sent only once a day
+        let mut last_run: Option<NaiveDate> = None;
+        loop {
+            let now = Utc::now().with_timezone(&timezone);
+            if now.hour() == digest_hour && last_run != Some(now.date_naive()) {
+                last_run = Some(now.date_naive());
+                let day = now.date_naive() - Days::new(1);
+                if let Err(e) = send(&pool, &alerts, &handlebars, day, timezone).await {
+                    error!(error = ?e, %day, "Failed to send the daily digest");
+                }
+            }
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    });
+}
+
+async fn send(
+    pool: &Pool<Postgres>,
+    alerts: &Alerter,
+    handlebars: &Handlebars<'static>,
+    day: NaiveDate,
+    timezone: Tz,
+) -> Result<(), sqlx::Error> {
+    let channels = get_alert_channels_with_site(pool, AlertKind::DailyDigest.as_str()).await?;
+    // Another web service sharing the database may have sent it already
+    if channels.is_empty() || !claim_digest_day(pool, day).await? {
+        return Ok(());
+    }
+
+    // One digest per site, shared by the channels of that site
+    let mut sites: Vec<Option<Uuid>> = channels.iter().map(|channel| channel.site_id).collect();
+    sites.sort();
+    sites.dedup();
+    let mut messages = HashMap::new();
+    for site in sites {
+        let digest = collect(pool, site, day, timezone).await?;
+        match render(handlebars, &digest) {
+            Ok(message) => {
+                messages.insert(site, message);
+            }
+            Err(e) => error!(error = %e, "Failed to render the daily digest"),
+        }
+    }
+
+    for channel in &channels {
+        let Some(message) = messages.get(&channel.site_id) else {
+            continue;
+        };
+        if let Err(e) = alerts.send_message(channel, message).await {
+            error!(channel = %channel.id, error = %e, "Failed to send the daily digest");
+        }
+    }
+
+    info!(%day, channels = channels.len(), "Sent the daily digest");
+    Ok(())
+}
+
+/// The activity of `day`, site time, in `site` or every site.
+pub async fn collect(
+    pool: &Pool<Postgres>,
+    site: Option<Uuid>,
+    day: NaiveDate,
+    timezone: Tz,
+) -> Result<Digest, sqlx::Error> {
+    let from = start_of(day, timezone);
+    let until = start_of(day + Days::new(1), timezone);
+    let site_name = match site {
+        Some(site) => get_site_by_id(pool, site).await?.map(|site| site.name),
+        None => None,
+    };
+
+    Ok(Digest {
+        day,
+        site: site_name,
+        counts: get_digest_counts(pool, site, from, until).await?,
+        doors: get_door_errors(pool, site, from, until).await?,
+    })
+}
+
+pub fn render(handlebars: &Handlebars<'static>, digest: &Digest) -> Result<Message, String> {
+    let html = handlebars
+        .render(TEMPLATE_NAME, digest)
+        .map_err(|e| e.to_string())?;
+    Ok(Message {
+        subject: digest.subject(),
+        text: digest.text(),
+        html,
+    })
+}
+
+// Midnight of `day` in the site timezone; when a clock change skips midnight, UTC midnight
+fn start_of(day: NaiveDate, timezone: Tz) -> DateTime<Utc> {
+    let midnight = day.and_time(NaiveTime::MIN);
+    midnight
+        .and_local_timezone(timezone)
+        .earliest()
+        .map(|start| start.with_timezone(&Utc))
+        .unwrap_or_else(|| midnight.and_utc())
+}
@@ -0,0 +1,889 @@
+use crate::config::{
+    Config, DoorBackendKind, GpioConfig, IntelliMConfig, SecondaryController, UnlockRetryConfig,
+};
+
+use access_control::{DoorUnlockClient, UnlockResponse};
+use rocket::tokio::{
+    self,
+    sync::{Mutex, mpsc, oneshot},
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+// How long the IntelliM health probe waits for the controller to answer
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Commands queued per IntelliM worker before callers have to wait to queue theirs
+const WORKER_QUEUE: usize = 32;
+
+// Wait before retrying a failed unlock: doubles from the initial delay up to the cap
+const RETRY_DELAY_INITIAL: Duration = Duration::from_millis(500);
+const RETRY_DELAY_MAX: Duration = Duration::from_secs(4);
+
+/// Last known state of a door lock, as reported by its backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DoorState {
+    Locked,
+    Unlocked,
+    Unknown,
+}
+
+/// Whether a door is physically open, as reported by its door contact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DoorPosition {
+    Open,
+    Closed,
+    Unknown,
+}
+
+/// State of a backend's `CircuitBreaker`, shown on the health check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// Which of a door's controllers carried out an unlock, see `FailoverBackend`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Controller {
+    Primary,
+    Secondary,
+}
+
+/// Hardware the doors are driven through. Errors are human readable and end up in the access log.
+#[rocket::async_trait]
+pub trait DoorBackend: Send + Sync {
+    /// Holds the door unlocked for `seconds`, or the backend's default when `None`.
+    async fn unlock(&self, door_id: u32, seconds: Option<u32>) -> Result<(), String>;
+    /// Like `unlock`, also telling which controller carried it out.
+    async fn unlock_via(&self, door_id: u32, seconds: Option<u32>) -> Result<Controller, String> {
+        self.unlock(door_id, seconds)
+            .await
+            .map(|()| Controller::Primary)
+    }
+    async fn lock(&self, door_id: u32) -> Result<(), String>;
+    async fn status(&self, door_id: u32) -> Result<DoorState, String>;
+    /// `Unknown` for doors without a sensor.
+    async fn position(&self, door_id: u32) -> Result<DoorPosition, String>;
+    /// Whether the hardware can be reached right now, for the readiness check.
+    async fn health(&self) -> Result<(), String>;
+    /// Whether a failed command may succeed if sent again, such as on a network error.
+    fn is_transient(&self, _error: &str) -> bool {
+        false
+    }
+    /// State of the backend's circuit breaker, for backends that have one.
+    fn circuit(&self) -> Option<CircuitState> {
+        None
+    }
+}
+
+/// Resends an unlock that failed on a transient error, up to `unlock_retry.attempts` more
+/// times, for as long as the command is younger than `unlock_retry.ttl_secs`. Past that the
+/// person has likely given up, and opening the door late would let in whoever stands there.
+#[derive(Clone, Copy)]
+pub struct UnlockRetry {
+    attempts: u32,
+    ttl: Duration,
+}
+
+/// Outcome of an unlock, how many retries it took and which controller carried it out.
+pub struct UnlockOutcome {
+    pub result: Result<(), String>,
+    pub retries: u32,
+    pub controller: Controller,
+}
+
+impl UnlockOutcome {
+    /// What the access log says about a successful unlock that took retries or went through
+    /// the secondary controller, so it shows whether the person got in after all.
+    pub fn note(&self) -> Option<String> {
+        let mut notes = Vec::new();
+        if self.retries > 0 {
+            notes.push(format!("unlocked after {} retries", self.retries));
+        }
+        if self.controller == Controller::Secondary {
+            notes.push("unlocked by the secondary controller".to_string());
+        }
+        (self.result.is_ok() && !notes.is_empty()).then(|| notes.join("; "))
+    }
+}
+
+impl UnlockRetry {
+    pub fn new(config: &UnlockRetryConfig) -> Self {
+        Self {
+            attempts: config.attempts,
+            ttl: Duration::from_secs(config.ttl_secs),
+        }
+    }
+
+    pub async fn unlock(
+        &self,
+        backend: &dyn DoorBackend,
+        door_id: u32,
+        seconds: Option<u32>,
+    ) -> UnlockOutcome {
+        let deadline = Instant::now() + self.ttl;
+        let mut delay = RETRY_DELAY_INITIAL;
+        let mut retries = 0;
+
+        loop {
+            // A hanging request must not outlive the command either
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let result = if retries == 0 {
+                backend.unlock_via(door_id, seconds).await
+            } else {
+                tokio::time::timeout(remaining, backend.unlock_via(door_id, seconds))
+                    .await
+                    .unwrap_or_else(|_| Err("door unlock timed out".to_string()))
+            };
+
+            match result {
+                Err(e)
+                    if retries < self.attempts
+                        && backend.is_transient(&e)
+                        && Instant::now() + delay < deadline =>
+                {
+                    warn!(door_id, error = %e, retry = retries + 1, "Door unlock failed, retrying");
+                    tokio::time::sleep(jittered(delay)).await;
+                    delay = (delay * 2).min(RETRY_DELAY_MAX);
+                    retries += 1;
+                }
+                Err(e) if retries > 0 => {
+                    return UnlockOutcome {
+                        result: Err(format!("{} (gave up after {} retries)", e, retries)),
+                        retries,
+                        controller: Controller::Primary,
+                    };
+                }
+                result => {
+                    return UnlockOutcome {
+                        controller: *result.as_ref().unwrap_or(&Controller::Primary),
+                        result: result.map(|_| ()),
+                        retries,
+                    };
+                }
+            }
+        }
+    }
+}
+
+// Half the delay plus a random share of the other half, so doors that failed together do not
+// all retry at the same moment
+fn jittered(delay: Duration) -> Duration {
+    let half = delay / 2;
+    let random = Uuid::new_v4().as_u64_pair().1;
+    half + Duration::from_millis(random % (half.as_millis() as u64 + 1))
+}
+
+/// Stops sending commands to a controller that keeps failing to answer. After `threshold`
+/// network failures or timeouts in a row the circuit opens and commands fail straight away; once
+/// `cooldown` has passed a single command is let through to probe the controller, and the circuit
+/// closes again when it gets an answer. A `threshold` of 0 never opens it.
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: std::sync::Mutex<Breaker>,
+}
+
+enum Breaker {
+    Closed { failures: u32 },
+    Open { since: Instant },
+    HalfOpen { since: Instant },
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: std::sync::Mutex::new(Breaker::Closed { failures: 0 }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Breaker> {
+        self.state.lock().expect("Circuit breaker lock poisoned")
+    }
+
+    /// Whether a command may be sent now. A probe that never reported back, because its caller
+    /// gave up on it, is replaced by another one after `cooldown`.
+    pub fn allow(&self) -> Result<(), String> {
+        let mut state = self.lock();
+        match *state {
+            Breaker::Closed { .. } => Ok(()),
+            Breaker::Open { since } | Breaker::HalfOpen { since }
+                if since.elapsed() >= self.cooldown =>
+            {
+                *state = Breaker::HalfOpen {
+                    since: Instant::now(),
+                };
+                Ok(())
+            }
+            Breaker::Open { since } => Err(format!(
+                "controller circuit open after repeated failures, trying again in {}s",
+                self.cooldown.saturating_sub(since.elapsed()).as_secs() + 1
+            )),
+            Breaker::HalfOpen { .. } => {
+                Err("controller circuit open, waiting on a probe command".to_string())
+            }
+        }
+    }
+
+    /// Records whether a command reached the controller, whatever it answered.
+    pub fn record(&self, reached: bool) {
+        let mut state = self.lock();
+        *state = match (reached, &*state) {
+            (true, Breaker::Closed { .. }) => Breaker::Closed { failures: 0 },
+            (true, _) => {
+                info!("Controller answering again, circuit closed");
+                Breaker::Closed { failures: 0 }
+            }
+            (false, _) if self.threshold == 0 => Breaker::Closed { failures: 0 },
+            (false, Breaker::Closed { failures }) if failures + 1 < self.threshold => {
+                Breaker::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (false, _) => {
+                warn!(
+                    cooldown_secs = self.cooldown.as_secs(),
+                    "Controller not answering, circuit opened"
+                );
+                Breaker::Open {
+                    since: Instant::now(),
+                }
+            }
+        };
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match *self.lock() {
+            Breaker::Closed { .. } => CircuitState::Closed,
+            Breaker::Open { .. } => CircuitState::Open,
+            Breaker::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+}
+
+/// Builds the backend selected by `door.backend` (`intellim`, `gpio` or `mock`; default
+/// `intellim`), behind a `FailoverBackend` when doors have secondary controllers in `[failover]`.
+pub fn from_config(config: &Config) -> Result<Arc<dyn DoorBackend>, String> {
+    info!(backend = ?config.door.backend, "Door backend selected");
+
+    let primary: Arc<dyn DoorBackend> = match config.door.backend {
+        DoorBackendKind::IntelliM => Arc::new(IntelliMBackend::new(&config.intellim)),
+        DoorBackendKind::Gpio => Arc::new(GpioRelayBackend::from_config(&config.gpio)?),
+        DoorBackendKind::Mock => Arc::new(MockDoorBackend::default()),
+    };
+
+    let doors = config.failover.secondaries()?;
+    if doors.is_empty() {
+        return Ok(primary);
+    }
+
+    // Doors sharing a secondary controller share its backend
+    let mut intellim: HashMap<String, Arc<dyn DoorBackend>> = HashMap::new();
+    let mut gpio: Option<Arc<dyn DoorBackend>> = None;
+    let mut secondaries = HashMap::new();
+    for (door_id, secondary) in doors {
+        let backend = match secondary {
+            SecondaryController::IntelliM(base_url) => intellim
+                .entry(base_url.clone())
+                .or_insert_with(|| {
+                    Arc::new(IntelliMBackend::new(&IntelliMConfig {
+                        base_url,
+                        ..config.intellim.clone()
+                    }))
+                })
+                .clone(),
+            SecondaryController::Gpio => match &gpio {
+                Some(backend) => backend.clone(),
+                None => gpio
+                    .insert(Arc::new(GpioRelayBackend::from_config(&config.gpio)?))
+                    .clone(),
+            },
+        };
+        secondaries.insert(door_id, backend);
+    }
+
+    let doors: Vec<u32> = secondaries.keys().copied().collect();
+    info!(?doors, "Secondary door controllers configured");
+    Ok(Arc::new(FailoverBackend::new(primary, secondaries)))
+}
+
+/// Sends a door's unlocks to its secondary controller when its own controller fails them, for
+/// the doors given one in `[failover]`. Doors sharing a secondary IntelliM controller share its
+/// workers and circuit breaker, like the doors of the primary one.
+///
+/// Locks go to both controllers, since either may be holding the door open, and succeed when
+/// one of them does. Status and position are read from the secondary when the primary cannot
+/// answer; health and the circuit are the primary's.
+pub struct FailoverBackend {
+    primary: Arc<dyn DoorBackend>,
+    secondaries: HashMap<u32, Arc<dyn DoorBackend>>,
+}
+
+impl FailoverBackend {
+    /// `secondaries` maps controller door numbers to their secondary controller.
+    pub fn new(
+        primary: Arc<dyn DoorBackend>,
+        secondaries: HashMap<u32, Arc<dyn DoorBackend>>,
+    ) -> Self {
+        Self {
+            primary,
+            secondaries,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl DoorBackend for FailoverBackend {
+    async fn unlock(&self, door_id: u32, seconds: Option<u32>) -> Result<(), String> {
+        self.unlock_via(door_id, seconds).await.map(|_| ())
+    }
+
+    async fn unlock_via(&self, door_id: u32, seconds: Option<u32>) -> Result<Controller, String> {
+        let error = match self.primary.unlock(door_id, seconds).await {
+            Ok(()) => return Ok(Controller::Primary),
+            Err(e) => e,
+        };
+        let Some(secondary) = self.secondaries.get(&door_id) else {
+            return Err(error);
+        };
+
+        warn!(door_id, %error, "Door unlock failed, trying the secondary controller");
+        match secondary.unlock(door_id, seconds).await {
+            Ok(()) => {
+                info!(door_id, "Door unlocked by the secondary controller");
+                Ok(Controller::Secondary)
+            }
+            Err(e) => Err(format!("{}; secondary controller: {}", error, e)),
+        }
+    }
+
+    async fn lock(&self, door_id: u32) -> Result<(), String> {
+        let Some(secondary) = self.secondaries.get(&door_id) else {
+            return self.primary.lock(door_id).await;
+        };
+
+        match tokio::join!(self.primary.lock(door_id), secondary.lock(door_id)) {
+            (Err(primary), Err(secondary)) => {
+                Err(format!("{}; secondary controller: {}", primary, secondary))
+            }
+            (Err(e), Ok(())) | (Ok(()), Err(e)) => {
+                warn!(door_id, error = %e, "Door locked by only one of its controllers");
+                Ok(())
+            }
+            (Ok(()), Ok(())) => Ok(()),
+        }
+    }
+
+    async fn status(&self, door_id: u32) -> Result<DoorState, String> {
+        match (
+            self.primary.status(door_id).await,
+            self.secondaries.get(&door_id),
+        ) {
+            (Err(_), Some(secondary)) => secondary.status(door_id).await,
+            (result, _) => result,
+        }
+    }
+
+    async fn position(&self, door_id: u32) -> Result<DoorPosition, String> {
+        match (
+            self.primary.position(door_id).await,
+            self.secondaries.get(&door_id),
+        ) {
+            (Err(_), Some(secondary)) => secondary.position(door_id).await,
+            (result, _) => result,
+        }
+    }
+
+    async fn health(&self) -> Result<(), String> {
+        self.primary.health().await
+    }
+
+    fn is_transient(&self, error: &str) -> bool {
+        self.primary.is_transient(error)
+    }
+
+    fn circuit(&self) -> Option<CircuitState> {
+        self.primary.circuit()
+    }
+}
+
+/// IntelliM access controller, reached over its HTTP API.
+///
+/// Commands are handed to `intellim.workers` worker tasks, each with a client of its own that
+/// lives for the whole process, so its session and HTTP connections are reused between
+/// commands. A door's commands always go to the same worker and run in order, while other
+/// doors are served by the other workers at the same time. When the controller rejects a
+/// worker's session (expired or revoked), its client is replaced with a fresh one that has to log
+/// in again, and the command is retried once.
+///
+/// Commands give up after `intellim.timeout_secs`, time spent queued included, so a hanging
+/// controller cannot hold up the handshakes behind it for longer, and go through a
+/// `CircuitBreaker` that fails them straight away while the controller is not answering.
+pub struct IntelliMBackend {
+    config: IntelliMConfig,
+    workers: Vec<mpsc::Sender<Job>>,
+    timeout: Duration,
+    breaker: CircuitBreaker,
+    probe: reqwest::Client,
+}
+
+// A command and where its outcome goes
+type Job = (Command, oneshot::Sender<Result<(), String>>);
+
+impl IntelliMBackend {
+    pub fn new(config: &IntelliMConfig) -> Self {
+        info!(
+            base_url = %config.base_url,
+            username = %config.username,
+            workers = config.workers,
+            "Connecting to the IntelliM controller"
+        );
+
+        let timeout = Duration::from_secs(config.timeout_secs);
+        let workers = (0..config.workers.max(1))
+            .map(|_| {
+                let (jobs, queue) = mpsc::channel(WORKER_QUEUE);
+                tokio::spawn(Self::work(config.clone(), timeout, queue));
+                jobs
+            })
+            .collect();
+
+        Self {
+            config: config.clone(),
+            workers,
+            timeout,
+            breaker: CircuitBreaker::new(
+                config.breaker_failures,
+                Duration::from_secs(config.breaker_cooldown_secs),
+            ),
+            probe: reqwest::Client::builder()
+                .timeout(HEALTH_TIMEOUT)
+                .build()
+                .expect("Failed to build HTTP client"),
+        }
+    }
+
+    fn login(config: &IntelliMConfig) -> DoorUnlockClient {
+        DoorUnlockClient::new(
+            config.base_url.clone(),
+            config.username.clone(),
+            config.password.clone(),
+        )
+    }
+
+    // Runs the commands of one worker, one at a time
+    async fn work(config: IntelliMConfig, timeout: Duration, mut queue: mpsc::Receiver<Job>) {
+        let mut client = Self::login(&config);
+        while let Some((command, reply)) = queue.recv().await {
+            // The caller gave up while the command was queued; opening the door now would let
+            // in whoever stands there
+            if reply.is_closed() {
+                continue;
+            }
+
+            let result = tokio::time::timeout(timeout, async {
+                match send(&mut client, command).await {
+                    Err(e) if is_session_rejected(&e) => {
+                        warn!("IntelliM session rejected, logging in again");
+                        client = Self::login(&config);
+                        send(&mut client, command).await
+                    }
+                    result => result,
+                }
+            })
+            .await
+            .unwrap_or_else(|_| Err("IntelliM request timed out".to_string()));
+            let _ = reply.send(result);
+        }
+    }
+
+    async fn run(&self, command: Command) -> Result<(), String> {
+        self.breaker.allow()?;
+
+        let worker = &self.workers[command.door_id() as usize % self.workers.len()];
+        let (reply, outcome) = oneshot::channel();
+        let result = tokio::time::timeout(self.timeout, async {
+            worker
+                .send((command, reply))
+                .await
+                .map_err(|_| "IntelliM worker stopped".to_string())?;
+            outcome
+                .await
+                .map_err(|_| "IntelliM worker stopped".to_string())?
+        })
+        .await
+        .unwrap_or_else(|_| Err("IntelliM request timed out".to_string()));
+
+        self.breaker
+            .record(!matches!(&result, Err(e) if is_network_error(e)));
+        result
+    }
+}
+
+// One request to the controller
+#[derive(Clone, Copy)]
+enum Command {
+    Unlock { door_id: u32, seconds: Option<u32> },
+    Lock { door_id: u32 },
+}
+
+impl Command {
+    fn door_id(&self) -> u32 {
+        match *self {
+            Command::Unlock { door_id, .. } | Command::Lock { door_id } => door_id,
+        }
+    }
+}
+
+async fn send(client: &mut DoorUnlockClient, command: Command) -> Result<(), String> {
+    match command {
+        Command::Unlock { door_id, seconds } => try_unlock(client, door_id, seconds).await,
+        Command::Lock { door_id } => try_lock(client, door_id).await,
+    }
+}
+
+// Helper function to run one unlock request and flatten its outcome into the backend's errors.
+// -1 leaves the unlock time to the controller's own setting for the door.
+async fn try_unlock(
+    client: &mut DoorUnlockClient,
+    door_id: u32,
+    seconds: Option<u32>,
+) -> Result<(), String> {
+    let duration = seconds.map_or(-1, |seconds| seconds as i32);
+    let response = client
+        .unlock_door(door_id, Some(duration))
+        .await
+        .map_err(|e| format!("{}{}", UNLOCK_ERROR, e))?;
+
+    if response.success {
+        Ok(())
+    } else {
+        Err(format!("door unlock failed: {}", response.message))
+    }
+}
+
+/// Relocking for the IntelliM client, which only has a timed unlock: an unlock of zero seconds
+/// ends the one in progress and leaves the door locked.
+trait LockDoor {
+    async fn lock_door(
+        &mut self,
+        door_id: u32,
+    ) -> Result<UnlockResponse, Box<dyn std::error::Error>>;
+}
+
+impl LockDoor for DoorUnlockClient {
+    async fn lock_door(
+        &mut self,
+        door_id: u32,
+    ) -> Result<UnlockResponse, Box<dyn std::error::Error>> {
+        self.unlock_door(door_id, Some(0)).await
+    }
+}
+
+// Helper function to run one lock request, like `try_unlock`
+async fn try_lock(client: &mut DoorUnlockClient, door_id: u32) -> Result<(), String> {
+    let response = client
+        .lock_door(door_id)
+        .await
+        .map_err(|e| format!("{}{}", LOCK_ERROR, e))?;
+
+    if response.success {
+        Ok(())
+    } else {
+        Err(format!("door lock failed: {}", response.message))
+    }
+}
+
+// Prefixes of the errors the IntelliM client returns, as opposed to the controller's own
+// `door unlock failed: <message>` refusals
+const UNLOCK_ERROR: &str = "door unlock error: ";
+const LOCK_ERROR: &str = "door lock error: ";
+
+// Whether the IntelliM controller turned a request down for an expired or unknown session,
+// which it answers with HTTP 401. The client only hands back error text, so this looks for
+// `401` or `Unauthorized` as a whole word of a request error; a door refusing the unlock in
+// its own message, or `401` inside a longer number, is not a session problem.
+fn is_session_rejected(error: &str) -> bool {
+    let Some(error) = error
+        .strip_prefix(UNLOCK_ERROR)
+        .or_else(|| error.strip_prefix(LOCK_ERROR))
+    else {
+        return false;
+    };
+    error
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .any(|word| word == "401" || word.eq_ignore_ascii_case("unauthorized"))
+}
+
+// The client only hands back error text; these are the connection failures and timeouts of
+// its HTTP requests, as opposed to the controller refusing the unlock
+fn is_network_error(error: &str) -> bool {
+    let error = error.to_lowercase();
+    [
+        "error sending request",
+        "connect",
+        "timed out",
+        "timeout",
+        "dns",
+        "broken pipe",
+    ]
+    .iter()
+    .any(|needle| error.contains(needle))
+}
+
+#[rocket::async_trait]
+impl DoorBackend for IntelliMBackend {
+    async fn unlock(&self, door_id: u32, seconds: Option<u32>) -> Result<(), String> {
+        self.run(Command::Unlock { door_id, seconds }).await
+    }
+
+    async fn lock(&self, door_id: u32) -> Result<(), String> {
+        self.run(Command::Lock { door_id }).await
+    }
+
+    async fn status(&self, _door_id: u32) -> Result<DoorState, String> {
+        Ok(DoorState::Unknown)
+    }
+
+    // The IntelliM client has no door contact status either
+    async fn position(&self, _door_id: u32) -> Result<DoorPosition, String> {
+        Ok(DoorPosition::Unknown)
+    }
+
+    // Any HTTP answer means the controller is up; only connection errors and timeouts count
+    async fn health(&self) -> Result<(), String> {
+        self.probe
+            .get(&self.config.base_url)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("IntelliM controller unreachable: {}", e))
+    }
+
+    fn is_transient(&self, error: &str) -> bool {
+        is_network_error(error)
+    }
+
+    fn circuit(&self) -> Option<CircuitState> {
+        Some(self.breaker.state())
+    }
+}
+
+/// Relay wired to a GPIO pin, driven through the Linux sysfs interface.
+///
+/// `gpio.relay_pins` maps controller door numbers to pins (`1:17,2:27`); the relay is held for
+/// `gpio.pulse_secs` (default 5) on unlock.
+pub struct GpioRelayBackend {
+    pins: HashMap<u32, u32>,
+    sensor_pins: HashMap<u32, u32>,
+    pulse: Duration,
+}
+
+impl GpioRelayBackend {
+    pub fn from_config(config: &GpioConfig) -> Result<Self, String> {
+        Ok(Self {
+            pins: parse_pins("gpio.relay_pins", &config.relay_pins)?,
+            sensor_pins: parse_pins("gpio.sensor_pins", &config.sensor_pins)?,
+            pulse: Duration::from_secs(config.pulse_secs),
+        })
+    }
+
+    // Helper function to export the door's pin as an output and return its sysfs directory
+    async fn pin_dir(&self, door_id: u32) -> Result<String, String> {
+        let pin = self
+            .pins
+            .get(&door_id)
+            .ok_or_else(|| format!("no GPIO pin configured for door {}", door_id))?;
+        let dir = format!("/sys/class/gpio/gpio{}", pin);
+
+        if tokio::fs::metadata(&dir).await.is_err() {
+            tokio::fs::write("/sys/class/gpio/export", pin.to_string())
+                .await
+                .map_err(|e| format!("failed to export GPIO {}: {}", pin, e))?;
+        }
+        tokio::fs::write(format!("{}/direction", dir), "out")
+            .await
+            .map_err(|e| format!("failed to configure GPIO {}: {}", pin, e))?;
+
+        Ok(dir)
+    }
+
+    async fn read_sensor(&self, pin: u32) -> Result<DoorPosition, String> {
+        let dir = format!("/sys/class/gpio/gpio{}", pin);
+        if tokio::fs::metadata(&dir).await.is_err() {
+            tokio::fs::write("/sys/class/gpio/export", pin.to_string())
+                .await
+                .map_err(|e| format!("failed to export GPIO {}: {}", pin, e))?;
+            tokio::fs::write(format!("{}/direction", dir), "in")
+                .await
+                .map_err(|e| format!("failed to configure GPIO {}: {}", pin, e))?;
+        }
+
+        let value = tokio::fs::read_to_string(format!("{}/value", dir))
+            .await
+            .map_err(|e| format!("failed to read sensor GPIO {}: {}", pin, e))?;
+        Ok(match value.trim() {
+            "1" => DoorPosition::Open,
+            "0" => DoorPosition::Closed,
+            _ => DoorPosition::Unknown,
+        })
+    }
+
+    async fn write_relay(&self, door_id: u32, energized: bool) -> Result<(), String> {
+        let dir = self.pin_dir(door_id).await?;
+        tokio::fs::write(format!("{}/value", dir), if energized { "1" } else { "0" })
+            .await
+            .map_err(|e| format!("failed to switch relay for door {}: {}", door_id, e))
+    }
+}
+
+#[rocket::async_trait]
+impl DoorBackend for GpioRelayBackend {
+    async fn unlock(&self, door_id: u32, seconds: Option<u32>) -> Result<(), String> {
+        self.write_relay(door_id, true).await?;
+
+        // Drop the relay again once the pulse is over
+        let dir = self.pin_dir(door_id).await?;
+        let pulse = seconds
+            .map(|seconds| Duration::from_secs(seconds.into()))
+            .unwrap_or(self.pulse);
+        tokio::spawn(async move {
+            tokio::time::sleep(pulse).await;
+            if let Err(e) = tokio::fs::write(format!("{}/value", dir), "0").await {
+                error!(door_id, error = %e, "Failed to relock door");
+            }
+        });
+
+        Ok(())
+    }
+
+    async fn lock(&self, door_id: u32) -> Result<(), String> {
+        self.write_relay(door_id, false).await
+    }
+
+    async fn status(&self, door_id: u32) -> Result<DoorState, String> {
+        let dir = self.pin_dir(door_id).await?;
+        let value = tokio::fs::read_to_string(format!("{}/value", dir))
+            .await
+            .map_err(|e| format!("failed to read relay for door {}: {}", door_id, e))?;
+
+        Ok(match value.trim() {
+            "1" => DoorState::Unlocked,
+            "0" => DoorState::Locked,
+            _ => DoorState::Unknown,
+        })
+    }
+
+    async fn position(&self, door_id: u32) -> Result<DoorPosition, String> {
+        match self.sensor_pins.get(&door_id) {
+            Some(pin) => self.read_sensor(*pin).await,
+            None => Ok(DoorPosition::Unknown),
+        }
+    }
+
+    async fn health(&self) -> Result<(), String> {
+        tokio::fs::metadata("/sys/class/gpio/export")
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("GPIO sysfs interface unavailable: {}", e))
+    }
+}
+
+// Parses a `door:pin` list such as `1:17,2:27`
+fn parse_pins(setting: &str, value: &str) -> Result<HashMap<u32, u32>, String> {
+    let mut pins = HashMap::new();
+    for entry in value.split(',').filter(|entry| !entry.trim().is_empty()) {
+        let (door, pin) = entry
+            .trim()
+            .split_once(':')
+            .and_then(|(door, pin)| Some((door.parse().ok()?, pin.parse().ok()?)))
+            .ok_or_else(|| format!("{} entry '{}' must be door:pin", setting, entry))?;
+        pins.insert(door, pin);
+    }
+
+    Ok(pins)
+}
+
+/// In-memory backend for development and testing; every door starts locked.
+#[derive(Default)]
+pub struct MockDoorBackend {
+    states: Mutex<HashMap<u32, DoorState>>,
+    // Error every unlock fails with while set
+    unlock_error: Mutex<Option<String>>,
+}
+
+#[cfg(feature = "testing")]
+#[allow(dead_code)]
+impl MockDoorBackend {
+    /// Makes every unlock fail with `error` until it is set back to `None`.
+    pub async fn fail_unlocks(&self, error: Option<&str>) {
+        *self.unlock_error.lock().await = error.map(str::to_string);
+    }
+}
+
+#[rocket::async_trait]
+impl DoorBackend for MockDoorBackend {
+    async fn unlock(&self, door_id: u32, _seconds: Option<u32>) -> Result<(), String> {
+        if let Some(error) = self.unlock_error.lock().await.clone() {
+            warn!(door_id, %error, "[mock] Door unlock failed");
+            return Err(error);
+        }
+        info!(door_id, "[mock] Door unlocked");
+        self.states
+            .lock()
+            .await
+            .insert(door_id, DoorState::Unlocked);
+        Ok(())
+    }
+
+    async fn lock(&self, door_id: u32) -> Result<(), String> {
+        info!(door_id, "[mock] Door locked");
+        self.states.lock().await.insert(door_id, DoorState::Locked);
+        Ok(())
+    }
+
+    async fn status(&self, door_id: u32) -> Result<DoorState, String> {
+        let states = self.states.lock().await;
+        Ok(states.get(&door_id).copied().unwrap_or(DoorState::Locked))
+    }
+
+    async fn position(&self, _door_id: u32) -> Result<DoorPosition, String> {
+        Ok(DoorPosition::Closed)
+    }
+
+    async fn health(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_a_401_from_the_client_is_a_rejected_session() {
+        assert!(is_session_rejected(
+            "door unlock error: HTTP status client error (401 Unauthorized) for url"
+        ));
+        assert!(is_session_rejected("door lock error: unauthorized"));
+
+        // 401 inside a longer number, or the door refusing the unlock itself
+        assert!(!is_session_rejected(
+            "door unlock error: error sending request for url (http://10.0.0.4:4012/api)"
+        ));
+        assert!(!is_session_rejected(
+            "door unlock failed: card 401 unauthorized"
+        ));
+    }
+}
@@ -0,0 +1,145 @@
+use crate::admin_feed::{AdminEvent, AdminFeed};
+use crate::alerts::{Alert, AlertKind, Alerter};
+use crate::config::SensorsConfig;
+use crate::database::helpers::{get_all_doors, insert_door_event};
+use crate::door_backend::{DoorBackend, DoorPosition};
+use crate::webhooks::{AccessEvent, WebhookDispatcher};
+
+use rocket::tokio;
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+// What the monitor last saw of one door
+struct Tracked {
+    position: DoorPosition,
+    opened_at: Option<Instant>,
+    alerted: bool,
+}
+
+/// Polls the door contacts of every enabled door through the door backend, records open and
+/// close transitions in `door_events`, and raises a `held_open` alert (log, event row,
+/// `door.held_open` webhook and admin alert) once per opening that lasts longer than `sensors.held_open_secs`.
+/// Doors whose backend has no sensor report `Unknown` and are skipped.
+pub struct DoorSensorMonitor {
+    pool: Pool<Postgres>,
+    door: Arc<dyn DoorBackend>,
+    webhooks: WebhookDispatcher,
+    alerts: Alerter,
+    feed: AdminFeed,
+    held_open: Duration,
+    doors: HashMap<Uuid, Tracked>,
+}
+
+impl DoorSensorMonitor {
+    pub fn spawn(
+        pool: Pool<Postgres>,
+        door: Arc<dyn DoorBackend>,
+        webhooks: WebhookDispatcher,
+        alerts: Alerter,
+        feed: AdminFeed,
+        config: &SensorsConfig,
+    ) {
+        if config.poll_secs == 0 {
+            return;
+        }
+
+        let poll = Duration::from_secs(config.poll_secs);
+        let mut monitor = Self {
+            pool,
+            door,
+            webhooks,
+            alerts,
+            feed,
+            held_open: Duration::from_secs(config.held_open_secs),
+            doors: HashMap::new(),
+        };
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(poll).await;
+                monitor.poll().await;
+            }
+        });
+    }
+
+    async fn poll(&mut self) {
+        let doors = match get_all_doors(&self.pool, None).await {
+            Ok(doors) => doors,
+            Err(e) => {
+                error!(error = ?e, "Failed to load doors for sensor polling");
+                return;
+            }
+        };
+
+        for door in doors.into_iter().filter(|door| door.enabled) {
+            let position = match self.door.position(door.controller_door_id as u32).await {
+                Ok(DoorPosition::Unknown) => {
+                    self.doors.remove(&door.id);
+                    continue;
+                }
+                Ok(position) => position,
+                Err(e) => {
+                    warn!(door = %door.name, error = %e, "Failed to read door sensor");
+                    continue;
+                }
+            };
+
+            let Some(tracked) = self.doors.get_mut(&door.id) else {
+                // First reading is only a baseline
+                self.doors.insert(
+                    door.id,
+                    Tracked {
+                        position,
+                        opened_at: (position == DoorPosition::Open).then(Instant::now),
+                        alerted: false,
+                    },
+                );
+                continue;
+            };
+
+            let event = match (tracked.position, position) {
+                (DoorPosition::Closed, DoorPosition::Open) => {
+                    tracked.opened_at = Some(Instant::now());
+                    tracked.alerted = false;
+                    Some("opened")
+                }
+                (DoorPosition::Open, DoorPosition::Closed) => {
+                    tracked.opened_at = None;
+                    Some("closed")
+                }
+                (DoorPosition::Open, DoorPosition::Open) => match tracked.opened_at {
+                    Some(opened_at)
+                        if !tracked.alerted && opened_at.elapsed() >= self.held_open =>
+                    {
+                        let open_secs = opened_at.elapsed().as_secs();
+                        warn!(door = %door.name, open_secs, "Door held open");
+                        tracked.alerted = true;
+                        self.webhooks
+                            .notify(AccessEvent::held_open(door.controller_door_id, open_secs));
+                        self.alerts.notify(Alert::new(
+                            AlertKind::HeldOpen,
+                            Some(door.controller_door_id),
+                            format!("{} has been open for {} seconds", door.name, open_secs),
+                        ));
+                        Some("held_open")
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            tracked.position = position;
+
+            if let Some(event) = event {
+                info!(door = %door.name, event, "Door sensor event");
+                self.feed
+                    .publish(AdminEvent::door(door.controller_door_id, &door.name, event));
+                if let Err(e) = insert_door_event(&self.pool, door.id, event).await {
+                    error!(error = ?e, "Failed to record door event");
+                }
+            }
+        }
+    }
+}
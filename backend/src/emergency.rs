@@ -0,0 +1,92 @@
+use chrono::{DateTime, Duration, Utc};
+use rocket::tokio::sync::watch;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// Controller-wide emergency mode that overrides every per-key rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, rocket::form::FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum OverrideMode {
+    #[field(value = "grant_all")]
+    GrantAll,
+    #[field(value = "deny_all")]
+    DenyAll,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessOverride {
+    pub mode: OverrideMode,
+    pub expires_at: DateTime<Utc>,
+    pub triggered_by: String,
+}
+
+/// Shared between the Rocket handlers and the Portal background task. With the admin UI and
+/// the door controller in separate services, `bridge` follows the changes and copies them over.
+#[derive(Clone)]
+pub struct OverrideState(watch::Sender<Option<AccessOverride>>);
+
+impl Default for OverrideState {
+    fn default() -> Self {
+        Self(watch::Sender::new(None))
+    }
+}
+
+impl OverrideState {
+    pub async fn set(
+        &self,
+        mode: OverrideMode,
+        duration: Duration,
+        triggered_by: String,
+    ) -> AccessOverride {
+        let access_override = AccessOverride {
+            mode,
+            expires_at: Utc::now() + duration,
+            triggered_by,
+        };
+
+        warn!(
+            mode = ?access_override.mode,
+            triggered_by = %access_override.triggered_by,
+            expires_at = %access_override.expires_at,
+            "Emergency override set"
+        );
+
+        self.0.send_replace(Some(access_override.clone()));
+        access_override
+    }
+
+    pub async fn clear(&self, cleared_by: &str) {
+        if let Some(previous) = self.0.send_replace(None) {
+            warn!(mode = ?previous.mode, cleared_by, "Emergency override cleared");
+        }
+    }
+
+    /// Returns the active override, dropping it once it has expired.
+    pub async fn current(&self) -> Option<AccessOverride> {
+        self.0.send_if_modified(|current| {
+            if current.as_ref().is_some_and(|o| o.expires_at <= Utc::now())
+                && let Some(expired) = current.take()
+            {
+                info!(
+                    mode = ?expired.mode,
+                    triggered_by = %expired.triggered_by,
+                    "Emergency override expired"
+                );
+                return true;
+            }
+            false
+        });
+
+        self.0.borrow().clone()
+    }
+
+    /// Takes over the override set in another service, as it is.
+    pub fn replace(&self, access_override: Option<AccessOverride>) {
+        self.0.send_replace(access_override);
+    }
+
+    /// Follows every change, starting from the override in place now.
+    pub fn subscribe(&self) -> watch::Receiver<Option<AccessOverride>> {
+        self.0.subscribe()
+    }
+}
@@ -0,0 +1,142 @@
+//! Doors as Home Assistant lock entities, for the `/home-assistant` endpoints. A lock's state
+//! is asked of the door backend whenever the lock is looked at; `LockStates` remembers it, so
+//! each lock can also tell since when it has been locked or unlocked.
+
+use crate::admin_feed::{AdminEvent, AdminFeed};
+use crate::database::helpers::Door;
+use crate::door_backend::{DoorBackend, DoorPosition, DoorState};
+
+use chrono::{DateTime, Utc};
+use rocket::tokio::{self, sync::broadcast::error::RecvError};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// A door as a Home Assistant lock entity.
+#[derive(Debug, Serialize)]
+pub struct Lock {
+    pub id: Uuid,
+    pub name: String,
+    /// `unknown` while the door backend cannot be reached.
+    pub state: DoorState,
+    /// Whether the door contact reports the door open.
+    pub position: DoorPosition,
+    /// False for doors out of service and while the door backend cannot be reached.
+    pub available: bool,
+    /// When the lock was last seen going from locked to unlocked or back; `None` until it has
+    /// since the service started.
+    pub last_changed: Option<DateTime<Utc>>,
+}
+
+// A lock's state as last seen, and when it was last seen changing
+type Seen = (DoorState, Option<DateTime<Utc>>);
+
+/// The lock state of each door as last seen, by controller door id, and when it changed.
+#[derive(Clone, Default)]
+pub struct LockStates(Arc<Mutex<HashMap<i32, Seen>>>);
+
+impl LockStates {
+    /// Records the door's `state` as seen at `at`, and answers when it last changed.
+    pub fn observe(
+        &self,
+        door_id: i32,
+        state: DoorState,
+        at: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        let mut states = self.0.lock().expect("Lock state lock poisoned");
+        // A backend that cannot be reached says nothing about the lock
+        if state == DoorState::Unknown {
+            return states.get(&door_id).and_then(|(_, changed)| *changed);
+        }
+        match states.get_mut(&door_id) {
+            Some((known, changed)) if *known != state => {
+                *known = state;
+                *changed = Some(at);
+                *changed
+            }
+            Some((_, changed)) => *changed,
+            None => {
+                states.insert(door_id, (state, None));
+                None
+            }
+        }
+    }
+
+    // A change announced as it happened, dated when it happened
+    fn changed(&self, door_id: i32, state: DoorState, at: DateTime<Utc>) {
+        self.0
+            .lock()
+            .expect("Lock state lock poisoned")
+            .insert(door_id, (state, Some(at)));
+    }
+
+    /// Follows the doors admins, schedules and API tokens unlock and lock through `feed`, so
+    /// those changes are dated when they happened rather than when the lock is next looked at.
+    pub fn spawn_listener(&self, feed: &AdminFeed) {
+        let states = self.clone();
+        let mut events = feed.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                let AdminEvent::Door {
+                    door_id, state, at, ..
+                } = event
+                else {
+                    continue;
+                };
+                match state.as_str() {
+                    "unlocked" | "auto_unlocked" => {
+                        states.changed(door_id, DoorState::Unlocked, at)
+                    }
+                    "locked" | "auto_locked" => states.changed(door_id, DoorState::Locked, at),
+                    _ => {}
+                }
+            }
+        });
+    }
+}
+
+/// `door` as a lock entity, as its backend reports it now.
+pub async fn lock(backend: &dyn DoorBackend, states: &LockStates, door: &Door) -> Lock {
+    let controller_door_id = door.controller_door_id as u32;
+    let state = backend
+        .status(controller_door_id)
+        .await
+        .unwrap_or(DoorState::Unknown);
+    let position = backend
+        .position(controller_door_id)
+        .await
+        .unwrap_or(DoorPosition::Unknown);
+
+    Lock {
+        id: door.id,
+        name: door.name.clone(),
+        state,
+        position,
+        available: door.enabled && state != DoorState::Unknown,
+        last_changed: states.observe(door.controller_door_id, state, Utc::now()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_states_date_only_changes_they_saw() {
+        let states = LockStates::default();
+        let at = |secs| DateTime::from_timestamp(secs, 0).unwrap();
+
+        assert_eq!(states.observe(1, DoorState::Locked, at(0)), None);
+        assert_eq!(states.observe(1, DoorState::Locked, at(10)), None);
+        assert_eq!(states.observe(1, DoorState::Unlocked, at(20)), Some(at(20)));
+        assert_eq!(states.observe(1, DoorState::Unknown, at(30)), Some(at(20)));
+        assert_eq!(states.observe(1, DoorState::Unlocked, at(40)), Some(at(20)));
+        assert_eq!(states.observe(2, DoorState::Unlocked, at(40)), None);
+    }
+}
@@ -0,0 +1,171 @@
+use crate::config::I18nConfig;
+
+use rocket::http::{Cookie, CookieJar, SameSite};
+use rocket::request::{FromRequest, Outcome, Request};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Language the admin UI is written in; it needs no catalog.
+pub const SOURCE_LANGUAGE: &str = "en";
+
+/// Cookie holding the language an admin picked.
+pub const LANGUAGE_COOKIE: &str = "lang";
+
+// Catalog entry holding the name of its language
+const NAME_ENTRY: &str = "@name";
+
+// How long a language choice is remembered
+const LANGUAGE_COOKIE_DAYS: i64 = 365;
+
+/// Translations of the admin UI, one catalog per language, loaded from the `<code>.json` files in
+/// `i18n.locales_dir` at startup. A catalog maps English text, as written in the templates and
+/// error messages, to the translation; text missing from it is shown in English. Cloning is
+/// cheap.
+#[derive(Clone)]
+pub struct Catalogs {
+    catalogs: Arc<HashMap<String, HashMap<String, String>>>,
+    default_language: String,
+}
+
+impl Catalogs {
+    pub fn load(config: &I18nConfig) -> Result<Self, String> {
+        let mut catalogs = HashMap::new();
+        let dir = Path::new(&config.locales_dir);
+        let entries = fs::read_dir(dir)
+            .map_err(|e| format!("failed to read locales from {}: {}", dir.display(), e))?;
+        for entry in entries {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let Some(code) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+
+            let text = fs::read_to_string(&path)
+                .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+            let catalog: HashMap<String, String> = serde_json::from_str(&text)
+                .map_err(|e| format!("{} is not a valid catalog: {}", path.display(), e))?;
+            catalogs.insert(code.to_lowercase(), catalog);
+        }
+
+        let catalogs = Self {
+            catalogs: Arc::new(catalogs),
+            default_language: config.default_language.to_lowercase(),
+        };
+        if !catalogs.supports(&catalogs.default_language) {
+            return Err(format!(
+                "the default language '{}' has no catalog in {}",
+                config.default_language,
+                dir.display()
+            ));
+        }
+
+        Ok(catalogs)
+    }
+
+    pub fn supports(&self, language: &str) -> bool {
+        language == SOURCE_LANGUAGE || self.catalogs.contains_key(language)
+    }
+
+    /// Every language the UI can be shown in, by code, English first.
+    pub fn languages(&self) -> Vec<String> {
+        let mut languages: Vec<String> = self.catalogs.keys().cloned().collect();
+        languages.sort();
+        languages.retain(|language| language != SOURCE_LANGUAGE);
+        languages.insert(0, SOURCE_LANGUAGE.to_string());
+        languages
+    }
+
+    /// `text` in `language`, or unchanged when the catalog has no translation for it.
+    pub fn translate<'a>(&'a self, language: &str, text: &'a str) -> &'a str {
+        self.catalogs
+            .get(language)
+            .and_then(|catalog| catalog.get(text))
+            .map_or(text, String::as_str)
+    }
+
+    /// Like `translate`, filling `{0}`, `{1}`... with `args`.
+    pub fn format(&self, language: &str, text: &str, args: &[String]) -> String {
+        let mut translated = self.translate(language, text).to_string();
+        for (index, arg) in args.iter().enumerate() {
+            translated = translated.replace(&format!("{{{}}}", index), arg);
+        }
+        translated
+    }
+
+    // The language cookie if it names a known language, then the browser's preferences in the
+    // order it sent them, then `i18n.default_language`
+    fn pick(&self, cookie: Option<&str>, accept_language: Option<&str>) -> String {
+        let preferred = accept_language
+            .into_iter()
+            .flat_map(|header| header.split(','))
+            .map(|entry| {
+                let tag = entry.split(';').next().unwrap_or_default().trim();
+                tag.split('-').next().unwrap_or_default().to_lowercase()
+            });
+
+        cookie
+            .map(str::to_lowercase)
+            .into_iter()
+            .chain(preferred)
+            .find(|language| self.supports(language))
+            .unwrap_or_else(|| self.default_language.clone())
+    }
+
+    /// Names of the languages, each in its own language, for the language picker. A catalog
+    /// names its language under the `@name` entry.
+    pub fn names(&self) -> BTreeMap<String, String> {
+        self.languages()
+            .into_iter()
+            .map(|language| {
+                let name = match self.catalogs.get(&language) {
+                    Some(catalog) => catalog.get(NAME_ENTRY).cloned(),
+                    None => Some("English".to_string()),
+                };
+                let name = name.unwrap_or_else(|| language.clone());
+                (language, name)
+            })
+            .collect()
+    }
+}
+
+/// Language a request's pages and error messages are shown in, see `Catalogs`.
+pub struct Language(pub String);
+
+impl Language {
+    pub fn of(req: &Request<'_>) -> Self {
+        let language = match req.rocket().state::<Catalogs>() {
+            Some(catalogs) => catalogs.pick(
+                req.cookies()
+                    .get(LANGUAGE_COOKIE)
+                    .map(|cookie| cookie.value()),
+                req.headers().get_one("Accept-Language"),
+            ),
+            None => SOURCE_LANGUAGE.to_string(),
+        };
+        Language(language)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Language {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Language::of(req))
+    }
+}
+
+pub fn set_language_cookie(cookies: &CookieJar<'_>, language: &str) {
+    let mut cookie = Cookie::new(LANGUAGE_COOKIE, language.to_string());
+    cookie.set_path("/");
+    cookie.set_http_only(true);
+    cookie.set_same_site(SameSite::Lax);
+    cookie.set_max_age(rocket::time::Duration::days(LANGUAGE_COOKIE_DAYS));
+
+    cookies.add(cookie);
+}
@@ -0,0 +1,237 @@
+//! Keeps the rules of recently seen keys in memory, so a handshake does not wait on Postgres to
+//! check them. Triggers on the key tables NOTIFY on `portal_access_keys` with the npub of every
+//! key whose status, doors, groups or schedules change (`*` when a group's doors change), from
+//! the admin UI, the CLI or a service running elsewhere, and the entries are dropped as soon
+//! as that arrives.
+//!
+//! The door itself, its site's occupancy and the site-wide lockdown switch are kept the same
+//! way: triggers on `doors`, `sites` and `lockdown` NOTIFY on `portal_access_doors`, and every
+//! door is read again after any such change.
+//!
+//! Nothing is kept while the listening connection is down, since changes made meanwhile would
+//! go unnoticed; keys are then read from Postgres on every handshake, as without the cache.
+//! Entries are also read again after `key_cache.ttl_secs`, in case a notification got lost.
+
+use crate::access_events::Occupancy;
+use crate::config::KeyCacheConfig;
+use crate::database::helpers::{
+    Door, KeyRules, get_door_by_controller_id, get_key_rules, get_lockdown, get_site_by_id,
+};
+
+use rocket::tokio;
+use sqlx::postgres::PgListener;
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+const CHANNEL: &str = "portal_access_keys";
+const DOORS_CHANNEL: &str = "portal_access_doors";
+
+// Payload for changes that may touch any key
+const ALL_KEYS: &str = "*";
+
+// Unknown keys are kept too, so the cache is emptied rather than let grow past this
+const MAX_KEYS: usize = 10_000;
+
+// Pause before trying again after the database could not be reached
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Shared between the handshake loops; cloning is cheap.
+#[derive(Clone)]
+pub struct KeyCache {
+    cached: Arc<Mutex<Cached>>,
+    ttl: Duration,
+}
+
+#[derive(Default)]
+struct Cached {
+    listening: bool,
+    // Bumped on every change, so a key read before it is not kept
+    generation: u64,
+    // `None` for npubs with no key, with when they were read
+    keys: HashMap<String, (Instant, Option<Arc<KeyRules>>)>,
+    // By controller door id, with when they were read
+    doors: HashMap<i32, (Instant, Arc<DoorContext>)>,
+}
+
+/// What a handshake at a door is checked against besides the key.
+pub struct DoorContext {
+    /// `None` for doors that are not registered.
+    pub door: Option<Door>,
+    /// Only for entry doors of sites with an occupancy limit.
+    pub occupancy: Option<Occupancy>,
+    pub site_lockdown: bool,
+}
+
+impl Default for KeyCache {
+    fn default() -> Self {
+        Self::new(&KeyCacheConfig::default())
+    }
+}
+
+impl KeyCache {
+    pub fn new(config: &KeyCacheConfig) -> Self {
+        Self {
+            cached: Arc::default(),
+            ttl: Duration::from_secs(config.ttl_secs),
+        }
+    }
+
+    /// The rules of the key, or `None` when there is no such key.
+    pub async fn rules(
+        &self,
+        pool: &Pool<Postgres>,
+        npub: &str,
+    ) -> Result<Option<Arc<KeyRules>>, sqlx::Error> {
+        let generation = {
+            let cached = self.lock();
+            if let Some((read_at, rules)) = cached.keys.get(npub)
+                && read_at.elapsed() < self.ttl
+            {
+                return Ok(rules.clone());
+            }
+            cached.generation
+        };
+
+        let rules = get_key_rules(pool, npub).await?.map(Arc::new);
+
+        let mut cached = self.lock();
+        if cached.listening && cached.generation == generation && !self.ttl.is_zero() {
+            if cached.keys.len() >= MAX_KEYS {
+                cached.keys.clear();
+            }
+            cached
+                .keys
+                .insert(npub.to_string(), (Instant::now(), rules.clone()));
+        }
+        Ok(rules)
+    }
+
+    /// The door with this controller door id, its site's occupancy and the lockdown switch.
+    pub async fn door(
+        &self,
+        pool: &Pool<Postgres>,
+        controller_door_id: i32,
+    ) -> Result<Arc<DoorContext>, sqlx::Error> {
+        let generation = {
+            let cached = self.lock();
+            if let Some((read_at, context)) = cached.doors.get(&controller_door_id)
+                && read_at.elapsed() < self.ttl
+            {
+                return Ok(context.clone());
+            }
+            cached.generation
+        };
+
+        let door = get_door_by_controller_id(pool, controller_door_id).await?;
+        let occupancy = match &door {
+            Some(door) if door.direction.as_deref() == Some("entry") => {
+                get_site_by_id(pool, door.site_id).await?.and_then(|site| {
+                    Some(Occupancy {
+                        count: site.occupancy,
+                        max: site.max_occupancy?,
+                    })
+                })
+            }
+            _ => None,
+        };
+        let context = Arc::new(DoorContext {
+            door,
+            occupancy,
+            site_lockdown: get_lockdown(pool).await?.active,
+        });
+
+        let mut cached = self.lock();
+        if cached.listening && cached.generation == generation && !self.ttl.is_zero() {
+            cached
+                .doors
+                .insert(controller_door_id, (Instant::now(), context.clone()));
+        }
+        Ok(context)
+    }
+
+    fn forget(&self, npub: &str) {
+        let mut cached = self.lock();
+        cached.generation += 1;
+        if npub == ALL_KEYS {
+            cached.keys.clear();
+        } else {
+            cached.keys.remove(npub);
+        }
+    }
+
+    /// Drops the door if it counts towards its site's occupancy limit, after an entry through it
+    /// changed the count. Other doors hear of it through the notification.
+    pub fn forget_occupancy(&self, controller_door_id: i32) {
+        let mut cached = self.lock();
+        if cached
+            .doors
+            .get(&controller_door_id)
+            .is_some_and(|(_, context)| context.occupancy.is_some())
+        {
+            cached.generation += 1;
+            cached.doors.remove(&controller_door_id);
+        }
+    }
+
+    fn forget_doors(&self) {
+        let mut cached = self.lock();
+        cached.generation += 1;
+        cached.doors.clear();
+    }
+
+    fn set_listening(&self, listening: bool) {
+        let mut cached = self.lock();
+        cached.listening = listening;
+        cached.generation += 1;
+        cached.keys.clear();
+        cached.doors.clear();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Cached> {
+        self.cached.lock().expect("Key cache lock poisoned")
+    }
+}
+
+/// Listens for key and door changes and drops them from `cache`, reconnecting whenever the
+/// connection is lost.
+pub fn spawn_listener(pool: Pool<Postgres>, cache: KeyCache) {
+    tokio::spawn(async move {
+        loop {
+            let listener = match PgListener::connect_with(&pool).await {
+                Ok(mut listener) => listener
+                    .listen_all([CHANNEL, DOORS_CHANNEL])
+                    .await
+                    .map(|()| listener),
+                Err(e) => Err(e),
+            };
+            let mut listener = match listener {
+                Ok(listener) => listener,
+                Err(e) => {
+                    warn!(error = ?e, "Failed to listen for key changes, retrying");
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    continue;
+                }
+            };
+            cache.set_listening(true);
+            info!("Listening for key changes");
+
+            // Any change missed while disconnected would leave stale keys behind
+            let error = loop {
+                match listener.try_recv().await {
+                    Ok(Some(notification)) if notification.channel() == DOORS_CHANNEL => {
+                        cache.forget_doors()
+                    }
+                    Ok(Some(notification)) => cache.forget(notification.payload()),
+                    Ok(None) => break None,
+                    Err(e) => break Some(e),
+                }
+            };
+            cache.set_listening(false);
+            warn!(error = ?error, "Lost the connection for key changes, reading keys from the database until it is back");
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    });
+}
@@ -0,0 +1,7 @@
+//! Configuration, database layer and door backends, shared by the server and the
+//! `portal-access-ctl` administration tool.
+
+pub mod config;
+pub mod database;
+pub mod door_backend;
+pub mod secrets;
@@ -0,0 +1,27 @@
+use crate::database::helpers::AccessLog;
+
+use rocket::tokio::sync::broadcast;
+
+// Entries a slow subscriber may fall behind by before it skips ahead
+const CHANNEL_CAPACITY: usize = 64;
+
+/// Fans new access log entries out to the `/logs/stream` subscribers as they are recorded.
+#[derive(Clone)]
+pub struct LogFeed(broadcast::Sender<AccessLog>);
+
+impl Default for LogFeed {
+    fn default() -> Self {
+        Self(broadcast::channel(CHANNEL_CAPACITY).0)
+    }
+}
+
+impl LogFeed {
+    /// Never blocks; an entry published while nobody is watching is simply dropped.
+    pub fn publish(&self, log: AccessLog) {
+        let _ = self.0.send(log);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<AccessLog> {
+        self.0.subscribe()
+    }
+}
@@ -0,0 +1,15 @@
+use crate::config::{LogFormat, LoggingConfig};
+
+use tracing_subscriber::EnvFilter;
+
+/// Installs the global `tracing` subscriber: human-readable lines, or one JSON object per
+/// event for log shippers. Rocket's own `log` output is forwarded to it as well.
+pub fn init(config: &LoggingConfig) {
+    let filter = EnvFilter::try_new(&config.level).expect("Validated by Config::load");
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match config.format {
+        LogFormat::Pretty => subscriber.init(),
+        LogFormat::Json => subscriber.json().flatten_event(true).init(),
+    }
+}
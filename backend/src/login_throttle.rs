@@ -0,0 +1,120 @@
+use crate::client_ip::ClientIp;
+use crate::config::LoginConfig;
+use crate::database::helpers::{
+    clear_login_failures, get_login_lockout, lock_out_login, record_login_failure,
+};
+
+use chrono::Utc;
+use rocket::{
+    State,
+    http::Status,
+    request::{FromRequest, Outcome, Request},
+};
+use sqlx::{Pool, Postgres};
+use std::time::Duration;
+use tracing::{error, warn};
+
+/// Lockout policy for the admin login, managed by Rocket.
+///
+/// - `login.max_failures`: consecutive failures from one IP before it is locked out (default 5,
+///   0 turns the lockout off)
+/// - `login.lockout_secs`: first lockout, doubled on every further failure (default 30)
+/// - `login.max_lockout_secs`: longest lockout (default 3600)
+#[derive(Clone)]
+pub struct LoginThrottle {
+    max_failures: u32,
+    lockout: Duration,
+    max_lockout: Duration,
+}
+
+impl LoginThrottle {
+    pub fn new(config: &LoginConfig) -> Self {
+        Self {
+            max_failures: config.max_failures,
+            lockout: Duration::from_secs(config.lockout_secs),
+            max_lockout: Duration::from_secs(config.max_lockout_secs),
+        }
+    }
+
+    // Lockout after the given number of consecutive failures, if any
+    fn lockout_after(&self, failures: u32) -> Option<Duration> {
+        if self.max_failures == 0 {
+            return None;
+        }
+        let excess = failures.checked_sub(self.max_failures)?;
+        let factor = 1u32.checked_shl(excess).unwrap_or(u32::MAX);
+        Some(self.lockout.saturating_mul(factor).min(self.max_lockout))
+    }
+}
+
+/// Request guard for the login handler: counts failed logins per client IP in
+/// `login_failures` and locks the IP out once it has too many. Database errors never keep an
+/// admin from logging in; they are only logged.
+pub struct LoginAttempt {
+    pool: Pool<Postgres>,
+    throttle: LoginThrottle,
+    ip: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LoginAttempt {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let pool = req.guard::<&State<Pool<Postgres>>>().await;
+        let throttle = req.guard::<&State<LoginThrottle>>().await;
+        match (pool, throttle) {
+            (Outcome::Success(pool), Outcome::Success(throttle)) => {
+                Outcome::Success(LoginAttempt {
+                    pool: pool.inner().clone(),
+                    throttle: throttle.inner().clone(),
+                    ip: ClientIp::of(req).map(|ip| ip.to_string()),
+                })
+            }
+            _ => Outcome::Error((Status::InternalServerError, ())),
+        }
+    }
+}
+
+impl LoginAttempt {
+    /// Seconds left on the lockout of the client's IP, if it is locked out.
+    pub async fn locked_for(&self) -> Option<i64> {
+        let ip = self.ip.as_deref()?;
+        match get_login_lockout(&self.pool, ip).await {
+            Ok(locked_until) => locked_until.map(|until| (until - Utc::now()).num_seconds().max(1)),
+            Err(e) => {
+                error!(%ip, error = ?e, "Failed to check login lockout");
+                None
+            }
+        }
+    }
+
+    pub async fn failed(&self, username: &str) {
+        let Some(ip) = self.ip.as_deref() else {
+            return;
+        };
+        let failures = match record_login_failure(&self.pool, ip, username).await {
+            Ok(failures) => failures,
+            Err(e) => {
+                error!(%ip, error = ?e, "Failed to record login failure");
+                return;
+            }
+        };
+
+        if let Some(lockout) = self.throttle.lockout_after(failures as u32) {
+            warn!(%ip, ?lockout, failures, "Login locked out");
+            let locked_until = Utc::now() + lockout;
+            if let Err(e) = lock_out_login(&self.pool, ip, locked_until).await {
+                error!(%ip, error = ?e, "Failed to lock out login");
+            }
+        }
+    }
+
+    pub async fn succeeded(&self) {
+        if let Some(ip) = self.ip.as_deref()
+            && let Err(e) = clear_login_failures(&self.pool, ip).await
+        {
+            error!(%ip, error = ?e, "Failed to reset login failures");
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Email delivery through the SMTP server set up for alerts (`alerts.smtp_url` and
+//! `alerts.smtp_from`), shared by alert channels, the daily digest and visitor invitations.
+
+use crate::config::AlertsConfig;
+
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, Mailbox, MultiPart, SinglePart};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A file sent along with an email.
+pub struct MailAttachment {
+    pub filename: String,
+    pub content_type: ContentType,
+    pub body: Vec<u8>,
+}
+
+#[derive(Clone)]
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl Mailer {
+    /// The mailer for the configured SMTP server, or `None` when there is none.
+    pub fn new(config: &AlertsConfig) -> Result<Option<Self>, String> {
+        let (Some(url), Some(from)) = (&config.smtp_url, &config.smtp_from) else {
+            return Ok(None);
+        };
+
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(url)
+            .map_err(|e| format!("Invalid SMTP URL: {}", e))?
+            .timeout(Some(REQUEST_TIMEOUT))
+            .build();
+        let from = from
+            .parse()
+            .map_err(|e| format!("Invalid SMTP sender: {}", e))?;
+        Ok(Some(Self { transport, from }))
+    }
+
+    /// Sends a plain-text email to `to`, with `attachment` when there is one.
+    pub async fn send(
+        &self,
+        to: &str,
+        subject: &str,
+        body: String,
+        attachment: Option<MailAttachment>,
+    ) -> Result<(), String> {
+        let to: Mailbox = to.parse().map_err(|e| format!("invalid address: {}", e))?;
+        let builder = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject);
+        let message = match attachment {
+            None => builder.body(body),
+            Some(attachment) => builder.multipart(
+                MultiPart::mixed()
+                    .singlepart(SinglePart::plain(body))
+                    .singlepart(
+                        Attachment::new(attachment.filename)
+                            .body(attachment.body, attachment.content_type),
+                    ),
+            ),
+        }
+        .map_err(|e| e.to_string())?;
+
+        self.transport
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Sends an HTML email to `to`, with `text` for mail clients that do not show HTML.
+    pub async fn send_html(
+        &self,
+        to: &str,
+        subject: &str,
+        text: String,
+        html: String,
+    ) -> Result<(), String> {
+        let to: Mailbox = to.parse().map_err(|e| format!("invalid address: {}", e))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject)
+            .multipart(MultiPart::alternative_plain_html(text, html))
+            .map_err(|e| e.to_string())?;
+
+        self.transport
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
@@ -1,240 +1,864 @@
+mod access_events;
+mod admin_feed;
+mod alerts;
+mod anomalies;
+mod audit;
 mod auth;
+mod auto_lock;
+mod bridge;
+mod cache_headers;
+mod card_readers;
+mod client_ip;
 mod controllers;
-mod database;
+mod digest;
+mod door_sensors;
+mod emergency;
+mod home_assistant;
+mod i18n;
+mod key_cache;
+mod live_logs;
+mod logging;
+mod login_throttle;
+mod mailer;
+mod mqtt;
+mod nip05;
+mod openapi;
+mod opening_hours;
+mod photos;
+mod policy;
+mod portal_loop;
+mod profiles;
+mod rate_limit;
+mod relays;
+mod relock;
+mod request_id;
+mod request_log;
+mod retention;
+mod security_headers;
+mod sessions;
+mod templating;
+mod theme;
+// Only the tests use it; the server itself never builds these stand-ins
+#[cfg(feature = "testing")]
+#[allow(dead_code)]
+mod testing;
+mod totp;
+mod webhooks;
 
 use anyhow::Result;
-use dotenvy::dotenv;
-use portal::nostr::nips::nip19::ToBech32;
+use backend::{config, database, door_backend};
 use rocket::fs::{FileServer, relative};
-use rocket::tokio::sync::Mutex;
 use rocket::{catchers, routes, Build, Rocket};
 use rocket_cors::{AllowedHeaders, AllowedOrigins, CorsOptions};
-use rocket_dyn_templates::Template;
-use sqlx::{Pool, Postgres, postgres::PgPoolOptions};
-use std::env;
+use sqlx::migrate::MigrateError;
+use sqlx::{Pool, Postgres, postgres::{PgConnectOptions, PgPoolOptions}};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
 
-use crate::auth::JWTSecret;
+use crate::access_events::EventLog;
+use crate::admin_feed::AdminFeed;
+use crate::alerts::Alerter;
+use crate::auth::{JWTKeys, hash_password};
+use crate::bridge::RemoteDoors;
+use crate::cache_headers::CacheHeaders;
+use crate::client_ip::ClientNetworks;
+use crate::config::{AdminConfig, Config, DatabaseConfig, ServiceRole};
 use crate::controllers::access::{
-    add_key, delete_key, health_check, keys_page, login, login_page, logout, logs_page, not_found_handler, protected_endpoint, toggle_key, unauthorized_handler
+    add_key, approve_key_request, batch_keys, delete_key, deny_key_request, export_keys, export_logs, health_check, import_keys, key_credential, key_page, key_photo, keys_page, keys_summary, login, login_page, logout, log_events_page, logs_page, logs_summary, not_found_handler, protected_endpoint, refresh, restore_key, stream_logs, toggle_key, update_key, upload_key_photo, delete_key_photo, verify_logs, unauthorized_handler, forbidden_handler
 };
-use crate::database::helpers::is_key_enabled;
+use crate::controllers::alerts::{add_alert_channel, alerts_page, delete_alert_channel, test_alert_channel};
+use crate::controllers::api_docs::{openapi_document, swagger_ui};
+use crate::controllers::api_tokens::{add_api_token, api_tokens_page, delete_api_token};
+use crate::controllers::admin_socket::admin_socket;
+use crate::controllers::audit::{audit_page, clear_login_lockout, login_failures_page};
+use crate::controllers::cards::{add_card, cards_page, delete_card};
+use crate::controllers::dashboard::{dashboard_page, occupancy};
+use crate::controllers::doorbell::{approve_visitor, deny_visitor};
+use crate::controllers::doors::{
+    add_door, add_door_schedule, delete_door, delete_door_schedule, door_handshake_url, door_kiosk,
+    door_page, door_qr, doors_page, lock_door, set_door_group_permissions, set_door_permissions,
+    set_handshake, set_lockdown_door, set_price, set_timezone, set_direction, set_required_permissions,
+    set_unlock_duration, stream_door_handshake_url, toggle_door, unlock_door,
+};
+use crate::controllers::emergency::{clear_override, emergency_page, set_override, set_site_lockdown};
+use crate::controllers::groups::{
+    add_group, delete_group, group_page, groups_page, set_group_members,
+};
+use crate::controllers::guest_passes::{add_guest_pass, delete_guest_pass, guest_passes_page};
+use crate::controllers::home_assistant::{home_assistant_lock, home_assistant_locks, home_assistant_unlock};
+use crate::controllers::invites::{InvitePolicy, accept_invite, add_invite, invite_page, invites_page, revoke_invite};
+use crate::controllers::key_requests::{request_access, request_access_page};
+use crate::controllers::language::set_language;
+use crate::controllers::negotiation::{API_BASE, JsonExtension};
+use crate::controllers::opening_hours::{OpeningHoursPolicy, opening_hours_page};
+use crate::controllers::pwa::{manifest, service_worker};
+use crate::controllers::schedules::{add_schedule, delete_schedule, schedules_page};
+use crate::controllers::sessions::{revoke_session, sessions_page};
+use crate::controllers::settings::{
+    change_password, delete_logo, password_page, set_theme_settings, theme_logo, theme_page, upload_logo,
+};
+use crate::controllers::sites::{add_site, set_occupancy, sites_page, switch_site};
+use crate::controllers::status::{metrics, status_page};
+use crate::controllers::users::{add_user, delete_user, users_page};
+use crate::controllers::webhooks::{add_webhook, delete_webhook, toggle_webhook, webhooks_page};
+use crate::database::MIGRATOR;
+use crate::database::health::DbHealth;
+use crate::database::helpers::{ensure_admin_user, ensure_door, prepare_unlock_queries};
+use crate::door_backend::{DoorBackend, UnlockRetry};
+use crate::auto_lock::AutoLockScheduler;
+use crate::door_sensors::DoorSensorMonitor;
+use crate::i18n::Catalogs;
+use crate::emergency::OverrideState;
+use crate::home_assistant::LockStates;
+use crate::key_cache::KeyCache;
+use crate::live_logs::LogFeed;
+use crate::login_throttle::LoginThrottle;
+use crate::photos::Photos;
+use crate::portal_loop::{AccessController, HandshakeUrls, RelayHealth, Supervisor};
+use crate::rate_limit::{Debouncer, RateLimiter};
+use crate::relays::{PortalClient, PortalConnection, PortalIdentities, PortalIdentity};
+use crate::relock::RelockVerifier;
+use crate::request_id::RequestIds;
+use crate::request_log::{RequestLog, RequestMetrics};
+use crate::security_headers::SecurityHeaders;
+use crate::sessions::SessionPolicy;
+use crate::webhooks::WebhookDispatcher;
 
-use access_control::DoorUnlockClient;
-use portal::protocol::model::auth::AuthResponseStatus;
+// Pool sizing and timeouts from `[database]`. Each connection gets a server-side
+// `statement_timeout`, so a stuck query fails instead of holding up an unlock, and prepares the
+// unlock queries as soon as it is opened.
+fn pool_options(config: &DatabaseConfig) -> Result<(PgPoolOptions, PgConnectOptions)> {
+    let mut connect_options = PgConnectOptions::from_str(&config.url)?
+        .statement_cache_capacity(config.statement_cache_capacity);
+    if config.statement_timeout_ms > 0 {
+        connect_options = connect_options
+            .options([("statement_timeout", config.statement_timeout_ms.to_string())]);
+    }
 
-async fn db_setup() -> Result<Pool<Postgres>> {
-    dotenv().ok();
-    let db_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let idle_timeout =
+        (config.idle_timeout_secs > 0).then(|| Duration::from_secs(config.idle_timeout_secs));
+    let pool_options = PgPoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .acquire_timeout(Duration::from_secs(config.acquire_timeout_secs))
+        .idle_timeout(idle_timeout)
+        .after_connect(|conn, _| {
+            Box::pin(async move {
+                // Fails until the first migration has run; the queries are then prepared on first use
+                if let Err(e) = prepare_unlock_queries(conn).await {
+                    warn!(error = ?e, "Failed to prepare unlock queries");
+                }
+                Ok(())
+            })
+        });
 
-    // Create connection pool
-    let pool = PgPoolOptions::new().connect(&db_url).await?;
-    Ok(pool)
+    Ok((pool_options, connect_options))
 }
 
-fn build_rocket(pool: Pool<Postgres>) -> Rocket<Build> {
-    // Load environment variables
-    dotenv().ok();
-    let jwt_secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-
-    let cors = CorsOptions::default()
-        .allowed_origins(AllowedOrigins::all())
-        .allowed_methods(
-            vec![
-                rocket::http::Method::Get,
-                rocket::http::Method::Post,
-                rocket::http::Method::Options,
-            ]
-            .into_iter()
-            .map(From::from)
-            .collect(),
-        )
-        .allowed_headers(AllowedHeaders::some(&[
-            "Content-Type",
-            "Accept",
-            "User-Agent",
-        ]))
-        .allow_credentials(true)
-        .max_age(Some(86400)) // 24 hours
-        .to_cors()
-        .expect("Error creating CORS fairing");
-
-    rocket::build()
-        .configure(rocket::Config::figment().merge(("secret_key", jwt_secret.as_bytes())))
-        .manage(pool)
-        .manage(JWTSecret::new(jwt_secret))
+async fn db_setup(config: &DatabaseConfig) -> Result<(Pool<Postgres>, DbHealth)> {
+    let (pool_options, connect_options) = pool_options(config)?;
+
+    // Create connection pool. If the database is down at startup we still come up
+    // with a lazy pool so the admin UI can report the outage instead of crashing.
+    let connected = pool_options.clone().connect_with(connect_options.clone()).await;
+    let (pool, db_health) = match connected {
+        Ok(pool) => {
+            MIGRATOR.run(&pool).await?;
+            info!("Database migrations applied");
+            (pool, DbHealth::new(true))
+        }
+        Err(e) => {
+            error!(error = ?e, "Database unavailable at startup, running degraded");
+            let pool = pool_options.connect_lazy_with(connect_options);
+            spawn_deferred_migrations(pool.clone());
+            (pool, DbHealth::new(false))
+        }
+    };
+    db_health.spawn_probe(pool.clone());
+
+    Ok((pool, db_health))
+}
+
+// Apply migrations as soon as a database that was down at startup becomes reachable
+fn spawn_deferred_migrations(pool: Pool<Postgres>) {
+    rocket::tokio::spawn(async move {
+        loop {
+            match MIGRATOR.run(&pool).await {
+                Ok(()) => {
+                    info!("Database migrations applied");
+                    return;
+                }
+                Err(MigrateError::Execute(sqlx::Error::PoolTimedOut | sqlx::Error::Io(_))) => {
+                    rocket::tokio::time::sleep(Duration::from_secs(15)).await;
+                }
+                Err(e) => {
+                    error!(error = ?e, "Failed to apply database migrations");
+                    return;
+                }
+            }
+        }
+    });
+}
+
+// Seed the first admin account from AUTH_PASS so deployments that used the shared password keep working
+async fn seed_admin_user(pool: &Pool<Postgres>, admin: &AdminConfig) {
+    let Some(password) = &admin.password else {
+        return;
+    };
+    let username = &admin.username;
+
+    let password_hash = match hash_password(password) {
+        Ok(password_hash) => password_hash,
+        Err(e) => {
+            error!(error = %e, "Failed to hash the admin password");
+            return;
+        }
+    };
+
+    match ensure_admin_user(pool, username, &password_hash).await {
+        Ok(true) => info!(%username, "Created admin user from AUTH_PASS"),
+        Ok(false) => {}
+        Err(e) => error!(error = ?e, "Failed to seed admin user"),
+    }
+}
+
+fn build_rocket(
+    config: &Config,
+    jwt_keys: JWTKeys,
+    db_health: DbHealth,
+    identities: PortalIdentities,
+    controller: AccessController,
+) -> Rocket<Build> {
+    // Rocket's own secret key keeps following JWT_SECRET whenever one is configured
+    let mut figment = rocket::Config::figment();
+    if let Some(jwt_secret) = &config.jwt.secret {
+        figment = figment.merge(("secret_key", jwt_secret.as_bytes()));
+    }
+    // Rocket reads the certificate once, at launch
+    if let Some((certs, key)) = config.tls.paths() {
+        figment = figment.merge(("tls.certs", certs)).merge(("tls.key", key));
+    }
+    // Key photos come in as form uploads, which Rocket caps at 1 MiB per file by default
+    let upload_limit = config.photos.max_bytes.max(1 << 20);
+    figment = figment
+        .merge(("limits.file", upload_limit))
+        .merge(("limits.data-form", upload_limit + (1 << 20)));
+
+    // Without allowed origins browsers keep to their same-origin policy, all the admin UI
+    // needs; the CORS fairing would also turn away its own form posts, which carry an Origin
+    let cors_origins = config.http.cors_origins();
+    let cors = (!cors_origins.is_empty()).then(|| {
+        CorsOptions::default()
+            .allowed_origins(AllowedOrigins::some_exact(&cors_origins))
+            .allowed_methods(
+                vec![
+                    rocket::http::Method::Get,
+                    rocket::http::Method::Post,
+                    rocket::http::Method::Options,
+                ]
+                .into_iter()
+                .map(From::from)
+                .collect(),
+            )
+            .allowed_headers(AllowedHeaders::some(&[
+                "Content-Type",
+                "Accept",
+                "Authorization",
+                "User-Agent",
+            ]))
+            .allow_credentials(true)
+            .max_age(Some(86400)) // 24 hours
+            .to_cors()
+            .expect("Error creating CORS fairing")
+    });
+
+    let request_metrics = RequestMetrics::default();
+    let catalogs = Catalogs::load(&config.i18n).unwrap_or_else(|e| exit_with_config_error(&e));
+    let photos = Photos::new(&config.photos).unwrap_or_else(|e| exit_with_config_error(&e));
+    let lock_states = LockStates::default();
+    lock_states.spawn_listener(&controller.admin_feed);
+
+    let rocket = rocket::build()
+        .configure(figment)
+        .manage(controller.pool.clone())
+        .manage(jwt_keys)
+        .manage(LoginThrottle::new(&config.login))
+        .manage(ClientNetworks::new(&config.http))
+        .manage(photos)
+        .manage(SessionPolicy::new(&config.sessions))
+        .manage(InvitePolicy::new(&config.invites))
+        .manage(OpeningHoursPolicy::new(&config.opening_hours, config.site.tz()))
+        .manage(controller.overrides.clone())
+        .manage(controller.door.clone())
+        .manage(controller.webhooks.clone())
+        .manage(controller.alerts.clone())
+        .manage(controller.log_feed.clone())
+        .manage(lock_states)
+        .manage(db_health.clone())
+        .manage(request_metrics.clone())
+        .manage(catalogs.clone())
+        .manage(identities.0[0].connection.clone())
+        .manage(identities)
+        .manage(controller)
         .mount(
             "/",
             routes![
                 health_check,
                 login_page,
                 login,
+                request_access_page,
+                request_access,
+                opening_hours_page,
+                invite_page,
+                accept_invite,
+                refresh,
+                set_language,
+                dashboard_page,
+                occupancy,
                 logs_page,
+                logs_summary,
+                export_logs,
+                log_events_page,
+                verify_logs,
+                stream_logs,
                 protected_endpoint,
                 logout,
                 keys_page,
+                keys_summary,
+                key_page,
+                update_key,
+                key_photo,
+                upload_key_photo,
+                delete_key_photo,
+                add_key,
+                import_keys,
+                export_keys,
+                key_credential,
+                toggle_key,
+                batch_keys,
+                delete_key,
+                restore_key,
+                approve_key_request,
+                deny_key_request,
+                groups_page,
+                add_group,
+                group_page,
+                set_group_members,
+                delete_group,
+                schedules_page,
+                add_schedule,
+                delete_schedule,
+                cards_page,
+                add_card,
+                delete_card,
+                doors_page,
+                add_door,
+                door_page,
+                door_qr,
+                door_handshake_url,
+                stream_door_handshake_url,
+                home_assistant_locks,
+                home_assistant_lock,
+                home_assistant_unlock,
+                door_kiosk,
+                admin_socket,
+                set_door_permissions,
+                set_door_group_permissions,
+                set_price,
+                set_timezone,
+                set_direction,
+                set_handshake,
+                add_door_schedule,
+                delete_door_schedule,
+                set_unlock_duration,
+                set_required_permissions,
+                toggle_door,
+                set_lockdown_door,
+                delete_door,
+                unlock_door,
+                lock_door,
+                approve_visitor,
+                deny_visitor,
+                emergency_page,
+                set_override,
+                clear_override,
+                set_site_lockdown,
+                sites_page,
+                add_site,
+                set_occupancy,
+                switch_site,
+                users_page,
+                add_user,
+                delete_user,
+                invites_page,
+                add_invite,
+                revoke_invite,
+                sessions_page,
+                revoke_session,
+                password_page,
+                change_password,
+                theme_page,
+                set_theme_settings,
+                upload_logo,
+                delete_logo,
+                theme_logo,
+                status_page,
+                metrics,
+                webhooks_page,
+                add_webhook,
+                toggle_webhook,
+                delete_webhook,
+                alerts_page,
+                add_alert_channel,
+                delete_alert_channel,
+                test_alert_channel,
+                api_tokens_page,
+                add_api_token,
+                delete_api_token,
+                guest_passes_page,
+                add_guest_pass,
+                delete_guest_pass,
+                audit_page,
+                login_failures_page,
+                clear_login_lockout,
+                openapi_document,
+                swagger_ui,
+                manifest,
+                service_worker
+            ],
+        )
+        .mount(
+            API_BASE,
+            routes![
+                health_check,
+                login,
+                request_access,
+                opening_hours_page,
+                invite_page,
+                accept_invite,
+                refresh,
+                dashboard_page,
+                occupancy,
+                logs_page,
+                logs_summary,
+                export_logs,
+                log_events_page,
+                verify_logs,
+                stream_logs,
+                keys_page,
+                keys_summary,
+                key_page,
+                update_key,
+                key_photo,
+                upload_key_photo,
+                delete_key_photo,
                 add_key,
+                import_keys,
+                export_keys,
                 toggle_key,
-                delete_key
+                batch_keys,
+                delete_key,
+                restore_key,
+                approve_key_request,
+                deny_key_request,
+                groups_page,
+                add_group,
+                group_page,
+                set_group_members,
+                delete_group,
+                schedules_page,
+                add_schedule,
+                delete_schedule,
+                cards_page,
+                add_card,
+                delete_card,
+                doors_page,
+                add_door,
+                door_page,
+                door_qr,
+                door_handshake_url,
+                stream_door_handshake_url,
+                home_assistant_locks,
+                home_assistant_lock,
+                home_assistant_unlock,
+                set_door_permissions,
+                set_door_group_permissions,
+                set_price,
+                set_timezone,
+                set_direction,
+                set_handshake,
+                add_door_schedule,
+                delete_door_schedule,
+                set_unlock_duration,
+                set_required_permissions,
+                toggle_door,
+                set_lockdown_door,
+                delete_door,
+                unlock_door,
+                lock_door,
+                approve_visitor,
+                deny_visitor,
+                emergency_page,
+                set_override,
+                clear_override,
+                set_site_lockdown,
+                sites_page,
+                add_site,
+                set_occupancy,
+                switch_site,
+                users_page,
+                add_user,
+                delete_user,
+                invites_page,
+                add_invite,
+                revoke_invite,
+                sessions_page,
+                revoke_session,
+                password_page,
+                change_password,
+                theme_page,
+                set_theme_settings,
+                upload_logo,
+                delete_logo,
+                theme_logo,
+                status_page,
+                metrics,
+                webhooks_page,
+                add_webhook,
+                toggle_webhook,
+                delete_webhook,
+                alerts_page,
+                add_alert_channel,
+                delete_alert_channel,
+                test_alert_channel,
+                api_tokens_page,
+                add_api_token,
+                delete_api_token,
+                guest_passes_page,
+                add_guest_pass,
+                delete_guest_pass,
+                audit_page,
+                login_failures_page,
+                clear_login_lockout
             ],
         )
         .mount("/static", FileServer::from(relative!("static")))
-        .attach(cors)
-        .attach(Template::fairing())
-        .register("/", catchers![unauthorized_handler, not_found_handler])
+        .attach(RequestIds)
+        .attach(RequestLog(request_metrics))
+        .attach(SecurityHeaders::new(&config.http))
+        .attach(CacheHeaders::new(&config.http))
+        .attach(JsonExtension)
+        .attach(openapi::fairing(&config.http.swagger_ui_url))
+        .attach(templating::fairing(db_health, config.site.tz(), catalogs))
+        .attach(theme::fairing())
+        .register("/", catchers![unauthorized_handler, forbidden_handler, not_found_handler]);
+
+    match cors {
+        Some(cors) => rocket.attach(cors),
+        None => rocket,
+    }
 }
 
-async fn build_access_ontrol(pool: Pool<Postgres>) {
-    // Read configuration from environment variables
-    let base_url =
-        env::var("INTELLIM_BASE_URL").expect("INTELLIM_BASE_URL environment variable is required");
-
-    let username =
-        env::var("INTELLIM_USERNAME").expect("INTELLIM_USERNAME environment variable is required");
-
-    let password =
-        env::var("INTELLIM_PASSWORD").expect("INTELLIM_PASSWORD environment variable is required");
-
-    // Portal configuration
-    let nostr_key =
-        env::var("PORTAL_NOSTR_KEY").expect("PORTAL_NOSTR_KEY environment variable is required");
-
-    let relay_url =
-        env::var("PORTAL_RELAY_URL").expect("PORTAL_RELAY_URL environment variable is required");
-
-    let door_id = env::var("DOOR_ID")
-        .expect("DOOR_ID environment variable is required")
-        .parse::<u32>()
-        .expect("DOOR_ID must be a valid number");
-
-    println!("=== IntelliM Door Access Control Client (Rocket) ===");
-    println!("Connecting to: {}", base_url);
-    println!("Username: {}", username);
-    println!("Door ID: {}", door_id);
-
-    // Initialize the door unlock client and Portal SDK
-    let client = Arc::new(Mutex::new(DoorUnlockClient::new(
-        base_url.clone(),
-        username,
-        password,
-    )));
-
-    // Portal SDK initialization (may return Result; unwrap/expect for simplicity)
-    let keys = portal::nostr::Keys::parse(&nostr_key).expect("Failed to parse nostr key");
-    let keypair = portal::protocol::LocalKeypair::new(keys, None);
-    let portal_sdk = Arc::new(
-        sdk::PortalSDK::new(keypair, vec![relay_url])
+// The main Portal identity serves every door but those listed in `portal.identities`, which
+// get a connection of their own for each key. Only a service driving the doors connects those.
+async fn connect_identities(config: &Config, main: PortalConnection) -> PortalIdentities {
+    let doors = config
+        .door
+        .handshake_subjects()
+        .expect("Door subjects were validated with the configuration");
+    let listed = if config.service.role.drives_doors() {
+        config
+            .portal
+            .identities()
+            .expect("Portal identities were validated with the configuration")
+    } else {
+        Vec::new()
+    };
+
+    let mut identities = vec![PortalIdentity {
+        connection: main,
+        health: RelayHealth::default(),
+        doors: doors
+            .iter()
+            .map(|(door_id, _)| *door_id)
+            .filter(|door_id| !listed.iter().any(|(id, _)| id == door_id))
+            .collect(),
+    }];
+    let mut keys: Vec<&str> = Vec::new();
+    for (door_id, nostr_key) in &listed {
+        // Doors listed with the same key share its connection
+        if let Some(position) = keys.iter().position(|key| key == nostr_key) {
+            identities[position + 1].doors.push(*door_id);
+            continue;
+        }
+        let connection = PortalConnection::connect_as(nostr_key, &config.portal)
             .await
-            .expect("Failed to initialize Portal SDK"),
+            .expect("Failed to initialize Portal SDK");
+        info!(npub = connection.npub(), "Portal identity connected");
+        keys.push(nostr_key);
+        identities.push(PortalIdentity {
+            connection,
+            health: RelayHealth::default(),
+            doors: vec![*door_id],
+        });
+    }
+
+    PortalIdentities(identities)
+}
+
+// One controller per served door, the first for `door.id`. They share the rate limiter and
+// everything else but the door, its debouncer and the Portal identity it is served under.
+async fn build_access_ontrol(
+    config: &Config,
+    pool: Pool<Postgres>,
+    overrides: OverrideState,
+    door_backend: Arc<dyn DoorBackend>,
+    webhooks: WebhookDispatcher,
+    alerts: Alerter,
+    identities: &PortalIdentities,
+) -> Vec<AccessController> {
+    let doors = config
+        .door
+        .handshake_subjects()
+        .expect("Door subjects were validated with the configuration");
+
+    let portals: Vec<(Arc<dyn PortalClient>, &PortalIdentity)> = identities
+        .0
+        .iter()
+        .map(|identity| {
+            let portal: Arc<dyn PortalClient> = Arc::new(identity.connection.clone());
+            (portal, identity)
+        })
+        .collect();
+    let rate_limiter = RateLimiter::new(&config.rate_limit);
+    let log_feed = LogFeed::default();
+    let admin_feed = AdminFeed::default();
+    let handshake_urls = HandshakeUrls::default();
+    let key_cache = KeyCache::new(&config.key_cache);
+    let events = EventLog::new(pool.clone());
+    let relock = RelockVerifier::new(
+        pool.clone(),
+        door_backend.clone(),
+        alerts.clone(),
+        admin_feed.clone(),
+        &config.relock,
     );
 
-    // Clone Arcs for the background task
-    let bg_client = Arc::clone(&client);
-    let bg_portal = Arc::clone(&portal_sdk);
+    let mut controllers = Vec::new();
+    for (door_id, subject) in doors {
+        let (portal, identity) = portals
+            .iter()
+            .find(|(_, identity)| identity.doors.contains(&door_id))
+            .unwrap_or(&portals[0]);
+        info!(door_id, %subject, npub = identity.connection.npub(), "Serving door");
 
-    // Spawn the long-running handshake/notification loop as a background task on the Rocket/Tokio runtime.
-    // DO NOT create another tokio runtime. Use rocket::tokio::spawn (or tokio::spawn) instead.
-    rocket::tokio::spawn(async move {
-        println!("Portal SDK background task started. Waiting for authentication requests...");
-        loop {
-            // Create a handshake URL and receive a notifications stream
-            match bg_portal
-                .new_key_handshake_url(Some("1910-main-cafe-entrance".to_string()), Some(false))
-                .await
-            {
-                Ok((key_handshake_url, mut notifications)) => {
-                    println!("Key handshake URL: {}", key_handshake_url);
-
-                    // Process notification stream until it ends or errors out
-                    while let Some(notification_result) = notifications.next().await {
-                        match notification_result {
-                            Err(e) => {
-                                println!("❌ Notification error: {:?}", e);
-                                // continue to wait for next notification or recreate handshake if stream ended
-                                continue;
-                            }
-                            Ok(event) => {
-                                let pub_key = event.main_key;
-                                
-                                println!("Trying with this npub: {}", pub_key.to_bech32().unwrap());
-                                match is_key_enabled(&pool, pub_key.to_bech32().expect("Infallible").as_str()).await {
-                                    Ok(true) => {
-                                        println!("✅ Key is enabled, proceeding with authentication");
-                                    }
-                                    Ok(false) => {
-                                        println!("❌ Key is disabled, skipping authentication");
-                                        continue;
-                                    }
-                                    Err(e) => {
-                                        // Database error - log and skip
-                                        println!("❌ Database error checking key: {:?}", e);
-                                        continue;
-                                    }
-                                }
-
-                                // Authenticate the key obtained from the notification
-                                match bg_portal.authenticate_key(pub_key, vec![]).await {
-                                    Ok(response) => {
-                                        match response.status {
-                                            AuthResponseStatus::Approved { .. } => {
-                                                println!("✅ Authentication successful");
-                                                // Attempt to unlock the door
-                                                match bg_client
-                                                    .lock()
-                                                    .await
-                                                    .unlock_door(door_id, Some(-1))
-                                                    .await
-                                                {
-                                                    Ok(unlock_response) => {
-                                                        if unlock_response.success {
-                                                            println!(
-                                                                "✅ Door {} unlocked successfully",
-                                                                door_id
-                                                            );
-                                                        } else {
-                                                            println!(
-                                                                "❌ Door unlock failed: {}",
-                                                                unlock_response.message
-                                                            );
-                                                        }
-                                                    }
-                                                    Err(e) => {
-                                                        println!("❌ Door unlock error: {}", e);
-                                                    }
-                                                }
-                                            }
-                                            AuthResponseStatus::Declined { .. } => {
-                                                println!("❌ Authentication declined");
-                                            }
-                                        }
-                                    }
-                                    Err(e) => {
-                                        println!("❌ Authentication error: {:?}", e);
-                                    }
-                                }
-                            }
-                        }
-                    }
-
-                    // If we get here the notification stream ended. Loop will recreate a new handshake URL.
-                    println!("Notification stream ended, re-creating handshake URL...");
-                }
-                Err(e) => {
-                    // Creating handshake URL failed; back off a bit and retry.
-                    println!("❌ Failed to create handshake URL: {:?}", e);
-                    rocket::tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-                }
-            }
+        // Make sure every door served by a loop exists in the doors table
+        if let Err(e) = ensure_door(&pool, door_id as i32).await {
+            error!(door_id, error = ?e, "Failed to register door");
         }
-    });
+        handshake_urls.serve(door_id);
+
+        controllers.push(AccessController {
+            pool: pool.clone(),
+            overrides: overrides.clone(),
+            portal: portal.clone(),
+            door: door_backend.clone(),
+            door_id,
+            subject,
+            webhooks: webhooks.clone(),
+            alerts: alerts.clone(),
+            rate_limiter: rate_limiter.clone(),
+            debouncer: Debouncer::new(&config.rate_limit),
+            log_feed: log_feed.clone(),
+            admin_feed: admin_feed.clone(),
+            payment_timeout: Duration::from_secs(config.payments.timeout_secs),
+            unlock_retry: UnlockRetry::new(&config.unlock_retry),
+            relay_health: identity.health.clone(),
+            handshake_urls: handshake_urls.clone(),
+            timezone: config.site.tz(),
+            enrollment_limit: config
+                .enrollment
+                .open
+                .then_some(config.enrollment.max_pending.into()),
+            doorbell_answer: config
+                .doorbell
+                .enabled
+                .then(|| Duration::from_secs(config.doorbell.answer_secs)),
+            key_cache: key_cache.clone(),
+            events: events.clone(),
+            relock: relock.clone(),
+        });
+    }
+
+    controllers
+}
+
+// Startup configuration problems are reported all at once instead of panicking on the first one.
+// Printed directly, since this can run before the tracing subscriber is installed.
+fn exit_with_config_error(problems: &str) -> ! {
+    println!("❌ Invalid configuration:");
+    for problem in problems.lines() {
+        println!("   - {}", problem);
+    }
+    std::process::exit(1);
 }
 
 #[rocket::main]
 async fn main() -> Result<(), rocket::Error> {
     // print_event_for_debug().await;
-    let pool = db_setup().await.expect("Database failed to connect");
-    build_access_ontrol(pool.clone()).await;
-    build_rocket(pool).launch().await?;
+    let config = Config::load().await.unwrap_or_else(|e| exit_with_config_error(&e));
+    logging::init(&config.logging);
+    info!(file = %config::config_file(), "Configuration loaded");
+
+    // `--migrate-only` brings the schema up to date and exits, e.g. from a deploy step
+    if std::env::args().any(|arg| arg == "--migrate-only") {
+        let pool = PgPoolOptions::new()
+            .connect(&config.database.url)
+            .await
+            .expect("Failed to connect to the database");
+        MIGRATOR.run(&pool).await.expect("Failed to apply database migrations");
+        info!("Database migrations applied");
+        return Ok(());
+    }
+
+    let role = config.service.role;
+    info!(?role, "Service role");
+    // Only the admin UI signs sessions
+    let jwt_keys = role
+        .serves_web()
+        .then(|| JWTKeys::from_config(&config.jwt).unwrap_or_else(|e| exit_with_config_error(&e)));
+    // The web service reaches the doors through the door service instead
+    let local_doors = role
+        .drives_doors()
+        .then(|| door_backend::from_config(&config).unwrap_or_else(|e| exit_with_config_error(&e)));
+
+    let (pool, db_health) = db_setup(&config.database)
+        .await
+        .expect("Invalid database configuration");
+    if role.serves_web() {
+        seed_admin_user(&pool, &config.admin).await;
+    }
+    let door_backend: Arc<dyn DoorBackend> = match local_doors {
+        Some(local_doors) => local_doors,
+        None => Arc::new(RemoteDoors::connect(pool.clone(), &config.unlock_retry)),
+    };
+    let overrides = OverrideState::default();
+    let webhooks = WebhookDispatcher::new(pool.clone(), &config.webhooks);
+    let alerts = Alerter::new(pool.clone(), &config.alerts)
+        .unwrap_or_else(|e| exit_with_config_error(&e));
+    // Portal SDK initialization, connected to every configured relay, for every identity
+    let portal = PortalConnection::connect(&config.portal)
+        .await
+        .expect("Failed to initialize Portal SDK");
+    let identities = connect_identities(&config, portal).await;
+    let controllers = build_access_ontrol(
+        &config,
+        pool,
+        overrides,
+        door_backend,
+        webhooks,
+        alerts,
+        &identities,
+    )
+    .await;
+    // The first door's controller also serves the admin UI and guest passes
+    let controller = controllers[0].clone();
+
+    // Spawn the long-running handshake/notification loops as background tasks on the Rocket/Tokio runtime.
+    // DO NOT create another tokio runtime. The supervisor restarts a loop if it crashes.
+    // The web service leaves them, and watching the doors, to the door service.
+    if role.drives_doors() {
+        card_readers::spawn(&controllers, &config.card_readers);
+    }
+    let supervisor = role.drives_doors().then(|| Supervisor::spawn(controllers));
+    for identity in &identities.0 {
+        identity.connection.spawn_monitor(identity.health.clone());
+    }
+    if role.drives_doors() {
+        DoorSensorMonitor::spawn(
+            controller.pool.clone(),
+            controller.door.clone(),
+            controller.webhooks.clone(),
+            controller.alerts.clone(),
+            controller.admin_feed.clone(),
+            &config.sensors,
+        );
+        AutoLockScheduler::spawn(
+            controller.pool.clone(),
+            controller.door.clone(),
+            controller.overrides.clone(),
+            controller.admin_feed.clone(),
+            config.site.tz(),
+        );
+        key_cache::spawn_listener(controller.pool.clone(), controller.key_cache.clone());
+        controller
+            .alerts
+            .spawn_backend_monitor(controller.door.clone(), &config.alerts);
+    }
+    match role {
+        ServiceRole::All => {}
+        ServiceRole::Web => bridge::spawn_web(&controller),
+        ServiceRole::Door => bridge::spawn_door(&controller),
+    }
+
+    // The door service serves no HTTP; it runs until it is told to stop
+    let Some(jwt_keys) = jwt_keys else {
+        shutdown_signal().await;
+        if let Some(supervisor) = supervisor {
+            supervisor.stop().await;
+        }
+        return Ok(());
+    };
+
+    mqtt::spawn(&controller, &config.mqtt);
+    profiles::spawn_refresher(
+        controller.pool.clone(),
+        controller.portal.clone(),
+        &config.profiles,
+    );
+    retention::spawn_pruner(
+        controller.pool.clone(),
+        &config.retention,
+        config.site.tz(),
+    );
+    anomalies::spawn_detector(
+        controller.pool.clone(),
+        controller.alerts.clone(),
+        &config.alerts,
+        config.site.tz(),
+    );
+    digest::spawn_digest(
+        controller.pool.clone(),
+        controller.alerts.clone(),
+        &config.alerts,
+        config.site.tz(),
+    );
+    controller.resume_guest_handshakes().await;
+
+    let mut rocket = build_rocket(&config, jwt_keys, db_health, identities, controller);
+    if let Some(supervisor) = supervisor {
+        rocket = rocket.attach(supervisor.fairing());
+    }
+    rocket.launch().await?;
 
     Ok(())
 }
+
+// Ctrl-C or SIGTERM, the signals Rocket shuts down on as well
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use rocket::tokio::signal::unix::{SignalKind, signal};
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        rocket::tokio::select! {
+            _ = rocket::tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = rocket::tokio::signal::ctrl_c().await;
+
+    info!("Shutting down");
+}
@@ -0,0 +1,324 @@
+//! Publishes access events and door states to an MQTT broker, for building automation such as
+//! Home Assistant or Node-RED, and takes unlock commands from it when `mqtt.commands` is on.
+//!
+//! Topics, under `mqtt.topic_prefix`:
+//! - `<prefix>/status`: `online` while connected and `offline`, the broker's last will, once
+//!   the connection is gone; retained
+//! - `<prefix>/access`: every new access log entry, as JSON like `/logs/stream` sends it
+//! - `<prefix>/doors/<door_id>/state`: the door's latest state, as JSON with the `state` of
+//!   `AdminEvent::Door`; retained
+//! - `<prefix>/doors/<door_id>/unlock`: commands, `{"token": "pat_..."}` with an API token
+//!   holding the `unlock` scope, and the door's site for tokens kept to one
+//!
+//! Door ids are the controller door ids of the access log. Commands are attributed to
+//! `token:<name>` in the access and audit logs, like unlocks through the API.
+
+use crate::admin_feed::AdminEvent;
+use crate::auth::{Scope, hash_api_token};
+use crate::config::MqttConfig;
+use crate::database::helpers::{
+    AccessResult, ApiTokenRecord, get_door_by_controller_id, insert_audit_entry,
+    insert_manual_access_log, touch_api_token,
+};
+use crate::portal_loop::AccessController;
+use crate::webhooks::AccessEvent;
+
+use chrono::{DateTime, Utc};
+use rocket::tokio::{self, sync::broadcast::error::RecvError};
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, QoS, Transport};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+// Messages waiting for the connection before new ones are dropped
+const QUEUE_SIZE: usize = 256;
+// Pause before connecting again after the broker could not be reached
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+struct Topics {
+    prefix: String,
+}
+
+impl Topics {
+    fn status(&self) -> String {
+        format!("{}/status", self.prefix)
+    }
+
+    fn access(&self) -> String {
+        format!("{}/access", self.prefix)
+    }
+
+    fn door_state(&self, door_id: i32) -> String {
+        format!("{}/doors/{}/state", self.prefix, door_id)
+    }
+
+    fn unlock_commands(&self) -> String {
+        format!("{}/doors/+/unlock", self.prefix)
+    }
+
+    // The door an unlock command is for
+    fn unlock_door(&self, topic: &str) -> Option<i32> {
+        topic
+            .strip_prefix(self.prefix.as_str())?
+            .strip_prefix("/doors/")?
+            .strip_suffix("/unlock")?
+            .parse()
+            .ok()
+    }
+}
+
+#[derive(Serialize)]
+struct DoorMessage<'a> {
+    door_id: i32,
+    name: &'a str,
+    state: &'a str,
+    at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct UnlockCommand {
+    token: String,
+}
+
+/// Connects to the broker in `config` and keeps publishing what `controller` reports until
+/// the process exits. Does nothing unless `mqtt.enabled`.
+pub fn spawn(controller: &AccessController, config: &MqttConfig) {
+    if !config.enabled {
+        return;
+    }
+
+    let topics = Topics {
+        prefix: config.topic_prefix.trim_matches('/').to_string(),
+    };
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(KEEP_ALIVE);
+    options.set_last_will(LastWill::new(
+        topics.status(),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if !config.username.is_empty() {
+        options.set_credentials(&config.username, &config.password);
+    }
+    if config.tls {
+        options.set_transport(Transport::tls_with_default_config());
+    }
+    let (client, eventloop) = AsyncClient::new(options, QUEUE_SIZE);
+    info!(host = %config.host, port = config.port, prefix = %topics.prefix, "Publishing to MQTT");
+
+    let mut entries = controller.log_feed.subscribe();
+    let publisher = client.clone();
+    let access = topics.access();
+    tokio::spawn(async move {
+        loop {
+            let entry = match entries.recv().await {
+                Ok(entry) => entry,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+            publish(&publisher, access.clone(), false, &entry);
+        }
+    });
+
+    let mut events = controller.admin_feed.subscribe();
+    let publisher = client.clone();
+    let door_topics = topics.clone();
+    tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+            if let AdminEvent::Door {
+                door_id,
+                name,
+                state,
+                at,
+            } = event
+            {
+                let message = DoorMessage {
+                    door_id,
+                    name: &name,
+                    state: &state,
+                    at,
+                };
+                publish(&publisher, door_topics.door_state(door_id), true, &message);
+            }
+        }
+    });
+
+    tokio::spawn(run(
+        client,
+        eventloop,
+        topics,
+        controller.clone(),
+        config.commands,
+    ));
+}
+
+// Never waits: while the broker is unreachable and the queue is full, the message is dropped
+fn publish(client: &AsyncClient, topic: String, retain: bool, payload: &impl Serialize) {
+    let payload = match serde_json::to_vec(payload) {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!(%topic, error = %e, "Failed to encode MQTT message");
+            return;
+        }
+    };
+    if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, retain, payload) {
+        debug!(error = %e, "MQTT queue full, dropping message");
+    }
+}
+
+// Drives the connection: reconnects, announces the service and (re)subscribes to commands
+// whenever the broker accepts it, and hands the commands out
+async fn run(
+    client: AsyncClient,
+    mut eventloop: EventLoop,
+    topics: Topics,
+    controller: AccessController,
+    commands: bool,
+) {
+    let mut connected = false;
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to the MQTT broker");
+                connected = true;
+                if let Err(e) =
+                    client.try_publish(topics.status(), QoS::AtLeastOnce, true, "online")
+                {
+                    warn!(error = %e, "Failed to announce the service over MQTT");
+                }
+                if commands
+                    && let Err(e) = client.try_subscribe(topics.unlock_commands(), QoS::AtLeastOnce)
+                {
+                    error!(error = %e, "Failed to subscribe to MQTT unlock commands");
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(message))) => {
+                let Some(door_id) = topics.unlock_door(&message.topic) else {
+                    continue;
+                };
+                match serde_json::from_slice::<UnlockCommand>(&message.payload) {
+                    Ok(command) => {
+                        // One slow door does not hold up commands for the others
+                        tokio::spawn(unlock(controller.clone(), door_id, command.token));
+                    }
+                    Err(_) => warn!(door_id, "Ignoring MQTT unlock command without a token"),
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                if connected {
+                    warn!(error = %e, "Lost the MQTT broker, reconnecting");
+                } else {
+                    debug!(error = %e, "MQTT broker still unreachable");
+                }
+                connected = false;
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+// The token behind an unlock command, if it may unlock the door
+async fn authorize(
+    controller: &AccessController,
+    door_id: i32,
+    token: &str,
+) -> Option<ApiTokenRecord> {
+    let record = match touch_api_token(&controller.pool, &hash_api_token(token.trim())).await {
+        Ok(Some(record)) => record,
+        Ok(None) => {
+            warn!(door_id, "MQTT unlock command with an unknown API token");
+            return None;
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to look up API token");
+            return None;
+        }
+    };
+    let scopes: Vec<Scope> = record
+        .scopes
+        .iter()
+        .filter_map(|scope| scope.parse().ok())
+        .collect();
+    if !Scope::granted(&scopes)
+        .iter()
+        .any(|scope| scope == Scope::Unlock.as_str())
+    {
+        warn!(token = %record.id, scope = Scope::Unlock.as_str(), "API token lacks scope");
+        return None;
+    }
+
+    Some(record)
+}
+
+/// Runs an unlock command for `door_id` carrying `token`. Commands whose token may not unlock
+/// the door are logged and ignored; the others are recorded and announced like an unlock
+/// through the API.
+pub async fn unlock(controller: AccessController, door_id: i32, token: String) {
+    let Some(token) = authorize(&controller, door_id, &token).await else {
+        return;
+    };
+    let pool = &controller.pool;
+    let door = match get_door_by_controller_id(pool, door_id).await {
+        Ok(Some(door)) if token.site_id.is_none_or(|site| site == door.site_id) => door,
+        Ok(_) => {
+            warn!(door_id, token = %token.id, "MQTT unlock command for an unknown door");
+            return;
+        }
+        Err(e) => {
+            error!(door_id, error = ?e, "Failed to load door");
+            return;
+        }
+    };
+    if !door.enabled {
+        warn!(door = %door.name, "Ignoring MQTT unlock command for a disabled door");
+        return;
+    }
+
+    let by = format!("token:{}", token.name);
+    let outcome = controller
+        .unlock_retry
+        .unlock(
+            controller.door.as_ref(),
+            door_id as u32,
+            door.unlock_duration(),
+        )
+        .await;
+    let note = outcome.note();
+    let (access_result, reason) = match (&outcome.result, note) {
+        (Ok(()), Some(note)) => (AccessResult::Granted, format!("MQTT unlock; {}", note)),
+        (Ok(()), None) => (AccessResult::Granted, "MQTT unlock".to_string()),
+        (Err(reason), _) => (AccessResult::Error, reason.clone()),
+    };
+    match insert_manual_access_log(pool, &by, door_id, access_result, Some(&reason)).await {
+        Ok(log) => controller.log_feed.publish(log),
+        Err(e) => error!(error = ?e, "Failed to record access log"),
+    }
+
+    match outcome.result {
+        Ok(()) => {
+            info!(door = %door.name, %by, "Door unlocked over MQTT");
+            if let Err(e) =
+                insert_audit_entry(pool, &by, "door.unlock", Some(&door.name), None).await
+            {
+                error!(error = ?e, "Failed to record audit entry");
+            }
+            controller.relock.watch(&door);
+            controller
+                .webhooks
+                .notify(AccessEvent::manual_unlock(&by, door_id));
+            controller
+                .admin_feed
+                .publish(AdminEvent::door(door_id, &door.name, "unlocked"));
+        }
+        Err(reason) => warn!(door = %door.name, %reason, "MQTT unlock failed"),
+    }
+}
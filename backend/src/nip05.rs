@@ -0,0 +1,81 @@
+use crate::database::helpers::set_key_nip05_verification;
+
+use portal::nostr::PublicKey;
+use portal::nostr::nips::nip19::FromBech32;
+use rocket::tokio;
+use sqlx::{Pool, Postgres};
+use std::time::Duration;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Check a newly added key's NIP-05 address in the background and store the outcome.
+pub fn spawn_verification(pool: Pool<Postgres>, key_id: Uuid, npub: String, nip05: String) {
+    tokio::spawn(async move {
+        let verified = match verify(&nip05, &npub).await {
+            Ok(true) => {
+                info!(%nip05, "NIP-05 verified");
+                true
+            }
+            Ok(false) => {
+                warn!(%nip05, %npub, "NIP-05 does not map to the key");
+                false
+            }
+            Err(e) => {
+                warn!(%nip05, error = %e, "NIP-05 check failed");
+                false
+            }
+        };
+
+        if let Err(e) = set_key_nip05_verification(&pool, key_id, verified).await {
+            error!(error = ?e, "Failed to store NIP-05 verification");
+        }
+    });
+}
+
+/// Resolves `name@domain` through `https://domain/.well-known/nostr.json?name=name` and checks
+/// that the document lists the key's hex public key under that name.
+pub async fn verify(nip05: &str, npub: &str) -> Result<bool, String> {
+    let (name, domain) = match nip05.trim().to_lowercase().split_once('@') {
+        Some((name, domain)) => (name.to_string(), domain.to_string()),
+        None => ("_".to_string(), nip05.trim().to_lowercase()),
+    };
+
+    // NIP-05 limits names to a-z0-9-_. so they can go into the query string as-is
+    let valid_name = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    let valid_domain = !domain.is_empty()
+        && domain
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | ':'));
+    if !valid_name || !valid_domain {
+        return Err(format!("'{}' is not a valid NIP-05 address", nip05));
+    }
+
+    let public_key =
+        PublicKey::from_bech32(npub).map_err(|e| format!("invalid npub {}: {:?}", npub, e))?;
+
+    // The spec forbids following redirects
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let url = format!("https://{}/.well-known/nostr.json?name={}", domain, name);
+    let response = client.get(&url).send().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("{} answered HTTP {}", url, response.status()));
+    }
+
+    let body = response.text().await.map_err(|e| e.to_string())?;
+    let document: serde_json::Value =
+        serde_json::from_str(&body).map_err(|e| format!("{} is not valid JSON: {}", url, e))?;
+
+    Ok(document["names"][name.as_str()]
+        .as_str()
+        .is_some_and(|hex| hex.eq_ignore_ascii_case(&public_key.to_hex())))
+}
@@ -0,0 +1,564 @@
+use crate::controllers::negotiation::API_BASE;
+
+use rocket::Route;
+use rocket::fairing::{AdHoc, Fairing};
+use rocket::http::uri::Absolute;
+use serde_json::{Map, Value, json};
+use tracing::warn;
+
+/// The OpenAPI 3 document of the `/api/v1` routes and where the Swagger UI assets are loaded
+/// from, managed by `fairing`.
+pub struct ApiDocs {
+    pub document: Value,
+    pub swagger_ui_url: Option<String>,
+}
+
+impl ApiDocs {
+    /// Scheme and host of `swagger_ui_url`, for the docs page's Content-Security-Policy.
+    pub fn swagger_ui_origin(&self) -> Option<String> {
+        let url = Absolute::parse(self.swagger_ui_url.as_deref()?).ok()?;
+        let authority = url.authority()?;
+        Some(format!("{}://{}", url.scheme(), authority))
+    }
+}
+
+/// Generates the document from the routes mounted under `API_BASE` once Rocket has them all.
+/// `swagger_ui_url` is the base URL of the `swagger-ui-dist` assets; empty turns the docs page
+/// off.
+pub fn fairing(swagger_ui_url: &str) -> impl Fairing {
+    let swagger_ui_url = swagger_ui_url.trim().trim_end_matches('/').to_string();
+    AdHoc::on_ignite("OpenAPI document", move |rocket| async move {
+        let document = document(rocket.routes());
+        rocket.manage(ApiDocs {
+            document,
+            swagger_ui_url: (!swagger_ui_url.is_empty()).then_some(swagger_ui_url),
+        })
+    })
+}
+
+// How a field of a query or form is written in `Operation`: `name` is a required string, and
+// `name?` an optional one; `:integer`, `:boolean` or `:binary` (an uploaded file) give another
+// type, and `[]` makes it a list, repeated once per value
+struct Field {
+    name: &'static str,
+    required: bool,
+    schema: Value,
+}
+
+impl Field {
+    fn parse(spec: &'static str) -> Self {
+        let (name, kind) = spec.split_once(':').unwrap_or((spec, "string"));
+        let (name, list) = match name.strip_suffix("[]") {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        let (name, required) = match name.strip_suffix('?') {
+            Some(name) => (name, false),
+            None => (name, !list),
+        };
+
+        let schema = match kind {
+            "binary" => json!({ "type": "string", "format": "binary" }),
+            kind => json!({ "type": kind }),
+        };
+        let schema = if list {
+            json!({ "type": "array", "items": schema })
+        } else {
+            schema
+        };
+        Field {
+            name,
+            required,
+            schema,
+        }
+    }
+}
+
+// What the document says about one handler, by its function name. Path parameters, and query
+// parameters named in the route, are taken from the route itself.
+struct Operation {
+    handler: &'static str,
+    summary: &'static str,
+    query: &'static [&'static str],
+    form: &'static [&'static str],
+    produces: &'static str,
+    public: bool,
+}
+
+const fn op(handler: &'static str, summary: &'static str) -> Operation {
+    Operation {
+        handler,
+        summary,
+        query: &[],
+        form: &[],
+        produces: "application/json",
+        public: false,
+    }
+}
+
+impl Operation {
+    // Query fields of a `<query..>` route, which the route does not name
+    const fn query(self, query: &'static [&'static str]) -> Self {
+        Operation { query, ..self }
+    }
+
+    const fn form(self, form: &'static [&'static str]) -> Self {
+        Operation { form, ..self }
+    }
+
+    const fn produces(self, produces: &'static str) -> Self {
+        Operation { produces, ..self }
+    }
+
+    const fn public(self) -> Self {
+        Operation {
+            public: true,
+            ..self
+        }
+    }
+}
+
+const OPERATIONS: &[Operation] = &[
+    op("health_check", "Readiness check with per-component status").public(),
+    op("login", "Exchange a username and password for a token")
+        .form(&["username", "password", "totp?"])
+        .public(),
+    op(
+        "refresh",
+        "Exchange the refresh_token cookie for a new token",
+    )
+    .public(),
+    op("request_access", "Ask for a key")
+        .form(&["npub", "note?"])
+        .public(),
+    op(
+        "opening_hours_page",
+        "Which doors are open now and their weekly hours",
+    )
+    .public(),
+    op(
+        "invite_page",
+        "What an invite link is for, with a new authenticator app secret",
+    )
+    .public(),
+    op("accept_invite", "Create the account an invite link is for")
+        .form(&[
+            "username",
+            "password",
+            "confirm_password",
+            "totp_secret?",
+            "totp_code?",
+        ])
+        .public(),
+    op("dashboard_page", "Usage statistics for the last 30 days"),
+    op("occupancy", "How many people each site holds"),
+    op("logs_page", "Access log, newest first, 50 entries per page").query(&[
+        "key?",
+        "door?:integer",
+        "result?",
+        "from?",
+        "to?",
+        "page?:integer",
+    ]),
+    op(
+        "logs_summary",
+        "Access log cut down for phones, newest first, 20 entries per page",
+    )
+    .query(&[
+        "key?",
+        "door?:integer",
+        "result?",
+        "from?",
+        "to?",
+        "page?:integer",
+    ]),
+    op("export_logs", "Download the access log as CSV or JSON").produces("text/csv"),
+    op(
+        "stream_logs",
+        "Server-Sent Events feed of new access log entries",
+    )
+    .produces("text/event-stream"),
+    op(
+        "log_events_page",
+        "Pipeline events behind an access log entry, with its decision replayed",
+    ),
+    op(
+        "verify_logs",
+        "Check the access log hash chain; 409 with the entries that break it",
+    ),
+    op("keys_page", "Keys, 50 per page").query(&[
+        "sort?",
+        "unused_days?:integer",
+        "q?",
+        "status?",
+        "tag?",
+        "page?:integer",
+    ]),
+    op("keys_summary", "Keys cut down for phones, 20 per page").query(&[
+        "sort?",
+        "unused_days?:integer",
+        "q?",
+        "status?",
+        "tag?",
+        "page?:integer",
+    ]),
+    op("add_key", "Add a key").form(&["npub", "nip05?", "profile_name?", "door_ids[]"]),
+    op(
+        "key_page",
+        "A key with its schedule, doors and recent access",
+    ),
+    op(
+        "update_key",
+        "Replace a key's notes and comma separated tags",
+    )
+    .form(&["notes", "tags"]),
+    op("key_photo", "The key's photo").produces("image/jpeg"),
+    op(
+        "upload_key_photo",
+        "Replace the key's photo with a JPEG, PNG or WebP image",
+    )
+    .form(&["photo:binary"]),
+    op("delete_key_photo", "Remove the key's photo"),
+    op("import_keys", "Add keys in bulk from a CSV or JSON file")
+        .form(&["file:binary", "door_ids[]"]),
+    op("export_keys", "Download every key as CSV or JSON").produces("text/csv"),
+    op("toggle_key", "Enable or disable a key"),
+    op(
+        "batch_keys",
+        "Enable, disable, delete, group or set the end date of several keys at once",
+    )
+    .form(&["key_ids[]", "action", "group_id?", "expires_at?"]),
+    op("delete_key", "Delete a key"),
+    op("restore_key", "Bring back a deleted key"),
+    op("approve_key_request", "Turn an access request into a key").form(&["door_ids[]"]),
+    op("deny_key_request", "Discard an access request"),
+    op("groups_page", "Groups"),
+    op("add_group", "Add a group").form(&["name"]),
+    op("group_page", "A group and its members"),
+    op("set_group_members", "Set a group's members").form(&["key_ids[]"]),
+    op("delete_group", "Delete a group"),
+    op("schedules_page", "A key's weekly time windows"),
+    op("add_schedule", "Add a time window to a key").form(&[
+        "weekdays[]:integer",
+        "start_time",
+        "end_time",
+    ]),
+    op("delete_schedule", "Remove a key's time window"),
+    op("cards_page", "A key's badge cards"),
+    op("add_card", "Add a badge card to a key").form(&["card_number", "label?"]),
+    op("delete_card", "Remove a key's badge card"),
+    op("doors_page", "Doors"),
+    op("add_door", "Add a door").form(&["name", "controller_door_id:integer"]),
+    op("door_page", "A door, its handshake URL and who may open it"),
+    op("door_qr", "The door's handshake URL as a QR code").produces("image/svg+xml"),
+    op(
+        "door_handshake_url",
+        "The door's current handshake URL, for kiosk displays",
+    ),
+    op(
+        "stream_door_handshake_url",
+        "Live feed of the door's handshake URL, for kiosk displays",
+    )
+    .produces("text/event-stream"),
+    op("set_door_permissions", "Set the keys granted a door").form(&["key_ids[]"]),
+    op(
+        "set_door_group_permissions",
+        "Set the groups granted a door",
+    )
+    .form(&["group_ids[]"]),
+    op("set_price", "Set a door's price, empty for a free door").form(&["price_sats?:integer"]),
+    op(
+        "set_timezone",
+        "Set a door's timezone, empty for the site's",
+    )
+    .form(&["timezone?"]),
+    op(
+        "set_direction",
+        "Count a door's grants as entries or exits, empty to not count them",
+    )
+    .form(&["direction?"]),
+    op(
+        "set_handshake",
+        "Make a door's handshake URL static, rotating or single use",
+    )
+    .form(&[
+        "rotating?:boolean",
+        "token?",
+        "rotate_secs?:integer",
+        "single_use?:boolean",
+    ]),
+    op(
+        "add_door_schedule",
+        "Add a window the door is held unlocked for",
+    )
+    .form(&["weekdays[]:integer", "unlock_time", "lock_time"]),
+    op("delete_door_schedule", "Remove a door's unlock window"),
+    op("set_unlock_duration", "Set how long a door stays unlocked")
+        .form(&["unlock_seconds?:integer"]),
+    op(
+        "set_required_permissions",
+        "Set the permissions the wallet has to grant",
+    )
+    .form(&["required_permissions?"]),
+    op("toggle_door", "Take a door out of service or put it back"),
+    op("set_lockdown_door", "Lock a door down or lift its lockdown").form(&["locked_down:boolean"]),
+    op("delete_door", "Delete a door"),
+    op("unlock_door", "Unlock a door"),
+    op("lock_door", "Lock a door"),
+    op(
+        "home_assistant_locks",
+        "Every door as a Home Assistant lock entity",
+    ),
+    op(
+        "home_assistant_lock",
+        "A door as a Home Assistant lock entity",
+    ),
+    op(
+        "home_assistant_unlock",
+        "Unlock a door from its Home Assistant lock entity",
+    ),
+    op("approve_visitor", "Let a visitor at the doorbell in once"),
+    op("deny_visitor", "Turn a visitor at the doorbell away"),
+    op("emergency_page", "Emergency override and site lockdown"),
+    op(
+        "set_override",
+        "Open every door or deny every key for a while",
+    )
+    .form(&["mode", "minutes:integer"]),
+    op("clear_override", "End the emergency override"),
+    op("set_site_lockdown", "Switch the site lockdown on or off")
+        .form(&["active:boolean", "reason?"]),
+    op("sites_page", "Sites"),
+    op("add_site", "Add a site").form(&["name"]),
+    op(
+        "set_occupancy",
+        "Set a site's maximum occupancy, empty for no limit, and optionally reset its count",
+    )
+    .form(&["max_occupancy?:integer", "occupancy?:integer"]),
+    op("switch_site", "Work in one site, or every site when empty").form(&["site_id?"]),
+    op("users_page", "User accounts"),
+    op("add_user", "Add a user account").form(&["username", "password", "role", "site_id?"]),
+    op("delete_user", "Delete a user account"),
+    op("invites_page", "Invite links for new user accounts"),
+    op(
+        "add_invite",
+        "Make a one-time invite link for a new user account",
+    )
+    .form(&["role", "site_id?"]),
+    op("revoke_invite", "Revoke an unused invite link"),
+    op("sessions_page", "Active sign-in sessions"),
+    op("revoke_session", "Revoke a session"),
+    op(
+        "password_page",
+        "When the signed-in user's password was changed and when it expires",
+    ),
+    op(
+        "change_password",
+        "Change the signed-in user's password, signing out their other sessions",
+    )
+    .form(&["current_password", "new_password", "confirm_password"]),
+    op(
+        "theme_page",
+        "The site name, colors and logo the pages carry",
+    ),
+    op(
+        "set_theme_settings",
+        "Set the site name and colors, each empty for the built-in one",
+    )
+    .form(&[
+        "site_name",
+        "primary_color",
+        "background_color",
+        "text_color",
+    ]),
+    op(
+        "upload_logo",
+        "Replace the logo with a JPEG, PNG or WebP image",
+    )
+    .form(&["logo:binary"]),
+    op("delete_logo", "Remove the logo"),
+    op("theme_logo", "The logo").produces("image/png").public(),
+    op(
+        "status_page",
+        "Relay reachability and handshake loop status",
+    ),
+    op("metrics", "Prometheus metrics").produces("text/plain"),
+    op("webhooks_page", "Webhooks and their recent deliveries"),
+    op("add_webhook", "Add a webhook").form(&["url"]),
+    op("toggle_webhook", "Enable or disable a webhook"),
+    op("delete_webhook", "Delete a webhook"),
+    op("alerts_page", "Your alert channels"),
+    op("add_alert_channel", "Add an alert channel").form(&["kind", "target", "triggers[]"]),
+    op("delete_alert_channel", "Delete an alert channel"),
+    op("test_alert_channel", "Send a test alert"),
+    op("api_tokens_page", "API tokens"),
+    op("add_api_token", "Mint an API token").form(&["name", "scopes[]", "site_id?"]),
+    op("delete_api_token", "Revoke an API token"),
+    op("guest_passes_page", "Guest passes"),
+    op(
+        "add_guest_pass",
+        "Create a guest pass or pre-register a visitor",
+    )
+    .form(&[
+        "label",
+        "npub?",
+        "max_uses?:integer",
+        "valid_from?",
+        "valid_until?",
+        "host?",
+        "email?",
+    ]),
+    op("delete_guest_pass", "Delete a guest pass"),
+    op("audit_page", "Audit trail of admin actions and sign-ins"),
+    op("login_failures_page", "Failed logins per client IP"),
+    op("clear_login_lockout", "Lift a login lockout"),
+];
+
+fn document<'a>(routes: impl Iterator<Item = &'a Route>) -> Value {
+    let mut paths = Map::new();
+    for route in routes {
+        let path = route.uri.path();
+        if !path.starts_with(API_BASE) {
+            continue;
+        }
+        let handler = route.name.as_deref().unwrap_or_default();
+        let Some(operation) = OPERATIONS.iter().find(|op| op.handler == handler) else {
+            warn!(handler, "API route missing from the OpenAPI operations");
+            continue;
+        };
+
+        // `/doors/<door_id>` is `/doors/{door_id}` in OpenAPI
+        let mut parameters = Vec::new();
+        let segments: Vec<String> = path
+            .split('/')
+            .map(|segment| match param_name(segment) {
+                Some(name) => {
+                    parameters.push(json!({
+                        "name": name,
+                        "in": "path",
+                        "required": true,
+                        "schema": { "type": "string" },
+                    }));
+                    format!("{{{}}}", name)
+                }
+                None => segment.to_string(),
+            })
+            .collect();
+        let route_query = route
+            .uri
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter_map(param_name)
+            .map(|name| json!({ "name": name, "in": "query", "schema": { "type": "string" } }));
+        let listed_query = operation.query.iter().map(|spec| {
+            let field = Field::parse(spec);
+            json!({
+                "name": field.name,
+                "in": "query",
+                "required": field.required,
+                "schema": field.schema,
+            })
+        });
+        parameters.extend(route_query.chain(listed_query));
+
+        let tag = path[API_BASE.len()..]
+            .split('/')
+            .find(|segment| !segment.is_empty())
+            .unwrap_or("api");
+        let mut entry = json!({
+            "operationId": operation.handler,
+            "summary": operation.summary,
+            "tags": [tag],
+            "parameters": parameters,
+            "responses": {
+                "200": {
+                    "description": "Success; adding something answers 201 with its id",
+                    "content": { operation.produces: { "schema": { "type": "object" } } },
+                },
+                "default": {
+                    "description": "Error",
+                    "content": {
+                        "application/json": { "schema": { "$ref": "#/components/schemas/Error" } },
+                    },
+                },
+            },
+        });
+        if !operation.form.is_empty() {
+            entry["requestBody"] = request_body(operation.form);
+        }
+        if operation.public {
+            entry["security"] = json!([]);
+        }
+
+        let method = route.method.as_str().to_lowercase();
+        let item = paths
+            .entry(segments.join("/"))
+            .or_insert_with(|| Value::Object(Map::new()));
+        item[method.as_str()] = entry;
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Portal Access Control API",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Responses under /api/v1 are always JSON. Request bodies are form-encoded, exactly as the admin UI sends them.",
+        },
+        "paths": paths,
+        "security": [{ "bearerAuth": [] }, { "cookieAuth": [] }],
+        "components": {
+            "securitySchemes": {
+                "bearerAuth": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "A token from /api/v1/login, or an API token (pat_...) for the endpoints of its scopes",
+                },
+                "cookieAuth": { "type": "apiKey", "in": "cookie", "name": "auth_token" },
+            },
+            "schemas": {
+                "Error": {
+                    "type": "object",
+                    "properties": { "error": { "type": "string" } },
+                    "required": ["error"],
+                },
+            },
+        },
+    })
+}
+
+fn request_body(form: &[&'static str]) -> Value {
+    let fields: Vec<Field> = form.iter().map(|spec| Field::parse(spec)).collect();
+    let upload = form.iter().any(|spec| spec.ends_with(":binary"));
+    let properties: Map<String, Value> = fields
+        .iter()
+        .map(|field| (field.name.to_string(), field.schema.clone()))
+        .collect();
+    let required: Vec<&str> = fields
+        .iter()
+        .filter(|field| field.required)
+        .map(|field| field.name)
+        .collect();
+    let media_type = if upload {
+        "multipart/form-data"
+    } else {
+        "application/x-www-form-urlencoded"
+    };
+
+    let mut schema = json!({ "type": "object", "properties": properties });
+    if !required.is_empty() {
+        schema["required"] = json!(required);
+    }
+    json!({
+        "required": true,
+        "content": { media_type: { "schema": schema } },
+    })
+}
+
+// `<name>` of a dynamic route segment; trailing `<rest..>` segments are not parameters
+fn param_name(segment: &str) -> Option<&str> {
+    segment
+        .strip_prefix('<')?
+        .strip_suffix('>')
+        .filter(|name| !name.ends_with(".."))
+}
@@ -0,0 +1,176 @@
+//! Whether each door is open right now and its weekly hours, for the members' opening hours
+//! page. A door is open while one of its auto-lock windows holds it unlocked (see `auto_lock`)
+//! or a grant-all override is set. It opens to members' keys at other times, and it is closed
+//! while it is out of service, locked down, under the site lockdown or a deny-all override.
+//! An entry door of a site at its maximum occupancy counts as full.
+//!
+//! Like the policy, working out a door's state touches neither the database nor the clock.
+
+use crate::auto_lock::unlocked_until;
+use crate::database::helpers::{
+    Door, DoorSchedule, Site, get_all_door_schedules, get_all_doors, get_all_sites, get_lockdown,
+};
+use crate::emergency::{OverrideMode, OverrideState};
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+const WEEKDAYS: [&str; 7] = [
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+];
+
+/// What a member finds at the door right now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Availability {
+    /// Held unlocked, anyone can walk in.
+    Open,
+    /// Locked, members' keys open it.
+    Members,
+    /// The site holds as many people as it may.
+    Full,
+    Closed,
+}
+
+/// Everything a door's state is worked out from.
+pub struct Conditions<'a> {
+    pub site: Option<&'a Site>,
+    pub schedules: &'a [DoorSchedule],
+    pub site_lockdown: bool,
+    pub override_mode: Option<OverrideMode>,
+    pub at: DateTime<Tz>,
+}
+
+/// The state of `door`, and until when it is held unlocked when that is on schedule.
+pub fn availability(door: &Door, conditions: &Conditions) -> (Availability, Option<DateTime<Utc>>) {
+    if !door.enabled
+        || door.locked_down
+        || conditions.site_lockdown
+        || conditions.override_mode == Some(OverrideMode::DenyAll)
+    {
+        return (Availability::Closed, None);
+    }
+    if conditions.override_mode == Some(OverrideMode::GrantAll) {
+        return (Availability::Open, None);
+    }
+    let full = door.direction.as_deref() == Some("entry")
+        && conditions
+            .site
+            .and_then(|site| site.max_occupancy.map(|max| site.occupancy >= max))
+            .unwrap_or(false);
+    if full {
+        return (Availability::Full, None);
+    }
+    match unlocked_until(conditions.schedules, conditions.at) {
+        Some(until) => (Availability::Open, Some(until)),
+        None => (Availability::Members, None),
+    }
+}
+
+/// The windows a door is held unlocked on one day of the week, as `HH:MM–HH:MM`.
+#[derive(Serialize)]
+pub struct DayHours {
+    pub day: &'static str,
+    pub windows: Vec<String>,
+}
+
+/// A week of `schedules`, Monday first; empty for a door without any.
+pub fn weekly_hours(schedules: &[DoorSchedule]) -> Vec<DayHours> {
+    if schedules.is_empty() {
+        return Vec::new();
+    }
+    (1..=7)
+        .map(|weekday| DayHours {
+            day: WEEKDAYS[weekday as usize - 1],
+            windows: schedules
+                .iter()
+                .filter(|schedule| schedule.weekdays.contains(&weekday))
+                .map(|schedule| {
+                    format!(
+                        "{}–{}",
+                        schedule.unlock_time.format("%H:%M"),
+                        schedule.lock_time.format("%H:%M")
+                    )
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+pub struct DoorHours {
+    pub name: String,
+    pub state: Availability,
+    pub open_until: Option<DateTime<Utc>>,
+    pub hours: Vec<DayHours>,
+}
+
+#[derive(Serialize)]
+pub struct SiteHours {
+    pub name: String,
+    pub doors: Vec<DoorHours>,
+}
+
+/// Every site with its doors, as they are now. Schedules are read in the door's timezone, or
+/// `timezone` for doors without one, like the auto-lock scheduler does.
+pub async fn current(
+    pool: &Pool<Postgres>,
+    overrides: &OverrideState,
+    timezone: Tz,
+) -> Result<Vec<SiteHours>, sqlx::Error> {
+    let sites = get_all_sites(pool).await?;
+    let doors = get_all_doors(pool, None).await?;
+    let schedules = get_all_door_schedules(pool).await?;
+    let site_lockdown = get_lockdown(pool).await?.active;
+    let override_mode = overrides.current().await.map(|active| active.mode);
+
+    let mut by_door: HashMap<Uuid, Vec<DoorSchedule>> = HashMap::new();
+    for schedule in schedules {
+        by_door.entry(schedule.door_id).or_default().push(schedule);
+    }
+    let now = Utc::now();
+
+    Ok(sites
+        .iter()
+        .map(|site| SiteHours {
+            name: site.name.clone(),
+            doors: doors
+                .iter()
+                .filter(|door| door.site_id == site.id)
+                .map(|door| {
+                    let schedules = by_door.get(&door.id).map(Vec::as_slice).unwrap_or_default();
+                    let door_timezone = door
+                        .timezone
+                        .as_deref()
+                        .and_then(|timezone| timezone.parse().ok())
+                        .unwrap_or(timezone);
+                    let conditions = Conditions {
+                        site: Some(site),
+                        schedules,
+                        site_lockdown,
+                        override_mode,
+                        at: now.with_timezone(&door_timezone),
+                    };
+                    let (state, open_until) = availability(door, &conditions);
+                    DoorHours {
+                        name: door.name.clone(),
+                        state,
+                        open_until,
+                        hours: weekly_hours(schedules),
+                    }
+                })
+                .collect(),
+        })
+        .filter(|site| !site.doors.is_empty())
+        .collect())
+}
@@ -0,0 +1,262 @@
+//! Key photos, so security staff can match the person at the door to the key, and the logo of
+//! the `theme`. Photos are kept in a `PhotoStore`, on disk or in an S3 bucket as
+//! `photos.storage` says, under names the server makes up; the key or the theme only records the
+//! name and the image type.
+
+use crate::config::{PhotoStorage, PhotosConfig};
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::{Client, Method, StatusCode, Url};
+use rocket::tokio::fs;
+use sha2::{Digest, Sha256};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Headers covered by the S3 request signature
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+/// Where photos are kept. Errors are human readable and end up in the server log.
+#[rocket::async_trait]
+pub trait PhotoStore: Send + Sync {
+    async fn put(&self, name: &str, body: Vec<u8>, content_type: &str) -> Result<(), String>;
+    /// `None` when there is no photo of that name.
+    async fn get(&self, name: &str) -> Result<Option<Vec<u8>>, String>;
+    /// Removing a photo that is already gone is not an error.
+    async fn delete(&self, name: &str) -> Result<(), String>;
+}
+
+/// The configured store and upload limit, managed by Rocket.
+#[derive(Clone)]
+pub struct Photos {
+    pub store: Arc<dyn PhotoStore>,
+    pub max_bytes: u64,
+}
+
+impl Photos {
+    pub fn new(config: &PhotosConfig) -> Result<Self, String> {
+        let store: Arc<dyn PhotoStore> = match config.storage {
+            PhotoStorage::Disk => Arc::new(DiskStore {
+                dir: PathBuf::from(&config.dir),
+            }),
+            PhotoStorage::S3 => Arc::new(S3Store::new(config)?),
+        };
+        Ok(Self {
+            store,
+            max_bytes: config.max_bytes,
+        })
+    }
+}
+
+/// The image type of an upload, from its first bytes rather than what the browser claims.
+/// Only JPEG, PNG and WebP are taken.
+pub fn image_type(body: &[u8]) -> Option<(&'static str, &'static str)> {
+    if body.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(("image/jpeg", "jpg"))
+    } else if body.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(("image/png", "png"))
+    } else if body.len() >= 12 && &body[..4] == b"RIFF" && &body[8..12] == b"WEBP" {
+        Some(("image/webp", "webp"))
+    } else {
+        None
+    }
+}
+
+/// A new name for a photo of `key_id`. Every upload gets its own, so a replaced photo is never
+/// overwritten while someone is looking at it.
+pub fn photo_name(key_id: Uuid, extension: &str) -> String {
+    format!("{}-{}.{}", key_id, Uuid::new_v4().simple(), extension)
+}
+
+/// A new name for an uploaded logo, see `theme`.
+pub fn logo_name(extension: &str) -> String {
+    format!("logo-{}.{}", Uuid::new_v4().simple(), extension)
+}
+
+/// Photos as files in one directory, created on the first upload.
+pub struct DiskStore {
+    dir: PathBuf,
+}
+
+#[rocket::async_trait]
+impl PhotoStore for DiskStore {
+    async fn put(&self, name: &str, body: Vec<u8>, _content_type: &str) -> Result<(), String> {
+        fs::create_dir_all(&self.dir)
+            .await
+            .map_err(|e| format!("cannot create {}: {}", self.dir.display(), e))?;
+        fs::write(self.dir.join(name), body)
+            .await
+            .map_err(|e| format!("cannot write photo {}: {}", name, e))
+    }
+
+    async fn get(&self, name: &str) -> Result<Option<Vec<u8>>, String> {
+        match fs::read(self.dir.join(name)).await {
+            Ok(body) => Ok(Some(body)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("cannot read photo {}: {}", name, e)),
+        }
+    }
+
+    async fn delete(&self, name: &str) -> Result<(), String> {
+        match fs::remove_file(self.dir.join(name)).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(format!("cannot delete photo {}: {}", name, e)),
+        }
+    }
+}
+
+/// Photos as objects in an S3 bucket, signed with AWS Signature Version 4.
+pub struct S3Store {
+    client: Client,
+    endpoint: Url,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+impl S3Store {
+    fn new(config: &PhotosConfig) -> Result<Self, String> {
+        let endpoint: Url = config
+            .s3_endpoint
+            .parse()
+            .map_err(|e| format!("Invalid S3 endpoint: {}", e))?;
+        let client = Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            client,
+            endpoint,
+            bucket: config.s3_bucket.clone(),
+            region: config.s3_region.clone(),
+            access_key: config.s3_access_key.clone(),
+            secret_key: config.s3_secret_key.clone(),
+        })
+    }
+
+    // Sends one signed request for the object `name`. Names are made by `photo_name`, so they
+    // need no escaping in the path.
+    async fn send(
+        &self,
+        method: Method,
+        name: &str,
+        body: Vec<u8>,
+        content_type: Option<&str>,
+    ) -> Result<reqwest::Response, String> {
+        let mut url = self.endpoint.clone();
+        let path = format!(
+            "{}/{}/{}",
+            url.path().trim_end_matches('/'),
+            self.bucket,
+            name
+        );
+        url.set_path(&path);
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(Sha256::digest(&body));
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method.as_str(),
+            url.path(),
+            host,
+            payload_hash,
+            amz_date,
+            SIGNED_HEADERS,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signing_key = [date.as_str(), &self.region, "s3", "aws4_request"]
+            .iter()
+            .fold(
+                format!("AWS4{}", self.secret_key).into_bytes(),
+                |key, part| hmac(&key, part.as_bytes()),
+            );
+        let signature = hex::encode(hmac(&signing_key, string_to_sign.as_bytes()));
+
+        let mut request = self
+            .client
+            .request(method, url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header(
+                "Authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    self.access_key, scope, SIGNED_HEADERS, signature
+                ),
+            );
+        if let Some(content_type) = content_type {
+            request = request.header("Content-Type", content_type);
+        }
+        request
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| format!("S3 request failed: {}", e))
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[rocket::async_trait]
+impl PhotoStore for S3Store {
+    async fn put(&self, name: &str, body: Vec<u8>, content_type: &str) -> Result<(), String> {
+        let response = self
+            .send(Method::PUT, name, body, Some(content_type))
+            .await?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!(
+                "S3 upload of {} failed: {}",
+                name,
+                response.status()
+            ))
+        }
+    }
+
+    async fn get(&self, name: &str) -> Result<Option<Vec<u8>>, String> {
+        let response = self.send(Method::GET, name, Vec::new(), None).await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => response
+                .bytes()
+                .await
+                .map(|body| Some(body.to_vec()))
+                .map_err(|e| format!("S3 download of {} failed: {}", name, e)),
+            status => Err(format!("S3 download of {} failed: {}", name, status)),
+        }
+    }
+
+    async fn delete(&self, name: &str) -> Result<(), String> {
+        let response = self.send(Method::DELETE, name, Vec::new(), None).await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(()),
+            status if status.is_success() => Ok(()),
+            status => Err(format!("S3 delete of {} failed: {}", name, status)),
+        }
+    }
+}
@@ -0,0 +1,494 @@
+//! The access policy as one ordered table of rules. Each rule looks at the `Resolution` of an
+//! attempt, everything the handshake loop gathered about the door, the key and the moment, and
+//! either lets it through to the next rule or settles it. Rules touch neither the database nor
+//! the clock, so each can be tried on a hand-made resolution.
+//!
+//! Door-wide rules come first and apply to every key: a door taken out of service never opens,
+//! an active emergency override then settles every attempt, and a site or door lockdown declines
+//! everyone until it is lifted; a grant-all override is the one exception to a lockdown. An
+//! entry door of a site at its maximum occupancy declines everyone too. The key's own rules
+//! follow. A key they keep out may still come in on a guest pass, which is only
+//! resolved while it has uses left and is inside its window.
+
+use crate::access_events::{Decision, Resolution};
+use crate::database::helpers::{DenyReason, Door};
+use crate::emergency::OverrideMode;
+
+use chrono::{DateTime, Datelike};
+use chrono_tz::Tz;
+use tracing::debug;
+
+/// What a rule makes of an attempt.
+pub enum Verdict {
+    /// Nothing against it, on to the next rule.
+    Pass,
+    /// Settled, whoever the key is.
+    Decide(Decision),
+    /// The key's own rules keep it out, unless it holds a guest pass.
+    KeyDenied(DenyReason),
+}
+
+/// One rule of the policy, named for logs and tests.
+pub struct Rule {
+    pub name: &'static str,
+    pub check: fn(&Resolution) -> Verdict,
+}
+
+/// The policy, in the order the rules apply. The first rule that does not pass decides.
+pub const RULES: &[Rule] = &[
+    Rule {
+        name: "door_registered",
+        check: door_registered,
+    },
+    Rule {
+        name: "door_enabled",
+        check: door_enabled,
+    },
+    Rule {
+        name: "emergency_override",
+        check: emergency_override,
+    },
+    Rule {
+        name: "site_lockdown",
+        check: site_lockdown,
+    },
+    Rule {
+        name: "door_lockdown",
+        check: door_lockdown,
+    },
+    Rule {
+        name: "site_not_full",
+        check: site_not_full,
+    },
+    Rule {
+        name: "key_known",
+        check: key_known,
+    },
+    Rule {
+        name: "key_enabled",
+        check: key_enabled,
+    },
+    Rule {
+        name: "key_not_expired",
+        check: key_not_expired,
+    },
+    Rule {
+        name: "key_in_schedule",
+        check: key_in_schedule,
+    },
+    Rule {
+        name: "key_allowed_on_door",
+        check: key_allowed_on_door,
+    },
+];
+
+/// Whether the attempt may go on to Portal authentication, and on what grant. A regular key on
+/// a door with a price has to pay it before the door unlocks.
+pub fn evaluate(resolution: &Resolution) -> Decision {
+    for rule in RULES {
+        let decision = match (rule.check)(resolution) {
+            Verdict::Pass => continue,
+            Verdict::Decide(decision) => decision,
+            Verdict::KeyDenied(_) if resolution.guest_pass.is_some() => Decision::Guest,
+            Verdict::KeyDenied(reason) => Decision::Deny { reason },
+        };
+        debug!(rule = rule.name, ?decision, "Policy rule decided");
+        return decision;
+    }
+    Decision::Allow {
+        price_sats: resolution.door.as_ref().and_then(|door| door.price_sats),
+    }
+}
+
+/// Why the key's own rules keep it out of the door, if they do; door-wide rules are left out.
+/// Decides whether its guest pass has to be looked up.
+pub fn key_denial(resolution: &Resolution) -> Option<DenyReason> {
+    RULES
+        .iter()
+        .find_map(|rule| match (rule.check)(resolution) {
+            Verdict::KeyDenied(reason) => Some(reason),
+            _ => None,
+        })
+}
+
+/// The permissions the door requires that the wallet did not grant. Checked once Portal
+/// authentication reports what was granted; a card grants none.
+pub fn missing_permissions<'a>(door: &'a Door, granted: &[String]) -> Vec<&'a str> {
+    door.required_permissions
+        .iter()
+        .filter(|permission| !granted.contains(permission))
+        .map(String::as_str)
+        .collect()
+}
+
+fn deny(reason: DenyReason) -> Verdict {
+    Verdict::Decide(Decision::Deny { reason })
+}
+
+// The attempt's time on the door's wall clock, which key schedules are written in
+fn local_time(resolution: &Resolution) -> DateTime<Tz> {
+    let timezone: Tz = resolution.timezone.parse().unwrap_or(Tz::UTC);
+    resolution.at.with_timezone(&timezone)
+}
+
+fn door_registered(resolution: &Resolution) -> Verdict {
+    match resolution.door {
+        Some(_) => Verdict::Pass,
+        None => deny(DenyReason::DoorNotRegistered),
+    }
+}
+
+fn door_enabled(resolution: &Resolution) -> Verdict {
+    match &resolution.door {
+        Some(door) if !door.enabled => deny(DenyReason::DoorDisabled),
+        _ => Verdict::Pass,
+    }
+}
+
+fn emergency_override(resolution: &Resolution) -> Verdict {
+    match resolution.override_mode {
+        Some(OverrideMode::GrantAll) => Verdict::Decide(Decision::Allow { price_sats: None }),
+        Some(OverrideMode::DenyAll) => deny(DenyReason::EmergencyOverride),
+        None => Verdict::Pass,
+    }
+}
+
+fn site_lockdown(resolution: &Resolution) -> Verdict {
+    if resolution.site_lockdown {
+        deny(DenyReason::SiteLockdown)
+    } else {
+        Verdict::Pass
+    }
+}
+
+fn door_lockdown(resolution: &Resolution) -> Verdict {
+    match &resolution.door {
+        Some(door) if door.locked_down => deny(DenyReason::DoorLockdown),
+        _ => Verdict::Pass,
+    }
+}
+
+// An entry door of a full site lets nobody in until someone badges out or the count is reset
+fn site_not_full(resolution: &Resolution) -> Verdict {
+    match (&resolution.door, resolution.occupancy) {
+        (Some(door), Some(occupancy))
+            if door.direction.as_deref() == Some("entry") && occupancy.count >= occupancy.max =>
+        {
+            deny(DenyReason::OccupancyFull)
+        }
+        _ => Verdict::Pass,
+    }
+}
+
+fn key_known(resolution: &Resolution) -> Verdict {
+    match resolution.rules {
+        Some(_) => Verdict::Pass,
+        None => Verdict::KeyDenied(DenyReason::KeyUnknown),
+    }
+}
+
+fn key_enabled(resolution: &Resolution) -> Verdict {
+    match &resolution.rules {
+        Some(rules) if !rules.enabled => Verdict::KeyDenied(DenyReason::KeyDisabled),
+        _ => Verdict::Pass,
+    }
+}
+
+fn key_not_expired(resolution: &Resolution) -> Verdict {
+    match &resolution.rules {
+        Some(rules)
+            if rules
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= resolution.at) =>
+        {
+            Verdict::KeyDenied(DenyReason::KeyExpired)
+        }
+        _ => Verdict::Pass,
+    }
+}
+
+// A key with schedules only opens inside one of them
+fn key_in_schedule(resolution: &Resolution) -> Verdict {
+    let Some(rules) = &resolution.rules else {
+        return Verdict::Pass;
+    };
+    if rules.windows.is_empty() {
+        return Verdict::Pass;
+    }
+
+    let now = local_time(resolution);
+    let weekday = now.weekday().number_from_monday() as i16;
+    let time = now.time();
+    let in_schedule = rules.windows.iter().any(|window| {
+        window.weekdays.contains(&weekday) && window.start_time <= time && time < window.end_time
+    });
+    if in_schedule {
+        Verdict::Pass
+    } else {
+        Verdict::KeyDenied(DenyReason::OutsideSchedule)
+    }
+}
+
+// Directly or through one of the key's groups
+fn key_allowed_on_door(resolution: &Resolution) -> Verdict {
+    match (&resolution.rules, &resolution.door) {
+        (Some(rules), Some(door)) if !rules.door_ids.contains(&door.id) => {
+            Verdict::KeyDenied(DenyReason::DoorNotAllowed)
+        }
+        _ => Verdict::Pass,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_events::Occupancy;
+    use crate::database::helpers::{GuestPass, KeyRules, ScheduleWindow};
+
+    use chrono::{NaiveTime, TimeZone, Utc};
+    use uuid::Uuid;
+
+    // A Monday, 10:00 UTC
+    fn at() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2025, 6, 2, 10, 0, 0).unwrap()
+    }
+
+    // An enabled entry door, and a key allowed on it at any time
+    fn allowed() -> Resolution {
+        let door_id = Uuid::new_v4();
+        Resolution {
+            door: Some(Door {
+                id: door_id,
+                name: "Front".to_string(),
+                controller_door_id: 1,
+                enabled: true,
+                created_at: at(),
+                price_sats: None,
+                locked_down: false,
+                timezone: None,
+                unlock_seconds: None,
+                required_permissions: Vec::new(),
+                site_id: Uuid::new_v4(),
+                handshake_token: None,
+                handshake_rotate_secs: None,
+                handshake_single_use: false,
+                direction: Some("entry".to_string()),
+            }),
+            override_mode: None,
+            site_lockdown: false,
+            occupancy: None,
+            rules: Some(KeyRules {
+                enabled: true,
+                expires_at: None,
+                door_ids: [door_id].into(),
+                windows: Vec::new(),
+            }),
+            guest_pass: None,
+            at: at(),
+            timezone: "UTC".to_string(),
+        }
+    }
+
+    fn guest_pass() -> GuestPass {
+        GuestPass {
+            id: Uuid::new_v4(),
+            label: "Visitor".to_string(),
+            npub: None,
+            remaining_uses: Some(1),
+            uses: 0,
+            expires_at: None,
+            handshake_url: None,
+            created_by: "admin".to_string(),
+            created_at: at(),
+            last_used_at: None,
+            host: None,
+            email: None,
+            valid_from: None,
+            usable: true,
+            upcoming: false,
+        }
+    }
+
+    fn door(resolution: &mut Resolution) -> &mut Door {
+        resolution.door.as_mut().unwrap()
+    }
+
+    fn rules(resolution: &mut Resolution) -> &mut KeyRules {
+        resolution.rules.as_mut().unwrap()
+    }
+
+    fn window(weekdays: Vec<i16>, start: u32, end: u32) -> ScheduleWindow {
+        ScheduleWindow {
+            weekdays,
+            start_time: NaiveTime::from_hms_opt(start, 0, 0).unwrap(),
+            end_time: NaiveTime::from_hms_opt(end, 0, 0).unwrap(),
+        }
+    }
+
+    fn denied(reason: DenyReason) -> Decision {
+        Decision::Deny { reason }
+    }
+
+    #[test]
+    fn each_rule_names_its_reason() {
+        let free = Decision::Allow { price_sats: None };
+        assert_eq!(evaluate(&allowed()), free);
+
+        let mut paid = allowed();
+        door(&mut paid).price_sats = Some(21);
+        assert_eq!(
+            evaluate(&paid),
+            Decision::Allow {
+                price_sats: Some(21)
+            }
+        );
+
+        let mut unregistered = allowed();
+        unregistered.door = None;
+        assert_eq!(
+            evaluate(&unregistered),
+            denied(DenyReason::DoorNotRegistered)
+        );
+
+        let mut disabled = allowed();
+        door(&mut disabled).enabled = false;
+        assert_eq!(evaluate(&disabled), denied(DenyReason::DoorDisabled));
+
+        let mut deny_all = allowed();
+        deny_all.override_mode = Some(OverrideMode::DenyAll);
+        assert_eq!(evaluate(&deny_all), denied(DenyReason::EmergencyOverride));
+
+        let mut site_lockdown = allowed();
+        site_lockdown.site_lockdown = true;
+        assert_eq!(evaluate(&site_lockdown), denied(DenyReason::SiteLockdown));
+
+        let mut door_lockdown = allowed();
+        door(&mut door_lockdown).locked_down = true;
+        assert_eq!(evaluate(&door_lockdown), denied(DenyReason::DoorLockdown));
+
+        let mut full = allowed();
+        full.occupancy = Some(Occupancy { count: 5, max: 5 });
+        assert_eq!(evaluate(&full), denied(DenyReason::OccupancyFull));
+        full.occupancy = Some(Occupancy { count: 4, max: 5 });
+        assert_eq!(evaluate(&full), free);
+        full.occupancy = Some(Occupancy { count: 5, max: 5 });
+        door(&mut full).direction = Some("exit".to_string());
+        assert_eq!(evaluate(&full), free);
+
+        let mut unknown = allowed();
+        unknown.rules = None;
+        assert_eq!(evaluate(&unknown), denied(DenyReason::KeyUnknown));
+
+        let mut key_disabled = allowed();
+        rules(&mut key_disabled).enabled = false;
+        assert_eq!(evaluate(&key_disabled), denied(DenyReason::KeyDisabled));
+
+        let mut expired = allowed();
+        rules(&mut expired).expires_at = Some(at());
+        assert_eq!(evaluate(&expired), denied(DenyReason::KeyExpired));
+        rules(&mut expired).expires_at = Some(at() + chrono::Duration::seconds(1));
+        assert_eq!(evaluate(&expired), free);
+
+        let mut other_door = allowed();
+        rules(&mut other_door).door_ids = [Uuid::new_v4()].into();
+        assert_eq!(evaluate(&other_door), denied(DenyReason::DoorNotAllowed));
+    }
+
+    #[test]
+    fn schedules_follow_the_door_clock() {
+        let free = Decision::Allow { price_sats: None };
+
+        let mut scheduled = allowed();
+        rules(&mut scheduled).windows = vec![window(vec![1], 9, 17)];
+        assert_eq!(evaluate(&scheduled), free);
+
+        rules(&mut scheduled).windows = vec![window(vec![2, 3], 9, 17), window(vec![1], 11, 17)];
+        assert_eq!(evaluate(&scheduled), denied(DenyReason::OutsideSchedule));
+
+        // 10:00 UTC is 19:00 in Tokyo
+        rules(&mut scheduled).windows = vec![window(vec![1], 9, 17)];
+        scheduled.timezone = "Asia/Tokyo".to_string();
+        assert_eq!(evaluate(&scheduled), denied(DenyReason::OutsideSchedule));
+        rules(&mut scheduled).windows = vec![window(vec![1], 18, 20)];
+        assert_eq!(evaluate(&scheduled), free);
+    }
+
+    #[test]
+    fn the_first_rule_that_does_not_pass_decides() {
+        // A door out of service stays shut even under a grant-all override
+        let mut disabled = allowed();
+        door(&mut disabled).enabled = false;
+        disabled.override_mode = Some(OverrideMode::GrantAll);
+        assert_eq!(evaluate(&disabled), denied(DenyReason::DoorDisabled));
+
+        // A grant-all override lets anyone in, through lockdowns, a full site and key rules
+        let mut grant_all = allowed();
+        grant_all.override_mode = Some(OverrideMode::GrantAll);
+        grant_all.site_lockdown = true;
+        door(&mut grant_all).locked_down = true;
+        door(&mut grant_all).price_sats = Some(21);
+        grant_all.occupancy = Some(Occupancy { count: 5, max: 5 });
+        grant_all.rules = None;
+        assert_eq!(evaluate(&grant_all), Decision::Allow { price_sats: None });
+
+        let mut site_first = allowed();
+        site_first.site_lockdown = true;
+        door(&mut site_first).locked_down = true;
+        site_first.occupancy = Some(Occupancy { count: 5, max: 5 });
+        assert_eq!(evaluate(&site_first), denied(DenyReason::SiteLockdown));
+
+        let mut full_first = allowed();
+        full_first.occupancy = Some(Occupancy { count: 5, max: 5 });
+        full_first.rules = None;
+        assert_eq!(evaluate(&full_first), denied(DenyReason::OccupancyFull));
+
+        let mut key_first = allowed();
+        rules(&mut key_first).enabled = false;
+        rules(&mut key_first).expires_at = Some(at());
+        rules(&mut key_first).door_ids.clear();
+        assert_eq!(evaluate(&key_first), denied(DenyReason::KeyDisabled));
+        assert_eq!(key_denial(&key_first), Some(DenyReason::KeyDisabled));
+    }
+
+    #[test]
+    fn a_guest_pass_only_stands_in_for_the_key() {
+        let mut unknown = allowed();
+        unknown.rules = None;
+        assert_eq!(key_denial(&unknown), Some(DenyReason::KeyUnknown));
+        unknown.guest_pass = Some(guest_pass());
+        assert_eq!(evaluate(&unknown), Decision::Guest);
+
+        let mut other_door = allowed();
+        rules(&mut other_door).door_ids.clear();
+        other_door.guest_pass = Some(guest_pass());
+        assert_eq!(evaluate(&other_door), Decision::Guest);
+
+        // Door-wide rules turn the guest away all the same
+        let mut locked_down = unknown.clone();
+        door(&mut locked_down).locked_down = true;
+        assert_eq!(key_denial(&locked_down), Some(DenyReason::KeyUnknown));
+        assert_eq!(evaluate(&locked_down), denied(DenyReason::DoorLockdown));
+
+        // A key that is let in does not use up the pass
+        let mut member = allowed();
+        member.guest_pass = Some(guest_pass());
+        assert_eq!(key_denial(&member), None);
+        assert_eq!(evaluate(&member), Decision::Allow { price_sats: None });
+    }
+
+    #[test]
+    fn only_permissions_the_wallet_did_not_grant_are_missing() {
+        let mut resolution = allowed();
+        door(&mut resolution).required_permissions =
+            vec!["member".to_string(), "staff".to_string()];
+        let door = resolution.door.unwrap();
+        assert_eq!(
+            missing_permissions(&door, &["staff".to_string()]),
+            ["member"]
+        );
+        assert!(
+            missing_permissions(&door, &["member".to_string(), "staff".to_string()]).is_empty()
+        );
+        assert_eq!(missing_permissions(&door, &[]), ["member", "staff"]);
+    }
+}
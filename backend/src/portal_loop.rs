@@ -0,0 +1,1141 @@
+use crate::access_events::{Attempt, Decision, EventLog, PipelineEvent, Resolution};
+use crate::admin_feed::{AdminEvent, AdminFeed};
+use crate::alerts::{Alert, AlertKind, Alerter};
+use crate::database::helpers::{
+    AccessResult, DenyReason, Door, GuestPass, NewAccessLog, claim_guest_pass, consume_guest_pass,
+    disable_key_by_npub, enroll_key, get_credential_npub, get_door_by_controller_id,
+    get_open_guest_passes, get_usable_guest_pass, insert_access_log, insert_doorbell_ring,
+    set_guest_pass_handshake_url,
+};
+use crate::door_backend::{DoorBackend, UnlockRetry};
+use crate::emergency::OverrideState;
+use crate::key_cache::KeyCache;
+use crate::live_logs::LogFeed;
+use crate::policy;
+use crate::profiles;
+use crate::rate_limit::{Debouncer, RateDecision, RateLimiter};
+use crate::relays::{Authentication, PortalClient};
+use crate::relock::RelockVerifier;
+use crate::webhooks::{AccessEvent, WebhookDispatcher};
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use portal::nostr::PublicKey;
+use portal::nostr::nips::nip19::ToBech32;
+use portal::protocol::model::Timestamp;
+use portal::protocol::model::payment::{Currency, PaymentStatus, SinglePaymentRequestContent};
+use rocket::fairing::{AdHoc, Fairing};
+use rocket::futures::StreamExt;
+use rocket::tokio::{
+    self,
+    sync::{broadcast, watch},
+    task::JoinHandle,
+};
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{Instrument, error, info, info_span, warn};
+use uuid::Uuid;
+
+// Restart backoff for a crashed loop: doubles from the initial delay up to the cap, and
+// resets once the loop has stayed up for RESTART_RESET
+const RESTART_BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+const RESTART_RESET: Duration = Duration::from_secs(300);
+
+// How long shutdown waits for an in-flight handshake before abandoning it
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+// How long the doorbell waits on the relays for an unknown visitor's profile
+const DOORBELL_PROFILE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether the handshake loop currently holds a working relay connection: set when a
+/// handshake URL is created, cleared when creating one fails.
+#[derive(Clone, Default)]
+pub struct RelayHealth(Arc<AtomicBool>);
+
+impl RelayHealth {
+    pub fn is_connected(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_connected(&self, connected: bool) {
+        let was_connected = self.0.swap(connected, Ordering::Relaxed);
+        if was_connected && !connected {
+            warn!("Relay connection lost");
+        }
+    }
+}
+
+// Changes a slow subscriber may fall behind by before it skips ahead
+const HANDSHAKE_URL_CHANGES: usize = 64;
+
+/// The key handshake URL each served door's loop is currently listening on, keyed by
+/// controller door number, for the QR code endpoints. `None` until the loop has created one,
+/// and again while it cannot. Every change is announced through `subscribe`, so kiosk displays
+/// can show a new code as soon as it exists.
+#[derive(Clone)]
+pub struct HandshakeUrls {
+    urls: Arc<RwLock<HashMap<u32, Option<String>>>>,
+    changes: broadcast::Sender<(u32, Option<String>)>,
+}
+
+impl Default for HandshakeUrls {
+    fn default() -> Self {
+        Self {
+            urls: Arc::default(),
+            changes: broadcast::channel(HANDSHAKE_URL_CHANGES).0,
+        }
+    }
+}
+
+impl HandshakeUrls {
+    /// `None` for a door no loop serves, `Some(None)` while its URL is unavailable.
+    pub fn get(&self, door_id: u32) -> Option<Option<String>> {
+        self.urls
+            .read()
+            .expect("Handshake URL lock poisoned")
+            .get(&door_id)
+            .cloned()
+    }
+
+    pub fn serve(&self, door_id: u32) {
+        self.set(door_id, None);
+    }
+
+    pub(crate) fn set(&self, door_id: u32, url: Option<String>) {
+        let previous = self
+            .urls
+            .write()
+            .expect("Handshake URL lock poisoned")
+            .insert(door_id, url.clone());
+        if previous.as_ref() != Some(&url) {
+            let _ = self.changes.send((door_id, url));
+        }
+    }
+
+    /// Every served door with its current URL.
+    pub fn all(&self) -> HashMap<u32, Option<String>> {
+        self.urls
+            .read()
+            .expect("Handshake URL lock poisoned")
+            .clone()
+    }
+
+    /// Each door's URL as it changes.
+    pub fn subscribe(&self) -> broadcast::Receiver<(u32, Option<String>)> {
+        self.changes.subscribe()
+    }
+}
+
+// How often a static handshake URL checks whether the door's handshake settings changed
+const HANDSHAKE_SETTINGS_CHECK: Duration = Duration::from_secs(60);
+
+/// How a door's handshake URL is made, from `doors.handshake_token`,
+/// `doors.handshake_rotate_secs` and `doors.handshake_single_use`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct HandshakeSettings {
+    token: Option<String>,
+    rotate: Option<Duration>,
+    single_use: bool,
+}
+
+impl HandshakeSettings {
+    // A rotating or single-use URL gets a random token each time; a static one keeps the door's
+    // token, or the subject it is served under
+    fn static_token(&self, subject: &str) -> Option<String> {
+        if self.rotate.is_some() || self.single_use {
+            return None;
+        }
+        Some(self.token.clone().unwrap_or_else(|| subject.to_string()))
+    }
+
+    fn recheck_after(&self) -> Duration {
+        self.rotate.unwrap_or(HANDSHAKE_SETTINGS_CHECK)
+    }
+}
+
+/// Everything the handshake loop needs to turn a Portal notification into a door unlock. There
+/// is one per served door, each listening on its own handshake `subject`.
+#[derive(Clone)]
+pub struct AccessController {
+    pub pool: Pool<Postgres>,
+    pub overrides: OverrideState,
+    pub portal: Arc<dyn PortalClient>,
+    pub door: Arc<dyn DoorBackend>,
+    pub door_id: u32,
+    pub subject: String,
+    pub webhooks: WebhookDispatcher,
+    pub alerts: Alerter,
+    pub rate_limiter: RateLimiter,
+    pub debouncer: Debouncer,
+    pub log_feed: LogFeed,
+    pub admin_feed: AdminFeed,
+    pub payment_timeout: Duration,
+    pub unlock_retry: UnlockRetry,
+    pub relay_health: RelayHealth,
+    pub handshake_urls: HandshakeUrls,
+    /// Site timezone, for doors without one of their own.
+    pub timezone: Tz,
+    /// With open enrollment, how many self-enrolled keys may wait for an admin at once.
+    pub enrollment_limit: Option<i64>,
+    /// With the doorbell on, how long the admins have to let in a visitor whose key may not
+    /// open the door.
+    pub doorbell_answer: Option<Duration>,
+    pub key_cache: KeyCache,
+    pub events: EventLog,
+    pub relock: RelockVerifier,
+}
+
+impl AccessController {
+    /// Long-running handshake/notification loop. Returns once `shutdown` flips to true; a
+    /// handshake already being handled is always finished first.
+    pub async fn run(self, mut shutdown: watch::Receiver<bool>) {
+        info!(door_id = self.door_id, subject = %self.subject, "Portal SDK background task started, waiting for authentication requests");
+        while !*shutdown.borrow() {
+            // Create a handshake URL and receive a notifications stream
+            let settings = self.handshake_settings().await.unwrap_or_else(|e| {
+                warn!(door_id = self.door_id, error = ?e, "Failed to load handshake settings, using the static subject");
+                HandshakeSettings::default()
+            });
+            let static_token = settings.static_token(&self.subject);
+            let handshake = tokio::select! {
+                handshake = self.portal.key_handshakes(static_token) => handshake,
+                _ = shutdown.changed() => break,
+            };
+
+            match handshake {
+                Ok((key_handshake_url, mut notifications)) => {
+                    self.relay_health.set_connected(true);
+                    self.handshake_urls
+                        .set(self.door_id, Some(key_handshake_url.clone()));
+                    info!(door_id = self.door_id, url = %key_handshake_url, "Key handshake URL created");
+
+                    // A rotating URL is replaced when its time is up, a static one when an
+                    // admin changes the door's handshake settings
+                    let recheck = tokio::time::sleep(settings.recheck_after());
+                    tokio::pin!(recheck);
+
+                    // Process notification stream until it ends, errors out or we shut down
+                    loop {
+                        let notification_result = tokio::select! {
+                            notification = notifications.next() => notification,
+                            _ = &mut recheck => {
+                                if settings.rotate.is_some() {
+                                    info!(door_id = self.door_id, "Rotating handshake URL");
+                                    break;
+                                }
+                                if self.handshake_settings().await.is_ok_and(|current| current != settings) {
+                                    info!(door_id = self.door_id, "Handshake settings changed, re-creating handshake URL");
+                                    break;
+                                }
+                                recheck.as_mut().reset(tokio::time::Instant::now() + settings.recheck_after());
+                                continue;
+                            }
+                            _ = shutdown.changed() => return,
+                        };
+
+                        match notification_result {
+                            None => {
+                                // Loop will recreate a new handshake URL
+                                info!(
+                                    door_id = self.door_id,
+                                    "Notification stream ended, re-creating handshake URL"
+                                );
+                                break;
+                            }
+                            Some(Err(e)) => {
+                                error!(error = %e, "Notification error");
+                                // continue to wait for next notification or recreate handshake if stream ended
+                                continue;
+                            }
+                            Some(Ok(main_key)) => {
+                                self.handle_handshake(main_key).await;
+                                // Whatever came in on this URL, nothing else will
+                                if settings.single_use {
+                                    info!(
+                                        door_id = self.door_id,
+                                        "Handshake URL used, re-creating it"
+                                    );
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    // Creating handshake URL failed; back off a bit and retry.
+                    self.relay_health.set_connected(false);
+                    self.handshake_urls.set(self.door_id, None);
+                    error!(door_id = self.door_id, error = %e, "Failed to create handshake URL");
+                    tokio::select! {
+                        _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+                        _ = shutdown.changed() => break,
+                    }
+                }
+            }
+        }
+    }
+
+    // The door's handshake settings; a door missing from the table keeps the static subject
+    async fn handshake_settings(&self) -> Result<HandshakeSettings, sqlx::Error> {
+        let context = self.key_cache.door(&self.pool, self.door_id as i32).await?;
+        Ok(context
+            .door
+            .as_ref()
+            .map(|door| HandshakeSettings {
+                token: door.handshake_token.clone(),
+                rotate: door
+                    .handshake_rotate_secs
+                    .map(|secs| Duration::from_secs(secs.max(1) as u64)),
+                single_use: door.handshake_single_use,
+            })
+            .unwrap_or_default())
+    }
+
+    // Every event logged while handling a handshake carries the npub through the span
+    async fn handle_handshake(&self, pub_key: PublicKey) {
+        let attempt = Attempt::new(pub_key.to_bech32().expect("Infallible"));
+        let span = info_span!(
+            "handshake",
+            npub = %attempt.npub,
+            attempt = %attempt.id,
+            door_id = self.door_id
+        );
+        self.process_handshake(pub_key, &attempt)
+            .instrument(span)
+            .await
+    }
+
+    async fn process_handshake(&self, pub_key: PublicKey, attempt: &Attempt) {
+        info!("Handshake received");
+        self.emit(attempt, PipelineEvent::HandshakeReceived);
+        let npub = attempt.npub.as_str();
+        let Some((resolution, decision)) = self.screen(attempt).await else {
+            return;
+        };
+
+        let (door, guest_pass, price_sats) = match (decision, resolution.door) {
+            (Decision::Allow { price_sats }, Some(door)) => {
+                info!("Access granted, proceeding with authentication");
+                (door, None, price_sats)
+            }
+            (Decision::Guest, Some(door)) => {
+                let pass = resolution.guest_pass;
+                info!(
+                    guest_pass = pass.as_ref().map(|pass| pass.label.as_str()),
+                    "Guest pass accepted, proceeding with authentication"
+                );
+                (door, pass, None)
+            }
+            (Decision::Deny { reason }, _) => {
+                info!(
+                    reason = reason.as_str(),
+                    "Access denied, skipping authentication"
+                );
+                let detail = match reason {
+                    DenyReason::KeyUnknown => self.enroll(npub).await,
+                    _ => None,
+                };
+                self.refuse(attempt, AccessResult::Denied, reason, detail)
+                    .await;
+                if matches!(
+                    reason,
+                    DenyReason::KeyUnknown | DenyReason::KeyDisabled | DenyReason::KeyExpired
+                ) {
+                    self.ring_doorbell(npub, reason);
+                }
+                return;
+            }
+            // Only a registered door is ever allowed
+            (_, None) => return,
+        };
+
+        // Authenticate the key obtained from the notification
+        match self.portal.authenticate(pub_key).await {
+            Ok(Authentication::Approved(granted)) => {
+                info!(?granted, "Authentication successful");
+                self.emit(
+                    attempt,
+                    PipelineEvent::Authenticated {
+                        approved: true,
+                        granted_permissions: granted.clone(),
+                        detail: None,
+                    },
+                );
+                let missing = policy::missing_permissions(&door, &granted);
+                if !missing.is_empty() {
+                    info!(?missing, "Required permissions not granted");
+                    let detail = format!("missing permissions: {}", missing.join(", "));
+                    self.record_entry(
+                        attempt,
+                        AccessResult::Declined,
+                        Some(DenyReason::MissingPermissions),
+                        Some(&detail),
+                        Some(&granted),
+                    )
+                    .await;
+                    return;
+                }
+
+                let reason = match &guest_pass {
+                    Some(pass) => match self.use_guest_pass(attempt, pass).await {
+                        Some(reason) => Some(reason),
+                        None => return,
+                    },
+                    None => None,
+                };
+                if let Some(price_sats) = price_sats
+                    && !self.collect_payment(pub_key, attempt, price_sats).await
+                {
+                    return;
+                }
+                self.unlock(attempt, &door, &granted, reason.as_deref())
+                    .await;
+            }
+            Ok(Authentication::Declined(reason)) => {
+                info!(reason = reason.as_deref(), "Authentication declined");
+                // The user's own reason, when their wallet gave one, goes in the log as well
+                let detail = reason.map(|reason| format!("authentication declined: {}", reason));
+                self.emit(
+                    attempt,
+                    PipelineEvent::Authenticated {
+                        approved: false,
+                        granted_permissions: Vec::new(),
+                        detail: detail.clone(),
+                    },
+                );
+                self.refuse(
+                    attempt,
+                    AccessResult::Declined,
+                    DenyReason::AuthenticationDeclined,
+                    detail.as_deref(),
+                )
+                .await;
+            }
+            Err(e) => {
+                error!(error = %e, "Authentication error");
+                self.emit(
+                    attempt,
+                    PipelineEvent::Authenticated {
+                        approved: false,
+                        granted_permissions: Vec::new(),
+                        detail: Some(e),
+                    },
+                );
+                self.record(attempt, AccessResult::Error, Some("authentication error"))
+                    .await;
+            }
+        }
+    }
+
+    /// Handles a card presented at this door's badge reader, see `card_readers`. The card stands
+    /// in for its key through the same decision as a handshake; a card can neither approve an
+    /// authentication nor pay, so doors asking for wallet permissions or a price stay locked.
+    /// Unknown cards are logged under `card:<number>`.
+    pub async fn handle_card(&self, card_number: String) {
+        let lookup = get_credential_npub(&self.pool, &card_number).await;
+        let npub = match &lookup {
+            Ok(Some(npub)) => npub.clone(),
+            _ => format!("card:{}", card_number),
+        };
+        let attempt = Attempt::card(npub, card_number);
+        let span = info_span!(
+            "card",
+            card = attempt.credential.as_deref(),
+            npub = %attempt.npub,
+            attempt = %attempt.id,
+            door_id = self.door_id
+        );
+        self.process_card(lookup, &attempt).instrument(span).await
+    }
+
+    async fn process_card(&self, lookup: Result<Option<String>, sqlx::Error>, attempt: &Attempt) {
+        info!("Card presented");
+        self.emit(
+            attempt,
+            PipelineEvent::CardPresented {
+                card_number: attempt.credential.clone().unwrap_or_default(),
+                known: matches!(lookup, Ok(Some(_))),
+            },
+        );
+        if let Err(e) = lookup {
+            error!(error = ?e, "Database error checking card");
+            self.record(
+                attempt,
+                AccessResult::Error,
+                Some("database error checking card"),
+            )
+            .await;
+            return;
+        }
+        // An unknown card has no rules under its stand-in npub, so it is denied as unknown
+        let Some((resolution, decision)) = self.screen(attempt).await else {
+            return;
+        };
+
+        let (door, guest_pass) = match (decision, resolution.door) {
+            (
+                Decision::Allow {
+                    price_sats: Some(_),
+                },
+                Some(_),
+            ) => {
+                info!("Door has a price, which a card cannot pay");
+                self.record(
+                    attempt,
+                    AccessResult::Unpaid,
+                    Some("the door has a price, which a card cannot pay"),
+                )
+                .await;
+                return;
+            }
+            (Decision::Allow { price_sats: None }, Some(door)) => (door, None),
+            (Decision::Guest, Some(door)) => (door, resolution.guest_pass),
+            (Decision::Deny { reason }, _) => {
+                info!(reason = reason.as_str(), "Card denied");
+                let detail = (reason == DenyReason::KeyUnknown).then_some("card not registered");
+                self.refuse(attempt, AccessResult::Denied, reason, detail)
+                    .await;
+                return;
+            }
+            (_, None) => return,
+        };
+
+        if !policy::missing_permissions(&door, &[]).is_empty() {
+            info!("Door requires permissions, which a card cannot grant");
+            self.refuse(
+                attempt,
+                AccessResult::Declined,
+                DenyReason::MissingPermissions,
+                Some("the door requires permissions, which a card cannot grant"),
+            )
+            .await;
+            return;
+        }
+        let reason = match &guest_pass {
+            Some(pass) => match self.use_guest_pass(attempt, pass).await {
+                Some(reason) => Some(reason),
+                None => return,
+            },
+            None => None,
+        };
+        self.unlock(attempt, &door, &[], reason.as_deref()).await;
+    }
+
+    // Debounces, rate limits and decides an attempt; `None` once it has been recorded as going
+    // no further
+    async fn screen(&self, attempt: &Attempt) -> Option<(Resolution, Decision)> {
+        let npub = attempt.npub.as_str();
+
+        // Duplicates of a handshake or card just handled don't count against the rate limit either
+        if !self.debouncer.check(npub) {
+            info!("Duplicate handshake debounced");
+            self.record(
+                attempt,
+                AccessResult::Debounced,
+                Some("repeat within debounce window"),
+            )
+            .await;
+            return None;
+        }
+
+        // Over-limit attempts never reach Portal authentication
+        if let RateDecision::Limited { disable_key } = self.rate_limiter.check(npub) {
+            warn!("Rate limit exceeded");
+            self.record(
+                attempt,
+                AccessResult::RateLimited,
+                Some("rate limit exceeded"),
+            )
+            .await;
+            if disable_key {
+                self.disable_abusive_key(attempt).await;
+            }
+            return None;
+        }
+
+        let (resolution, decision) = match self.evaluate(npub, Utc::now()).await {
+            Ok(evaluated) => evaluated,
+            Err(e) => {
+                // Database error - log and skip
+                error!(error = ?e, "Database error checking key");
+                self.record(
+                    attempt,
+                    AccessResult::Error,
+                    Some("database error checking key"),
+                )
+                .await;
+                return None;
+            }
+        };
+        self.emit(
+            attempt,
+            PipelineEvent::KeyResolved {
+                resolution: Box::new(resolution.clone()),
+            },
+        );
+        self.emit(
+            attempt,
+            PipelineEvent::Decided {
+                decision: decision.clone(),
+            },
+        );
+        Some((resolution, decision))
+    }
+
+    /// Decides whether `npub` may go on to Portal authentication at this door at `at`, with
+    /// what the decision was made from.
+    pub async fn evaluate(
+        &self,
+        npub: &str,
+        at: DateTime<Utc>,
+    ) -> Result<(Resolution, Decision), sqlx::Error> {
+        let resolution = self.resolve(npub, at).await?;
+        let decision = policy::evaluate(&resolution);
+        Ok((resolution, decision))
+    }
+
+    // Gathers what the decision is made from, see `policy::evaluate`. The door and the key's
+    // rules come from the cache; its guest pass is only looked up when the rules keep it out.
+    async fn resolve(&self, npub: &str, at: DateTime<Utc>) -> Result<Resolution, sqlx::Error> {
+        let context = self.key_cache.door(&self.pool, self.door_id as i32).await?;
+        let override_mode = self.overrides.current().await.map(|active| {
+            warn!(
+                mode = ?active.mode,
+                triggered_by = %active.triggered_by,
+                expires_at = %active.expires_at,
+                "Emergency override applied"
+            );
+            active.mode
+        });
+        let timezone = context
+            .door
+            .as_ref()
+            .and_then(|door| door.timezone.as_deref())
+            .and_then(|timezone| timezone.parse::<Tz>().ok())
+            .unwrap_or(self.timezone);
+
+        let mut resolution = Resolution {
+            door: context.door.clone(),
+            override_mode,
+            site_lockdown: context.site_lockdown,
+            occupancy: context.occupancy,
+            rules: self
+                .key_cache
+                .rules(&self.pool, npub)
+                .await?
+                .map(|rules| rules.as_ref().clone()),
+            guest_pass: None,
+            at,
+            timezone: timezone.name().to_string(),
+        };
+        if policy::key_denial(&resolution).is_some() {
+            resolution.guest_pass = get_usable_guest_pass(&self.pool, npub).await?;
+        }
+        Ok(resolution)
+    }
+
+    // Takes one use off the pass; returns the reason to log with the unlock, or None when the
+    // pass ran out in the meantime (the attempt is then already recorded as denied)
+    async fn use_guest_pass(&self, attempt: &Attempt, pass: &GuestPass) -> Option<String> {
+        match consume_guest_pass(&self.pool, pass.id).await {
+            Ok(true) => {
+                info!(guest_pass = %pass.label, host = pass.host.as_deref(), "Guest pass used");
+                // A pre-registered visitor is logged with the person they came to see
+                Some(match &pass.host {
+                    Some(host) => format!("guest pass: {}, visiting {}", pass.label, host),
+                    None => format!("guest pass: {}", pass.label),
+                })
+            }
+            Ok(false) => {
+                info!(guest_pass = %pass.label, "Guest pass expired or used up");
+                self.refuse(
+                    attempt,
+                    AccessResult::Denied,
+                    DenyReason::GuestPassExpired,
+                    None,
+                )
+                .await;
+                None
+            }
+            Err(e) => {
+                error!(error = ?e, "Database error using guest pass");
+                self.record(
+                    attempt,
+                    AccessResult::Error,
+                    Some("database error using guest pass"),
+                )
+                .await;
+                None
+            }
+        }
+    }
+
+    // Asks the key's wallet for the door's price and waits for the payment to settle; returns
+    // whether the door may unlock. Anything short of a settled payment is logged as unpaid.
+    async fn collect_payment(
+        &self,
+        pub_key: PublicKey,
+        attempt: &Attempt,
+        price_sats: i64,
+    ) -> bool {
+        info!(price_sats, "Requesting payment");
+        let request = SinglePaymentRequestContent {
+            amount: price_sats as u64 * 1000,
+            currency: Currency::Millisats,
+            current_exchange_rate: None,
+            expires_at: Timestamp::now_plus_seconds(self.payment_timeout.as_secs()),
+            subscription_id: None,
+            auth_token: None,
+            request_id: Uuid::new_v4().to_string(),
+            description: Some(format!("Access to door {}", self.door_id)),
+        };
+
+        let mut responses = match self.portal.request_payment(pub_key, request).await {
+            Ok(responses) => responses,
+            Err(e) => {
+                error!(error = %e, "Payment request error");
+                self.emit(
+                    attempt,
+                    PipelineEvent::Paid {
+                        price_sats,
+                        paid: false,
+                        detail: Some(e),
+                    },
+                );
+                self.record(attempt, AccessResult::Error, Some("payment request error"))
+                    .await;
+                return false;
+            }
+        };
+
+        let outcome = tokio::time::timeout(self.payment_timeout, async {
+            while let Some(response) = responses.next().await {
+                match response {
+                    Ok(status) => match status {
+                        PaymentStatus::Success { .. } => return Ok(()),
+                        PaymentStatus::Rejected { .. } => return Err("payment rejected"),
+                        PaymentStatus::Failed { .. } => return Err("payment failed"),
+                        // Approved by the user but not settled yet
+                        _ => {}
+                    },
+                    Err(e) => error!(error = %e, "Payment notification error"),
+                }
+            }
+            Err("payment request closed")
+        })
+        .await
+        .unwrap_or(Err("payment timed out"));
+
+        self.emit(
+            attempt,
+            PipelineEvent::Paid {
+                price_sats,
+                paid: outcome.is_ok(),
+                detail: outcome.err().map(str::to_string),
+            },
+        );
+        match outcome {
+            Ok(()) => {
+                info!(price_sats, "Payment received");
+                true
+            }
+            Err(reason) => {
+                info!(reason, "No payment");
+                self.record(attempt, AccessResult::Unpaid, Some(reason))
+                    .await;
+                false
+            }
+        }
+    }
+
+    /// Opens a handshake URL dedicated to a guest pass that is not tied to an npub yet. The
+    /// first key to complete it claims the pass; every handshake then goes through the usual
+    /// checks. The listener stops when the pass expires or the notification stream ends.
+    pub async fn open_guest_handshake(&self, pass: &GuestPass) -> Result<String, String> {
+        let (url, mut notifications) = self
+            .portal
+            .key_handshakes(Some(format!("guest-{}", pass.id)))
+            .await
+            .map_err(|e| format!("failed to create handshake URL: {}", e))?;
+        if let Err(e) = set_guest_pass_handshake_url(&self.pool, pass.id, &url).await {
+            error!(error = ?e, "Failed to store guest pass handshake URL");
+        }
+
+        let controller = self.clone();
+        let pass_id = pass.id;
+        let expires_at = pass.expires_at;
+        tokio::spawn(async move {
+            let mut expired = std::pin::pin!(async {
+                match expires_at {
+                    Some(at) => {
+                        tokio::time::sleep((at - Utc::now()).to_std().unwrap_or_default()).await
+                    }
+                    None => std::future::pending().await,
+                }
+            });
+
+            loop {
+                let notification = tokio::select! {
+                    notification = notifications.next() => notification,
+                    _ = &mut expired => return,
+                };
+
+                match notification {
+                    None => return,
+                    Some(Err(e)) => error!(error = %e, "Guest pass notification error"),
+                    Some(Ok(main_key)) => {
+                        let npub = main_key.to_bech32().expect("Infallible");
+                        match claim_guest_pass(&controller.pool, pass_id, &npub).await {
+                            Ok(true) => info!(%pass_id, %npub, "Guest pass claimed"),
+                            Ok(false) => {}
+                            Err(e) => error!(error = ?e, "Failed to claim guest pass"),
+                        }
+                        controller.handle_handshake(main_key).await;
+                    }
+                }
+            }
+        });
+
+        Ok(url)
+    }
+
+    /// Reopens the handshake URLs of guest passes that are still usable, e.g. after a restart.
+    pub async fn resume_guest_handshakes(&self) {
+        let passes = match get_open_guest_passes(&self.pool).await {
+            Ok(passes) => passes,
+            Err(e) => {
+                error!(error = ?e, "Failed to load guest passes");
+                return;
+            }
+        };
+
+        for pass in passes {
+            if let Err(e) = self.open_guest_handshake(&pass).await {
+                error!(guest_pass = %pass.label, error = %e, "Failed to open guest pass handshake");
+            }
+        }
+    }
+
+    // A retried unlock, or one the secondary controller carried out, says so in the log
+    async fn unlock(
+        &self,
+        attempt: &Attempt,
+        door: &Door,
+        granted: &[String],
+        reason: Option<&str>,
+    ) {
+        let outcome = self
+            .unlock_retry
+            .unlock(self.door.as_ref(), self.door_id, door.unlock_duration())
+            .await;
+        self.emit(
+            attempt,
+            PipelineEvent::Actuated {
+                unlocked: outcome.result.is_ok(),
+                retries: outcome.retries,
+                controller: outcome.controller,
+                error: outcome.result.clone().err(),
+            },
+        );
+        if outcome.result.is_ok() {
+            self.relock.watch(door);
+        }
+        match (&outcome.result, outcome.note()) {
+            (Ok(()), Some(note)) => {
+                info!(
+                    retries = outcome.retries,
+                    controller = ?outcome.controller,
+                    "Door unlocked after a failure"
+                );
+                let reason = match reason {
+                    Some(reason) => format!("{}; {}", reason, note),
+                    None => note,
+                };
+                self.record_entry(
+                    attempt,
+                    AccessResult::Granted,
+                    None,
+                    Some(&reason),
+                    Some(granted),
+                )
+                .await;
+            }
+            (Ok(()), None) => {
+                info!("Door unlocked");
+                self.record_entry(attempt, AccessResult::Granted, None, reason, Some(granted))
+                    .await;
+            }
+            (Err(reason), _) => {
+                error!(%reason, "Door unlock failed");
+                self.record_entry(
+                    attempt,
+                    AccessResult::Error,
+                    None,
+                    Some(reason),
+                    Some(granted),
+                )
+                .await;
+            }
+        }
+    }
+
+    // Take a key out of service after repeated over-limit attempts; an admin re-enables it
+    async fn disable_abusive_key(&self, attempt: &Attempt) {
+        let npub = attempt.npub.as_str();
+        match disable_key_by_npub(&self.pool, npub).await {
+            Ok(true) => {
+                warn!("Key disabled after repeated rate limit violations");
+                self.alerts.notify(Alert::new(
+                    AlertKind::KeyDisabled,
+                    Some(self.door_id as i32),
+                    format!("{} was disabled after repeated rate limit violations", npub),
+                ));
+                self.record(
+                    attempt,
+                    AccessResult::RateLimited,
+                    Some("key disabled after repeated rate limit violations"),
+                )
+                .await;
+            }
+            Ok(false) => {}
+            Err(e) => error!(error = ?e, "Failed to disable key"),
+        }
+    }
+
+    // With open enrollment, adds an unknown key as pending on this door and fetches its profile;
+    // returns the detail to log when it was added
+    async fn enroll(&self, npub: &str) -> Option<&'static str> {
+        let limit = self.enrollment_limit?;
+        let door = match get_door_by_controller_id(&self.pool, self.door_id as i32).await {
+            Ok(door) => door?,
+            Err(e) => {
+                error!(error = ?e, "Failed to load door for enrollment");
+                return None;
+            }
+        };
+
+        match enroll_key(&self.pool, npub, door.id, limit).await {
+            Ok(Some(key_id)) => {
+                info!("Key enrolled, waiting for an admin to enable it");
+                profiles::spawn_fetch(
+                    self.pool.clone(),
+                    self.portal.clone(),
+                    vec![(key_id, npub.to_string())],
+                );
+                Some("enrolled, waiting for an admin to enable the key")
+            }
+            Ok(None) => {
+                info!("Key not enrolled: already known, requested or too many waiting");
+                None
+            }
+            Err(e) => {
+                error!(error = ?e, "Failed to enroll key");
+                None
+            }
+        }
+    }
+
+    // Shows the visitor to the admins watching the live channel, who may let them in once. Runs
+    // in the background, so looking up the profile of an unknown key does not hold up the door.
+    fn ring_doorbell(&self, npub: &str, reason: DenyReason) {
+        let Some(answer) = self.doorbell_answer else {
+            return;
+        };
+
+        let controller = self.clone();
+        let npub = npub.to_string();
+        let span = info_span!("doorbell", npub = %npub, door_id = self.door_id);
+        tokio::spawn(
+            async move {
+                // A known key shows the profile stored with it
+                let (name, picture_url) = match reason {
+                    DenyReason::KeyUnknown => profiles::lookup(
+                        controller.portal.as_ref(),
+                        &npub,
+                        DOORBELL_PROFILE_TIMEOUT,
+                    )
+                    .await
+                    .unwrap_or_else(|e| {
+                        warn!(error = %e, "Profile fetch failed");
+                        (None, None)
+                    }),
+                    _ => (None, None),
+                };
+
+                let door_id = controller.door_id as i32;
+                match insert_doorbell_ring(&controller.pool, &npub, door_id, answer.as_secs()).await
+                {
+                    Ok(ring) => {
+                        info!(ring_id = %ring.id, "Doorbell rung, waiting for an admin");
+                        controller.admin_feed.publish(AdminEvent::doorbell(
+                            &ring,
+                            name,
+                            picture_url,
+                            "ringing",
+                            None,
+                        ));
+                    }
+                    Err(e) => error!(error = ?e, "Failed to ring the doorbell"),
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    // Persist the attempt and announce it to webhooks, alerts and live log viewers; none of it ever blocks the door
+    async fn record(&self, attempt: &Attempt, result: AccessResult, reason: Option<&str>) {
+        self.record_entry(attempt, result, None, reason, None).await;
+    }
+
+    // Records why the door stayed locked; `detail` replaces the reason's default text
+    async fn refuse(
+        &self,
+        attempt: &Attempt,
+        result: AccessResult,
+        reason: DenyReason,
+        detail: Option<&str>,
+    ) {
+        let detail = detail.unwrap_or(reason.describe());
+        self.record_entry(attempt, result, Some(reason), Some(detail), None)
+            .await;
+    }
+
+    // `granted_permissions` once the wallet has approved the authentication
+    async fn record_entry(
+        &self,
+        attempt: &Attempt,
+        result: AccessResult,
+        reason_code: Option<DenyReason>,
+        reason: Option<&str>,
+        granted_permissions: Option<&[String]>,
+    ) {
+        let npub = attempt.npub.as_str();
+        let door_id = Some(self.door_id as i32);
+        let logged = insert_access_log(
+            &self.pool,
+            &NewAccessLog {
+                npub,
+                door_id,
+                result,
+                reason_code,
+                reason,
+                granted_permissions,
+                credential: attempt.credential.as_deref(),
+            },
+        )
+        .await;
+        self.emit(
+            attempt,
+            PipelineEvent::Logged {
+                access_log_id: logged.as_ref().ok().map(|log| log.id),
+                result: result.as_str().to_string(),
+                reason_code,
+                reason: reason.map(str::to_string),
+            },
+        );
+        match logged {
+            Ok(log) => self.log_feed.publish(log),
+            Err(e) => error!(error = ?e, "Failed to record access log"),
+        }
+        // An entry through a door holding the site to its limit changed the count; the next
+        // handshake there should not wait for the notification to see it
+        if result == AccessResult::Granted {
+            self.key_cache.forget_occupancy(self.door_id as i32);
+        }
+
+        if let Some(event) = AccessEvent::from_result(npub, door_id, result, reason) {
+            self.webhooks.notify(event);
+        }
+        if result == AccessResult::Declined {
+            self.alerts.declined(npub, door_id);
+        }
+    }
+
+    fn emit(&self, attempt: &Attempt, event: PipelineEvent) {
+        self.events.emit(attempt, self.door_id as i32, event);
+    }
+}
+
+/// Owns the background handshake loops, one per door: restarts each with exponential backoff
+/// if it panics and stops them all cleanly when Rocket shuts down.
+pub struct Supervisor {
+    shutdown: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Supervisor {
+    /// Spawn a supervised loop per controller on the Rocket runtime.
+    pub fn spawn(controllers: Vec<AccessController>) -> Self {
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let tasks = controllers
+            .into_iter()
+            .map(|controller| tokio::spawn(supervise(controller, shutdown_rx.clone())))
+            .collect();
+
+        Self { shutdown, tasks }
+    }
+
+    /// Shutdown fairing, stopping the loops along with the server.
+    pub fn fairing(self) -> impl Fairing {
+        AdHoc::on_shutdown("Portal loop", move |_| Box::pin(self.stop()))
+    }
+
+    /// Signals the loops, waits for them to wind down, and lets the last handle to the Portal
+    /// SDK drop so its relay connections are closed.
+    pub async fn stop(self) {
+        info!("Stopping Portal loops");
+        let _ = self.shutdown.send(true);
+
+        let mut tasks = self.tasks;
+        let all_stopped = rocket::futures::future::join_all(tasks.iter_mut());
+        if tokio::time::timeout(SHUTDOWN_GRACE, all_stopped)
+            .await
+            .is_err()
+        {
+            warn!("Portal loops did not stop in time, aborting them");
+            tasks.iter().for_each(JoinHandle::abort);
+        }
+        info!("Portal loops stopped");
+    }
+}
+
+async fn supervise(controller: AccessController, mut shutdown: watch::Receiver<bool>) {
+    let mut backoff = RESTART_BACKOFF_INITIAL;
+
+    loop {
+        let started = Instant::now();
+        let result = tokio::spawn(controller.clone().run(shutdown.clone())).await;
+
+        match result {
+            // run() only returns on shutdown
+            Ok(()) => return,
+            Err(e) if e.is_panic() => {
+                error!(door_id = controller.door_id, error = ?e, "Portal loop panicked");
+                controller.admin_feed.publish(AdminEvent::task(
+                    format!("portal_loop:{}", controller.door_id),
+                    "restarting",
+                    Some(format!("panicked, restarting in {:?}", backoff)),
+                ));
+            }
+            Err(_) => return,
+        }
+
+        if started.elapsed() >= RESTART_RESET {
+            backoff = RESTART_BACKOFF_INITIAL;
+        }
+        info!(
+            door_id = controller.door_id,
+            ?backoff,
+            "Restarting Portal loop"
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = shutdown.changed() => return,
+        }
+        backoff = (backoff * 2).min(RESTART_BACKOFF_MAX);
+    }
+}
@@ -0,0 +1,115 @@
+use crate::config::ProfilesConfig;
+use crate::database::helpers::{get_keys_due_profile_refresh, set_key_profile};
+use crate::relays::PortalClient;
+
+use chrono::Utc;
+use portal::nostr::PublicKey;
+use portal::nostr::nips::nip19::FromBech32;
+use rocket::tokio::{self, time::timeout};
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+// How long one profile lookup may take on the relays
+const FETCH_TIMEOUT: Duration = Duration::from_secs(15);
+
+// How often the refresher looks for stale profiles
+const REFRESH_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+// Longest display name and picture URL kept, since profiles are written by anyone
+const MAX_DISPLAY_NAME_LEN: usize = 100;
+const MAX_PICTURE_URL_LEN: usize = 1024;
+
+/// Fetch the Nostr profiles of newly added keys in the background, one after the other, and
+/// store their names and pictures.
+pub fn spawn_fetch(pool: Pool<Postgres>, portal: Arc<dyn PortalClient>, keys: Vec<(Uuid, String)>) {
+    tokio::spawn(async move {
+        for (key_id, npub) in keys {
+            refresh(&pool, portal.as_ref(), key_id, &npub).await;
+        }
+    });
+}
+
+/// Re-fetches every profile older than `profiles.refresh_hours` (default 24, 0 turns it off),
+/// one key at a time so the relays are not flooded.
+pub fn spawn_refresher(
+    pool: Pool<Postgres>,
+    portal: Arc<dyn PortalClient>,
+    config: &ProfilesConfig,
+) {
+    if config.refresh_hours == 0 {
+        return;
+    }
+
+    let max_age = chrono::Duration::hours(config.refresh_hours.into());
+    tokio::spawn(async move {
+        loop {
+            match get_keys_due_profile_refresh(&pool, Utc::now() - max_age).await {
+                Ok(keys) => {
+                    for (key_id, npub) in keys {
+                        refresh(&pool, portal.as_ref(), key_id, &npub).await;
+                    }
+                }
+                Err(e) => error!(error = ?e, "Failed to load keys for profile refresh"),
+            }
+            tokio::time::sleep(REFRESH_CHECK_INTERVAL).await;
+        }
+    });
+}
+
+// A key without a profile is stored as such, so it is not fetched again until it is due;
+// a failed lookup leaves the last known profile in place
+async fn refresh(pool: &Pool<Postgres>, portal: &dyn PortalClient, key_id: Uuid, npub: &str) {
+    let (display_name, picture_url) = match lookup(portal, npub, FETCH_TIMEOUT).await {
+        Ok(profile) => profile,
+        Err(e) => {
+            warn!(%npub, error = %e, "Profile fetch failed");
+            return;
+        }
+    };
+
+    match set_key_profile(
+        pool,
+        key_id,
+        display_name.as_deref(),
+        picture_url.as_deref(),
+    )
+    .await
+    {
+        Ok(()) => info!(%npub, display_name = ?display_name, "Profile updated"),
+        Err(e) => error!(error = ?e, "Failed to store profile"),
+    }
+}
+
+/// The display name and picture URL from the key's Nostr profile, trimmed and length-capped as
+/// they are stored; both `None` when it has not published one. Gives up after `wait`.
+pub async fn lookup(
+    portal: &dyn PortalClient,
+    npub: &str,
+    wait: Duration,
+) -> Result<(Option<String>, Option<String>), String> {
+    let public_key =
+        PublicKey::from_bech32(npub).map_err(|e| format!("invalid npub {}: {:?}", npub, e))?;
+
+    let profile = match timeout(wait, portal.fetch_profile(public_key)).await {
+        Ok(result) => result?,
+        Err(_) => return Err("timed out".to_string()),
+    };
+
+    Ok(match profile {
+        Some(profile) => (
+            clean(profile.display_name.or(profile.name), MAX_DISPLAY_NAME_LEN),
+            clean(profile.picture, MAX_PICTURE_URL_LEN)
+                .filter(|url| url.starts_with("https://") || url.starts_with("http://")),
+        ),
+        None => (None, None),
+    })
+}
+
+fn clean(value: Option<String>, max_len: usize) -> Option<String> {
+    value
+        .map(|value| value.trim().chars().take(max_len).collect::<String>())
+        .filter(|value| !value.is_empty())
+}
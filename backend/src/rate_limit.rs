@@ -0,0 +1,146 @@
+use crate::config::RateLimitConfig;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+// Attempts are counted over a sliding minute; over-limit attempts over a longer window, so a
+// key hammering the reader keeps accumulating strikes across several minutes
+const WINDOW: Duration = Duration::from_secs(60);
+const ABUSE_WINDOW: Duration = Duration::from_secs(600);
+
+// Past this many tracked npubs, idle entries are swept on the next check
+const SWEEP_THRESHOLD: usize = 1024;
+
+/// Drops repeat handshakes by the same npub within `rate_limit.debounce_secs` (default 5, 0 turns
+/// it off), such as a relay delivering one event twice or a double scan. Only handshakes that
+/// get through restart the window.
+#[derive(Clone)]
+pub struct Debouncer {
+    last_seen: Arc<Mutex<HashMap<String, Instant>>>,
+    window: Duration,
+}
+
+impl Debouncer {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            last_seen: Arc::default(),
+            window: Duration::from_secs(config.debounce_secs),
+        }
+    }
+
+    /// Whether a handshake by `npub` may go ahead.
+    pub fn check(&self, npub: &str) -> bool {
+        if self.window.is_zero() {
+            return true;
+        }
+
+        let now = Instant::now();
+        let mut last_seen = self.last_seen.lock().unwrap_or_else(|e| e.into_inner());
+        if last_seen.len() > SWEEP_THRESHOLD {
+            last_seen.retain(|_, at| now.duration_since(*at) < self.window);
+        }
+
+        match last_seen.get(npub) {
+            Some(at) if now.duration_since(*at) < self.window => false,
+            _ => {
+                last_seen.insert(npub.to_string(), now);
+                true
+            }
+        }
+    }
+}
+
+/// Outcome of `RateLimiter::check` for a single attempt.
+pub enum RateDecision {
+    Allowed,
+    /// `disable_key` is set on the attempt that crosses the abuse threshold.
+    Limited {
+        disable_key: bool,
+    },
+}
+
+#[derive(Default)]
+struct Window {
+    attempts: VecDeque<Instant>,
+    rejected: VecDeque<Instant>,
+}
+
+impl Window {
+    fn prune(&mut self, now: Instant) {
+        while self
+            .attempts
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= WINDOW)
+        {
+            self.attempts.pop_front();
+        }
+        while self
+            .rejected
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= ABUSE_WINDOW)
+        {
+            self.rejected.pop_front();
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.attempts.is_empty() && self.rejected.is_empty()
+    }
+}
+
+/// Per-npub sliding-window limit on unlock attempts, shared by the handshake loop.
+///
+/// - `rate_limit.per_minute`: attempts a key may make per minute (default 5)
+/// - `rate_limit.disable_after`: over-limit attempts within ten minutes after which the key
+///   is disabled (default 0, never)
+#[derive(Clone)]
+pub struct RateLimiter {
+    windows: Arc<Mutex<HashMap<String, Window>>>,
+    max_attempts: usize,
+    disable_after: usize,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let max_attempts = config.per_minute.max(1);
+        let disable_after = config.disable_after;
+        info!(
+            per_minute = max_attempts,
+            disable_after, "Unlock rate limit configured"
+        );
+
+        Self {
+            windows: Arc::default(),
+            max_attempts,
+            disable_after,
+        }
+    }
+
+    /// Count an attempt by `npub` and decide whether it may go ahead.
+    pub fn check(&self, npub: &str) -> RateDecision {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+
+        if windows.len() > SWEEP_THRESHOLD {
+            windows.retain(|_, window| {
+                window.prune(now);
+                !window.is_idle()
+            });
+        }
+
+        let window = windows.entry(npub.to_string()).or_default();
+        window.prune(now);
+
+        if window.attempts.len() < self.max_attempts {
+            window.attempts.push_back(now);
+            return RateDecision::Allowed;
+        }
+
+        window.rejected.push_back(now);
+        RateDecision::Limited {
+            disable_key: self.disable_after > 0 && window.rejected.len() == self.disable_after,
+        }
+    }
+}
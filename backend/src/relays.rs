@@ -0,0 +1,454 @@
+use crate::config::PortalConfig;
+use crate::portal_loop::RelayHealth;
+use crate::request_log::escape;
+
+use chrono::{DateTime, Utc};
+use portal::nostr::PublicKey;
+use portal::nostr::nips::nip19::ToBech32;
+use portal::profile::Profile;
+use portal::protocol::model::auth::AuthResponseStatus;
+use portal::protocol::model::payment::{PaymentStatus, SinglePaymentRequestContent};
+use rocket::futures::SinkExt;
+use rocket::futures::stream::{self, BoxStream, StreamExt};
+use rocket::tokio::{self, net::TcpStream, time::timeout};
+use sdk::PortalSDK;
+use serde_json::Value;
+use std::fmt::Write;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tracing::{debug, error, info, warn};
+
+// How often every relay is probed, and how long a probe may take
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Name of the subscription that counts what each relay delivers
+const DELIVERIES: &str = "deliveries";
+
+/// Keys completing a handshake URL, as they arrive.
+pub type KeyHandshakes = BoxStream<'static, Result<PublicKey, String>>;
+/// Status updates of one payment request, until it settles or is given up.
+pub type PaymentUpdates = BoxStream<'static, Result<PaymentStatus, String>>;
+
+/// What the handshake loop and the profile fetcher ask of Portal. `PortalConnection` goes through the SDK and its
+/// relays; with the `testing` feature, `testing::InProcessPortal` answers in-process.
+#[rocket::async_trait]
+pub trait PortalClient: Send + Sync {
+    /// Opens a key handshake URL and returns it with the keys completing it. The URL carries
+    /// `static_token`, so it is the same every time, or a new random token without one.
+    async fn key_handshakes(
+        &self,
+        static_token: Option<String>,
+    ) -> Result<(String, KeyHandshakes), String>;
+    /// Asks the key's wallet to approve the login.
+    async fn authenticate(&self, key: PublicKey) -> Result<Authentication, String>;
+    async fn request_payment(
+        &self,
+        key: PublicKey,
+        request: SinglePaymentRequestContent,
+    ) -> Result<PaymentUpdates, String>;
+    /// The key's Nostr profile, or None when it has not published one.
+    async fn fetch_profile(&self, key: PublicKey) -> Result<Option<Profile>, String>;
+}
+
+/// The wallet's answer to an authentication request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Authentication {
+    /// With the permissions the wallet granted.
+    Approved(Vec<String>),
+    /// With the reason the user gave, if the wallet passed one on.
+    Declined(Option<String>),
+}
+
+/// Last probe result for one relay, for the status page.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct RelayStatus {
+    pub url: String,
+    /// `None` until the first probe has run.
+    pub reachable: Option<bool>,
+    pub latency_ms: Option<u64>,
+    pub last_checked: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    /// Events addressed to the service identity the relay delivered since the server started.
+    pub events: u64,
+    pub last_event: Option<DateTime<Utc>>,
+}
+
+/// The Portal SDK and the relays it talks through.
+///
+/// The SDK is handed every relay in `portal.relay_url` and publishes through all of them, so
+/// losing one relay is covered by the others. A background monitor probes each relay; when
+/// the handshake loop has lost its connection while a relay is reachable, the SDK is rebuilt
+/// so it reconnects. Callers take the current SDK with `sdk()` for each operation.
+///
+/// The SDK does not say which relay an event came through, so every relay also gets a
+/// subscription of its own to the events addressed to the service identity, and counts what it
+/// delivers. A relay that stays reachable but never delivers shows up there.
+#[derive(Clone)]
+pub struct PortalConnection {
+    sdk: Arc<RwLock<Arc<PortalSDK>>>,
+    nostr_key: String,
+    npub: String,
+    pubkey: String,
+    statuses: Arc<RwLock<Vec<RelayStatus>>>,
+}
+
+impl PortalConnection {
+    /// Connects as the main service identity, `portal.nostr_key`.
+    pub async fn connect(config: &PortalConfig) -> Result<Self, String> {
+        Self::connect_as(&config.nostr_key, config).await
+    }
+
+    /// Connects as the identity of `nostr_key`, through the relays of `config`.
+    pub async fn connect_as(nostr_key: &str, config: &PortalConfig) -> Result<Self, String> {
+        let relays = config.relay_urls();
+        let public_key = portal::nostr::Keys::parse(nostr_key)
+            .map_err(|e| format!("{}", e))?
+            .public_key();
+        let npub = public_key.to_bech32().map_err(|e| format!("{}", e))?;
+        let sdk = build_sdk(nostr_key, relays.clone()).await?;
+        let statuses = relays
+            .into_iter()
+            .map(|url| RelayStatus {
+                url,
+                reachable: None,
+                latency_ms: None,
+                last_checked: None,
+                last_error: None,
+                events: 0,
+                last_event: None,
+            })
+            .collect();
+
+        Ok(Self {
+            sdk: Arc::new(RwLock::new(Arc::new(sdk))),
+            nostr_key: nostr_key.to_string(),
+            npub,
+            pubkey: public_key.to_hex(),
+            statuses: Arc::new(RwLock::new(statuses)),
+        })
+    }
+
+    pub fn sdk(&self) -> Arc<PortalSDK> {
+        self.sdk.read().expect("Portal SDK lock poisoned").clone()
+    }
+
+    /// The public key of the service identity, which wallets see on handshakes.
+    pub fn npub(&self) -> &str {
+        &self.npub
+    }
+
+    pub fn statuses(&self) -> Vec<RelayStatus> {
+        self.statuses
+            .read()
+            .expect("Relay status lock poisoned")
+            .clone()
+    }
+
+    fn relay_urls(&self) -> Vec<String> {
+        self.statuses()
+            .into_iter()
+            .map(|status| status.url)
+            .collect()
+    }
+
+    /// Spawns the relay monitor and the delivery counters. `health` is the handshake loop's view
+    /// of the connection.
+    pub fn spawn_monitor(&self, health: RelayHealth) {
+        for url in self.relay_urls() {
+            let connection = self.clone();
+            tokio::spawn(async move {
+                loop {
+                    let e = connection.count_deliveries(&url).await;
+                    debug!(relay = %url, error = %e, "Delivery subscription ended, subscribing again");
+                    tokio::time::sleep(PROBE_INTERVAL).await;
+                }
+            });
+        }
+
+        let connection = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let reachable = connection.probe_all().await;
+                if reachable > 0 && !health.is_connected() {
+                    connection.reconnect().await;
+                } else if reachable == 0 {
+                    error!("No Portal relay is reachable");
+                }
+                tokio::time::sleep(PROBE_INTERVAL).await;
+            }
+        });
+    }
+
+    // Probes every relay concurrently and returns how many are reachable
+    async fn probe_all(&self) -> usize {
+        let probes = self.relay_urls().into_iter().map(|url| async move {
+            let started = Instant::now();
+            let result = probe(&url).await;
+            (url, result, started.elapsed())
+        });
+        let results = rocket::futures::future::join_all(probes).await;
+
+        let mut statuses = self.statuses.write().expect("Relay status lock poisoned");
+        for (status, (url, result, elapsed)) in statuses.iter_mut().zip(results) {
+            let was_reachable = status.reachable;
+            status.last_checked = Some(Utc::now());
+            match result {
+                Ok(()) => {
+                    status.reachable = Some(true);
+                    status.latency_ms = Some(elapsed.as_millis() as u64);
+                    status.last_error = None;
+                    if was_reachable == Some(false) {
+                        info!(relay = %url, "Relay reachable again");
+                    }
+                }
+                Err(e) => {
+                    if was_reachable != Some(false) {
+                        warn!(relay = %url, error = %e, "Relay unreachable");
+                    }
+                    status.reachable = Some(false);
+                    status.latency_ms = None;
+                    status.last_error = Some(e);
+                }
+            }
+        }
+
+        statuses
+            .iter()
+            .filter(|status| status.reachable == Some(true))
+            .count()
+    }
+
+    // Subscribes to what the relay delivers to the service identity from now on, and counts each
+    // event until the connection drops
+    async fn count_deliveries(&self, url: &str) -> String {
+        let mut socket = match timeout(PROBE_TIMEOUT, connect_async(url)).await {
+            Ok(Ok((socket, _))) => socket,
+            Ok(Err(e)) => return e.to_string(),
+            Err(_) => return "timed out".to_string(),
+        };
+        let request = serde_json::json!([
+            "REQ",
+            DELIVERIES,
+            { "#p": [self.pubkey], "since": Utc::now().timestamp() },
+        ]);
+        if let Err(e) = socket.send(Message::text(request.to_string())).await {
+            return e.to_string();
+        }
+
+        while let Some(message) = socket.next().await {
+            match message {
+                Ok(Message::Text(text)) if is_delivery(&text) => self.record_delivery(url),
+                Ok(_) => {}
+                Err(e) => return e.to_string(),
+            }
+        }
+        "connection closed".to_string()
+    }
+
+    fn record_delivery(&self, url: &str) {
+        let mut statuses = self.statuses.write().expect("Relay status lock poisoned");
+        if let Some(status) = statuses.iter_mut().find(|status| status.url == url) {
+            status.events += 1;
+            status.last_event = Some(Utc::now());
+        }
+    }
+
+    // Reachable relays go first, so the new SDK starts out on a working one
+    async fn reconnect(&self) {
+        let mut statuses = self.statuses();
+        statuses.sort_by_key(|status| status.reachable != Some(true));
+        let relays: Vec<String> = statuses.into_iter().map(|status| status.url).collect();
+
+        info!(?relays, "Reconnecting the Portal SDK");
+        match build_sdk(&self.nostr_key, relays).await {
+            Ok(sdk) => *self.sdk.write().expect("Portal SDK lock poisoned") = Arc::new(sdk),
+            Err(e) => error!(error = %e, "Failed to reconnect the Portal SDK"),
+        }
+    }
+}
+
+/// One Portal service identity: its connection, the handshake loops' view of it and the doors
+/// served under it.
+#[derive(Clone)]
+pub struct PortalIdentity {
+    pub connection: PortalConnection,
+    pub health: RelayHealth,
+    pub doors: Vec<u32>,
+}
+
+/// Every service identity the handshake loops run under, the main one first. Managed by
+/// Rocket for the status page and the health check.
+#[derive(Clone)]
+pub struct PortalIdentities(pub Vec<PortalIdentity>);
+
+impl PortalIdentities {
+    /// Whether every identity holds a working relay connection.
+    pub fn all_connected(&self) -> bool {
+        self.0.iter().all(|identity| identity.health.is_connected())
+    }
+
+    /// How many events each relay delivered to each identity, in the Prometheus text format.
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::from(
+            "# HELP portal_relay_events_total Events addressed to a Portal service identity that a relay delivered.\n\
+             # TYPE portal_relay_events_total counter\n",
+        );
+        for identity in &self.0 {
+            render_events(
+                &mut out,
+                identity.connection.npub(),
+                &identity.connection.statuses(),
+            );
+        }
+        out
+    }
+}
+
+fn render_events(out: &mut String, npub: &str, statuses: &[RelayStatus]) {
+    for status in statuses {
+        let _ = writeln!(
+            out,
+            "portal_relay_events_total{{identity=\"{}\",relay=\"{}\"}} {}",
+            npub,
+            escape(&status.url),
+            status.events
+        );
+    }
+}
+
+// An event sent for the deliveries subscription, as opposed to its end-of-stored-events
+// marker or a notice
+fn is_delivery(message: &str) -> bool {
+    match serde_json::from_str::<Value>(message) {
+        Ok(Value::Array(fields)) => {
+            fields.first().and_then(Value::as_str) == Some("EVENT")
+                && fields.get(1).and_then(Value::as_str) == Some(DELIVERIES)
+        }
+        _ => false,
+    }
+}
+
+#[rocket::async_trait]
+impl PortalClient for PortalConnection {
+    async fn key_handshakes(
+        &self,
+        static_token: Option<String>,
+    ) -> Result<(String, KeyHandshakes), String> {
+        let (url, notifications) = self
+            .sdk()
+            .new_key_handshake_url(static_token, Some(false))
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+        let keys = stream::unfold(notifications, |mut notifications| async move {
+            let event = notifications.next().await?;
+            let key = event
+                .map(|event| event.main_key)
+                .map_err(|e| format!("{:?}", e));
+            Some((key, notifications))
+        });
+
+        Ok((url.to_string(), keys.boxed()))
+    }
+
+    async fn authenticate(&self, key: PublicKey) -> Result<Authentication, String> {
+        let response = self
+            .sdk()
+            .authenticate_key(key, vec![])
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+
+        Ok(match response.status {
+            AuthResponseStatus::Approved {
+                granted_permissions,
+                ..
+            } => Authentication::Approved(granted_permissions),
+            AuthResponseStatus::Declined { reason } => Authentication::Declined(reason),
+        })
+    }
+
+    async fn request_payment(
+        &self,
+        key: PublicKey,
+        request: SinglePaymentRequestContent,
+    ) -> Result<PaymentUpdates, String> {
+        let responses = self
+            .sdk()
+            .request_single_payment(key, vec![], request)
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+        let updates = stream::unfold(responses, |mut responses| async move {
+            let response = responses.next().await?;
+            let status = response
+                .map(|response| response.status)
+                .map_err(|e| format!("{:?}", e));
+            Some((status, responses))
+        });
+
+        Ok(updates.boxed())
+    }
+
+    async fn fetch_profile(&self, key: PublicKey) -> Result<Option<Profile>, String> {
+        self.sdk()
+            .fetch_profile(key)
+            .await
+            .map_err(|e| format!("{:?}", e))
+    }
+}
+
+async fn build_sdk(nostr_key: &str, relays: Vec<String>) -> Result<PortalSDK, String> {
+    let keys = portal::nostr::Keys::parse(nostr_key).map_err(|e| format!("{}", e))?;
+    let keypair = portal::protocol::LocalKeypair::new(keys, None);
+    PortalSDK::new(keypair, relays)
+        .await
+        .map_err(|e| format!("Failed to initialize Portal SDK: {:?}", e))
+}
+
+// A relay counts as reachable when its host accepts a TCP connection
+async fn probe(url: &str) -> Result<(), String> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| format!("invalid URL: {}", e))?;
+    let host = parsed.host_str().ok_or("URL has no host")?;
+    let port = parsed.port_or_known_default().ok_or("URL has no port")?;
+
+    match timeout(PROBE_TIMEOUT, TcpStream::connect((host, port))).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timed out".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_events_of_the_subscription_count_as_deliveries() {
+        assert!(is_delivery(r#"["EVENT","deliveries",{"kind":21000}]"#));
+        assert!(!is_delivery(r#"["EOSE","deliveries"]"#));
+        assert!(!is_delivery(r#"["EVENT","other",{"kind":21000}]"#));
+        assert!(!is_delivery(r#"["NOTICE","rate limited"]"#));
+        assert!(!is_delivery("not json"));
+
+        let status = |url: &str, events| RelayStatus {
+            url: url.to_string(),
+            reachable: Some(true),
+            latency_ms: Some(20),
+            last_checked: None,
+            last_error: None,
+            events,
+            last_event: None,
+        };
+        let mut out = String::new();
+        render_events(
+            &mut out,
+            "npub1door",
+            &[status("wss://a.example", 3), status("wss://b\"", 0)],
+        );
+        assert_eq!(
+            out,
+            "portal_relay_events_total{identity=\"npub1door\",relay=\"wss://a.example\"} 3\n\
+             portal_relay_events_total{identity=\"npub1door\",relay=\"wss://b\\\"\"} 0\n"
+        );
+    }
+}
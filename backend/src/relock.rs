@@ -0,0 +1,142 @@
+use crate::admin_feed::{AdminEvent, AdminFeed};
+use crate::alerts::{Alert, AlertKind, Alerter};
+use crate::config::RelockConfig;
+use crate::database::helpers::{Door, insert_door_event, is_held_on_schedule};
+use crate::door_backend::{DoorBackend, DoorState};
+
+use rocket::tokio::{self, time::Instant};
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+/// Checks that a door locks again after an unlock. Once the door's unlock duration (or
+/// `relock.default_unlock_secs`) is over, its lock state is polled every `relock.poll_secs` for
+/// up to `relock.grace_secs`; a door still reporting unlocked by then gets a `relock_failed`
+/// door event, shown on its page and the admin channel, and a `door.relock_failed` alert.
+///
+/// A watch ends early when the door reports locked, when its backend cannot tell, or when a
+/// newer unlock of the same door takes over. Doors held unlocked on schedule are left alone.
+#[derive(Clone)]
+pub struct RelockVerifier {
+    pool: Pool<Postgres>,
+    door: Arc<dyn DoorBackend>,
+    alerts: Alerter,
+    feed: AdminFeed,
+    grace: Duration,
+    poll: Duration,
+    default_unlock: Duration,
+    // How many unlocks each door has been sent, so a watch can tell it was superseded
+    unlocks: Arc<Mutex<HashMap<i32, u64>>>,
+}
+
+impl RelockVerifier {
+    pub fn new(
+        pool: Pool<Postgres>,
+        door: Arc<dyn DoorBackend>,
+        alerts: Alerter,
+        feed: AdminFeed,
+        config: &RelockConfig,
+    ) -> Self {
+        Self {
+            pool,
+            door,
+            alerts,
+            feed,
+            grace: Duration::from_secs(config.grace_secs),
+            poll: Duration::from_secs(config.poll_secs.max(1)),
+            default_unlock: Duration::from_secs(config.default_unlock_secs),
+            unlocks: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Starts watching `door` after an unlock was sent to it. Never waits.
+    pub fn watch(&self, door: &Door) {
+        if self.grace.is_zero() {
+            return;
+        }
+
+        let unlock = {
+            let mut unlocks = self.unlocks.lock().expect("Relock tracker lock poisoned");
+            let count = unlocks.entry(door.controller_door_id).or_default();
+            *count += 1;
+            *count
+        };
+        let verifier = self.clone();
+        let door = door.clone();
+        tokio::spawn(async move { verifier.verify(door, unlock).await });
+    }
+
+    // Whether another unlock was sent to the door after the watched one
+    fn superseded(&self, door: &Door, unlock: u64) -> bool {
+        let unlocks = self.unlocks.lock().expect("Relock tracker lock poisoned");
+        unlocks.get(&door.controller_door_id) != Some(&unlock)
+    }
+
+    async fn verify(&self, door: Door, unlock: u64) {
+        let unlocked_for = door
+            .unlock_duration()
+            .map(|seconds| Duration::from_secs(seconds.into()))
+            .unwrap_or(self.default_unlock);
+        tokio::time::sleep(unlocked_for).await;
+
+        let controller_door_id = door.controller_door_id as u32;
+        let deadline = Instant::now() + self.grace;
+        let mut last = None;
+        loop {
+            if self.superseded(&door, unlock) {
+                return;
+            }
+            match self.door.status(controller_door_id).await {
+                Ok(DoorState::Locked) => return,
+                Ok(DoorState::Unknown) => {
+                    debug!(door = %door.name, "Door backend does not report the lock state, relock not verified");
+                    return;
+                }
+                Ok(DoorState::Unlocked) => last = Some(DoorState::Unlocked),
+                Err(e) => warn!(door = %door.name, error = %e, "Failed to read door state"),
+            }
+            if Instant::now() >= deadline {
+                break;
+            }
+            tokio::time::sleep(self.poll).await;
+        }
+
+        if last != Some(DoorState::Unlocked) {
+            warn!(door = %door.name, "Relock could not be verified, the door state was never read");
+            return;
+        }
+        if self.superseded(&door, unlock) {
+            return;
+        }
+        match is_held_on_schedule(&self.pool, door.id).await {
+            Ok(true) => {
+                info!(door = %door.name, "Door held unlocked on schedule, not a failed relock");
+                return;
+            }
+            Ok(false) => {}
+            Err(e) => error!(error = ?e, "Failed to check the door's schedule"),
+        }
+
+        warn!(door = %door.name, "Door still unlocked after its unlock ended");
+        if let Err(e) = insert_door_event(&self.pool, door.id, "relock_failed").await {
+            error!(error = ?e, "Failed to record door event");
+        }
+        self.feed.publish(AdminEvent::door(
+            door.controller_door_id,
+            &door.name,
+            "relock_failed",
+        ));
+        self.alerts.notify(Alert::new(
+            AlertKind::RelockFailed,
+            Some(door.controller_door_id),
+            format!(
+                "{} still reports unlocked {} seconds after its {} second unlock ended",
+                door.name,
+                self.grace.as_secs(),
+                unlocked_for.as_secs()
+            ),
+        ));
+    }
+}
@@ -0,0 +1,71 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::{Data, Response};
+use std::convert::Infallible;
+use std::fmt;
+use uuid::Uuid;
+
+const HEADER: &str = "X-Request-Id";
+
+// Longest caller-supplied id we pass through; anything else gets a fresh one
+const MAX_INCOMING_LEN: usize = 128;
+
+/// Correlation id of the current request, echoed back in the `X-Request-Id` header and
+/// attached to the logs of work done on its behalf (e.g. manual unlocks).
+#[derive(Debug, Clone)]
+pub struct RequestId(String);
+
+impl RequestId {
+    // Honours an id set by a proxy in front of us so logs line up across both
+    pub(crate) fn of<'r>(req: &'r Request<'_>) -> &'r RequestId {
+        req.local_cache(|| {
+            let incoming = req.headers().get_one(HEADER).filter(|id| {
+                !id.is_empty()
+                    && id.len() <= MAX_INCOMING_LEN
+                    && id.chars().all(|c| c.is_ascii_graphic())
+            });
+            RequestId(match incoming {
+                Some(id) => id.to_string(),
+                None => Uuid::new_v4().to_string(),
+            })
+        })
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RequestId::of(req).clone())
+    }
+}
+
+/// Assigns every request an id and echoes it in the response.
+pub struct RequestIds;
+
+#[rocket::async_trait]
+impl Fairing for RequestIds {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request IDs",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        RequestId::of(req);
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let request_id = RequestId::of(req);
+        res.set_header(Header::new(HEADER, request_id.to_string()));
+    }
+}
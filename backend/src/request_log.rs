@@ -0,0 +1,148 @@
+use crate::request_id::RequestId;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::request::Request;
+use rocket::{Data, Response};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// Label for requests no route matched, so probing for random paths cannot add series
+const UNMATCHED: &str = "unmatched";
+
+struct Started(Instant);
+
+// Who the request was authenticated as, recorded by the auth guards
+struct Subject(Option<String>);
+
+/// Notes `subject` as the one a request was authenticated as, for its log line. The first
+/// subject recorded for a request wins.
+pub fn record_subject(req: &Request<'_>, subject: &str) {
+    req.local_cache(|| Subject(Some(subject.to_string())));
+}
+
+// One histogram per method, route and status
+type Series = BTreeMap<(String, String, u16), Histogram>;
+
+#[derive(Default)]
+struct Histogram {
+    // Not cumulative; `render` adds them up
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// Request latencies by method, route and status, served in the Prometheus text format at
+/// `/metrics`. Cloning is cheap.
+#[derive(Clone, Default)]
+pub struct RequestMetrics(Arc<Mutex<Series>>);
+
+impl RequestMetrics {
+    fn observe(&self, method: &str, route: &str, status: u16, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        let mut series = self.0.lock().expect("Request metrics lock poisoned");
+        let histogram = series
+            .entry((method.to_string(), route.to_string(), status))
+            .or_default();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            histogram.buckets[bucket] += 1;
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    pub fn render(&self) -> String {
+        let series = self.0.lock().expect("Request metrics lock poisoned");
+        let mut out = String::from(
+            "# HELP http_request_duration_seconds Time taken to answer HTTP requests.\n\
+             # TYPE http_request_duration_seconds histogram\n",
+        );
+        for ((method, route, status), histogram) in series.iter() {
+            let labels = format!(
+                "method=\"{}\",route=\"{}\",status=\"{}\"",
+                method,
+                escape(route),
+                status
+            );
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_sum{{{}}} {}",
+                labels, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_count{{{}}} {}",
+                labels, histogram.count
+            );
+        }
+        out
+    }
+}
+
+/// Escapes a Prometheus label value.
+pub fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Logs one line per response, with the route, status, latency and the subject the request was
+/// authenticated as, and adds its latency to `RequestMetrics`.
+pub struct RequestLog(pub RequestMetrics);
+
+#[rocket::async_trait]
+impl Fairing for RequestLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request log",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        req.local_cache(|| Started(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let latency = req.local_cache(|| Started(Instant::now())).0.elapsed();
+        let subject = req.local_cache(|| Subject(None)).0.as_deref();
+        // The route's pattern, e.g. /keys/<id>, rather than the path, which holds ids
+        let route = req
+            .route()
+            .map(|route| route.uri.to_string())
+            .unwrap_or_else(|| UNMATCHED.to_string());
+        let status = res.status().code;
+
+        info!(
+            request_id = %RequestId::of(req),
+            method = %req.method(),
+            uri = %req.uri(),
+            route = %route,
+            status,
+            latency_ms = latency.as_millis() as u64,
+            subject,
+            "Request handled"
+        );
+        self.0
+            .observe(req.method().as_str(), &route, status, latency);
+    }
+}
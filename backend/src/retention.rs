@@ -0,0 +1,145 @@
+use crate::config::RetentionConfig;
+use crate::database::helpers::{LogTable, delete_log_rows_before, stream_log_rows_before};
+
+use chrono::{DateTime, NaiveDate, Timelike, Utc};
+use chrono_tz::Tz;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use rocket::futures::StreamExt;
+use rocket::tokio::{self, task};
+use sqlx::{Pool, Postgres};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{error, info};
+
+// How often the pruner checks whether the nightly run is due
+const CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+// Archived rows buffered before they are compressed and written out
+const WRITE_BATCH_BYTES: usize = 1024 * 1024;
+
+type Archive = GzEncoder<File>;
+
+/// Archives and deletes access and audit log rows past `retention.access_log_days` and
+/// `retention.audit_log_days` once a night, at `retention.run_hour` site time. Does nothing
+/// when both are 0.
+pub fn spawn_pruner(pool: Pool<Postgres>, config: &RetentionConfig, timezone: Tz) {
+    let logs: Vec<(LogTable, u32)> = [
+        (LogTable::AccessLogs, config.access_log_days),
+        // The pipeline events behind access log entries go with them
+        (LogTable::Events, config.access_log_days),
+        (LogTable::AdminAudit, config.audit_log_days),
+    ]
+    .into_iter()
+    .filter(|(_, days)| *days > 0)
+    .collect();
+    if logs.is_empty() {
+        return;
+    }
+
+    let archive_dir = PathBuf::from(&config.archive_dir);
+    let run_hour = config.run_hour;
+    tokio::spawn(async move {
+        // The hour is checked several times, but pruned only once a night
+        let mut last_run: Option<NaiveDate> = None;
+        loop {
+            let now = Utc::now().with_timezone(&timezone);
+            if now.hour() == run_hour && last_run != Some(now.date_naive()) {
+                last_run = Some(now.date_naive());
+                for (table, days) in &logs {
+                    let cutoff = Utc::now() - chrono::Duration::days((*days).into());
+                    let table_name = table.name();
+                    match prune(&pool, &archive_dir, *table, cutoff).await {
+                        Ok(0) => {}
+                        Ok(rows) => info!(table = table_name, rows, "Pruned old log rows"),
+                        Err(e) => error!(table = table_name, error = %e, "Log pruning failed"),
+                    }
+                }
+            }
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    });
+}
+
+// Rows are only deleted once their archive file is complete and synced to disk, so a failure
+// anywhere before that leaves them in Postgres for the next night. Returns the rows deleted.
+async fn prune(
+    pool: &Pool<Postgres>,
+    archive_dir: &Path,
+    table: LogTable,
+    cutoff: DateTime<Utc>,
+) -> Result<u64, String> {
+    let path = archive_dir.join(format!(
+        "{}-before-{}.ndjson.gz",
+        table.name(),
+        cutoff.format("%Y%m%dT%H%M%SZ")
+    ));
+
+    let mut archive = blocking(path.clone(), |path| {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(GzEncoder::new(
+            File::create_new(&path)?,
+            Compression::default(),
+        ))
+    })
+    .await?;
+
+    let mut rows = stream_log_rows_before(pool, table, cutoff);
+    let mut archived = 0;
+    let mut buffer = Vec::new();
+    while let Some(row) = rows.next().await {
+        let row = row.map_err(|e| format!("failed to read rows: {}", e))?;
+        serde_json::to_writer(&mut buffer, &row).map_err(|e| e.to_string())?;
+        buffer.push(b'\n');
+        archived += 1;
+        if buffer.len() >= WRITE_BATCH_BYTES {
+            archive = write(archive, std::mem::take(&mut buffer)).await?;
+        }
+    }
+    drop(rows);
+
+    archive = write(archive, buffer).await?;
+    blocking(archive, |archive| archive.finish()?.sync_all()).await?;
+    if archived == 0 {
+        // Nothing was old enough; the empty archive is not worth keeping
+        let _ = blocking(path, fs::remove_file).await;
+        return Ok(0);
+    }
+
+    delete_log_rows_before(pool, table, cutoff)
+        .await
+        .map_err(|e| {
+            format!(
+                "archived to {}, but failed to delete rows: {}",
+                path.display(),
+                e
+            )
+        })
+}
+
+async fn write(archive: Archive, buffer: Vec<u8>) -> Result<Archive, String> {
+    blocking(archive, move |mut archive| {
+        archive.write_all(&buffer)?;
+        Ok(archive)
+    })
+    .await
+}
+
+// File I/O runs off the async workers
+async fn blocking<T, R>(
+    value: T,
+    f: impl FnOnce(T) -> std::io::Result<R> + Send + 'static,
+) -> Result<R, String>
+where
+    T: Send + 'static,
+    R: Send + 'static,
+{
+    task::spawn_blocking(move || f(value))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("failed to write archive: {}", e))
+}
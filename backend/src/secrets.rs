@@ -0,0 +1,180 @@
+//! Where the secrets in `config::SECRETS` come from: the JWT signing keys, the Portal nostr keys
+//! and the IntelliM and MQTT passwords. Like every other setting they can sit in the config file or the
+//! environment, or they come from the `SecretStore` that `[secrets]` names: one file per secret
+//! in a directory, such as Docker or Kubernetes secrets mounted at `/run/secrets`, or a secret
+//! in HashiCorp Vault. The store is read once, by `Config::load`, and what it holds wins over the
+//! config file and the environment.
+
+use crate::config::{SecretsConfig, SecretsProvider};
+
+use reqwest::{Client, StatusCode};
+use rocket::tokio::fs;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const VAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Looks secrets up by name, the name of the environment variable that would otherwise hold
+/// them (`JWT_SECRET`). Errors are human readable and never contain a secret.
+#[rocket::async_trait]
+pub trait SecretStore: Send + Sync {
+    /// The secrets among `names` the store holds; the others are left out.
+    async fn load(&self, names: &[&'static str]) -> Result<HashMap<&'static str, String>, String>;
+}
+
+/// The store `config` names. Its settings are checked by `Config::load` first.
+pub fn store(config: &SecretsConfig) -> Result<Box<dyn SecretStore>, String> {
+    Ok(match config.provider {
+        SecretsProvider::Env => Box::new(EnvStore),
+        SecretsProvider::File => Box::new(FileStore::new(&config.dir)),
+        SecretsProvider::Vault => Box::new(VaultStore::new(config)?),
+    })
+}
+
+/// Secrets as environment variables of their own name, the same ones `Config::load` reads
+/// anyway.
+pub struct EnvStore;
+
+#[rocket::async_trait]
+impl SecretStore for EnvStore {
+    async fn load(&self, names: &[&'static str]) -> Result<HashMap<&'static str, String>, String> {
+        Ok(names
+            .iter()
+            .filter_map(|name| env::var(name).ok().map(|value| (*name, value)))
+            .collect())
+    }
+}
+
+/// Secrets as files in one directory, each named after its secret. A trailing newline, as
+/// editors and `echo` leave, is not part of the secret.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+#[rocket::async_trait]
+impl SecretStore for FileStore {
+    async fn load(&self, names: &[&'static str]) -> Result<HashMap<&'static str, String>, String> {
+        let mut secrets = HashMap::new();
+        for name in names {
+            let path = self.dir.join(name);
+            match fs::read_to_string(&path).await {
+                Ok(value) => {
+                    secrets.insert(*name, value.trim_end_matches(['\r', '\n']).to_string());
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(format!("cannot read {}: {}", path.display(), e)),
+            }
+        }
+
+        Ok(secrets)
+    }
+}
+
+/// Secrets as the fields of one secret in HashiCorp Vault, read with a Vault token. Both
+/// versions of the KV engine work: `vault_path` is `secret/data/portal-access` for a version 2
+/// engine mounted at `secret`, or `secret/portal-access` for version 1.
+pub struct VaultStore {
+    client: Client,
+    url: String,
+    path: String,
+    token: String,
+}
+
+impl VaultStore {
+    fn new(config: &SecretsConfig) -> Result<Self, String> {
+        let client = Client::builder()
+            .timeout(VAULT_TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let path = config.vault_path.trim_matches('/').to_string();
+        Ok(Self {
+            client,
+            url: format!("{}/v1/{}", config.vault_addr.trim_end_matches('/'), path),
+            path,
+            token: config.vault_token.clone(),
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl SecretStore for VaultStore {
+    async fn load(&self, names: &[&'static str]) -> Result<HashMap<&'static str, String>, String> {
+        let response = self
+            .client
+            .get(&self.url)
+            .header("X-Vault-Token", &self.token)
+            .send()
+            .await
+            .map_err(|e| format!("Vault request failed: {}", e))?;
+        match response.status() {
+            StatusCode::NOT_FOUND => return Err(format!("Vault has no secret at {}", self.path)),
+            status if !status.is_success() => {
+                return Err(format!("Vault answered {} for {}", status, self.path));
+            }
+            _ => {}
+        }
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| format!("Vault request failed: {}", e))?;
+        let body: Value = serde_json::from_slice(&body)
+            .map_err(|e| format!("Vault answered with an unreadable secret: {}", e))?;
+
+        // Version 2 of the KV engine nests the fields one level deeper, next to their metadata
+        let fields = match &body["data"]["data"] {
+            Value::Object(fields) => fields,
+            _ => match &body["data"] {
+                Value::Object(fields) => fields,
+                _ => return Err(format!("Vault secret {} has no fields", self.path)),
+            },
+        };
+        let mut secrets = HashMap::new();
+        for name in names {
+            match fields.get(*name) {
+                Some(Value::String(value)) => {
+                    secrets.insert(*name, value.clone());
+                }
+                Some(_) => return Err(format!("Vault field {} must be a string", name)),
+                None => {}
+            }
+        }
+
+        Ok(secrets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uuid::Uuid;
+
+    #[rocket::async_test]
+    async fn file_secrets_drop_the_trailing_newline() {
+        let dir = std::env::temp_dir().join(format!("secrets-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).expect("Failed to create secrets dir");
+        std::fs::write(dir.join("JWT_SECRET"), "rotated secret\n").expect("Failed to write secret");
+
+        let secrets = FileStore::new(&dir)
+            .load(&["JWT_SECRET", "INTELLIM_PASSWORD"])
+            .await
+            .expect("Failed to load secrets");
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(
+            secrets.get("JWT_SECRET").map(String::as_str),
+            Some("rotated secret")
+        );
+        assert!(!secrets.contains_key("INTELLIM_PASSWORD"));
+    }
+}
@@ -0,0 +1,49 @@
+use crate::config::HttpConfig;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::{Request, Response};
+
+/// Sets the Content-Security-Policy, Strict-Transport-Security, X-Frame-Options,
+/// X-Content-Type-Options and Referrer-Policy headers on every response. A route that sets its
+/// own Content-Security-Policy keeps it.
+pub struct SecurityHeaders {
+    content_security_policy: Option<String>,
+    strict_transport_security: Option<String>,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: &HttpConfig) -> Self {
+        let policy = config.content_security_policy.trim();
+        Self {
+            content_security_policy: (!policy.is_empty()).then(|| policy.to_string()),
+            // Browsers ignore it over plain HTTP, so it only takes hold once HTTPS is in front
+            strict_transport_security: (config.hsts_max_age_secs > 0)
+                .then(|| format!("max-age={}", config.hsts_max_age_secs)),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for SecurityHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Security headers",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, _: &'r Request<'_>, res: &mut Response<'r>) {
+        if let Some(policy) = &self.content_security_policy
+            && res.headers().get_one("Content-Security-Policy").is_none()
+        {
+            res.set_header(Header::new("Content-Security-Policy", policy.clone()));
+        }
+        if let Some(hsts) = &self.strict_transport_security {
+            res.set_header(Header::new("Strict-Transport-Security", hsts.clone()));
+        }
+        res.set_header(Header::new("X-Frame-Options", "DENY"));
+        res.set_header(Header::new("X-Content-Type-Options", "nosniff"));
+        res.set_header(Header::new("Referrer-Policy", "same-origin"));
+    }
+}
@@ -0,0 +1,262 @@
+use crate::auth::{Claims, JWTKeys, Role, create_token, remove_auth_cookie, set_auth_cookie};
+use crate::client_ip::ClientIp;
+use crate::config::SessionsConfig;
+use crate::database::helpers::{
+    delete_expired_sessions, delete_session_by_token, get_user_by_username, insert_session,
+    set_session_site, touch_session,
+};
+
+use chrono::{DateTime, Duration, Utc};
+use rocket::{
+    State,
+    http::{Cookie, CookieJar, Status},
+    request::{FromRequest, Outcome, Request},
+};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres};
+use tracing::{error, info};
+use uuid::Uuid;
+
+const REFRESH_COOKIE: &str = "refresh_token";
+
+// Longest User-Agent kept with a session
+const MAX_USER_AGENT_LEN: usize = 256;
+
+/// How long access tokens and idle refresh sessions last, and passwords when they expire.
+#[derive(Clone, Copy)]
+pub struct SessionPolicy {
+    pub access_ttl: Duration,
+    pub idle: Duration,
+    pub password_max_age: Option<Duration>,
+}
+
+impl SessionPolicy {
+    pub fn new(config: &SessionsConfig) -> Self {
+        Self {
+            access_ttl: Duration::minutes(config.access_token_minutes.into()),
+            idle: Duration::days(config.idle_days.into()),
+            password_max_age: (config.password_max_age_days > 0)
+                .then(|| Duration::days(config.password_max_age_days.into())),
+        }
+    }
+
+    /// When a password set at `changed_at` has to be changed, if passwords expire.
+    pub fn password_expires_at(&self, changed_at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.password_max_age.map(|max_age| changed_at + max_age)
+    }
+
+    pub fn password_expired(&self, changed_at: DateTime<Utc>) -> bool {
+        self.password_expires_at(changed_at)
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+}
+
+/// Request guard for signing in, refreshing and signing out. A login starts a row in
+/// `sessions` and hands out a short-lived access token plus a refresh token in an HttpOnly
+/// cookie; each refresh issues a new access token and pushes the session's expiry forward,
+/// so only sessions left idle for `SessionPolicy::idle` run out.
+pub struct Sessions<'r> {
+    pool: Pool<Postgres>,
+    keys: &'r JWTKeys,
+    policy: SessionPolicy,
+    cookies: &'r CookieJar<'r>,
+    user_agent: Option<String>,
+    ip: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Sessions<'r> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let pool = req.guard::<&State<Pool<Postgres>>>().await;
+        let keys = req.guard::<&State<JWTKeys>>().await;
+        let policy = req.guard::<&State<SessionPolicy>>().await;
+        match (pool, keys, policy) {
+            (Outcome::Success(pool), Outcome::Success(keys), Outcome::Success(policy)) => {
+                Outcome::Success(Sessions {
+                    pool: pool.inner().clone(),
+                    keys: keys.inner(),
+                    policy: *policy.inner(),
+                    cookies: req.cookies(),
+                    user_agent: req
+                        .headers()
+                        .get_one("User-Agent")
+                        .map(|ua| ua.chars().take(MAX_USER_AGENT_LEN).collect()),
+                    ip: ClientIp::of(req).map(|ip| ip.to_string()),
+                })
+            }
+            _ => Outcome::Error((Status::InternalServerError, ())),
+        }
+    }
+}
+
+impl Sessions<'_> {
+    pub fn access_ttl(&self) -> Duration {
+        self.policy.access_ttl
+    }
+
+    pub fn password_expired(&self, changed_at: DateTime<Utc>) -> bool {
+        self.policy.password_expired(changed_at)
+    }
+
+    /// Opens a session for a user who just proved their password and sets both cookies.
+    /// `site` is the user's own site, `None` for users of every site. Returns the access token;
+    /// when the password set at `password_changed_at` has expired, it only lets the user change it.
+    pub async fn start(
+        &self,
+        username: String,
+        role: Role,
+        site: Option<Uuid>,
+        password_changed_at: DateTime<Utc>,
+    ) -> Result<String, String> {
+        if let Err(e) = delete_expired_sessions(&self.pool).await {
+            error!(error = ?e, "Failed to purge expired sessions");
+        }
+
+        let refresh_token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        let session_id = insert_session(
+            &self.pool,
+            &username,
+            &hash_refresh_token(&refresh_token),
+            self.user_agent.as_deref(),
+            self.ip.as_deref(),
+            Utc::now() + self.policy.idle,
+            site,
+        )
+        .await
+        .map_err(|e| format!("Failed to start session: {:?}", e))?;
+
+        let mut claims = Claims::new(
+            username,
+            role,
+            Some(session_id),
+            site,
+            self.policy.access_ttl,
+        );
+        claims.password_expired = self.policy.password_expired(password_changed_at);
+        let token = create_token(&claims, self.keys)
+            .map_err(|e| format!("Failed to create authentication token: {}", e))?;
+
+        self.set_refresh_cookie(refresh_token);
+        set_auth_cookie(self.cookies, token.clone(), self.policy.access_ttl);
+        Ok(token)
+    }
+
+    /// Trades the refresh cookie for a new access token, re-reading the user's role and site; a
+    /// user not tied to one site keeps the site picked for the session. Fails
+    /// with `Unauthorized` when the session is missing, revoked or expired.
+    pub async fn refresh(&self) -> Result<(Claims, String), Status> {
+        let refresh_token = match self.cookies.get(REFRESH_COOKIE) {
+            Some(cookie) => cookie.value().to_string(),
+            None => return Err(Status::Unauthorized),
+        };
+
+        let session = match touch_session(
+            &self.pool,
+            &hash_refresh_token(&refresh_token),
+            Utc::now() + self.policy.idle,
+        )
+        .await
+        {
+            Ok(Some(session)) => session,
+            Ok(None) => {
+                self.cookies.remove(Cookie::new(REFRESH_COOKIE, ""));
+                return Err(Status::Unauthorized);
+            }
+            Err(e) => {
+                error!(error = ?e, "Failed to refresh session");
+                return Err(Status::InternalServerError);
+            }
+        };
+
+        let (role, site, password_changed_at) =
+            match get_user_by_username(&self.pool, &session.username).await {
+                Ok(Some(user)) => (
+                    user.role
+                        .parse::<Role>()
+                        .map_err(|_| Status::Unauthorized)?,
+                    user.site_id.or(session.site_id),
+                    user.password_changed_at,
+                ),
+                Ok(None) => return Err(Status::Unauthorized),
+                Err(e) => {
+                    error!(error = ?e, "Failed to load session user");
+                    return Err(Status::InternalServerError);
+                }
+            };
+
+        let mut claims = Claims::new(
+            session.username,
+            role,
+            Some(session.id),
+            site,
+            self.policy.access_ttl,
+        );
+        claims.password_expired = self.policy.password_expired(password_changed_at);
+        let token = create_token(&claims, self.keys).map_err(|_| Status::InternalServerError)?;
+
+        info!(session = %session.id, user = %claims.sub, "Session refreshed");
+        self.set_refresh_cookie(refresh_token);
+        set_auth_cookie(self.cookies, token.clone(), self.policy.access_ttl);
+        Ok((claims, token))
+    }
+
+    /// Moves the session of `claims` to `site`, or to every site with `None`, and re-issues the
+    /// access token for it.
+    pub async fn switch_site(&self, claims: &Claims, site: Option<Uuid>) -> Result<(), String> {
+        let Some(session_id) = claims.sid else {
+            return Err("Only signed-in sessions can switch sites".to_string());
+        };
+        if !set_session_site(&self.pool, session_id, site)
+            .await
+            .map_err(|e| format!("Failed to switch site: {:?}", e))?
+        {
+            return Err("Session no longer exists".to_string());
+        }
+
+        let mut switched = Claims::new(
+            claims.sub.clone(),
+            claims.role,
+            Some(session_id),
+            site,
+            self.policy.access_ttl,
+        );
+        switched.password_expired = claims.password_expired;
+        let token = create_token(&switched, self.keys)
+            .map_err(|e| format!("Failed to create authentication token: {}", e))?;
+        set_auth_cookie(self.cookies, token, self.policy.access_ttl);
+        Ok(())
+    }
+
+    /// Revokes the session behind the refresh cookie, if any, and clears both cookies.
+    pub async fn end(&self) {
+        if let Some(cookie) = self.cookies.get(REFRESH_COOKIE)
+            && let Err(e) =
+                delete_session_by_token(&self.pool, &hash_refresh_token(cookie.value())).await
+        {
+            error!(error = ?e, "Failed to revoke session");
+        }
+
+        self.cookies.remove(Cookie::new(REFRESH_COOKIE, ""));
+        remove_auth_cookie(self.cookies);
+    }
+
+    // The cookie's lifetime slides along with the session's expiry
+    fn set_refresh_cookie(&self, refresh_token: String) {
+        let mut cookie = Cookie::new(REFRESH_COOKIE, refresh_token);
+        cookie.set_http_only(true);
+        cookie.set_secure(true);
+        cookie.set_same_site(rocket::http::SameSite::Strict);
+        cookie.set_max_age(rocket::time::Duration::seconds(
+            self.policy.idle.num_seconds(),
+        ));
+
+        self.cookies.add(cookie);
+    }
+}
+
+// Only a hash is stored, so a leaked sessions table cannot be replayed
+fn hash_refresh_token(refresh_token: &str) -> String {
+    hex::encode(Sha256::digest(refresh_token.as_bytes()))
+}
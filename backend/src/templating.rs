@@ -0,0 +1,172 @@
+use crate::database::health::DbHealth;
+use crate::i18n::{Catalogs, Language, SOURCE_LANGUAGE};
+use crate::theme::Theme;
+use chrono::DateTime;
+use chrono_tz::Tz;
+use rocket::fairing::Fairing;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+use rocket_dyn_templates::Template;
+use rocket_dyn_templates::handlebars::{
+    Context, Handlebars, Helper, HelperDef, RenderContext, RenderError, ScopedJson,
+};
+use serde::Serialize;
+
+/// `{{#if (db_degraded)}}` in templates, backed by the shared database health flag.
+struct DbDegradedHelper(DbHealth);
+
+impl HelperDef for DbDegradedHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        _: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        Ok(ScopedJson::Derived(serde_json::Value::Bool(
+            !self.0.is_healthy(),
+        )))
+    }
+}
+
+// How `local_time` renders a timestamp
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// `{{local_time this.created_at}}` shows a stored UTC timestamp in the site timezone.
+/// Anything that is not an RFC 3339 timestamp is passed through unchanged.
+struct LocalTimeHelper(Tz);
+
+impl HelperDef for LocalTimeHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let value = helper.param(0).map(|param| param.value());
+        let rendered = match value.and_then(|value| value.as_str()) {
+            Some(text) => match DateTime::parse_from_rfc3339(text) {
+                Ok(timestamp) => timestamp
+                    .with_timezone(&self.0)
+                    .format(TIME_FORMAT)
+                    .to_string(),
+                Err(_) => text.to_string(),
+            },
+            None => String::new(),
+        };
+
+        Ok(ScopedJson::Derived(serde_json::Value::String(rendered)))
+    }
+}
+
+/// `{{site_timezone}}`, the IANA name of the timezone `local_time` renders in.
+struct SiteTimezoneHelper(Tz);
+
+impl HelperDef for SiteTimezoneHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        _: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        Ok(ScopedJson::Derived(serde_json::Value::String(
+            self.0.name().to_string(),
+        )))
+    }
+}
+
+/// `{{t "Keys"}}` shows English text in the page's language, see `i18n::Catalogs`. Further
+/// parameters fill `{0}`, `{1}`... in the text: `{{t "Page {0} of {1}" page total_pages}}`.
+struct TranslateHelper(Catalogs);
+
+impl HelperDef for TranslateHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let text = helper
+            .param(0)
+            .and_then(|param| param.value().as_str())
+            .unwrap_or_default();
+        let language = ctx
+            .data()
+            .get("lang")
+            .and_then(|lang| lang.as_str())
+            .unwrap_or(SOURCE_LANGUAGE);
+        let args: Vec<String> = helper
+            .params()
+            .iter()
+            .skip(1)
+            .map(|param| match param.value() {
+                serde_json::Value::String(arg) => arg.clone(),
+                arg => arg.to_string(),
+            })
+            .collect();
+
+        Ok(ScopedJson::Derived(serde_json::Value::String(
+            self.0.format(language, text, &args),
+        )))
+    }
+}
+
+/// A template rendered in the request's `Language`, which `{{t}}` reads from the `lang` it adds
+/// to the context, next to the `languages` to pick from and the current `theme`.
+pub struct Page {
+    name: &'static str,
+    context: serde_json::Value,
+    status: Status,
+}
+
+impl Page {
+    pub fn render<C: Serialize>(name: &'static str, context: C) -> Self {
+        Page {
+            name,
+            context: serde_json::to_value(context).unwrap_or_default(),
+            status: Status::Ok,
+        }
+    }
+
+    /// Answers with `status` instead of 200, e.g. for a form shown again with its error.
+    pub fn with_status(self, status: Status) -> Self {
+        Page { status, ..self }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Page {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let mut context = self.context;
+        if let (Some(catalogs), Some(fields)) =
+            (req.rocket().state::<Catalogs>(), context.as_object_mut())
+        {
+            let Language(language) = Language::of(req);
+            fields.insert("languages".into(), serde_json::json!(catalogs.names()));
+            fields.insert("lang".into(), serde_json::Value::String(language));
+        }
+        if let (Some(theme), Some(fields)) =
+            (req.rocket().state::<Theme>(), context.as_object_mut())
+        {
+            fields.insert("theme".into(), serde_json::json!(theme.current()));
+        }
+
+        let mut response = Template::render(self.name, context).respond_to(req)?;
+        response.set_status(self.status);
+        Ok(response)
+    }
+}
+
+/// Template fairing with the application's custom helpers registered.
+pub fn fairing(db_health: DbHealth, timezone: Tz, catalogs: Catalogs) -> impl Fairing {
+    Template::custom(move |engines| {
+        let handlebars = &mut engines.handlebars;
+        handlebars.register_helper("t", Box::new(TranslateHelper(catalogs.clone())));
+        handlebars.register_helper("db_degraded", Box::new(DbDegradedHelper(db_health.clone())));
+        handlebars.register_helper("local_time", Box::new(LocalTimeHelper(timezone)));
+        handlebars.register_helper("site_timezone", Box::new(SiteTimezoneHelper(timezone)));
+    })
+}
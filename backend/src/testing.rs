@@ -0,0 +1,1298 @@
+//! Stand-ins for Portal and the door hardware, so the handshake pipeline (notification, key
+//! checks, authentication, unlock, access log) can run in-process. Built with the `testing`
+//! feature; `cargo test --features testing` runs the pipeline tests below against the Postgres
+//! server in `DATABASE_URL`, each in a fresh database.
+
+pub use crate::door_backend::MockDoorBackend;
+
+use crate::access_events::EventLog;
+use crate::admin_feed::AdminFeed;
+use crate::alerts::Alerter;
+use crate::config::{
+    AlertsConfig, PaymentsConfig, RateLimitConfig, RelockConfig, SiteConfig, UnlockRetryConfig,
+    WebhooksConfig,
+};
+use crate::door_backend::UnlockRetry;
+use crate::emergency::OverrideState;
+use crate::key_cache::KeyCache;
+use crate::live_logs::LogFeed;
+use crate::portal_loop::{AccessController, HandshakeUrls, RelayHealth};
+use crate::rate_limit::{Debouncer, RateLimiter};
+use crate::relays::{Authentication, KeyHandshakes, PaymentUpdates, PortalClient};
+use crate::relock::RelockVerifier;
+use crate::webhooks::WebhookDispatcher;
+
+use portal::nostr::PublicKey;
+use portal::profile::Profile;
+use portal::protocol::model::payment::SinglePaymentRequestContent;
+use rocket::futures::{StreamExt, stream};
+use rocket::tokio::sync::{Notify, mpsc};
+use sqlx::{Pool, Postgres};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// In-process notification source standing in for Portal and its relays. A test completes a
+/// handshake with `complete_handshake`; authentication requests are approved unless the key
+/// was passed to `decline`. Payment requests always fail, so paid doors record an error, and no key has a profile.
+#[derive(Default)]
+pub struct InProcessPortal {
+    handshakes: Mutex<HashMap<String, mpsc::UnboundedSender<Result<PublicKey, String>>>>,
+    opened: Notify,
+    declined: Mutex<HashSet<PublicKey>>,
+    authentications: Mutex<Vec<PublicKey>>,
+}
+
+impl InProcessPortal {
+    /// Delivers `key` as if it completed the handshake URL opened under `subject`, waiting
+    /// for the handshake loop to open it first.
+    pub async fn complete_handshake(&self, subject: &str, key: PublicKey) {
+        loop {
+            let opened = self.opened.notified();
+            let sender = self
+                .handshakes
+                .lock()
+                .expect("Handshake lock poisoned")
+                .get(subject)
+                .cloned();
+            if let Some(sender) = sender
+                && sender.send(Ok(key)).is_ok()
+            {
+                return;
+            }
+            opened.await;
+        }
+    }
+
+    /// Whether the handshake URL opened under `subject` is still listened on.
+    pub fn is_open(&self, subject: &str) -> bool {
+        self.handshakes
+            .lock()
+            .expect("Handshake lock poisoned")
+            .get(subject)
+            .is_some_and(|sender| !sender.is_closed())
+    }
+
+    /// Makes the key's wallet decline every authentication request.
+    pub fn decline(&self, key: PublicKey) {
+        self.declined
+            .lock()
+            .expect("Declined keys lock poisoned")
+            .insert(key);
+    }
+
+    /// Keys that were asked to authenticate, in order.
+    pub fn authentications(&self) -> Vec<PublicKey> {
+        self.authentications
+            .lock()
+            .expect("Authentications lock poisoned")
+            .clone()
+    }
+}
+
+#[rocket::async_trait]
+impl PortalClient for InProcessPortal {
+    async fn key_handshakes(
+        &self,
+        static_token: Option<String>,
+    ) -> Result<(String, KeyHandshakes), String> {
+        let subject = static_token.unwrap_or_else(|| Uuid::new_v4().to_string());
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let url = format!("nostr+walletconnect://in-process/{}", subject);
+        self.handshakes
+            .lock()
+            .expect("Handshake lock poisoned")
+            .insert(subject, sender);
+        self.opened.notify_waiters();
+
+        let handshakes = stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|key| (key, receiver))
+        });
+
+        Ok((url, handshakes.boxed()))
+    }
+
+    async fn authenticate(&self, key: PublicKey) -> Result<Authentication, String> {
+        self.authentications
+            .lock()
+            .expect("Authentications lock poisoned")
+            .push(key);
+        let declined = self
+            .declined
+            .lock()
+            .expect("Declined keys lock poisoned")
+            .contains(&key);
+
+        Ok(if declined {
+            Authentication::Declined(None)
+        } else {
+            Authentication::Approved(Vec::new())
+        })
+    }
+
+    async fn request_payment(
+        &self,
+        _key: PublicKey,
+        _request: SinglePaymentRequestContent,
+    ) -> Result<PaymentUpdates, String> {
+        Err("payments are not simulated".to_string())
+    }
+
+    async fn fetch_profile(&self, _key: PublicKey) -> Result<Option<Profile>, String> {
+        Ok(None)
+    }
+}
+
+/// A handshake loop for `door_id` wired to `portal` and `door`, with the default settings.
+pub fn controller(
+    pool: Pool<Postgres>,
+    portal: Arc<InProcessPortal>,
+    door: Arc<MockDoorBackend>,
+    door_id: u32,
+) -> AccessController {
+    let rate_limit = RateLimitConfig::default();
+    let alerts = Alerter::new(pool.clone(), &AlertsConfig::default())
+        .expect("Default alert settings are valid");
+    let admin_feed = AdminFeed::default();
+    AccessController {
+        webhooks: WebhookDispatcher::new(pool.clone(), &WebhooksConfig::default()),
+        events: EventLog::new(pool.clone()),
+        relock: RelockVerifier::new(
+            pool.clone(),
+            door.clone(),
+            alerts.clone(),
+            admin_feed.clone(),
+            &RelockConfig::default(),
+        ),
+        alerts,
+        pool,
+        overrides: OverrideState::default(),
+        portal,
+        door,
+        door_id,
+        subject: format!("door-{}", door_id),
+        rate_limiter: RateLimiter::new(&rate_limit),
+        debouncer: Debouncer::new(&rate_limit),
+        log_feed: LogFeed::default(),
+        admin_feed,
+        payment_timeout: Duration::from_secs(PaymentsConfig::default().timeout_secs),
+        unlock_retry: UnlockRetry::new(&UnlockRetryConfig::default()),
+        relay_health: RelayHealth::default(),
+        handshake_urls: HandshakeUrls::default(),
+        timezone: SiteConfig::default().tz(),
+        enrollment_limit: None,
+        doorbell_answer: None,
+        key_cache: KeyCache::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_events::{Decision, replay};
+    use crate::admin_feed::AdminEvent;
+    use crate::auth::{Scope, hash_api_token};
+    use crate::client_ip::ClientNetworks;
+    use crate::config::HttpConfig;
+    use crate::controllers::access::deny_key_request;
+    use crate::controllers::home_assistant::home_assistant_unlock;
+    use crate::database::helpers::{
+        AccessLog, AccessResult, DEFAULT_SITE, DenyReason, KeyBatchAction, NewAccessLog,
+        ScheduleWindow, accept_admin_invite, approve_pending_key, batch_update_keys, ensure_door,
+        get_admin_invite_by_token_hash, get_admin_invites, get_all_keys, get_door_by_controller_id,
+        get_door_schedules, get_pending_keys, get_recent_door_events, get_site_by_id,
+        get_unusual_hour_unlocks, get_user_by_username, grant_key_doors, insert_access_log,
+        insert_admin_invite, insert_api_token, insert_credential, insert_door_schedule,
+        insert_group, insert_key, insert_manual_access_log, insert_pending_key, insert_site,
+        revoke_admin_invite, set_door_direction, set_door_groups, set_door_handshake,
+        set_group_keys, set_key_photo, set_lockdown, set_site_occupancy, take_doorbell_ring,
+        use_totp_step, verify_access_log_chain,
+    };
+    use crate::digest;
+    use crate::door_backend::{DoorBackend, DoorState, FailoverBackend};
+    use crate::emergency::OverrideMode;
+    use crate::mqtt;
+    use crate::opening_hours::{Availability, Conditions, availability, weekly_hours};
+    use crate::policy;
+    use portal::nostr::Keys;
+    use portal::nostr::nips::nip19::ToBech32;
+    use rocket::http::{Accept, Header, Status};
+    use rocket::local::asynchronous::Client;
+    use rocket::tokio::sync::{broadcast, watch};
+    use rocket::tokio::{
+        self,
+        time::{sleep, timeout},
+    };
+
+    const DOOR_ID: u32 = 1;
+
+    // One running handshake loop and what it talks to
+    struct Pipeline {
+        pool: Pool<Postgres>,
+        portal: Arc<InProcessPortal>,
+        door: Arc<MockDoorBackend>,
+        controller: AccessController,
+        subject: String,
+        entries: broadcast::Receiver<AccessLog>,
+        events: broadcast::Receiver<AdminEvent>,
+        _shutdown: watch::Sender<bool>,
+    }
+
+    impl Pipeline {
+        async fn start(pool: Pool<Postgres>) -> Self {
+            Self::start_with(pool, |_| {}).await
+        }
+
+        // Starts the loop with settings changed from the defaults
+        async fn start_with(
+            pool: Pool<Postgres>,
+            configure: impl FnOnce(&mut AccessController),
+        ) -> Self {
+            ensure_door(&pool, DOOR_ID as i32)
+                .await
+                .expect("Failed to register door");
+            let portal = Arc::new(InProcessPortal::default());
+            let door = Arc::new(MockDoorBackend::default());
+            let mut controller = controller(pool.clone(), portal.clone(), door.clone(), DOOR_ID);
+            configure(&mut controller);
+            let entries = controller.log_feed.subscribe();
+            let events = controller.admin_feed.subscribe();
+            let subject = controller.subject.clone();
+
+            let (shutdown, shutdown_rx) = watch::channel(false);
+            tokio::spawn(controller.clone().run(shutdown_rx));
+
+            Self {
+                pool,
+                portal,
+                door,
+                controller,
+                subject,
+                entries,
+                events,
+                _shutdown: shutdown,
+            }
+        }
+
+        // A key allowed on the door
+        async fn allowed_key(&self) -> PublicKey {
+            let key = Keys::generate().public_key();
+            let npub = key.to_bech32().expect("Infallible");
+            let key_id = insert_key(&self.pool, &npub, None, None, DEFAULT_SITE)
+                .await
+                .expect("Failed to add key");
+            let door = get_door_by_controller_id(&self.pool, DOOR_ID as i32)
+                .await
+                .expect("Failed to load door")
+                .expect("Door is registered");
+            grant_key_doors(&self.pool, key_id, &[door.id])
+                .await
+                .expect("Failed to grant door");
+            key
+        }
+
+        // Completes a handshake and waits for the access log entry it ends in
+        async fn handshake(&mut self, key: PublicKey) -> AccessLog {
+            self.portal.complete_handshake(&self.subject, key).await;
+            timeout(Duration::from_secs(5), self.entries.recv())
+                .await
+                .expect("No access log entry recorded")
+                .expect("Log feed closed")
+        }
+
+        // Presents a card at the door's badge reader and waits for its access log entry
+        async fn card(&mut self, card_number: &str) -> AccessLog {
+            self.controller.handle_card(card_number.to_string()).await;
+            timeout(Duration::from_secs(5), self.entries.recv())
+                .await
+                .expect("No access log entry recorded")
+                .expect("Log feed closed")
+        }
+
+        async fn door_state(&self) -> DoorState {
+            self.door
+                .status(DOOR_ID)
+                .await
+                .expect("Mock door always answers")
+        }
+    }
+
+    #[sqlx::test]
+    async fn allowed_key_unlocks_the_door(pool: Pool<Postgres>) {
+        let mut pipeline = Pipeline::start(pool).await;
+        let key = pipeline.allowed_key().await;
+
+        let entry = pipeline.handshake(key).await;
+
+        assert_eq!(entry.result, "granted");
+        assert_eq!(entry.door_id, Some(DOOR_ID as i32));
+        assert_eq!(pipeline.door_state().await, DoorState::Unlocked);
+    }
+
+    #[sqlx::test]
+    async fn unknown_key_is_denied_before_authentication(pool: Pool<Postgres>) {
+        let mut pipeline = Pipeline::start(pool).await;
+
+        let entry = pipeline.handshake(Keys::generate().public_key()).await;
+
+        assert_eq!(entry.result, "denied");
+        assert_eq!(entry.reason_code.as_deref(), Some("key_unknown"));
+        assert!(pipeline.portal.authentications().is_empty());
+        assert_eq!(pipeline.door_state().await, DoorState::Locked);
+    }
+
+    #[sqlx::test]
+    async fn open_enrollment_adds_unknown_key_as_pending(pool: Pool<Postgres>) {
+        let mut pipeline = Pipeline::start_with(pool, |controller| {
+            controller.enrollment_limit = Some(10);
+        })
+        .await;
+        let key = Keys::generate().public_key();
+        let npub = key.to_bech32().expect("Infallible");
+
+        let entry = pipeline.handshake(key).await;
+
+        assert_eq!(entry.result, "denied");
+        assert_eq!(entry.reason_code.as_deref(), Some("key_unknown"));
+        let keys = get_all_keys(&pipeline.pool, None)
+            .await
+            .expect("Failed to load keys");
+        let enrolled = keys
+            .iter()
+            .find(|enrolled| enrolled.npub == npub)
+            .expect("Key was enrolled");
+        assert!(enrolled.pending);
+        assert!(!enrolled.status);
+        assert_eq!(pipeline.door_state().await, DoorState::Locked);
+    }
+
+    #[sqlx::test]
+    async fn unknown_key_rings_the_doorbell_once(pool: Pool<Postgres>) {
+        let mut pipeline = Pipeline::start_with(pool, |controller| {
+            controller.doorbell_answer = Some(Duration::from_secs(60));
+        })
+        .await;
+        let key = Keys::generate().public_key();
+        let npub = key.to_bech32().expect("Infallible");
+
+        let entry = pipeline.handshake(key).await;
+
+        assert_eq!(entry.result, "denied");
+        let event = timeout(Duration::from_secs(5), pipeline.events.recv())
+            .await
+            .expect("Doorbell did not ring")
+            .expect("Admin feed closed");
+        let AdminEvent::Doorbell {
+            ring_id,
+            npub: visitor,
+            state,
+            ..
+        } = event
+        else {
+            panic!("Expected a doorbell event, got {:?}", event);
+        };
+        assert_eq!(visitor, npub);
+        assert_eq!(state, "ringing");
+        let answered = take_doorbell_ring(&pipeline.pool, ring_id, None)
+            .await
+            .expect("Failed to answer");
+        assert!(answered.is_some());
+        let answered_again = take_doorbell_ring(&pipeline.pool, ring_id, None)
+            .await
+            .expect("Failed to answer");
+        assert!(answered_again.is_none());
+        assert_eq!(pipeline.door_state().await, DoorState::Locked);
+    }
+
+    #[sqlx::test]
+    async fn key_of_another_site_is_not_allowed(pool: Pool<Postgres>) {
+        let mut pipeline = Pipeline::start(pool).await;
+        let key = Keys::generate().public_key();
+        let npub = key.to_bech32().expect("Infallible");
+        let site = insert_site(&pipeline.pool, "Annex")
+            .await
+            .expect("Failed to add site");
+        let key_id = insert_key(&pipeline.pool, &npub, None, None, site)
+            .await
+            .expect("Failed to add key");
+        // Groups are shared by every site, so they can reach doors of other sites
+        let door = get_door_by_controller_id(&pipeline.pool, DOOR_ID as i32)
+            .await
+            .expect("Failed to load door")
+            .expect("Door is registered");
+        let group_id = insert_group(&pipeline.pool, "staff")
+            .await
+            .expect("Failed to add group");
+        set_group_keys(&pipeline.pool, group_id, &[key_id])
+            .await
+            .expect("Failed to add key to group");
+        set_door_groups(&pipeline.pool, door.id, &[group_id])
+            .await
+            .expect("Failed to grant door to group");
+
+        let entry = pipeline.handshake(key).await;
+
+        assert_eq!(entry.result, "denied");
+        assert_eq!(entry.reason_code.as_deref(), Some("door_not_allowed"));
+        assert_eq!(entry.site_id, Some(DEFAULT_SITE));
+        assert_eq!(pipeline.door_state().await, DoorState::Locked);
+    }
+
+    #[sqlx::test]
+    async fn declined_authentication_keeps_the_door_locked(pool: Pool<Postgres>) {
+        let mut pipeline = Pipeline::start(pool).await;
+        let key = pipeline.allowed_key().await;
+        pipeline.portal.decline(key);
+
+        let entry = pipeline.handshake(key).await;
+
+        assert_eq!(entry.result, "declined");
+        assert_eq!(
+            entry.reason_code.as_deref(),
+            Some("authentication_declined")
+        );
+        assert_eq!(pipeline.portal.authentications(), vec![key]);
+        assert_eq!(pipeline.door_state().await, DoorState::Locked);
+    }
+
+    #[sqlx::test]
+    async fn failed_unlock_is_logged_as_error(pool: Pool<Postgres>) {
+        let mut pipeline = Pipeline::start(pool).await;
+        let key = pipeline.allowed_key().await;
+        pipeline.door.fail_unlocks(Some("controller offline")).await;
+
+        let entry = pipeline.handshake(key).await;
+
+        assert_eq!(entry.result, "error");
+        assert_eq!(entry.reason.as_deref(), Some("controller offline"));
+    }
+
+    #[sqlx::test]
+    async fn daily_digest_sums_up_the_day(pool: Pool<Postgres>) {
+        let mut pipeline = Pipeline::start(pool).await;
+        let key = pipeline.allowed_key().await;
+        let other_key = pipeline.allowed_key().await;
+        pipeline.handshake(key).await;
+        pipeline.handshake(Keys::generate().public_key()).await;
+        pipeline.door.fail_unlocks(Some("controller offline")).await;
+        pipeline.handshake(other_key).await;
+
+        let today = chrono::Utc::now().date_naive();
+        let digest = digest::collect(&pipeline.pool, None, today, chrono_tz::Tz::UTC)
+            .await
+            .expect("Failed to collect digest");
+
+        assert_eq!(digest.counts.unlocks, 1);
+        assert_eq!(digest.counts.declined, 1);
+        assert_eq!(digest.counts.new_keys, 2);
+        assert_eq!(digest.doors.len(), 1);
+        assert_eq!(digest.doors[0].door_id, DOOR_ID as i32);
+        assert_eq!(digest.doors[0].unlock_errors, 1);
+
+        let template = digest::template().expect("Digest template is valid");
+        let message = digest::render(&template, &digest).expect("Failed to render digest");
+        assert!(message.subject.contains(&today.to_string()));
+        assert!(message.html.contains("Doors with errors"));
+        assert!(message.text.contains("1 failed unlocks"));
+    }
+
+    #[sqlx::test]
+    async fn failed_unlock_fails_over_to_the_secondary_controller(pool: Pool<Postgres>) {
+        let secondary = Arc::new(MockDoorBackend::default());
+        let secondaries = HashMap::from([(DOOR_ID, secondary.clone() as Arc<dyn DoorBackend>)]);
+        let mut pipeline = Pipeline::start_with(pool, |controller| {
+            controller.door = Arc::new(FailoverBackend::new(controller.door.clone(), secondaries));
+        })
+        .await;
+        let key = pipeline.allowed_key().await;
+        pipeline.door.fail_unlocks(Some("controller offline")).await;
+
+        let entry = pipeline.handshake(key).await;
+
+        assert_eq!(entry.result, "granted");
+        assert_eq!(
+            entry.reason.as_deref(),
+            Some("unlocked by the secondary controller")
+        );
+        assert_eq!(
+            secondary
+                .status(DOOR_ID)
+                .await
+                .expect("Mock door always answers"),
+            DoorState::Unlocked
+        );
+    }
+
+    #[sqlx::test]
+    async fn site_lockdown_denies_allowed_keys(pool: Pool<Postgres>) {
+        let mut pipeline = Pipeline::start(pool).await;
+        let key = pipeline.allowed_key().await;
+        set_lockdown(&pipeline.pool, true, None, "test")
+            .await
+            .expect("Failed to set lockdown");
+
+        let entry = pipeline.handshake(key).await;
+
+        assert_eq!(entry.result, "denied");
+        assert_eq!(entry.reason_code.as_deref(), Some("site_lockdown"));
+        assert_eq!(pipeline.door_state().await, DoorState::Locked);
+    }
+
+    #[sqlx::test]
+    async fn full_site_declines_entries(pool: Pool<Postgres>) {
+        let mut pipeline = Pipeline::start(pool).await;
+        let door = get_door_by_controller_id(&pipeline.pool, DOOR_ID as i32)
+            .await
+            .expect("Failed to load door")
+            .expect("Door is registered");
+        set_door_direction(&pipeline.pool, door.id, Some("entry"))
+            .await
+            .expect("Failed to set direction");
+        set_site_occupancy(&pipeline.pool, DEFAULT_SITE, Some(1), None)
+            .await
+            .expect("Failed to set occupancy limit");
+        let first = pipeline.allowed_key().await;
+        let second = pipeline.allowed_key().await;
+
+        let entry = pipeline.handshake(first).await;
+        assert_eq!(entry.result, "granted");
+        let site = get_site_by_id(&pipeline.pool, DEFAULT_SITE)
+            .await
+            .expect("Failed to load site")
+            .expect("Default site exists");
+        assert_eq!(site.occupancy, 1);
+
+        let entry = pipeline.handshake(second).await;
+        assert_eq!(entry.result, "denied");
+        assert_eq!(entry.reason_code.as_deref(), Some("occupancy_full"));
+    }
+
+    #[sqlx::test]
+    async fn single_use_handshake_url_is_replaced_after_a_handshake(pool: Pool<Postgres>) {
+        ensure_door(&pool, DOOR_ID as i32)
+            .await
+            .expect("Failed to register door");
+        let door = get_door_by_controller_id(&pool, DOOR_ID as i32)
+            .await
+            .expect("Failed to load door")
+            .expect("Door is registered");
+        set_door_handshake(&pool, door.id, None, None, true)
+            .await
+            .expect("Failed to set handshake");
+        let mut pipeline = Pipeline::start(pool).await;
+        let mut changes = pipeline.controller.handshake_urls.subscribe();
+
+        // The URL the loop listens on, once it has one
+        let urls = pipeline.controller.handshake_urls.clone();
+        let next_url = |previous: Option<String>| {
+            let urls = urls.clone();
+            async move {
+                loop {
+                    if let Some(Some(url)) = urls.get(DOOR_ID)
+                        && Some(&url) != previous.as_ref()
+                    {
+                        return url;
+                    }
+                    sleep(Duration::from_millis(20)).await;
+                }
+            }
+        };
+        let first = timeout(Duration::from_secs(5), next_url(None))
+            .await
+            .expect("No handshake URL created");
+        assert!(!first.ends_with(&pipeline.subject));
+
+        let key = pipeline.allowed_key().await;
+        pipeline.subject = first
+            .rsplit('/')
+            .next()
+            .expect("URL has a path")
+            .to_string();
+        let entry = pipeline.handshake(key).await;
+        assert_eq!(entry.result, "granted");
+
+        let second = timeout(Duration::from_secs(5), next_url(Some(first.clone())))
+            .await
+            .expect("Handshake URL was not replaced");
+        assert_ne!(first, second);
+        let announced = loop {
+            let (door_id, url) = changes.recv().await.expect("Changes are announced");
+            assert_eq!(door_id, DOOR_ID);
+            if url.as_ref() != Some(&first) {
+                break url;
+            }
+        };
+        assert_eq!(announced, Some(second.clone()));
+
+        // Nothing listens on the used URL any more, only on its replacement
+        let portal = pipeline.portal.clone();
+        let used = pipeline.subject.clone();
+        timeout(Duration::from_secs(5), async move {
+            while portal.is_open(&used) {
+                sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("Used handshake URL is still open");
+        let replacement = second.rsplit('/').next().expect("URL has a path");
+        assert!(pipeline.portal.is_open(replacement));
+    }
+
+    #[sqlx::test]
+    async fn expired_key_is_denied(pool: Pool<Postgres>) {
+        let mut pipeline = Pipeline::start(pool).await;
+        let key = pipeline.allowed_key().await;
+        let key_ids: Vec<_> = get_all_keys(&pipeline.pool, None)
+            .await
+            .expect("Failed to load keys")
+            .iter()
+            .map(|key| key.id)
+            .collect();
+        let expired =
+            KeyBatchAction::SetExpiry(Some(chrono::Utc::now() - chrono::Duration::minutes(1)));
+        assert!(
+            batch_update_keys(&pipeline.pool, &key_ids, &expired, None)
+                .await
+                .expect("Failed to expire key")
+        );
+
+        let entry = pipeline.handshake(key).await;
+
+        assert_eq!(entry.result, "denied");
+        assert_eq!(entry.reason_code.as_deref(), Some("key_expired"));
+        assert_eq!(pipeline.door_state().await, DoorState::Locked);
+    }
+
+    #[sqlx::test]
+    async fn granted_decision_replays_from_its_events(pool: Pool<Postgres>) {
+        let mut pipeline = Pipeline::start(pool).await;
+        let key = pipeline.allowed_key().await;
+
+        let entry = pipeline.handshake(key).await;
+
+        // Events are written in the background, the logged stage last
+        let trail = timeout(Duration::from_secs(5), async {
+            loop {
+                let trail = replay(&pipeline.pool, entry.id)
+                    .await
+                    .expect("Failed to replay");
+                if trail.replay.is_some() {
+                    return trail;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("Events were not recorded");
+        let stages: Vec<_> = trail
+            .events
+            .iter()
+            .map(|event| event.stage.as_str())
+            .collect();
+        assert_eq!(
+            stages,
+            [
+                "handshake_received",
+                "key_resolved",
+                "decided",
+                "authenticated",
+                "actuated",
+                "logged"
+            ]
+        );
+        let replayed = trail.replay.expect("Decision was replayed");
+        assert_eq!(replayed.recorded, Decision::Allow { price_sats: None });
+        assert!(replayed.matches);
+    }
+
+    #[sqlx::test]
+    async fn policy_rules_apply_in_order(pool: Pool<Postgres>) {
+        let pipeline = Pipeline::start(pool).await;
+        let key = pipeline.allowed_key().await;
+        let npub = key.to_bech32().expect("Infallible");
+
+        let (resolution, decision) = pipeline
+            .controller
+            .evaluate(&npub, chrono::Utc::now())
+            .await
+            .expect("Failed to evaluate");
+        assert_eq!(decision, Decision::Allow { price_sats: None });
+
+        // A door lockdown keeps allowed keys out, except under a grant-all override
+        let mut locked = resolution.clone();
+        if let Some(door) = locked.door.as_mut() {
+            door.locked_down = true;
+        }
+        assert_eq!(
+            policy::evaluate(&locked),
+            Decision::Deny {
+                reason: DenyReason::DoorLockdown
+            }
+        );
+        locked.override_mode = Some(OverrideMode::GrantAll);
+        assert_eq!(
+            policy::evaluate(&locked),
+            Decision::Allow { price_sats: None }
+        );
+
+        // A disabled door goes before the override
+        if let Some(door) = locked.door.as_mut() {
+            door.enabled = false;
+        }
+        assert_eq!(
+            policy::evaluate(&locked),
+            Decision::Deny {
+                reason: DenyReason::DoorDisabled
+            }
+        );
+
+        // The key's own rules, in order
+        let mut outside = resolution.clone();
+        if let Some(rules) = outside.rules.as_mut() {
+            rules.windows = vec![ScheduleWindow {
+                weekdays: Vec::new(),
+                start_time: chrono::NaiveTime::MIN,
+                end_time: chrono::NaiveTime::MIN,
+            }];
+            rules.door_ids.clear();
+        }
+        assert_eq!(
+            policy::key_denial(&outside),
+            Some(DenyReason::OutsideSchedule)
+        );
+        outside.rules = None;
+        assert_eq!(
+            policy::evaluate(&outside),
+            Decision::Deny {
+                reason: DenyReason::KeyUnknown
+            }
+        );
+    }
+
+    #[sqlx::test]
+    async fn live_entry_carries_the_key_photo(pool: Pool<Postgres>) {
+        let mut pipeline = Pipeline::start(pool).await;
+        let key = pipeline.allowed_key().await;
+        let npub = key.to_bech32().expect("Infallible");
+        let key_id = get_all_keys(&pipeline.pool, None)
+            .await
+            .expect("Failed to load keys")
+            .into_iter()
+            .find(|key| key.npub == npub)
+            .expect("Key was added")
+            .id;
+        set_key_photo(
+            &pipeline.pool,
+            key_id,
+            None,
+            Some(("photo.jpg", "image/jpeg")),
+        )
+        .await
+        .expect("Failed to set photo")
+        .expect("Key exists");
+
+        let entry = pipeline.handshake(key).await;
+
+        assert_eq!(entry.result, "granted");
+        assert_eq!(entry.photo_url, Some(format!("/keys/{}/photo", key_id)));
+    }
+
+    #[sqlx::test]
+    async fn unlock_outside_the_usual_hours_is_flagged(pool: Pool<Postgres>) {
+        let today = chrono::Utc::now()
+            .date_naive()
+            .and_hms_opt(0, 0, 0)
+            .expect("Midnight exists")
+            .and_utc();
+        // Twenty mornings at nine, then one unlock at nine and one at three today
+        let mut times: Vec<_> = (1..=20)
+            .map(|days| today - chrono::Duration::days(days) + chrono::Duration::hours(9))
+            .collect();
+        times.push(today + chrono::Duration::minutes(9 * 60 + 30));
+        times.push(today + chrono::Duration::hours(3));
+        for at in times {
+            sqlx::query(
+                "INSERT INTO access_logs (npub, door_id, result, created_at)
+                 VALUES ('npub1habits', 1, 'granted', $1)",
+            )
+            .bind(at)
+            .execute(&pool)
+            .await
+            .expect("Failed to insert access log entry");
+        }
+
+        let unusual = get_unusual_hour_unlocks(
+            &pool,
+            today,
+            today + chrono::Duration::days(1),
+            30,
+            20,
+            "UTC",
+        )
+        .await
+        .expect("Failed to check unlocks");
+
+        assert_eq!(unusual.len(), 1);
+        assert_eq!(unusual[0].hour, 3);
+        assert_eq!(unusual[0].history, 20);
+        // With less history the key is not checked at all
+        let unusual = get_unusual_hour_unlocks(
+            &pool,
+            today,
+            today + chrono::Duration::days(1),
+            30,
+            22,
+            "UTC",
+        )
+        .await
+        .expect("Failed to check unlocks");
+        assert!(unusual.is_empty());
+    }
+
+    #[sqlx::test]
+    async fn card_opens_the_doors_of_its_key(pool: Pool<Postgres>) {
+        let mut pipeline = Pipeline::start(pool).await;
+        let key = pipeline.allowed_key().await;
+        let npub = key.to_bech32().expect("Infallible");
+        let key_id = get_all_keys(&pipeline.pool, None)
+            .await
+            .expect("Failed to load keys")
+            .into_iter()
+            .find(|key| key.npub == npub)
+            .expect("Key was added")
+            .id;
+        insert_credential(&pipeline.pool, key_id, "4711", None)
+            .await
+            .expect("Failed to add card")
+            .expect("Card is new");
+
+        let unknown = pipeline.card("999").await;
+        assert_eq!(unknown.result, "denied");
+        assert_eq!(unknown.reason_code.as_deref(), Some("key_unknown"));
+        assert_eq!(unknown.npub.as_deref(), Some("card:999"));
+        assert_eq!(pipeline.door_state().await, DoorState::Locked);
+
+        let entry = pipeline.card("4711").await;
+        assert_eq!(entry.result, "granted");
+        assert_eq!(entry.npub, Some(npub));
+        assert_eq!(entry.credential.as_deref(), Some("4711"));
+        assert!(pipeline.portal.authentications().is_empty());
+        assert_eq!(pipeline.door_state().await, DoorState::Unlocked);
+    }
+
+    #[sqlx::test]
+    async fn door_left_unlocked_is_reported(pool: Pool<Postgres>) {
+        let mut pipeline = Pipeline::start_with(pool, |controller| {
+            let relock = RelockConfig {
+                grace_secs: 1,
+                poll_secs: 1,
+                default_unlock_secs: 0,
+            };
+            controller.relock = RelockVerifier::new(
+                controller.pool.clone(),
+                controller.door.clone(),
+                controller.alerts.clone(),
+                controller.admin_feed.clone(),
+                &relock,
+            );
+        })
+        .await;
+        let key = pipeline.allowed_key().await;
+
+        // The mock door stays unlocked until it is told to lock
+        let entry = pipeline.handshake(key).await;
+        assert_eq!(entry.result, "granted");
+
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let event = pipeline.events.recv().await.expect("Admin feed closed");
+                if matches!(event, AdminEvent::Door { state, .. } if state == "relock_failed") {
+                    break;
+                }
+            }
+        })
+        .await
+        .expect("Failed relock not reported");
+        let door = get_door_by_controller_id(&pipeline.pool, DOOR_ID as i32)
+            .await
+            .expect("Failed to load door")
+            .expect("Door is registered");
+        let events = get_recent_door_events(&pipeline.pool, door.id, 10)
+            .await
+            .expect("Failed to load door events");
+        assert!(events.iter().any(|event| event.event == "relock_failed"));
+    }
+
+    #[sqlx::test]
+    async fn tampered_access_log_breaks_the_chain(pool: Pool<Postgres>) {
+        let granted = vec!["door".to_string(), "visite à l'étage".to_string()];
+        let mut ids = Vec::new();
+        for reason in [None, Some("premier passage"), Some("a\nb")] {
+            let entry = insert_access_log(
+                &pool,
+                &NewAccessLog {
+                    npub: "npub1tamper",
+                    door_id: Some(DOOR_ID as i32),
+                    result: AccessResult::Granted,
+                    reason_code: None,
+                    reason,
+                    granted_permissions: Some(&granted),
+                    credential: None,
+                },
+            )
+            .await
+            .expect("Failed to log access");
+            ids.push(entry.id);
+        }
+        insert_manual_access_log(&pool, "root", DOOR_ID as i32, AccessResult::Granted, None)
+            .await
+            .expect("Failed to log manual unlock");
+
+        let verification = verify_access_log_chain(&pool)
+            .await
+            .expect("Failed to verify");
+        assert!(verification.is_intact(), "{:?}", verification.breaks);
+        assert_eq!(verification.entries, 4);
+        assert_eq!(verification.anchor, None);
+
+        sqlx::query("UPDATE access_logs SET result = 'denied' WHERE id = $1")
+            .bind(ids[1])
+            .execute(&pool)
+            .await
+            .expect("Failed to tamper");
+        sqlx::query("DELETE FROM access_logs WHERE id = $1")
+            .bind(ids[2])
+            .execute(&pool)
+            .await
+            .expect("Failed to tamper");
+
+        let verification = verify_access_log_chain(&pool)
+            .await
+            .expect("Failed to verify");
+        let problems: Vec<_> = verification
+            .breaks
+            .iter()
+            .map(|chain_break| chain_break.problem)
+            .collect();
+        assert_eq!(
+            problems,
+            [
+                "content does not match its hash",
+                "does not follow the entry before it"
+            ]
+        );
+        assert_eq!(verification.breaks[0].id, ids[1]);
+    }
+
+    #[sqlx::test]
+    async fn invite_link_creates_one_account(pool: Pool<Postgres>) {
+        let expires_at = chrono::Utc::now() + chrono::Duration::hours(1);
+        insert_admin_invite(&pool, "link", "admin", None, "root", expires_at)
+            .await
+            .expect("Failed to add invite");
+        insert_admin_invite(&pool, "revoked", "viewer", None, "root", expires_at)
+            .await
+            .expect("Failed to add invite");
+
+        let accepted = accept_admin_invite(&pool, "link", "alice", "hash", Some("00ff"), Some(100))
+            .await
+            .expect("Failed to accept invite");
+        assert!(accepted.is_some());
+        let user = get_user_by_username(&pool, "alice")
+            .await
+            .expect("Failed to load user")
+            .expect("Account was created");
+        assert_eq!(user.role, "admin");
+        assert_eq!(user.totp_secret.as_deref(), Some("00ff"));
+
+        // The code that confirmed the authenticator app, or an older one, cannot sign in; each
+        // later code signs in once
+        for (step, accepted) in [(100, false), (99, false), (101, true), (101, false)] {
+            assert_eq!(
+                use_totp_step(&pool, user.id, step)
+                    .await
+                    .expect("Failed to record code"),
+                accepted,
+                "step {}",
+                step
+            );
+        }
+
+        // The link works once
+        let again = accept_admin_invite(&pool, "link", "mallory", "hash", None, None)
+            .await
+            .expect("Failed to accept invite");
+        assert!(again.is_none());
+
+        let revoked = get_admin_invite_by_token_hash(&pool, "revoked")
+            .await
+            .expect("Failed to load invite")
+            .expect("Invite exists");
+        assert!(
+            revoke_admin_invite(&pool, revoked.id, None)
+                .await
+                .expect("Failed to revoke invite")
+        );
+        let refused = accept_admin_invite(&pool, "revoked", "mallory", "hash", None, None)
+            .await
+            .expect("Failed to accept invite");
+        assert!(refused.is_none());
+        let invites = get_admin_invites(&pool, None)
+            .await
+            .expect("Failed to load invites");
+        let mut statuses: Vec<_> = invites
+            .iter()
+            .map(|invite| invite.status.as_str())
+            .collect();
+        statuses.sort();
+        assert_eq!(statuses, ["accepted", "revoked"]);
+    }
+
+    #[sqlx::test]
+    async fn opening_hours_follow_schedules_and_lockdowns(pool: Pool<Postgres>) {
+        ensure_door(&pool, DOOR_ID as i32)
+            .await
+            .expect("Failed to register door");
+        let door = get_door_by_controller_id(&pool, DOOR_ID as i32)
+            .await
+            .expect("Failed to load door")
+            .expect("Door exists");
+        let time = |hour| chrono::NaiveTime::from_hms_opt(hour, 0, 0).expect("Valid time");
+        insert_door_schedule(&pool, door.id, &[1, 2, 3, 4, 5], time(8), time(18))
+            .await
+            .expect("Failed to add schedule");
+        let schedules = get_door_schedules(&pool, door.id)
+            .await
+            .expect("Failed to load schedules");
+
+        // Monday 2026-10-12, in Rome
+        let rome: chrono_tz::Tz = "Europe/Rome".parse().expect("Valid timezone");
+        let at = |hour| {
+            chrono::TimeZone::with_ymd_and_hms(&rome, 2026, 10, 12, hour, 30, 0)
+                .single()
+                .expect("Valid time")
+        };
+        let conditions = |hour, site_lockdown, override_mode| Conditions {
+            site: None,
+            schedules: &schedules,
+            site_lockdown,
+            override_mode,
+            at: at(hour),
+        };
+
+        let (state, until) = availability(&door, &conditions(9, false, None));
+        assert_eq!(state, Availability::Open);
+        assert_eq!(
+            until,
+            Some(chrono::TimeZone::with_ymd_and_hms(&chrono::Utc, 2026, 10, 12, 16, 0, 0).unwrap())
+        );
+        assert_eq!(
+            availability(&door, &conditions(19, false, None)).0,
+            Availability::Members
+        );
+        assert_eq!(
+            availability(&door, &conditions(9, true, None)).0,
+            Availability::Closed
+        );
+        assert_eq!(
+            availability(&door, &conditions(19, false, Some(OverrideMode::GrantAll))).0,
+            Availability::Open
+        );
+
+        let hours = weekly_hours(&schedules);
+        assert_eq!(hours[0].day, "Monday");
+        assert_eq!(hours[0].windows, ["08:00–18:00"]);
+        assert!(hours[6].windows.is_empty());
+    }
+
+    #[sqlx::test]
+    async fn mqtt_unlock_commands_need_the_unlock_scope(pool: Pool<Postgres>) {
+        let mut pipeline = Pipeline::start(pool).await;
+        for (token, scope) in [
+            ("pat_readonly", Scope::ReadLogs),
+            ("pat_unlock", Scope::Unlock),
+        ] {
+            insert_api_token(
+                &pipeline.pool,
+                token,
+                &hash_api_token(token),
+                &[scope.as_str().to_string()],
+                "admin",
+                None,
+            )
+            .await
+            .expect("Failed to insert API token");
+        }
+
+        mqtt::unlock(
+            pipeline.controller.clone(),
+            DOOR_ID as i32,
+            "pat_readonly".to_string(),
+        )
+        .await;
+        assert_eq!(pipeline.door_state().await, DoorState::Locked);
+
+        mqtt::unlock(
+            pipeline.controller.clone(),
+            DOOR_ID as i32,
+            "pat_unlock".to_string(),
+        )
+        .await;
+        assert_eq!(pipeline.door_state().await, DoorState::Unlocked);
+        let entry = pipeline.entries.try_recv().expect("Unlock recorded");
+        assert_eq!(entry.triggered_by.as_deref(), Some("token:pat_unlock"));
+        assert_eq!(entry.result, "granted");
+    }
+
+    #[sqlx::test]
+    async fn access_requests_are_hidden_from_other_sites(pool: Pool<Postgres>) {
+        let annex = insert_site(&pool, "Annex")
+            .await
+            .expect("Failed to add site");
+        for (token, site) in [("pat_annex", Some(annex)), ("pat_everywhere", None)] {
+            insert_api_token(
+                &pool,
+                token,
+                &hash_api_token(token),
+                &[Scope::ManageKeys.as_str().to_string()],
+                "admin",
+                site,
+            )
+            .await
+            .expect("Failed to insert API token");
+        }
+        let npub = Keys::generate()
+            .public_key()
+            .to_bech32()
+            .expect("Infallible");
+        insert_pending_key(&pool, &npub, None, Some("192.0.2.1"))
+            .await
+            .expect("Failed to file request");
+        let request = get_pending_keys(&pool, None)
+            .await
+            .expect("Failed to load requests")
+            .pop()
+            .expect("Request filed");
+        assert!(
+            get_pending_keys(&pool, Some(annex))
+                .await
+                .expect("Failed to load requests")
+                .is_empty()
+        );
+        assert!(
+            approve_pending_key(&pool, request.id, Some(annex))
+                .await
+                .expect("Failed to approve request")
+                .is_none()
+        );
+
+        let rocket = rocket::build()
+            .manage(pool.clone())
+            .mount("/", rocket::routes![deny_key_request]);
+        let client = Client::untracked(rocket)
+            .await
+            .expect("Failed to start Rocket");
+        let deny = |token: &'static str| {
+            client
+                .post(format!("/keys/pending/{}/deny", request.id))
+                .header(Header::new("Authorization", format!("Bearer {}", token)))
+                .header(Accept::JSON)
+                .dispatch()
+        };
+
+        assert_eq!(deny("pat_annex").await.status(), Status::NotFound);
+        assert_eq!(deny("pat_everywhere").await.status(), Status::Ok);
+    }
+
+    #[sqlx::test]
+    async fn api_tokens_are_held_to_the_admin_allowlist(pool: Pool<Postgres>) {
+        insert_api_token(
+            &pool,
+            "pat_keys",
+            &hash_api_token("pat_keys"),
+            &[Scope::ManageKeys.as_str().to_string()],
+            "admin",
+            None,
+        )
+        .await
+        .expect("Failed to insert API token");
+
+        for (allowlist_api_tokens, outside) in
+            [(true, Status::Forbidden), (false, Status::NotFound)]
+        {
+            let http = HttpConfig {
+                admin_allowlist: "10.0.0.0/8".to_string(),
+                allowlist_api_tokens,
+                ..HttpConfig::default()
+            };
+            let rocket = rocket::build()
+                .manage(pool.clone())
+                .manage(ClientNetworks::new(&http))
+                .mount("/", rocket::routes![deny_key_request]);
+            let client = Client::untracked(rocket)
+                .await
+                .expect("Failed to start Rocket");
+            let deny = |remote: &str| {
+                client
+                    .post(format!("/keys/pending/{}/deny", Uuid::new_v4()))
+                    .remote(remote.parse().expect("Valid address"))
+                    .header(Header::new("Authorization", "Bearer pat_keys"))
+                    .header(Accept::JSON)
+                    .dispatch()
+            };
+
+            // Past the guard, the request is simply not there
+            assert_eq!(deny("10.0.0.5:4000").await.status(), Status::NotFound);
+            assert_eq!(deny("192.0.2.1:4000").await.status(), outside);
+        }
+    }
+
+    #[sqlx::test]
+    async fn home_assistant_unlock_needs_the_unlock_scope(pool: Pool<Postgres>) {
+        let mut pipeline = Pipeline::start(pool).await;
+        for (token, scopes) in [
+            ("pat_locks", vec![Scope::HomeAssistant]),
+            ("pat_unlock", vec![Scope::HomeAssistant, Scope::Unlock]),
+        ] {
+            insert_api_token(
+                &pipeline.pool,
+                token,
+                &hash_api_token(token),
+                &scopes
+                    .iter()
+                    .map(|scope| scope.as_str().to_string())
+                    .collect::<Vec<_>>(),
+                "admin",
+                None,
+            )
+            .await
+            .expect("Failed to insert API token");
+        }
+        let door = get_door_by_controller_id(&pipeline.pool, DOOR_ID as i32)
+            .await
+            .expect("Failed to load door")
+            .expect("Door is registered");
+        let rocket = rocket::build()
+            .manage(pipeline.pool.clone())
+            .manage(pipeline.controller.clone())
+            .mount("/", rocket::routes![home_assistant_unlock]);
+        let client = Client::untracked(rocket)
+            .await
+            .expect("Failed to start Rocket");
+        let unlock = |token: &'static str| {
+            client
+                .post(format!("/home-assistant/locks/{}/unlock", door.id))
+                .header(Header::new("Authorization", format!("Bearer {}", token)))
+                .dispatch()
+        };
+
+        assert_eq!(unlock("pat_locks").await.status(), Status::Forbidden);
+        assert_eq!(pipeline.door_state().await, DoorState::Locked);
+
+        assert_eq!(unlock("pat_unlock").await.status(), Status::Ok);
+        assert_eq!(pipeline.door_state().await, DoorState::Unlocked);
+        let entry = pipeline.entries.try_recv().expect("Unlock recorded");
+        assert_eq!(entry.triggered_by.as_deref(), Some("token:pat_unlock"));
+    }
+}
@@ -0,0 +1,141 @@
+//! Branding of the pages: the site name, colors and logo admins set on `/settings/theme`, so
+//! the admin panel, the kiosk and the visitor pages can carry the operator's look without
+//! editing templates. The theme is kept in memory for rendering and shared with every template
+//! as `theme` by `templating::Page`; a change shows up on the next page load, and web services
+//! sharing the database read it again every minute.
+
+use crate::database::helpers::{ThemeSettings, get_theme};
+
+use rocket::fairing::{AdHoc, Fairing};
+use rocket::tokio;
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{error, warn};
+
+/// The name pages carry when no site name is set.
+pub const DEFAULT_SITE_NAME: &str = "Portal Access Control";
+
+// How often the theme is read again, for changes made through another web service
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What templates get as `theme`. Colors are only set when they replace the built-in ones.
+#[derive(Clone, Serialize)]
+pub struct ThemeContext {
+    pub site_name: String,
+    pub primary_color: Option<String>,
+    pub background_color: Option<String>,
+    pub text_color: Option<String>,
+    /// Changes with every new logo, so browsers do not keep showing the old one.
+    pub logo_url: Option<String>,
+}
+
+impl Default for ThemeContext {
+    fn default() -> Self {
+        Self {
+            site_name: DEFAULT_SITE_NAME.to_string(),
+            primary_color: None,
+            background_color: None,
+            text_color: None,
+            logo_url: None,
+        }
+    }
+}
+
+impl From<&ThemeSettings> for ThemeContext {
+    fn from(settings: &ThemeSettings) -> Self {
+        Self {
+            site_name: settings
+                .site_name
+                .clone()
+                .unwrap_or_else(|| DEFAULT_SITE_NAME.to_string()),
+            primary_color: settings.primary_color.clone(),
+            background_color: settings.background_color.clone(),
+            text_color: settings.text_color.clone(),
+            logo_url: settings
+                .logo
+                .as_ref()
+                .map(|_| format!("/theme/logo?v={}", settings.updated_at.timestamp())),
+        }
+    }
+}
+
+/// The current theme, managed by Rocket. Cloning is cheap.
+#[derive(Clone, Default)]
+pub struct Theme(Arc<RwLock<ThemeContext>>);
+
+impl Theme {
+    pub fn current(&self) -> ThemeContext {
+        self.0.read().expect("Theme lock poisoned").clone()
+    }
+
+    pub fn set(&self, settings: &ThemeSettings) {
+        *self.0.write().expect("Theme lock poisoned") = settings.into();
+    }
+}
+
+/// Whether `value` is a color the theme takes: `#` and six hex digits.
+pub fn is_color(value: &str) -> bool {
+    value.len() == 7 && value.starts_with('#') && value[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Loads the theme when Rocket ignites, manages it and keeps it fresh. Pages keep the built-in
+/// look until the theme could be read.
+pub fn fairing() -> impl Fairing {
+    AdHoc::on_ignite("Theme", |rocket| async move {
+        let theme = Theme::default();
+        if let Some(pool) = rocket.state::<Pool<Postgres>>() {
+            match get_theme(pool).await {
+                Ok(settings) => theme.set(&settings),
+                Err(e) => warn!(error = ?e, "Failed to load the theme, using the built-in one"),
+            }
+            tokio::spawn(refresh(pool.clone(), theme.clone()));
+        }
+        rocket.manage(theme)
+    })
+}
+
+async fn refresh(pool: Pool<Postgres>, theme: Theme) {
+    loop {
+        tokio::time::sleep(REFRESH_INTERVAL).await;
+        match get_theme(&pool).await {
+            Ok(settings) => theme.set(&settings),
+            Err(e) => error!(error = ?e, "Failed to refresh the theme"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::helpers::set_theme;
+
+    #[sqlx::test]
+    async fn theme_falls_back_to_the_built_in_look(pool: Pool<Postgres>) {
+        let theme = Theme::default();
+        theme.set(&get_theme(&pool).await.expect("Failed to load theme"));
+        let current = theme.current();
+        assert_eq!(current.site_name, DEFAULT_SITE_NAME);
+        assert_eq!(current.primary_color, None);
+        assert_eq!(current.logo_url, None);
+
+        let settings = set_theme(
+            &pool,
+            Some("Acme HQ"),
+            [Some("#ff6600"), None, None],
+            "admin",
+        )
+        .await
+        .expect("Failed to set theme");
+        theme.set(&settings);
+        let current = theme.current();
+        assert_eq!(current.site_name, "Acme HQ");
+        assert_eq!(current.primary_color.as_deref(), Some("#ff6600"));
+        assert_eq!(current.background_color, None);
+
+        assert!(is_color("#4fC3f7"));
+        assert!(!is_color("red"));
+        assert!(!is_color("#4fc3f7; }"));
+    }
+}
@@ -0,0 +1,110 @@
+//! Time-based one-time passwords (RFC 6238) for two-factor sign in: 6 digits from HMAC-SHA1
+//! over 30 second steps, the settings every authenticator app assumes when given no others.
+//! Secrets are kept as hex and handed to the app in base32.
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use sha1::Sha1;
+use uuid::Uuid;
+
+const ISSUER: &str = "Portal Access Control";
+const STEP_SECS: i64 = 30;
+const DIGITS: u32 = 6;
+// Steps either side of the current one still accepted, for phone clocks that are a little off
+const SKEW_STEPS: i64 = 1;
+
+/// A new 20 byte secret, as hex.
+pub fn generate_secret() -> String {
+    // v4 UUIDs are from the OS random source, like the password salts
+    let mut secret = Uuid::new_v4().as_bytes().to_vec();
+    secret.extend_from_slice(&Uuid::new_v4().as_bytes()[..4]);
+    hex::encode(secret)
+}
+
+/// The secret as typed into an authenticator app by hand. `None` for a secret that is not hex.
+pub fn setup_key(secret: &str) -> Option<String> {
+    hex::decode(secret).ok().map(|secret| base32(&secret))
+}
+
+/// The `otpauth://` URI of the secret for `account`, which authenticator apps scan as a QR code.
+pub fn otpauth_uri(account: &str, secret: &str) -> Option<String> {
+    let mut uri = Url::parse("otpauth://totp/").ok()?;
+    uri.set_path(&format!("{}:{}", ISSUER, account));
+    // Spaces as %20, since not every app reads `+` in the query as one
+    uri.set_query(Some(&format!(
+        "secret={}&issuer={}",
+        setup_key(secret)?,
+        ISSUER
+    )));
+    Some(uri.to_string())
+}
+
+/// The time step of `code` when it is the secret's current code, or the one just before or
+/// after it. A code stays valid for a few steps, so callers keep the last step used and only
+/// take later ones, see `use_totp_step`.
+pub fn verify(secret: &str, code: &str) -> Option<i64> {
+    let key = hex::decode(secret).ok()?;
+    let code = code.trim();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+    let code = code.parse::<u32>().ok()?;
+
+    let step = Utc::now().timestamp() / STEP_SECS;
+    (step - SKEW_STEPS..=step + SKEW_STEPS).find(|step| code_at(&key, *step as u64) == code)
+}
+
+// The code of one time step, with the dynamic truncation of RFC 4226
+fn code_at(key: &[u8], step: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    value % 10u32.pow(DIGITS)
+}
+
+// Base32 without padding (RFC 4648), the secret format of otpauth URIs
+fn base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut encoded = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+        buffer &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        encoded.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_matched_to_their_step() {
+        let secret = generate_secret();
+        let key = hex::decode(&secret).unwrap();
+        let step = Utc::now().timestamp() / STEP_SECS;
+        let code = |step: i64| format!("{:06}", code_at(&key, step as u64));
+
+        // A step may tick over between reading the clock here and in `verify`
+        assert!(matches!(verify(&secret, &code(step)), Some(found) if found >= step - 1));
+        assert_eq!(verify(&secret, &code(step - 5)), None);
+        assert_eq!(verify(&secret, "12345"), None);
+    }
+}
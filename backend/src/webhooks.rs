@@ -0,0 +1,314 @@
+use crate::config::WebhooksConfig;
+use crate::database::helpers::{
+    AccessResult, get_enabled_webhooks, insert_webhook_delivery, update_webhook_delivery,
+};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rocket::tokio::{
+    self,
+    sync::{OwnedSemaphorePermit, Semaphore, mpsc},
+};
+use sha2::Sha256;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+// Attempts per delivery before it is marked failed; the delay doubles from the initial backoff
+const MAX_ATTEMPTS: i32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Payload POSTed to every enabled webhook.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct AccessEvent {
+    pub event: &'static str,
+    pub npub: Option<String>,
+    pub triggered_by: Option<String>,
+    pub door_id: Option<i32>,
+    pub result: &'static str,
+    pub reason: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl AccessEvent {
+    /// Only unlocks, declined authentications and unpaid unlocks are announced; other outcomes
+    /// stay in the log.
+    pub fn from_result(
+        npub: &str,
+        door_id: Option<i32>,
+        result: AccessResult,
+        reason: Option<&str>,
+    ) -> Option<Self> {
+        let event = match result {
+            AccessResult::Granted => "door.unlocked",
+            AccessResult::Declined => "auth.declined",
+            AccessResult::Unpaid => "payment.failed",
+            AccessResult::Denied
+            | AccessResult::Error
+            | AccessResult::RateLimited
+            | AccessResult::Debounced => return None,
+        };
+
+        Some(Self {
+            event,
+            npub: Some(npub.to_string()),
+            triggered_by: None,
+            door_id,
+            result: result.as_str(),
+            reason: reason.map(str::to_string),
+            timestamp: Utc::now(),
+        })
+    }
+
+    /// A door unlocked by an admin from the UI instead of a Portal handshake.
+    pub fn manual_unlock(triggered_by: &str, door_id: i32) -> Self {
+        Self {
+            event: "door.unlocked",
+            npub: None,
+            triggered_by: Some(triggered_by.to_string()),
+            door_id: Some(door_id),
+            result: AccessResult::Granted.as_str(),
+            reason: Some("manual unlock".to_string()),
+            timestamp: Utc::now(),
+        }
+    }
+
+    /// A visitor let in by an admin answering the doorbell.
+    pub fn doorbell_unlock(npub: &str, triggered_by: &str, door_id: i32) -> Self {
+        Self {
+            event: "door.unlocked",
+            npub: Some(npub.to_string()),
+            triggered_by: Some(triggered_by.to_string()),
+            door_id: Some(door_id),
+            result: AccessResult::Granted.as_str(),
+            reason: Some("doorbell".to_string()),
+            timestamp: Utc::now(),
+        }
+    }
+
+    /// A door whose sensor has reported it open for longer than `sensors.held_open_secs`.
+    pub fn held_open(door_id: i32, open_secs: u64) -> Self {
+        Self {
+            event: "door.held_open",
+            npub: None,
+            triggered_by: None,
+            door_id: Some(door_id),
+            result: "held_open",
+            reason: Some(format!("open for {} seconds", open_secs)),
+            timestamp: Utc::now(),
+        }
+    }
+}
+
+/// Fans access events out to the configured webhooks in the background.
+///
+/// Events go through a bounded queue and deliveries run under a semaphore, so a burst of
+/// events can neither block the unlock path nor open an unbounded number of connections:
+/// - `webhooks.concurrency`: deliveries in flight at once (default 8)
+/// - `webhooks.queue_size`: events waiting for a free slot before new ones are dropped (default 256)
+#[derive(Clone)]
+pub struct WebhookDispatcher {
+    queue: mpsc::Sender<AccessEvent>,
+}
+
+impl WebhookDispatcher {
+    pub fn new(pool: Pool<Postgres>, config: &WebhooksConfig) -> Self {
+        let concurrency = config.concurrency.max(1);
+        let queue_size = config.queue_size.max(1);
+
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("Failed to build webhook HTTP client");
+
+        let (queue, events) = mpsc::channel(queue_size);
+        tokio::spawn(run_worker(
+            pool,
+            client,
+            Arc::new(Semaphore::new(concurrency)),
+            events,
+        ));
+
+        Self { queue }
+    }
+
+    /// Queue an event for delivery. Never waits: when the queue is full the event is dropped.
+    pub fn notify(&self, event: AccessEvent) {
+        if let Err(e) = self.queue.try_send(event) {
+            warn!(error = %e, "Webhook queue full or closed, dropping event");
+        }
+    }
+}
+
+async fn run_worker(
+    pool: Pool<Postgres>,
+    client: reqwest::Client,
+    permits: Arc<Semaphore>,
+    mut events: mpsc::Receiver<AccessEvent>,
+) {
+    while let Some(event) = events.recv().await {
+        let webhooks = match get_enabled_webhooks(&pool).await {
+            Ok(webhooks) => webhooks,
+            Err(e) => {
+                error!(error = ?e, "Failed to load webhooks");
+                continue;
+            }
+        };
+        if webhooks.is_empty() {
+            continue;
+        }
+
+        let payload = match serde_json::to_string(&event) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!(error = ?e, "Failed to serialize webhook payload");
+                continue;
+            }
+        };
+
+        for webhook in webhooks {
+            let delivery_id =
+                match insert_webhook_delivery(&pool, webhook.id, event.event, &payload).await {
+                    Ok(id) => id,
+                    Err(e) => {
+                        error!(error = ?e, "Failed to record webhook delivery");
+                        continue;
+                    }
+                };
+
+            // Waiting here is what queues events once every slot is busy
+            let Ok(permit) = permits.clone().acquire_owned().await else {
+                return;
+            };
+
+            tokio::spawn(deliver(
+                pool.clone(),
+                client.clone(),
+                permit,
+                Delivery {
+                    id: delivery_id,
+                    url: webhook.url,
+                    secret: webhook.secret,
+                    event: event.event,
+                    payload: payload.clone(),
+                },
+            ));
+        }
+    }
+}
+
+struct Delivery {
+    id: Uuid,
+    url: String,
+    secret: String,
+    event: &'static str,
+    payload: String,
+}
+
+// Holds its permit for every attempt, including the backoff in between
+async fn deliver(
+    pool: Pool<Postgres>,
+    client: reqwest::Client,
+    _permit: OwnedSemaphorePermit,
+    delivery: Delivery,
+) {
+    let mut backoff = INITIAL_BACKOFF;
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        // Signed again for every attempt, so a retry is not refused as a stale replay
+        let timestamp = Utc::now().timestamp();
+        let signature = sign(&delivery.secret, timestamp, &delivery.payload);
+        let result = client
+            .post(&delivery.url)
+            .header("Content-Type", "application/json")
+            .header("X-Portal-Event", delivery.event)
+            .header("X-Portal-Delivery", delivery.id.to_string())
+            .header("X-Portal-Timestamp", timestamp.to_string())
+            .header("X-Portal-Signature", format!("sha256={}", signature))
+            .body(delivery.payload.clone())
+            .send()
+            .await;
+
+        let (response_status, error) = match result {
+            Ok(response) if response.status().is_success() => {
+                let response_status = Some(response.status().as_u16() as i32);
+                record_attempt(
+                    &pool,
+                    delivery.id,
+                    "delivered",
+                    attempt,
+                    response_status,
+                    None,
+                )
+                .await;
+                return;
+            }
+            Ok(response) => (
+                Some(response.status().as_u16() as i32),
+                format!("HTTP {}", response.status()),
+            ),
+            Err(e) => (None, e.to_string()),
+        };
+
+        let status = if attempt == MAX_ATTEMPTS {
+            "failed"
+        } else {
+            "pending"
+        };
+        record_attempt(
+            &pool,
+            delivery.id,
+            status,
+            attempt,
+            response_status,
+            Some(&error),
+        )
+        .await;
+
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        } else {
+            warn!(url = %delivery.url, attempt, %error, "Webhook delivery failed");
+        }
+    }
+}
+
+async fn record_attempt(
+    pool: &Pool<Postgres>,
+    delivery_id: Uuid,
+    status: &str,
+    attempts: i32,
+    response_status: Option<i32>,
+    last_error: Option<&str>,
+) {
+    if let Err(e) = update_webhook_delivery(
+        pool,
+        delivery_id,
+        status,
+        attempts,
+        response_status,
+        last_error,
+    )
+    .await
+    {
+        error!(error = ?e, "Failed to update webhook delivery");
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of `<timestamp>.<body>`, keyed with the webhook's secret. The
+/// timestamp is signed too, so a captured delivery cannot be replayed later on.
+fn sign(secret: &str, timestamp: i64, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(format!("{}.{}", timestamp, payload).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Fresh random signing secret for a new webhook.
+pub fn generate_secret() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
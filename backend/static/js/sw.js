@@ -0,0 +1,58 @@
+// Service worker of the installed admin panel, served as /sw.js so its scope is the whole site.
+// Static files are kept for offline starts and refreshed in the background; pages and API
+// answers always come from the server, since they are per user and must be current.
+const CACHE = 'portal-static-v1';
+
+self.addEventListener('install', function(event) {
+    event.waitUntil(
+        caches.open(CACHE)
+            .then(function(cache) { return cache.addAll(['/static/css/style.css', '/static/icons/icon.svg']); })
+            .then(function() { return self.skipWaiting(); })
+    );
+});
+
+self.addEventListener('activate', function(event) {
+    event.waitUntil(
+        caches.keys()
+            .then(function(names) {
+                return Promise.all(names
+                    .filter(function(name) { return name !== CACHE; })
+                    .map(function(name) { return caches.delete(name); }));
+            })
+            .then(function() { return self.clients.claim(); })
+    );
+});
+
+self.addEventListener('fetch', function(event) {
+    const url = new URL(event.request.url);
+    if (event.request.method !== 'GET' || url.origin !== self.location.origin) return;
+
+    if (url.pathname.startsWith('/static/')) {
+        event.respondWith(caches.open(CACHE).then(function(cache) {
+            return cache.match(event.request).then(function(cached) {
+                const fresh = fetch(event.request).then(function(response) {
+                    if (response.ok) cache.put(event.request, response.clone());
+                    return response;
+                });
+                if (!cached) return fresh;
+                event.waitUntil(fresh.catch(function() {}));
+                return cached;
+            });
+        }));
+        return;
+    }
+
+    // Pages fail with a short note instead of the browser's error page while offline
+    if (event.request.mode === 'navigate') {
+        event.respondWith(fetch(event.request).catch(function() {
+            return new Response(
+                '<!DOCTYPE html><meta name="viewport" content="width=device-width, initial-scale=1.0">' +
+                '<title>Offline - Portal Access Control</title>' +
+                '<link rel="stylesheet" href="/static/css/style.css">' +
+                '<div class="container"><div class="empty-state"><h3>Offline</h3>' +
+                '<p>The admin panel needs a connection. Try again once you are back online.</p></div></div>',
+                { status: 503, headers: { 'Content-Type': 'text/html; charset=utf-8' } }
+            );
+        }));
+    }
+});